@@ -0,0 +1,1057 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use crate::config::{env_parse, ApiScope, Config, JwtConfig, JwtKey, KeyQuota, TableGrant};
+use crate::graphql::GRAPHQL_PATH;
+use crate::handlers::{constant_time_eq, is_admin, is_health_path, MULTI_PATH, SQL_PATH};
+use crate::messages::{bad_request, error_response, error_response_with, Message};
+use crate::middleware::API_KEY_HEADER;
+use crate::query::{fetch_relation_updatable, RESULT_TABLE_PREFIX};
+use crate::writes::json_to_text;
+
+// Tables and views the API may serve, as visible on the search path
+pub(crate) async fn fetch_table_names(pool: &PgPool) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT c.relname::text
+         FROM pg_class c
+         WHERE c.relkind IN ('r', 'v', 'm', 'f', 'p')
+           AND pg_table_is_visible(c.oid)
+           AND c.relnamespace NOT IN ('pg_catalog'::regnamespace, 'information_schema'::regnamespace)
+           AND c.relname NOT LIKE $1",
+    )
+    .bind(format!("{}%", RESULT_TABLE_PREFIX))
+    .fetch_all(pool)
+    .await
+}
+
+// `*` matches any run of characters, everything else itself
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return pattern == name;
+    };
+    let Some(mut remaining) = name.strip_prefix(prefix) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    let suffix = parts.pop().unwrap_or_default();
+    for part in parts {
+        match remaining.find(part) {
+            Some(i) => remaining = &remaining[i + part.len()..],
+            None => return false,
+        }
+    }
+    remaining.len() >= suffix.len() && remaining.ends_with(suffix)
+}
+
+// With DATAPI_EXPOSE_TABLES (comma-separated globs such as `api_*`), only
+// matching tables are served. The catalog is polled so tables created later
+// are exposed without a restart, or held for approval while approval is
+// required. Names are kept lowercase.
+pub(crate) struct Exposure {
+    pub(crate) patterns: Vec<String>,
+    pub(crate) require_approval: std::sync::atomic::AtomicBool,
+    pub(crate) tables: Mutex<ExposedTables>,
+}
+
+#[derive(Default, Serialize)]
+pub(crate) struct ExposedTables {
+    pub(crate) exposed: std::collections::BTreeSet<String>,
+    pub(crate) pending: std::collections::BTreeSet<String>,
+}
+
+impl Exposure {
+    pub(crate) fn from_env() -> Option<Self> {
+        let patterns: Vec<String> = env::var("DATAPI_EXPOSE_TABLES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|p| p.trim().to_lowercase())
+            .filter(|p| !p.is_empty())
+            .collect();
+        if patterns.is_empty() {
+            return None;
+        }
+        Some(Exposure {
+            patterns,
+            require_approval: std::sync::atomic::AtomicBool::new(env_parse(
+                "DATAPI_EXPOSE_REQUIRE_APPROVAL",
+                false,
+            )),
+            tables: Mutex::new(ExposedTables::default()),
+        })
+    }
+    
+    pub(crate) fn matches(&self, table: &str) -> bool {
+        self.patterns.iter().any(|p| glob_match(p, table))
+    }
+    
+    pub(crate) fn is_exposed(&self, table: &str) -> bool {
+        self.tables.lock().unwrap().exposed.contains(&table.to_lowercase())
+    }
+    
+    // Brings the sets in line with the catalog. Tables present at startup
+    // are exposed directly; later ones wait for approval if it is required.
+    pub(crate) fn sync(&self, names: Vec<String>, startup: bool) {
+        let current: std::collections::BTreeSet<String> = names
+            .into_iter()
+            .map(|n| n.to_lowercase())
+            .filter(|n| self.matches(n))
+            .collect();
+        let approval = !startup && self.require_approval.load(Ordering::Relaxed);
+        
+        let mut tables = self.tables.lock().unwrap();
+        tables.exposed.retain(|t| current.contains(t));
+        tables.pending.retain(|t| current.contains(t));
+        for table in current {
+            if tables.exposed.contains(&table) || tables.pending.contains(&table) {
+                continue;
+            }
+            if approval {
+                log::info!("Table {} is waiting for approval", table);
+                tables.pending.insert(table);
+            } else {
+                if !startup {
+                    log::info!("Exposing new table {}", table);
+                }
+                tables.exposed.insert(table);
+            }
+        }
+    }
+}
+
+pub(crate) async fn watch_catalog(pool: PgPool, exposure: web::Data<Exposure>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match fetch_table_names(&pool).await {
+            Ok(names) => exposure.sync(names, false),
+            Err(e) => log::warn!("Catalog watch failed: {}", e),
+        }
+    }
+}
+
+// With API keys configured, every request except health checks and the
+// admin API (which has its own token) must present a key in X-API-Key.
+// Keys from the keys table are reloaded periodically, so keys can be added
+// and revoked without a restart.
+pub(crate) struct ApiKeys {
+    pub(crate) configured: Vec<(String, ApiScope)>,
+    pub(crate) table: Option<String>,
+    pub(crate) loaded: Mutex<Vec<(String, ApiScope)>>,
+    pub(crate) quotas: BTreeMap<String, KeyQuota>,
+    // Rows served today per key with a daily quota, kept in memory, so each
+    // instance counts its own
+    pub(crate) rows_today: Mutex<HashMap<String, (chrono::NaiveDate, u64)>>,
+}
+
+impl ApiKeys {
+    pub(crate) fn from_config(config: &Config) -> Option<Self> {
+        if config.api_keys.is_empty() && config.api_keys_table.is_none() {
+            return None;
+        }
+        Some(ApiKeys {
+            configured: config.api_keys.clone(),
+            table: config.api_keys_table.clone(),
+            loaded: Mutex::new(Vec::new()),
+            quotas: config.api_key_quotas.clone(),
+            rows_today: Mutex::new(HashMap::new()),
+        })
+    }
+    
+    pub(crate) fn rows_served(&self, key: &str) -> u64 {
+        let today = chrono::Utc::now().date_naive();
+        match self.rows_today.lock().unwrap().get(key) {
+            Some((day, rows)) if *day == today => *rows,
+            _ => 0,
+        }
+    }
+    
+    pub(crate) fn record_rows(&self, key: &str, rows: u64) {
+        let today = chrono::Utc::now().date_naive();
+        let mut served = self.rows_today.lock().unwrap();
+        let (day, total) = served.entry(key.to_string()).or_insert((today, 0));
+        if *day != today {
+            *day = today;
+            *total = 0;
+        }
+        *total += rows;
+    }
+    
+    // Every key is compared, in constant time, so timing doesn't reveal a
+    // matching prefix
+    pub(crate) fn scope(&self, presented: &str) -> Option<ApiScope> {
+        let loaded = self.loaded.lock().unwrap();
+        let mut scope = None;
+        for (key, key_scope) in self.configured.iter().chain(loaded.iter()) {
+            if constant_time_eq(key.as_bytes(), presented.as_bytes()) {
+                scope = Some(*key_scope);
+            }
+        }
+        scope
+    }
+    
+    pub(crate) async fn reload(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        let Some(table) = &self.table else {
+            return Ok(());
+        };
+        let rows = sqlx::query(&format!("SELECT key::text AS key, scope::text AS scope FROM {}", table))
+            .fetch_all(pool)
+            .await?;
+        let keys = rows
+            .iter()
+            .filter_map(|row| {
+                let key: String = row.try_get("key").ok().filter(|k: &String| !k.is_empty())?;
+                let scope: String = row.try_get("scope").unwrap_or_default();
+                match ApiScope::parse(&scope) {
+                    Some(scope) => Some((key, scope)),
+                    None => {
+                        log::warn!("Ignoring API key with invalid scope {:?} in {}", scope, table);
+                        None
+                    }
+                }
+            })
+            .collect();
+        *self.loaded.lock().unwrap() = keys;
+        Ok(())
+    }
+}
+
+// Panics unless the table's schema is off the search path, where the API
+// can't serve the table itself
+pub(crate) async fn check_off_search_path(pool: &PgPool, table: &str, what: &str) {
+    let Some((schema, _)) = table.split_once('.') else {
+        return;
+    };
+    let served: bool = sqlx::query_scalar("SELECT $1 = ANY(current_schemas(true))")
+        .bind(schema.to_lowercase())
+        .fetch_one(pool)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to check the {} schema: {}", what, e));
+    if served {
+        panic!("The {} table must be in a schema off the search path, {} is on it", what, schema);
+    }
+}
+
+pub(crate) async fn watch_api_keys(pool: PgPool, keys: web::Data<ApiKeys>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = keys.reload(&pool).await {
+            log::warn!("API key reload failed: {}", e);
+        }
+    }
+}
+
+// The tables a validated JWT grants access to, kept in the request
+// extensions for handlers that touch tables not named in the path
+#[derive(Debug, Clone)]
+pub(crate) struct TokenGrants {
+    pub(crate) subject: Option<String>,
+    pub(crate) tables: Vec<TableGrant>,
+    pub(crate) claims: serde_json::Value,
+}
+
+impl TokenGrants {
+    pub(crate) fn scope(&self, table: &str) -> Option<ApiScope> {
+        let table = table.trim_matches('"');
+        self.tables
+            .iter()
+            .filter(|g| g.table == "*" || g.table.eq_ignore_ascii_case(table))
+            .map(|g| g.scope)
+            .max_by_key(|scope| *scope == ApiScope::Readwrite)
+    }
+    
+    pub(crate) fn allows(&self, table: &str, method: &actix_web::http::Method) -> bool {
+        self.scope(table).is_some_and(|scope| scope.allows(method))
+    }
+}
+
+// Refuses writes to a table configured read_only, and to views Postgres
+// can't write through and materialized views
+pub(crate) async fn table_read_only(req: &HttpRequest, pool: &PgPool, config: &Config, table: &str) -> Option<HttpResponse> {
+    let read_only = config.read_only_table(table)
+        || match fetch_relation_updatable(pool, table).await {
+            Ok(updatable) => updatable == Some(false),
+            Err(e) => {
+                // The write itself reports what's wrong
+                log::warn!("Relation kind of {} unavailable: {}", table, e);
+                false
+            }
+        };
+    read_only.then(|| {
+        error_response(
+            req,
+            StatusCode::FORBIDDEN,
+            Message::new("table_read_only").arg("table", table.trim_matches('"')),
+        )
+    })
+}
+
+// Denies a JWT-authenticated request access to a table its grants don't cover
+pub(crate) fn table_grant_denied(req: &HttpRequest, table: &str, method: &actix_web::http::Method) -> Option<HttpResponse> {
+    table_grant_message(req, table, method).map(|message| bad_request(req, message))
+}
+
+// The refusal of table_grant_denied, answered with 403 by bad_request
+pub(crate) fn table_grant_message(req: &HttpRequest, table: &str, method: &actix_web::http::Method) -> Option<Message> {
+    let extensions = req.extensions();
+    let grants = extensions.get::<TokenGrants>()?;
+    if grants.allows(table, method) {
+        return None;
+    }
+    let access = if ApiScope::Readonly.allows(method) { "read" } else { "write" };
+    let message = Message::new("table_not_granted").arg("access", access).arg("table", table.trim_matches('"'));
+    Some(message.status(StatusCode::FORBIDDEN))
+}
+
+// The values a table's claim_filters pin its columns to for this request.
+// Requests without a bearer token, or whose token lacks a claim, get none of
+// the table's rows.
+pub(crate) fn claim_values(req: &HttpRequest, config: &Config, table: &str) -> Result<Vec<(String, String)>, Message> {
+    let Some(table_config) = config.table(table.trim_matches('"')).filter(|t| !t.claim_filters.is_empty()) else {
+        return Ok(Vec::new());
+    };
+    let extensions = req.extensions();
+    let claims = extensions.get::<TokenGrants>().map(|g| &g.claims);
+    table_config
+        .claim_filters
+        .iter()
+        .map(|(column, claim)| {
+            let value = claims.and_then(|c| claim.split('.').try_fold(c, |value, key| value.get(key)));
+            match value {
+                Some(serde_json::Value::String(s)) => Ok((column.clone(), s.clone())),
+                Some(v @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_))) => Ok((column.clone(), v.to_string())),
+                _ => Err(Message::new("claim_required").arg("table", table.trim_matches('"')).arg("claim", claim)),
+            }
+        })
+        .collect()
+}
+
+// Sets the pinned columns of a row to be written, rejecting other values
+pub(crate) fn pin_claim_values(pinned: &[(String, String)], obj: &mut serde_json::Map<String, serde_json::Value>) -> Result<(), Message> {
+    for (column, value) in pinned {
+        if let Some(given) = obj.get(column) {
+            if json_to_text(given).as_deref() != Some(value.as_str()) {
+                return Err(Message::new("claim_mismatch").arg("column", column));
+            }
+        }
+        obj.insert(column.clone(), serde_json::Value::String(value.clone()));
+    }
+    Ok(())
+}
+
+// Validates bearer JWTs against the configured key. JWKS keys are refetched
+// periodically so the identity provider can rotate them.
+pub(crate) struct JwtAuth {
+    pub(crate) config: JwtConfig,
+    pub(crate) keys: Mutex<Vec<(Option<String>, Algorithm, DecodingKey)>>,
+}
+
+impl JwtAuth {
+    pub(crate) fn from_config(config: &Config) -> Option<Self> {
+        let jwt = config.jwt.clone()?;
+        let keys = match &jwt.key {
+            JwtKey::Secret(secret) => vec![(None, Algorithm::HS256, DecodingKey::from_secret(secret.as_bytes()))],
+            JwtKey::PublicKey(pem) => match DecodingKey::from_rsa_pem(pem.as_bytes()) {
+                Ok(key) => vec![(None, Algorithm::RS256, key)],
+                Err(_) => Vec::new(),
+            },
+            JwtKey::Jwks(_) => Vec::new(),
+        };
+        Some(JwtAuth { config: jwt, keys: Mutex::new(keys) })
+    }
+    
+    pub(crate) async fn reload(&self) -> Result<(), reqwest::Error> {
+        let JwtKey::Jwks(url) = &self.config.key else {
+            return Ok(());
+        };
+        let set: jsonwebtoken::jwk::JwkSet = reqwest::get(url).await?.error_for_status()?.json().await?;
+        let keys: Vec<_> = set
+            .keys
+            .iter()
+            .filter(|jwk| matches!(jwk.algorithm, jsonwebtoken::jwk::AlgorithmParameters::RSA(_)))
+            .filter(|jwk| jwk.common.key_algorithm.is_none_or(|alg| alg == jsonwebtoken::jwk::KeyAlgorithm::RS256))
+            .filter_map(|jwk| {
+                let key = DecodingKey::from_jwk(jwk).ok()?;
+                Some((jwk.common.key_id.clone(), Algorithm::RS256, key))
+            })
+            .collect();
+        if keys.is_empty() {
+            log::warn!("No RS256 keys in the JWKS at {}", url);
+        }
+        *self.keys.lock().unwrap() = keys;
+        Ok(())
+    }
+    
+    // The token's algorithm must be the one of the configured key, so an
+    // RS256 public key can't be used as an HS256 secret
+    pub(crate) fn validate(&self, token: &str) -> Result<TokenGrants, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+        let key = {
+            let keys = self.keys.lock().unwrap();
+            keys.iter()
+                .find(|(kid, alg, _)| *alg == header.alg && (kid.is_none() || header.kid.is_none() || *kid == header.kid))
+                .map(|(_, alg, key)| (*alg, key.clone()))
+        };
+        let Some((alg, key)) = key else {
+            return Err(format!("no key for algorithm {:?}", header.alg));
+        };
+        let mut validation = Validation::new(alg);
+        if let Some(issuer) = &self.config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        let claims = jsonwebtoken::decode::<serde_json::Value>(token, &key, &validation)
+            .map_err(|e| e.to_string())?
+            .claims;
+        
+        let claim = |path: &str| {
+            let value = path.split('.').try_fold(&claims, |value, key| value.get(key));
+            match value {
+                Some(serde_json::Value::String(s)) => vec![s.as_str()],
+                Some(serde_json::Value::Array(items)) => items.iter().filter_map(|v| v.as_str()).collect(),
+                _ => Vec::new(),
+            }
+        };
+        let mut tables: Vec<TableGrant> = claim(&self.config.tables_claim)
+            .into_iter()
+            .filter_map(TableGrant::parse)
+            .collect();
+        for role in claim(&self.config.role_claim) {
+            tables.extend(self.config.roles.get(role).into_iter().flatten().cloned());
+        }
+        Ok(TokenGrants {
+            subject: claims.get("sub").and_then(|s| s.as_str()).map(str::to_string),
+            tables,
+            claims,
+        })
+    }
+}
+
+pub(crate) async fn watch_jwks(jwt: web::Data<JwtAuth>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = jwt.reload().await {
+            log::warn!("JWKS reload failed: {}", e);
+        }
+    }
+}
+
+// The table a request addresses in its path, checked against JWT grants.
+// Other `_` endpoints check the tables they touch themselves.
+pub(crate) fn path_table(path: &str) -> Option<String> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    let table = match segments.next()? {
+        "_schema" => segments.next()?,
+        t if t.is_empty() || t.starts_with('_') => return None,
+        t => t,
+    };
+    urlencoding::decode(table).ok().map(|t| t.into_owned())
+}
+
+// Requests must present a valid bearer JWT or API key when either is
+// configured. The admin token is accepted as a bearer token too.
+pub(crate) async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let keys = req.app_data::<web::Data<ApiKeys>>().cloned();
+    let jwt = req.app_data::<web::Data<JwtAuth>>();
+    if keys.is_none() && jwt.is_none() {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+    let path = req.path();
+    // Share links carry their own signature
+    let exempt = path.starts_with("/_admin/") || path.starts_with("/_shared/");
+    if is_health_path(path) || path == "/_admin" || path == SQL_PATH || exempt {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+    let config = req.app_data::<web::Data<Config>>().cloned();
+    let table = path_table(req.path());
+    let admin = config.as_ref().is_some_and(|config| is_admin(req.request(), config));
+    
+    let bearer = req
+        .headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    let mut quota_key = None;
+    let mut rejection = match (jwt, bearer, &keys) {
+        _ if admin => None,
+        (Some(jwt), Some(token), _) => match jwt.validate(token) {
+            Ok(grants) => {
+                req.extensions_mut().insert(grants);
+                if let Some(table) = &table {
+                    if let Some(resp) = table_grant_denied(req.request(), table, req.method()) {
+                        return Ok(req.into_response(resp).map_into_right_body());
+                    }
+                }
+                None
+            }
+            Err(cause) => Some((StatusCode::UNAUTHORIZED, Message::new("invalid_token").arg("reason", cause))),
+        },
+        (_, _, Some(keys)) => {
+            let presented = req.headers().get(API_KEY_HEADER).and_then(|h| h.to_str().ok());
+            match presented.and_then(|key| keys.scope(key)) {
+                None => Some((StatusCode::UNAUTHORIZED, Message::new("invalid_api_key"))),
+                // Function calls, GraphQL queries and multi-reads run read-only
+                Some(scope) if !scope.allows(req.method()) && !path.starts_with("/_rpc/") && path != GRAPHQL_PATH && path != MULTI_PATH => Some((
+                    StatusCode::FORBIDDEN,
+                    Message::new("insufficient_scope").arg("method", req.method()),
+                )),
+                Some(_) => {
+                    quota_key = presented.filter(|key| keys.quotas.contains_key(*key)).map(str::to_string);
+                    None
+                }
+            }
+        }
+        (_, _, None) => Some((StatusCode::UNAUTHORIZED, Message::new("missing_token"))),
+    };
+    // Claim filters bind every caller, the admin token included
+    if let (None, Some(config), Some(table)) = (&rejection, &config, &table) {
+        if let Err(message) = claim_values(req.request(), config, table) {
+            rejection = Some((StatusCode::FORBIDDEN, message));
+        }
+    }
+    if let Some((status, message)) = rejection {
+        let resp = error_response(req.request(), status, message);
+        return Ok(req.into_response(resp).map_into_right_body());
+    }
+    let (Some(keys), Some(key)) = (keys, quota_key) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    if let Some(resp) = apply_quota(req.request(), keys, key) {
+        return Ok(req.into_response(resp).map_into_right_body());
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+// Puts an API key's quota on a request, with a RowMeter to count its rows,
+// or refuses it with a 429 once the key's rows for the day are used up
+pub(crate) fn apply_quota(req: &HttpRequest, keys: web::Data<ApiKeys>, key: String) -> Option<HttpResponse> {
+    let quota = keys.quotas.get(&key).cloned().unwrap_or_default();
+    if let Some(max_rows) = quota.max_rows_per_day {
+        if keys.rows_served(&key) >= max_rows {
+            use chrono::Timelike;
+            let secs = 86_400 - u64::from(chrono::Utc::now().num_seconds_from_midnight());
+            let mut resp = error_response(
+                req,
+                StatusCode::TOO_MANY_REQUESTS,
+                Message::new("row_quota_exceeded").arg("max_rows", max_rows).arg("seconds", secs),
+            );
+            resp.headers_mut().insert(
+                actix_web::http::header::RETRY_AFTER,
+                actix_web::http::header::HeaderValue::from(secs),
+            );
+            return Some(resp);
+        }
+    }
+    req.extensions_mut().insert(quota);
+    req.extensions_mut().insert(RowMeter { keys, key });
+    None
+}
+
+// Names a key with a quota in a share link, which must not carry the key
+// itself
+pub(crate) fn key_fingerprint(key: &str) -> String {
+    let digest = <sha2::Sha256 as sha2::Digest>::digest(key.as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Counts the rows served to an API key with a quota, in the request
+// extensions of its requests. Reads count what they return through
+// note_read, streams as their chunks go out.
+#[derive(Clone)]
+pub(crate) struct RowMeter {
+    pub(crate) keys: web::Data<ApiKeys>,
+    pub(crate) key: String,
+}
+
+impl RowMeter {
+    pub(crate) fn record(&self, rows: u64) {
+        if rows > 0 {
+            self.keys.record_rows(&self.key, rows);
+        }
+    }
+}
+
+pub(crate) fn row_meter(req: &HttpRequest) -> Option<RowMeter> {
+    req.extensions().get::<RowMeter>().cloned()
+}
+
+// The page size of a read by an API key with a quota, at most the quota's
+// max_page_size
+pub(crate) fn quota_page_size(req: &HttpRequest, page_size: usize) -> usize {
+    match req.extensions().get::<KeyQuota>().and_then(|q| q.max_page_size) {
+        Some(max) => page_size.min(max),
+        None => page_size,
+    }
+}
+
+// The rows an API key with a quota gets per response, for streams that
+// otherwise return the whole result
+pub(crate) fn quota_max_page_size(req: &HttpRequest) -> Option<usize> {
+    req.extensions().get::<KeyQuota>().and_then(|q| q.max_page_size)
+}
+
+// The row limit of a read, the lower of the table's and the API key's
+pub(crate) fn quota_max_rows(req: &HttpRequest, max_rows: Option<u64>) -> Option<u64> {
+    let quota = req.extensions().get::<KeyQuota>().and_then(|q| q.max_rows);
+    match (max_rows, quota) {
+        (Some(table), Some(key)) => Some(table.min(key)),
+        (table, key) => table.or(key),
+    }
+}
+
+// Answers requests for tables outside the exposure with the same 404 as for a
+// missing table.
+pub(crate) async fn exposure_guard(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let hidden = req.app_data::<web::Data<Exposure>>().and_then(|exposure| {
+        let table = req.path().trim_start_matches('/').split('/').next()?;
+        let table = urlencoding::decode(table).ok()?.into_owned();
+        let fixed = table.is_empty() || table.starts_with('_') || table == "health" || table == "readyz";
+        (!fixed && !exposure.is_exposed(&table)).then_some(table)
+    });
+    if let Some(table) = hidden {
+        let resp = error_response(
+            req.request(),
+            StatusCode::NOT_FOUND,
+            Message::new("table_not_found").arg("table", &table),
+        );
+        return Ok(req.into_response(resp).map_into_right_body());
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+pub(crate) fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+pub(crate) const MAX_TABLE_SUGGESTIONS: usize = 3;
+
+#[derive(Deserialize)]
+pub(crate) struct SchemaParam {
+    pub(crate) schema: Option<String>,
+}
+
+// With DATAPI_RESOLVE_TABLES, maps a sanitized table name onto an existing
+// relation ignoring case; relations with upper-case names come back quoted.
+// Unknown names get a 404 listing the closest names by edit distance.
+// `?schema=` instead qualifies the name with one of DATAPI_SCHEMAS.
+pub(crate) async fn resolve_table(
+    req: &HttpRequest,
+    pool: &PgPool,
+    config: &Config,
+    table: String,
+) -> Result<String, HttpResponse> {
+    let schema = web::Query::<SchemaParam>::from_query(req.query_string()).ok().and_then(|q| q.0.schema);
+    if let Some(schema) = schema {
+        return qualify_table(req, pool, config, &schema, &table).await;
+    }
+    if !config.resolve_tables {
+        return Ok(table);
+    }
+    let names = fetch_table_names(pool).await.map_err(|e| {
+        log::error!("Database error: {}", e);
+        error_response(req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"))
+    })?;
+    
+    if names.contains(&table.to_lowercase()) {
+        return Ok(table.to_lowercase());
+    }
+    if let Some(name) = names.iter().find(|n| n.eq_ignore_ascii_case(&table)) {
+        return Ok(format!("\"{}\"", name));
+    }
+    
+    let requested = table.to_lowercase();
+    let max_distance = (requested.chars().count() / 3).max(2);
+    let mut candidates: Vec<(usize, &String)> = names
+        .iter()
+        .map(|n| (edit_distance(&requested, &n.to_lowercase()), n))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    candidates.sort();
+    let suggestions: Vec<&String> = candidates
+        .into_iter()
+        .take(MAX_TABLE_SUGGESTIONS)
+        .map(|(_, n)| n)
+        .collect();
+    
+    let message = if suggestions.is_empty() {
+        Message::new("table_not_found").arg("table", &table)
+    } else {
+        let list: Vec<&str> = suggestions.iter().map(|s| s.as_str()).collect();
+        Message::new("table_not_found_suggestions")
+            .arg("table", &table)
+            .arg("suggestions", list.join(", "))
+    };
+    let mut body = serde_json::Map::new();
+    body.insert("suggestions".to_string(), serde_json::json!(suggestions));
+    Err(error_response_with(req, StatusCode::NOT_FOUND, message, body))
+}
+
+// Looks the table up in the schema itself, as it's off the search path.
+// Grants and exposure were checked against the bare name, so they are checked
+// again for the qualified one.
+pub(crate) async fn qualify_table(
+    req: &HttpRequest,
+    pool: &PgPool,
+    config: &Config,
+    schema: &str,
+    table: &str,
+) -> Result<String, HttpResponse> {
+    let schema = schema.to_lowercase();
+    if !config.schemas.contains(&schema) {
+        return Err(error_response(
+            req,
+            StatusCode::NOT_FOUND,
+            Message::new("schema_not_found").arg("schema", &schema),
+        ));
+    }
+    let table = table.to_lowercase();
+    let exists: bool = sqlx::query_scalar(
+        "SELECT EXISTS (
+             SELECT 1 FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace
+             WHERE n.nspname = $1 AND c.relname = $2 AND c.relkind IN ('r', 'v', 'm', 'f', 'p')
+         )",
+    )
+    .bind(&schema)
+    .bind(&table)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        log::error!("Database error: {}", e);
+        error_response(req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"))
+    })?;
+    let qualified = format!("{}.{}", schema, table);
+    let hidden = req.app_data::<web::Data<Exposure>>().is_some_and(|e| !e.is_exposed(&qualified));
+    if !exists || hidden {
+        return Err(error_response(
+            req,
+            StatusCode::NOT_FOUND,
+            Message::new("table_not_found").arg("table", &qualified),
+        ));
+    }
+    if let Some(resp) = table_grant_denied(req, &qualified, req.method()) {
+        return Err(resp);
+    }
+    Ok(qualified)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use actix_web::test::TestRequest;
+    use actix_web::App;
+    use actix_web::middleware::from_fn;
+    use crate::filters::{build_where_clause, finish_filters_with, parse_multiple_filters};
+    use crate::writes::pin_bulk_claim_values;
+
+    #[test]
+    fn edit_distance_counts_single_character_edits() {
+        assert_eq!(edit_distance("loans", "loans"), 0);
+        assert_eq!(edit_distance("laons", "loans"), 2);
+        assert_eq!(edit_distance("loan", "loans"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[actix_web::test]
+    async fn table_resolution_is_opt_in() {
+        let req = TestRequest::default().to_http_request();
+        let table = resolve_table(&req, &offline_pool(), &test_config(), "Loans".to_string()).await;
+        assert_eq!(table.unwrap(), "Loans");
+
+        let config = Config { resolve_tables: true, ..test_config() };
+        let resp = resolve_table(&req, &offline_pool(), &config, "Loans".to_string()).await.unwrap_err();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn table_globs_match_whole_names() {
+        assert!(glob_match("api_*", "api_loans"));
+        assert!(glob_match("*_v2", "loans_v2"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(glob_match("loans", "loans"));
+        assert!(!glob_match("api_*", "old_api_loans"));
+        assert!(!glob_match("a*bc", "abc_"));
+        assert!(!glob_match("a*a", "a"));
+    }
+
+    #[test]
+    fn new_tables_wait_for_approval_when_required() {
+        let exposure = exposure(true);
+        exposure.sync(vec!["API_Loans".to_string(), "secrets".to_string()], true);
+        assert!(exposure.is_exposed("api_loans"));
+        assert!(!exposure.is_exposed("secrets"));
+
+        exposure.sync(vec!["api_loans".to_string(), "api_new".to_string()], false);
+        assert!(!exposure.is_exposed("api_new"));
+        assert!(exposure.tables.lock().unwrap().pending.contains("api_new"));
+
+        // Dropped tables leave both sets
+        exposure.sync(Vec::new(), false);
+        let tables = exposure.tables.lock().unwrap();
+        assert!(tables.exposed.is_empty() && tables.pending.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn hidden_tables_look_missing() {
+        let exposure = web::Data::new(exposure(false));
+        exposure.sync(vec!["api_loans".to_string(), "secrets".to_string()], true);
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(exposure.clone())
+                .wrap(from_fn(exposure_guard))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let status = |path: &str| {
+            let req = TestRequest::get().uri(path).to_request();
+            let app = &app;
+            async move { actix_web::test::call_service(app, req).await.status() }
+        };
+        assert_eq!(status("/api_loans/id=1").await, StatusCode::OK);
+        assert_eq!(status("/secrets").await, StatusCode::NOT_FOUND);
+        assert_eq!(status("/_admin/tables").await, StatusCode::OK);
+        assert_eq!(status("/health").await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn api_keys_guard_everything_but_health_and_admin() {
+        use actix_web::http::Method;
+        assert!(ApiKeys::from_config(&test_config()).is_none());
+        let config = Config {
+            api_keys: vec![("reader".to_string(), ApiScope::Readonly), ("writer".to_string(), ApiScope::Readwrite)],
+            ..test_config()
+        };
+        let keys = ApiKeys::from_config(&config).unwrap();
+        assert_eq!(keys.scope("writer"), Some(ApiScope::Readwrite));
+        assert_eq!(keys.scope("write"), None);
+
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(keys))
+                .wrap(from_fn(authenticate))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let status = |method: Method, path: &str, key: Option<&str>| {
+            let mut req = TestRequest::default().method(method).uri(path);
+            if let Some(key) = key {
+                req = req.insert_header((API_KEY_HEADER, key));
+            }
+            let req = req.to_request();
+            let app = &app;
+            async move { actix_web::test::call_service(app, req).await.status() }
+        };
+        assert_eq!(status(Method::GET, "/loans", None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(Method::GET, "/loans", Some("reader")).await, StatusCode::OK);
+        assert_eq!(status(Method::POST, "/loans", Some("reader")).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(Method::POST, "/loans", Some("writer")).await, StatusCode::OK);
+        assert_eq!(status(Method::GET, "/health", None).await, StatusCode::OK);
+        assert_eq!(status(Method::GET, "/_admin/cache", None).await, StatusCode::OK);
+    }
+
+    #[test]
+    fn grants_cover_their_tables_and_scopes() {
+        use actix_web::http::Method;
+        let all = grants(&["loans", "notes:readwrite", "*"]);
+        assert!(all.allows("\"Loans\"", &Method::GET));
+        assert!(!all.allows("loans", &Method::POST));
+        assert!(all.allows("notes", &Method::DELETE));
+        assert!(all.allows("customers", &Method::GET));
+        assert!(!all.allows("customers", &Method::PATCH));
+        assert!(!grants(&["loans"]).allows("customers", &Method::GET));
+    }
+
+    #[test]
+    fn tables_outside_the_grants_are_forbidden() {
+        use actix_web::http::Method;
+        let req = TestRequest::default().to_http_request();
+        assert!(table_grant_denied(&req, "loans", &Method::GET).is_none());
+
+        req.extensions_mut().insert(grants(&["loans"]));
+        assert!(table_grant_denied(&req, "loans", &Method::GET).is_none());
+        assert_eq!(table_grant_denied(&req, "loans", &Method::POST).unwrap().status(), StatusCode::FORBIDDEN);
+        assert_eq!(table_grant_denied(&req, "customers", &Method::GET).unwrap().status(), StatusCode::FORBIDDEN);
+        assert_eq!(path_table("/_schema/loans"), Some("loans".to_string()));
+        assert_eq!(path_table("/My%20Loans/1"), Some("My Loans".to_string()));
+        assert_eq!(path_table("/_openapi.json"), None);
+    }
+
+    #[test]
+    fn jwts_are_validated_and_grant_tables_by_claim_and_role() {
+        let jwt = JwtAuth::from_config(&Config {
+            jwt: Some(JwtConfig {
+                key: JwtKey::Secret("s3cret".to_string()),
+                issuer: Some("https://id.example".to_string()),
+                audience: None,
+                tables_claim: "tables".to_string(),
+                role_claim: "realm.roles".to_string(),
+                roles: BTreeMap::from([("clerk".to_string(), vec![TableGrant::parse("notes:readwrite").unwrap()])]),
+                settings: BTreeMap::new(),
+            }),
+            ..test_config()
+        })
+        .unwrap();
+        let token = |secret: &str, claims: serde_json::Value| {
+            jsonwebtoken::encode(
+                &jsonwebtoken::Header::default(),
+                &claims,
+                &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+            )
+            .unwrap()
+        };
+        let claims = serde_json::json!({
+            "sub": "alice", "iss": "https://id.example", "exp": 4_000_000_000u64,
+            "tables": "loans", "realm": { "roles": ["clerk", "unknown"] }
+        });
+        let grants = jwt.validate(&token("s3cret", claims.clone())).unwrap();
+        assert_eq!(grants.subject.as_deref(), Some("alice"));
+        assert_eq!(grants.scope("loans"), Some(ApiScope::Readonly));
+        assert_eq!(grants.scope("notes"), Some(ApiScope::Readwrite));
+        assert!(jwt.validate(&token("guess", claims.clone())).is_err());
+        let mut foreign = claims;
+        foreign["iss"] = serde_json::json!("https://other.example");
+        assert!(jwt.validate(&token("s3cret", foreign)).is_err());
+    }
+
+    #[test]
+    fn claim_filters_pin_reads_and_writes_to_the_token() {
+        let config = config_with(serde_json::json!({ "loans": { "claim_filters": { "org_id": "org.id" } } }));
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(claim_values(&req, &config, "loans").unwrap_err().key, "claim_required");
+        assert!(claim_values(&req, &config, "customers").unwrap().is_empty());
+
+        req.extensions_mut().insert(grants(&["loans"]));
+        let pinned = claim_values(&req, &config, "\"loans\"").unwrap();
+        assert_eq!(pinned, vec![("org_id".to_string(), "7".to_string())]);
+        let filters = finish_filters_with(&req, parse_multiple_filters("id=1").unwrap(), None, &config, "loans", &HashMap::new()).unwrap();
+        assert_eq!(build_where_clause(&filters), " WHERE id::text = $1::text AND org_id::text = $2::text");
+
+        let mut row = objects(serde_json::json!({ "id": 1 })).remove(0);
+        pin_claim_values(&pinned, &mut row).unwrap();
+        assert_eq!(row["org_id"], "7");
+        let mut row = objects(serde_json::json!({ "org_id": 8 })).remove(0);
+        assert_eq!(pin_claim_values(&pinned, &mut row).unwrap_err().key, "claim_mismatch");
+
+        let (mut header, mut rows) = (vec!["id".to_string()], vec![vec![Some("1".to_string())]]);
+        pin_bulk_claim_values(&pinned, &mut header, &mut rows).unwrap();
+        assert_eq!((header, rows), (vec!["id".to_string(), "org_id".to_string()], vec![vec![Some("1".to_string()), Some("7".to_string())]]));
+    }
+
+    #[actix_web::test]
+    async fn only_listed_schemas_can_be_read() {
+        let config = Config { schemas: vec!["reporting".to_string()], ..test_config() };
+        let resolve = |uri: &str| {
+            let req = TestRequest::get().uri(uri).to_http_request();
+            let config = &config;
+            async move {
+                match resolve_table(&req, &offline_pool(), config, "loans".to_string()).await {
+                    Ok(table) => Ok(table),
+                    Err(resp) => Err(resp.status()),
+                }
+            }
+        };
+        assert_eq!(resolve("/loans").await, Ok("loans".to_string()));
+        assert_eq!(resolve("/loans?schema=internal").await, Err(StatusCode::NOT_FOUND));
+        // The table is looked up in the catalog
+        assert_eq!(resolve("/loans?schema=Reporting").await, Err(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+
+    #[test]
+    fn quotas_cap_pages_and_row_limits() {
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(quota_page_size(&req, 500), 500);
+        assert_eq!(quota_max_rows(&req, Some(100)), Some(100));
+
+        req.extensions_mut().insert(KeyQuota { max_rows: Some(50), max_rows_per_day: None, max_page_size: Some(20) });
+        assert_eq!(quota_page_size(&req, 500), 20);
+        assert_eq!(quota_page_size(&req, 10), 10);
+        assert_eq!(quota_max_page_size(&req), Some(20));
+        assert_eq!(quota_max_rows(&req, Some(100)), Some(50));
+        assert_eq!(quota_max_rows(&req, Some(10)), Some(10));
+        assert_eq!(quota_max_rows(&req, None), Some(50));
+    }
+
+    #[test]
+    fn daily_rows_are_counted_per_key() {
+        let keys = ApiKeys {
+            configured: Vec::new(),
+            table: None,
+            loaded: Mutex::new(Vec::new()),
+            quotas: BTreeMap::new(),
+            rows_today: Mutex::new(HashMap::new()),
+        };
+        keys.record_rows("k1", 4);
+        keys.record_rows("k1", 6);
+        assert_eq!(keys.rows_served("k1"), 10);
+        assert_eq!(keys.rows_served("k2"), 0);
+        // Yesterday's rows don't count
+        let yesterday = chrono::Utc::now().date_naive().pred_opt().unwrap();
+        keys.rows_today.lock().unwrap().insert("k1".to_string(), (yesterday, 10));
+        assert_eq!(keys.rows_served("k1"), 0);
+        keys.record_rows("k1", 3);
+        assert_eq!(keys.rows_served("k1"), 3);
+    }
+
+    fn keys(quota: KeyQuota) -> web::Data<ApiKeys> {
+        let config = Config {
+            api_keys: vec![("k1".to_string(), ApiScope::Readonly)],
+            api_key_quotas: BTreeMap::from([("k1".to_string(), quota)]),
+            ..test_config()
+        };
+        web::Data::new(ApiKeys::from_config(&config).unwrap())
+    }
+
+    #[test]
+    fn daily_row_quota_is_metered_then_refused() {
+        let keys = keys(KeyQuota { max_rows: None, max_rows_per_day: Some(10), max_page_size: Some(5) });
+        let req = TestRequest::default().to_http_request();
+        assert!(apply_quota(&req, keys.clone(), "k1".to_string()).is_none());
+        assert_eq!(quota_page_size(&req, 100), 5);
+        let meter = row_meter(&req).unwrap();
+        meter.record(4);
+        assert_eq!(keys.rows_served("k1"), 4);
+        meter.record(6);
+
+        let req = TestRequest::default().to_http_request();
+        let refused = apply_quota(&req, keys.clone(), "k1".to_string()).unwrap();
+        assert_eq!(refused.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(refused.headers().contains_key(actix_web::http::header::RETRY_AFTER));
+        assert!(row_meter(&req).is_none());
+
+        // Keys without a quota are metered but never refused
+        assert!(apply_quota(&req, keys, "k2".to_string()).is_none());
+        assert_eq!(quota_page_size(&req, 100), 100);
+    }
+
+    #[test]
+    fn key_fingerprints_are_sha256_hex() {
+        assert_eq!(key_fingerprint("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
@@ -0,0 +1,1371 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Column, Executor, PgPool, Row};
+use crate::access::claim_values;
+use crate::config::{
+    default_history_key, is_http_url, Config, ExportConfig, HistoryConfig, RetentionConfig,
+    RollupConfig, WebhookConfig, ALIAS_SCHEMA,
+};
+use crate::filters::{bind_filters, build_where_clause, sanitize_table_name, FilterExpr};
+use crate::handlers::reject_non_admin;
+use crate::messages::{bad_request, error_response, Message, Translations};
+use crate::middleware::{AuditLog, AuditMessage, AuditRecord};
+use crate::query::{
+    begin_table_transaction, parse_result_comment, parse_select, result_table_name, tag_query,
+    unix_now, RowCounts, RESULT_CLEANUP_INTERVAL, RESULT_TABLE_PREFIX,
+};
+use crate::serialization::{csv_field, protect_columns, row_to_json, ValueFormat};
+use crate::writes::{json_to_text, quote_identifier};
+
+// Recreates the alias views, dropping those of aliases no longer configured.
+// Views over a single relation are updatable, so writes go through them too.
+pub(crate) async fn install_aliases(pool: &PgPool, config: &Config) {
+    if config.aliases.is_empty() {
+        return;
+    }
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('datapi_aliases'))")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(&format!("CREATE SCHEMA IF NOT EXISTS {}", ALIAS_SCHEMA))
+            .execute(&mut *tx)
+            .await?;
+        let existing: Vec<String> = sqlx::query_scalar("SELECT viewname::text FROM pg_views WHERE schemaname = $1")
+            .bind(ALIAS_SCHEMA)
+            .fetch_all(&mut *tx)
+            .await?;
+        for view in existing {
+            sqlx::query(&format!("DROP VIEW {}.{}", ALIAS_SCHEMA, quote_identifier(&view)))
+                .execute(&mut *tx)
+                .await?;
+        }
+        // RLS policies of the target then apply to the caller, as for tables
+        let invoker: bool = sqlx::query_scalar("SELECT current_setting('server_version_num')::int >= 150000")
+            .fetch_one(&mut *tx)
+            .await?;
+        
+        for (alias, target) in &config.aliases {
+            let columns: Vec<String> = sqlx::query_scalar(
+                "SELECT attname::text FROM pg_attribute
+                 WHERE attrelid = to_regclass($1) AND attnum > 0 AND NOT attisdropped
+                 ORDER BY attnum",
+            )
+            .bind(&target.target)
+            .fetch_all(&mut *tx)
+            .await?;
+            if columns.is_empty() {
+                panic!("Target {} of alias {} does not exist", target.target, alias);
+            }
+            for column in target.columns.values() {
+                if !columns.contains(column) {
+                    panic!("Column {} of alias {} does not exist in {}", column, alias, target.target);
+                }
+            }
+            let select: Vec<String> = columns
+                .iter()
+                .map(|column| match target.columns.iter().find(|(_, c)| *c == column) {
+                    Some((public, _)) => format!("{} AS {}", quote_identifier(column), public),
+                    None => quote_identifier(column),
+                })
+                .collect();
+            sqlx::query(&format!(
+                "CREATE VIEW {}.{}{} AS SELECT {} FROM {}",
+                ALIAS_SCHEMA,
+                alias,
+                if invoker { " WITH (security_invoker = true)" } else { "" },
+                select.join(", "),
+                target.target
+            ))
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+    .await;
+    
+    if let Err(e) = result {
+        panic!("Failed to install table aliases: {}", e);
+    }
+    log::info!("Serving {} table aliases", config.aliases.len());
+}
+
+// When POST /_schema/reload last ran. Connections opened before it are
+// closed instead of handed out, so statements they prepared against the old
+// schema aren't reused.
+pub(crate) static SCHEMA_RELOADED: Mutex<Option<Instant>> = Mutex::new(None);
+
+// Connections look up alias views before the relations on the default
+// search path.
+pub(crate) fn pool_options(config: &Config) -> PgPoolOptions {
+    let options = PgPoolOptions::new()
+        .max_connections(config.pool_max_connections)
+        .min_connections(config.pool_min_connections)
+        .acquire_timeout(config.pool_acquire_timeout)
+        .idle_timeout(config.pool_idle_timeout)
+        .max_lifetime(config.pool_max_lifetime)
+        .test_before_acquire(config.pool_test_before_acquire)
+        .before_acquire(|_, meta| {
+            Box::pin(async move {
+                let reloaded = *SCHEMA_RELOADED.lock().unwrap();
+                Ok(reloaded.is_none_or(|at| meta.age <= at.elapsed()))
+            })
+        });
+    let aliases = !config.aliases.is_empty();
+    let read_only = config.read_only;
+    let statement_timeout_ms = config.statement_timeout_ms;
+    if !aliases && !read_only && statement_timeout_ms.is_none() {
+        return options;
+    }
+    options.after_connect(move |conn, _| {
+        Box::pin(async move {
+            if aliases {
+                conn.execute(format!(
+                    "SELECT set_config('search_path', '{}, ' || current_setting('search_path'), false)",
+                    ALIAS_SCHEMA
+                ).as_str())
+                .await?;
+            }
+            // A statement that slips past the disabled endpoints still can't
+            // change anything
+            if read_only {
+                conn.execute("SET default_transaction_read_only = on").await?;
+            }
+            // Bounds how long a runaway query can hold the connection;
+            // tables and requests may set their own within a transaction
+            if let Some(ms) = statement_timeout_ms {
+                conn.execute(format!("SET statement_timeout = {}", ms).as_str()).await?;
+            }
+            Ok(())
+        })
+    })
+}
+
+pub(crate) const ACTOR_HEADER: &str = "x-actor";
+
+// Row versions of tables with `history` are written by a trigger into
+// datapi_history.changes, a schema off the search path so the API doesn't
+// serve it as a table. Each version is the row after an insert or update, or
+// the last state before a delete.
+pub(crate) const HISTORY_SETUP: &[&str] = &[
+    "CREATE SCHEMA IF NOT EXISTS datapi_history",
+    "CREATE TABLE IF NOT EXISTS datapi_history.changes (
+        id bigserial PRIMARY KEY,
+        table_name text NOT NULL,
+        row_key text,
+        operation text NOT NULL,
+        row_data jsonb NOT NULL,
+        changed_by text NOT NULL,
+        changed_at timestamptz NOT NULL DEFAULT now()
+    )",
+    "CREATE INDEX IF NOT EXISTS changes_row_idx ON datapi_history.changes (table_name, row_key, id)",
+    "CREATE OR REPLACE FUNCTION datapi_history.record_change() RETURNS trigger
+     LANGUAGE plpgsql AS $$
+     DECLARE
+         data jsonb := to_jsonb(CASE WHEN TG_OP = 'DELETE' THEN OLD ELSE NEW END);
+     BEGIN
+         INSERT INTO datapi_history.changes (table_name, row_key, operation, row_data, changed_by)
+         VALUES (
+             TG_ARGV[0],
+             data ->> TG_ARGV[1],
+             TG_OP,
+             data,
+             COALESCE(NULLIF(current_setting('datapi.actor', true), ''), session_user)
+         );
+         RETURN NULL;
+     END
+     $$",
+];
+
+// Installs the history table and triggers, failing startup like other
+// configuration errors. Several instances may start at once, hence the lock.
+pub(crate) async fn install_history(pool: &PgPool, config: &Config) {
+    let mut tables: Vec<(&String, &HistoryConfig)> = config
+        .tables
+        .iter()
+        .filter_map(|(name, table)| table.history.as_ref().map(|h| (name, h)))
+        .collect();
+    if tables.is_empty() {
+        return;
+    }
+    tables.sort_by_key(|(name, _)| name.as_str());
+    
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('datapi_history'))")
+            .execute(&mut *tx)
+            .await?;
+        for statement in HISTORY_SETUP {
+            sqlx::query(statement).execute(&mut *tx).await?;
+        }
+        for (name, history) in &tables {
+            sqlx::query(&format!("DROP TRIGGER IF EXISTS datapi_history ON {}", name))
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(&format!(
+                "CREATE TRIGGER datapi_history AFTER INSERT OR UPDATE OR DELETE ON {} \
+                 FOR EACH ROW EXECUTE FUNCTION datapi_history.record_change('{}', '{}')",
+                name, name, history.key
+            ))
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+    .await;
+    
+    if let Err(e) = result {
+        panic!("Failed to install history triggers: {}", e);
+    }
+    log::info!("Recording change history for {} tables", tables.len());
+}
+
+pub(crate) const CHANGES_CHANNEL: &str = "datapi_changes";
+
+// NOTIFY payloads are limited to 8000 bytes; a larger row is sent as its key
+// alone, marked truncated.
+pub(crate) const CHANGES_SETUP: &[&str] = &[
+    "CREATE SCHEMA IF NOT EXISTS datapi_changes",
+    "CREATE OR REPLACE FUNCTION datapi_changes.notify_change() RETURNS trigger
+     LANGUAGE plpgsql AS $$
+     DECLARE
+         data jsonb := to_jsonb(CASE WHEN TG_OP = 'DELETE' THEN OLD ELSE NEW END);
+         payload text := jsonb_build_object('table', TG_ARGV[0], 'operation', lower(TG_OP), 'row', data)::text;
+     BEGIN
+         IF octet_length(payload) > 7900 THEN
+             payload := jsonb_build_object(
+                 'table', TG_ARGV[0],
+                 'operation', lower(TG_OP),
+                 'row', jsonb_build_object(TG_ARGV[1], data -> TG_ARGV[1]),
+                 'truncated', true
+             )::text;
+         END IF;
+         PERFORM pg_notify('datapi_changes', payload);
+         RETURN NULL;
+     END
+     $$",
+];
+
+// Installs the notify triggers of tables with `changes` or `webhooks`, like
+// install_history
+pub(crate) async fn install_change_triggers(pool: &PgPool, config: &Config) -> bool {
+    let mut tables: Vec<(&String, String)> = config
+        .tables
+        .iter()
+        .filter(|(_, table)| table.changes.is_some() || !table.webhooks.is_empty())
+        .map(|(name, table)| (name, table.changes.as_ref().map_or_else(default_history_key, |c| c.key.clone())))
+        .collect();
+    if tables.is_empty() {
+        return false;
+    }
+    tables.sort_by_key(|(name, _)| name.as_str());
+    
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('datapi_changes'))")
+            .execute(&mut *tx)
+            .await?;
+        for statement in CHANGES_SETUP {
+            sqlx::query(statement).execute(&mut *tx).await?;
+        }
+        for (name, key) in &tables {
+            sqlx::query(&format!("DROP TRIGGER IF EXISTS datapi_changes ON {}", name))
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(&format!(
+                "CREATE TRIGGER datapi_changes AFTER INSERT OR UPDATE OR DELETE ON {} \
+                 FOR EACH ROW EXECUTE FUNCTION datapi_changes.notify_change('{}', '{}')",
+                name, name, key
+            ))
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+    .await;
+    
+    if let Err(e) = result {
+        panic!("Failed to install change triggers: {}", e);
+    }
+    log::info!("Streaming changes of {} tables", tables.len());
+    true
+}
+
+// Fails startup when a virtual column expression or a configured filter
+// doesn't compile against its table.
+pub(crate) async fn check_table_expressions(pool: &PgPool, config: &Config) {
+    for (table, table_config) in &config.tables {
+        if table_config.virtual_columns.is_empty() && table_config.filters.is_empty() {
+            continue;
+        }
+        if sanitize_table_name(table).is_err() {
+            panic!("Invalid table name {} with virtual columns or filters", table);
+        }
+        if !table_config.virtual_columns.is_empty() {
+            let columns: Vec<String> = table_config
+                .virtual_columns
+                .iter()
+                .map(|(name, expr)| format!("({}) AS {}", expr, name))
+                .collect();
+            let query = format!("SELECT {} FROM {} LIMIT 0", columns.join(", "), table);
+            if let Err(e) = sqlx::query(&query).execute(pool).await {
+                panic!("Invalid virtual columns for table {}: {}", table, e);
+            }
+        }
+        for filter in &table_config.filters {
+            let query = format!("SELECT 1 FROM {} WHERE ({}) LIMIT 0", table, filter);
+            if let Err(e) = sqlx::query(&query).execute(pool).await {
+                panic!("Invalid filter {:?} for table {}: {}", filter, table, e);
+            }
+        }
+    }
+}
+
+// Keeps a rollup table up to date: a full rebuild at startup, then periodic
+// recomputation of the trailing buckets. An advisory lock keeps several
+// instances from refreshing the same rollup concurrently.
+pub(crate) async fn maintain_rollup(pool: PgPool, rollup: RollupConfig) {
+    let create = format!(
+        "CREATE TABLE IF NOT EXISTS {} AS SELECT {} FROM {} GROUP BY {} WITH NO DATA",
+        rollup.table,
+        rollup.select_list(),
+        rollup.source,
+        rollup.group_by()
+    );
+    let index = format!(
+        "CREATE INDEX IF NOT EXISTS {}_bucket_idx ON {} (bucket)",
+        rollup.table, rollup.table
+    );
+    for statement in [&create, &index] {
+        if let Err(e) = sqlx::query(statement).execute(&pool).await {
+            log::error!("Failed to create rollup {}: {}", rollup.table, e);
+            return;
+        }
+    }
+    
+    let mut full = true;
+    loop {
+        let started = Instant::now();
+        match refresh_rollup(&pool, &rollup, full).await {
+            Ok(Some(rows)) => {
+                log::info!(
+                    "Refreshed rollup {} ({} rows, {} refresh) in {:?}",
+                    rollup.table,
+                    rows,
+                    if full { "full" } else { "incremental" },
+                    started.elapsed()
+                );
+                full = false;
+            }
+            Ok(None) => log::info!("Rollup {} is being refreshed elsewhere", rollup.table),
+            Err(e) => log::error!("Failed to refresh rollup {}: {}", rollup.table, e),
+        }
+        tokio::time::sleep(Duration::from_secs(rollup.refresh_secs.max(1))).await;
+    }
+}
+
+// Returns the number of rows written, or None when another session holds
+// the rollup's lock.
+pub(crate) async fn refresh_rollup(
+    pool: &PgPool,
+    rollup: &RollupConfig,
+    full: bool,
+) -> Result<Option<u64>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    
+    let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_xact_lock(hashtext($1))")
+        .bind(format!("datapi_rollup:{}", rollup.table))
+        .fetch_one(&mut *tx)
+        .await?;
+    if !locked {
+        return Ok(None);
+    }
+    
+    let since = format!(
+        "date_trunc('{}', now()) - $1::int * interval '1 {}'",
+        rollup.bucket, rollup.bucket
+    );
+    let (delete, insert) = if full {
+        (
+            format!("DELETE FROM {}", rollup.table),
+            format!(
+                "INSERT INTO {} SELECT {} FROM {} GROUP BY {}",
+                rollup.table,
+                rollup.select_list(),
+                rollup.source,
+                rollup.group_by()
+            ),
+        )
+    } else {
+        (
+            format!("DELETE FROM {} WHERE bucket >= {}", rollup.table, since),
+            format!(
+                "INSERT INTO {} SELECT {} FROM {} WHERE {} >= {} GROUP BY {}",
+                rollup.table,
+                rollup.select_list(),
+                rollup.source,
+                rollup.time_column,
+                since,
+                rollup.group_by()
+            ),
+        )
+    };
+    
+    let mut delete_query = sqlx::query(&delete);
+    let mut insert_query = sqlx::query(&insert);
+    if !full {
+        delete_query = delete_query.bind(rollup.lookback_buckets);
+        insert_query = insert_query.bind(rollup.lookback_buckets);
+    }
+    delete_query.execute(&mut *tx).await?;
+    let rows = insert_query.execute(&mut *tx).await?.rows_affected();
+    
+    tx.commit().await?;
+    Ok(Some(rows))
+}
+
+// Drops result tables whose TTL has elapsed.
+pub(crate) async fn cleanup_results(pool: PgPool) {
+    loop {
+        tokio::time::sleep(RESULT_CLEANUP_INTERVAL).await;
+        
+        let rows = sqlx::query(
+            "SELECT c.relname::text AS name, obj_description(c.oid, 'pg_class') AS comment
+             FROM pg_class c
+             WHERE c.relkind = 'r'
+               AND c.relname LIKE 'datapi\\_result\\_%'
+               AND pg_table_is_visible(c.oid)",
+        )
+        .fetch_all(&pool)
+        .await;
+        let rows = match rows {
+            Ok(r) => r,
+            Err(e) => {
+                log::warn!("Result cleanup failed: {}", e);
+                continue;
+            }
+        };
+        
+        let now = unix_now();
+        for row in rows {
+            let name: String = row.try_get("name").unwrap_or_default();
+            let comment: Option<String> = row.try_get("comment").unwrap_or_default();
+            let expired = match comment.as_deref().and_then(parse_result_comment) {
+                Some(meta) => meta.expires <= now,
+                None => false,
+            };
+            if expired && result_table_name(&name[RESULT_TABLE_PREFIX.len()..]).is_some() {
+                match sqlx::query(&format!("DROP TABLE IF EXISTS {}", name)).execute(&pool).await {
+                    Ok(_) => log::info!("Dropped expired result table {}", name),
+                    Err(e) => log::warn!("Failed to drop result table {}: {}", name, e),
+                }
+            }
+        }
+    }
+}
+
+// Outcome of the retention runs per table, served at /_admin/retention
+#[derive(Default)]
+pub(crate) struct RetentionStats {
+    pub(crate) tables: Mutex<BTreeMap<String, RetentionRun>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct RetentionRun {
+    pub(crate) last_run_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub(crate) last_duration_ms: u64,
+    // Rows deleted (or soft-deleted) by the last run and since startup
+    pub(crate) last_rows: u64,
+    pub(crate) total_rows: u64,
+    pub(crate) last_error: Option<String>,
+}
+
+// Checks each retention setting against its table, so a wrong column or
+// interval fails startup instead of every run.
+pub(crate) async fn check_retention(pool: &PgPool, config: &Config) {
+    for (table, table_config) in &config.tables {
+        let Some(retention) = &table_config.retention else {
+            continue;
+        };
+        let query = format!(
+            "SELECT 1 FROM {} WHERE {} < now() - $1::interval{} LIMIT 0",
+            table,
+            retention.column,
+            retention.soft_delete.as_ref().map(|c| format!(" AND {} IS NULL", quote_identifier(c))).unwrap_or_default()
+        );
+        if let Err(e) = sqlx::query(&query).bind(&retention.max_age).execute(pool).await {
+            panic!("Invalid retention for table {}: {}", table, e);
+        }
+    }
+}
+
+// Expires the table's rows in batches every interval_secs. Each batch is its
+// own transaction, and a run is skipped while another instance holds the
+// table's advisory lock.
+pub(crate) async fn enforce_retention(pool: PgPool, table: String, retention: RetentionConfig, stats: web::Data<RetentionStats>) {
+    loop {
+        let started = Instant::now();
+        let started_at = chrono::Utc::now();
+        let mut rows = 0u64;
+        let error = loop {
+            match expire_rows(&pool, &table, &retention).await {
+                Ok(Some(n)) => {
+                    rows += n;
+                    if n < retention.batch_size as u64 {
+                        break None;
+                    }
+                }
+                Ok(None) => {
+                    log::info!("Retention for {} is running elsewhere", table);
+                    break None;
+                }
+                Err(e) => {
+                    log::error!("Retention for {} failed: {}", table, e);
+                    break Some(e.to_string());
+                }
+            }
+        };
+        if rows > 0 {
+            log::info!("Expired {} rows of {} in {:?}", rows, table, started.elapsed());
+        }
+        {
+            let mut tables = stats.tables.lock().unwrap();
+            let run = tables.entry(table.clone()).or_default();
+            run.last_run_at = Some(started_at);
+            run.last_duration_ms = started.elapsed().as_millis() as u64;
+            run.last_rows = rows;
+            run.total_rows += rows;
+            run.last_error = error;
+        }
+        tokio::time::sleep(Duration::from_secs(retention.interval_secs)).await;
+    }
+}
+
+// Deletes one batch of expired rows. Returns the number of rows affected, or
+// None when another session holds the table's lock.
+pub(crate) async fn expire_rows(pool: &PgPool, table: &str, retention: &RetentionConfig) -> Result<Option<u64>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_xact_lock(hashtext($1))")
+        .bind(format!("datapi_retention:{}", table))
+        .fetch_one(&mut *tx)
+        .await?;
+    if !locked {
+        return Ok(None);
+    }
+    // Named in row history as the one who changed the row
+    sqlx::query("SELECT set_config('datapi.actor', 'datapi retention', true)")
+        .execute(&mut *tx)
+        .await?;
+    
+    let expired = format!(
+        "SELECT ctid FROM {} WHERE {} < now() - $1::interval{} LIMIT $2",
+        table,
+        retention.column,
+        retention.soft_delete.as_ref().map(|c| format!(" AND {} IS NULL", quote_identifier(c))).unwrap_or_default()
+    );
+    let statement = match &retention.soft_delete {
+        Some(column) => format!("UPDATE {} SET {} = now() WHERE ctid IN ({})", table, quote_identifier(column), expired),
+        None => format!("DELETE FROM {} WHERE ctid IN ({})", table, expired),
+    };
+    let result = sqlx::query(&statement)
+        .bind(&retention.max_age)
+        .bind(retention.batch_size)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(Some(result.rows_affected()))
+}
+
+pub(crate) async fn retention_status(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    stats: web::Data<RetentionStats>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    let tables = stats.tables.lock().unwrap();
+    HttpResponse::Ok().json(&*tables)
+}
+
+pub(crate) const EXPORT_HISTORY_RUNS: usize = 20;
+pub(crate) const EXPORT_UPLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Recent runs of each scheduled export, served at /_admin/exports
+#[derive(Default)]
+pub(crate) struct ExportStats {
+    pub(crate) exports: Mutex<BTreeMap<String, ExportHistory>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct ExportHistory {
+    pub(crate) schedule: String,
+    pub(crate) next_run_at: Option<chrono::DateTime<chrono::Utc>>,
+    // Newest first, the last EXPORT_HISTORY_RUNS
+    pub(crate) runs: Vec<ExportRun>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ExportRun {
+    pub(crate) started_at: chrono::DateTime<chrono::Utc>,
+    pub(crate) duration_ms: u64,
+    pub(crate) rows: u64,
+    pub(crate) bytes: u64,
+    // The file written or the URL uploaded to
+    pub(crate) location: Option<String>,
+    pub(crate) error: Option<String>,
+}
+
+impl ExportStats {
+    pub(crate) fn update(&self, name: &str, export: &ExportConfig, f: impl FnOnce(&mut ExportHistory)) {
+        let mut exports = self.exports.lock().unwrap();
+        let history = exports.entry(name.to_string()).or_insert_with(|| ExportHistory {
+            schedule: export.schedule.clone(),
+            ..ExportHistory::default()
+        });
+        f(history);
+    }
+}
+
+// The export's SELECT and the filters it binds. Like every read of the table
+// it gets the table's configured filters.
+pub(crate) fn export_query(config: &Config, export: &ExportConfig) -> Result<(String, FilterExpr), Message> {
+    let filters = export.filters.clone().unwrap_or_else(FilterExpr::none).sanitize(config, &export.table)?;
+    let configured = config.table(&export.table).map(|t| t.filters.as_slice()).unwrap_or_default();
+    let mut children = match filters {
+        FilterExpr::And(children) => children,
+        other => vec![other],
+    };
+    children.extend(configured.iter().cloned().map(FilterExpr::Sql));
+    let filters = FilterExpr::And(children);
+    let select = parse_select(config, &export.table, export.select.as_deref(), &[], false)?;
+    let sql = format!("SELECT {} FROM {}{}", select, export.table, build_where_clause(&filters));
+    Ok((tag_query(&export.table, &sql), filters))
+}
+
+// Checks each export's query and destination directory, so a typo fails
+// startup instead of the first run hours later.
+pub(crate) async fn check_exports(pool: &PgPool, config: &Config) {
+    for (name, export) in &config.exports {
+        let (sql, filters) = export_query(config, export)
+            .unwrap_or_else(|e| panic!("Invalid export {}: {}", name, Translations::default().render(None, &e)));
+        let sql = format!("{} LIMIT 0", sql);
+        if let Err(e) = bind_filters(sqlx::query(&sql), &filters).execute(pool).await {
+            panic!("Invalid export {}: {}", name, e);
+        }
+        if !is_http_url(&export.destination) {
+            if let Err(e) = std::fs::create_dir_all(&export.destination) {
+                panic!("Invalid destination {} for export {}: {}", export.destination, name, e);
+            }
+        }
+    }
+}
+
+// Runs the export at every time its schedule matches and records the
+// outcome; failed runs are reported to alert_webhook. Instances share the
+// schedule, and the export's advisory lock lets only one of them run it.
+pub(crate) async fn schedule_export(pool: PgPool, config: web::Data<Config>, name: String, stats: web::Data<ExportStats>) {
+    let export = config.exports[&name].clone();
+    let Some(cron) = export.cron.clone() else {
+        return;
+    };
+    let client = reqwest::Client::builder()
+        .timeout(EXPORT_UPLOAD_TIMEOUT)
+        .build()
+        .expect("Failed to build the export client");
+    loop {
+        let Some(next) = cron.next_after(chrono::Utc::now()) else {
+            log::warn!("Export {} has a schedule that never matches", name);
+            return;
+        };
+        stats.update(&name, &export, |h| h.next_run_at = Some(next));
+        tokio::time::sleep((next - chrono::Utc::now()).to_std().unwrap_or_default()).await;
+
+        let started = Instant::now();
+        let started_at = chrono::Utc::now();
+        let (rows, bytes, location, error) = match run_export(&pool, &config, &client, &name, &export, started_at).await {
+            Ok(Some((rows, bytes, location))) => {
+                log::info!("Exported {} rows of {} to {} in {:?}", rows, export.table, location, started.elapsed());
+                (rows, bytes, Some(location), None)
+            }
+            Ok(None) => {
+                log::info!("Export {} is running elsewhere", name);
+                continue;
+            }
+            Err(e) => {
+                log::error!("Export {} failed: {}", name, e);
+                if let Some(url) = &export.alert_webhook {
+                    alert_export_failure(&client, url, &name, &export, started_at, &e).await;
+                }
+                (0, 0, None, Some(e))
+            }
+        };
+        let run = ExportRun {
+            started_at,
+            duration_ms: started.elapsed().as_millis() as u64,
+            rows,
+            bytes,
+            location,
+            error,
+        };
+        stats.update(&name, &export, |h| {
+            h.runs.insert(0, run);
+            h.runs.truncate(EXPORT_HISTORY_RUNS);
+        });
+    }
+}
+
+// Reads the rows and delivers them, returning the row count, size and where
+// they went, or None when another instance holds the export's lock. The lock
+// is held until delivery is done.
+pub(crate) async fn run_export(
+    pool: &PgPool,
+    config: &Config,
+    client: &reqwest::Client,
+    name: &str,
+    export: &ExportConfig,
+    started_at: chrono::DateTime<chrono::Utc>,
+) -> Result<Option<(u64, u64, String)>, String> {
+    let (sql, filters) = export_query(config, export).map_err(|e| Translations::default().render(None, &e))?;
+    let mut tx = begin_table_transaction(pool, config, &export.table).await.map_err(|e| e.to_string())?;
+    let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_xact_lock(hashtext($1))")
+        .bind(format!("datapi_export:{}", name))
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !locked {
+        return Ok(None);
+    }
+    // The header row is written even when no rows match
+    let columns: Vec<String> = (&mut *tx)
+        .describe(&sql)
+        .await
+        .map_err(|e| e.to_string())?
+        .columns()
+        .iter()
+        .map(|c| c.name().to_string())
+        .collect();
+
+    let mut body = Vec::new();
+    let mut csv = csv::Writer::from_writer(Vec::new());
+    if export.format == "csv" {
+        csv.write_record(&columns).map_err(|e| e.to_string())?;
+    }
+    let mut rows = 0u64;
+    {
+        let mut stream = bind_filters(sqlx::query(&sql), &filters).fetch(&mut *tx);
+        while let Some(row) = stream.try_next().await.map_err(|e| e.to_string())? {
+            let numeric_strings = config.numeric_strings || export.format == "csv";
+            let mut obj = row_to_json(&row, ValueFormat { numeric_strings, ..config.value_format() });
+            protect_columns(config, &export.table, &mut obj);
+            match export.format.as_str() {
+                "csv" => {
+                    let fields = columns.iter().map(|c| csv_field(obj.get(c).unwrap_or(&serde_json::Value::Null)));
+                    csv.write_record(fields).map_err(|e| e.to_string())?;
+                }
+                _ => {
+                    body.extend_from_slice(if rows == 0 || export.format == "ndjson" { b"" } else { b"," });
+                    serde_json::to_writer(&mut body, &obj).map_err(|e| e.to_string())?;
+                    if export.format == "ndjson" {
+                        body.push(b'\n');
+                    }
+                }
+            }
+            rows += 1;
+        }
+    }
+    let (body, content_type) = match export.format.as_str() {
+        "csv" => (csv.into_inner().map_err(|e| e.to_string())?, "text/csv; charset=utf-8"),
+        "ndjson" => (body, "application/x-ndjson"),
+        _ => ([b"[".as_slice(), &body, b"]"].concat(), "application/json"),
+    };
+    let bytes = body.len() as u64;
+
+    let file_name = format!("{}-{}.{}", name, started_at.format("%Y%m%dT%H%M%SZ"), export.format);
+    let location = if is_http_url(&export.destination) {
+        let response = client
+            .put(&export.destination)
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .header(reqwest::header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name))
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", export.destination, response.status()));
+        }
+        export.destination.clone()
+    } else {
+        // Written under a temporary name first, so nothing picks up half a file
+        let path = std::path::Path::new(&export.destination).join(&file_name);
+        let partial = path.with_extension(format!("{}.partial", export.format));
+        tokio::fs::create_dir_all(&export.destination).await.map_err(|e| e.to_string())?;
+        tokio::fs::write(&partial, &body).await.map_err(|e| e.to_string())?;
+        tokio::fs::rename(&partial, &path).await.map_err(|e| e.to_string())?;
+        path.display().to_string()
+    };
+    tx.commit().await.map_err(|e| e.to_string())?;
+    Ok(Some((rows, bytes, location)))
+}
+
+pub(crate) async fn alert_export_failure(
+    client: &reqwest::Client,
+    url: &str,
+    name: &str,
+    export: &ExportConfig,
+    started_at: chrono::DateTime<chrono::Utc>,
+    error: &str,
+) {
+    let alert = serde_json::json!({
+        "export": name,
+        "table": export.table,
+        "started_at": started_at,
+        "error": error,
+    });
+    if let Err(e) = client.post(url).json(&alert).send().await.and_then(|r| r.error_for_status()) {
+        log::warn!("Failed to send the alert for export {} to {}: {}", name, url, e);
+    }
+}
+
+pub(crate) async fn export_status(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    stats: web::Data<ExportStats>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    let exports = stats.exports.lock().unwrap();
+    HttpResponse::Ok().json(&*exports)
+}
+
+// A row change from the datapi_changes trigger
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ChangeEvent {
+    pub(crate) table: String,
+    pub(crate) operation: String,
+    pub(crate) row: serde_json::Map<String, serde_json::Value>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) truncated: bool,
+}
+
+// Fans the notifications of the changes channel out to the open streams
+pub(crate) struct ChangeFeed {
+    pub(crate) sender: tokio::sync::broadcast::Sender<ChangeEvent>,
+}
+
+// Events a slow stream may fall behind by before it skips ahead
+pub(crate) const CHANGE_FEED_CAPACITY: usize = 1024;
+
+pub(crate) const CHANGE_FEED_KEEPALIVE: Duration = Duration::from_secs(15);
+
+impl ChangeFeed {
+    pub(crate) fn new() -> Self {
+        ChangeFeed { sender: tokio::sync::broadcast::channel(CHANGE_FEED_CAPACITY).0 }
+    }
+}
+
+// Holds one LISTEN connection for all streams. The listener reconnects by
+// itself; notifications sent while it is disconnected are lost.
+pub(crate) async fn listen_changes(pool: PgPool, feed: web::Data<ChangeFeed>) {
+    let mut listener = loop {
+        match sqlx::postgres::PgListener::connect_with(&pool).await {
+            Ok(mut listener) => match listener.listen(CHANGES_CHANNEL).await {
+                Ok(()) => break listener,
+                Err(e) => log::warn!("LISTEN {} failed: {}", CHANGES_CHANNEL, e),
+            },
+            Err(e) => log::warn!("Change feed connection failed: {}", e),
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    };
+    loop {
+        match listener.recv().await {
+            Ok(notification) => match serde_json::from_str::<ChangeEvent>(notification.payload()) {
+                // Sending fails only while no stream is open
+                Ok(event) => {
+                    let _ = feed.sender.send(event);
+                }
+                Err(e) => log::warn!("Invalid change notification: {}", e),
+            },
+            // The pool closes on shutdown
+            Err(_) if pool.is_closed() => return,
+            Err(e) => {
+                log::warn!("Change feed connection lost: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+// Streams the table's changes as server-sent events named after the
+// operation, with the row as data. Rows outside the caller's claim filters
+// are left out; row-level security is not applied.
+pub(crate) async fn table_changes(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    feed: Option<web::Data<ChangeFeed>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let table = match sanitize_table_name(&path.into_inner()) {
+        Ok(t) => t.to_lowercase(),
+        Err(e) => return bad_request(&req, e),
+    };
+    let (Some(feed), Some(_)) = (feed, config.table(&table).and_then(|t| t.changes.as_ref())) else {
+        return error_response(
+            &req,
+            StatusCode::NOT_FOUND,
+            Message::new("changes_not_configured").arg("table", &table),
+        );
+    };
+    let claims = match claim_values(&req, &config, &table) {
+        Ok(claims) => claims,
+        Err(e) => return error_response(&req, StatusCode::FORBIDDEN, e),
+    };
+    
+    let receiver = feed.sender.subscribe();
+    let stream = futures_util::stream::unfold(receiver, move |mut receiver| {
+        let (config, table, claims) = (config.clone(), table.clone(), claims.clone());
+        async move {
+            loop {
+                let event = match tokio::time::timeout(CHANGE_FEED_KEEPALIVE, receiver.recv()).await {
+                    Err(_) => return Some((Ok::<_, actix_web::Error>(web::Bytes::from_static(b": keepalive\n\n")), receiver)),
+                    Ok(Err(tokio::sync::broadcast::error::RecvError::Lagged(missed))) => {
+                        let data = format!("event: lagged\ndata: {{\"missed\":{}}}\n\n", missed);
+                        return Some((Ok(web::Bytes::from(data)), receiver));
+                    }
+                    Ok(Err(tokio::sync::broadcast::error::RecvError::Closed)) => return None,
+                    Ok(Ok(event)) => event,
+                };
+                if RowCounts::key(&event.table) != table {
+                    continue;
+                }
+                let visible = claims
+                    .iter()
+                    .all(|(column, value)| event.row.get(column).and_then(json_to_text).as_deref() == Some(value.as_str()));
+                if !visible {
+                    continue;
+                }
+                let mut event = event;
+                protect_columns(&config, &table, &mut event.row);
+                let data = serde_json::to_string(&event).unwrap_or_default();
+                let frame = format!("event: {}\ndata: {}\n\n", event.operation, data);
+                return Some((Ok(web::Bytes::from(frame)), receiver));
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+        // Compression would hold events back until a buffer fills
+        .insert_header((actix_web::http::header::CONTENT_ENCODING, "identity"))
+        .streaming(stream)
+}
+
+// Deliveries per webhook, served at /_admin/webhooks
+#[derive(Default)]
+pub(crate) struct WebhookStats {
+    pub(crate) webhooks: Mutex<BTreeMap<String, WebhookDeliveries>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct WebhookDeliveries {
+    pub(crate) table: String,
+    pub(crate) url: String,
+    pub(crate) delivered: u64,
+    // Changes given up on after max_attempts or a 4xx response
+    pub(crate) failed: u64,
+    // Changes that went by while deliveries were behind
+    pub(crate) missed: u64,
+    pub(crate) last_delivered_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub(crate) last_error: Option<String>,
+    pub(crate) last_failed_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl WebhookStats {
+    pub(crate) fn update(&self, table: &str, webhook: &WebhookConfig, f: impl FnOnce(&mut WebhookDeliveries)) {
+        let mut webhooks = self.webhooks.lock().unwrap();
+        let deliveries = webhooks.entry(format!("{} {}", table, webhook.url)).or_insert_with(|| WebhookDeliveries {
+            table: table.to_string(),
+            url: webhook.url.clone(),
+            ..WebhookDeliveries::default()
+        });
+        f(deliveries);
+    }
+}
+
+pub(crate) const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) const WEBHOOK_MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// POSTs one change. Errors say whether the delivery is worth retrying:
+// network errors, 429 and 5xx are, other responses are not.
+pub(crate) async fn send_webhook(client: &reqwest::Client, webhook: &WebhookConfig, operation: &str, body: &[u8]) -> Result<(), (String, bool)> {
+    let mut request = client
+        .post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header("X-Datapi-Event", operation);
+    if let Some(secret) = &webhook.secret {
+        use hmac::Mac;
+        let mut mac = <hmac::Hmac<sha2::Sha256> as Mac>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(body);
+        let signature: String = mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+        request = request.header("X-Datapi-Signature", format!("sha256={}", signature));
+    }
+    match request.body(body.to_vec()).send().await {
+        Ok(resp) if resp.status().is_success() => Ok(()),
+        Ok(resp) => {
+            let status = resp.status();
+            let retry = status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+            Err((format!("HTTP {}", status), retry))
+        }
+        Err(e) => Err((e.to_string(), true)),
+    }
+}
+
+// Delivers the table's changes to one webhook in order. A failing delivery
+// is retried with exponential backoff, holding back the changes after it.
+pub(crate) async fn deliver_webhook(
+    table: String,
+    webhook: WebhookConfig,
+    mut receiver: tokio::sync::broadcast::Receiver<ChangeEvent>,
+    config: web::Data<Config>,
+    stats: web::Data<WebhookStats>,
+) {
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()
+        .expect("Failed to build the webhook client");
+    let key = RowCounts::key(&table);
+    loop {
+        let mut event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                log::warn!("Webhook {} for {} fell behind and missed {} changes", webhook.url, table, missed);
+                stats.update(&table, &webhook, |d| d.missed += missed);
+                continue;
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => return,
+        };
+        if RowCounts::key(&event.table) != key {
+            continue;
+        }
+        if !webhook.operations.is_empty() && !webhook.operations.contains(&event.operation) {
+            continue;
+        }
+        protect_columns(&config, &table, &mut event.row);
+        let body = serde_json::to_vec(&event).unwrap_or_default();
+        let mut attempt = 1;
+        loop {
+            match send_webhook(&client, &webhook, &event.operation, &body).await {
+                Ok(()) => {
+                    stats.update(&table, &webhook, |d| {
+                        d.delivered += 1;
+                        d.last_delivered_at = Some(chrono::Utc::now());
+                    });
+                    break;
+                }
+                Err((error, true)) if attempt < webhook.max_attempts => {
+                    let backoff = Duration::from_secs(1 << (attempt - 1).min(6)).min(WEBHOOK_MAX_BACKOFF);
+                    log::info!("Webhook {} failed ({}), retrying in {}s", webhook.url, error, backoff.as_secs());
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                Err((error, _)) => {
+                    log::warn!("Webhook {} for {} failed after {} attempts: {}", webhook.url, table, attempt, error);
+                    stats.update(&table, &webhook, |d| {
+                        d.failed += 1;
+                        d.last_error = Some(error);
+                        d.last_failed_at = Some(chrono::Utc::now());
+                    });
+                    break;
+                }
+            }
+        }
+    }
+}
+
+pub(crate) async fn webhook_status(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    stats: web::Data<WebhookStats>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    let webhooks = stats.webhooks.lock().unwrap();
+    HttpResponse::Ok().json(webhooks.values().collect::<Vec<_>>())
+}
+
+pub(crate) enum AuditSink {
+    Table { pool: PgPool, table: String },
+    File(tokio::fs::File),
+}
+
+impl AuditSink {
+    pub(crate) async fn write(&mut self, records: &[AuditRecord]) -> std::io::Result<()> {
+        match self {
+            AuditSink::Table { pool, table } => {
+                let batch = serde_json::to_value(records).map_err(std::io::Error::other)?;
+                let insert = format!(
+                    "INSERT INTO {} (at, request_id, principal, method, path, table_name, filters, rows, duration_ms, status) \
+                     SELECT at, request_id, principal, method, path, \"table\", filters, rows, duration_ms, status \
+                     FROM jsonb_to_recordset($1) AS r(at timestamptz, request_id text, principal text, method text, \
+                     path text, \"table\" text, filters jsonb, rows bigint, duration_ms float8, status int)",
+                    table
+                );
+                let result: Result<(), sqlx::Error> = async {
+                    let mut tx = pool.begin().await?;
+                    // DATAPI_READ_ONLY only makes read-only the default
+                    sqlx::query("SET TRANSACTION READ WRITE").execute(&mut *tx).await?;
+                    sqlx::query(&insert).bind(batch).execute(&mut *tx).await?;
+                    tx.commit().await
+                }
+                .await;
+                result.map_err(std::io::Error::other)
+            }
+            AuditSink::File(file) => {
+                use tokio::io::AsyncWriteExt;
+                let mut lines = Vec::new();
+                for record in records {
+                    serde_json::to_writer(&mut lines, record).map_err(std::io::Error::other)?;
+                    lines.push(b'\n');
+                }
+                file.write_all(&lines).await?;
+                file.flush().await
+            }
+        }
+    }
+}
+
+// Writes the queued audit records once batch_size have gathered and at least
+// every interval. Records of a failed write are retried with the next batch;
+// past the queue's capacity the oldest are dropped and counted.
+pub(crate) async fn write_audit_log(
+    mut receiver: tokio::sync::mpsc::Receiver<AuditMessage>,
+    mut sink: AuditSink,
+    audit: web::Data<AuditLog>,
+    batch_size: usize,
+    interval: Duration,
+) {
+    let mut pending: Vec<AuditRecord> = Vec::new();
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        let flushed = tokio::select! {
+            message = receiver.recv() => match message {
+                Some(AuditMessage::Record(record)) => {
+                    pending.push(record);
+                    if pending.len() < batch_size {
+                        continue;
+                    }
+                    None
+                }
+                Some(AuditMessage::Flush(done)) => Some(done),
+                None => return,
+            },
+            _ = ticker.tick() => None,
+        };
+        if !pending.is_empty() {
+            match sink.write(&pending).await {
+                Ok(()) => pending.clear(),
+                Err(e) => {
+                    log::error!("Writing {} audit records failed: {}", pending.len(), e);
+                    let excess = pending.len().saturating_sub(receiver.max_capacity());
+                    pending.drain(..excess);
+                    audit.dropped.fetch_add(excess as u64, Ordering::Relaxed);
+                }
+            }
+        }
+        let dropped = audit.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            log::warn!("Dropped {} audit records, the audit log fell behind", dropped);
+        }
+        if let Some(done) = flushed {
+            let _ = done.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use actix_web::test::TestRequest;
+    use actix_web::body::MessageBody;
+
+    #[actix_web::test]
+    async fn retention_runs_are_reported_to_admins() {
+        let config = config_with(serde_json::json!({ "events": { "retention": { "column": "created_at", "max_age": "90 days" } } }));
+        let retention = config.table("events").unwrap().retention.as_ref().unwrap();
+        assert_eq!((retention.batch_size, retention.interval_secs, retention.soft_delete.is_none()), (1000, 3600, true));
+
+        let stats = web::Data::new(RetentionStats::default());
+        stats.tables.lock().unwrap().insert("events".to_string(), RetentionRun { last_rows: 5, total_rows: 12, ..Default::default() });
+        let status = |token: Option<&str>| {
+            let req = admin_request(token);
+            let resp = retention_status(req.clone(), web::Data::new(test_config()), stats.clone());
+            async move { resp.await.respond_to(&req) }
+        };
+        assert_eq!(status(None).await.status(), StatusCode::UNAUTHORIZED);
+        let body = json_body(status(Some("secret")).await).await;
+        assert_eq!((body["events"]["last_rows"].clone(), body["events"]["total_rows"].clone()), (serde_json::json!(5), serde_json::json!(12)));
+    }
+
+    #[actix_web::test]
+    async fn changes_stream_the_table_rows_as_events() {
+        let config = web::Data::new(config_with(serde_json::json!({ "loans": { "changes": {} } })));
+        let req = TestRequest::get().uri("/loans/_changes").to_http_request();
+        let feed = web::Data::new(ChangeFeed::new());
+        let changes = |table: &str, feed: Option<web::Data<ChangeFeed>>| {
+            table_changes(req.clone(), config.clone(), feed, web::Path::from(table.to_string()))
+        };
+        let resp = changes("customers", Some(feed.clone())).await.respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(changes("loans", None).await.respond_to(&req).status(), StatusCode::NOT_FOUND);
+
+        let resp = changes("loans", Some(feed.clone())).await.respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("Content-Type").unwrap(), "text/event-stream");
+        let event = |table: &str, operation: &str| ChangeEvent {
+            table: table.to_string(),
+            operation: operation.to_string(),
+            row: serde_json::json!({ "id": 1 }).as_object().unwrap().clone(),
+            truncated: false,
+        };
+        feed.sender.send(event("customers", "insert")).unwrap();
+        feed.sender.send(event("Loans", "delete")).unwrap();
+        let mut body = Box::pin(resp.into_body());
+        let frame = std::future::poll_fn(|cx| body.as_mut().poll_next(cx)).await.unwrap().ok().unwrap();
+        assert_eq!(
+            frame,
+            "event: delete\ndata: {\"table\":\"Loans\",\"operation\":\"delete\",\"row\":{\"id\":1}}\n\n"
+        );
+    }
+
+    // Answers one HTTP request on a local port with the status, returning the
+    // URL and the request as it came in
+    async fn answer_once(status: u16) -> (String, tokio::task::JoinHandle<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 4096];
+            loop {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|line| line.to_lowercase().strip_prefix("content-length: ").map(|n| n.parse().unwrap()))
+                        .unwrap_or(0);
+                    if read == 0 || body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            let response = format!("HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        });
+        (url, handle)
+    }
+
+    #[actix_web::test]
+    async fn webhooks_are_signed_and_retried_only_when_worth_it() {
+        let webhook = |url: &str, secret: Option<&str>| WebhookConfig {
+            url: url.to_string(),
+            operations: Vec::new(),
+            secret: secret.map(str::to_string),
+            max_attempts: 1,
+        };
+        let client = reqwest::Client::new();
+        let (url, request) = answer_once(200).await;
+        send_webhook(&client, &webhook(&url, Some("key")), "insert", br#"{"id":1}"#).await.unwrap();
+        let request = request.await.unwrap().to_lowercase();
+        assert!(request.starts_with("post /hook "));
+        assert!(request.contains("x-datapi-event: insert\r\n"));
+        use hmac::Mac;
+        let mut mac = <hmac::Hmac<sha2::Sha256> as Mac>::new_from_slice(b"key").unwrap();
+        mac.update(br#"{"id":1}"#);
+        let signature: String = mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect();
+        assert!(request.contains(&format!("x-datapi-signature: sha256={}\r\n", signature)));
+
+        for (status, retry) in [(503, true), (429, true), (404, false)] {
+            let (url, _) = answer_once(status).await;
+            let (error, retried) = send_webhook(&client, &webhook(&url, None), "update", b"{}").await.unwrap_err();
+            assert_eq!((error, retried), (format!("HTTP {}", StatusCode::from_u16(status).unwrap()), retry));
+        }
+        assert!(send_webhook(&client, &webhook("http://127.0.0.1:1/hook", None), "delete", b"{}").await.unwrap_err().1);
+
+        // Deliveries follow the table and the operations, and count failures
+        let feed = ChangeFeed::new();
+        let stats = web::Data::new(WebhookStats::default());
+        let (url, _) = answer_once(404).await;
+        let hook = WebhookConfig { operations: vec!["delete".to_string()], ..webhook(&url, None) };
+        let delivery = tokio::spawn(deliver_webhook(
+            "loans".to_string(),
+            hook,
+            feed.sender.subscribe(),
+            web::Data::new(test_config()),
+            stats.clone(),
+        ));
+        let event = |table: &str, operation: &str| ChangeEvent {
+            table: table.to_string(),
+            operation: operation.to_string(),
+            row: serde_json::Map::new(),
+            truncated: false,
+        };
+        for (table, operation) in [("customers", "delete"), ("loans", "insert"), ("loans", "delete")] {
+            feed.sender.send(event(table, operation)).unwrap();
+        }
+        drop(feed);
+        delivery.await.unwrap();
+        let webhooks = stats.webhooks.lock().unwrap();
+        let deliveries = webhooks.values().next().unwrap();
+        assert_eq!((deliveries.delivered, deliveries.failed), (0, 1));
+        assert_eq!(deliveries.last_error.as_deref(), Some("HTTP 404 Not Found"));
+    }
+
+    #[actix_web::test]
+    async fn audit_records_are_written_in_batches() {
+        let path = std::env::temp_dir().join(format!("datapi-audit-{}.jsonl", std::process::id()));
+        let file = tokio::fs::File::create(&path).await.unwrap();
+        let (sender, receiver) = tokio::sync::mpsc::channel(10);
+        let audit = web::Data::new(AuditLog { sender, dropped: Default::default() });
+        let writer = tokio::spawn(write_audit_log(receiver, AuditSink::File(file), audit.clone(), 2, Duration::from_secs(3600)));
+        let record = |status: u16| AuditRecord {
+            at: chrono::Utc::now(),
+            request_id: format!("r{}", status),
+            principal: Some("admin".to_string()),
+            method: "GET".to_string(),
+            path: "/loans?amount=gt.5".to_string(),
+            table: Some("loans".to_string()),
+            filters: vec!["amount > 5".to_string()],
+            rows: Some(3),
+            duration_ms: 1.5,
+            status,
+        };
+        audit.record(record(200));
+        audit.record(record(404));
+        audit.record(record(500));
+        audit.flush().await;
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = written.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1]["status"], 404);
+        assert_eq!(lines[0]["filters"], serde_json::json!(["amount > 5"]));
+        // The writer holds the log, and with it the sender, until shutdown
+        writer.abort();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,632 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use crate::access::{fetch_table_names, Exposure};
+use crate::config::Config;
+use crate::middleware::{JournalRecord, REQUEST_ID_HEADER};
+use crate::openapi::openapi_document;
+use crate::query::{fetch_column_types, tag_query};
+use crate::serialization::{protect_columns, row_to_json};
+use crate::writes::quote_identifier;
+
+// Contract snapshots: `datapi snapshot` records the API's tables, column
+// types, an example response and the OpenAPI document as `v<N>.json` in a
+// directory, and reports what changed since the previous version.
+
+pub(crate) const DEFAULT_SNAPSHOT_DIR: &str = "snapshots";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub(crate) version: u32,
+    pub(crate) created_at: String,
+    pub(crate) tables: BTreeMap<String, TableSnapshot>,
+    #[serde(default)]
+    pub(crate) openapi: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct TableSnapshot {
+    pub(crate) columns: BTreeMap<String, ColumnSnapshot>,
+    // First row as the API renders it; informational, not compared
+    pub(crate) example: Option<serde_json::Value>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ColumnSnapshot {
+    #[serde(rename = "type")]
+    pub(crate) kind: String,
+    pub(crate) nullable: bool,
+}
+
+// A difference between snapshots. Breaking changes are those a client
+// relying on the previous contract can trip over.
+pub(crate) struct SnapshotChange {
+    pub(crate) breaking: bool,
+    pub(crate) description: String,
+}
+
+impl Snapshot {
+    // The tables the API serves, restricted to DATAPI_EXPOSE_TABLES
+    pub(crate) async fn capture(pool: &PgPool, config: &Config, version: u32) -> Result<Self, sqlx::Error> {
+        let exposure = Exposure::from_env();
+        let mut tables = BTreeMap::new();
+        for name in fetch_table_names(pool).await? {
+            if exposure.as_ref().is_some_and(|e| !e.matches(&name.to_lowercase())) {
+                continue;
+            }
+            let table = quote_identifier(&name);
+            let columns = fetch_column_types(pool, &table)
+                .await?
+                .into_iter()
+                .map(|(column, (kind, not_null))| (column, ColumnSnapshot { kind, nullable: !not_null }))
+                .collect();
+            let row = sqlx::query(&tag_query(&table, &format!("SELECT * FROM {} LIMIT 1", table)))
+                .fetch_optional(pool)
+                .await?;
+            let example = row.map(|row| {
+                let mut obj = row_to_json(&row, config.value_format());
+                protect_columns(config, &name, &mut obj);
+                serde_json::Value::Object(obj)
+            });
+            tables.insert(name, TableSnapshot { columns, example });
+        }
+        let names: Vec<String> = tables.keys().cloned().collect();
+        let openapi = openapi_document(pool, config, &names).await?;
+        Ok(Snapshot { version, created_at: chrono::Utc::now().to_rfc3339(), tables, openapi })
+    }
+    
+    // `METHOD /path` of every operation in the OpenAPI document
+    pub(crate) fn operations(&self) -> Vec<String> {
+        let Some(paths) = self.openapi.get("paths").and_then(|p| p.as_object()) else {
+            return Vec::new();
+        };
+        paths
+            .iter()
+            .flat_map(|(path, item)| {
+                let methods = item.as_object().map(|m| m.keys().cloned().collect()).unwrap_or_else(Vec::new);
+                methods.into_iter().map(move |method| format!("{} {}", method.to_uppercase(), path))
+            })
+            .collect()
+    }
+    
+    pub(crate) fn diff(&self, current: &Snapshot) -> Vec<SnapshotChange> {
+        let change = |breaking, description: String| SnapshotChange { breaking, description };
+        let mut changes = Vec::new();
+        for (name, table) in &self.tables {
+            let Some(now) = current.tables.get(name) else {
+                changes.push(change(true, format!("table {} was removed", name)));
+                continue;
+            };
+            for (column, before) in &table.columns {
+                match now.columns.get(column) {
+                    None => changes.push(change(true, format!("column {}.{} was removed", name, column))),
+                    Some(after) if after.kind != before.kind => changes.push(change(
+                        true,
+                        format!("column {}.{} changed type from {} to {}", name, column, before.kind, after.kind),
+                    )),
+                    Some(after) if after.nullable && !before.nullable => {
+                        changes.push(change(true, format!("column {}.{} may now be null", name, column)))
+                    }
+                    Some(after) if !after.nullable && before.nullable => {
+                        changes.push(change(false, format!("column {}.{} is no longer nullable", name, column)))
+                    }
+                    Some(_) => {}
+                }
+            }
+            for column in now.columns.keys().filter(|c| !table.columns.contains_key(*c)) {
+                changes.push(change(false, format!("column {}.{} was added", name, column)));
+            }
+        }
+        for name in current.tables.keys().filter(|t| !self.tables.contains_key(*t)) {
+            changes.push(change(false, format!("table {} was added", name)));
+        }
+        
+        // Operations of tables that still exist, e.g. writes being disabled;
+        // older snapshots without a document are skipped
+        let table_of = |operation: &str| {
+            let path = operation.split_once(' ').map(|(_, path)| path).unwrap_or_default();
+            path.trim_start_matches('/').split('/').next().unwrap_or_default().to_string()
+        };
+        if !self.openapi.is_null() {
+            let (before, after) = (self.operations(), current.operations());
+            for operation in before.iter().filter(|o| !after.contains(o)) {
+                if !self.tables.contains_key(&table_of(operation)) || current.tables.contains_key(&table_of(operation)) {
+                    changes.push(change(true, format!("operation {} was removed", operation)));
+                }
+            }
+            for operation in after.iter().filter(|o| !before.contains(o)) {
+                if !current.tables.contains_key(&table_of(operation)) || self.tables.contains_key(&table_of(operation)) {
+                    changes.push(change(false, format!("operation {} was added", operation)));
+                }
+            }
+        }
+        changes
+    }
+}
+
+// Sends the journaled writes, in order, to a datapi serving the restored
+// database. Writes that failed the first time are skipped; ones without an
+// outcome were cut short and are replayed. Stops at the first write that
+// fails, so the replay can be resumed with --after once it is dealt with.
+pub(crate) async fn run_replay(path: &str, target: &str, after: Option<&str>) -> std::io::Result<()> {
+    let text = std::fs::read_to_string(path)?;
+    let mut records = Vec::new();
+    for (i, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let record: JournalRecord = serde_json::from_str(line)
+            .map_err(|e| std::io::Error::other(format!("{} line {}: {}", path, i + 1, e)))?;
+        records.push(record);
+    }
+    let outcomes: HashMap<&str, u16> = records
+        .iter()
+        .filter_map(|record| match record {
+            JournalRecord::Outcome { request_id, status } => Some((request_id.as_str(), *status)),
+            _ => None,
+        })
+        .collect();
+    if after.is_some_and(|after| !records.iter().any(|r| matches!(r, JournalRecord::Request { request_id, .. } if request_id == after))) {
+        return Err(std::io::Error::other(format!("{} has no request {}", path, after.unwrap_or_default())));
+    }
+    
+    let client = reqwest::Client::new();
+    let admin_token = env::var("DATAPI_ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+    let api_key = env::var("DATAPI_REPLAY_API_KEY").ok().filter(|k| !k.is_empty());
+    let (mut replayed, mut skipped) = (0, 0);
+    let mut last = after;
+    let mut started = after.is_none();
+    for record in &records {
+        let JournalRecord::Request { request_id, method, path: request_path, database, content_type, body, body_base64, .. } = record else {
+            continue;
+        };
+        if !started {
+            started = request_id == after.unwrap_or_default();
+            continue;
+        }
+        match outcomes.get(request_id.as_str()) {
+            Some(status) if !(200..300).contains(status) => {
+                skipped += 1;
+                continue;
+            }
+            Some(_) => {}
+            None => println!("{} {} {} has no outcome, replaying it", request_id, method, request_path),
+        }
+        
+        let body = match body_base64 {
+            Some(encoded) => BASE64.decode(encoded).map_err(std::io::Error::other)?,
+            None => body.clone().unwrap_or_default().into_bytes(),
+        };
+        let prefix = database.as_ref().map(|name| format!("/db/{}", name)).unwrap_or_default();
+        let method = reqwest::Method::from_bytes(method.as_bytes()).map_err(std::io::Error::other)?;
+        let mut request = client
+            .request(method, format!("{}{}{}", target.trim_end_matches('/'), prefix, request_path))
+            .header(REQUEST_ID_HEADER, request_id.as_str())
+            .body(body);
+        if let Some(content_type) = content_type {
+            request = request.header(reqwest::header::CONTENT_TYPE, content_type.as_str());
+        }
+        if let Some(token) = &admin_token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(key) = &api_key {
+            request = request.header("X-API-Key", key.as_str());
+        }
+        let response = request.send().await.map_err(std::io::Error::other)?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            println!("{} {} failed with {}: {}", request_id, request_path, status, text);
+            match last {
+                Some(last) => println!("Replayed {} writes, skipped {}; resume with --after {}", replayed, skipped, last),
+                None => println!("Replayed {} writes, skipped {}; rerun once it is fixed", replayed, skipped),
+            }
+            std::process::exit(1);
+        }
+        replayed += 1;
+        last = Some(request_id);
+    }
+    println!("Replayed {} writes, skipped {} that had failed", replayed, skipped);
+    Ok(())
+}
+
+// The highest `v<N>.json` in the directory
+pub(crate) fn latest_snapshot(dir: &std::path::Path) -> Option<(u32, std::path::PathBuf)> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let version = path.file_name()?.to_str()?.strip_prefix('v')?.strip_suffix(".json")?.parse().ok()?;
+            Some((version, path))
+        })
+        .max_by_key(|(version, _)| *version)
+}
+
+// Captures the current contract and compares it with the latest snapshot.
+// Writes it as the next version, or with `check` only reports and fails on
+// breaking changes.
+pub(crate) async fn run_snapshot(pool: &PgPool, dir: &str, check: bool) -> std::io::Result<()> {
+    let config = Config::from_env();
+    let dir = std::path::Path::new(dir);
+    let previous = match latest_snapshot(dir) {
+        Some((version, path)) => {
+            let text = std::fs::read_to_string(&path)?;
+            let snapshot: Snapshot = serde_json::from_str(&text)
+                .map_err(|e| std::io::Error::other(format!("{}: {}", path.display(), e)))?;
+            Some((version, snapshot))
+        }
+        None => None,
+    };
+    let version = previous.as_ref().map_or(1, |(version, _)| version + 1);
+    let current = Snapshot::capture(pool, &config, version).await.map_err(std::io::Error::other)?;
+    
+    let changes = match &previous {
+        Some((_, snapshot)) => snapshot.diff(&current),
+        None => Vec::new(),
+    };
+    for change in &changes {
+        println!("{} {}", if change.breaking { "BREAKING" } else { "        " }, change.description);
+    }
+    let breaking = changes.iter().filter(|c| c.breaking).count();
+    if let Some((version, _)) = &previous {
+        println!("{} changes since v{}, {} breaking", changes.len(), version, breaking);
+    }
+    
+    if check {
+        if previous.is_none() {
+            println!("No snapshot in {} to check against", dir.display());
+        }
+        if breaking > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if previous.is_some() && changes.is_empty() {
+        println!("Contract unchanged, no new snapshot written");
+        return Ok(());
+    }
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("v{}.json", version));
+    let mut text = serde_json::to_string_pretty(&current).map_err(std::io::Error::other)?;
+    text.push('\n');
+    std::fs::write(&path, text)?;
+    println!("Wrote {}", path.display());
+    Ok(())
+}
+
+// A setting of `datapi.toml` and its CLI flag, standing for an environment
+// variable. Flags win over the environment, which wins over the file.
+pub(crate) struct Setting {
+    pub(crate) key: &'static str,
+    // Secrets are only read from the file or the environment
+    pub(crate) flag: Option<&'static str>,
+    pub(crate) env: &'static str,
+    // A flag given without a value turns the setting on
+    pub(crate) switch: bool,
+}
+
+pub(crate) const fn setting(key: &'static str, flag: &'static str, env: &'static str) -> Setting {
+    Setting { key, flag: Some(flag), env, switch: false }
+}
+
+pub(crate) const fn switch(key: &'static str, flag: &'static str, env: &'static str) -> Setting {
+    Setting { key, flag: Some(flag), env, switch: true }
+}
+
+pub(crate) const fn secret(key: &'static str, env: &'static str) -> Setting {
+    Setting { key, flag: None, env, switch: false }
+}
+
+pub(crate) const SETTINGS: &[Setting] = &[
+    setting("server.host", "--host", "HOST"),
+    setting("server.port", "--port", "PORT"),
+    setting("server.tls_cert", "--tls-cert", "DATAPI_TLS_CERT"),
+    setting("server.tls_key", "--tls-key", "DATAPI_TLS_KEY"),
+    setting("server.tls_redirect_port", "--tls-redirect-port", "DATAPI_TLS_REDIRECT_PORT"),
+    setting("server.socket", "--socket", "DATAPI_SOCKET"),
+    setting("server.socket_mode", "--socket-mode", "DATAPI_SOCKET_MODE"),
+    switch("server.socket_only", "--socket-only", "DATAPI_SOCKET_ONLY"),
+    setting("server.shutdown_grace_secs", "--shutdown-grace-secs", "DATAPI_SHUTDOWN_GRACE_SECS"),
+    secret("database.url", "DATABASE_URL"),
+    secret("database.replica_url", "DATAPI_REPLICA_URL"),
+    setting("database.max_connections", "--max-connections", "DATAPI_POOL_MAX_CONNECTIONS"),
+    setting("database.min_connections", "--min-connections", "DATAPI_POOL_MIN_CONNECTIONS"),
+    setting("database.acquire_timeout_ms", "--acquire-timeout-ms", "DATAPI_POOL_ACQUIRE_TIMEOUT_MS"),
+    setting("database.idle_timeout_secs", "--idle-timeout-secs", "DATAPI_POOL_IDLE_TIMEOUT_SECS"),
+    setting("database.max_lifetime_secs", "--max-lifetime-secs", "DATAPI_POOL_MAX_LIFETIME_SECS"),
+    switch("database.test_before_acquire", "--test-before-acquire", "DATAPI_POOL_TEST_BEFORE_ACQUIRE"),
+    setting("database.statement_timeout_ms", "--statement-timeout-ms", "DATAPI_STATEMENT_TIMEOUT_MS"),
+    setting("database.max_statement_timeout_ms", "--max-statement-timeout-ms", "DATAPI_MAX_STATEMENT_TIMEOUT_MS"),
+    setting("database.max_isolation", "--max-isolation", "DATAPI_MAX_ISOLATION"),
+    setting("pagination.default_page_size", "--default-page-size", "DATAPI_DEFAULT_PAGE_SIZE"),
+    setting("pagination.max_page_size", "--max-page-size", "DATAPI_MAX_PAGE_SIZE"),
+    setting("pagination.max_rows", "--max-rows", "DATAPI_MAX_ROWS"),
+    setting("pagination.page_byte_budget", "--page-byte-budget", "DATAPI_PAGE_BYTE_BUDGET"),
+    setting("pagination.count_failure_skip_secs", "--count-failure-skip-secs", "DATAPI_COUNT_FAILURE_SKIP_SECS"),
+    setting("tables.config", "--tables-config", "DATAPI_CONFIG"),
+    setting("tables.expose", "--expose-tables", "DATAPI_EXPOSE_TABLES"),
+    setting("tables.schemas", "--schemas", "DATAPI_SCHEMAS"),
+    setting("tables.queries_table", "--queries-table", "DATAPI_QUERIES_TABLE"),
+    switch("tables.enable_writes", "--enable-writes", "DATAPI_ENABLE_WRITES"),
+    switch("tables.read_only", "--read-only", "DATAPI_READ_ONLY"),
+    switch("tables.enable_sql", "--enable-sql", "DATAPI_ENABLE_SQL"),
+    secret("auth.admin_token", "DATAPI_ADMIN_TOKEN"),
+    secret("auth.api_keys", "DATAPI_API_KEYS"),
+    setting("auth.api_keys_table", "--api-keys-table", "DATAPI_API_KEYS_TABLE"),
+    secret("auth.jwt_secret", "DATAPI_JWT_SECRET"),
+    setting("auth.jwt_public_key", "--jwt-public-key", "DATAPI_JWT_PUBLIC_KEY"),
+    setting("auth.jwt_jwks_url", "--jwt-jwks-url", "DATAPI_JWT_JWKS_URL"),
+    setting("auth.jwt_issuer", "--jwt-issuer", "DATAPI_JWT_ISSUER"),
+    setting("auth.jwt_audience", "--jwt-audience", "DATAPI_JWT_AUDIENCE"),
+    switch("format.compression", "--compression", "DATAPI_COMPRESSION"),
+    setting("format.compression_min_bytes", "--compression-min-bytes", "DATAPI_COMPRESSION_MIN_BYTES"),
+    switch("format.server_timing", "--server-timing", "DATAPI_SERVER_TIMING"),
+    switch("format.etags", "--etags", "DATAPI_ETAGS"),
+    switch("format.numeric_strings", "--numeric-strings", "DATAPI_NUMERIC_STRINGS"),
+    setting("format.binary_max_bytes", "--binary-max-bytes", "DATAPI_BINARY_MAX_BYTES"),
+    setting("format.log_format", "--log-format", "DATAPI_LOG_FORMAT"),
+    setting("format.collations", "--collations", "DATAPI_COLLATIONS"),
+];
+
+// Read from the working directory unless --config names another file
+pub(crate) const DEFAULT_SETTINGS_FILE: &str = "datapi.toml";
+
+// Takes `--config` and the settings flags, in `--flag value` or
+// `--flag=value` form, out of the arguments. Returns the remaining
+// arguments, the settings file and the flags' values by variable.
+#[allow(clippy::type_complexity)]
+pub(crate) fn take_settings(args: Vec<String>) -> Result<(Vec<String>, Option<String>, Vec<(&'static str, String)>), String> {
+    let mut rest = Vec::new();
+    let mut file = None;
+    let mut values = Vec::new();
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None),
+        };
+        if name == "--config" {
+            file = Some(inline.or_else(|| args.next()).ok_or("--config needs a file")?);
+            continue;
+        }
+        let Some(setting) = SETTINGS.iter().find(|s| s.flag == Some(name.as_str())) else {
+            rest.push(arg);
+            continue;
+        };
+        let value = match inline {
+            Some(value) => value,
+            None if setting.switch && !args.peek().is_some_and(|next| next == "true" || next == "false") => {
+                "true".to_string()
+            }
+            None => args.next().ok_or_else(|| format!("{} needs a value", name))?,
+        };
+        values.push((setting.env, value));
+    }
+    Ok((rest, file, values))
+}
+
+// The settings of a TOML file by variable. Arrays are joined with commas,
+// e.g. `expose = ["loans", "customers"]`.
+pub(crate) fn load_settings(path: &str) -> Vec<(&'static str, String)> {
+    let text = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Failed to read settings file {}: {}", path, e));
+    let document: toml::Table = text
+        .parse()
+        .unwrap_or_else(|e| panic!("Invalid settings file {}: {}", path, e));
+    let scalar = |key: &str, value: &toml::Value| match value {
+        toml::Value::String(s) => s.clone(),
+        toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => value.to_string(),
+        _ => panic!("Setting {} in {} must be a string, number or boolean", key, path),
+    };
+    let mut values = Vec::new();
+    for (section, table) in &document {
+        let Some(table) = table.as_table() else {
+            panic!("Setting {} in {} must be under a section such as [server]", section, path);
+        };
+        for (name, value) in table {
+            let key = format!("{}.{}", section, name);
+            let setting = SETTINGS
+                .iter()
+                .find(|s| s.key == key)
+                .unwrap_or_else(|| panic!("Unknown setting {} in {}", key, path));
+            let value = match value {
+                toml::Value::Array(items) => items.iter().map(|item| scalar(&key, item)).collect::<Vec<_>>().join(","),
+                value => scalar(&key, value),
+            };
+            values.push((setting.env, value));
+        }
+    }
+    values
+}
+
+pub(crate) enum Command {
+    // `datapi` or `datapi serve [--mock schema.json]`
+    Serve { mock: Option<String> },
+    // `datapi snapshot [--dir snapshots] [--check]`
+    Snapshot { dir: String, check: bool },
+    // `datapi replay journal.jsonl [--target url] [--after request_id]`
+    Replay { journal: String, target: Option<String>, after: Option<String> },
+}
+
+pub(crate) fn parse_command(args: &[String]) -> Result<Command, String> {
+    let mut args = args.iter();
+    match args.next().map(String::as_str) {
+        None => Ok(Command::Serve { mock: None }),
+        Some("serve") => {
+            let mut mock = None;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--mock" => mock = Some(args.next().ok_or("--mock needs a schema file")?.clone()),
+                    other => return Err(format!("unknown option {}", other)),
+                }
+            }
+            Ok(Command::Serve { mock })
+        }
+        Some("snapshot") => {
+            let mut dir = DEFAULT_SNAPSHOT_DIR.to_string();
+            let mut check = false;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--dir" => dir = args.next().ok_or("--dir needs a directory")?.clone(),
+                    "--check" => check = true,
+                    other => return Err(format!("unknown option {}", other)),
+                }
+            }
+            Ok(Command::Snapshot { dir, check })
+        }
+        Some("replay") => {
+            let journal = args.next().ok_or("replay needs a journal file")?.clone();
+            let (mut target, mut after) = (None, None);
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--target" => target = Some(args.next().ok_or("--target needs a URL")?.clone()),
+                    "--after" => after = Some(args.next().ok_or("--after needs a request ID")?.clone()),
+                    other => return Err(format!("unknown option {}", other)),
+                }
+            }
+            Ok(Command::Replay { journal, target, after })
+        }
+        Some(other) => Err(format!("unknown command {}", other)),
+    }
+}
+
+pub(crate) const USAGE: &str = "usage: datapi [serve [--mock schema.json]]\n       datapi snapshot [--dir snapshots] [--check]\n       datapi replay journal.jsonl [--target url] [--after request_id]\noptions: --config datapi.toml and setting flags such as --port 8080, see the README";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_parse_from_the_arguments() {
+        let args = |list: &[&str]| list.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert!(matches!(parse_command(&[]), Ok(Command::Serve { mock: None })));
+        assert!(matches!(
+            parse_command(&args(&["serve", "--mock", "m.json"])),
+            Ok(Command::Serve { mock: Some(ref path) }) if path == "m.json"
+        ));
+        assert!(parse_command(&args(&["serve", "--mock"])).is_err());
+        assert!(parse_command(&args(&["migrate"])).is_err());
+    }
+
+    fn snapshot(tables: serde_json::Value) -> Snapshot {
+        serde_json::from_value(serde_json::json!({ "version": 1, "created_at": "2024-01-01T00:00:00Z", "tables": tables }))
+            .unwrap()
+    }
+
+    #[test]
+    fn snapshot_diffs_flag_breaking_changes() {
+        let column = |kind: &str, nullable: bool| serde_json::json!({ "type": kind, "nullable": nullable });
+        let before = snapshot(serde_json::json!({
+            "loans": { "columns": {
+                "id": column("integer", false),
+                "amount": column("integer", false),
+                "note": column("text", true),
+                "due": column("date", false),
+                "rate": column("numeric", true),
+            }, "example": null },
+            "legacy": { "columns": {}, "example": null },
+        }));
+        let after = snapshot(serde_json::json!({
+            "loans": { "columns": {
+                "id": column("integer", false),
+                "amount": column("bigint", false),
+                "note": column("text", false),
+                "due": column("date", true),
+                "opened": column("date", true),
+            }, "example": null },
+            "customers": { "columns": {}, "example": null },
+        }));
+        let changes: Vec<(bool, String)> = before.diff(&after).into_iter().map(|c| (c.breaking, c.description)).collect();
+        assert_eq!(
+            changes,
+            [
+                (true, "table legacy was removed"),
+                (true, "column loans.amount changed type from integer to bigint"),
+                (true, "column loans.due may now be null"),
+                (false, "column loans.note is no longer nullable"),
+                (true, "column loans.rate was removed"),
+                (false, "column loans.opened was added"),
+                (false, "table customers was added"),
+            ]
+            .map(|(breaking, description)| (breaking, description.to_string()))
+        );
+        assert!(before.diff(&before).is_empty());
+    }
+
+    #[test]
+    fn latest_snapshot_has_the_highest_version() {
+        let dir = std::env::temp_dir().join(format!("datapi-snapshots-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["v2.json", "v10.json", "v3.json", "notes.txt", "vx.json"] {
+            std::fs::write(dir.join(name), "{}").unwrap();
+        }
+        let latest = latest_snapshot(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(latest.map(|(version, _)| version), Some(10));
+        assert!(latest_snapshot(&dir).is_none());
+
+        let args = ["snapshot", "--dir", "contracts", "--check"].map(String::from);
+        assert!(matches!(
+            parse_command(&args),
+            Ok(Command::Snapshot { ref dir, check: true }) if dir == "contracts"
+        ));
+    }
+
+    #[test]
+    fn removed_operations_of_remaining_tables_are_breaking() {
+        let tables = serde_json::json!({ "loans": { "columns": {}, "example": null } });
+        let with_paths = |paths: serde_json::Value| Snapshot { openapi: serde_json::json!({ "paths": paths }), ..snapshot(tables.clone()) };
+        let before = with_paths(serde_json::json!({ "/loans": { "get": {}, "post": {} } }));
+        let after = with_paths(serde_json::json!({ "/loans": { "get": {} }, "/health": { "get": {} } }));
+        assert_eq!(before.operations(), ["GET /loans", "POST /loans"]);
+        let changes: Vec<(bool, String)> = before.diff(&after).into_iter().map(|c| (c.breaking, c.description)).collect();
+        assert_eq!(
+            changes,
+            [(true, "operation POST /loans was removed".to_string()), (false, "operation GET /health was added".to_string())]
+        );
+        // Snapshots from before the document compare tables only
+        assert!(snapshot(tables.clone()).diff(&after).is_empty());
+    }
+
+    #[test]
+    fn settings_come_from_flags_and_the_file() {
+        let args = ["serve", "--port", "9000", "--enable-writes", "--read-only", "false", "--config=site.toml", "--max-rows=10", "--mock", "m.json"]
+            .map(String::from)
+            .to_vec();
+        let (rest, file, values) = take_settings(args).unwrap();
+        assert_eq!(rest, ["serve", "--mock", "m.json"]);
+        assert_eq!(file.as_deref(), Some("site.toml"));
+        let values: Vec<(&str, &str)> = values.iter().map(|(name, value)| (*name, value.as_str())).collect();
+        assert_eq!(
+            values,
+            [("PORT", "9000"), ("DATAPI_ENABLE_WRITES", "true"), ("DATAPI_READ_ONLY", "false"), ("DATAPI_MAX_ROWS", "10")]
+        );
+        assert_eq!(take_settings(vec!["--port".to_string()]).unwrap_err(), "--port needs a value");
+        // Secrets have no flag
+        assert_eq!(take_settings(vec!["--admin-token=x".to_string()]).unwrap().0, ["--admin-token=x"]);
+
+        let path = std::env::temp_dir().join(format!("datapi-settings-{}.toml", std::process::id()));
+        std::fs::write(&path, "[server]\nport = 8081\n\n[tables]\nexpose = [\"loans\", \"customers\"]\nenable_writes = true\n").unwrap();
+        let mut values = load_settings(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        values.sort();
+        assert_eq!(
+            values,
+            [
+                ("DATAPI_ENABLE_WRITES", "true".to_string()),
+                ("DATAPI_EXPOSE_TABLES", "loans,customers".to_string()),
+                ("PORT", "8081".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown setting server.colour")]
+    fn unknown_settings_are_refused() {
+        let path = std::env::temp_dir().join(format!("datapi-unknown-{}.toml", std::process::id()));
+        std::fs::write(&path, "[server]\ncolour = \"blue\"\n").unwrap();
+        let result = std::panic::catch_unwind(|| load_settings(path.to_str().unwrap()));
+        std::fs::remove_file(&path).unwrap();
+        std::panic::resume_unwind(result.unwrap_err());
+    }
+}
@@ -0,0 +1,2333 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::time::Duration;
+use actix_cors::Cors;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use jsonwebtoken::DecodingKey;
+use serde::{Deserialize, Serialize};
+use crate::filters::{
+    coerce_value, parse_multiple_filters, sanitize_column_name, sanitize_table_name,
+    split_sort_modifiers, Aggregate, Coercion, FilterExpr, FilterValue,
+};
+use crate::handlers::parse_time_bound;
+use crate::messages::{Message, Translations};
+use crate::query::CountMode;
+use crate::serialization::{ValueFormat, DEFAULT_BINARY_MAX_BYTES};
+use crate::writes::{json_to_text, ConflictStrategy, IsolationLevel};
+
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    // API collation name -> Postgres collation, from DATAPI_COLLATIONS
+    pub(crate) collations: HashMap<String, String>,
+    // Bearer token for the /_admin endpoints; unset disables them
+    pub(crate) admin_token: Option<String>,
+    // Consecutive connection-level failures that open the circuit breaker
+    pub(crate) circuit_failure_threshold: u32,
+    // Seconds between database probes while the circuit is open
+    pub(crate) circuit_probe_secs: u64,
+    // Requests per minute and burst size per client, from
+    // DATAPI_RATE_LIMIT_PER_MINUTE and DATAPI_RATE_LIMIT_BURST; unset or 0
+    // disables rate limiting
+    pub(crate) rate_limit_per_minute: Option<u32>,
+    pub(crate) rate_limit_burst: u32,
+    // Lifetime of materialized result tables
+    pub(crate) result_ttl_secs: u64,
+    // Per-table configuration from the DATAPI_CONFIG file, keyed by lowercase
+    // table name
+    pub(crate) tables: HashMap<String, TableConfig>,
+    pub(crate) cipher: Option<FieldCipher>,
+    pub(crate) rollups: Vec<RollupConfig>,
+    // Match table names case-insensitively and suggest similar names for
+    // unknown tables, from DATAPI_RESOLVE_TABLES
+    pub(crate) resolve_tables: bool,
+    // Legacy query parameter name -> datapi parameter, e.g. limit -> page_size
+    pub(crate) parameter_aliases: HashMap<String, String>,
+    // Most rows a read may match, from DATAPI_MAX_ROWS; unset or 0 for no
+    // limit. Tables may override it.
+    pub(crate) max_rows: Option<u64>,
+    // Response compression per Accept-Encoding, from DATAPI_COMPRESSION
+    pub(crate) compression: bool,
+    // Bodies smaller than this are sent uncompressed
+    pub(crate) compression_min_bytes: u64,
+    // Weak ETags and 304s for If-None-Match on GET, from DATAPI_ETAGS
+    pub(crate) etags: bool,
+    // Numerics as strings instead of JSON numbers, from DATAPI_NUMERIC_STRINGS
+    pub(crate) numeric_strings: bool,
+    // Larger bytea values aren't inlined, from DATAPI_BINARY_MAX_BYTES
+    pub(crate) binary_max_bytes: usize,
+    pub(crate) priority_classes: BTreeMap<String, PriorityClassConfig>,
+    // Enables the write endpoints, from DATAPI_ENABLE_WRITES
+    pub(crate) writes_enabled: bool,
+    // Enables POST /_sql for admin callers, from DATAPI_ENABLE_SQL
+    pub(crate) sql_enabled: bool,
+    // Opens every connection with default_transaction_read_only and turns
+    // off everything that writes, from DATAPI_READ_ONLY
+    pub(crate) read_only: bool,
+    // Lets updates without a filter change every row, from
+    // DATAPI_ALLOW_UNFILTERED_WRITES
+    pub(crate) allow_unfiltered_writes: bool,
+    // Filtered reads by admin callers slower than this get an index
+    // suggestion, from DATAPI_QUERY_HINT_MS; unset disables hints
+    pub(crate) query_hint_ms: Option<u64>,
+    // Reads slower than this are logged at WARN and counted, from
+    // DATAPI_SLOW_QUERY_MS; unset disables the slow query log
+    pub(crate) slow_query_ms: Option<u64>,
+    // API keys from DATAPI_API_KEYS (`key:scope,...`) and the config file
+    pub(crate) api_keys: Vec<(String, ApiScope)>,
+    // Table of further keys (`key` and `scope` columns), from
+    // DATAPI_API_KEYS_TABLE. It must be schema-qualified, in a schema off the
+    // search path, so the API itself can't serve it.
+    pub(crate) api_keys_table: Option<String>,
+    // Per-key limits on top of the table limits, from the config file
+    pub(crate) api_key_quotas: BTreeMap<String, KeyQuota>,
+    // Bearer JWT validation, see JwtConfig
+    pub(crate) jwt: Option<JwtConfig>,
+    // Public table name -> the relation it is served from
+    pub(crate) aliases: BTreeMap<String, AliasConfig>,
+    // Query templates served at /_templates/{name}, keyed by lowercase name
+    pub(crate) templates: BTreeMap<String, TemplateConfig>,
+    // Table of further templates (`name`, `sql`, `params` and `tables`
+    // columns), from DATAPI_QUERIES_TABLE. Schema-qualified and off the
+    // search path like the API keys table.
+    pub(crate) queries_table: Option<String>,
+    // Where every request is recorded, see AuditLog: a table from
+    // DATAPI_AUDIT_TABLE, schema-qualified and off the search path like the
+    // API keys table, or a JSON lines file from DATAPI_AUDIT_FILE
+    pub(crate) audit_table: Option<String>,
+    pub(crate) audit_file: Option<String>,
+    // Functions callable at POST /_rpc/{function}, lowercase and optionally
+    // schema-qualified
+    pub(crate) functions: Vec<String>,
+    // Scheduled exports by lowercase name, see ExportConfig
+    pub(crate) exports: BTreeMap<String, ExportConfig>,
+    // Shape of paged responses, see EnvelopeConfig
+    pub(crate) envelope: EnvelopeConfig,
+    // Cross-origin access for browsers, from DATAPI_CORS_ORIGINS
+    pub(crate) cors: Option<CorsConfig>,
+    // Seconds table reads are cached for, from DATAPI_CACHE_TTL_SECS; tables
+    // may override it, 0 caches only on request
+    pub(crate) cache_ttl_secs: u64,
+    // Longest TTL a request's Cache-Control max-age may opt into, from
+    // DATAPI_CACHE_MAX_AGE_SECS
+    pub(crate) cache_max_age_secs: u64,
+    // From DATAPI_CACHE_MAX_ENTRIES and DATAPI_CACHE_MAX_BODY_BYTES
+    pub(crate) cache_max_entries: usize,
+    pub(crate) cache_max_body_bytes: u64,
+    // Shared Redis cache instead of the in-process one, from
+    // DATAPI_CACHE_REDIS_URL
+    pub(crate) cache_redis_url: Option<String>,
+    // Signs share links, from DATAPI_SHARE_SECRET; unset disables them
+    pub(crate) share_secret: Option<String>,
+    // Longest lifetime of a share link, from DATAPI_SHARE_MAX_SECS
+    pub(crate) share_max_secs: u64,
+    // Default statement_timeout of every connection, from
+    // DATAPI_STATEMENT_TIMEOUT_MS; unset or 0 for none
+    pub(crate) statement_timeout_ms: Option<u64>,
+    // Cap on `?timeout_ms=`, from DATAPI_MAX_STATEMENT_TIMEOUT_MS, by default
+    // the statement timeout
+    pub(crate) max_statement_timeout_ms: Option<u64>,
+    // How bind values appear in logs, from DATAPI_LOG_BINDS
+    pub(crate) log_binds: BindLogging,
+    // Sends the phase timings of table reads as a Server-Timing header, from
+    // DATAPI_SERVER_TIMING
+    pub(crate) server_timing: bool,
+    // Exports a trace per request over OTLP, when OTEL_EXPORTER_OTLP_ENDPOINT
+    // is set
+    pub(crate) otlp_tracing: bool,
+    // Further databases served under /db/{name}/: lowercase name -> URL, from
+    // DATAPI_DB_<NAME>
+    pub(crate) databases: BTreeMap<String, String>,
+    // Schemas off the search path that `?schema=` may read tables from, from
+    // DATAPI_SCHEMAS
+    pub(crate) schemas: Vec<String>,
+    // Rows a `?sort=random` sample returns at most, from
+    // DATAPI_RANDOM_SORT_MAX_ROWS
+    pub(crate) random_sort_max_rows: usize,
+    // Connections of each database pool, from DATAPI_POOL_MAX_CONNECTIONS
+    pub(crate) pool_max_connections: u32,
+    // Connections each pool keeps open however idle, from
+    // DATAPI_POOL_MIN_CONNECTIONS
+    pub(crate) pool_min_connections: u32,
+    // How long a request waits for a free connection, from
+    // DATAPI_POOL_ACQUIRE_TIMEOUT_MS
+    pub(crate) pool_acquire_timeout: Duration,
+    // Idle connections and connections older than this are closed, from
+    // DATAPI_POOL_IDLE_TIMEOUT_SECS and DATAPI_POOL_MAX_LIFETIME_SECS; 0 keeps
+    // them
+    pub(crate) pool_idle_timeout: Option<Duration>,
+    pub(crate) pool_max_lifetime: Option<Duration>,
+    // Pings a connection before handing it out, from
+    // DATAPI_POOL_TEST_BEFORE_ACQUIRE
+    pub(crate) pool_test_before_acquire: bool,
+    // Prepared statements each connection keeps, from
+    // DATAPI_STATEMENT_CACHE_CAPACITY
+    pub(crate) statement_cache_capacity: usize,
+    // How long table metadata is cached, see SchemaCache, from
+    // DATAPI_SCHEMA_CACHE_SECS; 0 looks it up on every request
+    pub(crate) schema_cache_ttl: Duration,
+    // Rows per page without and at most with `?page_size=`, from
+    // DATAPI_DEFAULT_PAGE_SIZE and DATAPI_MAX_PAGE_SIZE
+    pub(crate) default_page_size: usize,
+    pub(crate) max_page_size: usize,
+    // Rows of a `format=xlsx` workbook, from DATAPI_XLSX_MAX_ROWS, at most
+    // what a sheet holds
+    pub(crate) xlsx_max_rows: usize,
+    // Bytes a page of JSON rows should stay under, from
+    // DATAPI_PAGE_BYTE_BUDGET; page sizes are capped by the estimated row width
+    pub(crate) page_byte_budget: Option<usize>,
+    // How long a table whose exact count failed gets estimated counts
+    // instead, from DATAPI_COUNT_FAILURE_SKIP_SECS; None keeps counting
+    pub(crate) count_failure_skip: Option<Duration>,
+    // Strictest `?isolation=` a batch may ask for, from DATAPI_MAX_ISOLATION
+    pub(crate) max_isolation: IsolationLevel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BindLogging {
+    // `?` in place of each value
+    Redact,
+    // A hash of each value, so requests with equal values can be matched up
+    Hash,
+    Plain,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CorsConfig {
+    // Allowed origins, `*` for any
+    pub(crate) origins: Vec<String>,
+    pub(crate) methods: Vec<String>,
+    // Allowed request headers, `*` for any
+    pub(crate) headers: Vec<String>,
+    pub(crate) max_age_secs: usize,
+}
+
+impl CorsConfig {
+    pub(crate) fn from_env() -> Option<Self> {
+        let list = |name: &str, default: &str| -> Vec<String> {
+            env::var(name)
+                .unwrap_or_else(|_| default.to_string())
+                .split(',')
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .collect()
+        };
+        let origins = list("DATAPI_CORS_ORIGINS", "");
+        if origins.is_empty() {
+            return None;
+        }
+        for origin in &origins {
+            if origin != "*" && !(origin.starts_with("http://") || origin.starts_with("https://")) {
+                panic!("Invalid CORS origin {:?}, expected e.g. https://dashboard.example.com", origin);
+            }
+        }
+        let methods = list("DATAPI_CORS_METHODS", "GET,POST,PATCH,DELETE");
+        for method in &methods {
+            if actix_web::http::Method::from_bytes(method.as_bytes()).is_err() {
+                panic!("Invalid CORS method {:?}", method);
+            }
+        }
+        Some(CorsConfig {
+            origins,
+            methods,
+            headers: list("DATAPI_CORS_HEADERS", "Authorization,Content-Type,Accept,X-API-Key,X-Actor,Prefer"),
+            max_age_secs: env_parse("DATAPI_CORS_MAX_AGE_SECS", 3600),
+        })
+    }
+
+    // Preflight requests are answered by the middleware itself, before
+    // authentication; response headers are all exposed to the page.
+    pub(crate) fn middleware(&self) -> Cors {
+        let mut cors = Cors::default()
+            .allowed_methods(self.methods.iter().map(String::as_str))
+            .expose_any_header()
+            .max_age(self.max_age_secs);
+        if self.origins.iter().any(|o| o == "*") {
+            cors = cors.allow_any_origin();
+        } else {
+            for origin in &self.origins {
+                cors = cors.allowed_origin(origin);
+            }
+        }
+        if self.headers.iter().any(|h| h == "*") {
+            cors.allow_any_header()
+        } else {
+            cors.allowed_headers(self.headers.iter().map(String::as_str))
+        }
+    }
+}
+
+// Optional JSON configuration file, pointed to by DATAPI_CONFIG.
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ConfigFile {
+    #[serde(default)]
+    pub(crate) tables: HashMap<String, TableConfig>,
+    #[serde(default)]
+    pub(crate) rollups: Vec<RollupConfig>,
+    #[serde(default)]
+    pub(crate) parameter_aliases: HashMap<String, String>,
+    #[serde(default)]
+    pub(crate) priority_classes: BTreeMap<String, PriorityClassConfig>,
+    // API key -> scope
+    #[serde(default)]
+    pub(crate) api_keys: BTreeMap<String, ApiScope>,
+    // API key -> its quota, see KeyQuota
+    #[serde(default)]
+    pub(crate) api_key_quotas: BTreeMap<String, KeyQuota>,
+    // Value of the JWT role claim -> table grants, see TableGrant
+    #[serde(default)]
+    pub(crate) jwt_roles: BTreeMap<String, Vec<String>>,
+    // Setting -> JWT claim, see JwtConfig::settings
+    #[serde(default)]
+    pub(crate) jwt_settings: BTreeMap<String, String>,
+    #[serde(default)]
+    pub(crate) aliases: BTreeMap<String, AliasConfig>,
+    #[serde(default)]
+    pub(crate) templates: BTreeMap<String, TemplateConfig>,
+    #[serde(default)]
+    pub(crate) functions: Vec<String>,
+    #[serde(default)]
+    pub(crate) envelope: EnvelopeConfig,
+    #[serde(default)]
+    pub(crate) exports: BTreeMap<String, ExportConfig>,
+}
+
+// A stable public name for a relation that may move or change shape: datapi
+// serves it through a view in the datapi_aliases schema, which is put first
+// on the search path. `columns` renames target columns (public -> target),
+// the others keep their names.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct AliasConfig {
+    // Optionally schema-qualified, e.g. sales.orders_v2
+    pub(crate) target: String,
+    #[serde(default)]
+    pub(crate) columns: BTreeMap<String, String>,
+}
+
+pub(crate) const ALIAS_SCHEMA: &str = "datapi_aliases";
+
+impl AliasConfig {
+    pub(crate) fn validate(mut self, alias: &str) -> Self {
+        if sanitize_table_name(alias).is_err() || alias.is_empty() {
+            panic!("Invalid alias name {}", alias);
+        }
+        let valid_target = !self.target.is_empty()
+            && self.target.split('.').count() <= 2
+            && self.target.split('.').all(|part| !part.is_empty() && sanitize_table_name(part).is_ok());
+        if !valid_target {
+            panic!("Invalid target {} for alias {}", self.target, alias);
+        }
+        self.columns = std::mem::take(&mut self.columns)
+            .into_iter()
+            .map(|(public, column)| {
+                let valid = |c: &str| !c.is_empty() && sanitize_column_name(c).is_ok();
+                if !valid(&public) || !valid(&column) {
+                    panic!("Invalid column rename {} -> {} for alias {}", public, column, alias);
+                }
+                (public.to_lowercase(), column)
+            })
+            .collect();
+        self
+    }
+}
+
+// A read the operator writes once and clients parameterize: `sql` is a SELECT
+// with `:name` placeholders, each declared in `params` with its type. Clients
+// pass the values as query parameters of /_templates/{name}; they are checked
+// against the type and bound, never spliced into the SQL.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct TemplateConfig {
+    pub(crate) sql: String,
+    #[serde(default)]
+    pub(crate) params: BTreeMap<String, TemplateParam>,
+    // Relations the SQL reads. JWT callers need a read grant for each.
+    #[serde(default)]
+    pub(crate) tables: Vec<String>,
+    // The SQL with positional parameters, and the placeholder of each
+    #[serde(skip)]
+    pub(crate) statement: String,
+    #[serde(skip)]
+    pub(crate) placeholders: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct TemplateParam {
+    // One of TEMPLATE_TYPES
+    #[serde(rename = "type")]
+    pub(crate) kind: String,
+    // Used when the parameter is left out; parameters without one are required
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) default: Option<serde_json::Value>,
+    // Restricts the parameter to these values
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) values: Vec<String>,
+}
+
+// Query parameters of /_templates/{name} that aren't template parameters
+pub(crate) const TEMPLATE_RESERVED_PARAMS: &[&str] = &["page", "page_size", "fields", "envelope", "timeout_ms", "consistency"];
+
+// Template parameter type -> the Postgres type it is cast to
+pub(crate) const TEMPLATE_TYPES: &[(&str, &str)] = &[
+    ("text", "text"),
+    ("integer", "integer"),
+    ("bigint", "bigint"),
+    ("numeric", "numeric"),
+    ("boolean", "boolean"),
+    ("date", "date"),
+    ("timestamp", "timestamp without time zone"),
+    ("timestamptz", "timestamp with time zone"),
+];
+
+impl TemplateParam {
+    pub(crate) fn sql_type(&self) -> &'static str {
+        TEMPLATE_TYPES.iter().find(|(name, _)| *name == self.kind).map_or("text", |(_, t)| t)
+    }
+    
+    // The text bound for a value, or None if it doesn't fit the parameter
+    pub(crate) fn check(&self, value: &str) -> Option<String> {
+        if !self.values.is_empty() && !self.values.iter().any(|v| v == value) {
+            return None;
+        }
+        coerce_value(value, Coercion::of(self.sql_type()), None)
+    }
+}
+
+impl TemplateConfig {
+    pub(crate) fn validate(mut self, name: &str, tables: &HashMap<String, TableConfig>) -> Result<Self, String> {
+        if name.is_empty() || sanitize_table_name(name).is_err() {
+            return Err(format!("Invalid template name {}", name));
+        }
+        let sql = self.sql.trim().trim_end_matches(';').trim();
+        let first = sql.split_whitespace().next().unwrap_or_default().to_lowercase();
+        if first != "select" && first != "with" {
+            return Err(format!("Template {} must be a SELECT", name));
+        }
+        let positional = sql.contains("$$") || sql.split('$').skip(1).any(|rest| rest.starts_with(|c: char| c.is_ascii_digit()));
+        if sql.contains(';') || sql.contains("--") || sql.contains("/*") || positional {
+            return Err(format!("Template {} must be a single statement without comments or positional parameters", name));
+        }
+        for (param, config) in &self.params {
+            if param.is_empty() || sanitize_column_name(param).is_err() || TEMPLATE_RESERVED_PARAMS.contains(&param.as_str()) {
+                return Err(format!("Invalid parameter name {} for template {}", param, name));
+            }
+            if !TEMPLATE_TYPES.iter().any(|(kind, _)| *kind == config.kind) {
+                return Err(format!("Invalid type {} for parameter {} of template {}", config.kind, param, name));
+            }
+            for value in config.values.iter().chain(config.default.as_ref().and_then(json_to_text).as_ref()) {
+                if coerce_value(value, Coercion::of(config.sql_type()), None).is_none() {
+                    return Err(format!("Value {} doesn't fit parameter {} of template {}", value, param, name));
+                }
+            }
+        }
+        for table in &mut self.tables {
+            if table.is_empty() || sanitize_table_name(table).is_err() {
+                return Err(format!("Invalid table {} for template {}", table, name));
+            }
+            // The filters would have to be written into the SQL itself
+            if tables.get(&table.to_lowercase()).is_some_and(|t| !t.claim_filters.is_empty()) {
+                return Err(format!("Template {} reads {}, which has claim_filters", name, table));
+            }
+            *table = table.to_lowercase();
+        }
+        let (statement, placeholders) =
+            compile_template(sql, &self.params).map_err(|e| format!("Invalid template {}: {}", name, e))?;
+        self.statement = statement;
+        self.placeholders = placeholders;
+        Ok(self)
+    }
+}
+
+// Replaces the `:name` (or `$name`) placeholders outside string literals,
+// quoted identifiers and `::` casts with `$n::type`, returning the name bound
+// to each `$n`. A placeholder used twice binds the same parameter.
+pub(crate) fn compile_template(sql: &str, params: &BTreeMap<String, TemplateParam>) -> Result<(String, Vec<String>), String> {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut statement = String::with_capacity(sql.len());
+    let mut placeholders: Vec<String> = Vec::new();
+    let mut quote = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == ':' && next == Some(':') => {
+                statement.push_str("::");
+                i += 2;
+                continue;
+            }
+            None if (c == ':' || c == '$') && next.is_some_and(|n| n.is_ascii_alphabetic() || n == '_') => {
+                let len = chars[i + 1..].iter().take_while(|n| n.is_ascii_alphanumeric() || **n == '_').count();
+                let placeholder: String = chars[i + 1..i + 1 + len].iter().collect();
+                let param = params.get(&placeholder).ok_or_else(|| format!("undeclared parameter {}{}", c, placeholder))?;
+                let index = match placeholders.iter().position(|p| *p == placeholder) {
+                    Some(index) => index + 1,
+                    None => {
+                        placeholders.push(placeholder);
+                        placeholders.len()
+                    }
+                };
+                statement.push_str(&format!("(${}::{})", index, param.sql_type()));
+                i += 1 + len;
+                continue;
+            }
+            None => {}
+        }
+        statement.push(c);
+        i += 1;
+    }
+    if quote.is_some() {
+        return Err("unbalanced quotes".to_string());
+    }
+    if let Some(unused) = params.keys().find(|p| !placeholders.contains(p)) {
+        return Err(format!("parameter {} is not used", unused));
+    }
+    Ok((statement, placeholders))
+}
+
+// A read of a table run on a schedule and written somewhere, for nightly
+// extracts and the like that used to poll the API from cron jobs. `schedule`
+// is a five-field cron expression in UTC, `filter` and `select` take the path
+// and `?select=` syntax. `destination` is a directory the result is written
+// to as `{name}-{time}.{format}`, or an http(s) URL it is PUT to.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ExportConfig {
+    pub(crate) schedule: String,
+    pub(crate) table: String,
+    #[serde(default)]
+    pub(crate) filter: Option<String>,
+    #[serde(default)]
+    pub(crate) select: Option<String>,
+    // csv, ndjson or json
+    #[serde(default = "default_export_format")]
+    pub(crate) format: String,
+    pub(crate) destination: String,
+    // Gets a JSON POST when a run fails
+    #[serde(default)]
+    pub(crate) alert_webhook: Option<String>,
+    #[serde(skip)]
+    pub(crate) cron: Option<CronSchedule>,
+    #[serde(skip)]
+    pub(crate) filters: Option<FilterExpr>,
+}
+
+pub(crate) fn default_export_format() -> String {
+    "csv".to_string()
+}
+
+pub(crate) const EXPORT_FORMATS: &[&str] = &["csv", "ndjson", "json"];
+
+pub(crate) fn is_http_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+impl ExportConfig {
+    pub(crate) fn validate(mut self, name: &str, tables: &HashMap<String, TableConfig>) -> Result<Self, String> {
+        if name.is_empty() || sanitize_table_name(name).is_err() {
+            return Err(format!("Invalid export name {}", name));
+        }
+        self.cron = Some(CronSchedule::parse(&self.schedule).map_err(|e| format!("Invalid schedule for export {}: {}", name, e))?);
+        if self.table.is_empty() || sanitize_table_name(&self.table).is_err() {
+            return Err(format!("Invalid table {} for export {}", self.table, name));
+        }
+        self.table = self.table.to_lowercase();
+        // There is no caller whose claims they could take
+        if tables.get(&self.table).is_some_and(|t| !t.claim_filters.is_empty()) {
+            return Err(format!("Export {} reads {}, which has claim_filters", name, self.table));
+        }
+        if let Some(filter) = &self.filter {
+            let filters = parse_multiple_filters(filter)
+                .map_err(|e| format!("Invalid filter for export {}: {}", name, Translations::default().render(None, &e)))?;
+            self.filters = Some(filters);
+        }
+        self.format = self.format.to_lowercase();
+        if !EXPORT_FORMATS.contains(&self.format.as_str()) {
+            return Err(format!("Invalid format {} for export {}, expected one of {}", self.format, name, EXPORT_FORMATS.join(", ")));
+        }
+        if self.destination.is_empty() {
+            return Err(format!("Export {} has no destination", name));
+        }
+        if self.alert_webhook.as_deref().is_some_and(|url| !is_http_url(url)) {
+            return Err(format!("Invalid alert_webhook for export {}, expected an http(s) URL", name));
+        }
+        Ok(self)
+    }
+}
+
+// A parsed five-field cron expression (minute, hour, day of month, month,
+// day of week) as one bit per allowed value. Fields take `*`, values, ranges,
+// lists and `/step`; `@hourly`, `@daily`, `@weekly` and `@monthly` are
+// shorthands. As in cron, a day matches either day field when both are
+// restricted.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CronSchedule {
+    pub(crate) minutes: u64,
+    pub(crate) hours: u64,
+    pub(crate) days: u64,
+    pub(crate) months: u64,
+    pub(crate) weekdays: u64,
+    pub(crate) any_day: bool,
+    pub(crate) any_weekday: bool,
+}
+
+impl CronSchedule {
+    pub(crate) fn parse(expr: &str) -> Result<Self, String> {
+        let expr = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("expected 5 fields, got {}", fields.len()));
+        };
+        let mut weekdays = cron_field(weekday, 0, 7)?;
+        // 7 is Sunday as well as 0
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(CronSchedule {
+            minutes: cron_field(minute, 0, 59)?,
+            hours: cron_field(hour, 0, 23)?,
+            days: cron_field(day, 1, 31)?,
+            months: cron_field(month, 1, 12)?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    pub(crate) fn day_matches(&self, date: chrono::NaiveDate) -> bool {
+        use chrono::Datelike;
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    // The first matching minute after `after`, skipping whole months, days
+    // and hours that can't match. None for schedules that never fire, like
+    // `0 0 30 2 *`.
+    pub(crate) fn next_after(&self, after: chrono::DateTime<chrono::Utc>) -> Option<chrono::DateTime<chrono::Utc>> {
+        use chrono::{Datelike, Timelike};
+        let start = after.naive_utc().with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        let mut time = start;
+        // Leap days recur within 8 years
+        while time < start + chrono::Duration::days(8 * 366) {
+            if self.months & (1 << time.month()) == 0 {
+                let (year, month) = if time.month() == 12 { (time.year() + 1, 1) } else { (time.year(), time.month() + 1) };
+                time = chrono::NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.day_matches(time.date()) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if self.hours & (1 << time.hour()) == 0 {
+                time = time.with_minute(0)? + chrono::Duration::hours(1);
+            } else if self.minutes & (1 << time.minute()) == 0 {
+                time += chrono::Duration::minutes(1);
+            } else {
+                return Some(time.and_utc());
+            }
+        }
+        None
+    }
+}
+
+// One cron field as a bitmask of the values from min to max it allows.
+pub(crate) fn cron_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("invalid step in {}", part)),
+            },
+            None => (part, 1),
+        };
+        let value = |v: &str| match v.parse::<u32>() {
+            Ok(v) if (min..=max).contains(&v) => Ok(v),
+            _ => Err(format!("{} is not between {} and {}", v, min, max)),
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (value(from)?, value(to)?),
+            // `5/15` runs from 5 to the end of the range
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if from > to {
+            return Err(format!("empty range {}", range));
+        }
+        for v in (from..=to).step_by(step as usize) {
+            bits |= 1 << v;
+        }
+    }
+    Ok(bits)
+}
+
+// How paged responses (`{"data": [...], "count": ..., ...}`) are shaped for
+// clients with their own API conventions. `style` is the default, requests
+// pick another with `?envelope=`; `keys` renames envelope keys in every style
+// but bare.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct EnvelopeConfig {
+    #[serde(default)]
+    pub(crate) style: EnvelopeStyle,
+    // Envelope key -> the name it is written as, e.g. data -> results
+    #[serde(default)]
+    pub(crate) keys: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EnvelopeStyle {
+    #[default]
+    Default,
+    // The bare data array; paging moves to headers
+    Bare,
+    // `{"items": [...], "meta": {...}}`
+    ItemsMeta,
+}
+
+pub(crate) const ENVELOPE_KEYS: &[&str] = &[
+    "data", "count", "page", "page_size", "total_count", "total_count_estimated", "total_pages", "has_next",
+    "next_cursor", "sort_fingerprint", "result_token", "items", "meta",
+];
+
+impl EnvelopeStyle {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value {
+            "default" => Some(EnvelopeStyle::Default),
+            "bare" => Some(EnvelopeStyle::Bare),
+            "items_meta" => Some(EnvelopeStyle::ItemsMeta),
+            _ => None,
+        }
+    }
+}
+
+impl EnvelopeConfig {
+    pub(crate) fn validate(self) -> Self {
+        for (key, name) in &self.keys {
+            if !ENVELOPE_KEYS.contains(&key.as_str()) {
+                panic!("Unknown envelope key {}, expected one of {}", key, ENVELOPE_KEYS.join(", "));
+            }
+            if name.is_empty() || self.keys.values().filter(|n| *n == name).count() > 1 {
+                panic!("Invalid name {:?} for envelope key {}", name, key);
+            }
+        }
+        self
+    }
+    
+    pub(crate) fn rename(&self, object: serde_json::Map<String, serde_json::Value>) -> serde_json::Map<String, serde_json::Value> {
+        object
+            .into_iter()
+            .map(|(key, value)| match self.keys.get(&key) {
+                Some(name) => (name.clone(), value),
+                None => (key, value),
+            })
+            .collect()
+    }
+    
+    // Reshapes a paged response body; other bodies are returned as they are.
+    // Bare responses return the headers that replace the envelope.
+    pub(crate) fn apply(&self, style: EnvelopeStyle, body: serde_json::Value) -> (serde_json::Value, Vec<(&'static str, String)>) {
+        let serde_json::Value::Object(mut object) = body else {
+            return (body, Vec::new());
+        };
+        if !object.get("data").is_some_and(serde_json::Value::is_array) {
+            return (serde_json::Value::Object(object), Vec::new());
+        }
+        match style {
+            EnvelopeStyle::Default => (serde_json::Value::Object(self.rename(object)), Vec::new()),
+            EnvelopeStyle::Bare => {
+                let data = object.remove("data").unwrap_or_default();
+                let headers = [
+                    ("x-total-count", "total_count"),
+                    ("x-next-cursor", "next_cursor"),
+                    ("x-sort-fingerprint", "sort_fingerprint"),
+                    ("x-result-token", "result_token"),
+                ];
+                let headers = headers
+                    .into_iter()
+                    .filter_map(|(header, key)| Some((header, json_to_text(object.get(key)?)?)))
+                    .collect();
+                (data, headers)
+            }
+            EnvelopeStyle::ItemsMeta => {
+                let items = object.remove("data").unwrap_or_default();
+                let mut wrapped = serde_json::Map::new();
+                wrapped.insert("items".to_string(), items);
+                wrapped.insert("meta".to_string(), serde_json::Value::Object(self.rename(object)));
+                (serde_json::Value::Object(self.rename(wrapped)), Vec::new())
+            }
+        }
+    }
+}
+
+// Access to a table (`*` for all) granted by a JWT, written `table` or
+// `table:scope` like API keys, e.g. `loans` or `notes:readwrite`
+#[derive(Debug, Clone)]
+pub(crate) struct TableGrant {
+    pub(crate) table: String,
+    pub(crate) scope: ApiScope,
+}
+
+impl TableGrant {
+    pub(crate) fn parse(entry: &str) -> Option<Self> {
+        let (table, scope) = match entry.split_once(':') {
+            Some((table, scope)) => (table.trim(), ApiScope::parse(scope)?),
+            None => (entry.trim(), ApiScope::Readonly),
+        };
+        if table != "*" && sanitize_table_name(table).is_err() {
+            return None;
+        }
+        Some(TableGrant { table: table.to_lowercase(), scope })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum JwtKey {
+    // HS256 shared secret, from DATAPI_JWT_SECRET
+    Secret(String),
+    // RS256 public key PEM, read from the file in DATAPI_JWT_PUBLIC_KEY
+    PublicKey(String),
+    // RS256 keys of an identity provider, from DATAPI_JWT_JWKS_URL
+    Jwks(String),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct JwtConfig {
+    pub(crate) key: JwtKey,
+    // Required `iss` and `aud`, from DATAPI_JWT_ISSUER and DATAPI_JWT_AUDIENCE
+    pub(crate) issuer: Option<String>,
+    pub(crate) audience: Option<String>,
+    // Claims holding table grants and roles, dotted for nested claims such as
+    // `realm_access.roles`, from DATAPI_JWT_TABLES_CLAIM and
+    // DATAPI_JWT_ROLE_CLAIM
+    pub(crate) tables_claim: String,
+    pub(crate) role_claim: String,
+    pub(crate) roles: BTreeMap<String, Vec<TableGrant>>,
+    // Custom settings (with a dot, like `request.jwt.claim.user_id`) set
+    // from the token's claims in each transaction, for RLS policies and
+    // triggers to read with current_setting. Claims are dotted like
+    // role_claim.
+    pub(crate) settings: BTreeMap<String, String>,
+}
+
+impl JwtConfig {
+    pub(crate) fn from_env(roles: BTreeMap<String, Vec<String>>, settings: BTreeMap<String, String>) -> Option<Self> {
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+        let keys = [
+            var("DATAPI_JWT_SECRET").map(JwtKey::Secret),
+            var("DATAPI_JWT_PUBLIC_KEY").map(|path| {
+                let pem = std::fs::read_to_string(&path)
+                    .unwrap_or_else(|e| panic!("Failed to read DATAPI_JWT_PUBLIC_KEY {}: {}", path, e));
+                if let Err(e) = DecodingKey::from_rsa_pem(pem.as_bytes()) {
+                    panic!("Invalid RSA public key in {}: {}", path, e);
+                }
+                JwtKey::PublicKey(pem)
+            }),
+            var("DATAPI_JWT_JWKS_URL").map(JwtKey::Jwks),
+        ];
+        let mut keys = keys.into_iter().flatten();
+        let key = keys.next();
+        if keys.next().is_some() {
+            panic!("Set only one of DATAPI_JWT_SECRET, DATAPI_JWT_PUBLIC_KEY and DATAPI_JWT_JWKS_URL");
+        }
+        if key.is_none() && !roles.is_empty() {
+            panic!("jwt_roles are configured but no JWT key is set");
+        }
+        if key.is_none() && !settings.is_empty() {
+            panic!("jwt_settings are configured but no JWT key is set");
+        }
+        // Only custom settings: a claim must not choose the role or the
+        // search path
+        for (setting, claim) in &settings {
+            let valid = setting.contains('.')
+                && setting.split('.').all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+            if !valid || claim.is_empty() {
+                panic!("Invalid JWT setting {}, expected a custom setting like request.jwt.claim.user_id", setting);
+            }
+        }
+        
+        let roles = roles
+            .into_iter()
+            .map(|(role, grants)| {
+                let grants = grants
+                    .iter()
+                    .map(|g| TableGrant::parse(g).unwrap_or_else(|| panic!("Invalid grant {} for JWT role {}", g, role)))
+                    .collect();
+                (role, grants)
+            })
+            .collect();
+        Some(JwtConfig {
+            key: key?,
+            issuer: var("DATAPI_JWT_ISSUER"),
+            audience: var("DATAPI_JWT_AUDIENCE"),
+            tables_claim: var("DATAPI_JWT_TABLES_CLAIM").unwrap_or_else(|| "tables".to_string()),
+            role_claim: var("DATAPI_JWT_ROLE_CLAIM").unwrap_or_else(|| "role".to_string()),
+            roles,
+            settings,
+        })
+    }
+}
+
+// What an API key may do: readonly keys are limited to GET, HEAD and OPTIONS
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ApiScope {
+    Readonly,
+    Readwrite,
+}
+
+impl ApiScope {
+    pub(crate) fn parse(scope: &str) -> Option<Self> {
+        match scope.trim().to_lowercase().as_str() {
+            "readonly" => Some(ApiScope::Readonly),
+            "readwrite" => Some(ApiScope::Readwrite),
+            _ => None,
+        }
+    }
+    
+    pub(crate) fn allows(self, method: &actix_web::http::Method) -> bool {
+        use actix_web::http::Method;
+        self == ApiScope::Readwrite || [Method::GET, Method::HEAD, Method::OPTIONS].contains(method)
+    }
+}
+
+// Limits of one API key, tighter than the table limits it reads under, so a
+// public key can get small pages and a daily allowance next to generous
+// internal keys. Unset limits fall back to the table's.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct KeyQuota {
+    // Most rows a single read may match, like max_rows
+    pub(crate) max_rows: Option<u64>,
+    // Rows of table reads served to the key per UTC day
+    pub(crate) max_rows_per_day: Option<u64>,
+    pub(crate) max_page_size: Option<usize>,
+}
+
+// A class of API traffic served by its own connection pools, so e.g. batch
+// exports can't take the connections interactive dashboards need. Requests
+// join the class by API key or by path.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct PriorityClassConfig {
+    pub(crate) max_connections: u32,
+    // Values of the X-API-Key header that select the class
+    #[serde(default)]
+    pub(crate) api_keys: Vec<String>,
+    // Path prefixes (whole segments, e.g. `/loans` or `/_results`)
+    #[serde(default)]
+    pub(crate) routes: Vec<String>,
+}
+
+// Query parameters an alias may stand for. `offset` is a row offset, turned
+// into the page for the effective page_size.
+pub(crate) const ALIAS_TARGETS: &[&str] = &[
+    "page", "page_size", "sort", "order", "collate", "materialize", "select", "or", "group_by",
+    "agg", "format", "offset",
+];
+
+// A rollup table maintained by datapi: `measures` aggregated per time bucket
+// and dimension values of a source table, recomputed periodically.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct RollupConfig {
+    pub(crate) table: String,
+    pub(crate) source: String,
+    pub(crate) time_column: String,
+    // hour, day, week or month
+    #[serde(default = "default_rollup_bucket")]
+    pub(crate) bucket: String,
+    #[serde(default)]
+    pub(crate) dimensions: Vec<String>,
+    // Re-aggregatable functions only: count(*), count(col), sum, min, max
+    pub(crate) measures: Vec<String>,
+    #[serde(default = "default_rollup_refresh_secs")]
+    pub(crate) refresh_secs: u64,
+    // Trailing buckets recomputed on each incremental refresh
+    #[serde(default = "default_rollup_lookback")]
+    pub(crate) lookback_buckets: i32,
+    #[serde(skip)]
+    pub(crate) aggregates: Vec<Aggregate>,
+}
+
+pub(crate) fn default_rollup_bucket() -> String {
+    "day".to_string()
+}
+
+pub(crate) fn default_rollup_refresh_secs() -> u64 {
+    300
+}
+
+pub(crate) fn default_rollup_lookback() -> i32 {
+    2
+}
+
+pub(crate) const ROLLUP_BUCKETS: &[&str] = &["hour", "day", "week", "month"];
+
+impl RollupConfig {
+    // Sanitizes every identifier and parses the measures, panicking on
+    // configuration errors like the rest of startup.
+    pub(crate) fn validate(mut self) -> Self {
+        let check = |name: &str, what: &str| {
+            if name.is_empty() || sanitize_column_name(name).is_err() {
+                panic!("Invalid {} {:?} in rollup {}", what, name, self.table);
+            }
+        };
+        check(&self.table, "table");
+        check(&self.source, "source table");
+        check(&self.time_column, "time column");
+        for dimension in &self.dimensions {
+            check(dimension, "dimension");
+        }
+        if !ROLLUP_BUCKETS.contains(&self.bucket.as_str()) {
+            panic!("Invalid bucket {:?} in rollup {}", self.bucket, self.table);
+        }
+        
+        self.aggregates = self
+            .measures
+            .iter()
+            .map(|m| match Aggregate::parse(m) {
+                Ok(a) if a.function != "avg" => a,
+                _ => panic!("Invalid measure {:?} in rollup {}", m, self.table),
+            })
+            .collect();
+        self
+    }
+    
+    pub(crate) fn select_list(&self) -> String {
+        let mut columns = vec![format!(
+            "date_trunc('{}', {})::timestamp AS bucket",
+            self.bucket, self.time_column
+        )];
+        columns.extend(self.dimensions.iter().cloned());
+        columns.extend(self.aggregates.iter().map(|a| format!("{} AS {}", a.sql(), a.alias())));
+        columns.join(", ")
+    }
+    
+    pub(crate) fn group_by(&self) -> String {
+        let positions: Vec<String> = (1..=self.dimensions.len() + 1).map(|i| i.to_string()).collect();
+        positions.join(", ")
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct TableConfig {
+    // Session settings (e.g. work_mem, statement_timeout) applied with
+    // SET LOCAL before every query against the table
+    #[serde(default)]
+    pub(crate) settings: BTreeMap<String, String>,
+    // Lifecycle metadata announced to consumers via response headers
+    #[serde(default)]
+    pub(crate) deprecation: Option<Deprecation>,
+    // Columns whose values are encrypted with the deployment key before
+    // serialization; `tag:<tag>` names every column with a column tag
+    #[serde(default)]
+    pub(crate) encrypted_columns: Vec<String>,
+    // Columns redacted in every response, e.g. email -> hash, ssn -> omit;
+    // `tag:<tag>` keys mask every column with a column tag
+    #[serde(default)]
+    pub(crate) masked_columns: BTreeMap<String, Mask>,
+    // Classification tags per column, e.g. email -> [pii], published in the
+    // schema and OpenAPI documents
+    #[serde(default)]
+    pub(crate) column_tags: BTreeMap<String, Vec<String>>,
+    // Computed columns: name -> SQL expression over the table's columns
+    #[serde(default)]
+    pub(crate) virtual_columns: BTreeMap<String, String>,
+    // Overrides DATAPI_MAX_ROWS for the table, 0 for no limit
+    #[serde(default)]
+    pub(crate) max_rows: Option<u64>,
+    // Sort for reads without `sort`, e.g. created_at:desc
+    #[serde(default)]
+    pub(crate) default_sort: Option<String>,
+    // Override DATAPI_DEFAULT_PAGE_SIZE and DATAPI_MAX_PAGE_SIZE, the maximum
+    // may be above the global one
+    #[serde(default)]
+    pub(crate) default_page_size: Option<usize>,
+    #[serde(default)]
+    pub(crate) max_page_size: Option<usize>,
+    // Count mode for reads without `count`: exact, estimated or none
+    #[serde(default)]
+    pub(crate) default_count: Option<String>,
+    // Refuses writes to the table even with DATAPI_ENABLE_WRITES
+    #[serde(default)]
+    pub(crate) read_only: bool,
+    // Reviewed SQL conditions AND-ed into every read of the table, for what
+    // the filter grammar can't express, e.g. `status NOT IN (SELECT ...)`
+    #[serde(default)]
+    pub(crate) filters: Vec<String>,
+    // Named filters in the path syntax that reads opt into with `?preset=`,
+    // e.g. overdue -> loan_status=overdue&overdue_debt_amount>0
+    #[serde(default)]
+    pub(crate) presets: BTreeMap<String, String>,
+    // Records every version of the table's rows for GET /{table}/{id}/history
+    #[serde(default)]
+    pub(crate) history: Option<HistoryConfig>,
+    // Streams the table's inserts, updates and deletes at GET
+    // /{table}/_changes, see install_change_triggers
+    #[serde(default)]
+    pub(crate) changes: Option<ChangesConfig>,
+    // POSTs the table's changes to these URLs, see deliver_webhook
+    #[serde(default)]
+    pub(crate) webhooks: Vec<WebhookConfig>,
+    // Column -> JWT claim (dotted for nested claims) every read and write of
+    // the table is restricted to, e.g. tenant_id -> tenant_id
+    #[serde(default)]
+    pub(crate) claim_filters: BTreeMap<String, String>,
+    // Deletes rows once their timestamp column is older than max_age
+    #[serde(default)]
+    pub(crate) retention: Option<RetentionConfig>,
+    // Timestamp column set to now() by deletes instead of removing the row;
+    // reads and writes only see rows where it is NULL
+    #[serde(default)]
+    pub(crate) soft_delete: Option<String>,
+    // Writes through the API wait in datapi_pending.changes until an admin
+    // approves them, see stage_write
+    #[serde(default)]
+    pub(crate) moderated: bool,
+    // Overrides DATAPI_CACHE_TTL_SECS for the table, 0 to never cache it
+    #[serde(default)]
+    pub(crate) cache_ttl_secs: Option<u64>,
+    // Requests per minute each client may make to the table, on top of
+    // DATAPI_RATE_LIMIT_PER_MINUTE
+    #[serde(default)]
+    pub(crate) rate_limit_per_minute: Option<u32>,
+    // Keeps the table's row count in memory, recounted this often and moved
+    // by datapi's own inserts and deletes in between, for the total_count of
+    // unfiltered reads
+    #[serde(default)]
+    pub(crate) count_refresh_secs: Option<u64>,
+    // Partitions detached to another database, read there when the filters
+    // only match archived rows
+    #[serde(default)]
+    pub(crate) archive: Option<ArchiveConfig>,
+    // What inserts do with rows that collide with an existing key, unless
+    // the request says otherwise
+    #[serde(default)]
+    pub(crate) on_conflict: Option<ConflictStrategy>,
+}
+
+// How a masked column is served: the SHA-256 of its text, which still joins
+// and groups, a fixed placeholder, or not at all. NULLs stay NULL unless
+// omitted.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Mask {
+    Hash,
+    Redact,
+    Omit,
+}
+
+impl Mask {
+    pub(crate) fn apply(self, value: &serde_json::Value) -> Option<serde_json::Value> {
+        if value.is_null() {
+            return (self != Mask::Omit).then_some(serde_json::Value::Null);
+        }
+        match self {
+            Mask::Hash => {
+                let text = json_to_text(value).unwrap_or_default();
+                let digest = <sha2::Sha256 as sha2::Digest>::digest(text.as_bytes());
+                Some(serde_json::Value::String(digest.iter().map(|b| format!("{:02x}", b)).collect()))
+            }
+            Mask::Redact => Some(serde_json::Value::String("[REDACTED]".to_string())),
+            Mask::Omit => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ArchiveConfig {
+    // A database of DATAPI_DB_<NAME> holding the detached partitions under
+    // the same table name
+    pub(crate) database: String,
+    // Partition key
+    pub(crate) column: String,
+    // Date or timestamp before which rows are only in the archive
+    pub(crate) before: String,
+    #[serde(skip)]
+    pub(crate) boundary: Option<chrono::NaiveDateTime>,
+}
+
+impl ArchiveConfig {
+    // Whether every row the filters match lies before the boundary. Only
+    // comparisons on the partition key count; any AND-ed one is enough, OR
+    // groups need all of their branches archived.
+    pub(crate) fn covers(&self, filters: &FilterExpr) -> bool {
+        let Some(boundary) = self.boundary else {
+            return false;
+        };
+        let archived = |value: &str, inclusive: bool| {
+            parse_time_bound(value).is_some_and(|v| if inclusive { v < boundary } else { v <= boundary })
+        };
+        match filters {
+            FilterExpr::Condition(c) if c.column.eq_ignore_ascii_case(&self.column) => {
+                match (c.operator.as_str(), &c.value) {
+                    ("<", FilterValue::Single(v)) => archived(v, false),
+                    ("<=" | "=", FilterValue::Single(v)) => archived(v, true),
+                    ("IN", FilterValue::List(values)) => {
+                        !values.is_empty() && values.iter().all(|v| archived(v, true))
+                    }
+                    ("BETWEEN", FilterValue::List(values)) => values.last().is_some_and(|v| archived(v, true)),
+                    _ => false,
+                }
+            }
+            FilterExpr::And(children) => children.iter().any(|c| self.covers(c)),
+            FilterExpr::Or(children) => !children.is_empty() && children.iter().all(|c| self.covers(c)),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct RetentionConfig {
+    // Timestamp column the age of a row is measured by
+    pub(crate) column: String,
+    // A Postgres interval, e.g. `90 days`
+    pub(crate) max_age: String,
+    // Column set to now() instead of deleting the row, which then isn't
+    // expired again
+    #[serde(default)]
+    pub(crate) soft_delete: Option<String>,
+    // Rows deleted per transaction
+    #[serde(default = "default_retention_batch_size")]
+    pub(crate) batch_size: i64,
+    #[serde(default = "default_retention_interval_secs")]
+    pub(crate) interval_secs: u64,
+}
+
+pub(crate) fn default_retention_batch_size() -> i64 {
+    1000
+}
+
+pub(crate) fn default_retention_interval_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct HistoryConfig {
+    // Column identifying a row across versions
+    #[serde(default = "default_history_key")]
+    pub(crate) key: String,
+}
+
+pub(crate) fn default_history_key() -> String {
+    "id".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct ChangesConfig {
+    // Column sent alone when the row is too large for a notification
+    #[serde(default = "default_history_key")]
+    pub(crate) key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct WebhookConfig {
+    pub(crate) url: String,
+    // insert, update or delete; all when empty
+    #[serde(default)]
+    pub(crate) operations: Vec<String>,
+    // Signs the body with HMAC-SHA256 in X-Datapi-Signature
+    #[serde(default)]
+    pub(crate) secret: Option<String>,
+    #[serde(default = "default_webhook_attempts")]
+    pub(crate) max_attempts: u32,
+}
+
+pub(crate) fn default_webhook_attempts() -> u32 {
+    5
+}
+
+// Virtual column expressions are spliced into generated SQL, so they must be
+// a single scalar expression over the row: no statement separators,
+// comments, parameters or subqueries.
+pub(crate) fn validate_virtual_expression(expr: &str) -> Result<(), &'static str> {
+    if expr.trim().is_empty() {
+        return Err("empty expression");
+    }
+    if expr.contains(';') || expr.contains("--") || expr.contains("/*") || expr.contains('$') {
+        return Err("statement separators, comments and parameters are not allowed");
+    }
+    
+    // Keywords are checked outside string literals only
+    let mut unquoted = String::new();
+    let mut quoted = false;
+    let mut depth = 0i32;
+    for c in expr.chars() {
+        match c {
+            '\'' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            _ => {}
+        }
+        if depth < 0 {
+            return Err("unbalanced parentheses");
+        }
+        unquoted.push(if quoted { ' ' } else { c });
+    }
+    if quoted || depth != 0 {
+        return Err("unbalanced quotes or parentheses");
+    }
+    
+    const FORBIDDEN: &[&str] = &[
+        "select", "from", "insert", "update", "delete", "with", "union", "into", "drop", "alter",
+        "create", "truncate", "grant", "revoke", "copy",
+    ];
+    let has_forbidden = unquoted
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .any(|word| FORBIDDEN.contains(&word.to_lowercase().as_str()));
+    if has_forbidden {
+        return Err("subqueries and statements are not allowed");
+    }
+    Ok(())
+}
+
+// Field-level encryption with the deployment key (DATAPI_ENCRYPTION_KEY,
+// base64 of 32 bytes). Each value's JSON text is sealed with AES-256-GCM
+// under a fresh nonce and rendered as `enc:A256GCM:<key id>:<base64(nonce || ciphertext)>`,
+// so caches in between never see plaintext while key holders can decrypt.
+#[derive(Clone)]
+pub(crate) struct FieldCipher {
+    pub(crate) key_id: String,
+    pub(crate) cipher: Aes256Gcm,
+}
+
+impl std::fmt::Debug for FieldCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FieldCipher").field("key_id", &self.key_id).finish_non_exhaustive()
+    }
+}
+
+impl FieldCipher {
+    pub(crate) fn from_env() -> Option<Self> {
+        let encoded = env::var("DATAPI_ENCRYPTION_KEY").ok()?;
+        let key = BASE64.decode(encoded.trim()).expect("DATAPI_ENCRYPTION_KEY must be base64");
+        if key.len() != 32 {
+            panic!("DATAPI_ENCRYPTION_KEY must decode to 32 bytes");
+        }
+        Some(FieldCipher {
+            key_id: env::var("DATAPI_ENCRYPTION_KEY_ID").unwrap_or_else(|_| "default".to_string()),
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+        })
+    }
+
+    pub(crate) fn encrypt(&self, value: &serde_json::Value) -> String {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let plaintext = value.to_string();
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .expect("AES-GCM encryption failed");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        format!("enc:A256GCM:{}:{}", self.key_id, BASE64.encode(sealed))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub(crate) struct Deprecation {
+    pub(crate) since: Option<chrono::NaiveDate>,
+    pub(crate) sunset: Option<chrono::NaiveDate>,
+    pub(crate) replacement: Option<String>,
+}
+
+impl Deprecation {
+    // Deprecation (RFC 9745), Sunset (RFC 8594) and successor Link headers.
+    pub(crate) fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![(
+            "deprecation",
+            match self.since {
+                Some(d) => format!("@{}", d.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp()),
+                None => "true".to_string(),
+            },
+        )];
+        if let Some(sunset) = self.sunset {
+            headers.push((
+                "sunset",
+                sunset.and_hms_opt(0, 0, 0).unwrap().format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            ));
+        }
+        if let Some(replacement) = &self.replacement {
+            headers.push(("link", format!("</{}>; rel=\"successor-version\"", replacement)));
+        }
+        headers
+    }
+}
+
+impl ConfigFile {
+    pub(crate) fn load(path: &str) -> Self {
+        let text = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Failed to read config file {}: {}", path, e));
+        serde_json::from_str(&text)
+            .unwrap_or_else(|e| panic!("Invalid config file {}: {}", path, e))
+    }
+}
+
+pub(crate) fn is_valid_setting_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+impl Config {
+    pub(crate) fn from_env() -> Self {
+        let file = match env::var("DATAPI_CONFIG") {
+            Ok(path) => ConfigFile::load(&path),
+            Err(_) => ConfigFile::default(),
+        };
+
+        let mut tables = HashMap::new();
+        for (name, mut table) in file.tables {
+            if let Some(replacement) = table.deprecation.as_ref().and_then(|d| d.replacement.as_ref()) {
+                if sanitize_table_name(replacement).is_err() {
+                    panic!("Invalid replacement table {} for {}", replacement, name);
+                }
+            }
+            table.settings.retain(|setting, _| {
+                let valid = is_valid_setting_name(setting);
+                if !valid {
+                    log::warn!("Ignoring invalid setting {} for table {}", setting, name);
+                }
+                valid
+            });
+            table.column_tags = std::mem::take(&mut table.column_tags)
+                .into_iter()
+                .map(|(column, tags)| {
+                    let valid = |c: &str| !c.is_empty() && sanitize_column_name(c).is_ok();
+                    if !valid(&column) || !tags.iter().all(|t| valid(t)) {
+                        panic!("Invalid tags {:?} for column {} of table {}", tags, column, name);
+                    }
+                    (column.to_lowercase(), tags.iter().map(|t| t.to_lowercase()).collect())
+                })
+                .collect();
+            let mut encrypted = Vec::new();
+            for entry in std::mem::take(&mut table.encrypted_columns) {
+                let entry = entry.to_lowercase();
+                let Some(tag) = entry.strip_prefix("tag:") else {
+                    encrypted.push(entry);
+                    continue;
+                };
+                let tagged: Vec<&String> =
+                    table.column_tags.iter().filter(|(_, tags)| tags.iter().any(|t| t == tag)).map(|(c, _)| c).collect();
+                if tagged.is_empty() {
+                    panic!("encrypted_columns of table {} names tag {}, which no column has", name, tag);
+                }
+                encrypted.extend(tagged.into_iter().cloned());
+            }
+            encrypted.sort();
+            encrypted.dedup();
+            table.encrypted_columns = encrypted;
+            let mut masked = BTreeMap::new();
+            for (entry, mask) in std::mem::take(&mut table.masked_columns) {
+                let entry = entry.to_lowercase();
+                let Some(tag) = entry.strip_prefix("tag:") else {
+                    if entry.is_empty() || sanitize_column_name(&entry).is_err() {
+                        panic!("Invalid masked column {:?} of table {}", entry, name);
+                    }
+                    masked.insert(entry, mask);
+                    continue;
+                };
+                let tagged: Vec<&String> =
+                    table.column_tags.iter().filter(|(_, tags)| tags.iter().any(|t| t == tag)).map(|(c, _)| c).collect();
+                if tagged.is_empty() {
+                    panic!("masked_columns of table {} names tag {}, which no column has", name, tag);
+                }
+                // A column's own entry wins over its tags
+                for column in tagged {
+                    masked.entry(column.clone()).or_insert(mask);
+                }
+            }
+            table.masked_columns = masked;
+            for filter in &table.filters {
+                let unsafe_sql = filter.contains(';')
+                    || filter.contains("--")
+                    || filter.contains("/*")
+                    || filter.contains('$');
+                if filter.trim().is_empty() || unsafe_sql {
+                    panic!("Invalid filter {:?} for table {}: statement separators, comments and parameters are not allowed", filter, name);
+                }
+            }
+            table.virtual_columns = std::mem::take(&mut table.virtual_columns)
+                .into_iter()
+                .map(|(column, expr)| {
+                    if column.is_empty() || sanitize_column_name(&column).is_err() {
+                        panic!("Invalid virtual column name {} for table {}", column, name);
+                    }
+                    if let Err(reason) = validate_virtual_expression(&expr) {
+                        panic!("Invalid virtual column {} for table {}: {}", column, name, reason);
+                    }
+                    (column.to_lowercase(), expr)
+                })
+                .collect();
+            if let Some(history) = &mut table.history {
+                if history.key.is_empty() || sanitize_column_name(&history.key).is_err() {
+                    panic!("Invalid history key {} for table {}", history.key, name);
+                }
+                if sanitize_table_name(&name).is_err() || name.contains('.') {
+                    panic!("Invalid table {} for history, expected an unqualified name", name);
+                }
+                // Versions are served as stored, which would bypass the filters
+                if !table.filters.is_empty() || !table.claim_filters.is_empty() {
+                    panic!("history can't be combined with filters for table {}", name);
+                }
+                history.key = history.key.to_lowercase();
+            }
+            if let Some(changes) = &mut table.changes {
+                if changes.key.is_empty() || sanitize_column_name(&changes.key).is_err() {
+                    panic!("Invalid changes key {} for table {}", changes.key, name);
+                }
+                if sanitize_table_name(&name).is_err() {
+                    panic!("Invalid table {} for changes", name);
+                }
+                // Events are matched against claim filters, but not against
+                // arbitrary filter expressions
+                if !table.filters.is_empty() {
+                    panic!("changes can't be combined with filters for table {}", name);
+                }
+                changes.key = changes.key.to_lowercase();
+            }
+            for webhook in &table.webhooks {
+                if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                    panic!("Invalid webhook URL {} for table {}, expected http:// or https://", webhook.url, name);
+                }
+                if let Some(op) = webhook.operations.iter().find(|op| !["insert", "update", "delete"].contains(&op.as_str())) {
+                    panic!("Invalid webhook operation {} for table {}, expected insert, update or delete", op, name);
+                }
+            }
+            if !table.webhooks.is_empty() && sanitize_table_name(&name).is_err() {
+                panic!("Invalid table {} for webhooks", name);
+            }
+            for (preset, filter) in &table.presets {
+                if preset.is_empty() || sanitize_column_name(preset).is_err() {
+                    panic!("Invalid preset name {} for table {}", preset, name);
+                }
+                if let Err(e) = parse_multiple_filters(filter) {
+                    panic!("Invalid preset {} for table {}: {}", preset, name, Translations::default().render(None, &e));
+                }
+            }
+            for (column, claim) in &table.claim_filters {
+                if column.is_empty() || sanitize_column_name(column).is_err() || claim.is_empty() {
+                    panic!("Invalid claim filter {} -> {} for table {}", column, claim, name);
+                }
+            }
+            if let Some(sort) = &table.default_sort {
+                let valid = split_sort_modifiers(sort).is_ok_and(|(column, _)| !column.is_empty() && sanitize_column_name(column).is_ok());
+                if !valid {
+                    panic!(
+                        "Invalid default_sort {:?} for table {}, expected a column with optional :asc or :desc, :nullsfirst or :nullslast and :ci",
+                        sort, name
+                    );
+                }
+            }
+            if table.max_page_size == Some(0) || table.default_page_size == Some(0) {
+                panic!("Page sizes of table {} must be at least 1", name);
+            }
+            if let Err(e) = CountMode::parse(table.default_count.as_deref()) {
+                panic!("Invalid default_count for table {}: {}", name, Translations::default().render(None, &e));
+            }
+            if table.moderated && table.read_only {
+                panic!("Table {} can't be both moderated and read_only", name);
+            }
+            // Staged writes would lose the requester's claims
+            if table.moderated && !table.claim_filters.is_empty() {
+                panic!("Table {} can't be moderated, it has claim_filters", name);
+            }
+            if let Some(column) = &table.soft_delete {
+                if column.is_empty() || sanitize_column_name(column).is_err() {
+                    panic!("Invalid soft_delete column {} for table {}", column, name);
+                }
+            }
+            if let Some(retention) = &mut table.retention {
+                let columns = std::iter::once(&retention.column).chain(retention.soft_delete.as_ref());
+                for column in columns {
+                    if column.is_empty() || sanitize_column_name(column).is_err() {
+                        panic!("Invalid retention column {} for table {}", column, name);
+                    }
+                }
+                if sanitize_table_name(&name).is_err() {
+                    panic!("Invalid table name {} with retention", name);
+                }
+                retention.batch_size = retention.batch_size.max(1);
+                retention.interval_secs = retention.interval_secs.max(1);
+            }
+            if let Some(archive) = &mut table.archive {
+                if archive.column.is_empty() || sanitize_column_name(&archive.column).is_err() {
+                    panic!("Invalid archive column {} for table {}", archive.column, name);
+                }
+                archive.boundary = parse_time_bound(&archive.before);
+                if archive.boundary.is_none() {
+                    panic!("Invalid archive boundary {} for table {}, expected a date", archive.before, name);
+                }
+                archive.database = archive.database.to_lowercase();
+            }
+            tables.insert(name.to_lowercase(), table);
+        }
+
+        let mut parameter_aliases = HashMap::new();
+        for (alias, target) in file.parameter_aliases {
+            if !ALIAS_TARGETS.contains(&target.as_str()) {
+                panic!("Invalid target {} for parameter alias {}", target, alias);
+            }
+            parameter_aliases.insert(alias, target);
+        }
+
+        let mut priority_classes = file.priority_classes;
+        for (name, class) in &mut priority_classes {
+            if class.max_connections == 0 {
+                panic!("Priority class {} needs max_connections", name);
+            }
+            for route in &mut class.routes {
+                if !route.starts_with('/') {
+                    panic!("Invalid route {} for priority class {}, expected a path", route, name);
+                }
+                route.truncate(route.trim_end_matches('/').len());
+            }
+        }
+
+        // Keys without a scope are read-only
+        let mut api_keys: Vec<(String, ApiScope)> = env::var("DATAPI_API_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .map(|entry| match entry.split_once(':') {
+                Some((key, scope)) => match ApiScope::parse(scope) {
+                    Some(scope) => (key.to_string(), scope),
+                    None => panic!("Invalid scope {} in DATAPI_API_KEYS, use readonly or readwrite", scope),
+                },
+                None => (entry.to_string(), ApiScope::Readonly),
+            })
+            .collect();
+        api_keys.extend(file.api_keys);
+        if api_keys.iter().any(|(key, _)| key.is_empty()) {
+            panic!("API keys must not be empty");
+        }
+        let api_keys_table = env::var("DATAPI_API_KEYS_TABLE").ok().filter(|t| !t.is_empty());
+        let api_key_quotas = file.api_key_quotas;
+        // Keys of the keys table can't be checked before it is loaded
+        if api_keys_table.is_none() && api_key_quotas.keys().any(|key| !api_keys.iter().any(|(k, _)| k == key)) {
+            panic!("api_key_quotas lists a key that is not configured");
+        }
+        for quota in api_key_quotas.values() {
+            if quota.max_page_size == Some(0) || quota.max_rows == Some(0) || quota.max_rows_per_day == Some(0) {
+                panic!("API key quotas must be positive");
+            }
+        }
+        let queries_table = env::var("DATAPI_QUERIES_TABLE").ok().filter(|t| !t.is_empty());
+        let audit_table = env::var("DATAPI_AUDIT_TABLE").ok().filter(|t| !t.is_empty());
+        let audit_file = env::var("DATAPI_AUDIT_FILE").ok().filter(|f| !f.is_empty());
+        if audit_table.is_some() && audit_file.is_some() {
+            panic!("DATAPI_AUDIT_TABLE and DATAPI_AUDIT_FILE can't both be set");
+        }
+        for (setting, table, example) in [
+            ("DATAPI_API_KEYS_TABLE", &api_keys_table, "datapi.api_keys"),
+            ("DATAPI_QUERIES_TABLE", &queries_table, "datapi._datapi_queries"),
+            ("DATAPI_AUDIT_TABLE", &audit_table, "datapi_audit.requests"),
+        ] {
+            let Some(table) = table else {
+                continue;
+            };
+            let qualified = table
+                .split_once('.')
+                .is_some_and(|(schema, name)| sanitize_table_name(schema).is_ok() && sanitize_table_name(name).is_ok());
+            if !qualified {
+                panic!("{} must be schema-qualified, e.g. {}, got {}", setting, example, table);
+            }
+        }
+
+        let jwt = JwtConfig::from_env(file.jwt_roles, file.jwt_settings);
+        if jwt.is_none() && tables.values().any(|t| !t.claim_filters.is_empty()) {
+            panic!("claim_filters are configured but JWT authentication is not");
+        }
+        let aliases: BTreeMap<String, AliasConfig> = file
+            .aliases
+            .into_iter()
+            .map(|(alias, config)| {
+                let config = config.validate(&alias);
+                (alias.to_lowercase(), config)
+            })
+            .collect();
+        let templates: BTreeMap<String, TemplateConfig> = file
+            .templates
+            .into_iter()
+            .map(|(name, template)| {
+                let template = template.validate(&name, &tables).unwrap_or_else(|e| panic!("{}", e));
+                (name.to_lowercase(), template)
+            })
+            .collect();
+        let exports: BTreeMap<String, ExportConfig> = file
+            .exports
+            .into_iter()
+            .map(|(name, export)| {
+                let export = export.validate(&name, &tables).unwrap_or_else(|e| panic!("{}", e));
+                (name.to_lowercase(), export)
+            })
+            .collect();
+        let functions: Vec<String> = file.functions.iter().map(|f| f.to_lowercase()).collect();
+        for function in &functions {
+            let valid = function.split('.').count() <= 2
+                && function.split('.').all(|part| !part.is_empty() && sanitize_table_name(part).is_ok());
+            if !valid {
+                panic!("Invalid function name {}", function);
+            }
+        }
+
+        let read_only = env_parse("DATAPI_READ_ONLY", false);
+        if read_only {
+            if env_parse("DATAPI_ENABLE_WRITES", false) {
+                panic!("DATAPI_READ_ONLY and DATAPI_ENABLE_WRITES can't both be set");
+            }
+            let writers = [
+                ("rollups", !file.rollups.is_empty()),
+                ("aliases", !aliases.is_empty()),
+                ("history", tables.values().any(|t| t.history.is_some())),
+                ("changes", tables.values().any(|t| t.changes.is_some())),
+                ("webhooks", tables.values().any(|t| !t.webhooks.is_empty())),
+                ("retention", tables.values().any(|t| t.retention.is_some())),
+                ("moderated tables", tables.values().any(|t| t.moderated)),
+            ];
+            if let Some((feature, _)) = writers.iter().find(|(_, configured)| *configured) {
+                panic!("{} can't be configured with DATAPI_READ_ONLY, they write to the database", feature);
+            }
+        }
+        let rate_limit_per_minute = Some(env_parse("DATAPI_RATE_LIMIT_PER_MINUTE", 0u32)).filter(|r| *r > 0);
+        let databases: BTreeMap<String, String> = env::vars()
+            .filter_map(|(name, url)| Some((name.strip_prefix("DATAPI_DB_")?.to_lowercase(), url)))
+            .inspect(|(name, _)| {
+                if name.is_empty() || sanitize_table_name(name).is_err() {
+                    panic!("Invalid database name {} in DATAPI_DB_{}", name, name.to_uppercase());
+                }
+            })
+            .collect();
+        for (name, table) in &tables {
+            if let Some(archive) = table.archive.as_ref().filter(|a| !databases.contains_key(&a.database)) {
+                panic!(
+                    "Unknown archive database {} for table {}, expected DATAPI_DB_{}",
+                    archive.database,
+                    name,
+                    archive.database.to_uppercase()
+                );
+            }
+        }
+        let schemas: Vec<String> = env::var("DATAPI_SCHEMAS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect();
+        for schema in &schemas {
+            if sanitize_table_name(schema).is_err() {
+                panic!("Invalid schema {} in DATAPI_SCHEMAS", schema);
+            }
+            if api_keys_table.as_ref().is_some_and(|t| t.to_lowercase().split('.').next() == Some(schema.as_str())) {
+                panic!("DATAPI_SCHEMAS can't include {}, the schema of DATAPI_API_KEYS_TABLE", schema);
+            }
+            if queries_table.as_ref().is_some_and(|t| t.to_lowercase().split('.').next() == Some(schema.as_str())) {
+                panic!("DATAPI_SCHEMAS can't include {}, the schema of DATAPI_QUERIES_TABLE", schema);
+            }
+            if audit_table.as_ref().is_some_and(|t| t.to_lowercase().split('.').next() == Some(schema.as_str())) {
+                panic!("DATAPI_SCHEMAS can't include {}, the schema of DATAPI_AUDIT_TABLE", schema);
+            }
+        }
+        let statement_timeout_ms = Some(env_parse("DATAPI_STATEMENT_TIMEOUT_MS", 0u64)).filter(|t| *t > 0);
+        let max_statement_timeout_ms =
+            Some(env_parse("DATAPI_MAX_STATEMENT_TIMEOUT_MS", 0u64)).filter(|t| *t > 0).or(statement_timeout_ms);
+
+        let cipher = FieldCipher::from_env();
+        if cipher.is_none() && tables.values().any(|t| !t.encrypted_columns.is_empty()) {
+            panic!("encrypted_columns are configured but DATAPI_ENCRYPTION_KEY is not set");
+        }
+
+        let max_page_size = env_parse("DATAPI_MAX_PAGE_SIZE", 1000usize).max(1);
+        let pool_max_connections = env_parse("DATAPI_POOL_MAX_CONNECTIONS", 5u32).max(1);
+
+        Config {
+            collations: parse_collations(&env::var("DATAPI_COLLATIONS").unwrap_or_default()),
+            admin_token: env::var("DATAPI_ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+            circuit_failure_threshold: env_parse("DATAPI_CIRCUIT_FAILURE_THRESHOLD", 5).max(1),
+            circuit_probe_secs: env_parse("DATAPI_CIRCUIT_PROBE_SECS", 10).max(1),
+            rate_limit_per_minute,
+            rate_limit_burst: env_parse("DATAPI_RATE_LIMIT_BURST", rate_limit_per_minute.unwrap_or(0)).max(1),
+            result_ttl_secs: env_parse("DATAPI_RESULT_TTL_SECS", 600),
+            tables,
+            cipher,
+            rollups: file.rollups.into_iter().map(RollupConfig::validate).collect(),
+            resolve_tables: env_parse("DATAPI_RESOLVE_TABLES", false),
+            parameter_aliases,
+            max_rows: Some(env_parse("DATAPI_MAX_ROWS", 0u64)).filter(|m| *m > 0),
+            compression: env_parse("DATAPI_COMPRESSION", true),
+            compression_min_bytes: env_parse("DATAPI_COMPRESSION_MIN_BYTES", 1024),
+            etags: env_parse("DATAPI_ETAGS", true),
+            numeric_strings: env_parse("DATAPI_NUMERIC_STRINGS", false),
+            binary_max_bytes: env_parse("DATAPI_BINARY_MAX_BYTES", DEFAULT_BINARY_MAX_BYTES),
+            priority_classes,
+            writes_enabled: env_parse("DATAPI_ENABLE_WRITES", false),
+            sql_enabled: env_parse("DATAPI_ENABLE_SQL", false),
+            read_only,
+            allow_unfiltered_writes: env_parse("DATAPI_ALLOW_UNFILTERED_WRITES", false),
+            query_hint_ms: env::var("DATAPI_QUERY_HINT_MS").ok().and_then(|v| v.parse().ok()),
+            slow_query_ms: env::var("DATAPI_SLOW_QUERY_MS").ok().and_then(|v| v.parse().ok()),
+            api_keys,
+            api_keys_table,
+            api_key_quotas,
+            jwt,
+            aliases,
+            templates,
+            queries_table,
+            audit_table,
+            audit_file,
+            functions,
+            exports,
+            envelope: file.envelope.validate(),
+            cors: CorsConfig::from_env(),
+            cache_ttl_secs: env_parse("DATAPI_CACHE_TTL_SECS", 0),
+            cache_max_age_secs: env_parse("DATAPI_CACHE_MAX_AGE_SECS", 300),
+            cache_max_entries: env_parse("DATAPI_CACHE_MAX_ENTRIES", 1000).max(1),
+            cache_max_body_bytes: env_parse("DATAPI_CACHE_MAX_BODY_BYTES", 1024 * 1024),
+            cache_redis_url: env::var("DATAPI_CACHE_REDIS_URL").ok().filter(|u| !u.is_empty()),
+            share_secret: env::var("DATAPI_SHARE_SECRET").ok().filter(|s| !s.is_empty()),
+            share_max_secs: env_parse("DATAPI_SHARE_MAX_SECS", 7 * 24 * 60 * 60).max(1),
+            statement_timeout_ms,
+            max_statement_timeout_ms,
+            log_binds: match env::var("DATAPI_LOG_BINDS").unwrap_or_default().as_str() {
+                "" | "redact" => BindLogging::Redact,
+                "hash" => BindLogging::Hash,
+                "plain" => BindLogging::Plain,
+                other => panic!("Invalid DATAPI_LOG_BINDS {}, use redact, hash or plain", other),
+            },
+            server_timing: env_parse("DATAPI_SERVER_TIMING", false),
+            otlp_tracing: env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_ok_and(|e| !e.is_empty()),
+            databases,
+            schemas,
+            random_sort_max_rows: env_parse("DATAPI_RANDOM_SORT_MAX_ROWS", 100usize).max(1),
+            pool_max_connections,
+            pool_min_connections: env_parse("DATAPI_POOL_MIN_CONNECTIONS", 0u32).min(pool_max_connections),
+            pool_acquire_timeout: Duration::from_millis(env_parse("DATAPI_POOL_ACQUIRE_TIMEOUT_MS", 30_000u64).max(1)),
+            pool_idle_timeout: Some(env_parse("DATAPI_POOL_IDLE_TIMEOUT_SECS", 600u64))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            pool_max_lifetime: Some(env_parse("DATAPI_POOL_MAX_LIFETIME_SECS", 1800u64))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            pool_test_before_acquire: env_parse("DATAPI_POOL_TEST_BEFORE_ACQUIRE", true),
+            statement_cache_capacity: env_parse("DATAPI_STATEMENT_CACHE_CAPACITY", 100usize),
+            schema_cache_ttl: Duration::from_secs(env_parse("DATAPI_SCHEMA_CACHE_SECS", 60)),
+            default_page_size: env_parse("DATAPI_DEFAULT_PAGE_SIZE", 100usize).min(max_page_size),
+            max_page_size,
+            xlsx_max_rows: env_parse("DATAPI_XLSX_MAX_ROWS", 100_000usize).clamp(1, 1_048_575),
+            page_byte_budget: Some(env_parse("DATAPI_PAGE_BYTE_BUDGET", 0usize)).filter(|b| *b > 0),
+            count_failure_skip: Some(env_parse("DATAPI_COUNT_FAILURE_SKIP_SECS", 0u64))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            max_isolation: match env::var("DATAPI_MAX_ISOLATION") {
+                Ok(level) => IsolationLevel::parse(&level)
+                    .unwrap_or_else(|| panic!("Invalid DATAPI_MAX_ISOLATION {}, use read_committed, repeatable_read or serializable", level)),
+                Err(_) => IsolationLevel::Serializable,
+            },
+        }
+    }
+
+    pub(crate) fn page_size(&self, requested: Option<usize>) -> usize {
+        requested.unwrap_or(self.default_page_size).min(self.max_page_size)
+    }
+    
+    // page_size with the table's default_page_size and max_page_size
+    pub(crate) fn table_page_size(&self, table: &str, requested: Option<usize>) -> usize {
+        let default = self.table(table.trim_matches('"')).and_then(|t| t.default_page_size);
+        requested.or(default).unwrap_or(self.default_page_size).min(self.table_max_page_size(table))
+    }
+    
+    pub(crate) fn table_max_page_size(&self, table: &str) -> usize {
+        self.table(table.trim_matches('"')).and_then(|t| t.max_page_size).unwrap_or(self.max_page_size)
+    }
+
+    pub(crate) fn table(&self, name: &str) -> Option<&TableConfig> {
+        self.tables.get(&name.to_lowercase())
+    }
+
+    pub(crate) fn virtual_column(&self, table: &str, column: &str) -> Option<&str> {
+        let table = self.table(table.trim_matches('"'))?;
+        table.virtual_columns.get(&column.to_lowercase()).map(String::as_str)
+    }
+
+    pub(crate) fn moderated(&self, table: &str) -> bool {
+        self.table(table.trim_matches('"')).is_some_and(|t| t.moderated)
+    }
+    
+    pub(crate) fn read_only_table(&self, table: &str) -> bool {
+        self.table(table.trim_matches('"')).is_some_and(|t| t.read_only)
+    }
+
+    pub(crate) fn soft_delete_column(&self, table: &str) -> Option<&str> {
+        self.table(table.trim_matches('"')).and_then(|t| t.soft_delete.as_deref())
+    }
+
+    pub(crate) fn value_format(&self) -> ValueFormat {
+        ValueFormat { numeric_strings: self.numeric_strings, binary_max_bytes: self.binary_max_bytes, tz: chrono_tz::UTC }
+    }
+    
+    pub(crate) fn max_rows(&self, table: &str) -> Option<u64> {
+        match self.table(table.trim_matches('"')).and_then(|t| t.max_rows) {
+            Some(max_rows) => Some(max_rows).filter(|m| *m > 0),
+            None => self.max_rows,
+        }
+    }
+
+    // Column as it appears in expressions: the parenthesized expression for a
+    // virtual column, the name otherwise
+    pub(crate) fn mask(&self, table: &str, column: &str) -> Option<Mask> {
+        let table = self.table(table.trim_matches('"'))?;
+        table.masked_columns.get(&column.trim_matches('"').to_lowercase()).copied()
+    }
+    
+    // Filtering, sorting or aggregating on a masked column would disclose
+    // its values through the rows it selects
+    pub(crate) fn check_unmasked(&self, table: &str, column: &str) -> Result<(), Message> {
+        match self.mask(table, column) {
+            Some(_) => Err(Message::new("masked_column").arg("column", column.to_lowercase())),
+            None => Ok(()),
+        }
+    }
+    
+    pub(crate) fn is_encrypted(&self, table: &str, column: &str) -> bool {
+        let column = column.trim_matches('"').to_lowercase();
+        self.table(table.trim_matches('"')).is_some_and(|t| t.encrypted_columns.contains(&column))
+    }
+    
+    // Aggregates and groups are computed from the stored plaintext and come
+    // back under their own names, which encryption doesn't cover
+    pub(crate) fn check_aggregatable(&self, table: &str, column: &str) -> Result<(), Message> {
+        self.check_unmasked(table, column)?;
+        match self.is_encrypted(table, column) {
+            true => Err(Message::new("aggregate_encrypted").arg("column", column.to_lowercase())),
+            false => Ok(()),
+        }
+    }
+    
+    pub(crate) fn column_sql(&self, table: &str, column: &str) -> String {
+        match self.virtual_column(table, column) {
+            Some(expr) => format!("({})", expr),
+            None => column.to_string(),
+        }
+    }
+}
+
+pub(crate) fn env_parse<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(v) => v.parse().unwrap_or_else(|_| {
+            log::warn!("Invalid value for {}: {}", name, v);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+// Parses `tr-TR=tr-TR-x-icu,de-DE` into a whitelist. A bare entry maps the
+// name onto a Postgres collation of the same name.
+// The database DATABASE_URL names when it isn't Postgres. MySQL, SQLite and
+// the like are out of scope rather than unfinished: the SQL datapi generates
+// leans on Postgres throughout (catalogs, COPY, RLS, advisory locks), so they
+// are refused at startup instead of failing on the first request. SQLite
+// files are told apart by extension or a `file:` URI before the scheme, so
+// `C:\data\loans.db` isn't taken for a `c` database.
+pub(crate) fn unsupported_database(database_url: &str) -> Option<&str> {
+    let lower = database_url.to_lowercase();
+    let sqlite_file = !lower.contains("://") && [".db", ".sqlite", ".sqlite3"].iter().any(|ext| lower.ends_with(ext));
+    if sqlite_file || lower.starts_with("file:") {
+        return Some("sqlite");
+    }
+    match database_url.split_once(':') {
+        Some((scheme, _)) if !scheme.to_lowercase().starts_with("postgres") => Some(scheme),
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_collations(spec: &str) -> HashMap<String, String> {
+    let mut collations = HashMap::new();
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, collation) = match entry.split_once('=') {
+            Some((n, c)) => (n.trim(), c.trim()),
+            None => (entry, entry),
+        };
+        if collation.contains('"') {
+            log::warn!("Ignoring invalid collation: {}", collation);
+            continue;
+        }
+        collations.insert(name.to_string(), collation.to_string());
+    }
+    collations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use actix_web::test::TestRequest;
+    use actix_web::http::StatusCode;
+    use actix_web::{web, App, HttpResponse};
+    use crate::access::table_read_only;
+    use crate::background::export_query;
+    use crate::filters::{build_where_clause, resolve_collation};
+
+    #[test]
+    fn collations_are_whitelisted() {
+        let collations = parse_collations(" tr-TR=tr-TR-x-icu, de-DE ,,bad=x\"y");
+        assert_eq!(collations.len(), 2);
+        assert_eq!(collations["tr-TR"], "tr-TR-x-icu");
+        assert_eq!(collations["de-DE"], "de-DE");
+        let config = test_config();
+        assert_eq!(resolve_collation(&config, "de").unwrap(), "de-DE-x-icu");
+        assert_eq!(resolve_collation(&config, "fr").unwrap_err().key, "unsupported_collation");
+    }
+
+    #[test]
+    fn table_settings_are_read_per_table() {
+        assert!(is_valid_setting_name("work_mem"));
+        assert!(is_valid_setting_name("pg_trgm.similarity_threshold"));
+        assert!(!is_valid_setting_name("work_mem; DROP"));
+        assert!(!is_valid_setting_name(""));
+        let config = config_with(serde_json::json!({ "loans": { "settings": { "work_mem": "64MB" } } }));
+        assert_eq!(config.table("LOANS").unwrap().settings["work_mem"], "64MB");
+        assert!(config.table("customers").is_none());
+    }
+
+    #[test]
+    fn example_config_file_parses() {
+        ConfigFile::load("datapi.example.json");
+    }
+
+    #[test]
+    fn lists_bind_as_one_array_parameter() {
+        assert_eq!(
+            where_sql("id>1&status=in.(a,b)&kind=not.in.(c)"),
+            " WHERE id::text > $1::text AND status::text = ANY($2::text[]) AND kind::text <> ALL($3::text[])"
+        );
+        assert_eq!(build_where_clause(&FilterExpr::none()), "");
+    }
+
+    #[test]
+    fn rollups_group_by_bucket_and_dimensions() {
+        let rollup = rollup(&["count(*)", "sum(total)"]).validate();
+        assert_eq!((rollup.bucket.as_str(), rollup.refresh_secs, rollup.lookback_buckets), ("day", 300, 2));
+        assert_eq!(
+            rollup.select_list(),
+            "date_trunc('day', created_at)::timestamp AS bucket, region, count(*) AS count, sum(total) AS sum_total"
+        );
+        assert_eq!(rollup.group_by(), "1, 2");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid measure")]
+    fn rollups_cannot_average() {
+        rollup(&["avg(total)"]).validate();
+    }
+
+    #[test]
+    fn filter_groups_nest_and_number_parameters_in_order() {
+        assert_eq!(
+            where_sql("a=1&or=(b=2,and=(c=3,d=in.(x,y)))&e=is.null"),
+            " WHERE a::text = $1::text AND (b::text = $2::text OR (c::text = $3::text AND d::text = ANY($4::text[]))) AND e IS NULL"
+        );
+        for invalid in ["or=b=2", "or=(b=2,)", "or=(b=2", "or=(b=\"2)"] {
+            assert_eq!(parse_multiple_filters(invalid).unwrap_err().key, "invalid_filter_group", "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn virtual_columns_are_single_scalar_expressions() {
+        assert!(validate_virtual_expression("coalesce(first_name, '') || ' ' || last_name").is_ok());
+        assert!(validate_virtual_expression("'select' || name").is_ok());
+        for invalid in [
+            "",
+            "1; drop table orders",
+            "net -- comment",
+            "net * $1",
+            "(select max(net) from orders)",
+            "(net",
+            "net)",
+            "'open",
+        ] {
+            assert!(validate_virtual_expression(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn tables_override_the_row_limit() {
+        let config = Config {
+            max_rows: Some(1000),
+            ..config_with(serde_json::json!({
+                "events": { "max_rows": 50 },
+                "audit": { "max_rows": 0 },
+            }))
+        };
+        assert_eq!(config.max_rows("loans"), Some(1000));
+        assert_eq!(config.max_rows("\"events\""), Some(50));
+        assert_eq!(config.max_rows("audit"), None);
+    }
+
+    #[test]
+    fn api_key_scopes_limit_methods() {
+        use actix_web::http::Method;
+        assert_eq!(ApiScope::parse(" ReadWrite "), Some(ApiScope::Readwrite));
+        assert_eq!(ApiScope::parse("admin"), None);
+        assert!(ApiScope::Readonly.allows(&Method::HEAD));
+        assert!(!ApiScope::Readonly.allows(&Method::POST));
+        assert!(ApiScope::Readwrite.allows(&Method::DELETE));
+    }
+
+    #[test]
+    fn table_grants_parse_like_api_keys() {
+        let grant = TableGrant::parse("Notes:readwrite").unwrap();
+        assert_eq!((grant.table.as_str(), grant.scope), ("notes", ApiScope::Readwrite));
+        assert_eq!(TableGrant::parse("*").unwrap().scope, ApiScope::Readonly);
+        assert!(TableGrant::parse("loans:admin").is_none());
+        assert!(TableGrant::parse("loans;drop").is_none());
+    }
+
+    fn alias(config: serde_json::Value) -> AliasConfig {
+        serde_json::from_value::<AliasConfig>(config).unwrap().validate("orders")
+    }
+
+    #[test]
+    fn alias_renames_are_keyed_by_public_name() {
+        let orders = alias(serde_json::json!({ "target": "sales.orders_v2", "columns": { "Total": "amount_cents" } }));
+        assert_eq!(orders.target, "sales.orders_v2");
+        assert_eq!(orders.columns, BTreeMap::from([("total".to_string(), "amount_cents".to_string())]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid target a.b.c for alias orders")]
+    fn alias_targets_are_at_most_schema_qualified() {
+        alias(serde_json::json!({ "target": "a.b.c" }));
+    }
+
+    #[actix_web::test]
+    async fn cors_preflights_are_answered_for_allowed_origins() {
+        let cors = CorsConfig {
+            origins: vec!["https://dashboard.example.com".to_string()],
+            methods: vec!["GET".to_string()],
+            headers: vec!["X-API-Key".to_string()],
+            max_age_secs: 600,
+        };
+        let app = actix_web::test::init_service(App::new().wrap(cors.middleware()).default_service(web::to(HttpResponse::Ok))).await;
+        let preflight = |origin: &str| {
+            let req = TestRequest::default()
+                .method(actix_web::http::Method::OPTIONS)
+                .uri("/loans")
+                .insert_header(("Origin", origin))
+                .insert_header(("Access-Control-Request-Method", "GET"))
+                .to_request();
+            let app = &app;
+            async move { actix_web::test::call_service(app, req).await }
+        };
+        let allowed = preflight("https://dashboard.example.com").await;
+        assert_eq!(allowed.status(), StatusCode::OK);
+        assert_eq!(allowed.headers().get("Access-Control-Allow-Origin").unwrap(), "https://dashboard.example.com");
+        assert_eq!(allowed.headers().get("Access-Control-Max-Age").unwrap(), "600");
+        assert!(preflight("https://elsewhere.example.com").await.headers().get("Access-Control-Allow-Origin").is_none());
+    }
+
+    #[test]
+    fn templates_bind_typed_placeholders() {
+        let overdue = template(serde_json::json!({
+            "sql": "SELECT id, due::date FROM loans WHERE status = :status AND due < :before AND note <> ':skip' AND due > :before - 30;",
+            "params": {
+                "status": { "type": "text", "values": ["open", "late"], "default": "open" },
+                "before": { "type": "date" }
+            }
+        }));
+        assert_eq!(
+            overdue.statement,
+            "SELECT id, due::date FROM loans WHERE status = ($1::text) AND due < ($2::date) AND note <> ':skip' AND due > ($2::date) - 30"
+        );
+        assert_eq!(overdue.placeholders, vec!["status", "before"]);
+        assert_eq!(overdue.params["status"].check("late").as_deref(), Some("late"));
+        assert_eq!(overdue.params["status"].check("closed"), None);
+        assert_eq!(overdue.params["before"].check("tomorrow"), None);
+
+        let params = BTreeMap::from([("id".to_string(), TemplateParam { kind: "integer".to_string(), default: None, values: Vec::new() })]);
+        assert_eq!(compile_template("SELECT :other", &params).unwrap_err(), "undeclared parameter :other");
+        assert_eq!(compile_template("SELECT $other", &params).unwrap_err(), "undeclared parameter $other");
+        assert_eq!(compile_template("SELECT $id + :id", &params).unwrap().0, "SELECT ($1::integer) + ($1::integer)");
+        assert_eq!(compile_template("SELECT 1", &params).unwrap_err(), "parameter id is not used");
+        assert_eq!(compile_template("SELECT ':id", &params).unwrap_err(), "unbalanced quotes");
+    }
+
+    #[test]
+    fn templates_only_read() {
+        let check = |sql: &str| serde_json::from_value::<TemplateConfig>(serde_json::json!({ "sql": sql })).unwrap().validate("overdue", &HashMap::new());
+        assert_eq!(check("DELETE FROM loans").err().unwrap(), "Template overdue must be a SELECT");
+        assert!(check("SELECT $1").is_err());
+        assert!(check("SELECT $$a$$").is_err());
+        assert!(check("SELECT 1; DELETE FROM loans").is_err());
+    }
+
+    #[test]
+    fn envelopes_reshape_paged_bodies() {
+        let envelope: EnvelopeConfig = serde_json::from_value(serde_json::json!({ "keys": { "data": "results" } })).unwrap();
+        let envelope = envelope.validate();
+        let page = serde_json::json!({ "data": [{ "id": 1 }], "count": 1, "total_count": 9, "next_cursor": "abc", "sort_fingerprint": "f0" });
+        assert_eq!(
+            envelope.apply(EnvelopeStyle::Default, page.clone()).0,
+            serde_json::json!({ "results": [{ "id": 1 }], "count": 1, "total_count": 9, "next_cursor": "abc", "sort_fingerprint": "f0" })
+        );
+        assert_eq!(
+            envelope.apply(EnvelopeStyle::ItemsMeta, page.clone()).0,
+            serde_json::json!({ "items": [{ "id": 1 }], "meta": { "count": 1, "total_count": 9, "next_cursor": "abc", "sort_fingerprint": "f0" } })
+        );
+        let (body, headers) = envelope.apply(EnvelopeStyle::Bare, page);
+        assert_eq!(body, serde_json::json!([{ "id": 1 }]));
+        assert_eq!(
+            headers,
+            vec![
+                ("x-total-count", "9".to_string()),
+                ("x-next-cursor", "abc".to_string()),
+                ("x-sort-fingerprint", "f0".to_string()),
+            ]
+        );
+        let other = serde_json::json!({ "data": "not a page" });
+        assert_eq!(envelope.apply(EnvelopeStyle::Bare, other.clone()), (other, Vec::new()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown envelope key rows")]
+    fn envelope_keys_must_be_known() {
+        let envelope: EnvelopeConfig = serde_json::from_value(serde_json::json!({ "keys": { "rows": "results" } })).unwrap();
+        envelope.validate();
+    }
+
+    #[test]
+    fn archives_cover_filters_wholly_before_the_boundary() {
+        let archive = ArchiveConfig {
+            database: "archive".to_string(),
+            column: "created_at".to_string(),
+            before: "2024-01-01".to_string(),
+            boundary: parse_time_bound("2024-01-01"),
+        };
+        let covers = |filters: &str| archive.covers(&parse_multiple_filters(filters).unwrap());
+        assert!(covers("created_at<2024-01-01"));
+        assert!(covers("status=open&created_at<=2023-12-31"));
+        assert!(covers("created_at=in.(2022-05-01,2023-06-01)"));
+        assert!(!covers("created_at<=2024-01-01"));
+        assert!(!covers("created_at>2020-01-01"));
+        assert!(!covers("status=open"));
+        assert!(!archive.covers(&FilterExpr::none()));
+    }
+
+    #[test]
+    fn page_sizes_default_and_cap_per_config() {
+        let config = Config { default_page_size: 20, max_page_size: 50, ..test_config() };
+        assert_eq!(config.page_size(None), 20);
+        assert_eq!(config.page_size(Some(30)), 30);
+        assert_eq!(config.page_size(Some(500)), 50);
+    }
+
+    #[test]
+    fn cron_schedules_find_the_next_run() {
+        let at = |text: &str| chrono::DateTime::parse_from_rfc3339(text).unwrap().with_timezone(&chrono::Utc);
+        let next = |expr: &str, after: &str| CronSchedule::parse(expr).unwrap().next_after(at(after)).map(|t| t.to_rfc3339());
+        assert_eq!(next("*/15 * * * *", "2026-03-01T10:07:30Z").as_deref(), Some("2026-03-01T10:15:00+00:00"));
+        assert_eq!(next("@daily", "2026-03-01T00:00:00Z").as_deref(), Some("2026-03-02T00:00:00+00:00"));
+        assert_eq!(next("30 2 * * 1-5", "2026-03-06T03:00:00Z").as_deref(), Some("2026-03-09T02:30:00+00:00"));
+        // Sunday as 7, and either day field when both are restricted
+        assert_eq!(next("0 0 * * 7", "2026-03-02T00:00:00Z").as_deref(), Some("2026-03-08T00:00:00+00:00"));
+        assert_eq!(next("0 0 13 * 5", "2026-03-01T00:00:00Z").as_deref(), Some("2026-03-06T00:00:00+00:00"));
+        assert_eq!(next("0 12 29 2 *", "2026-03-01T00:00:00Z").as_deref(), Some("2028-02-29T12:00:00+00:00"));
+        assert_eq!(next("0 0 30 2 *", "2026-03-01T00:00:00Z"), None);
+
+        assert_eq!(cron_field("5/20", 0, 59).unwrap(), (1 << 5) | (1 << 25) | (1 << 45));
+        assert_eq!(cron_field("1,3-4", 1, 12).unwrap(), 0b11010);
+        assert_eq!(cron_field("60", 0, 59).unwrap_err(), "60 is not between 0 and 59");
+        assert_eq!(cron_field("*/0", 0, 59).unwrap_err(), "invalid step in */0");
+        assert_eq!(cron_field("9-3", 0, 23).unwrap_err(), "empty range 9-3");
+        assert_eq!(CronSchedule::parse("0 0 * *").unwrap_err(), "expected 5 fields, got 4");
+    }
+
+    #[test]
+    fn exports_are_checked_and_read_like_the_table() {
+        let tables = config_with(serde_json::json!({
+            "loans": { "filters": ["deleted_at IS NULL"] },
+            "notes": { "claim_filters": { "owner": "sub" } }
+        }))
+        .tables;
+        let export = |config: serde_json::Value| serde_json::from_value::<ExportConfig>(config).unwrap().validate("nightly", &tables);
+        let nightly = export(serde_json::json!({
+            "schedule": "@daily", "table": "Loans", "filter": "status=open", "select": "id,status", "destination": "/tmp/exports"
+        }))
+        .unwrap();
+        assert_eq!((nightly.table.as_str(), nightly.format.as_str()), ("loans", "csv"));
+        let config = Config { tables: tables.clone(), ..test_config() };
+        let (sql, _) = export_query(&config, &nightly).unwrap();
+        assert_eq!(sql, "/* datapi table=loans */ SELECT id, status FROM loans WHERE status::text = $1::text AND (deleted_at IS NULL)");
+
+        let invalid = |config: serde_json::Value| export(config).err().unwrap();
+        assert_eq!(
+            invalid(serde_json::json!({ "schedule": "daily", "table": "loans", "destination": "/tmp" })),
+            "Invalid schedule for export nightly: expected 5 fields, got 1"
+        );
+        assert_eq!(
+            invalid(serde_json::json!({ "schedule": "@daily", "table": "notes", "destination": "/tmp" })),
+            "Export nightly reads notes, which has claim_filters"
+        );
+        assert_eq!(
+            invalid(serde_json::json!({ "schedule": "@daily", "table": "loans", "format": "xlsx", "destination": "/tmp" })),
+            "Invalid format xlsx for export nightly, expected one of csv, ndjson, json"
+        );
+        assert_eq!(
+            invalid(serde_json::json!({ "schedule": "@daily", "table": "loans", "destination": "/tmp", "alert_webhook": "ops@example.com" })),
+            "Invalid alert_webhook for export nightly, expected an http(s) URL"
+        );
+    }
+
+    #[actix_web::test]
+    async fn tables_set_their_own_page_sizes_and_can_be_read_only() {
+        let config = config_with(serde_json::json!({
+            "events": { "default_page_size": 20, "max_page_size": 5000, "read_only": true },
+            "loans": { "max_page_size": 10 }
+        }));
+        assert_eq!(config.table_page_size("\"events\"", None), 20);
+        assert_eq!(config.table_page_size("events", Some(4000)), 4000);
+        assert_eq!(config.table_page_size("loans", None), 10);
+        assert_eq!(config.table_page_size("customers", Some(4000)), 1000);
+        assert_eq!(config.table_max_page_size("events"), 5000);
+
+        let req = TestRequest::default().to_http_request();
+        // Without the database only the configuration decides
+        let pool = offline_pool();
+        assert!(table_read_only(&req, &pool, &config, "loans").await.is_none());
+        let resp = table_read_only(&req, &pool, &config, "\"events\"").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(json_body(resp).await["error"], "Table events is read-only");
+    }
+
+    #[test]
+    fn protected_columns_cannot_be_aggregated() {
+        let config = config_with(serde_json::json!({
+            "people": { "encrypted_columns": ["ssn"], "masked_columns": { "email": "redact" } }
+        }));
+        assert!(config.is_encrypted("\"people\"", "SSN"));
+        assert!(config.check_aggregatable("people", "age").is_ok());
+        assert_eq!(config.check_aggregatable("people", "ssn").unwrap_err().key, "aggregate_encrypted");
+        assert_eq!(config.check_aggregatable("people", "email").unwrap_err().key, "masked_column");
+    }
+
+    #[test]
+    fn only_postgres_urls_are_served() {
+        assert_eq!(unsupported_database("postgres://datapi@localhost/datapi"), None);
+        assert_eq!(unsupported_database("postgresql://datapi@localhost/datapi"), None);
+        assert_eq!(unsupported_database("mysql://root@localhost/loans"), Some("mysql"));
+        assert_eq!(unsupported_database("mariadb://root@localhost/loans"), Some("mariadb"));
+        assert_eq!(unsupported_database("sqlite://loans.db"), Some("sqlite"));
+        assert_eq!(unsupported_database("data/loans.db"), Some("sqlite"));
+        assert_eq!(unsupported_database("Loans.SQLite3"), Some("sqlite"));
+        assert_eq!(unsupported_database("C:\\data\\loans.db"), Some("sqlite"));
+        assert_eq!(unsupported_database("file:loans.db?mode=ro"), Some("sqlite"));
+        assert_eq!(unsupported_database("POSTGRES://datapi@localhost/datapi"), None);
+    }
+}
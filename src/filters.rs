@@ -0,0 +1,1681 @@
+use std::collections::HashMap;
+use actix_web::{web, HttpRequest};
+use serde::Deserialize;
+use sqlx::PgPool;
+use crate::access::claim_values;
+use crate::config::Config;
+use crate::handlers::is_admin;
+use crate::hooks::request_hooks;
+use crate::messages::Message;
+use crate::query::{column_types, PgQuery, QueryParams};
+use crate::writes::quote_identifier;
+
+#[derive(Debug, Clone)]
+pub struct FilterCondition {
+    pub column: String,
+    pub operator: String,
+    pub value: FilterValue,
+    // The type the value is cast to, see FilterExpr::typed; None compares as
+    // text
+    pub cast: Option<String>,
+}
+
+impl FilterCondition {
+    // `contains.(...)` and `overlaps.(...)` on array columns
+    pub(crate) fn is_array_operator(&self) -> bool {
+        matches!(self.value, FilterValue::List(_)) && (self.operator == "@>" || self.operator == "&&")
+    }
+    
+    // `bbox.(...)` and `dwithin.(...)` on geometry and geography columns
+    pub(crate) fn is_spatial_operator(&self) -> bool {
+        self.operator == "bbox" || self.operator == "dwithin"
+    }
+    
+    pub(crate) fn is_range_operator(&self) -> bool {
+        self.operator == "BETWEEN" || self.operator == "NOT BETWEEN"
+    }
+    
+    // `column operator value`, for the audit log
+    pub(crate) fn describe(&self) -> String {
+        match &self.value {
+            FilterValue::Single(value) => format!("{} {} {}", self.column, self.operator, value),
+            FilterValue::List(values) => format!("{} {} ({})", self.column, self.operator, values.join(",")),
+            FilterValue::Null => format!("{} {} NULL", self.column, self.operator),
+        }
+    }
+}
+
+// The bounds of `between.(low,high)`
+pub(crate) fn range_bounds(list: &str) -> Result<Vec<String>, Message> {
+    let values = parse_value_list(list)?;
+    if values.len() != 2 {
+        return Err(Message::new("invalid_range").arg("value", list));
+    }
+    Ok(values)
+}
+
+// `col=low..high` on a numeric, date or timestamp column, whose values can't
+// contain `..`, as BETWEEN; `low..` and `..high` leave the other end open
+pub(crate) fn shorthand_range(c: FilterCondition) -> FilterCondition {
+    let FilterValue::Single(value) = &c.value else {
+        return c;
+    };
+    let Some((low, high)) = value.split_once("..").filter(|_| c.operator == "=") else {
+        return c;
+    };
+    let (low, high) = (low.trim().to_string(), high.trim().to_string());
+    let (operator, value) = match (low.is_empty(), high.is_empty()) {
+        (false, false) => ("BETWEEN", FilterValue::List(vec![low, high])),
+        (false, true) => (">=", FilterValue::Single(low)),
+        (true, false) => ("<=", FilterValue::Single(high)),
+        (true, true) => return c,
+    };
+    FilterCondition { operator: operator.to_string(), value, ..c }
+}
+
+// PostGIS column types, selected as GeoJSON and filtered with `bbox.` and
+// `dwithin.`
+pub(crate) const SPATIAL_TYPES: &[&str] = &["geometry", "geography"];
+
+// A format_type() name without its modifiers, e.g. `character varying` for
+// `character varying(20)`
+pub(crate) fn without_typmod(sql_type: &str) -> String {
+    match (sql_type.find('('), sql_type.find(')')) {
+        (Some(open), Some(close)) if open < close => format!("{}{}", &sql_type[..open], &sql_type[close + 1..]),
+        _ => sql_type.to_string(),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum FilterValue {
+    Single(String),
+    // Values of an IN / NOT IN list, bound as one array parameter
+    List(Vec<String>),
+    // IS NULL / IS NOT NULL, no parameter
+    Null,
+}
+
+// Filters combined with AND / OR, from `a=1&b=2` and `or=(a=1,and=(b=2,c=3))`.
+#[derive(Debug, Clone)]
+pub enum FilterExpr {
+    Condition(FilterCondition),
+    And(Vec<FilterExpr>),
+    Or(Vec<FilterExpr>),
+    // A condition from the table's configured filters, never from the request
+    Sql(String),
+}
+
+impl FilterExpr {
+    pub(crate) fn none() -> Self {
+        FilterExpr::And(Vec::new())
+    }
+    
+    // Both conditions, keeping a top-level AND flat
+    pub fn and(self, other: FilterExpr) -> FilterExpr {
+        match self {
+            FilterExpr::And(mut children) => {
+                children.push(other);
+                FilterExpr::And(children)
+            }
+            this => FilterExpr::And(vec![this, other]),
+        }
+    }
+    
+    pub(crate) fn is_empty(&self) -> bool {
+        matches!(self, FilterExpr::And(children) if children.is_empty())
+    }
+    
+    pub(crate) fn has_sql(&self) -> bool {
+        match self {
+            FilterExpr::Sql(_) => true,
+            FilterExpr::Condition(_) => false,
+            FilterExpr::And(children) | FilterExpr::Or(children) => children.iter().any(|c| c.has_sql()),
+        }
+    }
+    
+    pub(crate) fn param_count(&self) -> usize {
+        self.conditions().iter().filter(|c| !matches!(c.value, FilterValue::Null)).count()
+    }
+    
+    // Leaf conditions in the order their parameters are numbered
+    pub(crate) fn conditions(&self) -> Vec<&FilterCondition> {
+        match self {
+            FilterExpr::Condition(c) => vec![c],
+            FilterExpr::Sql(_) => Vec::new(),
+            FilterExpr::And(children) | FilterExpr::Or(children) => {
+                children.iter().flat_map(|c| c.conditions()).collect()
+            }
+        }
+    }
+    
+    // Sanitizes the column names and substitutes virtual columns
+    pub(crate) fn sanitize(self, config: &Config, table: &str) -> Result<Self, Message> {
+        let sanitize_all = |children: Vec<FilterExpr>| {
+            children
+                .into_iter()
+                .map(|c| c.sanitize(config, table))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(match self {
+            FilterExpr::Condition(c) => FilterExpr::Condition(FilterCondition {
+                column: filter_column_sql(config, table, &c.column).map_err(|e| e.param(&c.column))?,
+                ..c
+            }),
+            FilterExpr::And(children) => FilterExpr::And(sanitize_all(children)?),
+            FilterExpr::Or(children) => FilterExpr::Or(sanitize_all(children)?),
+            FilterExpr::Sql(sql) => FilterExpr::Sql(sql),
+        })
+    }
+    
+    // Compares columns of the numeric, boolean, date and timestamp types as
+    // their type instead of as text, so `price>100` isn't lexicographic and
+    // the column's index applies. Values are checked and normalized like bulk
+    // values. Pattern matches and other types stay text comparisons. Array
+    // operators compare as the array column's type, spatial ones note whether
+    // the column is a geometry or a geography.
+    pub(crate) fn typed(self, columns: &HashMap<String, (String, bool)>) -> Result<Self, Message> {
+        let type_all = |children: Vec<FilterExpr>| {
+            children
+                .into_iter()
+                .map(|c| c.typed(columns))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(match self {
+            FilterExpr::Condition(c) => {
+                let array = c.is_array_operator();
+                let comparison = ["=", "!=", "<", ">", "<=", ">=", "IN", "NOT IN", "BETWEEN", "NOT BETWEEN"].contains(&c.operator.as_str());
+                let name = c.column.trim_matches('"');
+                let column = columns.get(name).or_else(|| columns.get(&name.to_lowercase()));
+                let ranged = column.is_some_and(|(sql_type, _)| {
+                    matches!(
+                        Coercion::of(sql_type),
+                        Coercion::Integer | Coercion::Decimal | Coercion::Date | Coercion::Timestamp | Coercion::TimestampTz
+                    )
+                });
+                let c = if ranged { shorthand_range(c) } else { c };
+                let name = c.column.trim_matches('"');
+                if c.is_spatial_operator() {
+                    let Some((sql_type, _)) = column else {
+                        return Ok(FilterExpr::Condition(c));
+                    };
+                    let base = sql_type.split('(').next().unwrap_or_default();
+                    if !SPATIAL_TYPES.contains(&base) {
+                        return Err(Message::new("spatial_filter_unsupported").arg("column", name).arg("type", sql_type).param(name));
+                    }
+                    return Ok(FilterExpr::Condition(FilterCondition { cast: Some(base.to_string()), ..c }));
+                }
+                let Some((sql_type, _)) = column.filter(|_| comparison || array) else {
+                    return Ok(FilterExpr::Condition(c));
+                };
+                let (coercion, cast) = if array {
+                    let Some(element) = sql_type.strip_suffix("[]") else {
+                        return Err(Message::new("array_filter_unsupported").arg("column", name).arg("type", sql_type).param(name));
+                    };
+                    (Coercion::of(element), format!("{}[]", without_typmod(element)))
+                } else {
+                    let coercion = Coercion::of(sql_type);
+                    let cast = match coercion {
+                        Coercion::Integer => "bigint",
+                        Coercion::Decimal if sql_type.starts_with("numeric") => "numeric",
+                        Coercion::Decimal => "double precision",
+                        Coercion::Bool => "boolean",
+                        Coercion::Date => "date",
+                        Coercion::Timestamp => "timestamp",
+                        Coercion::TimestampTz => "timestamptz",
+                        Coercion::Uuid => "uuid",
+                        Coercion::Other => return Ok(FilterExpr::Condition(c)),
+                    };
+                    (coercion, cast.to_string())
+                };
+                let coerce = |value: String| {
+                    coerce_value(&value, coercion, None).ok_or_else(|| {
+                        Message::new("invalid_filter_value")
+                            .arg("column", name)
+                            .arg("type", coercion.name())
+                            .arg("value", &value)
+                            .param(name)
+                    })
+                };
+                let value = match c.value {
+                    FilterValue::Single(v) => FilterValue::Single(coerce(v)?),
+                    FilterValue::List(values) => FilterValue::List(values.into_iter().map(coerce).collect::<Result<_, _>>()?),
+                    FilterValue::Null => FilterValue::Null,
+                };
+                FilterExpr::Condition(FilterCondition { value, cast: Some(cast), ..c })
+            }
+            FilterExpr::And(children) => FilterExpr::And(type_all(children)?),
+            FilterExpr::Or(children) => FilterExpr::Or(type_all(children)?),
+            FilterExpr::Sql(sql) => FilterExpr::Sql(sql),
+        })
+    }
+    
+    pub(crate) fn to_sql(&self, param: &mut usize) -> String {
+        let (children, separator) = match self {
+            FilterExpr::Condition(c) => return condition_sql(c, param),
+            FilterExpr::Sql(sql) => return format!("({})", sql),
+            // Empty groups, which a hook may leave nested
+            FilterExpr::And(children) if children.is_empty() => return "TRUE".to_string(),
+            FilterExpr::Or(children) if children.is_empty() => return "FALSE".to_string(),
+            FilterExpr::And(children) => (children, " AND "),
+            FilterExpr::Or(children) => (children, " OR "),
+        };
+        let parts: Vec<String> = children.iter().map(|c| c.to_sql(param)).collect();
+        format!("({})", parts.join(separator))
+    }
+}
+
+// Parses one `&`-separated part: a condition or an `or=(...)` / `and=(...)`
+// group of comma-separated parts, which may nest. The input is decoded.
+pub(crate) fn parse_filter_expr(part: &str) -> Result<FilterExpr, Message> {
+    let trimmed = part.trim();
+    for (prefix, any) in [("or=", true), ("and=", false)] {
+        if let Some(group) = trimmed.strip_prefix(prefix) {
+            return parse_filter_group(group, any);
+        }
+    }
+    parse_filter(trimmed).map(FilterExpr::Condition)
+}
+
+pub(crate) fn parse_filter_group(group: &str, any: bool) -> Result<FilterExpr, Message> {
+    let inner = group
+        .trim()
+        .strip_prefix('(')
+        .and_then(|g| g.strip_suffix(')'))
+        .ok_or_else(|| Message::new("invalid_filter_group"))?;
+    
+    let children = split_group_items(inner)?
+        .into_iter()
+        .map(parse_filter_expr)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(if any { FilterExpr::Or(children) } else { FilterExpr::And(children) })
+}
+
+// Splits on commas outside parentheses, brackets, braces and double quotes,
+// so nested groups, in.(...) lists and JSON values stay intact.
+pub(crate) fn split_group_items(inner: &str) -> Result<Vec<&str>, Message> {
+    let mut items = Vec::new();
+    let mut depth = 0usize;
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' | '[' | '{' if !quoted => depth += 1,
+            ')' | ']' | '}' if !quoted => {
+                depth = depth.checked_sub(1).ok_or_else(|| Message::new("invalid_filter_group"))?
+            }
+            ',' if !quoted && depth == 0 => {
+                items.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&inner[start..]);
+    
+    if quoted || depth != 0 || items.iter().any(|item| item.trim().is_empty()) {
+        return Err(Message::new("invalid_filter_group"));
+    }
+    Ok(items)
+}
+
+pub(crate) fn parse_filter(filter_str: &str) -> Result<FilterCondition, Message> {
+    // URL decode the filter string
+    let decoded = urlencoding::decode(filter_str)
+        .map_err(|_| Message::new("url_decode_failed"))?
+        .to_string();
+    
+    // Longer operators first so `>=` wins over `>` at the same position.
+    // `~` / `~*` are LIKE / ILIKE pattern matches, `!~` / `!~*` negate them,
+    // `@>` is JSON containment.
+    let operators = ["!~*", "!~", "~*", ">=", "<=", "!=", "@>", "=", ">", "<", "~"];
+    
+    // The operator is the one occurring first after the column and its JSON
+    // path, so values may contain operator characters themselves
+    let path_end = json_path_end(&decoded);
+    let found = operators
+        .iter()
+        .filter_map(|op| decoded[path_end..].find(op).map(|pos| (path_end + pos, *op)))
+        .min_by_key(|(pos, _)| *pos);
+    
+    let Some((pos, op)) = found else {
+        return Err(Message::new("no_valid_operator"));
+    };
+    
+    let column = decoded[..pos].trim().to_string();
+    let value = decoded[pos + op.len()..].trim().to_string();
+    
+    if column.is_empty() || value.is_empty() {
+        return Err(Message::new("invalid_filter_format"));
+    }
+    
+    // `col=is.null`, `col=not.is.null` and `col!=is.null` test for NULL
+    if op == "=" || op == "!=" {
+        let negated = match value.as_str() {
+            "is.null" => Some(op == "!="),
+            "not.is.null" => Some(true),
+            _ => None,
+        };
+        if let Some(negated) = negated {
+            return Ok(FilterCondition {
+                column,
+                operator: if negated { "IS NOT" } else { "IS" }.to_string(),
+                value: FilterValue::Null,
+                cast: None,
+            });
+        }
+    }
+    
+    // `col=contains.(a,b)` and `col=overlaps.(a,b)` match array columns
+    // holding all or any of the values
+    if op == "=" {
+        for (prefix, operator) in [("contains.", "@>"), ("overlaps.", "&&")] {
+            if let Some(list) = value.strip_prefix(prefix) {
+                return Ok(FilterCondition {
+                    column,
+                    operator: operator.to_string(),
+                    value: FilterValue::List(parse_value_list(list)?),
+                    cast: None,
+                });
+            }
+        }
+    }
+
+    // `col=bbox.(minx,miny,maxx,maxy)` and `col=dwithin.(lon,lat,meters)`
+    // match geometries in a box or within a distance of a point
+    if op == "=" {
+        for (prefix, operator, count) in [("bbox.", "bbox", 4), ("dwithin.", "dwithin", 3)] {
+            if let Some(list) = value.strip_prefix(prefix) {
+                let values = parse_value_list(list)?;
+                let numbers = values.iter().all(|v| v.parse::<f64>().is_ok_and(f64::is_finite));
+                if values.len() != count || !numbers {
+                    return Err(Message::new("invalid_spatial_filter").arg("operator", operator).arg("count", count).arg("value", list));
+                }
+                return Ok(FilterCondition { column, operator: operator.to_string(), value: FilterValue::List(values), cast: None });
+            }
+        }
+    }
+    
+    // `col=between.(low,high)` and `col=not.between.(low,high)` match ranges,
+    // both ends included
+    if op == "=" {
+        for (prefix, operator) in [("between.", "BETWEEN"), ("not.between.", "NOT BETWEEN")] {
+            if let Some(list) = value.strip_prefix(prefix) {
+                return Ok(FilterCondition {
+                    column,
+                    operator: operator.to_string(),
+                    value: FilterValue::List(range_bounds(list)?),
+                    cast: None,
+                });
+            }
+        }
+    }
+    
+    // `col=in.(a,b)`, `col=not.in.(a,b)` and `col!=in.(a,b)` match lists
+    if op == "=" || op == "!=" {
+        let (negated, list) = if let Some(list) = value.strip_prefix("not.in.") {
+            (true, Some(list))
+        } else {
+            (op == "!=", value.strip_prefix("in."))
+        };
+        if let Some(list) = list {
+            return Ok(FilterCondition {
+                column,
+                operator: if negated { "NOT IN" } else { "IN" }.to_string(),
+                value: FilterValue::List(parse_value_list(list)?),
+                cast: None,
+            });
+        }
+    }
+    
+    if op == "@>" && serde_json::from_str::<serde_json::Value>(&value).is_err() {
+        return Err(Message::new("invalid_json_filter").arg("value", &value));
+    }
+    
+    let operator = match op {
+        "~" => "LIKE",
+        "~*" => "ILIKE",
+        "!~" => "NOT LIKE",
+        "!~*" => "NOT ILIKE",
+        other => other,
+    };
+    
+    Ok(FilterCondition {
+        column,
+        operator: operator.to_string(),
+        value: FilterValue::Single(value),
+        cast: None,
+    })
+}
+
+// Parses `(a,b,"c,d")`. Items may be double-quoted to contain commas.
+pub(crate) fn parse_value_list(list: &str) -> Result<Vec<String>, Message> {
+    let inner = list
+        .strip_prefix('(')
+        .and_then(|l| l.strip_suffix(')'))
+        .ok_or_else(|| Message::new("invalid_value_list"))?;
+    
+    let mut values = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in inner.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => values.push(std::mem::take(&mut current).trim().to_string()),
+            _ => current.push(c),
+        }
+    }
+    if quoted {
+        return Err(Message::new("invalid_value_list"));
+    }
+    values.push(current.trim().to_string());
+    
+    if values.iter().any(|v| v.is_empty()) {
+        return Err(Message::new("invalid_value_list"));
+    }
+    Ok(values)
+}
+
+// Builds the WHERE clause for sanitized filters; parameters are numbered in
+// condition order, skipping NULL tests, matching bind_filters.
+pub(crate) fn build_where_clause(filters: &FilterExpr) -> String {
+    if filters.is_empty() {
+        return String::new();
+    }
+    let mut param = 0;
+    let conditions = match filters {
+        // Top-level conditions need no parentheses
+        FilterExpr::And(children) => children
+            .iter()
+            .map(|c| c.to_sql(&mut param))
+            .collect::<Vec<String>>()
+            .join(" AND "),
+        other => other.to_sql(&mut param),
+    };
+    format!(" WHERE {}", conditions)
+}
+
+pub(crate) fn condition_sql(f: &FilterCondition, param: &mut usize) -> String {
+    if !matches!(f.value, FilterValue::Null) {
+        *param += 1;
+    }
+    let (column, cast) = match &f.cast {
+        Some(cast) => (f.column.clone(), cast.as_str()),
+        None if f.is_array_operator() => (format!("{}::text[]", f.column), "text[]"),
+        None => (format!("{}::text", f.column), "text"),
+    };
+    match &f.value {
+        FilterValue::Single(_) if f.operator == "@>" => format!("{}::jsonb @> ${}::jsonb", f.column, param),
+        FilterValue::Single(_) if f.operator == "@@" => format!("{} @@ {}", f.column, tsquery_sql(f.cast.as_deref(), *param)),
+        FilterValue::List(_) if f.is_array_operator() => format!("{} {} ${}::{}", column, f.operator, param, cast),
+        FilterValue::List(_) if f.is_spatial_operator() => spatial_sql(f, *param),
+        FilterValue::List(_) if f.is_range_operator() => {
+            format!("{} {} (${}::{}[])[1] AND (${}::{}[])[2]", column, f.operator, param, cast, param, cast)
+        }
+        FilterValue::Single(_) => format!("{} {} ${}::{}", column, f.operator, param, cast),
+        FilterValue::List(_) if f.operator == "NOT IN" => {
+            format!("{} <> ALL(${}::{}[])", column, param, cast)
+        }
+        FilterValue::List(_) => format!("{} = ANY(${}::{}[])", column, param, cast),
+        FilterValue::Null => format!("{} {} NULL", f.column, f.operator),
+    }
+}
+
+// Coordinates are WGS 84 longitude / latitude and distances meters, so
+// geometries are compared as geographies for `dwithin`
+pub(crate) fn spatial_sql(f: &FilterCondition, param: usize) -> String {
+    let value = |i: usize| format!("(${}::float8[])[{}]", param, i);
+    let geography = f.cast.as_deref() == Some("geography");
+    match f.operator.as_str() {
+        "bbox" => format!(
+            "{} && ST_MakeEnvelope({}, {}, {}, {}, 4326){}",
+            f.column,
+            value(1),
+            value(2),
+            value(3),
+            value(4),
+            if geography { "::geography" } else { "" }
+        ),
+        _ => format!(
+            "ST_DWithin({}{}, ST_SetSRID(ST_MakePoint({}, {}), 4326)::geography, {})",
+            f.column,
+            if geography { "" } else { "::geography" },
+            value(1),
+            value(2),
+            value(3)
+        ),
+    }
+}
+
+pub(crate) fn bind_filters<'q>(mut query: PgQuery<'q>, filters: &'q FilterExpr) -> PgQuery<'q> {
+    for filter in filters.conditions() {
+        query = match &filter.value {
+            FilterValue::Single(v) => query.bind(v),
+            FilterValue::List(v) => query.bind(v),
+            FilterValue::Null => query,
+        };
+    }
+    query
+}
+
+pub(crate) fn parse_multiple_filters(filters_str: &str) -> Result<FilterExpr, Message> {
+    let decoded = urlencoding::decode(filters_str)
+        .map_err(|_| Message::new("url_decode_failed"))?
+        .to_string();
+    
+    let filter_parts: Vec<&str> = decoded.split('&').collect();
+    let mut conditions = Vec::new();
+    
+    for part in filter_parts {
+        let filter = parse_filter_expr(part).map_err(|e| e.param(part.trim()))?;
+        conditions.push(filter);
+    }
+    
+    Ok(FilterExpr::And(conditions))
+}
+
+// The operators of `?filter[column][operator]=value` and the path operators
+// they stand for
+pub(crate) const STRUCTURED_OPERATORS: [(&str, &str); 17] = [
+    ("eq", "="),
+    ("neq", "!="),
+    ("gt", ">"),
+    ("gte", ">="),
+    ("lt", "<"),
+    ("lte", "<="),
+    ("like", "LIKE"),
+    ("ilike", "ILIKE"),
+    ("nlike", "NOT LIKE"),
+    ("nilike", "NOT ILIKE"),
+    ("in", "IN"),
+    ("nin", "NOT IN"),
+    ("between", "BETWEEN"),
+    ("nbetween", "NOT BETWEEN"),
+    ("contains", "@>"),
+    ("overlaps", "&&"),
+    ("is", "IS"),
+];
+
+// `?filter[price][gte]=100&filter[status]=active`, the query-string form of
+// the path filters for routers and proxies that normalize `&` and `=` in
+// paths. `filter[column]` alone compares with `=`. List operators take
+// comma-separated values, double-quoted to contain commas; `is` takes null or
+// not_null. Values are taken as they are, so `filter[x][eq]=in.(1)` compares
+// with the text.
+pub(crate) fn parse_structured_filters(query: &str) -> Result<Vec<FilterExpr>, Message> {
+    let pairs = web::Query::<Vec<(String, String)>>::from_query(query)
+        .map_err(|_| Message::new("url_decode_failed"))?
+        .into_inner();
+    let mut conditions = Vec::new();
+    for (key, value) in pairs {
+        let Some(rest) = key.strip_prefix("filter[") else {
+            continue;
+        };
+        let invalid = || Message::new("invalid_structured_filter").arg("parameter", &key).param(&key);
+        let (column, rest) = rest.split_once(']').ok_or_else(invalid)?;
+        let name = match rest {
+            "" => "eq",
+            _ => rest.strip_prefix('[').and_then(|r| r.strip_suffix(']')).ok_or_else(invalid)?,
+        };
+        let column = column.trim().to_string();
+        let value = value.trim().to_string();
+        if column.is_empty() || value.is_empty() {
+            return Err(Message::new("invalid_filter_format").param(&key));
+        }
+        let Some((_, operator)) = STRUCTURED_OPERATORS.iter().find(|(n, _)| *n == name) else {
+            let names: Vec<&str> = STRUCTURED_OPERATORS.iter().map(|(n, _)| *n).collect();
+            let message = Message::new("unknown_structured_operator").arg("operator", name).arg("operators", names.join(", "));
+            return Err(message.param(&key));
+        };
+        let list = |value: &str| format!("({})", value);
+        let (operator, value) = match *operator {
+            "IS" => match value.as_str() {
+                "null" => ("IS", FilterValue::Null),
+                "not_null" => ("IS NOT", FilterValue::Null),
+                _ => return Err(Message::new("invalid_structured_null").arg("value", &value).param(&key)),
+            },
+            "IN" | "NOT IN" | "@>" | "&&" => {
+                (*operator, FilterValue::List(parse_value_list(&list(&value)).map_err(|e| e.param(&key))?))
+            }
+            "BETWEEN" | "NOT BETWEEN" => {
+                (*operator, FilterValue::List(range_bounds(&list(&value)).map_err(|e| e.param(&key))?))
+            }
+            other => (other, FilterValue::Single(value)),
+        };
+        conditions.push(FilterExpr::Condition(FilterCondition { column, operator: operator.to_string(), value, cast: None }));
+    }
+    Ok(conditions)
+}
+
+// The path filters (`/{table}/{filter}`, None on `/{table}`) AND-ed with the
+// request's `?filter[...]` parameters
+pub(crate) fn request_filters(req: &HttpRequest, path: Option<&str>) -> Result<FilterExpr, Message> {
+    let filters = match path {
+        Some(path) => parse_multiple_filters(path)?,
+        None => FilterExpr::none(),
+    };
+    Ok(parse_structured_filters(req.query_string())?.into_iter().fold(filters, FilterExpr::and))
+}
+
+// Whether the request has `?filter[...]` parameters, which staged writes
+// don't keep
+pub(crate) fn has_structured_filters(req: &HttpRequest) -> bool {
+    web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .is_ok_and(|pairs| pairs.iter().any(|(key, _)| key.starts_with("filter[")))
+}
+
+// `?search=` as a condition matching the search columns against
+// websearch_to_tsquery. tsvector columns are used as they are, others as
+// to_tsvector of their text, so an index on either applies. The condition's
+// column is the document vector and its cast the text search configuration.
+pub(crate) fn search_condition(
+    config: &Config,
+    table: &str,
+    query_params: &QueryParams,
+    columns: &HashMap<String, (String, bool)>,
+) -> Result<Option<FilterCondition>, Message> {
+    let Some(search) = query_params.search.as_deref() else {
+        return Ok(None);
+    };
+    if search.trim().is_empty() {
+        return Err(Message::new("empty_search"));
+    }
+    let search_config = match query_params.search_config.as_deref() {
+        Some(c) => Some(sanitize_column_name(c).map_err(|_| Message::new("invalid_search_config").arg("config", c))?),
+        None => None,
+    };
+    let names: Vec<String> = match query_params.search_columns.as_deref() {
+        Some(list) => list
+            .split(',')
+            .map(|c| sanitize_column_name(c.trim()).map(|c| c.to_lowercase()))
+            .collect::<Result<_, _>>()?,
+        None => {
+            let mut vectors: Vec<String> =
+                columns.iter().filter(|(_, (t, _))| t == "tsvector").map(|(name, _)| name.clone()).collect();
+            vectors.sort();
+            vectors
+        }
+    };
+    if names.is_empty() {
+        return Err(Message::new("search_columns_required").arg("table", table.trim_matches('"')));
+    }
+    let encrypted = config.table(table.trim_matches('"')).map(|t| t.encrypted_columns.as_slice()).unwrap_or_default();
+    let mut vectors = Vec::new();
+    for name in &names {
+        // Ciphertext has no words to match
+        if encrypted.contains(name) {
+            return Err(Message::new("search_encrypted").arg("column", name));
+        }
+        config.check_unmasked(table, name)?;
+        let expr = match (config.virtual_column(table, name), columns.get(name)) {
+            (Some(expr), _) => format!("({})", expr),
+            (None, Some(_)) => quote_identifier(name),
+            (None, None) => {
+                return Err(Message::new("unknown_column").arg("column", name).arg("table", table.trim_matches('"')))
+            }
+        };
+        let is_vector = columns.get(name).is_some_and(|(t, _)| t == "tsvector");
+        vectors.push(match (is_vector, &search_config) {
+            (true, _) => expr,
+            (false, Some(c)) => format!("to_tsvector('{}', coalesce({}::text, ''))", c, expr),
+            (false, None) => format!("to_tsvector(coalesce({}::text, ''))", expr),
+        });
+    }
+    Ok(Some(FilterCondition {
+        column: format!("({})", vectors.join(" || ")),
+        operator: "@@".to_string(),
+        value: FilterValue::Single(search.to_string()),
+        cast: search_config,
+    }))
+}
+
+pub(crate) fn tsquery_sql(search_config: Option<&str>, param: usize) -> String {
+    match search_config {
+        Some(c) => format!("websearch_to_tsquery('{}', ${})", c, param),
+        None => format!("websearch_to_tsquery(${})", param),
+    }
+}
+
+// Adds the `or` query parameter's group to the path filters, sanitizes
+// every column name and types the comparisons by the table's columns. If the
+// columns can't be looked up the filters compare as text.
+pub(crate) async fn finish_filters(
+    req: &HttpRequest,
+    pool: &PgPool,
+    filters: FilterExpr,
+    or: Option<&str>,
+    config: &Config,
+    table: &str,
+) -> Result<FilterExpr, Message> {
+    let columns = match column_types(req, pool, table).await {
+        Ok(columns) => columns,
+        Err(e) => {
+            log::warn!("Column types of {} unavailable, filters compare as text: {}", table, e);
+            HashMap::new()
+        }
+    };
+    finish_filters_with(req, filters, or, config, table, &columns)
+}
+
+pub(crate) fn finish_filters_with(
+    req: &HttpRequest,
+    filters: FilterExpr,
+    or: Option<&str>,
+    config: &Config,
+    table: &str,
+    columns: &HashMap<String, (String, bool)>,
+) -> Result<FilterExpr, Message> {
+    let filters = match or {
+        Some(group) => {
+            let group = parse_filter_group(group, true)
+                .map_err(|e| Message::new("invalid_filter").cause(e).param("or"))?;
+            filters.and(group)
+        }
+        None => filters,
+    };
+    let hooks = request_hooks(req);
+    let filters = match hooks.is_empty() {
+        true => filters,
+        false => hooks.before_query(req, &table.replace('"', ""), filters)?,
+    };
+    let filters = filters.sanitize(config, table)?.typed(columns)?;
+    
+    // Configured filters come last, they take no parameters
+    let claimed = claim_values(req, config, table)?;
+    let configured = config.table(table.trim_matches('"')).map(|t| t.filters.as_slice()).unwrap_or_default();
+    let live = live_rows(req, config, table)?;
+    if configured.is_empty() && claimed.is_empty() && live.is_none() {
+        return Ok(filters);
+    }
+    let mut children = match filters {
+        FilterExpr::And(children) => children,
+        other => vec![other],
+    };
+    children.extend(claimed.into_iter().map(|(column, value)| {
+        FilterExpr::Condition(FilterCondition { column, operator: "=".to_string(), value: FilterValue::Single(value), cast: None })
+    }));
+    children.extend(configured.iter().cloned().chain(live).map(FilterExpr::Sql));
+    Ok(FilterExpr::And(children))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IncludeDeletedParam {
+    pub(crate) include_deleted: Option<bool>,
+}
+
+// The soft-delete condition of a table, unless the admin lifted it
+pub(crate) fn live_rows(req: &HttpRequest, config: &Config, table: &str) -> Result<Option<String>, Message> {
+    Ok(match config.soft_delete_column(table) {
+        Some(column) if !include_deleted(req, config)? => Some(format!("{} IS NULL", quote_identifier(column))),
+        _ => None,
+    })
+}
+
+// `?include_deleted=true` lifts the soft-delete filter, for the admin token
+// only
+fn include_deleted(req: &HttpRequest, config: &Config) -> Result<bool, Message> {
+    let requested = web::Query::<IncludeDeletedParam>::from_query(req.query_string())
+        .ok()
+        .and_then(|q| q.0.include_deleted)
+        .unwrap_or(false);
+    if requested && !is_admin(req, config) {
+        return Err(Message::new("include_deleted_admin_only").param("include_deleted"));
+    }
+    Ok(requested)
+}
+
+pub(crate) fn sanitize_table_name(table: &str) -> Result<String, Message> {
+    if !table.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(Message::new("invalid_table_name"));
+    }
+    Ok(table.to_string())
+}
+
+// Where a leading `column->key->>key` JSON path ends, 0 without one. Keys
+// are names or array indexes; `->>` takes the text and ends the path.
+pub(crate) fn json_path_end(filter: &str) -> usize {
+    let name_len = |s: &str| s.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(s.len());
+    let mut end = name_len(filter);
+    let mut steps = 0;
+    while end > 0 {
+        let rest = &filter[end..];
+        let arrow = if rest.starts_with("->>") { 3 } else if rest.starts_with("->") { 2 } else { break };
+        let key = name_len(&rest[arrow..]);
+        if key == 0 {
+            break;
+        }
+        end += arrow + key;
+        steps += 1;
+        if arrow == 3 {
+            break;
+        }
+    }
+    if steps > 0 { end } else { 0 }
+}
+
+// The SQL of a filter column: a sanitized (or virtual) column, with a JSON
+// path applied if it has one, e.g. `(metadata->'address'->>'city')`
+pub(crate) fn filter_column_sql(config: &Config, table: &str, column: &str) -> Result<String, Message> {
+    let base = sanitize_column_name(&column[..column.find("->").unwrap_or(column.len())])?;
+    config.check_unmasked(table, &base)?;
+    let Some(start) = column.find("->") else {
+        return Ok(config.column_sql(table, &base));
+    };
+    let mut sql = config.column_sql(table, &base);
+    let mut rest = &column[start..];
+    while !rest.is_empty() {
+        let (arrow, after) = match rest.strip_prefix("->>") {
+            Some(after) => ("->>", after),
+            None => ("->", rest.strip_prefix("->").ok_or_else(|| Message::new("invalid_column_name"))?),
+        };
+        let key_len = after.find("->").unwrap_or(after.len());
+        let key = sanitize_column_name(&after[..key_len])?;
+        if key.is_empty() {
+            return Err(Message::new("invalid_column_name"));
+        }
+        match key.chars().all(|c| c.is_ascii_digit()) {
+            true => sql.push_str(&format!("{}{}", arrow, key)),
+            false => sql.push_str(&format!("{}'{}'", arrow, key)),
+        }
+        rest = &after[key_len..];
+    }
+    Ok(format!("({})", sql))
+}
+
+pub(crate) fn sanitize_column_name(column: &str) -> Result<String, Message> {
+    if !column.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(Message::new("invalid_column_name"));
+    }
+    Ok(column.to_string())
+}
+
+pub(crate) const AGGREGATE_FUNCTIONS: &[&str] = &["count", "sum", "avg", "min", "max"];
+
+// An aggregate such as `sum(total)` or `count(*)`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Aggregate {
+    pub(crate) function: String,
+    // None for count(*)
+    pub(crate) column: Option<String>,
+}
+
+impl Aggregate {
+    pub(crate) fn parse(expr: &str) -> Result<Self, Message> {
+        let invalid = || Message::new("invalid_aggregate").arg("aggregate", expr);
+        let (function, rest) = expr.trim().split_once('(').ok_or_else(invalid)?;
+        let argument = rest.strip_suffix(')').ok_or_else(invalid)?.trim();
+        let function = function.trim().to_lowercase();
+        
+        if !AGGREGATE_FUNCTIONS.contains(&function.as_str()) {
+            return Err(Message::new("unsupported_aggregate").arg("function", function));
+        }
+        let column = match argument {
+            "*" if function == "count" => None,
+            "" | "*" => return Err(invalid()),
+            column => Some(sanitize_column_name(column)?.to_lowercase()),
+        };
+        Ok(Aggregate { function, column })
+    }
+    
+    pub(crate) fn sql(&self) -> String {
+        format!("{}({})", self.function, self.column.as_deref().unwrap_or("*"))
+    }
+    
+    // Output column name, e.g. `sum_total` or `count`
+    pub(crate) fn alias(&self) -> String {
+        match &self.column {
+            Some(column) => format!("{}_{}", self.function, column),
+            None => self.function.clone(),
+        }
+    }
+}
+
+// Functions allowed in request expressions
+pub(crate) const EXPRESSION_FUNCTIONS: &[&str] = &[
+    "abs", "ceil", "floor", "round", "length", "char_length", "lower", "upper", "trim",
+    "coalesce", "nullif", "greatest", "least", "date_part", "date_trunc",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ExprToken {
+    Ident(String),
+    Number(String),
+    Str(String),
+    Op(&'static str),
+    Open,
+    Close,
+    Comma,
+}
+
+pub(crate) fn tokenize_expression(text: &str) -> Result<Vec<ExprToken>, Message> {
+    let invalid = || Message::new("invalid_expression").arg("expression", text);
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        i += 1;
+        let token = match c {
+            ' ' => continue,
+            '(' => ExprToken::Open,
+            ')' => ExprToken::Close,
+            ',' => ExprToken::Comma,
+            '+' => ExprToken::Op("+"),
+            '-' => ExprToken::Op("-"),
+            '*' => ExprToken::Op("*"),
+            '/' => ExprToken::Op("/"),
+            '%' => ExprToken::Op("%"),
+            '|' if chars.get(i) == Some(&'|') => {
+                i += 1;
+                ExprToken::Op("||")
+            }
+            '\'' => {
+                let mut value = String::new();
+                loop {
+                    match chars.get(i) {
+                        Some('\'') if chars.get(i + 1) == Some(&'\'') => {
+                            value.push('\'');
+                            i += 2;
+                        }
+                        Some('\'') => {
+                            i += 1;
+                            break;
+                        }
+                        Some(&ch) => {
+                            value.push(ch);
+                            i += 1;
+                        }
+                        None => return Err(invalid()),
+                    }
+                }
+                ExprToken::Str(value)
+            }
+            c if c.is_ascii_digit() => {
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let number: String = chars[start..i].iter().collect();
+                if number.parse::<f64>().is_err() {
+                    return Err(invalid());
+                }
+                ExprToken::Number(number)
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                ExprToken::Ident(chars[start..i].iter().collect())
+            }
+            _ => return Err(invalid()),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+// Request expressions, e.g. `length(name)` or `amount*quantity`: columns,
+// number and string literals, + - * / % ||, parentheses and calls of
+// EXPRESSION_FUNCTIONS. Parsed and rendered again rather than passed
+// through, so nothing outside the grammar reaches the SQL.
+pub(crate) struct ExpressionParser<'a> {
+    pub(crate) config: &'a Config,
+    pub(crate) table: &'a str,
+    pub(crate) text: &'a str,
+    pub(crate) tokens: Vec<ExprToken>,
+    pub(crate) pos: usize,
+}
+
+impl ExpressionParser<'_> {
+    pub(crate) fn parse(config: &Config, table: &str, text: &str) -> Result<String, Message> {
+        let mut parser = ExpressionParser { config, table, text, tokens: tokenize_expression(text)?, pos: 0 };
+        let sql = parser.expression(0)?;
+        if parser.pos != parser.tokens.len() {
+            return Err(parser.invalid());
+        }
+        Ok(sql)
+    }
+    
+    pub(crate) fn invalid(&self) -> Message {
+        Message::new("invalid_expression").arg("expression", self.text)
+    }
+    
+    pub(crate) fn next(&mut self) -> Option<ExprToken> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+    
+    pub(crate) fn expression(&mut self, depth: usize) -> Result<String, Message> {
+        // Bounds the recursion on inputs like `((((...`
+        if depth > 32 {
+            return Err(self.invalid());
+        }
+        let mut sql = self.term(depth)?;
+        while let Some(ExprToken::Op(op)) = self.tokens.get(self.pos).cloned() {
+            self.pos += 1;
+            sql = format!("{} {} {}", sql, op, self.term(depth)?);
+        }
+        Ok(sql)
+    }
+    
+    pub(crate) fn term(&mut self, depth: usize) -> Result<String, Message> {
+        match self.next() {
+            Some(ExprToken::Op("-")) => Ok(format!("-{}", self.term(depth)?)),
+            Some(ExprToken::Number(n)) => Ok(n),
+            Some(ExprToken::Str(s)) => Ok(format!("'{}'", s.replace('\'', "''"))),
+            Some(ExprToken::Open) => {
+                let inner = self.expression(depth + 1)?;
+                match self.next() {
+                    Some(ExprToken::Close) => Ok(format!("({})", inner)),
+                    _ => Err(self.invalid()),
+                }
+            }
+            Some(ExprToken::Ident(name)) if self.tokens.get(self.pos) == Some(&ExprToken::Open) => {
+                let function = name.to_lowercase();
+                if !EXPRESSION_FUNCTIONS.contains(&function.as_str()) {
+                    return Err(Message::new("unsupported_function").arg("function", name));
+                }
+                self.pos += 1;
+                let mut args = Vec::new();
+                if self.tokens.get(self.pos) == Some(&ExprToken::Close) {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        args.push(self.expression(depth + 1)?);
+                        match self.next() {
+                            Some(ExprToken::Comma) => continue,
+                            Some(ExprToken::Close) => break,
+                            _ => return Err(self.invalid()),
+                        }
+                    }
+                }
+                Ok(format!("{}({})", function, args.join(", ")))
+            }
+            Some(ExprToken::Ident(name)) => {
+                let name = sanitize_column_name(&name)?;
+                self.config.check_unmasked(self.table, &name)?;
+                Ok(self.config.column_sql(self.table, &name))
+            }
+            _ => Err(self.invalid()),
+        }
+    }
+}
+
+// `?sort=` value: a column or an expression, optionally followed by `:asc`
+// or `:desc`, which takes precedence over `?order=`. Returns the SQL and
+// the direction.
+pub(crate) fn parse_sort(config: &Config, table: &str, sort: &str) -> Result<(String, SortModifiers), Message> {
+    let (sort, modifiers) = split_sort_modifiers(sort)?;
+    let sql = match sanitize_column_name(sort) {
+        Ok(column) => {
+            config.check_unmasked(table, &column)?;
+            config.column_sql(table, &column)
+        }
+        Err(_) => ExpressionParser::parse(config, table, sort)?,
+    };
+    let sql = match modifiers.case_insensitive {
+        true => format!("lower(({})::text)", sql),
+        false => sql,
+    };
+    Ok((sql, modifiers))
+}
+
+// What follows a sort expression: `:asc` / `:desc`, `:nullsfirst` /
+// `:nullslast` and `:ci` (compare lowercased), in any order
+#[derive(Debug, Default)]
+pub(crate) struct SortModifiers {
+    pub(crate) direction: Option<String>,
+    pub(crate) nulls: Option<&'static str>,
+    pub(crate) case_insensitive: bool,
+}
+
+pub(crate) fn split_sort_modifiers(sort: &str) -> Result<(&str, SortModifiers), Message> {
+    let mut parts = sort.split(':');
+    let expr = parts.next().unwrap_or_default();
+    let mut modifiers = SortModifiers::default();
+    for part in parts {
+        match part.to_lowercase().as_str() {
+            "asc" | "desc" => modifiers.direction = Some(part.to_uppercase()),
+            "nullsfirst" => modifiers.nulls = Some("FIRST"),
+            "nullslast" => modifiers.nulls = Some("LAST"),
+            "ci" => modifiers.case_insensitive = true,
+            _ => return Err(Message::new("invalid_sort_modifier").arg("modifier", part)),
+        }
+    }
+    Ok((expr, modifiers))
+}
+
+pub(crate) fn validate_sort_order(order: &str) -> Result<String, Message> {
+    let order_upper = order.to_uppercase();
+    if order_upper == "ASC" || order_upper == "DESC" {
+        Ok(order_upper)
+    } else {
+        Err(Message::new("invalid_sort_order"))
+    }
+}
+
+pub(crate) fn resolve_collation<'a>(config: &'a Config, collate: &str) -> Result<&'a str, Message> {
+    config
+        .collations
+        .get(collate)
+        .map(String::as_str)
+        .ok_or_else(|| Message::new("unsupported_collation").arg("collation", collate))
+}
+
+// Bulk ingest: rows are checked and normalized against the column types,
+// then streamed to Postgres with COPY.
+
+// How bulk values are checked and normalized for a column type, by the
+// format_type() text
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Coercion {
+    Integer,
+    Decimal,
+    Bool,
+    Date,
+    Timestamp,
+    TimestampTz,
+    Uuid,
+    // Passed on as-is, Postgres validates
+    Other,
+}
+
+impl Coercion {
+    pub(crate) fn of(sql_type: &str) -> Self {
+        match sql_type {
+            "smallint" | "integer" | "bigint" => Coercion::Integer,
+            "real" | "double precision" => Coercion::Decimal,
+            "boolean" => Coercion::Bool,
+            "date" => Coercion::Date,
+            "uuid" => Coercion::Uuid,
+            t if t.starts_with("timestamp") && t.ends_with("with time zone") => Coercion::TimestampTz,
+            t if t.starts_with("numeric") => Coercion::Decimal,
+            t if t.starts_with("timestamp") => Coercion::Timestamp,
+            _ => Coercion::Other,
+        }
+    }
+    
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Coercion::Integer => "integer",
+            Coercion::Decimal => "number",
+            Coercion::Bool => "boolean",
+            Coercion::Date => "date",
+            Coercion::Timestamp | Coercion::TimestampTz => "timestamp",
+            Coercion::Uuid => "uuid",
+            Coercion::Other => "text",
+        }
+    }
+}
+
+// Per-column override from `?formats=col:format,...`: a chrono format for
+// dates and timestamps, or `comma` / `point` as the decimal separator.
+#[derive(Debug, Clone)]
+pub(crate) enum ColumnFormat {
+    Chrono(String),
+    DecimalComma,
+    DecimalPoint,
+}
+
+pub(crate) fn parse_column_formats(spec: &str) -> Result<HashMap<String, ColumnFormat>, Message> {
+    let mut formats = HashMap::new();
+    for entry in spec.split(',').filter(|e| !e.trim().is_empty()) {
+        let (column, format) = entry
+            .split_once(':')
+            .filter(|(c, f)| !c.trim().is_empty() && !f.is_empty())
+            .ok_or_else(|| Message::new("invalid_column_format").arg("format", entry))?;
+        let format = match format {
+            "comma" => ColumnFormat::DecimalComma,
+            "point" => ColumnFormat::DecimalPoint,
+            other => ColumnFormat::Chrono(other.to_string()),
+        };
+        formats.insert(column.trim().to_lowercase(), format);
+    }
+    Ok(formats)
+}
+
+pub(crate) const DATE_FORMATS: &[&str] = &["%Y-%m-%d", "%d.%m.%Y", "%Y/%m/%d", "%Y%m%d"];
+pub(crate) const TIMESTAMP_FORMATS: &[&str] = &[
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%dT%H:%M",
+    "%d.%m.%Y %H:%M:%S",
+    "%d.%m.%Y %H:%M",
+];
+
+// Checks a value against the column type and returns the text Postgres
+// accepts. Numbers may use a decimal comma (the last of `.` and `,` is the
+// decimal separator, a lone `,` is one too) and spaces as digit grouping.
+pub(crate) fn coerce_value(value: &str, coercion: Coercion, format: Option<&ColumnFormat>) -> Option<String> {
+    let trimmed = value.trim();
+    match coercion {
+        Coercion::Other => Some(value.to_string()),
+        Coercion::Integer => {
+            let digits: String = trimmed.chars().filter(|c| !c.is_whitespace()).collect();
+            digits.parse::<i64>().ok().map(|v| v.to_string())
+        }
+        Coercion::Decimal => {
+            let digits: String = trimmed.chars().filter(|c| !c.is_whitespace()).collect();
+            let decimal = match format {
+                Some(ColumnFormat::DecimalComma) => Some(','),
+                Some(ColumnFormat::DecimalPoint) => Some('.'),
+                _ => digits.rfind(['.', ',']).map(|i| digits.as_bytes()[i] as char),
+            };
+            let normalized: String = digits
+                .chars()
+                .filter_map(|c| match c {
+                    '.' | ',' if Some(c) == decimal => Some('.'),
+                    '.' | ',' => None,
+                    other => Some(other),
+                })
+                .collect();
+            normalized.parse::<f64>().ok().filter(|v| v.is_finite()).map(|_| normalized)
+        }
+        Coercion::Bool => match trimmed.to_lowercase().as_str() {
+            "true" | "t" | "yes" | "y" | "on" | "1" => Some("true".to_string()),
+            "false" | "f" | "no" | "n" | "off" | "0" => Some("false".to_string()),
+            _ => None,
+        },
+        Coercion::Date => {
+            let parse = |f: &str| chrono::NaiveDate::parse_from_str(trimmed, f).ok();
+            let date = match format {
+                Some(ColumnFormat::Chrono(f)) => parse(f),
+                _ => DATE_FORMATS.iter().find_map(|f| parse(f)),
+            };
+            date.map(|d| d.format("%Y-%m-%d").to_string())
+        }
+        Coercion::Timestamp | Coercion::TimestampTz => {
+            if coercion == Coercion::TimestampTz && format.is_none() {
+                if let Ok(ts) = chrono::DateTime::parse_from_rfc3339(trimmed) {
+                    return Some(ts.to_rfc3339());
+                }
+            }
+            let parse = |f: &str| {
+                chrono::NaiveDateTime::parse_from_str(trimmed, f)
+                    .ok()
+                    .or_else(|| chrono::NaiveDate::parse_from_str(trimmed, f).ok()?.and_hms_opt(0, 0, 0))
+            };
+            let ts = match format {
+                Some(ColumnFormat::Chrono(f)) => parse(f),
+                _ => TIMESTAMP_FORMATS.iter().chain(DATE_FORMATS).find_map(|f| parse(f)),
+            };
+            ts.map(|t| t.format("%Y-%m-%d %H:%M:%S%.f").to_string())
+        }
+        // The forms Postgres accepts: optional braces, hyphens anywhere
+        Coercion::Uuid => {
+            let inner = trimmed.strip_prefix('{').and_then(|t| t.strip_suffix('}')).unwrap_or(trimmed);
+            let hex: String = inner.chars().filter(|c| *c != '-').collect::<String>().to_ascii_lowercase();
+            if hex.len() != 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            Some(format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use actix_web::test::TestRequest;
+    use crate::handlers::find_rollup;
+
+    fn condition(filter: &str) -> FilterCondition {
+        parse_filter(filter).unwrap()
+    }
+
+    #[test]
+    fn parses_comparison_filters() {
+        let c = condition("amount>=10");
+        assert_eq!((c.column.as_str(), c.operator.as_str()), ("amount", ">="));
+        assert!(matches!(c.value, FilterValue::Single(ref v) if v == "10"));
+        // The first operator wins, so values may contain operators
+        assert!(matches!(condition("note=a>=b").value, FilterValue::Single(ref v) if v == "a>=b"));
+        assert_eq!(parse_filter("amount").unwrap_err().key, "no_valid_operator");
+        assert_eq!(parse_filter("=1").unwrap_err().key, "invalid_filter_format");
+    }
+
+    #[test]
+    fn parses_in_lists() {
+        let c = condition("status=in.(open, \"a,b\")");
+        assert_eq!(c.operator, "IN");
+        assert!(matches!(c.value, FilterValue::List(ref v) if v == &["open", "a,b"]));
+        assert_eq!(condition("status=not.in.(x)").operator, "NOT IN");
+        assert_eq!(condition("status!=in.(x)").operator, "NOT IN");
+        for invalid in ["status=in.(a,,b)", "status=in.a,b", "status=in.(\"a)"] {
+            assert_eq!(parse_filter(invalid).unwrap_err().key, "invalid_value_list", "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_pattern_filters() {
+        assert_eq!(condition("name~Bo%").operator, "LIKE");
+        assert_eq!(condition("name~*bo%").operator, "ILIKE");
+        assert_eq!(condition("name!~Bo%").operator, "NOT LIKE");
+        assert_eq!(condition("name!~*bo%").operator, "NOT ILIKE");
+        assert!(matches!(condition("name~*a=b").value, FilterValue::Single(ref v) if v == "a=b"));
+        assert_eq!(where_sql("name~*bo%"), " WHERE name::text ILIKE $1::text");
+    }
+
+    #[test]
+    fn parses_aggregates() {
+        let sum = Aggregate::parse(" SUM( total ) ").unwrap();
+        assert_eq!((sum.sql(), sum.alias()), ("sum(total)".to_string(), "sum_total".to_string()));
+        let count = Aggregate::parse("count(*)").unwrap();
+        assert_eq!((count.sql(), count.alias()), ("count(*)".to_string(), "count".to_string()));
+        assert_eq!(Aggregate::parse("sum(*)").unwrap_err().key, "invalid_aggregate");
+        assert_eq!(Aggregate::parse("sum(total").unwrap_err().key, "invalid_aggregate");
+        assert_eq!(Aggregate::parse("median(total)").unwrap_err().key, "unsupported_aggregate");
+        assert_eq!(Aggregate::parse("sum(a;b)").unwrap_err().key, "invalid_column_name");
+    }
+
+    #[test]
+    fn null_tests_take_no_parameter() {
+        assert_eq!(condition("note=is.null").operator, "IS");
+        assert_eq!(condition("note!=is.null").operator, "IS NOT");
+        assert_eq!(condition("note=not.is.null").operator, "IS NOT");
+        assert!(matches!(condition("note=is.null").value, FilterValue::Null));
+        assert_eq!(
+            where_sql("a=1&note=is.null&b=2"),
+            " WHERE a::text = $1::text AND note IS NULL AND b::text = $2::text"
+        );
+    }
+
+    #[test]
+    fn the_or_parameter_joins_the_path_filters() {
+        let filters = finish_filters_with(&TestRequest::default().to_http_request(), parse_multiple_filters("id>1").unwrap(), Some("(status=active,status=pending)"), &test_config(), "t", &HashMap::new()).unwrap();
+        assert_eq!(
+            build_where_clause(&filters),
+            " WHERE id::text > $1::text AND (status::text = $2::text OR status::text = $3::text)"
+        );
+        assert_eq!(finish_filters_with(&TestRequest::default().to_http_request(), FilterExpr::none(), Some("(na;me=1)"), &test_config(), "t", &HashMap::new()).unwrap_err().key, "invalid_column_name");
+    }
+
+    #[test]
+    fn comparisons_are_typed_by_the_column() {
+        let columns = HashMap::from([
+            ("amount".to_string(), ("integer".to_string(), false)),
+            ("price".to_string(), ("numeric(10,2)".to_string(), false)),
+            ("due".to_string(), ("timestamp with time zone".to_string(), false)),
+            ("paid".to_string(), ("boolean".to_string(), false)),
+            ("name".to_string(), ("text".to_string(), true)),
+        ]);
+        let req = TestRequest::default().to_http_request();
+        let where_clause = |filters: &str, or: Option<&str>| {
+            let filters = parse_multiple_filters(filters)?;
+            finish_filters_with(&req, filters, or, &test_config(), "loans", &columns).map(|f| build_where_clause(&f))
+        };
+        assert_eq!(
+            where_clause("amount>=10&name=Bob", None).unwrap(),
+            " WHERE amount >= $1::bigint AND name::text = $2::text"
+        );
+        assert_eq!(
+            where_clause("note=is.null", Some("(amount<5,price=in.(1.5,2))")).unwrap(),
+            " WHERE note IS NULL AND (amount < $1::bigint OR price = ANY($2::numeric[]))"
+        );
+        assert_eq!(
+            where_clause("due>2024-01-01T00:00:00Z&paid=true&amount~1%", None).unwrap(),
+            " WHERE due > $1::timestamptz AND paid = $2::boolean AND amount::text LIKE $3::text"
+        );
+        let e = where_clause("amount=ten", None).unwrap_err();
+        assert_eq!(e.key, "invalid_filter_value");
+        assert_eq!(e.args, [("column", "amount".to_string()), ("type", "integer".to_string()), ("value", "ten".to_string())]);
+        assert_eq!(e.parameter(), Some("amount"));
+    }
+
+    #[test]
+    fn configured_filters_apply_to_every_read() {
+        let config = Config {
+            rollups: vec![rollup(&["count(*)"]).validate()],
+            ..config_with(serde_json::json!({
+                "orders": { "filters": ["tenant_id = current_setting('app.tenant')::int"] }
+            }))
+        };
+        let filters = finish_filters_with(&TestRequest::default().to_http_request(), parse_multiple_filters("id=1").unwrap(), Some("(a=1,b=2)"), &config, "orders", &HashMap::new()).unwrap();
+        assert_eq!(
+            build_where_clause(&filters),
+            " WHERE id::text = $1::text AND (a::text = $2::text OR b::text = $3::text) AND (tenant_id = current_setting('app.tenant')::int)"
+        );
+        assert_eq!(filters.conditions().len(), 3);
+
+        // A rollup can't evaluate them
+        let filters = finish_filters_with(&TestRequest::default().to_http_request(), FilterExpr::none(), None, &config, "orders", &HashMap::new()).unwrap();
+        let count = [Aggregate::parse("count(*)").unwrap()];
+        assert!(find_rollup(&config, "orders", &[], &count, &filters).is_none());
+    }
+
+    #[test]
+    fn bulk_values_are_coerced_to_their_column_type() {
+        let coerce = |value: &str, sql_type: &str| coerce_value(value, Coercion::of(sql_type), None);
+        assert_eq!(coerce(" 1 000 ", "bigint").as_deref(), Some("1000"));
+        assert_eq!(coerce("1.5", "integer"), None);
+        assert_eq!(coerce("1.234,56", "numeric(10,2)").as_deref(), Some("1234.56"));
+        assert_eq!(coerce("1,234.56", "double precision").as_deref(), Some("1234.56"));
+        assert_eq!(coerce("2,5", "real").as_deref(), Some("2.5"));
+        assert_eq!(coerce("NaN", "numeric"), None);
+        assert_eq!(coerce("Yes", "boolean").as_deref(), Some("true"));
+        assert_eq!(coerce("maybe", "boolean"), None);
+        assert_eq!(coerce("31.12.2024", "date").as_deref(), Some("2024-12-31"));
+        assert_eq!(coerce("2024-02-30", "date"), None);
+        assert_eq!(coerce("2024-12-31T08:30", "timestamp without time zone").as_deref(), Some("2024-12-31 08:30:00"));
+        assert_eq!(
+            coerce("2024-12-31T08:30:00+01:00", "timestamp with time zone").as_deref(),
+            Some("2024-12-31T08:30:00+01:00")
+        );
+        assert_eq!(coerce(" as is ", "text").as_deref(), Some(" as is "));
+
+        let formats = parse_column_formats("Amount:comma, due:%m/%d/%Y").unwrap();
+        let amount = formats.get("amount");
+        assert_eq!(coerce_value("1.5", Coercion::Decimal, amount).as_deref(), Some("15"));
+        assert_eq!(coerce_value("12/31/2024", Coercion::Date, formats.get("due")).as_deref(), Some("2024-12-31"));
+        assert_eq!(parse_column_formats("amount").unwrap_err().key, "invalid_column_format");
+    }
+
+    #[test]
+    fn sort_expressions_are_parsed_and_rendered() {
+        let config = config_with(serde_json::json!({
+            "orders": { "virtual_columns": { "gross": "net * 1.19" } }
+        }));
+        let sort = |text: &str| parse_sort(&config, "orders", text).map(|(sql, modifiers)| (sql, modifiers.direction));
+        assert_eq!(sort("name").unwrap(), ("name".to_string(), None));
+        assert_eq!(sort("gross:desc").unwrap(), ("(net * 1.19)".to_string(), Some("DESC".to_string())));
+        assert_eq!(sort("LENGTH(name):asc").unwrap(), ("length(name)".to_string(), Some("ASC".to_string())));
+        assert_eq!(
+            sort("coalesce(nick,'it''s')||-qty*(2.5+gross)").unwrap().0,
+            "coalesce(nick, 'it''s') || -qty * (2.5 + (net * 1.19))"
+        );
+        assert_eq!(sort("pg_sleep(10)").unwrap_err().key, "unsupported_function");
+        assert_eq!(sort("name:sideways").unwrap_err().key, "invalid_sort_modifier");
+        for invalid in ["a;b", "length(name", "'open", "a +", "1..2", &"(".repeat(40)] {
+            assert_eq!(sort(invalid).unwrap_err().key, "invalid_expression", "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn json_paths_and_containment_filter_json_columns() {
+        let c = parse_filter("metadata->address->>city=Ber->lin").unwrap();
+        assert_eq!((c.column.as_str(), c.operator.as_str()), ("metadata->address->>city", "="));
+        assert!(matches!(c.value, FilterValue::Single(ref v) if v == "Ber->lin"));
+        let c = parse_filter("tags@>[\"a\",\"b\"]").unwrap();
+        assert_eq!((c.column.as_str(), c.operator.as_str()), ("tags", "@>"));
+        assert_eq!(parse_filter("tags@>[a").unwrap_err().key, "invalid_json_filter");
+
+        let config = test_config();
+        assert_eq!(filter_column_sql(&config, "t", "metadata->address->>city").unwrap(), "(metadata->'address'->>'city')");
+        assert_eq!(filter_column_sql(&config, "t", "items->0->>sku").unwrap(), "(items->0->>'sku')");
+        assert_eq!(filter_column_sql(&config, "t", "metadata->").unwrap_err().key, "invalid_column_name");
+
+        let req = TestRequest::default().to_http_request();
+        let filters = parse_multiple_filters("metadata->>kind=book&tags@>{\"a\":1,\"b\":2}").unwrap();
+        let filters = finish_filters_with(&req, filters, None, &config, "t", &HashMap::new()).unwrap();
+        assert_eq!(
+            build_where_clause(&filters),
+            " WHERE (metadata->>'kind')::text = $1::text AND tags::jsonb @> $2::jsonb"
+        );
+    }
+
+    #[test]
+    fn array_columns_filter_by_contains_and_overlaps() {
+        let c = parse_filter("tags=contains.(a,\"b,c\")").unwrap();
+        assert_eq!(c.operator, "@>");
+        assert!(matches!(c.value, FilterValue::List(ref v) if v == &["a", "b,c"]));
+        assert_eq!(without_typmod("character varying(20)[]"), "character varying[]");
+
+        let columns = HashMap::from([
+            ("tags".to_string(), ("character varying(20)[]".to_string(), false)),
+            ("scores".to_string(), ("integer[]".to_string(), false)),
+            ("name".to_string(), ("text".to_string(), true)),
+        ]);
+        let req = TestRequest::default().to_http_request();
+        let where_clause = |filters: &str, columns: &HashMap<String, (String, bool)>| {
+            let filters = parse_multiple_filters(filters)?;
+            finish_filters_with(&req, filters, None, &test_config(), "t", columns).map(|f| build_where_clause(&f))
+        };
+        assert_eq!(
+            where_clause("tags=contains.(a)&scores=overlaps.(1,2)", &columns).unwrap(),
+            " WHERE tags @> $1::character varying[] AND scores && $2::integer[]"
+        );
+        // Without the column types the arrays compare as text
+        assert_eq!(where_clause("tags=contains.(a)", &HashMap::new()).unwrap(), " WHERE tags::text[] @> $1::text[]");
+        assert_eq!(where_clause("scores=overlaps.(x)", &columns).unwrap_err().key, "invalid_filter_value");
+        let e = where_clause("name=contains.(a)", &columns).unwrap_err();
+        assert_eq!(e.key, "array_filter_unsupported");
+    }
+
+    #[test]
+    fn uuid_filters_compare_as_uuid() {
+        let canonical = "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11";
+        for form in [canonical, "{A0EEBC99-9C0B-4EF8-BB6D-6BB9BD380A11}", "a0eebc999c0b4ef8bb6d6bb9bd380a11"] {
+            assert_eq!(coerce_value(form, Coercion::Uuid, None).as_deref(), Some(canonical), "{}", form);
+        }
+        assert_eq!(coerce_value("a0eebc99", Coercion::Uuid, None), None);
+
+        let columns = HashMap::from([("id".to_string(), ("uuid".to_string(), true))]);
+        let req = TestRequest::default().to_http_request();
+        let filters = parse_multiple_filters("id=A0EEBC999C0B4EF8BB6D6BB9BD380A11").unwrap();
+        let filters = finish_filters_with(&req, filters, None, &test_config(), "t", &columns).unwrap();
+        assert_eq!(build_where_clause(&filters), " WHERE id = $1::uuid");
+        assert!(matches!(&filters.conditions()[0].value, FilterValue::Single(v) if v == canonical));
+        let filters = parse_multiple_filters("id=nope").unwrap();
+        let e = finish_filters_with(&req, filters, None, &test_config(), "t", &columns).unwrap_err();
+        assert_eq!(e.args, [("column", "id".to_string()), ("type", "uuid".to_string()), ("value", "nope".to_string())]);
+    }
+
+    #[test]
+    fn search_matches_the_search_columns_as_documents() {
+        let config = config_with(serde_json::json!({ "posts": { "encrypted_columns": ["secret"] } }));
+        let columns = HashMap::from([
+            ("doc".to_string(), ("tsvector".to_string(), false)),
+            ("title".to_string(), ("text".to_string(), true)),
+            ("secret".to_string(), ("text".to_string(), false)),
+        ]);
+        let search = |params: serde_json::Value, columns: &HashMap<String, (String, bool)>| {
+            search_condition(&config, "posts", &query_params(params), columns)
+        };
+        assert!(search(serde_json::json!({}), &columns).unwrap().is_none());
+        let condition = search(serde_json::json!({ "search": "rust -java" }), &columns).unwrap().unwrap();
+        let filters = FilterExpr::And(vec![FilterExpr::Condition(condition)]);
+        assert_eq!(build_where_clause(&filters), " WHERE (\"doc\") @@ websearch_to_tsquery($1)");
+
+        let params = serde_json::json!({ "search": "rust", "search_columns": "doc,Title", "search_config": "english" });
+        let condition = search(params, &columns).unwrap().unwrap();
+        assert_eq!(condition.column, "(\"doc\" || to_tsvector('english', coalesce(\"title\"::text, '')))");
+        assert_eq!(tsquery_sql(condition.cast.as_deref(), 3), "websearch_to_tsquery('english', $3)");
+
+        let invalid = |params: serde_json::Value, columns| search(params, columns).unwrap_err().key;
+        assert_eq!(invalid(serde_json::json!({ "search": " " }), &columns), "empty_search");
+        assert_eq!(invalid(serde_json::json!({ "search": "a", "search_config": "x'y" }), &columns), "invalid_search_config");
+        assert_eq!(invalid(serde_json::json!({ "search": "a", "search_columns": "secret" }), &columns), "search_encrypted");
+        assert_eq!(invalid(serde_json::json!({ "search": "a", "search_columns": "body" }), &columns), "unknown_column");
+        assert_eq!(invalid(serde_json::json!({ "search": "a" }), &HashMap::new()), "search_columns_required");
+    }
+
+    #[test]
+    fn structured_filter_parameters_join_the_path_filters() {
+        let req = TestRequest::with_uri("/loans?filter[amount][gte]=100&filter[status]=open&filter[note][is]=not_null&page=2")
+            .to_http_request();
+        let filters = request_filters(&req, Some("id=1")).unwrap();
+        assert_eq!(
+            build_where_clause(&filters),
+            " WHERE id::text = $1::text AND amount::text >= $2::text AND status::text = $3::text AND note IS NOT NULL"
+        );
+        assert!(has_structured_filters(&req));
+        let conditions = parse_structured_filters("filter[tags][in]=a,%22b,c%22").unwrap();
+        assert!(matches!(&conditions[0], FilterExpr::Condition(c) if matches!(&c.value, FilterValue::List(v) if v == &["a", "b,c"])));
+
+        let invalid = |query: &str| parse_structured_filters(query).unwrap_err();
+        assert_eq!(invalid("filter[amount][between]=1").key, "invalid_range");
+        assert_eq!(invalid("filter[amount][near]=1").key, "unknown_structured_operator");
+        assert_eq!(invalid("filter[amount]gte=1").key, "invalid_structured_filter");
+        assert_eq!(invalid("filter[note][is]=empty").key, "invalid_structured_null");
+        assert_eq!(invalid("filter[amount][eq]=").key, "invalid_filter_format");
+        assert!(!has_structured_filters(&TestRequest::with_uri("/loans?page=2").to_http_request()));
+    }
+
+    #[test]
+    fn ranges_include_both_ends() {
+        let columns = HashMap::from([
+            ("amount".to_string(), ("integer".to_string(), false)),
+            ("created".to_string(), ("date".to_string(), false)),
+            ("code".to_string(), ("text".to_string(), false)),
+        ]);
+        let req = TestRequest::default().to_http_request();
+        let where_clause = |filters: &str| {
+            let filters = parse_multiple_filters(filters)?;
+            finish_filters_with(&req, filters, None, &test_config(), "loans", &columns).map(|f| build_where_clause(&f))
+        };
+        assert_eq!(
+            where_clause("amount=between.(1,10)&created=not.between.(2024-01-01,2024-02-01)").unwrap(),
+            " WHERE amount BETWEEN ($1::bigint[])[1] AND ($1::bigint[])[2] \
+             AND created NOT BETWEEN ($2::date[])[1] AND ($2::date[])[2]"
+        );
+        assert_eq!(
+            where_clause("amount=5..&created=..2024-02-01").unwrap(),
+            " WHERE amount >= $1::bigint AND created <= $2::date"
+        );
+        // Text may contain `..`
+        assert_eq!(where_clause("code=a..b").unwrap(), " WHERE code::text = $1::text");
+        assert_eq!(where_clause("amount=between.(1)").unwrap_err().key, "invalid_range");
+        let req = TestRequest::with_uri("/loans?filter[amount][between]=1,10").to_http_request();
+        assert_eq!(
+            build_where_clause(&request_filters(&req, None).unwrap()),
+            " WHERE amount::text BETWEEN ($1::text[])[1] AND ($1::text[])[2]"
+        );
+    }
+
+    #[test]
+    fn sort_modifiers_place_nulls_and_ignore_case() {
+        let config = test_config();
+        let (sql, modifiers) = parse_sort(&config, "orders", "name:ci:desc:nullslast").unwrap();
+        assert_eq!(sql, "lower((name)::text)");
+        assert_eq!(modifiers.direction.as_deref(), Some("DESC"));
+        assert_eq!(modifiers.nulls, Some("LAST"));
+        assert!(modifiers.case_insensitive);
+        let (expr, modifiers) = split_sort_modifiers("due:NullsFirst").unwrap();
+        assert_eq!((expr, modifiers.direction, modifiers.nulls), ("due", None, Some("FIRST")));
+        assert_eq!(split_sort_modifiers("due:nulls").unwrap_err().key, "invalid_sort_modifier");
+    }
+
+    #[test]
+    fn soft_deleted_rows_are_filtered_unless_the_admin_includes_them() {
+        let config = config_with(serde_json::json!({ "loans": { "soft_delete": "Deleted_At" } }));
+        let where_clause = |req: &HttpRequest, table: &str| {
+            finish_filters_with(req, parse_multiple_filters("id=1").unwrap(), None, &config, table, &HashMap::new())
+                .map(|f| build_where_clause(&f))
+        };
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(where_clause(&req, "\"loans\"").unwrap(), " WHERE id::text = $1::text AND (\"Deleted_At\" IS NULL)");
+        assert_eq!(live_rows(&req, &config, "\"loans\"").unwrap().as_deref(), Some("\"Deleted_At\" IS NULL"));
+        assert_eq!(live_rows(&req, &config, "customers").unwrap(), None);
+        assert_eq!(where_clause(&req, "customers").unwrap(), " WHERE id::text = $1::text");
+
+        let req = TestRequest::with_uri("/loans?include_deleted=true").to_http_request();
+        assert_eq!(where_clause(&req, "loans").unwrap_err().key, "include_deleted_admin_only");
+        let req = TestRequest::with_uri("/loans?include_deleted=true")
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_http_request();
+        assert_eq!(where_clause(&req, "loans").unwrap(), " WHERE id::text = $1::text");
+    }
+}
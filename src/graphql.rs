@@ -0,0 +1,1301 @@
+use std::collections::HashMap;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use serde::Deserialize;
+use sqlx::postgres::PgRow;
+use sqlx::{PgPool, Row};
+use crate::access::{fetch_table_names, quota_page_size, row_meter, Exposure, TokenGrants};
+use crate::background::{ChangeEvent, ChangeFeed, CHANGE_FEED_KEEPALIVE};
+use crate::config::Config;
+use crate::filters::{
+    bind_filters, build_where_clause, finish_filters, FilterCondition, FilterExpr, FilterValue,
+};
+use crate::hooks::{request_hooks, Hooks};
+use crate::messages::{error_response, render_message, Message};
+use crate::middleware::{current_request_id, note_read, CircuitBreaker, Replica};
+use crate::query::{
+    apply_claim_settings, begin_table_transaction, claim_settings, fetch_column_types,
+    parse_select, tag_query, RowCounts,
+};
+use crate::serialization::{protect_columns, row_to_json};
+use crate::writes::{database_error_response, json_to_text};
+
+// GraphQL: `POST /_graphql` runs queries over the tables and `GET /_graphql`
+// serves the generated schema as SDL. Every table is a query field taking
+// `where`, `order_by`, `limit` and `offset`, and single-column foreign keys
+// are nested fields both ways. Tables with a change feed are subscription
+// fields too. No mutations, fragments, directives or introspection.
+
+pub(crate) const GRAPHQL_PATH: &str = "/_graphql";
+
+// Each nesting level is one more query
+pub(crate) const GRAPHQL_MAX_DEPTH: usize = 5;
+
+// Rank column of the per-parent limit of nested lists
+pub(crate) const GRAPHQL_RANK_COLUMN: &str = "__datapi_rank";
+
+#[derive(Debug, Clone)]
+pub(crate) enum GraphqlValue {
+    Null,
+    Bool(bool),
+    Number(serde_json::Number),
+    String(String),
+    Enum(String),
+    List(Vec<GraphqlValue>),
+    Object(Vec<(String, GraphqlValue)>),
+    Variable(String),
+}
+
+impl GraphqlValue {
+    // The value as JSON, with variables substituted and enum values as
+    // strings
+    pub(crate) fn resolve(&self, variables: &serde_json::Map<String, serde_json::Value>) -> serde_json::Value {
+        match self {
+            GraphqlValue::Null => serde_json::Value::Null,
+            GraphqlValue::Bool(b) => serde_json::Value::Bool(*b),
+            GraphqlValue::Number(n) => serde_json::Value::Number(n.clone()),
+            GraphqlValue::String(s) | GraphqlValue::Enum(s) => serde_json::Value::String(s.clone()),
+            GraphqlValue::List(items) => items.iter().map(|v| v.resolve(variables)).collect(),
+            GraphqlValue::Object(fields) => fields
+                .iter()
+                .map(|(name, v)| (name.clone(), v.resolve(variables)))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+            GraphqlValue::Variable(name) => variables.get(name).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct GraphqlField {
+    pub(crate) alias: Option<String>,
+    pub(crate) name: String,
+    pub(crate) arguments: Vec<(String, GraphqlValue)>,
+    pub(crate) selection: Vec<GraphqlField>,
+}
+
+impl GraphqlField {
+    // The response key
+    pub(crate) fn key(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.name)
+    }
+}
+
+// The query or subscription of a document and the defaults of its
+// variables
+pub(crate) struct GraphqlOperation {
+    pub(crate) selection: Vec<GraphqlField>,
+    pub(crate) defaults: Vec<(String, GraphqlValue)>,
+    pub(crate) subscription: bool,
+}
+
+// Recursive descent over the query document. Commas and comments are
+// ignored like whitespace.
+pub(crate) struct GraphqlParser {
+    pub(crate) chars: Vec<char>,
+    pub(crate) pos: usize,
+}
+
+impl GraphqlParser {
+    pub(crate) fn parse(query: &str) -> Result<GraphqlOperation, Message> {
+        let mut parser = GraphqlParser { chars: query.chars().collect(), pos: 0 };
+        parser.document().map_err(|reason| {
+            Message::new("graphql_syntax").arg("position", parser.pos).arg("reason", reason)
+        })
+    }
+
+    pub(crate) fn skip_ignored(&mut self) {
+        while let Some(&c) = self.chars.get(self.pos) {
+            match c {
+                '#' => {
+                    while self.chars.get(self.pos).is_some_and(|&c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                c if c.is_whitespace() || c == ',' || c == '\u{feff}' => self.pos += 1,
+                _ => break,
+            }
+        }
+    }
+
+    pub(crate) fn peek(&mut self) -> Option<char> {
+        self.skip_ignored();
+        self.chars.get(self.pos).copied()
+    }
+
+    pub(crate) fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    pub(crate) fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.eat(c) {
+            true => Ok(()),
+            false => Err(format!("expected '{}'", c)),
+        }
+    }
+
+    pub(crate) fn name(&mut self) -> Result<String, String> {
+        self.skip_ignored();
+        let start = self.pos;
+        while let Some(&c) = self.chars.get(self.pos) {
+            let valid = c == '_' || c.is_ascii_alphabetic() || (self.pos > start && c.is_ascii_digit());
+            if !valid {
+                break;
+            }
+            self.pos += 1;
+        }
+        match self.pos > start {
+            true => Ok(self.chars[start..self.pos].iter().collect()),
+            false => Err("expected a name".to_string()),
+        }
+    }
+
+    pub(crate) fn document(&mut self) -> Result<GraphqlOperation, String> {
+        let mut defaults = Vec::new();
+        let mut subscription = false;
+        if self.peek() != Some('{') {
+            match self.name()?.as_str() {
+                "query" => {}
+                "subscription" => subscription = true,
+                "mutation" => return Err("mutations are not supported".to_string()),
+                "fragment" => return Err("fragments are not supported".to_string()),
+                other => return Err(format!("unexpected {}", other)),
+            }
+            if self.peek().is_some_and(|c| c == '_' || c.is_ascii_alphabetic()) {
+                self.name()?;
+            }
+            if self.eat('(') {
+                while !self.eat(')') {
+                    self.expect('$')?;
+                    let name = self.name()?;
+                    self.expect(':')?;
+                    self.skip_type()?;
+                    if self.eat('=') {
+                        defaults.push((name, self.value()?));
+                    }
+                }
+            }
+        }
+        if self.peek() == Some('@') {
+            return Err("directives are not supported".to_string());
+        }
+        let selection = self.selection_set()?;
+        if self.peek().is_some() {
+            return Err("only one operation per document is supported".to_string());
+        }
+        Ok(GraphqlOperation { selection, defaults, subscription })
+    }
+
+    // Variable types aren't checked, the SQL casts the values
+    pub(crate) fn skip_type(&mut self) -> Result<(), String> {
+        if self.eat('[') {
+            self.skip_type()?;
+            self.expect(']')?;
+        } else {
+            self.name()?;
+        }
+        self.eat('!');
+        Ok(())
+    }
+
+    pub(crate) fn selection_set(&mut self) -> Result<Vec<GraphqlField>, String> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        while !self.eat('}') {
+            if self.peek().is_none() {
+                return Err("expected '}'".to_string());
+            }
+            if self.peek() == Some('.') {
+                return Err("fragments are not supported".to_string());
+            }
+            let mut name = self.name()?;
+            let mut alias = None;
+            if self.eat(':') {
+                alias = Some(name);
+                name = self.name()?;
+            }
+            let mut arguments = Vec::new();
+            if self.eat('(') {
+                while !self.eat(')') {
+                    let argument = self.name()?;
+                    self.expect(':')?;
+                    arguments.push((argument, self.value()?));
+                }
+            }
+            if self.peek() == Some('@') {
+                return Err("directives are not supported".to_string());
+            }
+            let selection = match self.peek() {
+                Some('{') => self.selection_set()?,
+                _ => Vec::new(),
+            };
+            fields.push(GraphqlField { alias, name, arguments, selection });
+        }
+        Ok(fields)
+    }
+
+    pub(crate) fn value(&mut self) -> Result<GraphqlValue, String> {
+        match self.peek() {
+            Some('$') => {
+                self.pos += 1;
+                Ok(GraphqlValue::Variable(self.name()?))
+            }
+            Some('"') => self.string().map(GraphqlValue::String),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                while !self.eat(']') {
+                    if self.peek().is_none() {
+                        return Err("expected ']'".to_string());
+                    }
+                    items.push(self.value()?);
+                }
+                Ok(GraphqlValue::List(items))
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                while !self.eat('}') {
+                    let name = self.name()?;
+                    self.expect(':')?;
+                    fields.push((name, self.value()?));
+                }
+                Ok(GraphqlValue::Object(fields))
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => {
+                let start = self.pos;
+                while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit() || "-+.eE".contains(*c)) {
+                    self.pos += 1;
+                }
+                let text: String = self.chars[start..self.pos].iter().collect();
+                serde_json::from_str(&text)
+                    .map(GraphqlValue::Number)
+                    .map_err(|_| format!("invalid number {}", text))
+            }
+            Some(_) => Ok(match self.name()?.as_str() {
+                "true" => GraphqlValue::Bool(true),
+                "false" => GraphqlValue::Bool(false),
+                "null" => GraphqlValue::Null,
+                other => GraphqlValue::Enum(other.to_string()),
+            }),
+            None => Err("expected a value".to_string()),
+        }
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String, String> {
+        let block = self.chars[self.pos..].starts_with(&['"', '"', '"']);
+        if block {
+            self.pos += 3;
+            let start = self.pos;
+            while !self.chars[self.pos..].starts_with(&['"', '"', '"']) {
+                if self.pos >= self.chars.len() {
+                    return Err("unterminated string".to_string());
+                }
+                self.pos += 1;
+            }
+            let text = self.chars[start..self.pos].iter().collect();
+            self.pos += 3;
+            return Ok(text);
+        }
+        self.pos += 1;
+        let mut text = String::new();
+        loop {
+            let Some(&c) = self.chars.get(self.pos) else {
+                return Err("unterminated string".to_string());
+            };
+            self.pos += 1;
+            match c {
+                '"' => return Ok(text),
+                '\n' => return Err("unterminated string".to_string()),
+                '\\' => {
+                    let escaped = self.chars.get(self.pos).copied();
+                    self.pos += 1;
+                    match escaped {
+                        Some('n') => text.push('\n'),
+                        Some('t') => text.push('\t'),
+                        Some('r') => text.push('\r'),
+                        Some('b') => text.push('\u{8}'),
+                        Some('f') => text.push('\u{c}'),
+                        Some('u') => {
+                            let hex: String = self.chars.iter().skip(self.pos).take(4).collect();
+                            let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                            text.push(c.ok_or_else(|| format!("invalid escape \\u{}", hex))?);
+                            self.pos += 4;
+                        }
+                        Some(c @ ('"' | '\\' | '/')) => text.push(c),
+                        _ => return Err("invalid escape".to_string()),
+                    }
+                }
+                c => text.push(c),
+            }
+        }
+    }
+}
+
+// A single-column foreign key seen from one of its tables: a nested object
+// from the referencing side, a nested list from the referenced side
+#[derive(Debug, Clone)]
+pub(crate) struct GraphqlRelation {
+    pub(crate) name: String,
+    pub(crate) many: bool,
+    // Column of this table
+    pub(crate) column: String,
+    pub(crate) target: String,
+    pub(crate) target_column: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct GraphqlTable {
+    pub(crate) columns: HashMap<String, (String, bool)>,
+    pub(crate) relations: Vec<GraphqlRelation>,
+}
+
+impl GraphqlTable {
+    pub(crate) fn relation(&self, name: &str) -> Option<&GraphqlRelation> {
+        self.relations.iter().find(|r| r.name == name)
+    }
+}
+
+pub(crate) fn is_graphql_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+// The table's columns and relations to other served tables. A relation is
+// named after its column without `_id` (or the referenced table), a list
+// after the referencing table; names that would clash get the column added.
+pub(crate) async fn fetch_graphql_table(
+    pool: &PgPool,
+    config: &Config,
+    tables: &[String],
+    table: &str,
+) -> Result<GraphqlTable, sqlx::Error> {
+    let mut columns = fetch_column_types(pool, table).await?;
+    if let Some(table_config) = config.table(table) {
+        for name in table_config.virtual_columns.keys() {
+            columns.insert(name.clone(), ("text".to_string(), false));
+        }
+    }
+    let keys = sqlx::query(
+        "SELECT src.relname::text AS source, sa.attname::text AS source_column,
+                dst.relname::text AS target, da.attname::text AS target_column,
+                con.conrelid = to_regclass($1) AS outgoing, con.confrelid = to_regclass($1) AS incoming
+         FROM pg_constraint con
+         JOIN pg_class src ON src.oid = con.conrelid
+         JOIN pg_class dst ON dst.oid = con.confrelid
+         JOIN pg_attribute sa ON sa.attrelid = con.conrelid AND sa.attnum = con.conkey[1]
+         JOIN pg_attribute da ON da.attrelid = con.confrelid AND da.attnum = con.confkey[1]
+         WHERE con.contype = 'f' AND cardinality(con.conkey) = 1
+           AND (con.conrelid = to_regclass($1) OR con.confrelid = to_regclass($1))
+         ORDER BY con.conname",
+    )
+    .bind(table)
+    .fetch_all(pool)
+    .await?;
+
+    let mut relations: Vec<GraphqlRelation> = Vec::new();
+    let taken = |relations: &[GraphqlRelation], name: &str| {
+        columns.contains_key(name) || relations.iter().any(|r| r.name == name)
+    };
+    for key in &keys {
+        let source: String = key.try_get("source")?;
+        let source_column: String = key.try_get("source_column")?;
+        let target: String = key.try_get("target")?;
+        let target_column: String = key.try_get("target_column")?;
+        if key.try_get("outgoing")? && tables.contains(&target) {
+            let base = source_column.strip_suffix("_id").filter(|b| !b.is_empty()).unwrap_or(&target);
+            let name = match taken(&relations, base) {
+                true => format!("{}_{}", source_column, target),
+                false => base.to_string(),
+            };
+            relations.push(GraphqlRelation {
+                name,
+                many: false,
+                column: source_column.clone(),
+                target: target.clone(),
+                target_column: target_column.clone(),
+            });
+        }
+        if key.try_get("incoming")? && tables.contains(&source) {
+            let siblings = keys.iter().filter(|k| {
+                k.try_get::<bool, _>("incoming").unwrap_or(false)
+                    && k.try_get::<String, _>("source").is_ok_and(|s| s == source)
+            });
+            let name = match siblings.count() > 1 || taken(&relations, &source) {
+                true => format!("{}_by_{}", source, source_column),
+                false => source.clone(),
+            };
+            relations.push(GraphqlRelation { name, many: true, column: target_column, target: source, target_column: source_column });
+        }
+    }
+    relations.retain(|r| is_graphql_name(&r.name));
+    Ok(GraphqlTable { columns, relations })
+}
+
+pub(crate) fn exposed_tables(mut tables: Vec<String>, exposure: Option<&Exposure>) -> Vec<String> {
+    if let Some(exposure) = exposure {
+        tables.retain(|table| exposure.is_exposed(table));
+    }
+    tables
+}
+
+pub(crate) enum GraphqlError {
+    Request(Message),
+    Database(sqlx::Error),
+}
+
+impl From<Message> for GraphqlError {
+    fn from(message: Message) -> Self {
+        GraphqlError::Request(message)
+    }
+}
+
+impl From<sqlx::Error> for GraphqlError {
+    fn from(e: sqlx::Error) -> Self {
+        GraphqlError::Database(e)
+    }
+}
+
+// A nested field's rows are those whose `column` is one of `values`, each
+// row keyed by its value
+pub(crate) struct GraphqlLink {
+    pub(crate) column: String,
+    pub(crate) values: Vec<String>,
+    pub(crate) many: bool,
+}
+
+pub(crate) struct GraphqlContext<'a> {
+    pub(crate) req: &'a HttpRequest,
+    pub(crate) pool: &'a PgPool,
+    pub(crate) config: &'a Config,
+    // The exposed tables
+    pub(crate) tables: Vec<String>,
+    pub(crate) variables: serde_json::Map<String, serde_json::Value>,
+    pub(crate) settings: Vec<(String, String)>,
+    pub(crate) loaded: HashMap<String, std::rc::Rc<GraphqlTable>>,
+}
+
+impl GraphqlContext<'_> {
+    // Whether the token's grants allow reading the table, like a GET of it
+    pub(crate) fn readable(&self, table: &str) -> bool {
+        self.req
+            .extensions()
+            .get::<TokenGrants>()
+            .is_none_or(|grants| grants.allows(table, &actix_web::http::Method::GET))
+    }
+
+    pub(crate) async fn table(&mut self, table: &str) -> Result<std::rc::Rc<GraphqlTable>, sqlx::Error> {
+        if let Some(loaded) = self.loaded.get(table) {
+            return Ok(loaded.clone());
+        }
+        let loaded = std::rc::Rc::new(fetch_graphql_table(self.pool, self.config, &self.tables, table).await?);
+        self.loaded.insert(table.to_string(), loaded.clone());
+        Ok(loaded)
+    }
+
+    pub(crate) fn argument(&self, field: &GraphqlField, name: &str) -> Option<serde_json::Value> {
+        field
+            .arguments
+            .iter()
+            .find(|(argument, _)| argument == name)
+            .map(|(_, value)| value.resolve(&self.variables))
+            .filter(|value| !value.is_null())
+    }
+}
+
+// Translates a `where` object: columns map to comparisons, `_and` and `_or`
+// to lists of filters.
+pub(crate) fn graphql_filter(value: &serde_json::Value, columns: &HashMap<String, (String, bool)>) -> Result<FilterExpr, String> {
+    let serde_json::Value::Object(object) = value else {
+        return Err("expected an object".to_string());
+    };
+    let mut children = Vec::new();
+    for (key, value) in object {
+        if key == "_and" || key == "_or" {
+            let serde_json::Value::Array(items) = value else {
+                return Err(format!("{} expects a list", key));
+            };
+            let items = items
+                .iter()
+                .map(|item| graphql_filter(item, columns))
+                .collect::<Result<Vec<_>, _>>()?;
+            // An empty filter matches every row
+            if key == "_or" && !items.is_empty() && !items.iter().any(FilterExpr::is_empty) {
+                children.push(FilterExpr::Or(items));
+            } else if key == "_and" {
+                children.extend(items.into_iter().filter(|item| !item.is_empty()));
+            }
+            continue;
+        }
+        if !columns.contains_key(key) {
+            return Err(format!("unknown column {}", key));
+        }
+        let serde_json::Value::Object(comparisons) = value else {
+            return Err(format!("{} expects an object of comparisons", key));
+        };
+        for (op, operand) in comparisons {
+            let condition = |operator: &str, value: FilterValue| {
+                FilterExpr::Condition(FilterCondition { column: key.clone(), operator: operator.to_string(), value, cast: None })
+            };
+            let single = || json_to_text(operand).ok_or_else(|| format!("{}.{} expects a value, use is_null", key, op));
+            let list = || match operand {
+                serde_json::Value::Array(items) => Ok(items.iter().filter_map(json_to_text).collect::<Vec<_>>()),
+                _ => Err(format!("{}.{} expects a list", key, op)),
+            };
+            children.push(match op.as_str() {
+                "eq" => condition("=", FilterValue::Single(single()?)),
+                "neq" => condition("!=", FilterValue::Single(single()?)),
+                "gt" => condition(">", FilterValue::Single(single()?)),
+                "gte" => condition(">=", FilterValue::Single(single()?)),
+                "lt" => condition("<", FilterValue::Single(single()?)),
+                "lte" => condition("<=", FilterValue::Single(single()?)),
+                "like" => condition("LIKE", FilterValue::Single(single()?)),
+                "ilike" => condition("ILIKE", FilterValue::Single(single()?)),
+                "in" => condition("IN", FilterValue::List(list()?)),
+                "nin" => condition("NOT IN", FilterValue::List(list()?)),
+                "between" => match list()? {
+                    bounds if bounds.len() == 2 => condition("BETWEEN", FilterValue::List(bounds)),
+                    _ => return Err(format!("{}.between expects a list of two bounds", key)),
+                },
+                "is_null" => match operand {
+                    serde_json::Value::Bool(true) => condition("IS", FilterValue::Null),
+                    serde_json::Value::Bool(false) => condition("IS NOT", FilterValue::Null),
+                    _ => return Err(format!("{}.is_null expects a boolean", key)),
+                },
+                other => return Err(format!("unknown comparison {}", other)),
+            });
+        }
+    }
+    Ok(FilterExpr::And(children))
+}
+
+// Translates `order_by`: an object of columns to asc or desc, or a list of
+// them for the sort priority.
+pub(crate) fn graphql_order(value: &serde_json::Value, config: &Config, table: &str, columns: &HashMap<String, (String, bool)>) -> Result<Vec<String>, String> {
+    let items = match value {
+        serde_json::Value::Array(items) => items.iter().collect(),
+        other => vec![other],
+    };
+    let mut order = Vec::new();
+    for item in items {
+        let serde_json::Value::Object(object) = item else {
+            return Err("expected an object of columns".to_string());
+        };
+        for (column, direction) in object {
+            if !columns.contains_key(column) {
+                return Err(format!("unknown column {}", column));
+            }
+            if config.mask(table, column).is_some() {
+                return Err(format!("{} is masked", column));
+            }
+            let direction = match direction.as_str() {
+                Some("asc") => "ASC",
+                Some("desc") => "DESC",
+                _ => return Err(format!("{} expects asc or desc", column)),
+            };
+            order.push(format!("{} {}", config.column_sql(table, column), direction));
+        }
+    }
+    Ok(order)
+}
+
+pub(crate) type GraphqlRows = Vec<(Option<String>, serde_json::Map<String, serde_json::Value>)>;
+
+// The output objects of a table field, each with its link value when
+// nested. Nested fields are fetched with one query per level for all the
+// parent rows.
+pub(crate) fn graphql_rows<'a>(
+    ctx: &'a mut GraphqlContext<'_>,
+    table: &'a str,
+    field: &'a GraphqlField,
+    link: Option<GraphqlLink>,
+    depth: usize,
+) -> futures_util::future::LocalBoxFuture<'a, Result<GraphqlRows, GraphqlError>> {
+    Box::pin(async move {
+        if depth > GRAPHQL_MAX_DEPTH {
+            return Err(Message::new("graphql_too_deep").arg("max", GRAPHQL_MAX_DEPTH).into());
+        }
+        if !ctx.readable(table) {
+            return Err(Message::new("table_not_granted").arg("access", "read").arg("table", table).into());
+        }
+        let info = ctx.table(table).await?;
+        let invalid = |argument: &str, reason: String| {
+            Message::new("graphql_invalid_argument").arg("argument", argument).arg("field", &field.name).arg("reason", reason)
+        };
+        if field.selection.is_empty() {
+            return Err(invalid("selection", "a table field needs a selection of columns".to_string()).into());
+        }
+
+        let mut needed: Vec<&str> = Vec::new();
+        for sub in &field.selection {
+            if sub.name == "__typename" {
+                continue;
+            }
+            if info.columns.contains_key(&sub.name) && sub.selection.is_empty() {
+                needed.push(&sub.name);
+            } else if let Some(relation) = info.relation(&sub.name) {
+                needed.push(&relation.column);
+            } else {
+                return Err(Message::new("graphql_unknown_field").arg("field", &sub.name).arg("type", table).into());
+            }
+        }
+        if let Some(link) = &link {
+            needed.push(&link.column);
+        }
+        needed.sort();
+        needed.dedup();
+        // A selection of only __typename still reads the rows
+        let select = match needed.is_empty() {
+            true => "1 AS __typename".to_string(),
+            false => parse_select(ctx.config, table, Some(&needed.join(",")), &[], false)?,
+        };
+
+        let mut filters = match ctx.argument(field, "where") {
+            Some(value) => graphql_filter(&value, &info.columns).map_err(|reason| invalid("where", reason))?,
+            None => FilterExpr::none(),
+        };
+        if let Some(link) = &link {
+            let condition = FilterCondition {
+                column: link.column.clone(),
+                operator: "IN".to_string(),
+                value: FilterValue::List(link.values.clone()),
+                cast: None,
+            };
+            filters = match filters {
+                FilterExpr::And(mut children) => {
+                    children.push(FilterExpr::Condition(condition));
+                    FilterExpr::And(children)
+                }
+                other => FilterExpr::And(vec![other, FilterExpr::Condition(condition)]),
+            };
+        }
+        let filters = finish_filters(ctx.req, ctx.pool, filters, None, ctx.config, table).await?;
+        let order = match ctx.argument(field, "order_by") {
+            Some(value) => graphql_order(&value, ctx.config, table, &info.columns).map_err(|reason| invalid("order_by", reason))?,
+            None => Vec::new(),
+        };
+        let number = |name: &str| match ctx.argument(field, name) {
+            Some(value) => value.as_u64().map(|n| Some(n as usize)).ok_or_else(|| invalid(name, "expected a non-negative integer".to_string())),
+            None => Ok(None),
+        };
+        let limit = quota_page_size(ctx.req, ctx.config.table_page_size(table, number("limit")?));
+        let offset = number("offset")?.unwrap_or(0);
+
+        let where_clause = build_where_clause(&filters);
+        let order_clause = match order.is_empty() {
+            true => String::new(),
+            false => format!(" ORDER BY {}", order.join(", ")),
+        };
+        let query = match &link {
+            Some(link) if !link.many => format!("SELECT {} FROM {}{}", select, table, where_clause),
+            Some(link) => format!(
+                "SELECT * FROM (SELECT {}, row_number() OVER (PARTITION BY {}{}) AS {} FROM {}{}) ranked WHERE {} > {} AND {} <= {}{}",
+                select,
+                link.column,
+                order_clause,
+                GRAPHQL_RANK_COLUMN,
+                table,
+                where_clause,
+                GRAPHQL_RANK_COLUMN,
+                offset,
+                GRAPHQL_RANK_COLUMN,
+                offset + limit,
+                match order.is_empty() {
+                    true => String::new(),
+                    false => format!(" ORDER BY {}", GRAPHQL_RANK_COLUMN),
+                }
+            ),
+            None => format!("SELECT {} FROM {}{}{} LIMIT {} OFFSET {}", select, table, where_clause, order_clause, limit, offset),
+        };
+        let query = tag_query(table, &query);
+        log::info!("Executing GraphQL field {}: {}", field.name, query);
+
+        let mut tx = begin_table_transaction(ctx.pool, ctx.config, table).await?;
+        apply_claim_settings(&mut tx, &ctx.settings).await?;
+        let rows = bind_filters(sqlx::query(&query), &filters).fetch_all(&mut *tx).await?;
+        tx.commit().await?;
+        note_read(ctx.req, table, Vec::new(), rows.len());
+        let mut rows: Vec<serde_json::Map<String, serde_json::Value>> = rows
+            .iter()
+            .map(|row| {
+                let mut obj = row_to_json(row, ctx.config.value_format());
+                protect_columns(ctx.config, table, &mut obj);
+                obj
+            })
+            .collect();
+        request_hooks(ctx.req).after_rows(table, &mut rows);
+
+        for sub in &field.selection {
+            let Some(relation) = info.relation(&sub.name).filter(|_| sub.name != "__typename") else {
+                continue;
+            };
+            let mut values: Vec<String> = rows.iter().filter_map(|row| row.get(&relation.column).and_then(json_to_text)).collect();
+            values.sort();
+            values.dedup();
+            let children = match values.is_empty() {
+                true => Vec::new(),
+                false => {
+                    let link = GraphqlLink { column: relation.target_column.clone(), values, many: relation.many };
+                    graphql_rows(ctx, &relation.target, sub, Some(link), depth + 1).await?
+                }
+            };
+            for row in &mut rows {
+                let value = row.get(&relation.column).and_then(json_to_text);
+                let mut matching = children.iter().filter(|(key, _)| value.is_some() && *key == value).map(|(_, obj)| serde_json::Value::Object(obj.clone()));
+                let nested = match relation.many {
+                    true => serde_json::Value::Array(matching.collect()),
+                    false => matching.next().unwrap_or_default(),
+                };
+                row.insert(format!("{}{}", GRAPHQL_RANK_COLUMN, sub.key()), nested);
+            }
+        }
+
+        Ok(rows
+            .into_iter()
+            .map(|mut row| {
+                let key = link.as_ref().and_then(|link| row.get(&link.column).and_then(json_to_text));
+                let mut out = serde_json::Map::new();
+                for sub in &field.selection {
+                    let value = match sub.name.as_str() {
+                        "__typename" => serde_json::Value::String(table.to_string()),
+                        _ if info.relation(&sub.name).is_some() => {
+                            row.remove(&format!("{}{}", GRAPHQL_RANK_COLUMN, sub.key())).unwrap_or_default()
+                        }
+                        name => row.get(name).cloned().unwrap_or_default(),
+                    };
+                    out.insert(sub.key().to_string(), value);
+                }
+                (key, out)
+            })
+            .collect())
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct GraphqlRequest {
+    pub(crate) query: String,
+    #[serde(default)]
+    pub(crate) variables: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+// The code and request ID go in the error's `extensions`, as GraphQL
+// clients expect
+pub(crate) fn graphql_error(req: &HttpRequest, status: StatusCode, message: Message) -> HttpResponse {
+    let (body, locale) = graphql_error_body(req, &message);
+    HttpResponse::build(status)
+        .insert_header(("Content-Language", locale.unwrap_or("en")))
+        .json(body)
+}
+
+pub(crate) fn graphql_error_body<'a>(req: &'a HttpRequest, message: &Message) -> (serde_json::Value, Option<&'a str>) {
+    let (text, locale) = render_message(req, message);
+    let mut extensions = serde_json::Map::new();
+    extensions.insert("code".to_string(), serde_json::json!(message.code()));
+    if let Some(id) = current_request_id() {
+        extensions.insert("request_id".to_string(), serde_json::json!(id));
+    }
+    (serde_json::json!({ "errors": [{ "message": text, "extensions": extensions }] }), locale)
+}
+
+pub(crate) fn graphql_failure(req: &HttpRequest, breaker: &CircuitBreaker, error: GraphqlError) -> HttpResponse {
+    match error {
+        GraphqlError::Request(message) => graphql_error(req, StatusCode::BAD_REQUEST, message),
+        GraphqlError::Database(e) => {
+            log::error!("GraphQL query error: {}", e);
+            breaker.record_error(&e);
+            graphql_error(req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"))
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn graphql_query(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    replica: web::Data<Replica>,
+    breaker: web::Data<CircuitBreaker>,
+    exposure: Option<web::Data<Exposure>>,
+    feed: Option<web::Data<ChangeFeed>>,
+    body: web::Json<GraphqlRequest>,
+) -> impl Responder {
+    let body = body.into_inner();
+    let operation = match GraphqlParser::parse(&body.query) {
+        Ok(operation) => operation,
+        Err(message) => return graphql_error(&req, StatusCode::BAD_REQUEST, message),
+    };
+    let mut variables = body.variables.unwrap_or_default();
+    for (name, default) in &operation.defaults {
+        if !variables.contains_key(name) {
+            variables.insert(name.clone(), default.resolve(&serde_json::Map::new()));
+        }
+    }
+    let pool = match replica.read_pool(&req, &pool).await {
+        Ok(p) => p,
+        Err(e) => return graphql_error(&req, StatusCode::BAD_REQUEST, e),
+    };
+    let tables = match fetch_table_names(&pool).await {
+        Ok(tables) => exposed_tables(tables, exposure.as_ref().map(|e| e.get_ref())),
+        Err(e) => return database_error_response(&req, &breaker, e),
+    };
+    let mut ctx = GraphqlContext {
+        req: &req,
+        pool: &pool,
+        config: &config,
+        tables,
+        variables,
+        settings: claim_settings(&req, &config),
+        loaded: HashMap::new(),
+    };
+    if operation.subscription {
+        return match graphql_subscription(&mut ctx, feed, &operation).await {
+            Ok(subscription) => subscription.into_response(&req, config.clone(), breaker.clone()),
+            Err(e) => graphql_failure(&req, &breaker, e),
+        };
+    }
+
+    let mut data = serde_json::Map::new();
+    for field in &operation.selection {
+        if field.name == "__typename" {
+            data.insert(field.key().to_string(), serde_json::json!("Query"));
+            continue;
+        }
+        if !ctx.tables.contains(&field.name) {
+            let message = Message::new("graphql_unknown_field").arg("field", &field.name).arg("type", "Query");
+            return graphql_error(&req, StatusCode::BAD_REQUEST, message);
+        }
+        match graphql_rows(&mut ctx, &field.name, field, None, 1).await {
+            Ok(rows) => {
+                let rows = rows.into_iter().map(|(_, obj)| serde_json::Value::Object(obj)).collect();
+                data.insert(field.key().to_string(), serde_json::Value::Array(rows));
+            }
+            Err(e) => return graphql_failure(&req, &breaker, e),
+        }
+    }
+    breaker.record_success();
+    HttpResponse::Ok().json(serde_json::json!({ "data": data }))
+}
+
+// A subscription's table field, checked like a query of it, to be run
+// against the change feed
+pub(crate) struct GraphqlSubscription {
+    pub(crate) feed: web::Data<ChangeFeed>,
+    pub(crate) pool: PgPool,
+    pub(crate) table: String,
+    // The feed's key column, all a row too large for a notification carries
+    pub(crate) key: String,
+    pub(crate) response_key: String,
+    // Response key and column (or __typename) of each selected field
+    pub(crate) fields: Vec<(String, String)>,
+    pub(crate) select: String,
+    pub(crate) filters: FilterExpr,
+    pub(crate) settings: Vec<(String, String)>,
+}
+
+// A subscription selects one table with a change feed, its columns and
+// optionally `where`. The claim, configured and soft-delete filters apply as
+// for a query.
+pub(crate) async fn graphql_subscription(
+    ctx: &mut GraphqlContext<'_>,
+    feed: Option<web::Data<ChangeFeed>>,
+    operation: &GraphqlOperation,
+) -> Result<GraphqlSubscription, GraphqlError> {
+    let [field] = operation.selection.as_slice() else {
+        return Err(Message::new("graphql_subscription_fields").arg("count", operation.selection.len()).into());
+    };
+    let table = field.name.as_str();
+    if !ctx.tables.iter().any(|t| t == table) {
+        return Err(Message::new("graphql_unknown_field").arg("field", table).arg("type", "Subscription").into());
+    }
+    if !ctx.readable(table) {
+        return Err(Message::new("table_not_granted").arg("access", "read").arg("table", table).into());
+    }
+    let changes = ctx.config.table(table).and_then(|t| t.changes.as_ref());
+    let (Some(feed), Some(changes)) = (feed, changes) else {
+        return Err(Message::new("changes_not_configured").arg("table", table).into());
+    };
+    let unsupported = |name: &str| Message::new("graphql_subscription_unsupported").arg("field", table).arg("name", name);
+    if let Some((name, _)) = field.arguments.iter().find(|(name, _)| name != "where") {
+        return Err(unsupported(name).into());
+    }
+    let info = ctx.table(table).await?;
+    let mut fields = Vec::new();
+    for sub in &field.selection {
+        if sub.name != "__typename" && (!info.columns.contains_key(&sub.name) || !sub.selection.is_empty()) {
+            return Err(unsupported(&sub.name).into());
+        }
+        fields.push((sub.key().to_string(), sub.name.clone()));
+    }
+    if fields.is_empty() {
+        let message = Message::new("graphql_invalid_argument")
+            .arg("argument", "selection")
+            .arg("field", table)
+            .arg("reason", "a table field needs a selection of columns");
+        return Err(message.into());
+    }
+    let mut needed: Vec<&str> = fields.iter().map(|(_, name)| name.as_str()).filter(|name| *name != "__typename").collect();
+    needed.sort();
+    needed.dedup();
+    let select = match needed.is_empty() {
+        true => "1 AS __typename".to_string(),
+        false => parse_select(ctx.config, table, Some(&needed.join(",")), &[], false)?,
+    };
+    let filters = match ctx.argument(field, "where") {
+        Some(value) => graphql_filter(&value, &info.columns).map_err(|reason| {
+            Message::new("graphql_invalid_argument").arg("argument", "where").arg("field", table).arg("reason", reason)
+        })?,
+        None => FilterExpr::none(),
+    };
+    let filters = finish_filters(ctx.req, ctx.pool, filters, None, ctx.config, table).await?;
+    Ok(GraphqlSubscription {
+        feed,
+        pool: ctx.pool.clone(),
+        table: table.to_string(),
+        key: changes.key.clone(),
+        response_key: field.key().to_string(),
+        fields,
+        select,
+        filters,
+        settings: ctx.settings.clone(),
+    })
+}
+
+impl GraphqlSubscription {
+    // The selected columns of a changed row that passes the filters. Postgres
+    // evaluates them on the notified row, so they mean what they mean in a
+    // query; a row sent as its key alone is read from the table, and such
+    // deletes are left out.
+    pub(crate) async fn row(&self, config: &Config, event: &ChangeEvent) -> Result<Option<PgRow>, sqlx::Error> {
+        let mut filters = self.filters.clone();
+        let source = match event.truncated {
+            false => format!("json_populate_record(NULL::{}, ${}::json) {}", self.table, filters.param_count() + 1, self.table),
+            true if event.operation == "delete" => return Ok(None),
+            true => {
+                let Some(key) = event.row.get(&self.key).and_then(json_to_text) else {
+                    return Ok(None);
+                };
+                let condition = FilterCondition { column: self.key.clone(), operator: "=".to_string(), value: FilterValue::Single(key), cast: None };
+                filters = match filters {
+                    FilterExpr::And(mut children) => {
+                        children.push(FilterExpr::Condition(condition));
+                        FilterExpr::And(children)
+                    }
+                    other => FilterExpr::And(vec![other, FilterExpr::Condition(condition)]),
+                };
+                self.table.clone()
+            }
+        };
+        let query = tag_query(&self.table, &format!("SELECT {} FROM {}{}", self.select, source, build_where_clause(&filters)));
+        let mut tx = begin_table_transaction(&self.pool, config, &self.table).await?;
+        apply_claim_settings(&mut tx, &self.settings).await?;
+        let mut query = bind_filters(sqlx::query(&query), &filters);
+        if !event.truncated {
+            query = query.bind(serde_json::Value::Object(event.row.clone()).to_string());
+        }
+        let row = query.fetch_optional(&mut *tx).await?;
+        tx.commit().await?;
+        Ok(row)
+    }
+
+    // The `next` event of a row, the field's object as `data` and the change
+    // in `extensions`
+    pub(crate) fn frame(&self, config: &Config, hooks: &Hooks, event: &ChangeEvent, row: &PgRow) -> String {
+        let mut obj = row_to_json(row, config.value_format());
+        protect_columns(config, &self.table, &mut obj);
+        let mut rows = vec![obj];
+        hooks.after_rows(&self.table, &mut rows);
+        let row = rows.pop().unwrap_or_default();
+        let mut out = serde_json::Map::new();
+        for (key, name) in &self.fields {
+            let value = match name.as_str() {
+                "__typename" => serde_json::Value::String(self.table.clone()),
+                name => row.get(name).cloned().unwrap_or_default(),
+            };
+            out.insert(key.clone(), value);
+        }
+        let payload = serde_json::json!({
+            "data": { self.response_key.clone(): out },
+            "extensions": { "operation": event.operation },
+        });
+        format!("event: next\ndata: {}\n\n", payload)
+    }
+
+    // Streams the table's changes as server-sent events, as the GraphQL over
+    // SSE protocol's `next` events. A change that can't be checked gets a
+    // `next` event with the error instead.
+    pub(crate) fn into_response(self, req: &HttpRequest, config: web::Data<Config>, breaker: web::Data<CircuitBreaker>) -> HttpResponse {
+        let failure = format!("event: next\ndata: {}\n\n", graphql_error_body(req, &Message::new("database_error")).0);
+        let hooks = request_hooks(req);
+        let meter = row_meter(req);
+        let receiver = self.feed.sender.subscribe();
+        let subscription = std::rc::Rc::new(self);
+        let stream = futures_util::stream::unfold(receiver, move |mut receiver| {
+            let (subscription, config, breaker) = (subscription.clone(), config.clone(), breaker.clone());
+            let (hooks, meter, failure) = (hooks.clone(), meter.clone(), failure.clone());
+            async move {
+                loop {
+                    let event = match tokio::time::timeout(CHANGE_FEED_KEEPALIVE, receiver.recv()).await {
+                        Err(_) => return Some((Ok::<_, actix_web::Error>(web::Bytes::from_static(b": keepalive\n\n")), receiver)),
+                        Ok(Err(tokio::sync::broadcast::error::RecvError::Lagged(missed))) => {
+                            let data = format!("event: lagged\ndata: {{\"missed\":{}}}\n\n", missed);
+                            return Some((Ok(web::Bytes::from(data)), receiver));
+                        }
+                        Ok(Err(tokio::sync::broadcast::error::RecvError::Closed)) => return None,
+                        Ok(Ok(event)) => event,
+                    };
+                    if RowCounts::key(&event.table) != subscription.table {
+                        continue;
+                    }
+                    let frame = match subscription.row(&config, &event).await {
+                        Ok(None) => continue,
+                        Ok(Some(row)) => {
+                            breaker.record_success();
+                            if let Some(meter) = &meter {
+                                meter.record(1);
+                            }
+                            subscription.frame(&config, &hooks, &event, &row)
+                        }
+                        Err(e) => {
+                            log::error!("GraphQL subscription error: {}", e);
+                            breaker.record_error(&e);
+                            failure
+                        }
+                    };
+                    return Some((Ok(web::Bytes::from(frame)), receiver));
+                }
+            }
+        });
+        HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"))
+            // Compression would hold events back until a buffer fills
+            .insert_header((actix_web::http::header::CONTENT_ENCODING, "identity"))
+            .streaming(stream)
+    }
+}
+
+// numeric is rendered as in the REST responses, a string with
+// DATAPI_NUMERIC_STRINGS
+pub(crate) fn graphql_scalar(sql_type: &str, numeric_strings: bool) -> &'static str {
+    match sql_type {
+        t if t.ends_with("[]") => "JSON",
+        "smallint" | "integer" | "bigint" => "Int",
+        "real" | "double precision" => "Float",
+        t if t.starts_with("numeric") && !numeric_strings => "Float",
+        "boolean" => "Boolean",
+        "json" | "jsonb" => "JSON",
+        _ => "String",
+    }
+}
+
+// The schema of the tables the caller may read, as SDL
+pub(crate) async fn graphql_schema(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    exposure: Option<web::Data<Exposure>>,
+    feed: Option<web::Data<ChangeFeed>>,
+) -> impl Responder {
+    let tables = match fetch_table_names(&pool).await {
+        Ok(tables) => exposed_tables(tables, exposure.as_ref().map(|e| e.get_ref())),
+        Err(e) => {
+            log::error!("Database error: {}", e);
+            return error_response(&req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"));
+        }
+    };
+    let mut ctx = GraphqlContext {
+        req: &req,
+        pool: &pool,
+        config: &config,
+        tables,
+        variables: serde_json::Map::new(),
+        settings: Vec::new(),
+        loaded: HashMap::new(),
+    };
+    let mut names: Vec<String> = ctx.tables.iter().filter(|t| is_graphql_name(t) && ctx.readable(t)).cloned().collect();
+    names.sort();
+
+    let list_arguments = |table: &str| {
+        format!("(where: {}_filter, order_by: [{}_order_by!], limit: Int, offset: Int)", table, table)
+    };
+    let mut query = String::from("type Query {\n");
+    let mut subscription = String::new();
+    let mut types = String::new();
+    let mut scalars = std::collections::BTreeSet::new();
+    for table in &names {
+        let info = match ctx.table(table).await {
+            Ok(info) => info,
+            Err(e) => {
+                log::error!("Database error: {}", e);
+                return error_response(&req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"));
+            }
+        };
+        query.push_str(&format!("  {}{}: [{}!]!\n", table, list_arguments(table), table));
+        if feed.is_some() && config.table(table).is_some_and(|t| t.changes.is_some()) {
+            subscription.push_str(&format!("  {}(where: {}_filter): {}!\n", table, table, table));
+        }
+        let mut columns: Vec<(&String, &(String, bool))> = info.columns.iter().filter(|(name, _)| is_graphql_name(name)).collect();
+        columns.sort();
+
+        types.push_str(&format!("type {} {{\n", table));
+        for (name, (sql_type, not_null)) in &columns {
+            types.push_str(&format!("  {}: {}{}\n", name, graphql_scalar(sql_type, config.numeric_strings), if *not_null { "!" } else { "" }));
+        }
+        for relation in info.relations.iter().filter(|r| names.contains(&r.target)) {
+            match relation.many {
+                true => types.push_str(&format!("  {}{}: [{}!]!\n", relation.name, list_arguments(&relation.target), relation.target)),
+                false => types.push_str(&format!("  {}: {}\n", relation.name, relation.target)),
+            }
+        }
+        types.push_str("}\n\n");
+
+        types.push_str(&format!("input {}_filter {{\n  _and: [{}_filter!]\n  _or: [{}_filter!]\n", table, table, table));
+        for (name, (sql_type, _)) in &columns {
+            let scalar = graphql_scalar(sql_type, config.numeric_strings);
+            scalars.insert(scalar);
+            types.push_str(&format!("  {}: {}_comparison\n", name, scalar));
+        }
+        types.push_str("}\n\n");
+        types.push_str(&format!("input {}_order_by {{\n", table));
+        for (name, _) in &columns {
+            types.push_str(&format!("  {}: order_direction\n", name));
+        }
+        types.push_str("}\n\n");
+    }
+    query.push_str("}\n\n");
+    if !subscription.is_empty() {
+        query.push_str(&format!("type Subscription {{\n{}}}\n\n", subscription));
+    }
+
+    let mut sdl = String::from("scalar JSON\n\nenum order_direction {\n  asc\n  desc\n}\n\n");
+    for scalar in scalars {
+        sdl.push_str(&format!(
+            "input {s}_comparison {{\n  eq: {s}\n  neq: {s}\n  gt: {s}\n  gte: {s}\n  lt: {s}\n  lte: {s}\n  like: String\n  ilike: String\n  in: [{s}!]\n  nin: [{s}!]\n  between: [{s}!]\n  is_null: Boolean\n}}\n\n",
+            s = scalar
+        ));
+    }
+    sdl.push_str(&query);
+    sdl.push_str(&types);
+    HttpResponse::Ok().content_type("text/plain; charset=utf-8").body(sdl.trim_end().to_string() + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn graphql_queries_parse_with_aliases_arguments_and_variables() {
+        let operation = GraphqlParser::parse(
+            r#"query Loans($status: String = "open", $limit: Int) {
+                open: loans(where: { status: { eq: $status } }, limit: $limit, order_by: [{ id: desc }]) {
+                    id, customer { name } # the borrower
+                }
+                __typename
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(operation.defaults.len(), 1);
+        assert_eq!(operation.defaults[0].0, "status");
+        let loans = &operation.selection[0];
+        assert_eq!((loans.key(), loans.name.as_str()), ("open", "loans"));
+        assert_eq!(loans.selection.iter().map(GraphqlField::key).collect::<Vec<_>>(), ["id", "customer"]);
+        assert_eq!(loans.selection[1].selection[0].name, "name");
+        assert_eq!(operation.selection[1].key(), "__typename");
+
+        let variables = serde_json::json!({ "status": "late" }).as_object().unwrap().clone();
+        let arguments: Vec<serde_json::Value> = loans.arguments.iter().map(|(_, value)| value.resolve(&variables)).collect();
+        assert_eq!(
+            arguments,
+            [
+                serde_json::json!({ "status": { "eq": "late" } }),
+                serde_json::Value::Null,
+                serde_json::json!([{ "id": "desc" }]),
+            ]
+        );
+        let strings = GraphqlParser::parse(r#"{ t(a: "x\"é\n", b: """raw "quotes" """, c: -1.5e2, d: [true, null]) { id } }"#).unwrap();
+        let values: Vec<serde_json::Value> = strings.selection[0].arguments.iter().map(|(_, v)| v.resolve(&serde_json::Map::new())).collect();
+        assert_eq!(values, [serde_json::json!("x\"é\n"), serde_json::json!("raw \"quotes\" "), serde_json::json!(-150.0), serde_json::json!([true, null])]);
+
+        let reason = |query: &str| {
+            let message = GraphqlParser::parse(query).err().unwrap();
+            assert_eq!(message.key, "graphql_syntax");
+            message.args.iter().find(|(name, _)| *name == "reason").unwrap().1.clone()
+        };
+        assert_eq!(reason("mutation { loans { id } }"), "mutations are not supported");
+        assert!(GraphqlParser::parse("subscription { loans { id } }").unwrap().subscription);
+        assert!(!GraphqlParser::parse("{ loans { id } }").unwrap().subscription);
+        assert_eq!(reason("{ loans { ...fields } }"), "fragments are not supported");
+        assert_eq!(reason("{ loans @include(if: true) { id } }"), "directives are not supported");
+        assert_eq!(reason("{ loans { id } } { customers { id } }"), "only one operation per document is supported");
+        assert_eq!(reason("{ loans(where: \"open) { id } }"), "unterminated string");
+        assert_eq!(reason("{ loans { id }"), "expected '}'");
+    }
+
+    #[test]
+    fn graphql_arguments_become_filters_and_order() {
+        let columns = loan_columns();
+        let filter = |value: serde_json::Value| graphql_filter(&value, &columns).map(|filters| build_where_clause(&filters));
+        assert_eq!(
+            filter(serde_json::json!({ "id": { "gte": 2, "in": [1, 2] }, "_or": [{ "status": { "is_null": true } }, { "status": { "ilike": "o%" } }] })).unwrap(),
+            " WHERE ((status IS NULL) OR (status::text ILIKE $1::text)) AND id::text >= $2::text AND id::text = ANY($3::text[])"
+        );
+        // An empty filter matches every row, so the _or does too
+        assert_eq!(filter(serde_json::json!({ "_or": [{}, { "id": { "eq": 1 } }], "_and": [] })).unwrap(), "");
+        assert_eq!(filter(serde_json::json!({ "amount": { "eq": 1 } })).unwrap_err(), "unknown column amount");
+        assert_eq!(filter(serde_json::json!({ "id": { "eq": null } })).unwrap_err(), "id.eq expects a value, use is_null");
+        assert_eq!(filter(serde_json::json!({ "id": { "near": 1 } })).unwrap_err(), "unknown comparison near");
+        assert_eq!(filter(serde_json::json!({ "id": { "in": 1 } })).unwrap_err(), "id.in expects a list");
+        assert_eq!(filter(serde_json::json!([])).unwrap_err(), "expected an object");
+
+        let config = test_config();
+        let order = |value: serde_json::Value| graphql_order(&value, &config, "loans", &columns);
+        assert_eq!(order(serde_json::json!([{ "status": "asc" }, { "id": "desc" }])).unwrap(), ["status ASC", "id DESC"]);
+        assert_eq!(order(serde_json::json!({ "id": "up" })).unwrap_err(), "id expects asc or desc");
+
+        assert_eq!(graphql_scalar("bigint", false), "Int");
+        assert_eq!(graphql_scalar("numeric", false), "Float");
+        assert_eq!(graphql_scalar("numeric", true), "String");
+        assert_eq!(graphql_scalar("integer[]", false), "JSON");
+        assert!(is_graphql_name("loans_2") && !is_graphql_name("2loans") && !is_graphql_name("my loans"));
+    }
+
+    #[actix_web::test]
+    async fn subscriptions_select_one_table_with_a_change_feed() {
+        let config = config_with(serde_json::json!({ "loans": { "changes": {} } }));
+        let req = TestRequest::default().to_http_request();
+        let pool = offline_pool();
+        let feed = web::Data::new(ChangeFeed::new());
+        let refusal = |query: &'static str, feed: Option<web::Data<ChangeFeed>>| {
+            let (req, pool, config) = (&req, &pool, &config);
+            let operation = GraphqlParser::parse(query).unwrap();
+            async move {
+                let mut ctx = GraphqlContext {
+                    req,
+                    pool,
+                    config,
+                    tables: vec!["loans".to_string(), "customers".to_string()],
+                    variables: serde_json::Map::new(),
+                    settings: Vec::new(),
+                    loaded: HashMap::new(),
+                };
+                match graphql_subscription(&mut ctx, feed, &operation).await {
+                    Err(GraphqlError::Request(message)) => message.key,
+                    _ => panic!("{} was accepted", query),
+                }
+            }
+        };
+        let feed = || Some(feed.clone());
+        assert_eq!(refusal("subscription { loans { id } customers { id } }", feed()).await, "graphql_subscription_fields");
+        assert_eq!(refusal("subscription { notes { id } }", feed()).await, "graphql_unknown_field");
+        assert_eq!(refusal("subscription { customers { id } }", feed()).await, "changes_not_configured");
+        assert_eq!(refusal("subscription { loans { id } }", None).await, "changes_not_configured");
+        assert_eq!(refusal("subscription { loans(limit: 5) { id } }", feed()).await, "graphql_subscription_unsupported");
+    }
+}
@@ -0,0 +1,3413 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use crate::access::{
+    apply_quota, key_fingerprint, quota_page_size, resolve_table, row_meter, table_grant_denied,
+    ApiKeys, Exposure, TokenGrants,
+};
+use crate::background::SCHEMA_RELOADED;
+use crate::config::{
+    ApiScope, Config, Deprecation, Mask, RollupConfig, TableConfig, TableGrant, TemplateConfig,
+    ROLLUP_BUCKETS,
+};
+use crate::filters::{
+    bind_filters, build_where_clause, finish_filters, parse_multiple_filters,
+    parse_structured_filters, request_filters, sanitize_column_name, sanitize_table_name,
+    Aggregate, FilterExpr,
+};
+use crate::messages::{bad_request, error_response, render_message, Message};
+use crate::middleware::{
+    log_binds, note_read, take_token, CircuitBreaker, Databases, LastError, Replica, ResponseCache,
+};
+use crate::query::{
+    apply_claim_settings, apply_request_timeout, begin_table_transaction, claim_settings,
+    read_pool, record_slow_query, relation_kind_name, run_query, select_columns, tag_query,
+    unix_now, CountFailures, QueryParams, QueryResult, SchemaCache, APPLICATION_NAME,
+    RELATION_UPDATABLE_SQL, RESULT_TABLE_PREFIX,
+};
+use crate::serialization::{protect_columns, row_to_json};
+use crate::writes::{
+    database_error_response, is_statement_timeout, json_to_text, postgres_message,
+    write_error_response,
+};
+
+// ANDs the `?preset=` filters of the table, if any, with the path filters
+pub(crate) fn with_preset(config: &Config, table: &str, filters: FilterExpr, preset: Option<&str>) -> Result<FilterExpr, Message> {
+    let Some(preset) = preset else {
+        return Ok(filters);
+    };
+    let filter = config
+        .table(table)
+        .and_then(|t| t.presets.get(preset))
+        .ok_or_else(|| Message::new("unknown_preset").arg("preset", preset).arg("table", table.trim_matches('"')).param("preset"))?;
+    let preset = parse_multiple_filters(filter)?;
+    Ok(match filters {
+        FilterExpr::And(mut children) => {
+            children.push(preset);
+            FilterExpr::And(children)
+        }
+        other => FilterExpr::And(vec![other, preset]),
+    })
+}
+
+pub(crate) async fn query_table(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    replica: web::Data<Replica>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<(String, String)>,
+    query_params: web::Query<QueryParams>,
+) -> impl Responder {
+    let (table_name, filters_str) = path.into_inner();
+    
+    // Sanitize table name
+    let table = match sanitize_table_name(&table_name) {
+        Ok(t) => t,
+        Err(e) => return bad_request(&req, e),
+    };
+    let table = match resolve_table(&req, &pool, &config, table).await {
+        Ok(t) => t,
+        Err(response) => return response,
+    };
+    
+    // Parse filters
+    let filters = match request_filters(&req, Some(&filters_str)) {
+        Ok(f) => f,
+        Err(e) => return bad_request(&req, Message::new("invalid_filter").cause(e)),
+    };
+    let filters = match with_preset(&config, &table, filters, query_params.preset.as_deref()) {
+        Ok(f) => f,
+        Err(e) => return bad_request(&req, e),
+    };
+    
+    // Validate and sanitize all column names
+    let sanitized_filters = match finish_filters(&req, &pool, filters, query_params.or.as_deref(), &config, &table).await {
+        Ok(f) => f,
+        Err(e) => return bad_request(&req, e),
+    };
+    
+    let pool = match read_pool(&req, &pool, &replica, &config, &table, &sanitized_filters, &query_params).await {
+        Ok(p) => p,
+        Err(e) => return bad_request(&req, e),
+    };
+    
+    run_query(&req, &pool, &config, &breaker, &table, &sanitized_filters, &query_params).await
+}
+
+// GET /{table}/{filter}/_blob/{column}: the bytea column of the one row the
+// filter matches, as the raw bytes. The content type is sniffed from the
+// leading bytes.
+pub(crate) async fn row_blob(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    replica: web::Data<Replica>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<(String, String, String)>,
+) -> HttpResponse {
+    let (table_name, filters_str, column) = path.into_inner();
+    let table = match sanitize_table_name(&table_name) {
+        Ok(t) => t,
+        Err(e) => return bad_request(&req, e),
+    };
+    let table = match resolve_table(&req, &pool, &config, table).await {
+        Ok(t) => t,
+        Err(response) => return response,
+    };
+    let column = match sanitize_column_name(&column) {
+        Ok(c) => c.to_lowercase(),
+        Err(e) => return bad_request(&req, e),
+    };
+    let encrypted = config.table(table.trim_matches('"')).is_some_and(|t| t.encrypted_columns.contains(&column));
+    if encrypted {
+        return bad_request(&req, Message::new("blob_encrypted").arg("column", &column));
+    }
+    if let Err(e) = config.check_unmasked(&table, &column) {
+        return bad_request(&req, e);
+    }
+    let columns = match select_columns(&req, &pool, &table).await {
+        Ok(c) => c,
+        Err(e) => return database_error_response(&req, &breaker, e),
+    };
+    match columns.iter().find(|(name, _)| *name == column) {
+        None => {
+            let message = Message::new("unknown_column").arg("column", &column).arg("table", table.trim_matches('"'));
+            return error_response(&req, StatusCode::NOT_FOUND, message);
+        }
+        Some((_, sql_type)) if sql_type != "bytea" => {
+            return bad_request(&req, Message::new("blob_not_binary").arg("column", &column).arg("type", sql_type));
+        }
+        Some(_) => {}
+    }
+    
+    let filters = match request_filters(&req, Some(&filters_str)) {
+        Ok(f) => f,
+        Err(e) => return bad_request(&req, Message::new("invalid_filter").cause(e)),
+    };
+    let filters = match finish_filters(&req, &pool, filters, None, &config, &table).await {
+        Ok(f) => f,
+        Err(e) => return bad_request(&req, e),
+    };
+    let pool = match replica.read_pool(&req, &pool).await {
+        Ok(p) => p,
+        Err(e) => return bad_request(&req, e),
+    };
+    
+    // Two rows are enough to tell an ambiguous filter
+    let query = tag_query(&table, &format!(
+        "SELECT {} FROM {}{} LIMIT 2",
+        column,
+        table,
+        build_where_clause(&filters)
+    ));
+    let fetched = async {
+        let mut tx = begin_table_transaction(&pool, &config, &table).await?;
+        apply_claim_settings(&mut tx, &claim_settings(&req, &config)).await?;
+        let rows = bind_filters(sqlx::query(&query), &filters).fetch_all(&mut *tx).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(rows)
+    };
+    let rows = match fetched.await {
+        Ok(rows) => rows,
+        Err(e) => return database_error_response(&req, &breaker, e),
+    };
+    breaker.record_success();
+    
+    let bytes = match rows.as_slice() {
+        [] => return error_response(&req, StatusCode::NOT_FOUND, Message::new("blob_row_not_found")),
+        [row] => row.try_get::<Option<Vec<u8>>, _>(0).unwrap_or_default(),
+        _ => return bad_request(&req, Message::new("blob_ambiguous")),
+    };
+    note_read(&req, &table, filters.conditions().iter().map(|c| c.describe()).collect(), 1);
+    match bytes {
+        Some(bytes) => HttpResponse::Ok().content_type(sniff_content_type(&bytes)).body(bytes),
+        None => error_response(&req, StatusCode::NOT_FOUND, Message::new("blob_null").arg("column", &column)),
+    }
+}
+
+// Well-known file signatures, application/octet-stream otherwise
+pub(crate) fn sniff_content_type(bytes: &[u8]) -> &'static str {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+    ];
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+    SIGNATURES
+        .iter()
+        .find(|(signature, _)| bytes.starts_with(signature))
+        .map_or("application/octet-stream", |(_, content_type)| content_type)
+}
+
+pub(crate) async fn query_all(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    replica: web::Data<Replica>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<String>,
+    query_params: web::Query<QueryParams>,
+) -> impl Responder {
+    let table_name = path.into_inner();
+    
+    // Sanitize table name
+    let table = match sanitize_table_name(&table_name) {
+        Ok(t) => t,
+        Err(e) => return bad_request(&req, e),
+    };
+    let table = match resolve_table(&req, &pool, &config, table).await {
+        Ok(t) => t,
+        Err(response) => return response,
+    };
+    
+    let filters = match request_filters(&req, None).map_err(|e| Message::new("invalid_filter").cause(e)) {
+        Ok(filters) => with_preset(&config, &table, filters, query_params.preset.as_deref()),
+        Err(e) => Err(e),
+    };
+    let filters = match filters {
+        Ok(filters) => finish_filters(&req, &pool, filters, query_params.or.as_deref(), &config, &table).await,
+        Err(e) => Err(e),
+    };
+    let filters = match filters {
+        Ok(f) => f,
+        Err(e) => return bad_request(&req, e),
+    };
+    
+    let pool = match read_pool(&req, &pool, &replica, &config, &table, &filters, &query_params).await {
+        Ok(p) => p,
+        Err(e) => return bad_request(&req, e),
+    };
+    
+    run_query(&req, &pool, &config, &breaker, &table, &filters, &query_params).await
+}
+
+pub(crate) const MULTI_PATH: &str = "/_multi";
+pub(crate) const MAX_MULTI_READS: usize = 50;
+
+// Read parameters that don't produce a JSON page, and so can't be part of a
+// multi-read
+pub(crate) const MULTI_UNSUPPORTED_PARAMS: &[&str] = &["format", "stream", "materialize", "checkpoints", "resume_token"];
+
+// Read parameters taken from the query string of POST /_multi itself, so they
+// apply to every read and can't differ between them
+pub(crate) const MULTI_REQUEST_PARAMS: &[&str] = &["schema", "include_deleted", "consistency"];
+
+// One read of POST /_multi: `filter` uses the path filter syntax, `params`
+// holds the read's query parameters, e.g. `{"select": "id,name", "page_size": 5}`
+// or `{"filter[amount][gte]": 100}`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct MultiRead {
+    pub(crate) table: String,
+    pub(crate) filter: Option<String>,
+    #[serde(default)]
+    pub(crate) params: serde_json::Map<String, serde_json::Value>,
+}
+
+// Runs table reads keyed by name concurrently, each on its own pool
+// connection, and returns each read's page (or error) with its status under
+// the same name. Reads are checked like GET /{table}/{filter}.
+pub(crate) async fn run_multi(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    replica: web::Data<Replica>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    exposure: Option<web::Data<Exposure>>,
+    body: web::Json<BTreeMap<String, MultiRead>>,
+) -> impl Responder {
+    let reads = body.into_inner();
+    if reads.is_empty() || reads.len() > MAX_MULTI_READS {
+        return bad_request(&req, Message::new("invalid_multi_body").arg("max", MAX_MULTI_READS));
+    }
+    let exposure = exposure.as_ref().map(|e| e.get_ref());
+    let responses = futures_util::future::join_all(
+        reads.values().map(|read| multi_read(&req, &pool, &replica, &config, &breaker, exposure, read)),
+    )
+    .await;
+    
+    // Each read noted its rows for the access log and row quotas
+    let mut results = serde_json::Map::new();
+    for (name, resp) in reads.keys().zip(responses) {
+        let status = resp.status();
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap_or_default();
+        let mut result = match serde_json::from_slice::<serde_json::Value>(&body) {
+            Ok(serde_json::Value::Object(obj)) => obj,
+            _ => serde_json::Map::new(),
+        };
+        result.insert("status".to_string(), serde_json::json!(status.as_u16()));
+        results.insert(name.clone(), serde_json::Value::Object(result));
+    }
+    HttpResponse::Ok().json(serde_json::json!({ "results": results }))
+}
+
+pub(crate) async fn multi_read(
+    req: &HttpRequest,
+    pool: &PgPool,
+    replica: &Replica,
+    config: &Config,
+    breaker: &CircuitBreaker,
+    exposure: Option<&Exposure>,
+    read: &MultiRead,
+) -> HttpResponse {
+    let table = match sanitize_table_name(&read.table) {
+        Ok(t) => t,
+        Err(e) => return bad_request(req, e),
+    };
+    if exposure.is_some_and(|exposure| !exposure.is_exposed(&table)) {
+        return error_response(req, StatusCode::NOT_FOUND, Message::new("table_not_found").arg("table", &table));
+    }
+    if let Some(resp) = table_grant_denied(req, &table, &actix_web::http::Method::GET) {
+        return resp;
+    }
+    if let Some(param) = MULTI_UNSUPPORTED_PARAMS.iter().find(|p| read.params.contains_key(**p)) {
+        return bad_request(req, Message::new("multi_param_unsupported").arg("param", param).param(param));
+    }
+    if let Some(param) = MULTI_REQUEST_PARAMS.iter().find(|p| read.params.contains_key(**p)) {
+        return bad_request(req, Message::new("multi_param_request_wide").arg("param", param).param(param));
+    }
+    let query: Vec<String> = read
+        .params
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            format!("{}={}", urlencoding::encode(name), urlencoding::encode(&value))
+        })
+        .collect();
+    let query = query.join("&");
+    let query_params = match web::Query::<QueryParams>::from_query(&query) {
+        Ok(q) => q.into_inner(),
+        Err(e) => return bad_request(req, Message::new("invalid_query_string").arg("reason", e)),
+    };
+    let table = match resolve_table(req, pool, config, table).await {
+        Ok(t) => t,
+        Err(response) => return response,
+    };
+    let filters = match read.filter.as_deref().map(parse_multiple_filters) {
+        Some(Ok(f)) => f,
+        Some(Err(e)) => return bad_request(req, Message::new("invalid_filter").cause(e)),
+        None => FilterExpr::none(),
+    };
+    // The read's own `filter[...]` params, as request_filters takes them
+    // from a GET's query string
+    let filters = match parse_structured_filters(&query) {
+        Ok(structured) => structured.into_iter().fold(filters, FilterExpr::and),
+        Err(e) => return bad_request(req, e),
+    };
+    let filters = match with_preset(config, &table, filters, query_params.preset.as_deref()) {
+        Ok(f) => f,
+        Err(e) => return bad_request(req, e),
+    };
+    let filters = match finish_filters(req, pool, filters, query_params.or.as_deref(), config, &table).await {
+        Ok(f) => f,
+        Err(e) => return bad_request(req, e),
+    };
+    let pool = match read_pool(req, pool, replica, config, &table, &filters, &query_params).await {
+        Ok(p) => p,
+        Err(e) => return bad_request(req, e),
+    };
+    run_query(req, &pool, config, breaker, &table, &filters, &query_params).await
+}
+
+// A shared query, signed with DATAPI_SHARE_SECRET into the link's token.
+// `claims` holds the sharer's values for the table's claim filters, so the
+// link shows no more rows than its creator could see.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ShareClaims {
+    // The table as resolved for the creator: schema-qualified when they
+    // shared one of DATAPI_SCHEMAS, quoted when its name has upper case
+    pub(crate) table: String,
+    pub(crate) filter: Option<String>,
+    // Query string of the shared read parameters
+    pub(crate) query: String,
+    pub(crate) claims: BTreeMap<String, serde_json::Value>,
+    pub(crate) sub: Option<String>,
+    // key_fingerprint of the creator's API key when it has a quota, which
+    // the link's reads count against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) quota: Option<String>,
+    pub(crate) exp: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ShareRequest {
+    pub(crate) table: String,
+    // Path filters, e.g. `loan_status=active&debt_amount>=1000`
+    pub(crate) filter: Option<String>,
+    pub(crate) select: Option<String>,
+    pub(crate) sort: Option<String>,
+    pub(crate) order: Option<String>,
+    pub(crate) or: Option<String>,
+    pub(crate) page_size: Option<usize>,
+    // Seconds until the link expires, default DEFAULT_SHARE_SECS
+    pub(crate) expires_in: Option<u64>,
+}
+
+pub(crate) const DEFAULT_SHARE_SECS: u64 = 24 * 60 * 60;
+
+// Parameters a share link's visitor may choose; everything else is fixed
+pub(crate) const SHARE_VISITOR_PARAMS: &[&str] = &["page", "format"];
+
+pub(crate) fn share_disabled(req: &HttpRequest) -> HttpResponse {
+    error_response(req, StatusCode::FORBIDDEN, Message::new("shares_disabled"))
+}
+
+// Signs a read of one table into a link that works without credentials
+// until it expires.
+pub(crate) async fn create_share(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    exposure: Option<web::Data<Exposure>>,
+    body: web::Json<ShareRequest>,
+) -> impl Responder {
+    let Some(secret) = &config.share_secret else {
+        return share_disabled(&req);
+    };
+    let share = body.into_inner();
+    let table = match sanitize_table_name(&share.table) {
+        Ok(t) => t,
+        Err(e) => return bad_request(&req, e),
+    };
+    if exposure.is_some_and(|exposure| !exposure.is_exposed(&table)) {
+        return error_response(&req, StatusCode::NOT_FOUND, Message::new("table_not_found").arg("table", &table));
+    }
+    if let Some(resp) = table_grant_denied(&req, &table, &actix_web::http::Method::GET) {
+        return resp;
+    }
+    let table = match resolve_table(&req, &pool, &config, table).await {
+        Ok(t) => t,
+        Err(response) => return response,
+    };
+    let expires_in = share.expires_in.unwrap_or(DEFAULT_SHARE_SECS.min(config.share_max_secs));
+    if expires_in == 0 || expires_in > config.share_max_secs {
+        return bad_request(&req, Message::new("invalid_share_expiry").arg("max", config.share_max_secs));
+    }
+    
+    // Parsed like a read of the table, so malformed links aren't handed out
+    let filters = match &share.filter {
+        Some(filter) => match parse_multiple_filters(filter) {
+            Ok(f) => f,
+            Err(e) => return bad_request(&req, Message::new("invalid_filter").cause(e)),
+        },
+        None => FilterExpr::none(),
+    };
+    if let Err(e) = finish_filters(&req, &pool, filters, share.or.as_deref(), &config, &table).await {
+        return bad_request(&req, e);
+    }
+    let mut query = Vec::new();
+    let page_size = share.page_size.map(|size| size.to_string());
+    for (name, value) in [
+        ("select", &share.select),
+        ("sort", &share.sort),
+        ("order", &share.order),
+        ("or", &share.or),
+        ("page_size", &page_size),
+    ] {
+        if let Some(value) = value {
+            query.push(format!("{}={}", name, urlencoding::encode(value)));
+        }
+    }
+    let query = query.join("&");
+    if let Err(e) = web::Query::<QueryParams>::from_query(&query) {
+        return bad_request(&req, Message::new("invalid_share").arg("reason", e));
+    }
+    
+    let grants = req.extensions().get::<TokenGrants>().cloned();
+    let claims = config
+        .table(table.trim_matches('"'))
+        .into_iter()
+        .flat_map(|t| t.claim_filters.values())
+        .filter_map(|path| {
+            let grants = grants.as_ref()?;
+            let value = path.split('.').try_fold(&grants.claims, |value, key| value.get(key))?;
+            Some((path.clone(), value.clone()))
+        })
+        .collect();
+    let expires_at = unix_now() + expires_in;
+    let claims = ShareClaims {
+        table: table.clone(),
+        filter: share.filter,
+        query,
+        claims,
+        sub: grants.and_then(|g| g.subject),
+        quota: row_meter(&req).map(|meter| key_fingerprint(&meter.key)),
+        exp: expires_at,
+    };
+    let token = jsonwebtoken::encode(
+        &jsonwebtoken::Header::new(Algorithm::HS256),
+        &claims,
+        &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+    );
+    let token = match token {
+        Ok(token) => token,
+        Err(e) => return bad_request(&req, Message::new("invalid_share").arg("reason", e)),
+    };
+    let expires_at = chrono::DateTime::from_timestamp(expires_at as i64, 0).unwrap_or_default();
+    HttpResponse::Created().json(serde_json::json!({
+        "url": format!("/_shared/{}", token),
+        "expires_at": expires_at,
+    }))
+}
+
+// Serves a share link's query. Visitors may only page through it and pick
+// the output format; the table is the signed one, never resolved again.
+pub(crate) async fn serve_share(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    replica: web::Data<Replica>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    exposure: Option<web::Data<Exposure>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let Some(secret) = &config.share_secret else {
+        return share_disabled(&req);
+    };
+    let decoded = jsonwebtoken::decode::<ShareClaims>(
+        &path.into_inner(),
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::new(Algorithm::HS256),
+    );
+    let share = match decoded {
+        Ok(decoded) => decoded.claims,
+        Err(e) if *e.kind() == jsonwebtoken::errors::ErrorKind::ExpiredSignature => {
+            return error_response(&req, StatusCode::GONE, Message::new("share_expired"));
+        }
+        Err(_) => return error_response(&req, StatusCode::NOT_FOUND, Message::new("share_not_found")),
+    };
+    let name = share.table.replace('"', "");
+    if exposure.is_some_and(|exposure| !exposure.is_exposed(&name)) {
+        return error_response(&req, StatusCode::NOT_FOUND, Message::new("share_not_found"));
+    }
+    let keys = req.app_data::<web::Data<ApiKeys>>().cloned();
+    let key = share.quota.as_ref().and_then(|fingerprint| {
+        keys.as_ref()?.quotas.keys().find(|key| key_fingerprint(key) == *fingerprint).cloned()
+    });
+    if let (Some(keys), Some(key)) = (keys, key) {
+        if let Some(resp) = apply_quota(&req, keys, key) {
+            return resp;
+        }
+    }
+    
+    let mut query = share.query.clone();
+    let visitor = match web::Query::<Vec<(String, String)>>::from_query(req.query_string()) {
+        Ok(q) => q.into_inner(),
+        Err(e) => return bad_request(&req, Message::new("invalid_share").arg("reason", e)),
+    };
+    if let Some((param, _)) = visitor.iter().find(|(param, _)| !SHARE_VISITOR_PARAMS.contains(&param.as_str())) {
+        return bad_request(&req, Message::new("share_parameter_fixed").arg("parameter", param).param(param));
+    }
+    for (name, value) in &visitor {
+        query.push_str(&format!("&{}={}", name, urlencoding::encode(value)));
+    }
+    let query_params = match web::Query::<QueryParams>::from_query(query.trim_start_matches('&')) {
+        Ok(q) => q.into_inner(),
+        Err(e) => return bad_request(&req, Message::new("invalid_share").arg("reason", e)),
+    };
+    let table = share.table;
+    let filters = match share.filter.as_deref().map(parse_multiple_filters) {
+        Some(Ok(f)) => f,
+        Some(Err(e)) => return bad_request(&req, Message::new("invalid_filter").cause(e)),
+        None => FilterExpr::none(),
+    };
+    
+    // The link stands in for its creator's token: read access to the one
+    // table, with the claims the table's claim filters need
+    let mut claims = serde_json::Value::Object(Default::default());
+    for (path, value) in share.claims {
+        let mut target = &mut claims;
+        for key in path.split('.') {
+            if !target.is_object() {
+                *target = serde_json::json!({});
+            }
+            target = target.as_object_mut().unwrap().entry(key).or_insert(serde_json::Value::Null);
+        }
+        *target = value;
+    }
+    req.extensions_mut().insert(TokenGrants {
+        subject: share.sub,
+        tables: vec![TableGrant { table: name.to_lowercase(), scope: ApiScope::Readonly }],
+        claims,
+    });
+    let filters = match finish_filters(&req, &pool, filters, query_params.or.as_deref(), &config, &table).await {
+        Ok(f) => f,
+        Err(e) => return bad_request(&req, e),
+    };
+    let pool = match read_pool(&req, &pool, &replica, &config, &table, &filters, &query_params).await {
+        Ok(p) => p,
+        Err(e) => return bad_request(&req, e),
+    };
+    run_query(&req, &pool, &config, &breaker, &table, &filters, &query_params).await
+}
+
+// Templates stored in DATAPI_QUERIES_TABLE, reloaded periodically so
+// queries can be added and changed without a restart. A row is checked like
+// a configured template and skipped with a warning if invalid; configured
+// templates win over rows of the same name.
+pub(crate) struct SavedQueries {
+    pub(crate) table: String,
+    pub(crate) loaded: Mutex<BTreeMap<String, TemplateConfig>>,
+}
+
+impl SavedQueries {
+    pub(crate) fn from_config(config: &Config) -> Option<Self> {
+        Some(SavedQueries { table: config.queries_table.clone()?, loaded: Mutex::new(BTreeMap::new()) })
+    }
+    
+    pub(crate) fn get(&self, name: &str) -> Option<TemplateConfig> {
+        self.loaded.lock().unwrap().get(name).cloned()
+    }
+    
+    pub(crate) async fn reload(&self, pool: &PgPool, config: &Config) -> Result<(), sqlx::Error> {
+        let rows = sqlx::query(&format!(
+            "SELECT name::text AS name, sql::text AS sql, to_jsonb(params) AS params, tables::text[] AS tables FROM {}",
+            self.table
+        ))
+        .fetch_all(pool)
+        .await?;
+        let mut loaded = BTreeMap::new();
+        for row in &rows {
+            let name: String = row.try_get("name")?;
+            let template = serde_json::json!({
+                "sql": row.try_get::<String, _>("sql")?,
+                "params": row.try_get::<Option<serde_json::Value>, _>("params")?.unwrap_or_else(|| serde_json::json!({})),
+                "tables": row.try_get::<Option<Vec<String>>, _>("tables")?.unwrap_or_default(),
+            });
+            let template = serde_json::from_value::<TemplateConfig>(template)
+                .map_err(|e| format!("Invalid query {}: {}", name, e))
+                .and_then(|template| template.validate(&name, &config.tables));
+            match template {
+                Ok(_) if config.templates.contains_key(&name.to_lowercase()) => {
+                    log::warn!("Ignoring query {} in {}, a configured template has its name", name, self.table);
+                }
+                Ok(template) => {
+                    loaded.insert(name.to_lowercase(), template);
+                }
+                Err(e) => log::warn!("Ignoring query in {}: {}", self.table, e),
+            }
+        }
+        *self.loaded.lock().unwrap() = loaded;
+        Ok(())
+    }
+}
+
+pub(crate) async fn watch_saved_queries(pool: PgPool, config: web::Data<Config>, queries: web::Data<SavedQueries>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = queries.reload(&pool, &config).await {
+            log::warn!("Saved query reload failed: {}", e);
+        }
+    }
+}
+
+// The configured and saved templates with their parameters
+pub(crate) async fn list_templates(config: web::Data<Config>, saved: Option<web::Data<SavedQueries>>) -> impl Responder {
+    let saved = saved.map(|saved| saved.loaded.lock().unwrap().clone()).unwrap_or_default();
+    let templates: Vec<serde_json::Value> = config
+        .templates
+        .iter()
+        .chain(saved.iter())
+        .map(|(name, template)| {
+            serde_json::json!({
+                "name": name,
+                "params": template.params,
+                "tables": template.tables,
+            })
+        })
+        .collect();
+    HttpResponse::Ok().json(serde_json::json!({ "count": templates.len(), "templates": templates }))
+}
+
+// Runs a template with the parameters from the query string, paged like the
+// table endpoints. The statement runs in a read-only transaction.
+// A page of `sql`, with LIMIT and OFFSET bound after its `bound` parameters
+// so every page of a statement shares one prepared statement
+pub(crate) fn paged_sql(sql: String, bound: usize) -> String {
+    format!("{} LIMIT ${}::bigint OFFSET ${}::bigint", sql, bound + 1, bound + 2)
+}
+
+pub(crate) async fn run_template(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    replica: web::Data<Replica>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    saved: Option<web::Data<SavedQueries>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let name = path.into_inner().to_lowercase();
+    let template = config.templates.get(&name).cloned().or_else(|| saved.and_then(|saved| saved.get(&name)));
+    let Some(template) = template else {
+        return error_response(&req, StatusCode::NOT_FOUND, Message::new("template_not_found").arg("template", &name));
+    };
+    for table in &template.tables {
+        if let Some(resp) = table_grant_denied(&req, table, req.method()) {
+            return resp;
+        }
+    }
+    let mut params = match web::Query::<HashMap<String, String>>::from_query(req.query_string()) {
+        Ok(q) => q.into_inner(),
+        Err(e) => return bad_request(&req, Message::new("invalid_query_string").arg("reason", e)),
+    };
+    let mut number = |key: &str| match params.remove(key) {
+        None => Ok(None),
+        Some(value) => value.parse::<usize>().map(Some).map_err(|_| {
+            Message::new("invalid_template_param").arg("param", key).arg("value", &value).arg("expected", "integer").param(key)
+        }),
+    };
+    let (page, page_size, timeout_ms) = match (number("page"), number("page_size"), number("timeout_ms")) {
+        (Ok(page), Ok(page_size), Ok(timeout_ms)) => (
+            page.unwrap_or(1).max(1),
+            quota_page_size(&req, config.page_size(page_size)),
+            timeout_ms.map(|ms| ms as u64),
+        ),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => return bad_request(&req, e),
+    };
+    // Handled by the fields and envelope middleware
+    params.remove("fields");
+    params.remove("envelope");
+    // Read by the replica selection
+    params.remove("consistency");
+    if let Some(unknown) = params.keys().find(|key| !template.params.contains_key(*key)) {
+        return bad_request(
+            &req,
+            Message::new("unknown_template_param").arg("param", unknown).arg("template", &name).param(unknown),
+        );
+    }
+    let mut values = Vec::with_capacity(template.placeholders.len());
+    for placeholder in &template.placeholders {
+        let param = &template.params[placeholder];
+        let Some(value) = params.get(placeholder).cloned().or_else(|| param.default.as_ref().and_then(json_to_text)) else {
+            return bad_request(
+                &req,
+                Message::new("missing_template_param").arg("param", placeholder).arg("template", &name).param(placeholder),
+            );
+        };
+        match param.check(&value) {
+            Some(bound) => values.push(bound),
+            None => {
+                let expected = if param.values.is_empty() {
+                    param.kind.clone()
+                } else {
+                    format!("one of {}", param.values.join(", "))
+                };
+                return bad_request(
+                    &req,
+                    Message::new("invalid_template_param")
+                        .arg("param", placeholder)
+                        .arg("value", &value)
+                        .arg("expected", expected)
+                        .param(placeholder),
+                );
+            }
+        }
+    }
+    
+    let sql = paged_sql(
+        format!("/* datapi template={} */ SELECT * FROM ({}) template", name, template.statement),
+        values.len(),
+    );
+    let pool = match replica.read_pool(&req, &pool).await {
+        Ok(p) => p,
+        Err(e) => return bad_request(&req, e),
+    };
+    let result = async {
+        let mut tx = pool.begin().await?;
+        sqlx::query("SET TRANSACTION READ ONLY").execute(&mut *tx).await?;
+        apply_request_timeout(&mut tx, &config, timeout_ms).await?;
+        apply_claim_settings(&mut tx, &claim_settings(&req, &config)).await?;
+        let query = values.iter().fold(sqlx::query(&sql), |query, value| query.bind(value));
+        let query = query.bind(page_size as i64).bind(((page - 1) * page_size) as i64);
+        let rows = query.fetch_all(&mut *tx).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(rows)
+    };
+    let rows = match result.await {
+        Ok(rows) => rows,
+        Err(e) => return database_error_response(&req, &breaker, e),
+    };
+    breaker.record_success();
+    note_read(&req, &template.tables.join(","), Vec::new(), rows.len());
+    let data: Vec<serde_json::Value> = rows.iter().map(|row| serde_json::Value::Object(row_to_json(row, config.value_format()))).collect();
+    QueryResult {
+        count: data.len(),
+        data,
+        page,
+        page_size,
+        total_count: None,
+        total_count_estimated: false,
+        result_token: None,
+        next_cursor: None,
+        sort_fingerprint: None,
+        warnings: Vec::new(),
+        total_pages: None,
+        has_next: false,
+    }
+    .into_response(&req, HttpResponse::Ok())
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RpcParams {
+    pub(crate) page: Option<usize>,
+    pub(crate) page_size: Option<usize>,
+    pub(crate) timeout_ms: Option<u64>,
+}
+
+// A signature of a function, from pg_proc: its input parameters and how many
+// of the trailing ones have defaults
+#[derive(sqlx::FromRow)]
+pub(crate) struct FunctionSignature {
+    pub(crate) schema: String,
+    pub(crate) names: Vec<String>,
+    pub(crate) types: Vec<String>,
+    pub(crate) defaults: i16,
+}
+
+impl FunctionSignature {
+    pub(crate) fn accepts(&self, args: &serde_json::Map<String, serde_json::Value>) -> bool {
+        let required = self.names.len() - (self.defaults.max(0) as usize).min(self.names.len());
+        args.keys().all(|arg| self.names.contains(arg))
+            && self.names[..required].iter().all(|name| args.contains_key(name))
+    }
+}
+
+// Calls a listed function with named arguments, the body's keys, and pages
+// its result set like a table read. The call runs in a read-only
+// transaction, so functions serving read models can't write.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn call_function(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    replica: web::Data<Replica>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<String>,
+    query_params: web::Query<RpcParams>,
+    body: Option<web::Json<serde_json::Map<String, serde_json::Value>>>,
+) -> impl Responder {
+    let name = path.into_inner().to_lowercase();
+    if !config.functions.contains(&name) {
+        return error_response(&req, StatusCode::NOT_FOUND, Message::new("function_not_found").arg("function", &name));
+    }
+    if let Some(resp) = table_grant_denied(&req, &name, &actix_web::http::Method::GET) {
+        return resp;
+    }
+    let args = body.map(|b| b.into_inner()).unwrap_or_default();
+    let page = query_params.page.unwrap_or(1).max(1);
+    let page_size = quota_page_size(&req, config.page_size(query_params.page_size));
+    let pool = match replica.read_pool(&req, &pool).await {
+        Ok(p) => p,
+        Err(e) => return bad_request(&req, e),
+    };
+    
+    // Input parameters exclude OUT and TABLE columns; unnamed ones can't be
+    // passed by name, so those signatures never match
+    let (schema, function) = name.split_once('.').map_or((None, name.as_str()), |(s, f)| (Some(s), f));
+    let signatures: Vec<FunctionSignature> = match sqlx::query_as(
+        "SELECT n.nspname::text AS schema,
+                array(SELECT a.name FROM unnest(coalesce(p.proargnames, array_fill(''::text, array[p.pronargs])),
+                                                coalesce(p.proargmodes::text[], array_fill('i'::text, array[p.pronargs])))
+                                    AS a(name, mode)
+                      WHERE a.mode IN ('i', 'b', 'v')) AS names,
+                array(SELECT format_type(t, NULL) FROM unnest(p.proargtypes::oid[]) WITH ORDINALITY AS u(t, i) ORDER BY i) AS types,
+                p.pronargdefaults AS defaults
+         FROM pg_proc p JOIN pg_namespace n ON n.oid = p.pronamespace
+         WHERE p.proname = $1 AND p.prokind = 'f'
+           AND CASE WHEN $2::text IS NULL THEN pg_function_is_visible(p.oid) ELSE n.nspname = $2 END",
+    )
+    .bind(function)
+    .bind(schema)
+    .fetch_all(&pool)
+    .await
+    {
+        Ok(s) => s,
+        Err(e) => return database_error_response(&req, &breaker, e),
+    };
+    if signatures.is_empty() {
+        return error_response(&req, StatusCode::NOT_FOUND, Message::new("function_not_found").arg("function", &name));
+    }
+    let matching: Vec<&FunctionSignature> = signatures
+        .iter()
+        .filter(|s| s.names.len() == s.types.len() && s.accepts(&args))
+        .collect();
+    let arg_list = args.keys().cloned().collect::<Vec<_>>().join(", ");
+    let signature = match matching.as_slice() {
+        [signature] => *signature,
+        [] => {
+            return bad_request(
+                &req,
+                Message::new("invalid_function_args").arg("function", &name).arg("args", arg_list),
+            )
+        }
+        _ => {
+            return bad_request(
+                &req,
+                Message::new("ambiguous_function_args").arg("function", &name).arg("args", arg_list),
+            )
+        }
+    };
+    
+    // Arguments are named in the call and bound as text cast to the parameter
+    // type, so Postgres parses them like literals
+    let mut values = Vec::with_capacity(args.len());
+    let mut call_args = Vec::with_capacity(args.len());
+    for (arg, value) in &args {
+        let position = signature.names.iter().position(|n| n == arg).unwrap_or_default();
+        values.push(json_to_text(value));
+        call_args.push(format!("\"{}\" => ${}::{}", arg.replace('"', "\"\""), values.len(), signature.types[position]));
+    }
+    let sql = paged_sql(
+        format!(
+            "/* datapi rpc={} */ SELECT * FROM \"{}\".\"{}\"({})",
+            name,
+            signature.schema.replace('"', "\"\""),
+            function,
+            call_args.join(", ")
+        ),
+        values.len(),
+    );
+    let logged: Vec<String> = values.iter().flatten().cloned().collect();
+    log::debug!("Calling {} with {:?}", name, log_binds(&config, &FilterExpr::none(), &logged));
+    let result = async {
+        let mut tx = pool.begin().await?;
+        sqlx::query("SET TRANSACTION READ ONLY").execute(&mut *tx).await?;
+        apply_request_timeout(&mut tx, &config, query_params.timeout_ms).await?;
+        apply_claim_settings(&mut tx, &claim_settings(&req, &config)).await?;
+        let query = values.iter().fold(sqlx::query(&sql), |query, value| query.bind(value));
+        let query = query.bind(page_size as i64).bind(((page - 1) * page_size) as i64);
+        let rows = query.fetch_all(&mut *tx).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(rows)
+    };
+    let rows = match result.await {
+        Ok(rows) => rows,
+        Err(e) => return write_error_response(&req, &breaker, e),
+    };
+    breaker.record_success();
+    note_read(&req, &name, Vec::new(), rows.len());
+    let data: Vec<serde_json::Value> = rows.iter().map(|row| serde_json::Value::Object(row_to_json(row, config.value_format()))).collect();
+    QueryResult {
+        count: data.len(),
+        data,
+        page,
+        page_size,
+        total_count: None,
+        total_count_estimated: false,
+        result_token: None,
+        next_cursor: None,
+        sort_fingerprint: None,
+        warnings: Vec::new(),
+        total_pages: None,
+        has_next: false,
+    }
+    .into_response(&req, HttpResponse::Ok())
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct AggregateParams {
+    pub(crate) page: Option<usize>,
+    pub(crate) page_size: Option<usize>,
+    // Comma-separated grouping columns
+    pub(crate) group_by: Option<String>,
+    // Comma-separated aggregates, e.g. `sum(total),count(*)`
+    pub(crate) agg: Option<String>,
+    pub(crate) or: Option<String>,
+    // Time bucketing: groups by date_trunc(bucket, time_column) first
+    pub(crate) bucket: Option<String>,
+    pub(crate) time_column: Option<String>,
+    // Range of time_column, `from` inclusive and `to` exclusive
+    pub(crate) from: Option<String>,
+    pub(crate) to: Option<String>,
+    // previous_period or previous_year, aligned to the buckets of from..to
+    pub(crate) compare: Option<String>,
+    pub(crate) timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Comparison {
+    PreviousPeriod,
+    PreviousYear,
+}
+
+// The time bucketing of an aggregate request, with the range and comparison
+// expressed as SQL literals built from parsed values.
+pub(crate) struct TimeBuckets {
+    pub(crate) unit: String,
+    pub(crate) column: String,
+    pub(crate) from: Option<chrono::NaiveDateTime>,
+    pub(crate) to: Option<chrono::NaiveDateTime>,
+    pub(crate) compare: Option<Comparison>,
+}
+
+pub(crate) fn parse_time_bound(value: &str) -> Option<chrono::NaiveDateTime> {
+    if let Ok(ts) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Some(ts.naive_utc());
+    }
+    chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S")
+        .ok()
+        .or_else(|| chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?.and_hms_opt(0, 0, 0))
+}
+
+pub(crate) fn time_literal(ts: chrono::NaiveDateTime) -> String {
+    format!("'{}'", ts.format("%Y-%m-%d %H:%M:%S%.f"))
+}
+
+impl TimeBuckets {
+    pub(crate) fn parse(params: &AggregateParams, config: &Config, table: &str) -> Result<Option<Self>, Message> {
+        let Some(unit) = params.bucket.as_deref() else {
+            if params.time_column.is_some() || params.compare.is_some() || params.from.is_some() || params.to.is_some() {
+                return Err(Message::new("missing_bucket").param("bucket"));
+            }
+            return Ok(None);
+        };
+        let unit = unit.to_lowercase();
+        if !ROLLUP_BUCKETS.contains(&unit.as_str()) {
+            return Err(Message::new("invalid_bucket").arg("bucket", &unit).param("bucket"));
+        }
+        let Some(column) = params.time_column.as_deref() else {
+            return Err(Message::new("missing_bucket").param("time_column"));
+        };
+        let column = sanitize_column_name(column)
+            .and_then(|c| config.check_aggregatable(table, &c).map(|_| c))
+            .map_err(|e| e.param("time_column"))?
+            .to_lowercase();
+        let bound = |param: &str, value: &Option<String>| match value.as_deref() {
+            Some(v) => parse_time_bound(v)
+                .map(Some)
+                .ok_or_else(|| Message::new("invalid_time_bound").arg("value", v).param(param)),
+            None => Ok(None),
+        };
+        let (from, to) = (bound("from", &params.from)?, bound("to", &params.to)?);
+        let compare = match params.compare.as_deref() {
+            None => None,
+            Some("previous_period") => Some(Comparison::PreviousPeriod),
+            Some("previous_year") => Some(Comparison::PreviousYear),
+            Some(other) => return Err(Message::new("invalid_compare").arg("compare", other).param("compare")),
+        };
+        if compare.is_some() && !matches!((from, to), (Some(from), Some(to)) if from < to) {
+            return Err(Message::new("compare_requires_range").param("compare"));
+        }
+        Ok(Some(TimeBuckets { unit, column: config.column_sql(table, &column), from, to, compare }))
+    }
+    
+    // The bucket column, shifted for the comparison series so its buckets
+    // line up with the current ones
+    pub(crate) fn select(&self, shift: Option<&str>) -> String {
+        format!("{}::timestamp AS bucket", self.group_expr(shift))
+    }
+    
+    pub(crate) fn group_expr(&self, shift: Option<&str>) -> String {
+        match shift {
+            Some(shift) => format!("date_trunc('{}', {} + {})", self.unit, self.column, shift),
+            None => format!("date_trunc('{}', {})", self.unit, self.column),
+        }
+    }
+    
+    pub(crate) fn range_sql(&self, from: Option<chrono::NaiveDateTime>, to: Option<chrono::NaiveDateTime>) -> Vec<String> {
+        let mut conditions = Vec::new();
+        if let Some(from) = from {
+            conditions.push(format!("{} >= {}", self.column, time_literal(from)));
+        }
+        if let Some(to) = to {
+            conditions.push(format!("{} < {}", self.column, time_literal(to)));
+        }
+        conditions
+    }
+    
+    // The comparison range and the interval that moves it onto the current one
+    pub(crate) fn previous_range(&self) -> Option<(chrono::NaiveDateTime, chrono::NaiveDateTime, String)> {
+        let (from, to) = (self.from?, self.to?);
+        match self.compare? {
+            Comparison::PreviousPeriod => {
+                let length = to - from;
+                Some((from - length, from, format!("interval '{} seconds'", length.num_seconds())))
+            }
+            Comparison::PreviousYear => {
+                let year = chrono::Months::new(12);
+                Some((from.checked_sub_months(year)?, to.checked_sub_months(year)?, "interval '1 year'".to_string()))
+            }
+        }
+    }
+}
+
+pub(crate) fn with_conditions(where_clause: &str, conditions: &[String]) -> String {
+    if conditions.is_empty() {
+        return where_clause.to_string();
+    }
+    let conditions = conditions.join(" AND ");
+    if where_clause.is_empty() {
+        format!(" WHERE {}", conditions)
+    } else {
+        format!("{} AND {}", where_clause, conditions)
+    }
+}
+
+// Percentage change from the previous value, for numbers and numeric strings
+pub(crate) fn delta_pct(current: &serde_json::Value, previous: &serde_json::Value) -> serde_json::Value {
+    let number = |v: &serde_json::Value| match v {
+        serde_json::Value::Number(n) => n.as_f64(),
+        serde_json::Value::String(s) => s.parse::<f64>().ok(),
+        _ => None,
+    };
+    match (number(current), number(previous)) {
+        (Some(current), Some(previous)) if previous != 0.0 => {
+            let pct = ((current - previous) / previous.abs() * 10000.0).round() / 100.0;
+            serde_json::json!(pct)
+        }
+        _ => serde_json::Value::Null,
+    }
+}
+
+pub(crate) async fn aggregate_table(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    replica: web::Data<Replica>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<(String, String)>,
+    params: web::Query<AggregateParams>,
+) -> impl Responder {
+    let (table_name, filters_str) = path.into_inner();
+    let filters = match request_filters(&req, Some(&filters_str)) {
+        Ok(f) => f,
+        Err(e) => return bad_request(&req, Message::new("invalid_filter").cause(e)),
+    };
+    run_aggregate(&req, &pool, &replica, &config, &breaker, &table_name, filters, &params).await
+}
+
+pub(crate) async fn aggregate_all(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    replica: web::Data<Replica>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<String>,
+    params: web::Query<AggregateParams>,
+) -> impl Responder {
+    let table_name = path.into_inner();
+    let filters = match request_filters(&req, None) {
+        Ok(f) => f,
+        Err(e) => return bad_request(&req, Message::new("invalid_filter").cause(e)),
+    };
+    run_aggregate(&req, &pool, &replica, &config, &breaker, &table_name, filters, &params).await
+}
+
+// Grouped aggregates over a table, optionally filtered. Answered from a
+// configured rollup when one covers the request.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn run_aggregate(
+    req: &HttpRequest,
+    pool: &PgPool,
+    replica: &Replica,
+    config: &Config,
+    breaker: &CircuitBreaker,
+    table_name: &str,
+    filters: FilterExpr,
+    params: &AggregateParams,
+) -> HttpResponse {
+    let table = match sanitize_table_name(table_name) {
+        Ok(t) => t,
+        Err(e) => return bad_request(req, e),
+    };
+    let table = match resolve_table(req, pool, config, table).await {
+        Ok(t) => t,
+        Err(response) => return response,
+    };
+    let filters = match finish_filters(req, pool, filters, params.or.as_deref(), config, &table).await {
+        Ok(f) => f,
+        Err(e) => return bad_request(req, e),
+    };
+    
+    let mut group_by = Vec::new();
+    for column in params.group_by.as_deref().unwrap_or("").split(',') {
+        let column = column.trim();
+        if column.is_empty() {
+            continue;
+        }
+        match sanitize_column_name(column).and_then(|c| config.check_aggregatable(&table, &c).map(|_| c)) {
+            Ok(c) => group_by.push(c.to_lowercase()),
+            Err(e) => return bad_request(req, e.param("group_by")),
+        }
+    }
+    
+    let Some(agg) = params.agg.as_deref().filter(|a| !a.trim().is_empty()) else {
+        return bad_request(req, Message::new("missing_aggregate").param("agg"));
+    };
+    let aggregates = match agg.split(',').map(Aggregate::parse).collect::<Result<Vec<_>, _>>() {
+        Ok(a) => a,
+        Err(e) => return bad_request(req, e.param("agg")),
+    };
+    let protected = aggregates.iter().filter_map(|a| a.column.as_deref()).find_map(|c| config.check_aggregatable(&table, c).err());
+    if let Some(message) = protected {
+        return bad_request(req, message.param("agg"));
+    }
+    
+    let buckets = match TimeBuckets::parse(params, config, &table) {
+        Ok(b) => b,
+        Err(e) => return bad_request(req, e),
+    };
+    
+    let page = params.page.unwrap_or(1).max(1);
+    let page_size = quota_page_size(req, config.page_size(params.page_size));
+    let offset = (page - 1) * page_size;
+    
+    // Rollup buckets can't express arbitrary time ranges
+    let rollup = match buckets {
+        Some(_) => None,
+        None => find_rollup(config, &table, &group_by, &aggregates, &filters),
+    };
+    let (source, measures) = match &rollup {
+        Some((rollup, measures)) => (rollup.table.clone(), measures.clone()),
+        None => (
+            table.clone(),
+            aggregates.iter().map(|a| format!("{} AS {}", a.sql(), a.alias())).collect(),
+        ),
+    };
+    
+    let where_clause = build_where_clause(&filters);
+    let mut group_exprs: Vec<String> = group_by.iter().map(|c| config.column_sql(&table, c)).collect();
+    let mut select: Vec<String> = group_by
+        .iter()
+        .zip(&group_exprs)
+        .map(|(name, expr)| if name == expr { name.clone() } else { format!("{} AS {}", expr, name) })
+        .collect();
+    let mut keys = group_by.clone();
+    if let Some(buckets) = &buckets {
+        group_exprs.insert(0, buckets.group_expr(None));
+        keys.insert(0, "bucket".to_string());
+    }
+    select.extend(measures);
+    // `GROUP BY ()` yields the single overall group, so the count query
+    // returns 1 without grouping columns
+    let (group_by_clause, order_by_clause) = if group_exprs.is_empty() {
+        (" GROUP BY ()".to_string(), String::new())
+    } else {
+        (
+            format!(" GROUP BY {}", group_exprs.join(", ")),
+            format!(" ORDER BY {}", group_exprs.join(", ")),
+        )
+    };
+    
+    // The page is bound after the filter values, like a table read's
+    let limit = format!(" LIMIT ${}::bigint OFFSET ${}::bigint", filters.param_count() + 1, filters.param_count() + 2);
+    let page_values = [page_size.to_string(), offset.to_string()];
+    let previous = buckets.as_ref().and_then(|b| b.previous_range());
+    let (query, count_query) = match (&buckets, &previous) {
+        // Both series are grouped separately, the previous one with its time
+        // column shifted onto the current range, then joined on the buckets
+        (Some(buckets), Some((previous_from, previous_to, shift))) => {
+            let series = |shift: Option<&str>, range: Vec<String>| {
+                let mut columns = vec![buckets.select(shift)];
+                columns.extend(select.iter().cloned());
+                let mut groups = vec![buckets.group_expr(shift)];
+                groups.extend(group_exprs[1..].iter().cloned());
+                format!(
+                    "SELECT {} FROM {}{} GROUP BY {}",
+                    columns.join(", "),
+                    source,
+                    with_conditions(&where_clause, &range),
+                    groups.join(", ")
+                )
+            };
+            let ctes = format!(
+                "WITH cur AS ({}), prev AS ({})",
+                series(None, buckets.range_sql(buckets.from, buckets.to)),
+                series(Some(shift), buckets.range_sql(Some(*previous_from), Some(*previous_to)))
+            );
+            let mut columns = keys.clone();
+            for a in &aggregates {
+                columns.push(format!("cur.{} AS {}", a.alias(), a.alias()));
+                columns.push(format!("prev.{} AS previous_{}", a.alias(), a.alias()));
+            }
+            let join = format!("cur FULL JOIN prev USING ({})", keys.join(", "));
+            (
+                tag_query(&source, &format!(
+                    "{} SELECT {} FROM {} ORDER BY {}{}",
+                    ctes,
+                    columns.join(", "),
+                    join,
+                    keys.join(", "),
+                    limit
+                )),
+                tag_query(&source, &format!("{} SELECT COUNT(*) AS count FROM {}", ctes, join)),
+            )
+        }
+        _ => {
+            let mut where_clause = where_clause.clone();
+            if let Some(buckets) = &buckets {
+                select.insert(0, buckets.select(None));
+                where_clause = with_conditions(&where_clause, &buckets.range_sql(buckets.from, buckets.to));
+            }
+            (
+                tag_query(&source, &format!(
+                    "SELECT {} FROM {}{}{}{}{}",
+                    select.join(", "),
+                    source,
+                    where_clause,
+                    group_by_clause,
+                    order_by_clause,
+                    limit
+                )),
+                tag_query(&source, &format!(
+                    "SELECT COUNT(*) AS count FROM (SELECT 1 FROM {}{}{}) groups",
+                    source, where_clause, group_by_clause
+                )),
+            )
+        }
+    };
+    log::info!("Executing aggregate: {}", query);
+    
+    let pool = match replica.read_pool(req, pool).await {
+        Ok(p) => p,
+        Err(e) => return bad_request(req, e),
+    };
+    let mut tx = match begin_table_transaction(&pool, config, &table).await {
+        Ok(tx) => tx,
+        Err(e) => return database_error_response(req, breaker, e),
+    };
+    if let Err(e) = apply_request_timeout(&mut tx, config, params.timeout_ms).await {
+        return database_error_response(req, breaker, e);
+    }
+    if let Err(e) = apply_claim_settings(&mut tx, &claim_settings(req, config)).await {
+        return database_error_response(req, breaker, e);
+    }
+    
+    let started = Instant::now();
+    let query_builder = page_values.iter().fold(bind_filters(sqlx::query(&query), &filters), |query, value| query.bind(value));
+    let rows = match query_builder.fetch_all(&mut *tx).await {
+        Ok(rows) => rows,
+        Err(e) => return database_error_response(req, breaker, e),
+    };
+    breaker.record_success();
+    note_read(req, &table, filters.conditions().iter().map(|c| c.describe()).collect(), rows.len());
+    if config.slow_query_ms.is_some() {
+        record_slow_query(req, config, &table, &query, &log_binds(config, &filters, &page_values), started.elapsed());
+    }
+    
+    let mut warnings = Vec::new();
+    let total_count = match bind_filters(sqlx::query(&count_query), &filters).fetch_one(&mut *tx).await {
+        Ok(row) => {
+            let count: i64 = row.try_get("count").unwrap_or(0);
+            Some(count as usize)
+        }
+        Err(e) => {
+            log::error!("Count query error: {}", e);
+            breaker.record_error(&e);
+            if let Some(failures) = req.app_data::<web::Data<CountFailures>>() {
+                failures.record(config, &table, &e);
+            }
+            warnings.push(render_message(req, &Message::new("count_failed")).0);
+            None
+        }
+    };
+    if total_count.is_some() {
+        if let Err(e) = tx.commit().await {
+            log::warn!("Commit failed: {}", e);
+        }
+    }
+    
+    let results: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| {
+            let mut obj = row_to_json(row, config.value_format());
+            protect_columns(config, &table, &mut obj);
+            if previous.is_some() {
+                for a in &aggregates {
+                    let alias = a.alias();
+                    let delta = match (obj.get(&alias), obj.get(&format!("previous_{}", alias))) {
+                        (Some(current), Some(previous)) => delta_pct(current, previous),
+                        _ => serde_json::Value::Null,
+                    };
+                    obj.insert(format!("{}_delta_pct", alias), delta);
+                }
+            }
+            serde_json::Value::Object(obj)
+        })
+        .collect();
+    
+    let mut builder = HttpResponse::Ok();
+    if let Some((rollup, _)) = rollup {
+        builder.insert_header(("X-Datapi-Rollup", rollup.table.as_str()));
+    }
+    QueryResult {
+        count: results.len(),
+        data: results,
+        page,
+        page_size,
+        total_count,
+        total_count_estimated: false,
+        result_token: None,
+        next_cursor: None,
+        sort_fingerprint: None,
+        warnings,
+        total_pages: None,
+        has_next: false,
+    }
+    .into_response(req, builder)
+}
+
+// A rollup of `table` answers an aggregate request when its dimensions cover
+// the grouping and filter columns and every aggregate can be recomputed from
+// its measures. Returns the rollup with the re-aggregating select list.
+pub(crate) fn find_rollup<'a>(
+    config: &'a Config,
+    table: &str,
+    group_by: &[String],
+    aggregates: &[Aggregate],
+    filters: &FilterExpr,
+) -> Option<(&'a RollupConfig, Vec<String>)> {
+    // Configured filters may reference any column of the source
+    if filters.has_sql() {
+        return None;
+    }
+    let filter_columns: Vec<String> =
+        filters.conditions().iter().map(|c| c.column.to_lowercase()).collect();
+    
+    config.rollups.iter().find_map(|rollup| {
+        if !rollup.source.eq_ignore_ascii_case(table) {
+            return None;
+        }
+        let dimensions: Vec<String> = rollup.dimensions.iter().map(|d| d.to_lowercase()).collect();
+        if !group_by.iter().chain(&filter_columns).all(|c| dimensions.contains(c)) {
+            return None;
+        }
+        let measures = aggregates
+            .iter()
+            .map(|a| rollup_measure(rollup, a))
+            .collect::<Option<Vec<_>>>()?;
+        Some((rollup, measures))
+    })
+}
+
+pub(crate) fn rollup_measure(rollup: &RollupConfig, aggregate: &Aggregate) -> Option<String> {
+    let stored = |function: &str| {
+        let wanted = Aggregate { function: function.to_string(), column: aggregate.column.clone() };
+        rollup.aggregates.contains(&wanted).then(|| wanted.alias())
+    };
+    let expression = match aggregate.function.as_str() {
+        "count" => format!("sum({})::bigint", stored("count")?),
+        "sum" => format!("sum({})", stored("sum")?),
+        "min" => format!("min({})", stored("min")?),
+        "max" => format!("max({})", stored("max")?),
+        "avg" => format!("sum({}) / nullif(sum({}), 0)", stored("sum")?, stored("count")?),
+        _ => return None,
+    };
+    Some(format!("{} AS {}", expression, aggregate.alias()))
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct TableInfo {
+    pub(crate) name: String,
+    pub(crate) schema: String,
+    // table, view, materialized_view, foreign_table or partitioned_table
+    pub(crate) kind: &'static str,
+    // From the planner statistics; None until the table is analyzed
+    pub(crate) estimated_rows: Option<i64>,
+    // COMMENT ON TABLE
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+    pub(crate) readable: bool,
+    pub(crate) writable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) deprecation: Option<Deprecation>,
+    // What the table config adds to reads: `?preset=` filters, the filters
+    // every read gets and the computed columns
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) presets: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) filters: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) virtual_columns: Vec<String>,
+}
+
+// Lists the tables and views the API can serve: those visible to datapi's
+// role on its search path, and exposed if DATAPI_EXPOSE_TABLES is set.
+// `readable` and `writable` combine the role's privileges with the config.
+pub(crate) async fn list_tables(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    exposure: Option<web::Data<Exposure>>,
+) -> impl Responder {
+    let rows = sqlx::query(&format!(
+        "SELECT n.nspname::text AS schema_name,
+                c.relname::text AS name,
+                c.relkind::text AS kind,
+                CASE WHEN c.reltuples >= 0 THEN c.reltuples::bigint END AS estimated_rows,
+                obj_description(c.oid, 'pg_class') AS description,
+                has_table_privilege(c.oid, 'SELECT') AS can_select,
+                has_table_privilege(c.oid, 'INSERT')
+                    OR has_table_privilege(c.oid, 'UPDATE')
+                    OR has_table_privilege(c.oid, 'DELETE') AS can_write,
+                {} AS updatable
+         FROM pg_class c
+         JOIN pg_namespace n ON n.oid = c.relnamespace
+         WHERE c.relkind IN ('r', 'v', 'm', 'f', 'p')
+           AND pg_table_is_visible(c.oid)
+           AND c.relnamespace NOT IN ('pg_catalog'::regnamespace, 'information_schema'::regnamespace)
+           AND c.relname NOT LIKE $1
+         ORDER BY c.relname",
+        RELATION_UPDATABLE_SQL
+    ))
+    .bind(format!("{}%", RESULT_TABLE_PREFIX))
+    .fetch_all(pool.get_ref())
+    .await;
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            log::error!("Database error: {}", e);
+            return error_response(&req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"));
+        }
+    };
+    
+    let tables: Vec<TableInfo> = rows
+        .iter()
+        .filter_map(|row| {
+            let name: String = row.try_get("name").unwrap_or_default();
+            if exposure.as_ref().is_some_and(|e| !e.is_exposed(&name)) {
+                return None;
+            }
+            let grant = match req.extensions().get::<TokenGrants>() {
+                Some(grants) => grants.scope(&name)?,
+                None => ApiScope::Readwrite,
+            };
+            let kind = relation_kind_name(&row.try_get::<String, _>("kind").unwrap_or_default());
+            let can_write: bool = row.try_get("can_write").unwrap_or(false);
+            let updatable: bool = row.try_get("updatable").unwrap_or(false);
+            let table_config = config.table(&name);
+            Some(TableInfo {
+                schema: row.try_get("schema_name").unwrap_or_default(),
+                kind,
+                estimated_rows: row.try_get("estimated_rows").unwrap_or_default(),
+                description: row.try_get("description").unwrap_or_default(),
+                readable: row.try_get("can_select").unwrap_or(false),
+                writable: config.writes_enabled
+                    && !table_config.is_some_and(|t| t.read_only)
+                    && can_write
+                    && updatable
+                    && grant == ApiScope::Readwrite,
+                deprecation: table_config.and_then(|t| t.deprecation.clone()),
+                presets: table_config.map(|t| t.presets.clone()).unwrap_or_default(),
+                filters: table_config.map(|t| t.filters.clone()).unwrap_or_default(),
+                virtual_columns: table_config.map(|t| t.virtual_columns.keys().cloned().collect()).unwrap_or_default(),
+                name,
+            })
+        })
+        .collect();
+    HttpResponse::Ok().json(serde_json::json!({ "count": tables.len(), "tables": tables }))
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ColumnInfo {
+    pub(crate) name: String,
+    #[serde(rename = "type")]
+    pub(crate) kind: String,
+    pub(crate) nullable: bool,
+    pub(crate) default: Option<String>,
+    // COMMENT ON COLUMN
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) description: Option<String>,
+    // Served as ciphertext, see encrypted_columns
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) encrypted: bool,
+    // See masked_columns
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) masked: Option<Mask>,
+    // Classification tags from column_tags
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) tags: Vec<String>,
+}
+
+pub(crate) fn column_tags(table_config: Option<&TableConfig>, column: &str) -> Vec<String> {
+    table_config
+        .and_then(|t| t.column_tags.get(&column.to_lowercase()))
+        .cloned()
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ForeignKeyInfo {
+    pub(crate) name: String,
+    pub(crate) columns: Vec<String>,
+    pub(crate) references: ReferencedKey,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ReferencedKey {
+    pub(crate) schema: String,
+    pub(crate) table: String,
+    pub(crate) columns: Vec<String>,
+}
+
+// After a migration: drops the cached table metadata and retires the
+// connections holding statements prepared against the old schema
+pub(crate) async fn reload_schema(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    cache: web::Data<SchemaCache>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    cache.clear();
+    *SCHEMA_RELOADED.lock().unwrap() = Some(Instant::now());
+    log::info!("Schema metadata reloaded");
+    HttpResponse::NoContent().finish()
+}
+
+// Describes a table's columns and keys from information_schema, so clients
+// can build forms and filters without knowing the schema up front.
+// Constraints are only visible for tables datapi's role has rights on.
+pub(crate) async fn table_schema(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    exposure: Option<web::Data<Exposure>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let table = match sanitize_table_name(&path.into_inner()) {
+        Ok(t) => t,
+        Err(e) => return bad_request(&req, e),
+    };
+    if exposure.is_some_and(|exposure| !exposure.is_exposed(&table)) {
+        return error_response(&req, StatusCode::NOT_FOUND, Message::new("table_not_found").arg("table", &table));
+    }
+    let table = match resolve_table(&req, &pool, &config, table).await {
+        Ok(t) => t,
+        Err(response) => return response,
+    };
+    let database_error = |e: sqlx::Error| {
+        log::error!("Database error: {}", e);
+        error_response(&req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"))
+    };
+    
+    let relation = sqlx::query(&format!(
+        "SELECT n.nspname::text AS schema_name, c.relname::text AS table_name,
+                c.relkind::text AS kind, {} AS updatable,
+                obj_description(c.oid, 'pg_class') AS description
+         FROM pg_class c
+         JOIN pg_namespace n ON n.oid = c.relnamespace
+         WHERE c.oid = to_regclass($1)",
+        RELATION_UPDATABLE_SQL
+    ))
+    .bind(&table)
+    .fetch_optional(pool.get_ref())
+    .await;
+    let relation = match relation {
+        Ok(Some(r)) => r,
+        Ok(None) => return error_response(
+            &req,
+            StatusCode::NOT_FOUND,
+            Message::new("table_not_found").arg("table", &table),
+        ),
+        Err(e) => return database_error(e),
+    };
+    let schema_name: String = relation.try_get("schema_name").unwrap_or_default();
+    let table_name: String = relation.try_get("table_name").unwrap_or_default();
+    let description: Option<String> = relation.try_get("description").unwrap_or_default();
+    let kind = relation_kind_name(&relation.try_get::<String, _>("kind").unwrap_or_default());
+    let updatable: bool = relation.try_get("updatable").unwrap_or(false);
+    
+    // information_schema leaves materialized views out
+    let columns_query = match kind {
+        "materialized_view" => {
+            "SELECT a.attname::text AS name,
+                    format_type(a.atttypid, NULL) AS type,
+                    NOT a.attnotnull AS nullable,
+                    NULL::text AS column_default,
+                    col_description(a.attrelid, a.attnum) AS description
+             FROM pg_attribute a
+             JOIN pg_class c ON c.oid = a.attrelid
+             JOIN pg_namespace n ON n.oid = c.relnamespace
+             WHERE n.nspname = $1 AND c.relname = $2 AND a.attnum > 0 AND NOT a.attisdropped
+             ORDER BY a.attnum"
+        }
+        _ => {
+            "SELECT column_name::text AS name,
+                    CASE WHEN data_type IN ('USER-DEFINED', 'ARRAY') THEN udt_name ELSE data_type END::text AS type,
+                    is_nullable = 'YES' AS nullable,
+                    column_default::text AS column_default,
+                    col_description(format('%I.%I', table_schema, table_name)::regclass, ordinal_position) AS description
+             FROM information_schema.columns
+             WHERE table_schema = $1 AND table_name = $2
+             ORDER BY ordinal_position"
+        }
+    };
+    let columns = sqlx::query(columns_query)
+        .bind(&schema_name)
+        .bind(&table_name)
+        .fetch_all(pool.get_ref())
+        .await;
+    let columns = match columns {
+        Ok(rows) => rows,
+        Err(e) => return database_error(e),
+    };
+    
+    // One row per key column, in key order; a foreign key column is paired
+    // with the column it references
+    let keys = sqlx::query(
+        "SELECT tc.constraint_name::text AS name,
+                tc.constraint_type::text AS kind,
+                kcu.column_name::text AS column_name,
+                ref.table_schema::text AS ref_schema,
+                ref.table_name::text AS ref_table,
+                ref.column_name::text AS ref_column
+         FROM information_schema.table_constraints tc
+         JOIN information_schema.key_column_usage kcu
+           ON kcu.constraint_schema = tc.constraint_schema AND kcu.constraint_name = tc.constraint_name
+         LEFT JOIN information_schema.referential_constraints rc
+           ON rc.constraint_schema = tc.constraint_schema AND rc.constraint_name = tc.constraint_name
+         LEFT JOIN information_schema.key_column_usage ref
+           ON ref.constraint_schema = rc.unique_constraint_schema
+          AND ref.constraint_name = rc.unique_constraint_name
+          AND ref.ordinal_position = kcu.position_in_unique_constraint
+         WHERE tc.table_schema = $1 AND tc.table_name = $2
+           AND tc.constraint_type IN ('PRIMARY KEY', 'FOREIGN KEY')
+         ORDER BY tc.constraint_name, kcu.ordinal_position",
+    )
+    .bind(&schema_name)
+    .bind(&table_name)
+    .fetch_all(pool.get_ref())
+    .await;
+    let keys = match keys {
+        Ok(rows) => rows,
+        Err(e) => return database_error(e),
+    };
+    
+    let table_config = config.table(&table_name);
+    let encrypted = |column: &str| table_config.is_some_and(|t| t.encrypted_columns.iter().any(|c| c == column));
+    let columns: Vec<ColumnInfo> = columns
+        .iter()
+        .map(|row| {
+            let name: String = row.try_get("name").unwrap_or_default();
+            ColumnInfo {
+                encrypted: encrypted(&name),
+                masked: config.mask(&table_name, &name),
+                tags: column_tags(table_config, &name),
+                name,
+                kind: row.try_get("type").unwrap_or_default(),
+                nullable: row.try_get("nullable").unwrap_or(true),
+                default: row.try_get("column_default").unwrap_or_default(),
+                description: row.try_get("description").unwrap_or_default(),
+            }
+        })
+        .collect();
+    
+    let mut primary_key = Vec::new();
+    let mut foreign_keys: Vec<ForeignKeyInfo> = Vec::new();
+    for row in &keys {
+        let name: String = row.try_get("name").unwrap_or_default();
+        let column: String = row.try_get("column_name").unwrap_or_default();
+        if row.try_get::<String, _>("kind").unwrap_or_default() == "PRIMARY KEY" {
+            primary_key.push(column);
+            continue;
+        }
+        let referenced: String = row.try_get("ref_column").unwrap_or_default();
+        match foreign_keys.last_mut() {
+            Some(key) if key.name == name => {
+                key.columns.push(column);
+                key.references.columns.push(referenced);
+            }
+            _ => foreign_keys.push(ForeignKeyInfo {
+                name,
+                columns: vec![column],
+                references: ReferencedKey {
+                    schema: row.try_get("ref_schema").unwrap_or_default(),
+                    table: row.try_get("ref_table").unwrap_or_default(),
+                    columns: vec![referenced],
+                },
+            }),
+        }
+    }
+    let virtual_columns: Vec<&String> = table_config.map(|t| t.virtual_columns.keys().collect()).unwrap_or_default();
+    let empty = BTreeMap::new();
+    
+    HttpResponse::Ok().json(serde_json::json!({
+        "table": table_name,
+        "schema": schema_name,
+        "kind": kind,
+        "writable": config.writes_enabled && !config.read_only_table(&table_name) && updatable,
+        "description": description,
+        "columns": columns,
+        "virtual_columns": virtual_columns,
+        "presets": table_config.map_or(&empty, |t| &t.presets),
+        "filters": table_config.map(|t| t.filters.as_slice()).unwrap_or_default(),
+        "primary_key": primary_key,
+        "foreign_keys": foreign_keys
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct HistoryParams {
+    // Only versions recorded at or before this date or timestamp
+    pub(crate) until: Option<String>,
+    pub(crate) limit: Option<i64>,
+}
+
+pub(crate) const DEFAULT_HISTORY_LIMIT: i64 = 100;
+pub(crate) const MAX_HISTORY_LIMIT: i64 = 1000;
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RowVersion {
+    pub(crate) operation: String,
+    pub(crate) changed_by: String,
+    pub(crate) changed_at: chrono::DateTime<chrono::Utc>,
+    pub(crate) row: serde_json::Value,
+}
+
+pub(crate) fn parse_history_until(value: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    if let Ok(ts) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Some(ts.with_timezone(&chrono::Utc));
+    }
+    // A bare date covers the whole day
+    let date = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()?;
+    Some(date.succ_opt()?.and_hms_opt(0, 0, 0)?.and_utc() - chrono::Duration::microseconds(1))
+}
+
+// Previous versions of a row, newest first, with who changed it and when.
+pub(crate) async fn row_history(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    path: web::Path<(String, String)>,
+    params: web::Query<HistoryParams>,
+) -> impl Responder {
+    let (table, id) = path.into_inner();
+    let table = match sanitize_table_name(&table) {
+        Ok(t) => t.to_lowercase(),
+        Err(e) => return bad_request(&req, e),
+    };
+    let Some(history) = config.table(&table).and_then(|t| t.history.as_ref()) else {
+        return error_response(
+            &req,
+            StatusCode::NOT_FOUND,
+            Message::new("history_not_configured").arg("table", &table),
+        );
+    };
+    let until = match params.until.as_deref().map(parse_history_until) {
+        Some(None) => return bad_request(&req, Message::new("invalid_history_until")),
+        Some(until) => until,
+        None => None,
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_HISTORY_LIMIT).clamp(1, MAX_HISTORY_LIMIT);
+    
+    let rows = sqlx::query(
+        "SELECT operation, changed_by, changed_at, row_data::text AS row_data
+         FROM datapi_history.changes
+         WHERE table_name = $1 AND row_key = $2 AND ($3::timestamptz IS NULL OR changed_at <= $3)
+         ORDER BY id DESC
+         LIMIT $4",
+    )
+    .bind(&table)
+    .bind(&id)
+    .bind(until)
+    .bind(limit)
+    .fetch_all(pool.get_ref())
+    .await;
+    
+    match rows {
+        Ok(rows) => {
+            let versions: Vec<RowVersion> = rows
+                .iter()
+                .map(|row| {
+                    let data: String = row.try_get("row_data").unwrap_or_default();
+                    let mut data = match serde_json::from_str(&data) {
+                        Ok(serde_json::Value::Object(obj)) => obj,
+                        _ => serde_json::Map::new(),
+                    };
+                    protect_columns(&config, &table, &mut data);
+                    RowVersion {
+                        operation: row.try_get("operation").unwrap_or_default(),
+                        changed_by: row.try_get("changed_by").unwrap_or_default(),
+                        changed_at: row.try_get("changed_at").unwrap_or_default(),
+                        row: serde_json::Value::Object(data),
+                    }
+                })
+                .collect();
+            HttpResponse::Ok().json(serde_json::json!({
+                "table": table,
+                "key": history.key,
+                "id": id,
+                "versions": versions
+            }))
+        }
+        Err(e) => {
+            log::error!("Database error: {}", e);
+            error_response(&req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PolicyInfo {
+    pub(crate) name: String,
+    pub(crate) permissive: String,
+    pub(crate) roles: Vec<String>,
+    pub(crate) command: String,
+    pub(crate) using: Option<String>,
+    pub(crate) with_check: Option<String>,
+}
+
+// Lists the row-level security policies on a table that apply to the role
+// datapi connects as, so clients can see why rows are filtered out.
+pub(crate) async fn table_policies(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    path: web::Path<String>,
+) -> impl Responder {
+    let table = match sanitize_table_name(&path.into_inner()) {
+        Ok(t) => t,
+        Err(e) => return bad_request(&req, e),
+    };
+    let table = match resolve_table(&req, &pool, &config, table).await {
+        Ok(t) => t,
+        Err(response) => return response,
+    };
+    
+    let relation = sqlx::query(
+        "SELECT n.nspname::text AS schema_name,
+                c.relname::text AS table_name,
+                c.relrowsecurity AS rls_enabled,
+                c.relforcerowsecurity AS rls_forced,
+                COALESCE((SELECT rolsuper OR rolbypassrls FROM pg_roles WHERE rolname = current_user), false)
+                    OR (pg_get_userbyid(c.relowner) = current_user AND NOT c.relforcerowsecurity)
+                    AS bypass_rls
+         FROM pg_class c
+         JOIN pg_namespace n ON n.oid = c.relnamespace
+         WHERE c.oid = to_regclass($1)",
+    )
+    .bind(&table)
+    .fetch_optional(pool.get_ref())
+    .await;
+    
+    let relation = match relation {
+        Ok(Some(r)) => r,
+        Ok(None) => return error_response(
+            &req,
+            StatusCode::NOT_FOUND,
+            Message::new("table_not_found").arg("table", &table),
+        ),
+        Err(e) => {
+            log::error!("Database error: {}", e);
+            return error_response(&req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"));
+        }
+    };
+    let schema_name: String = relation.try_get("schema_name").unwrap_or_default();
+    let table_name: String = relation.try_get("table_name").unwrap_or_default();
+    
+    let policies = sqlx::query(
+        "SELECT p.policyname::text AS name, p.permissive, p.roles::text[] AS roles, p.cmd,
+                p.qual, p.with_check
+         FROM pg_policies p
+         WHERE p.schemaname = $1
+           AND p.tablename = $2
+           AND EXISTS (
+               SELECT 1 FROM unnest(p.roles) AS r(role)
+               WHERE r.role = 'public' OR pg_has_role(current_user, r.role, 'MEMBER')
+           )
+         ORDER BY p.policyname",
+    )
+    .bind(&schema_name)
+    .bind(&table_name)
+    .fetch_all(pool.get_ref())
+    .await;
+    
+    match policies {
+        Ok(rows) => {
+            let policies: Vec<PolicyInfo> = rows
+                .iter()
+                .map(|row| PolicyInfo {
+                    name: row.try_get("name").unwrap_or_default(),
+                    permissive: row.try_get("permissive").unwrap_or_default(),
+                    roles: row.try_get("roles").unwrap_or_default(),
+                    command: row.try_get("cmd").unwrap_or_default(),
+                    using: row.try_get("qual").unwrap_or_default(),
+                    with_check: row.try_get("with_check").unwrap_or_default(),
+                })
+                .collect();
+            
+            HttpResponse::Ok().json(serde_json::json!({
+                "table": table_name,
+                "schema": schema_name,
+                "rls_enabled": relation.try_get::<bool, _>("rls_enabled").unwrap_or(false),
+                "rls_forced": relation.try_get::<bool, _>("rls_forced").unwrap_or(false),
+                "bypass_rls": relation.try_get::<bool, _>("bypass_rls").unwrap_or(false),
+                "policies": policies
+            }))
+        }
+        Err(e) => {
+            log::error!("Database error: {}", e);
+            error_response(&req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"))
+        }
+    }
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Returns the error response to send when the request lacks the admin token.
+pub(crate) fn reject_non_admin(req: &HttpRequest, config: &Config) -> Option<HttpResponse> {
+    if config.admin_token.is_none() {
+        return Some(error_response(req, StatusCode::FORBIDDEN, Message::new("admin_disabled")));
+    }
+    if is_admin(req, config) {
+        return None;
+    }
+    Some(error_response(req, StatusCode::UNAUTHORIZED, Message::new("unauthorized")))
+}
+
+pub(crate) fn is_admin(req: &HttpRequest, config: &Config) -> bool {
+    let Some(expected) = &config.admin_token else {
+        return false;
+    };
+    let presented = req
+        .headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    presented.is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes()))
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RunningQuery {
+    pub(crate) pid: i32,
+    pub(crate) table: Option<String>,
+    pub(crate) state: Option<String>,
+    pub(crate) duration_ms: Option<f64>,
+    pub(crate) sql: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ApprovalSetting {
+    pub(crate) required: bool,
+}
+
+// Exposure state: exposed and pending tables and whether new tables need
+// approval.
+pub(crate) async fn list_exposed_tables(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    exposure: Option<web::Data<Exposure>>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    let Some(exposure) = exposure else {
+        return HttpResponse::Ok().json(serde_json::json!({ "patterns": null }));
+    };
+    let tables = exposure.tables.lock().unwrap();
+    HttpResponse::Ok().json(serde_json::json!({
+        "patterns": exposure.patterns,
+        "require_approval": exposure.require_approval.load(Ordering::Relaxed),
+        "exposed": tables.exposed,
+        "pending": tables.pending,
+    }))
+}
+
+pub(crate) async fn approve_table(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    exposure: Option<web::Data<Exposure>>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    let table = path.into_inner().to_lowercase();
+    let approved = exposure.is_some_and(|exposure| {
+        let mut tables = exposure.tables.lock().unwrap();
+        let pending = tables.pending.remove(&table);
+        if pending {
+            tables.exposed.insert(table.clone());
+        }
+        pending
+    });
+    if !approved {
+        return error_response(
+            &req,
+            StatusCode::NOT_FOUND,
+            Message::new("table_not_pending").arg("table", &table),
+        );
+    }
+    log::info!("Table {} approved", table);
+    HttpResponse::NoContent().finish()
+}
+
+pub(crate) async fn set_table_approval(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    exposure: Option<web::Data<Exposure>>,
+    body: web::Json<ApprovalSetting>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    if let Some(exposure) = exposure {
+        exposure.require_approval.store(body.required, Ordering::Relaxed);
+    }
+    HttpResponse::NoContent().finish()
+}
+
+pub(crate) async fn list_queries(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+
+    let result = sqlx::query(
+        "SELECT pid, state, query,
+                EXTRACT(EPOCH FROM now() - query_start)::float8 * 1000 AS duration_ms,
+                substring(query FROM '^/\\* datapi table=(\\w+) \\*/') AS table_name
+         FROM pg_stat_activity
+         WHERE application_name = $1
+           AND state <> 'idle'
+           AND pid <> pg_backend_pid()
+         ORDER BY query_start",
+    )
+    .bind(APPLICATION_NAME)
+    .fetch_all(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(rows) => {
+            let queries: Vec<RunningQuery> = rows
+                .iter()
+                .map(|row| RunningQuery {
+                    pid: row.try_get("pid").unwrap_or_default(),
+                    table: row.try_get("table_name").unwrap_or_default(),
+                    state: row.try_get("state").unwrap_or_default(),
+                    duration_ms: row.try_get("duration_ms").unwrap_or_default(),
+                    sql: row.try_get("query").unwrap_or_default(),
+                })
+                .collect();
+
+            HttpResponse::Ok().json(serde_json::json!({
+                "queries": queries
+            }))
+        }
+        Err(e) => {
+            log::error!("Database error: {}", e);
+            error_response(&req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"))
+        }
+    }
+}
+
+pub(crate) async fn cancel_query(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    path: web::Path<String>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+
+    let pid: i32 = match path.into_inner().parse() {
+        Ok(p) => p,
+        Err(_) => return bad_request(&req, Message::new("invalid_pid")),
+    };
+
+    // Only backends owned by this API may be cancelled.
+    let result = sqlx::query(
+        "SELECT pg_cancel_backend(pid) AS cancelled
+         FROM pg_stat_activity
+         WHERE pid = $1 AND application_name = $2 AND pid <> pg_backend_pid()",
+    )
+    .bind(pid)
+    .bind(APPLICATION_NAME)
+    .fetch_optional(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(Some(row)) => {
+            let cancelled: bool = row.try_get("cancelled").unwrap_or(false);
+            log::info!("Cancel requested for pid {}: {}", pid, cancelled);
+            HttpResponse::Ok().json(serde_json::json!({
+                "pid": pid,
+                "cancelled": cancelled
+            }))
+        }
+        Ok(None) => error_response(
+            &req,
+            StatusCode::NOT_FOUND,
+            Message::new("query_not_found").arg("pid", pid),
+        ),
+        Err(e) => {
+            log::error!("Database error: {}", e);
+            error_response(&req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"))
+        }
+    }
+}
+
+// Checked against the admin token, not API keys or JWTs
+pub(crate) const SQL_PATH: &str = "/_sql";
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SqlRequest {
+    pub(crate) sql: String,
+    // Bound to $1, $2, ... by their JSON type; objects and arrays as jsonb
+    #[serde(default)]
+    pub(crate) params: Vec<serde_json::Value>,
+}
+
+// The statement's first keyword, past leading comments and parentheses
+pub(crate) fn statement_keyword(sql: &str) -> String {
+    let mut rest = sql.trim_start();
+    loop {
+        if let Some(line) = rest.strip_prefix("--") {
+            rest = line.split_once('\n').map_or("", |(_, r)| r).trim_start();
+        } else if let Some(block) = rest.strip_prefix("/*") {
+            rest = block.split_once("*/").map_or("", |(_, r)| r).trim_start();
+        } else if let Some(inner) = rest.strip_prefix('(') {
+            rest = inner.trim_start();
+        } else {
+            break;
+        }
+    }
+    rest.chars().take_while(|c| c.is_ascii_alphabetic()).collect::<String>().to_lowercase()
+}
+
+// Runs a parameterized read for internal tooling, behind the admin token and
+// DATAPI_ENABLE_SQL. Only SELECT-like statements get through, and they run
+// as a subquery in a read-only transaction, so a data-modifying CTE or a
+// second statement fails in Postgres even if it slips past the keyword check.
+pub(crate) async fn run_sql(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    params: web::Query<RpcParams>,
+    body: web::Json<SqlRequest>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    if !config.sql_enabled {
+        return error_response(&req, StatusCode::FORBIDDEN, Message::new("sql_disabled"));
+    }
+    let body = body.into_inner();
+    let keyword = statement_keyword(&body.sql);
+    if !matches!(keyword.as_str(), "select" | "with" | "values" | "table") {
+        return bad_request(&req, Message::new("sql_not_select").arg("statement", keyword.to_uppercase()));
+    }
+    let page = params.page.unwrap_or(1).max(1);
+    let page_size = config.page_size(params.page_size);
+    let sql = paged_sql(
+        format!("/* datapi sql */ SELECT * FROM ({}) q", body.sql.trim().trim_end_matches(';')),
+        body.params.len(),
+    );
+    log::info!("Executing admin SQL: {}", sql);
+    
+    let result = async {
+        let mut tx = pool.begin().await?;
+        sqlx::query("SET TRANSACTION READ ONLY").execute(&mut *tx).await?;
+        apply_request_timeout(&mut tx, &config, params.timeout_ms).await?;
+        let query = body.params.iter().fold(sqlx::query(&sql), |query, value| match value {
+            serde_json::Value::Null => query.bind(None::<String>),
+            serde_json::Value::Bool(b) => query.bind(*b),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(i) => query.bind(i),
+                None => query.bind(n.as_f64()),
+            },
+            serde_json::Value::String(s) => query.bind(s.clone()),
+            other => query.bind(other.clone()),
+        });
+        let query = query.bind(page_size as i64).bind(((page - 1) * page_size) as i64);
+        let rows = query.fetch_all(&mut *tx).await?;
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(rows)
+    };
+    let rows = match result.await {
+        Ok(rows) => rows,
+        Err(e) if matches!(&e, sqlx::Error::Database(_)) && !is_statement_timeout(&e) => {
+            return bad_request(&req, Message::new("database_rejected").arg("error", postgres_message(&e)));
+        }
+        Err(e) => return database_error_response(&req, &breaker, e),
+    };
+    breaker.record_success();
+    let data: Vec<serde_json::Value> = rows.iter().map(|row| serde_json::Value::Object(row_to_json(row, config.value_format()))).collect();
+    QueryResult {
+        count: data.len(),
+        data,
+        page,
+        page_size,
+        total_count: None,
+        total_count_estimated: false,
+        result_token: None,
+        next_cursor: None,
+        sort_fingerprint: None,
+        warnings: Vec::new(),
+        total_pages: None,
+        has_next: false,
+    }
+    .into_response(&req, HttpResponse::Ok())
+}
+
+// Limits of a table that PATCH /_admin/config changes at runtime. A value set
+// there replaces the table's configured one until it is set to null again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub(crate) struct TableLimitValues {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cache_ttl_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) max_rows: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) rate_limit_per_minute: Option<u32>,
+}
+
+pub(crate) const TABLE_LIMITS: &[&str] = &["cache_ttl_secs", "max_rows", "rate_limit_per_minute"];
+
+impl TableLimitValues {
+    pub(crate) fn configured(config: &Config, table: &str) -> Self {
+        let table = config.table(table.trim_matches('"'));
+        TableLimitValues {
+            cache_ttl_secs: table.and_then(|t| t.cache_ttl_secs),
+            max_rows: table.and_then(|t| t.max_rows),
+            rate_limit_per_minute: table.and_then(|t| t.rate_limit_per_minute),
+        }
+    }
+
+    pub(crate) fn or(self, other: Self) -> Self {
+        TableLimitValues {
+            cache_ttl_secs: self.cache_ttl_secs.or(other.cache_ttl_secs),
+            max_rows: self.max_rows.or(other.max_rows),
+            rate_limit_per_minute: self.rate_limit_per_minute.or(other.rate_limit_per_minute),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == TableLimitValues::default()
+    }
+
+    // Sets (or with null clears) one limit, e.g. from a PATCH body
+    pub(crate) fn set(&mut self, limit: &str, value: &serde_json::Value) -> Result<(), ()> {
+        let value = match value {
+            serde_json::Value::Null => None,
+            value => Some(value.as_u64().ok_or(())?),
+        };
+        match limit {
+            "cache_ttl_secs" => self.cache_ttl_secs = value,
+            "max_rows" => self.max_rows = value,
+            "rate_limit_per_minute" => {
+                self.rate_limit_per_minute = value.map(u32::try_from).transpose().map_err(|_| ())?
+            }
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+}
+
+// Runtime overrides of the per-table limits, keyed by lowercase table, and
+// the token buckets of the tables' rate limits
+#[derive(Default)]
+pub(crate) struct TableLimits {
+    pub(crate) overrides: Mutex<BTreeMap<String, TableLimitValues>>,
+    pub(crate) buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl TableLimits {
+    pub(crate) fn get(&self, config: &Config, table: &str) -> TableLimitValues {
+        let key = table.trim_matches('"').to_lowercase();
+        let live = self.overrides.lock().unwrap().get(&key).copied().unwrap_or_default();
+        live.or(TableLimitValues::configured(config, table))
+    }
+
+    pub(crate) fn max_rows(&self, config: &Config, table: &str) -> Option<u64> {
+        match self.get(config, table).max_rows {
+            Some(max_rows) => Some(max_rows).filter(|m| *m > 0),
+            None => config.max_rows,
+        }
+    }
+
+    // Takes a token from the client's bucket for the table, as RateLimiter
+    // does for all requests. 0 or no rate_limit_per_minute doesn't limit.
+    pub(crate) fn acquire(&self, config: &Config, table: &str, client: &str) -> Option<u64> {
+        let per_minute = self.get(config, table).rate_limit_per_minute.filter(|r| *r > 0)?;
+        let key = format!("{}|{}", table.trim_matches('"').to_lowercase(), client);
+        let rate = f64::from(per_minute);
+        take_token(&mut self.buckets.lock().unwrap(), &key, rate / 60.0, rate)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TableLimitsPatch {
+    // Table -> limit -> a non-negative integer, or null for the configured value
+    pub(crate) tables: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TableLimitsParams {
+    // Also writes the values into the DATAPI_CONFIG file
+    #[serde(default)]
+    pub(crate) persist: bool,
+}
+
+// The limits in effect for every table that has any, and which of them were
+// changed at runtime
+pub(crate) fn table_limits_body(config: &Config, limits: &TableLimits) -> serde_json::Value {
+    let overrides = limits.overrides.lock().unwrap().clone();
+    let names: std::collections::BTreeSet<&String> = config.tables.keys().chain(overrides.keys()).collect();
+    let tables: serde_json::Map<String, serde_json::Value> = names
+        .into_iter()
+        .filter_map(|table| {
+            let live = overrides.get(table).copied().unwrap_or_default();
+            let values = limits.get(config, table);
+            if values.is_empty() {
+                return None;
+            }
+            let mut body = serde_json::to_value(values).ok()?;
+            if !live.is_empty() {
+                let changed: Vec<&str> = [
+                    ("cache_ttl_secs", live.cache_ttl_secs.is_some()),
+                    ("max_rows", live.max_rows.is_some()),
+                    ("rate_limit_per_minute", live.rate_limit_per_minute.is_some()),
+                ]
+                .into_iter()
+                .filter_map(|(limit, set)| set.then_some(limit))
+                .collect();
+                body["overridden"] = serde_json::json!(changed);
+            }
+            Some((table.clone(), body))
+        })
+        .collect();
+    serde_json::json!({ "tables": tables })
+}
+
+pub(crate) async fn table_limits_status(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    limits: web::Data<TableLimits>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    HttpResponse::Ok().json(table_limits_body(&config, &limits))
+}
+
+// Changes the tables' limits for this instance. Cached responses of a table
+// whose TTL changed are dropped, so the new TTL applies right away. With
+// ?persist=true the values are written into the config file as well, to
+// survive a restart.
+pub(crate) async fn update_table_limits(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    limits: web::Data<TableLimits>,
+    cache: web::Data<ResponseCache>,
+    params: web::Query<TableLimitsParams>,
+    body: web::Json<TableLimitsPatch>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    let path = match env::var("DATAPI_CONFIG") {
+        Ok(path) => Some(path),
+        Err(_) if params.persist => return bad_request(&req, Message::new("config_not_persistable")),
+        Err(_) => None,
+    };
+    let mut changes = Vec::new();
+    {
+        let overrides = limits.overrides.lock().unwrap();
+        for (table, values) in &body.tables {
+            if table.is_empty() || !table.split('.').all(|part| sanitize_table_name(part).is_ok()) {
+                return bad_request(&req, Message::new("invalid_table_name"));
+            }
+            let key = table.trim_matches('"').to_lowercase();
+            let mut live = overrides.get(&key).copied().unwrap_or_default();
+            for (limit, value) in values {
+                if live.set(limit, value).is_err() {
+                    let message = match TABLE_LIMITS.contains(&limit.as_str()) {
+                        true => Message::new("invalid_table_limit").arg("limit", limit).arg("table", table),
+                        false => Message::new("unknown_table_limit").arg("limit", limit).arg("limits", TABLE_LIMITS.join(", ")),
+                    };
+                    return bad_request(&req, message);
+                }
+            }
+            changes.push((key, live));
+        }
+    }
+    if params.persist {
+        let path = path.as_deref().unwrap_or_default();
+        if let Err(e) = persist_table_limits(path, &body.tables) {
+            log::error!("Failed to write the table limits to {}: {}", path, e);
+            return error_response(&req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("config_persist_failed").arg("error", e));
+        }
+    }
+    for (table, live) in changes {
+        let ttl_changed = {
+            let mut overrides = limits.overrides.lock().unwrap();
+            let before = overrides.get(&table).copied().unwrap_or_default();
+            match live.is_empty() {
+                true => overrides.remove(&table),
+                false => overrides.insert(table.clone(), live),
+            };
+            before.cache_ttl_secs != live.cache_ttl_secs
+        };
+        log::info!("Table limits of {} changed at runtime: {:?}", table, live);
+        if ttl_changed {
+            cache.invalidate(Some(&table)).await;
+        }
+    }
+    HttpResponse::Ok().json(table_limits_body(&config, &limits))
+}
+
+// Writes the changed limits under `tables` in the JSON config file, through
+// a temporary file so a failed write leaves the old file in place
+pub(crate) fn persist_table_limits(path: &str, tables: &BTreeMap<String, BTreeMap<String, serde_json::Value>>) -> Result<(), String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let mut file: serde_json::Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+    let root = file.as_object_mut().ok_or("the config file is not a JSON object")?;
+    let configured = root.entry("tables").or_insert_with(|| serde_json::json!({}));
+    let configured = configured.as_object_mut().ok_or("tables is not a JSON object")?;
+    for (table, values) in tables {
+        // Table names in the file are matched as the config matches them
+        let name = configured.keys().find(|k| k.eq_ignore_ascii_case(table)).cloned().unwrap_or_else(|| table.clone());
+        let entry = configured.entry(name).or_insert_with(|| serde_json::json!({}));
+        let entry = entry.as_object_mut().ok_or_else(|| format!("tables.{} is not a JSON object", table))?;
+        for (limit, value) in values {
+            match value {
+                serde_json::Value::Null => entry.remove(limit),
+                value => entry.insert(limit.clone(), value.clone()),
+            };
+        }
+    }
+    let text = serde_json::to_string_pretty(&file).map_err(|e| e.to_string())? + "\n";
+    let partial = format!("{}.partial", path);
+    std::fs::write(&partial, text).map_err(|e| e.to_string())?;
+    std::fs::rename(&partial, path).map_err(|e| e.to_string())
+}
+
+// Liveness, with the default pool's saturation; it doesn't touch the
+// database. Mock mode has no pool.
+pub(crate) async fn health_check(
+    pool: Option<web::Data<PgPool>>,
+    breaker: Option<web::Data<CircuitBreaker>>,
+) -> impl Responder {
+    let mut body = serde_json::json!({
+        "status": "healthy"
+    });
+    if let (Some(pool), Some(breaker)) = (pool, breaker) {
+        body["pool"] = serde_json::json!(PoolStats::of(&pool, &breaker));
+    }
+    HttpResponse::Ok().json(body)
+}
+
+// Name under which the DATABASE_URL pool is reported.
+pub(crate) const DEFAULT_DATABASE: &str = "default";
+
+pub(crate) const READINESS_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize)]
+pub(crate) struct DatabaseHealth {
+    pub(crate) status: &'static str,
+    pub(crate) latency_ms: Option<f64>,
+    pub(crate) error: Option<String>,
+    pub(crate) pool: PoolStats,
+    pub(crate) last_error: Option<LastError>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PoolStats {
+    pub(crate) size: u32,
+    pub(crate) idle: usize,
+    pub(crate) in_use: u32,
+    pub(crate) max: u32,
+    // Share of max connections in use; at 1 requests queue for a connection
+    pub(crate) saturation: f64,
+    pub(crate) acquire_timeouts: u64,
+}
+
+impl PoolStats {
+    pub(crate) fn of(pool: &PgPool, breaker: &CircuitBreaker) -> Self {
+        let size = pool.size();
+        let idle = pool.num_idle();
+        let in_use = size.saturating_sub(idle as u32);
+        let max = pool.options().get_max_connections();
+        PoolStats {
+            size,
+            idle,
+            in_use,
+            max,
+            saturation: (f64::from(in_use) / f64::from(max.max(1)) * 100.0).round() / 100.0,
+            acquire_timeouts: breaker.pool_timeouts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Health endpoints skip auth, rate limiting, the circuit breaker and the cache
+pub(crate) fn is_health_path(path: &str) -> bool {
+    path == "/health" || path == "/readyz" || path == "/health/ready"
+}
+
+pub(crate) async fn check_database(pool: &PgPool, breaker: &CircuitBreaker) -> DatabaseHealth {
+    let mut health = ping_database(pool, breaker).await;
+    if let Some(error) = &health.error {
+        if !breaker.is_open() {
+            breaker.note_error(error.clone());
+        }
+    }
+    health.last_error = breaker.last_error();
+    health
+}
+
+pub(crate) async fn ping_database(pool: &PgPool, breaker: &CircuitBreaker) -> DatabaseHealth {
+    let pool_stats = PoolStats::of(pool, breaker);
+    if breaker.is_open() {
+        return DatabaseHealth {
+            status: "down",
+            latency_ms: None,
+            error: Some("circuit breaker open".to_string()),
+            pool: pool_stats,
+            last_error: None,
+        };
+    }
+
+    let started = Instant::now();
+    let result = tokio::time::timeout(
+        READINESS_PROBE_TIMEOUT,
+        sqlx::query("SELECT 1").execute(pool),
+    )
+    .await;
+    let latency_ms = Some(started.elapsed().as_secs_f64() * 1000.0);
+
+    let (status, error) = match result {
+        Ok(Ok(_)) => ("up", None),
+        Ok(Err(e)) => ("down", Some(e.to_string())),
+        Err(_) => ("down", Some("timed out".to_string())),
+    };
+    DatabaseHealth { status, latency_ms, error, pool: pool_stats, last_error: None }
+}
+
+// Readiness reports every configured database separately; the instance is
+// only unready (503) when none of them is reachable.
+pub(crate) async fn readiness_check(
+    pool: web::Data<PgPool>,
+    breaker: web::Data<CircuitBreaker>,
+    named: Option<web::Data<Databases>>,
+    draining: web::Data<Draining>,
+) -> impl Responder {
+    if draining.0.load(Ordering::Relaxed) {
+        return HttpResponse::ServiceUnavailable().json(serde_json::json!({ "status": "draining" }));
+    }
+    let mut databases = HashMap::new();
+    databases.insert(DEFAULT_DATABASE, check_database(pool.get_ref(), &breaker).await);
+    for (name, database) in named.iter().flat_map(|named| named.0.iter()) {
+        databases.insert(name.as_str(), check_database(&database.pool, &database.breaker).await);
+    }
+
+    let up = databases.values().filter(|d| d.status == "up").count();
+    let (mut builder, status) = if up == databases.len() {
+        (HttpResponse::Ok(), "ready")
+    } else if up > 0 {
+        (HttpResponse::Ok(), "degraded")
+    } else {
+        (HttpResponse::ServiceUnavailable(), "unavailable")
+    };
+
+    builder.json(serde_json::json!({
+        "status": status,
+        "databases": databases
+    }))
+}
+
+// Set once a shutdown signal came in, so readiness fails while the requests
+// in flight finish
+#[derive(Default)]
+pub(crate) struct Draining(pub(crate) std::sync::atomic::AtomicBool);
+
+// Waits for SIGTERM or Ctrl-C, then stops the server gracefully: listeners
+// close at once and workers get DATAPI_SHUTDOWN_GRACE_SECS to finish their
+// requests before they are dropped.
+pub(crate) async fn shutdown_on_signal(server: actix_web::dev::ServerHandle, draining: web::Data<Draining>, grace: Duration) {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                log::warn!("Cannot listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = terminate => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    log::info!("Shutting down, waiting up to {}s for requests in flight", grace.as_secs());
+    draining.0.store(true, Ordering::Relaxed);
+    server.stop(true).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use actix_web::test::TestRequest;
+    use crate::query::query_hint;
+
+    #[test]
+    fn admin_endpoints_need_the_admin_token() {
+        let config = test_config();
+        assert!(reject_non_admin(&admin_request(Some("secret")), &config).is_none());
+        let denied = reject_non_admin(&admin_request(Some("secreT")), &config).unwrap();
+        assert_eq!(denied.status(), StatusCode::UNAUTHORIZED);
+        assert!(reject_non_admin(&admin_request(None), &config).is_some());
+        let disabled = Config { admin_token: None, ..test_config() };
+        let denied = reject_non_admin(&admin_request(Some("secret")), &disabled).unwrap();
+        assert_eq!(denied.status(), StatusCode::FORBIDDEN);
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+    }
+
+    #[actix_web::test]
+    async fn cancelling_needs_a_numeric_pid() {
+        let req = admin_request(Some("secret"));
+        let resp = cancel_query(req.clone(), web::Data::new(offline_pool()), web::Data::new(test_config()), web::Path::from("abc".to_string()))
+            .await
+            .respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn readiness_reports_each_database() {
+        let breaker = web::Data::new(CircuitBreaker::new(&test_config()));
+        let req = TestRequest::get().uri("/readyz").to_http_request();
+        let resp = readiness_check(web::Data::new(offline_pool()), breaker.clone(), None, web::Data::new(Draining::default())).await.respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = json_body(resp).await;
+        assert_eq!(body["status"], "unavailable");
+        assert_eq!(body["databases"]["default"]["status"], "down");
+
+        breaker.record_error(&sqlx::Error::PoolTimedOut);
+        breaker.record_error(&sqlx::Error::PoolTimedOut);
+        let health = check_database(&offline_pool(), &breaker).await;
+        assert_eq!(health.error.as_deref(), Some("circuit breaker open"));
+    }
+
+    #[actix_web::test]
+    async fn policies_need_a_valid_reachable_table() {
+        let req = TestRequest::default().to_http_request();
+        let config = web::Data::new(test_config());
+        let policies = |table: &str| {
+            table_policies(req.clone(), web::Data::new(offline_pool()), config.clone(), web::Path::from(table.to_string()))
+        };
+        assert_eq!(policies("loans;drop").await.respond_to(&req).status(), StatusCode::BAD_REQUEST);
+        assert_eq!(policies("loans").await.respond_to(&req).status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn aggregates_are_answered_from_a_covering_rollup() {
+        let config = Config { rollups: vec![rollup(&["count(*)", "count(total)", "sum(total)"]).validate()], ..test_config() };
+        let aggregates = |list: &str| list.split(',').map(|a| Aggregate::parse(a).unwrap()).collect::<Vec<_>>();
+        let group_by = vec!["region".to_string()];
+
+        let (rollup, measures) =
+            find_rollup(&config, "orders", &group_by, &aggregates("count(*),avg(total)"), &FilterExpr::none()).unwrap();
+        assert_eq!(rollup.table, "daily_sales");
+        assert_eq!(
+            measures,
+            ["sum(count)::bigint AS count", "sum(sum_total) / nullif(sum(count_total), 0) AS avg_total"]
+        );
+
+        // Not a dimension, not a stored measure, another table
+        let by_customer = vec!["customer".to_string()];
+        assert!(find_rollup(&config, "orders", &by_customer, &aggregates("count(*)"), &FilterExpr::none()).is_none());
+        assert!(find_rollup(&config, "orders", &group_by, &aggregates("max(total)"), &FilterExpr::none()).is_none());
+        assert!(find_rollup(&config, "refunds", &group_by, &aggregates("count(*)"), &FilterExpr::none()).is_none());
+        let filters = parse_multiple_filters("status=paid").unwrap();
+        assert!(find_rollup(&config, "orders", &group_by, &aggregates("count(*)"), &filters).is_none());
+    }
+
+    #[actix_web::test]
+    async fn aggregates_need_an_agg_parameter() {
+        let req = TestRequest::default().to_http_request();
+        let params: AggregateParams = serde_json::from_value(serde_json::json!({ "group_by": "region" })).unwrap();
+        let resp = run_aggregate(
+            &req,
+            &offline_pool(),
+            &Replica { pool: None, max_wait: Duration::ZERO, max_lag_bytes: 0 },
+            &test_config(),
+            &CircuitBreaker::new(&test_config()),
+            "orders",
+            FilterExpr::none(),
+            &params,
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json_body(resp).await["error"], "Missing agg parameter, e.g. agg=count(*),sum(amount)");
+    }
+
+    #[actix_web::test]
+    async fn table_schemas_respect_exposure() {
+        let req = TestRequest::default().to_http_request();
+        let exposure = web::Data::new(exposure(false));
+        exposure.sync(vec!["api_loans".to_string()], true);
+        let describe = |table: &str| {
+            table_schema(
+                req.clone(),
+                web::Data::new(offline_pool()),
+                web::Data::new(test_config()),
+                Some(exposure.clone()),
+                web::Path::from(table.to_string()),
+            )
+        };
+        assert_eq!(describe("api;loans").await.respond_to(&req).status(), StatusCode::BAD_REQUEST);
+        assert_eq!(describe("secrets").await.respond_to(&req).status(), StatusCode::NOT_FOUND);
+        assert_eq!(describe("api_loans").await.respond_to(&req).status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let column = |description: Option<&str>| ColumnInfo {
+            name: "id".to_string(),
+            kind: "integer".to_string(),
+            nullable: false,
+            default: None,
+            description: description.map(str::to_string),
+            encrypted: false,
+            tags: Vec::new(),
+            masked: None,
+        };
+        assert_eq!(
+            serde_json::to_value(column(None)).unwrap(),
+            serde_json::json!({ "name": "id", "type": "integer", "nullable": false, "default": null })
+        );
+        assert_eq!(serde_json::to_value(column(Some("Loan number"))).unwrap()["description"], "Loan number");
+    }
+
+    #[actix_web::test]
+    async fn query_hints_are_for_admins_and_need_a_plan() {
+        let config = test_config();
+        assert!(is_admin(&admin_request(Some("secret")), &config));
+        assert!(!is_admin(&admin_request(Some("guess")), &config));
+        assert!(!is_admin(&admin_request(None), &config));
+        let open = Config { admin_token: None, ..test_config() };
+        assert!(!is_admin(&admin_request(Some("secret")), &open));
+
+        let filters = parse_multiple_filters("status=open").unwrap();
+        let query = "SELECT * FROM loans WHERE status::text = $1::text";
+        assert_eq!(query_hint(&offline_pool(), "loans", query, &filters, &[]).await, None);
+    }
+
+    #[actix_web::test]
+    async fn table_listing_needs_the_catalog() {
+        let req = TestRequest::default().to_http_request();
+        let resp = list_tables(req.clone(), web::Data::new(offline_pool()), web::Data::new(test_config()), None)
+            .await
+            .respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let info = TableInfo {
+            name: "loans".to_string(),
+            schema: "public".to_string(),
+            kind: "view",
+            estimated_rows: None,
+            description: None,
+            readable: true,
+            writable: false,
+            deprecation: None,
+            presets: BTreeMap::new(),
+            filters: Vec::new(),
+            virtual_columns: Vec::new(),
+        };
+        assert_eq!(
+            serde_json::to_value(&info).unwrap(),
+            serde_json::json!({
+                "name": "loans", "schema": "public", "kind": "view", "estimated_rows": null,
+                "readable": true, "writable": false
+            })
+        );
+        let configured = TableInfo {
+            presets: BTreeMap::from([("open".to_string(), "status=open".to_string())]),
+            virtual_columns: vec!["age".to_string()],
+            ..info
+        };
+        let listed = serde_json::to_value(&configured).unwrap();
+        assert_eq!((&listed["presets"]["open"], &listed["virtual_columns"][0]), (&serde_json::json!("status=open"), &serde_json::json!("age")));
+        assert!(listed.get("filters").is_none());
+    }
+
+    #[test]
+    fn presets_are_anded_with_the_path_filters() {
+        let config = config_with(serde_json::json!({ "loans": { "presets": { "overdue": "status=overdue&amount>0" } } }));
+        let where_clause = |filters: &str, preset| {
+            let filters = parse_multiple_filters(filters).unwrap();
+            with_preset(&config, "loans", filters, preset).map(|filters| build_where_clause(&filters))
+        };
+        assert_eq!(where_clause("id=1", None).unwrap(), " WHERE id::text = $1::text");
+        assert_eq!(
+            where_clause("id=1", Some("overdue")).unwrap(),
+            " WHERE id::text = $1::text AND (status::text = $2::text AND amount::text > $3::text)"
+        );
+        let unknown = where_clause("id=1", Some("late")).err().unwrap();
+        assert_eq!((unknown.key, unknown.args[1].1.as_str()), ("unknown_preset", "loans"));
+    }
+
+    #[test]
+    fn history_until_accepts_dates_and_timestamps() {
+        assert_eq!(
+            parse_history_until("2024-03-01").unwrap().to_rfc3339(),
+            "2024-03-01T23:59:59.999999+00:00"
+        );
+        assert_eq!(
+            parse_history_until("2024-03-01T12:00:00+02:00").unwrap().to_rfc3339(),
+            "2024-03-01T10:00:00+00:00"
+        );
+        assert!(parse_history_until("yesterday").is_none());
+    }
+
+    #[actix_web::test]
+    async fn history_is_served_only_for_configured_tables() {
+        let req = TestRequest::default().to_http_request();
+        let config = config_with(serde_json::json!({ "loans": { "history": {} } }));
+        let history = |table: &str, until: Option<&str>| {
+            row_history(
+                req.clone(),
+                web::Data::new(offline_pool()),
+                web::Data::new(config.clone()),
+                web::Path::from((table.to_string(), "1".to_string())),
+                web::Query(HistoryParams { until: until.map(str::to_string), limit: None }),
+            )
+        };
+        assert_eq!(history("payments", None).await.respond_to(&req).status(), StatusCode::NOT_FOUND);
+        assert_eq!(history("loans", Some("soon")).await.respond_to(&req).status(), StatusCode::BAD_REQUEST);
+        assert_eq!(history("loans", None).await.respond_to(&req).status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    fn time_buckets(params: serde_json::Value) -> Result<Option<TimeBuckets>, Message> {
+        let params: AggregateParams = serde_json::from_value(params).unwrap();
+        TimeBuckets::parse(&params, &test_config(), "orders")
+    }
+
+    #[test]
+    fn time_buckets_need_a_unit_column_and_range_for_comparisons() {
+        assert!(time_buckets(serde_json::json!({})).unwrap().is_none());
+        assert_eq!(time_buckets(serde_json::json!({ "time_column": "created_at" })).err().unwrap().key, "missing_bucket");
+        assert_eq!(time_buckets(serde_json::json!({ "bucket": "decade", "time_column": "created_at" })).err().unwrap().key, "invalid_bucket");
+        assert_eq!(
+            time_buckets(serde_json::json!({ "bucket": "day", "time_column": "created_at", "from": "soon" })).err().unwrap().key,
+            "invalid_time_bound"
+        );
+        assert_eq!(
+            time_buckets(serde_json::json!({ "bucket": "day", "time_column": "created_at", "compare": "previous_year" })).err().unwrap().key,
+            "compare_requires_range"
+        );
+
+        let buckets = time_buckets(serde_json::json!({
+            "bucket": "Day", "time_column": "created_at", "from": "2026-01-10", "to": "2026-01-20T00:00:00Z"
+        }))
+        .unwrap()
+        .unwrap();
+        assert_eq!(buckets.select(None), "date_trunc('day', created_at)::timestamp AS bucket");
+        assert_eq!(
+            with_conditions(" WHERE region = $1", &buckets.range_sql(buckets.from, buckets.to)),
+            " WHERE region = $1 AND created_at >= '2026-01-10 00:00:00' AND created_at < '2026-01-20 00:00:00'"
+        );
+        assert!(buckets.previous_range().is_none());
+    }
+
+    #[test]
+    fn comparisons_shift_the_previous_range_onto_the_current_one() {
+        let range = |compare: &str| {
+            let buckets = time_buckets(serde_json::json!({
+                "bucket": "day", "time_column": "created_at", "from": "2024-03-01", "to": "2024-03-08", "compare": compare
+            }))
+            .unwrap()
+            .unwrap();
+            let (from, to, shift) = buckets.previous_range().unwrap();
+            (from.to_string(), to.to_string(), shift)
+        };
+        assert_eq!(
+            range("previous_period"),
+            ("2024-02-23 00:00:00".to_string(), "2024-03-01 00:00:00".to_string(), "interval '604800 seconds'".to_string())
+        );
+        assert_eq!(
+            range("previous_year"),
+            ("2023-03-01 00:00:00".to_string(), "2023-03-08 00:00:00".to_string(), "interval '1 year'".to_string())
+        );
+        assert_eq!(delta_pct(&serde_json::json!(150), &serde_json::json!("120")), serde_json::json!(25.0));
+        assert_eq!(delta_pct(&serde_json::json!(5), &serde_json::json!(0)), serde_json::Value::Null);
+    }
+
+    #[actix_web::test]
+    async fn share_links_are_signed_and_expire() {
+        let req = TestRequest::default().to_http_request();
+        let config = Config { share_secret: Some("share-secret".to_string()), ..test_config() };
+        let share = |config: &Config, body: serde_json::Value| {
+            create_share(
+                req.clone(),
+                web::Data::new(offline_pool()),
+                web::Data::new(config.clone()),
+                None,
+                web::Json(serde_json::from_value(body).unwrap()),
+            )
+        };
+        let resp = share(&test_config(), serde_json::json!({ "table": "loans" })).await.respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = share(&config, serde_json::json!({ "table": "loans", "expires_in": 7200 })).await.respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = share(&config, serde_json::json!({ "table": "loans", "filter": "na;me=1" })).await.respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp = share(&config, serde_json::json!({ "table": "loans", "filter": "status=open", "page_size": 5 })).await.respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let url = json_body(resp).await["url"].as_str().unwrap().to_string();
+
+        let serve_with = |token: &str, query: &str| {
+            serve_share(
+                TestRequest::get().uri(&format!("/_shared/{}?{}", token, query)).to_http_request(),
+                web::Data::new(offline_pool()),
+                web::Data::new(Replica { pool: None, max_wait: Duration::ZERO, max_lag_bytes: 0 }),
+                web::Data::new(config.clone()),
+                web::Data::new(CircuitBreaker::new(&config)),
+                None,
+                web::Path::from(token.to_string()),
+            )
+        };
+        let serve = |token: &str| serve_with(token, "");
+        let token = url.strip_prefix("/_shared/").unwrap();
+        assert_eq!(serve(token).await.respond_to(&req).status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(serve_with(token, "page=2&format=csv").await.respond_to(&req).status(), StatusCode::INTERNAL_SERVER_ERROR);
+        for query in ["schema=private", "page=2&select=password", "filter[amount][gt]=0"] {
+            let resp = serve_with(token, query).await.respond_to(&req);
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(json_body(resp).await["code"], "share_parameter_fixed");
+        }
+        assert_eq!(serve(&format!("{}x", token)).await.respond_to(&req).status(), StatusCode::NOT_FOUND);
+        let expired = ShareClaims {
+            table: "loans".to_string(),
+            filter: None,
+            query: String::new(),
+            claims: BTreeMap::new(),
+            sub: None,
+            quota: None,
+            exp: unix_now() - 120,
+        };
+        let expired = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(Algorithm::HS256),
+            &expired,
+            &jsonwebtoken::EncodingKey::from_secret(b"share-secret"),
+        )
+        .unwrap();
+        assert_eq!(serve(&expired).await.respond_to(&req).status(), StatusCode::GONE);
+    }
+
+    #[test]
+    fn pages_are_bound_after_the_statement_parameters() {
+        assert_eq!(
+            paged_sql("SELECT * FROM loans WHERE due < $1::date".to_string(), 1),
+            "SELECT * FROM loans WHERE due < $1::date LIMIT $2::bigint OFFSET $3::bigint"
+        );
+        assert_eq!(paged_sql("SELECT 1".to_string(), 0), "SELECT 1 LIMIT $1::bigint OFFSET $2::bigint");
+    }
+
+    #[actix_web::test]
+    async fn template_parameters_are_checked_before_the_query() {
+        let templates = BTreeMap::from([(
+            "overdue".to_string(),
+            template(serde_json::json!({
+                "sql": "SELECT * FROM loans WHERE due < :before",
+                "params": { "before": { "type": "date" } }
+            })),
+        )]);
+        let config = Config { templates, ..test_config() };
+        let saved = web::Data::new(SavedQueries { table: "datapi._datapi_queries".to_string(), loaded: Mutex::new(BTreeMap::new()) });
+        saved.loaded.lock().unwrap().insert(
+            "late".to_string(),
+            template(serde_json::json!({ "sql": "SELECT * FROM loans WHERE status = $status", "params": { "status": { "type": "text" } } })),
+        );
+        let call = |name: &str, query: &str| {
+            let req = TestRequest::get().uri(&format!("/_templates/{}?{}", name, query)).to_http_request();
+            let (config, saved, name) = (config.clone(), saved.clone(), name.to_string());
+            async move {
+                let resp = run_template(
+                    req.clone(),
+                    web::Data::new(offline_pool()),
+                    web::Data::new(Replica { pool: None, max_wait: Duration::ZERO, max_lag_bytes: 0 }),
+                    web::Data::new(config.clone()),
+                    web::Data::new(CircuitBreaker::new(&config)),
+                    Some(saved),
+                    web::Path::from(name),
+                )
+                .await
+                .respond_to(&req);
+                let status = resp.status();
+                (status, json_body(resp).await["error"].as_str().unwrap_or_default().to_string())
+            }
+        };
+        assert_eq!(call("missing", "").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(
+            call("overdue", "").await,
+            (StatusCode::BAD_REQUEST, "Template overdue requires parameter before".to_string())
+        );
+        assert_eq!(
+            call("overdue", "before=2026-01-01&status=open").await,
+            (StatusCode::BAD_REQUEST, "Unknown parameter status for template overdue".to_string())
+        );
+        assert_eq!(
+            call("overdue", "before=soon").await,
+            (StatusCode::BAD_REQUEST, "Invalid value soon for parameter before, expected date".to_string())
+        );
+        assert_eq!(call("overdue", "before=2026-01-01&page_size=10").await.0, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            call("late", "").await,
+            (StatusCode::BAD_REQUEST, "Template late requires parameter status".to_string())
+        );
+
+        let req = TestRequest::get().uri("/_query").to_http_request();
+        let listed = list_templates(web::Data::new(config.clone()), Some(saved.clone())).await.respond_to(&req);
+        let listed = json_body(listed).await;
+        assert_eq!(listed["count"], 2);
+        assert_eq!(listed["templates"][1]["name"], "late");
+    }
+
+    #[test]
+    fn column_tags_are_published_per_column() {
+        let config = config_with(serde_json::json!({ "customers": { "column_tags": { "email": ["pii", "contact"] } } }));
+        let customers = config.table("customers");
+        assert_eq!(column_tags(customers, "EMAIL"), vec!["pii", "contact"]);
+        assert!(column_tags(customers, "id").is_empty());
+        assert!(column_tags(None, "email").is_empty());
+        let column = ColumnInfo {
+            name: "email".to_string(),
+            kind: "text".to_string(),
+            nullable: true,
+            default: None,
+            description: None,
+            encrypted: false,
+            tags: column_tags(customers, "email"),
+            masked: Some(Mask::Hash),
+        };
+        let column = serde_json::to_value(column).unwrap();
+        assert_eq!(column["tags"], serde_json::json!(["pii", "contact"]));
+        assert_eq!(column["masked"], "hash");
+    }
+
+    #[test]
+    fn function_signatures_take_named_arguments() {
+        let signature = FunctionSignature {
+            schema: "public".to_string(),
+            names: vec!["region".to_string(), "since".to_string()],
+            types: vec!["text".to_string(), "date".to_string()],
+            defaults: 1,
+        };
+        let args = |json| match json {
+            serde_json::Value::Object(obj) => obj,
+            _ => unreachable!(),
+        };
+        assert!(signature.accepts(&args(serde_json::json!({ "region": "eu" }))));
+        assert!(signature.accepts(&args(serde_json::json!({ "region": "eu", "since": "2026-01-01" }))));
+        assert!(!signature.accepts(&args(serde_json::json!({ "since": "2026-01-01" }))));
+        assert!(!signature.accepts(&args(serde_json::json!({ "region": "eu", "limit": 5 }))));
+    }
+
+    #[actix_web::test]
+    async fn only_listed_functions_are_callable() {
+        let req = TestRequest::post().to_http_request();
+        let config = Config { functions: vec!["reports.overdue".to_string()], ..test_config() };
+        let call = |name: &str| {
+            call_function(
+                req.clone(),
+                web::Data::new(offline_pool()),
+                web::Data::new(Replica { pool: None, max_wait: Duration::ZERO, max_lag_bytes: 0 }),
+                web::Data::new(config.clone()),
+                web::Data::new(CircuitBreaker::new(&config)),
+                web::Path::from(name.to_string()),
+                web::Query(RpcParams { page: None, page_size: None, timeout_ms: None }),
+                None,
+            )
+        };
+        assert_eq!(call("pg_sleep").await.respond_to(&req).status(), StatusCode::NOT_FOUND);
+        // Signatures are looked up in the catalog
+        assert_eq!(call("Reports.Overdue").await.respond_to(&req).status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn readiness_shows_pool_stats_and_the_last_error() {
+        assert!(is_health_path("/health/ready"));
+        assert!(!is_health_path("/health/other"));
+        let breaker = CircuitBreaker::new(&test_config());
+        assert!(breaker.last_error().is_none());
+        let health = check_database(&offline_pool(), &breaker).await;
+        assert_eq!(health.status, "down");
+        assert!(health.pool.max > 0);
+        let last = health.last_error.expect("the failed ping is remembered");
+        assert_eq!(Some(last.message), health.error);
+    }
+
+    #[actix_web::test]
+    async fn draining_servers_are_not_ready() {
+        let draining = web::Data::new(Draining::default());
+        draining.0.store(true, Ordering::Relaxed);
+        let breaker = web::Data::new(CircuitBreaker::new(&test_config()));
+        let req = TestRequest::get().uri("/readyz").to_http_request();
+        let resp = readiness_check(web::Data::new(offline_pool()), breaker.clone(), None, draining).await.respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json_body(resp).await, serde_json::json!({ "status": "draining" }));
+        // Draining answers before the databases are pinged
+        assert!(breaker.last_error().is_none());
+    }
+
+    #[actix_web::test]
+    async fn admin_sql_only_reads_when_enabled() {
+        assert_eq!(statement_keyword("  -- note\n/* a */ (SELECT 1)"), "select");
+        assert_eq!(statement_keyword("WITH x AS (DELETE FROM loans) SELECT 1"), "with");
+        assert_eq!(statement_keyword("delete from loans"), "delete");
+        assert_eq!(statement_keyword("-- only a comment"), "");
+
+        let call = |config: Config, token: Option<&str>, sql: &str| {
+            let req = admin_request(token);
+            let body = SqlRequest { sql: sql.to_string(), params: vec![serde_json::json!(1)] };
+            async move {
+                let resp = run_sql(
+                    req.clone(),
+                    web::Data::new(offline_pool()),
+                    web::Data::new(config.clone()),
+                    web::Data::new(CircuitBreaker::new(&config)),
+                    web::Query(RpcParams { page: None, page_size: None, timeout_ms: None }),
+                    web::Json(body),
+                )
+                .await
+                .respond_to(&req);
+                let status = resp.status();
+                (status, json_body(resp).await["error"].as_str().unwrap_or_default().to_string())
+            }
+        };
+        let enabled = || Config { sql_enabled: true, ..test_config() };
+        assert_eq!(call(enabled(), None, "SELECT 1").await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call(test_config(), Some("secret"), "SELECT 1").await.0, StatusCode::FORBIDDEN);
+        assert_eq!(
+            call(enabled(), Some("secret"), "UPDATE loans SET status = 'x'").await,
+            (StatusCode::BAD_REQUEST, "Only SELECT statements can be run, not UPDATE".to_string())
+        );
+        // The statement itself reaches the database
+        assert_eq!(call(enabled(), Some("secret"), "SELECT $1").await.0, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn table_limits_change_at_runtime() {
+        let config = config_with(serde_json::json!({ "loans": { "max_rows": 500, "rate_limit_per_minute": 2 } }));
+        let limits = web::Data::new(TableLimits::default());
+        assert_eq!(limits.max_rows(&config, "\"Loans\""), Some(500));
+        assert_eq!(limits.acquire(&config, "loans", "10.0.0.1"), None);
+        assert_eq!(limits.acquire(&config, "loans", "10.0.0.1"), None);
+        assert_eq!(limits.acquire(&config, "loans", "10.0.0.1"), Some(30));
+        assert_eq!(limits.acquire(&config, "loans", "10.0.0.2"), None);
+        assert_eq!(limits.acquire(&config, "customers", "10.0.0.1"), None);
+
+        let mut values = TableLimitValues::default();
+        assert!(values.set("max_rows", &serde_json::json!(-1)).is_err());
+        assert!(values.set("rate_limit_per_minute", &serde_json::json!(u64::MAX)).is_err());
+        assert!(values.set("page_size", &serde_json::json!(1)).is_err());
+
+        let patch = |body: serde_json::Value| {
+            let req = admin_request(Some("secret"));
+            let (config, limits) = (config.clone(), limits.clone());
+            async move {
+                let cache = web::Data::new(ResponseCache::from_config(&config).await);
+                let resp = update_table_limits(
+                    req.clone(),
+                    web::Data::new(config),
+                    limits,
+                    cache,
+                    web::Query(TableLimitsParams { persist: false }),
+                    web::Json(serde_json::from_value(body).unwrap()),
+                )
+                .await
+                .respond_to(&req);
+                let status = resp.status();
+                (status, json_body(resp).await)
+            }
+        };
+        let (status, body) = patch(serde_json::json!({ "tables": { "Loans": { "max_rows": 0, "cache_ttl_secs": 30 } } })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body["tables"]["loans"],
+            serde_json::json!({ "cache_ttl_secs": 30, "max_rows": 0, "rate_limit_per_minute": 2, "overridden": ["cache_ttl_secs", "max_rows"] })
+        );
+        // 0 lifts the cap, falling back to DATAPI_MAX_ROWS
+        assert_eq!(limits.max_rows(&config, "loans"), config.max_rows);
+        let (status, body) = patch(serde_json::json!({ "tables": { "loans": { "max_rows": null, "cache_ttl_secs": null } } })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["tables"]["loans"].get("overridden").is_none());
+        assert_eq!(limits.max_rows(&config, "loans"), Some(500));
+        let (status, body) = patch(serde_json::json!({ "tables": { "loans": { "page_size": 5 } } })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "page_size can't be changed at runtime, only cache_ttl_secs, max_rows, rate_limit_per_minute");
+        let (status, _) = patch(serde_json::json!({ "tables": { "loans;": { "max_rows": 5 } } })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn persisted_limits_are_merged_into_the_config_file() {
+        let path = std::env::temp_dir().join(format!("datapi-limits-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{ "tables": { "Loans": { "max_rows": 500, "filters": ["x > 0"] } } }"#).unwrap();
+        let tables = BTreeMap::from([
+            ("loans".to_string(), BTreeMap::from([("max_rows".to_string(), serde_json::Value::Null), ("cache_ttl_secs".to_string(), serde_json::json!(30))])),
+            ("notes".to_string(), BTreeMap::from([("max_rows".to_string(), serde_json::json!(10))])),
+        ]);
+        persist_table_limits(path.to_str().unwrap(), &tables).unwrap();
+        let file: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        std::fs::write(&path, "[]").unwrap();
+        let refused = persist_table_limits(path.to_str().unwrap(), &tables);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            file,
+            serde_json::json!({ "tables": {
+                "Loans": { "filters": ["x > 0"], "cache_ttl_secs": 30 },
+                "notes": { "max_rows": 10 }
+            } })
+        );
+        assert_eq!(refused.unwrap_err(), "the config file is not a JSON object");
+    }
+
+    #[actix_web::test]
+    async fn health_reports_pool_saturation() {
+        let req = TestRequest::default().to_http_request();
+        let body = json_body(health_check(None, None).await.respond_to(&req)).await;
+        assert!(body.get("pool").is_none());
+
+        let breaker = web::Data::new(CircuitBreaker::new(&test_config()));
+        breaker.record_error(&sqlx::Error::PoolTimedOut);
+        breaker.record_error(&sqlx::Error::RowNotFound);
+        let pool = web::Data::new(offline_pool());
+        let body = json_body(health_check(Some(pool), Some(breaker)).await.respond_to(&req)).await;
+        assert_eq!(body["pool"]["in_use"], 0);
+        assert_eq!(body["pool"]["saturation"], 0.0);
+        assert_eq!(body["pool"]["acquire_timeouts"], 1);
+    }
+
+    #[actix_web::test]
+    async fn multi_reads_answer_each_read_by_name() {
+        let config = test_config();
+        let req = TestRequest::post().uri(MULTI_PATH).to_http_request();
+        let multi = |body: serde_json::Value| {
+            run_multi(
+                req.clone(),
+                web::Data::new(offline_pool()),
+                web::Data::new(Replica { pool: None, max_wait: Duration::ZERO, max_lag_bytes: 0 }),
+                web::Data::new(config.clone()),
+                web::Data::new(CircuitBreaker::new(&config)),
+                None,
+                web::Json(serde_json::from_value(body).unwrap()),
+            )
+        };
+        let resp = multi(serde_json::json!({})).await.respond_to(&req);
+        assert_eq!(json_body(resp).await["error"], "A multi-read must be a JSON object of 1 to 50 named reads");
+
+        let resp = multi(serde_json::json!({
+            "csv": { "table": "loans", "params": { "format": "csv" } },
+            "bad": { "table": "lo;ans" },
+            "filtered": { "table": "loans", "filter": "na;me=1" }
+        }))
+        .await
+        .respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::OK);
+        let results = json_body(resp).await["results"].clone();
+        assert_eq!(results["csv"]["status"], 400);
+        assert_eq!(results["csv"]["error"], "format is not supported in a multi-read, which returns JSON pages");
+        assert_eq!(results["bad"]["status"], 400);
+        assert!(results["filtered"]["status"].as_u64().unwrap() >= 400);
+
+        let resp = multi(serde_json::json!({
+            "schema": { "table": "loans", "params": { "schema": "reporting" } },
+            "deleted": { "table": "loans", "params": { "include_deleted": true } },
+            "strong": { "table": "loans", "params": { "consistency": "strong" } },
+            "structured": { "table": "loans", "params": { "filter[amount][near]": 5 } },
+            "ranged": { "table": "loans", "params": { "filter[amount][gte]": 100 } }
+        }))
+        .await
+        .respond_to(&req);
+        let results = json_body(resp).await["results"].clone();
+        for (name, param) in [("schema", "schema"), ("deleted", "include_deleted"), ("strong", "consistency")] {
+            assert_eq!(results[name]["status"], 400);
+            assert_eq!(results[name]["code"], "multi_param_request_wide");
+            assert_eq!(results[name]["parameter"], param);
+        }
+        assert_eq!(results["structured"]["status"], 400);
+        assert_eq!(results["structured"]["code"], "unknown_structured_operator");
+        assert_eq!(results["ranged"]["status"], 500);
+    }
+}
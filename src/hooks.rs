@@ -0,0 +1,144 @@
+use std::sync::Arc;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest};
+use crate::filters::FilterExpr;
+use crate::messages::Message;
+
+// A compile-time plugin: an application embedding datapi (or a binary built
+// around run_with) registers hooks on Options with Options::hook, and they run
+// in registration order on every table read. Hooks are trusted code; a
+// FilterExpr::Sql condition they add goes into the query as written.
+pub trait QueryHook: Send + Sync {
+    // Runs before the request's filters are checked against the table, so
+    // conditions a hook adds or rewrites are validated and typed like the
+    // request's own. Filtered updates and deletes pass through here too, see
+    // the request's method. An Err refuses the request.
+    fn before_query(&self, query: &mut HookQuery) -> Result<(), Rejection> {
+        let _ = query;
+        Ok(())
+    }
+
+    // Runs on the rows of a response after masking and encryption, before
+    // they're serialized. Streamed (NDJSON) results pass through a row at a
+    // time.
+    fn after_rows(&self, table: &str, rows: &mut Vec<serde_json::Map<String, serde_json::Value>>) {
+        let _ = (table, rows);
+    }
+}
+
+// What before_query sees of a request
+pub struct HookQuery<'a> {
+    pub request: &'a HttpRequest,
+    // Unquoted, schema-qualified when the request named a schema
+    pub table: &'a str,
+    pub filters: FilterExpr,
+}
+
+// A hook's refusal, answered with the status and the structured error body
+// of the `hook_rejected` message: `{"code": "hook_rejected", "error": reason}`
+// plus the request id. The reason is shown as given, untranslated.
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    pub status: StatusCode,
+    pub reason: String,
+}
+
+impl Rejection {
+    pub fn new(status: StatusCode, reason: impl Into<String>) -> Self {
+        Rejection { status, reason: reason.into() }
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Hooks(pub(crate) Vec<Arc<dyn QueryHook>>);
+
+impl Hooks {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn before_query(&self, req: &HttpRequest, table: &str, filters: FilterExpr) -> Result<FilterExpr, Message> {
+        let mut query = HookQuery { request: req, table, filters };
+        for hook in &self.0 {
+            hook.before_query(&mut query).map_err(|rejection| {
+                Message::new("hook_rejected").arg("reason", rejection.reason).status(rejection.status)
+            })?;
+        }
+        Ok(query.filters)
+    }
+
+    pub(crate) fn after_rows(&self, table: &str, rows: &mut Vec<serde_json::Map<String, serde_json::Value>>) {
+        let table = table.replace('"', "");
+        for hook in &self.0 {
+            hook.after_rows(&table, rows);
+        }
+    }
+}
+
+// The request's hooks, or none outside datapi's routes
+pub(crate) fn request_hooks(req: &HttpRequest) -> Hooks {
+    req.app_data::<web::Data<Hooks>>().map(|hooks| hooks.get_ref().clone()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use actix_web::test::TestRequest;
+    use crate::filters::{build_where_clause, parse_multiple_filters, FilterCondition, FilterValue};
+    use crate::messages::bad_request;
+
+    // Pins reads to the tenant of the X-Tenant header, refusing requests
+    // without one
+    struct TenantHook;
+
+    impl QueryHook for TenantHook {
+        fn before_query(&self, query: &mut HookQuery) -> Result<(), Rejection> {
+            let tenant = query.request.headers().get("X-Tenant").and_then(|v| v.to_str().ok());
+            let Some(tenant) = tenant else {
+                return Err(Rejection::new(StatusCode::FORBIDDEN, "no tenant"));
+            };
+            let condition = FilterCondition {
+                column: "tenant".to_string(),
+                operator: "=".to_string(),
+                value: FilterValue::Single(tenant.to_string()),
+                cast: None,
+            };
+            let filters = std::mem::replace(&mut query.filters, FilterExpr::none());
+            query.filters = filters.and(FilterExpr::Condition(condition));
+            Ok(())
+        }
+
+        fn after_rows(&self, table: &str, rows: &mut Vec<serde_json::Map<String, serde_json::Value>>) {
+            for row in rows {
+                row.insert("table".to_string(), serde_json::json!(table));
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn hooks_rewrite_filters_and_rows() {
+        let hooks = Hooks(vec![Arc::new(TenantHook)]);
+        let req = TestRequest::default().insert_header(("X-Tenant", "acme")).to_http_request();
+        let filters = parse_multiple_filters("id=1").unwrap();
+        let filters = hooks.before_query(&req, "loans", filters).unwrap();
+        assert_eq!(build_where_clause(&filters), " WHERE id::text = $1::text AND tenant::text = $2::text");
+
+        let e = hooks.before_query(&TestRequest::default().to_http_request(), "loans", FilterExpr::none()).unwrap_err();
+        let resp = bad_request(&TestRequest::default().to_http_request(), e);
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = json_body(resp).await;
+        assert_eq!(body["code"], "hook_rejected");
+        assert_eq!(body["error"], "no tenant");
+
+        let mut rows = vec![serde_json::Map::new()];
+        hooks.after_rows("\"public\".\"loans\"", &mut rows);
+        assert_eq!(rows[0]["table"], "public.loans");
+        assert!(request_hooks(&req).is_empty());
+    }
+
+    #[test]
+    fn empty_groups_left_by_hooks_are_constant() {
+        assert_eq!(build_where_clause(&FilterExpr::Or(Vec::new()).and(FilterExpr::And(Vec::new()))), " WHERE FALSE AND TRUE");
+    }
+}
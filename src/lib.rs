@@ -0,0 +1,637 @@
+// datapi serves a PostgreSQL database as a REST API. The binary in main.rs
+// runs it standalone through `run`; `Options` and `configure` mount the same
+// routes inside another actix-web application.
+use std::collections::BTreeMap;
+use std::env;
+use std::time::Duration;
+use actix_web::middleware::{from_fn, Compress, Condition};
+use actix_web::{web, App, HttpServer};
+use sqlx::PgPool;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+
+mod access;
+mod background;
+mod cli;
+mod config;
+mod filters;
+mod graphql;
+mod handlers;
+mod hooks;
+mod messages;
+mod middleware;
+mod mock;
+mod openapi;
+mod query;
+mod serialization;
+mod writes;
+
+#[cfg(test)]
+mod test_support;
+
+use access::{
+    authenticate, check_off_search_path, exposure_guard, fetch_table_names, watch_api_keys,
+    watch_catalog, watch_jwks, ApiKeys, Exposure, JwtAuth,
+};
+use background::{
+    check_exports, check_retention, check_table_expressions, cleanup_results, deliver_webhook,
+    enforce_retention, export_status, install_aliases, install_change_triggers, install_history,
+    listen_changes, maintain_rollup, pool_options, retention_status, schedule_export,
+    table_changes, webhook_status, ChangeFeed, ExportStats, RetentionStats, WebhookStats,
+};
+use cli::{
+    load_settings, parse_command, run_replay, run_snapshot, take_settings, Command,
+    DEFAULT_SETTINGS_FILE, USAGE,
+};
+use config::{env_parse, unsupported_database, Config, CorsConfig, JwtKey};
+use graphql::{graphql_query, graphql_schema, GRAPHQL_PATH};
+use handlers::{
+    aggregate_all, aggregate_table, approve_table, call_function, cancel_query, create_share,
+    health_check, list_exposed_tables, list_queries, list_tables, list_templates, query_all,
+    query_table, readiness_check, reload_schema, row_blob, row_history, run_multi, run_sql,
+    run_template, serve_share, set_table_approval, shutdown_on_signal, table_limits_status,
+    table_policies, table_schema, update_table_limits, watch_saved_queries, Draining, SavedQueries,
+    TableLimits, MULTI_PATH, SQL_PATH,
+};
+use hooks::Hooks;
+use messages::{extractor_error, Translations};
+use middleware::{
+    circuit_breaker_guard, compression_threshold, conditional_get, database_route,
+    deprecation_headers, fields_projection, https_redirect, init_logging, init_tracing,
+    journal_writes, parameter_aliases, priority_class, probe_database, rate_limit, request_id,
+    response_cache, response_envelope, strip_identity_encoding, trace_request, AuditLog,
+    CircuitBreaker, Databases, HttpsPort, Journal, NamedDatabase, PriorityClass, PriorityClasses,
+    RateLimiter, Replica, ResponseCache, SocketConfig, TlsConfig,
+};
+use mock::serve_mock;
+use openapi::openapi_spec;
+use query::{
+    count_failure_status, maintain_row_count, query_result, slow_query_status, CountFailures,
+    RowCounts, RowWidths, SchemaCache, SlowQueries, APPLICATION_NAME,
+};
+use writes::{
+    approve_pending, bulk_insert, delete_all, delete_table, get_pending, insert_rows,
+    install_moderation, list_pending, reject_pending, run_batch, update_all, update_table,
+};
+
+pub use filters::{FilterCondition, FilterExpr, FilterValue};
+pub use hooks::{HookQuery, QueryHook, Rejection};
+
+// The state datapi's routes share, built once per process and handed to
+// configure for every worker's App
+#[derive(Clone)]
+pub struct Options {
+    pub(crate) config: web::Data<Config>,
+    pub(crate) translations: web::Data<Translations>,
+    pub(crate) breaker: web::Data<CircuitBreaker>,
+    pub(crate) retention_stats: web::Data<RetentionStats>,
+    pub(crate) table_limits: web::Data<TableLimits>,
+    pub(crate) export_stats: web::Data<ExportStats>,
+    pub(crate) row_counts: web::Data<RowCounts>,
+    pub(crate) row_widths: web::Data<RowWidths>,
+    pub(crate) count_failures: web::Data<CountFailures>,
+    pub(crate) slow_queries: web::Data<SlowQueries>,
+    pub(crate) schema_cache: web::Data<SchemaCache>,
+    pub(crate) webhook_stats: web::Data<WebhookStats>,
+    pub(crate) draining: web::Data<Draining>,
+    pub(crate) cache: web::Data<ResponseCache>,
+    pub(crate) replica: web::Data<Replica>,
+    pub(crate) priority_classes: web::Data<PriorityClasses>,
+    pub(crate) exposure: Option<web::Data<Exposure>>,
+    pub(crate) api_keys: Option<web::Data<ApiKeys>>,
+    pub(crate) saved_queries: Option<web::Data<SavedQueries>>,
+    pub(crate) jwt: Option<web::Data<JwtAuth>>,
+    pub(crate) rate_limiter: Option<web::Data<RateLimiter>>,
+    pub(crate) databases: Option<web::Data<Databases>>,
+    pub(crate) https_port: Option<web::Data<HttpsPort>>,
+    pub(crate) journal: Option<web::Data<Journal>>,
+    pub(crate) audit: Option<web::Data<AuditLog>>,
+    pub(crate) change_feed: Option<web::Data<ChangeFeed>>,
+    pub(crate) hooks: Hooks,
+    pub(crate) compression: bool,
+    pub(crate) cors: Option<CorsConfig>,
+    pub(crate) bulk_max_bytes: usize,
+}
+
+// Pool options with the connection settings datapi relies on (the alias
+// search path, read-only mode, the statement timeout), for the pool an
+// embedding application passes to Options::from_env and configure
+pub fn pool_options_from_env() -> PgPoolOptions {
+    pool_options(&Config::from_env())
+}
+
+impl Options {
+    // Reads the DATAPI_* settings and the configuration file like the binary,
+    // prepares the database through the pool (alias views, history and
+    // moderation tables, change triggers) and starts the background tasks.
+    // Panics on invalid configuration, like the binary's startup.
+    pub async fn from_env(pool: &PgPool) -> Options {
+        Options::with_config(pool, Config::from_env()).await
+    }
+    
+    pub(crate) async fn with_config(pool: &PgPool, config: Config) -> Options {
+        let config = web::Data::new(config);
+        let connect_options = (*pool.connect_options()).clone();
+        let replica_options = env::var("DATAPI_REPLICA_URL").ok().map(|url| {
+            url.parse::<PgConnectOptions>()
+                .expect("Invalid DATAPI_REPLICA_URL")
+                .application_name(APPLICATION_NAME)
+                .statement_cache_capacity(config.statement_cache_capacity)
+        });
+        let consistency_wait = Duration::from_millis(env_parse("DATAPI_CONSISTENCY_WAIT_MS", 200));
+        let max_lag_bytes = env_parse("DATAPI_MAX_REPLICA_LAG_BYTES", 16 * 1024 * 1024);
+        let replica = web::Data::new(Replica {
+            pool: match &replica_options {
+                Some(options) => {
+                    let replica_pool = pool_options(&config)
+                        .connect_with(options.clone())
+                        .await
+                        .expect("Failed to create replica pool");
+                    log::info!("Connected to read replica");
+                    Some(replica_pool)
+                }
+                None => None,
+            },
+            max_wait: consistency_wait,
+            max_lag_bytes,
+        });
+        
+        let mut classes = Vec::new();
+        for (name, class_config) in &config.priority_classes {
+            let options = pool_options(&config)
+                .max_connections(class_config.max_connections)
+                .min_connections(config.pool_min_connections.min(class_config.max_connections));
+            let class_pool = options
+                .clone()
+                .connect_with(connect_options.clone())
+                .await
+                .unwrap_or_else(|e| panic!("Failed to create pool for priority class {}: {}", name, e));
+            let class_replica = match &replica_options {
+                Some(replica_options) => Some(
+                    options
+                        .connect_with(replica_options.clone())
+                        .await
+                        .unwrap_or_else(|e| panic!("Failed to create replica pool for priority class {}: {}", name, e)),
+                ),
+                None => None,
+            };
+            classes.push(PriorityClass {
+                name: name.clone(),
+                config: class_config.clone(),
+                pool: web::Data::new(class_pool),
+                replica: web::Data::new(Replica { pool: class_replica, max_wait: consistency_wait, max_lag_bytes }),
+            });
+        }
+        let priority_classes = web::Data::new(PriorityClasses(classes));
+        install_aliases(pool, &config).await;
+        check_table_expressions(pool, &config).await;
+        install_history(pool, &config).await;
+        install_moderation(pool, &config).await;
+        let change_feed = install_change_triggers(pool, &config).await.then(|| web::Data::new(ChangeFeed::new()));
+        let webhook_stats = web::Data::new(WebhookStats::default());
+        if let Some(feed) = &change_feed {
+            for (table, table_config) in &config.tables {
+                for webhook in &table_config.webhooks {
+                    webhook_stats.update(table, webhook, |_| {});
+                    let receiver = feed.sender.subscribe();
+                    tokio::spawn(deliver_webhook(table.clone(), webhook.clone(), receiver, config.clone(), webhook_stats.clone()));
+                }
+            }
+            tokio::spawn(listen_changes(pool.clone(), feed.clone()));
+        }
+        check_retention(pool, &config).await;
+        check_exports(pool, &config).await;
+        let breaker = web::Data::new(CircuitBreaker::new(&config));
+        tokio::spawn(probe_database(pool.clone(), breaker.clone()));
+        
+        let mut named_databases = BTreeMap::new();
+        for (name, url) in &config.databases {
+            let options = url
+                .parse::<PgConnectOptions>()
+                .unwrap_or_else(|e| panic!("Invalid URL for database {}: {}", name, e))
+                .application_name(APPLICATION_NAME)
+                .statement_cache_capacity(config.statement_cache_capacity);
+            let named_pool = pool_options(&config)
+                .connect_with(options)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to create pool for database {}: {}", name, e));
+            let named_breaker = web::Data::new(CircuitBreaker::new(&config));
+            tokio::spawn(probe_database(named_pool.clone(), named_breaker.clone()));
+            named_databases.insert(name.clone(), NamedDatabase {
+                pool: web::Data::new(named_pool),
+                replica: web::Data::new(Replica { pool: None, max_wait: consistency_wait, max_lag_bytes }),
+                breaker: named_breaker,
+            });
+        }
+        let databases = (!named_databases.is_empty()).then(|| web::Data::new(Databases(named_databases)));
+        if !config.databases.is_empty() {
+            log::info!("Serving {} further databases under /db/", config.databases.len());
+        }
+        if !config.read_only {
+            tokio::spawn(cleanup_results(pool.clone()));
+        }
+        for rollup in &config.rollups {
+            tokio::spawn(maintain_rollup(pool.clone(), rollup.clone()));
+        }
+        let row_counts = web::Data::new(RowCounts::default());
+        for (table, table_config) in &config.tables {
+            if let Some(secs) = table_config.count_refresh_secs {
+                let interval = Duration::from_secs(secs.max(1));
+                tokio::spawn(maintain_row_count(pool.clone(), table.clone(), interval, row_counts.clone()));
+            }
+        }
+        let retention_stats = web::Data::new(RetentionStats::default());
+        for (table, table_config) in &config.tables {
+            if let Some(retention) = &table_config.retention {
+                tokio::spawn(enforce_retention(pool.clone(), table.clone(), retention.clone(), retention_stats.clone()));
+            }
+        }
+        let export_stats = web::Data::new(ExportStats::default());
+        for name in config.exports.keys() {
+            tokio::spawn(schedule_export(pool.clone(), config.clone(), name.clone(), export_stats.clone()));
+        }
+        let translations = web::Data::new(match env::var("DATAPI_LOCALES_DIR") {
+            Ok(dir) => Translations::load(&dir),
+            Err(_) => Translations::default(),
+        });
+        
+        for schema in &config.schemas {
+            let exists: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM pg_namespace WHERE nspname = $1)")
+                .bind(schema)
+                .fetch_one(pool)
+                .await
+                .expect("Failed to check schemas");
+            if !exists {
+                panic!("Schema {} of DATAPI_SCHEMAS does not exist", schema);
+            }
+        }
+        let exposure = Exposure::from_env().map(web::Data::new);
+        if exposure.is_some() && databases.is_some() {
+            panic!("DATAPI_EXPOSE_TABLES cannot be combined with DATAPI_DB_* databases");
+        }
+        if let Some(exposure) = &exposure {
+            let names = fetch_table_names(pool).await.expect("Failed to list tables");
+            exposure.sync(names, true);
+            let interval = Duration::from_secs(env_parse("DATAPI_CATALOG_WATCH_SECS", 30).max(1));
+            tokio::spawn(watch_catalog(pool.clone(), exposure.clone(), interval));
+        }
+        
+        let api_keys = ApiKeys::from_config(&config).map(web::Data::new);
+        if let Some(keys) = &api_keys {
+            if let Some(table) = &keys.table {
+                check_off_search_path(pool, table, "API keys").await;
+            }
+            keys.reload(pool).await.expect("Failed to load API keys");
+            if keys.table.is_some() {
+                let interval = Duration::from_secs(env_parse("DATAPI_API_KEYS_REFRESH_SECS", 60).max(1));
+                tokio::spawn(watch_api_keys(pool.clone(), keys.clone(), interval));
+            }
+        }
+        
+        let saved_queries = SavedQueries::from_config(&config).map(web::Data::new);
+        if let Some(queries) = &saved_queries {
+            check_off_search_path(pool, &queries.table, "saved queries").await;
+            queries.reload(pool, &config).await.expect("Failed to load saved queries");
+            let interval = Duration::from_secs(env_parse("DATAPI_QUERIES_REFRESH_SECS", 60).max(1));
+            tokio::spawn(watch_saved_queries(pool.clone(), config.clone(), queries.clone(), interval));
+        }
+        
+        let audit = AuditLog::start(pool, &config).await;
+        
+        let jwt = JwtAuth::from_config(&config).map(web::Data::new);
+        if let Some(jwt) = &jwt {
+            if let JwtKey::Jwks(url) = &jwt.config.key {
+                if let Err(e) = jwt.reload().await {
+                    panic!("Failed to fetch the JWKS from {}: {}", url, e);
+                }
+                let interval = Duration::from_secs(env_parse("DATAPI_JWT_JWKS_REFRESH_SECS", 300).max(1));
+                tokio::spawn(watch_jwks(jwt.clone(), interval));
+            }
+        }
+        
+        Options {
+            rate_limiter: RateLimiter::from_config(&config).map(web::Data::new),
+            cache: web::Data::new(ResponseCache::from_config(&config).await),
+            table_limits: web::Data::new(TableLimits::default()),
+            journal: Journal::from_env().map(web::Data::new),
+            audit,
+            row_widths: web::Data::new(RowWidths::default()),
+            count_failures: web::Data::new(CountFailures::default()),
+            slow_queries: web::Data::new(SlowQueries::default()),
+            schema_cache: web::Data::new(SchemaCache { ttl: config.schema_cache_ttl, ..Default::default() }),
+            draining: web::Data::new(Draining::default()),
+            compression: config.compression,
+            cors: config.cors.clone(),
+            bulk_max_bytes: env_parse("DATAPI_BULK_MAX_BYTES", 100 * 1024 * 1024),
+            https_port: None,
+            config,
+            translations,
+            breaker,
+            retention_stats,
+            export_stats,
+            row_counts,
+            webhook_stats,
+            replica,
+            priority_classes,
+            exposure,
+            api_keys,
+            saved_queries,
+            jwt,
+            databases,
+            change_feed,
+            hooks: Hooks::default(),
+        }
+    }
+    
+    // Registers a hook, run after the ones registered before it
+    pub fn hook(mut self, hook: impl QueryHook + 'static) -> Options {
+        self.hooks.0.push(std::sync::Arc::new(hook));
+        self
+    }
+    
+    // Every pool besides the primary one, to close once the server has drained
+    pub(crate) fn pools(&self) -> Vec<PgPool> {
+        let mut pools: Vec<PgPool> = self.replica.pool.iter().cloned().collect();
+        for class in &self.priority_classes.0 {
+            pools.push(class.pool.get_ref().clone());
+            pools.extend(class.replica.pool.clone());
+        }
+        for database in self.databases.iter().flat_map(|d| d.0.values()) {
+            pools.push(database.pool.get_ref().clone());
+        }
+        pools
+    }
+}
+
+// Mounts datapi's routes and middleware on an App, e.g.
+// `App::new().service(my_routes).configure(|cfg| datapi::configure(cfg, pool.clone(), &options))`.
+// The routes take every path (`/{table}` and below), so the application's
+// own routes go before them; the middleware only wraps datapi's routes.
+pub fn configure(cfg: &mut web::ServiceConfig, pool: PgPool, options: &Options) {
+    let options = options.clone();
+    cfg.app_data(web::Data::new(pool))
+        .app_data(web::Data::new(options.hooks))
+        .app_data(options.config)
+        .app_data(options.translations)
+        .app_data(options.breaker)
+        .app_data(options.retention_stats)
+        .app_data(options.table_limits)
+        .app_data(options.export_stats)
+        .app_data(options.row_counts)
+        .app_data(options.row_widths)
+        .app_data(options.count_failures)
+        .app_data(options.slow_queries)
+        .app_data(options.schema_cache)
+        .app_data(options.webhook_stats)
+        .app_data(options.draining)
+        .app_data(options.cache)
+        .app_data(options.replica)
+        .app_data(options.priority_classes)
+        .app_data(web::PayloadConfig::new(options.bulk_max_bytes))
+        .app_data(web::QueryConfig::default().error_handler(|e, req| extractor_error(req, "invalid_query_string", e)))
+        .app_data(web::JsonConfig::default().error_handler(|e, req| extractor_error(req, "invalid_json_body", e)))
+        .app_data(web::PathConfig::default().error_handler(|e, req| extractor_error(req, "invalid_path", e)));
+    if let Some(exposure) = options.exposure {
+        cfg.app_data(exposure);
+    }
+    if let Some(keys) = options.api_keys {
+        cfg.app_data(keys);
+    }
+    if let Some(queries) = options.saved_queries {
+        cfg.app_data(queries);
+    }
+    if let Some(jwt) = options.jwt {
+        cfg.app_data(jwt);
+    }
+    if let Some(limiter) = options.rate_limiter {
+        cfg.app_data(limiter);
+    }
+    if let Some(databases) = options.databases {
+        cfg.app_data(databases);
+    }
+    if let Some(https_port) = options.https_port {
+        cfg.app_data(https_port);
+    }
+    if let Some(journal) = options.journal {
+        cfg.app_data(journal);
+    }
+    if let Some(audit) = options.audit {
+        cfg.app_data(audit);
+    }
+    if let Some(feed) = options.change_feed {
+        cfg.app_data(feed);
+    }
+    let cors = options.cors;
+    cfg.service(
+        web::scope("")
+            .wrap(from_fn(priority_class))
+            .wrap(from_fn(circuit_breaker_guard))
+            .wrap(from_fn(deprecation_headers))
+            .wrap(from_fn(parameter_aliases))
+            .wrap(from_fn(exposure_guard))
+            .wrap(from_fn(fields_projection))
+            .wrap(from_fn(response_envelope))
+            .wrap(from_fn(response_cache))
+            .wrap(from_fn(conditional_get))
+            .wrap(from_fn(journal_writes))
+            .wrap(from_fn(rate_limit))
+            .wrap(from_fn(authenticate))
+            .wrap(from_fn(compression_threshold))
+            .wrap(Condition::new(options.compression, Compress::default()))
+            .wrap(from_fn(strip_identity_encoding))
+            .wrap(from_fn(database_route))
+            .wrap(Condition::new(cors.is_some(), cors.as_ref().map(CorsConfig::middleware).unwrap_or_default()))
+            .wrap(from_fn(trace_request))
+            .wrap(from_fn(request_id))
+            .wrap(from_fn(https_redirect))
+            .route("/health", web::get().to(health_check))
+            .route("/readyz", web::get().to(readiness_check))
+            .route("/health/ready", web::get().to(readiness_check))
+            .route(SQL_PATH, web::post().to(run_sql))
+            .route("/_admin/queries", web::get().to(list_queries))
+            .route("/_admin/queries/{pid}", web::delete().to(cancel_query))
+            .route("/_admin/tables", web::get().to(list_exposed_tables))
+            .route("/_admin/tables/approval", web::put().to(set_table_approval))
+            .route("/_admin/tables/{table}/approve", web::post().to(approve_table))
+            .route("/_admin/retention", web::get().to(retention_status))
+            .route("/_admin/exports", web::get().to(export_status))
+            .route("/_admin/pending", web::get().to(list_pending))
+            .route("/_admin/pending/{id}", web::get().to(get_pending))
+            .route("/_admin/pending/{id}/approve", web::post().to(approve_pending))
+            .route("/_admin/pending/{id}/reject", web::post().to(reject_pending))
+            .route("/_admin/config", web::get().to(table_limits_status))
+            .route("/_admin/config", web::patch().to(update_table_limits))
+            .route("/_admin/counts", web::get().to(count_failure_status))
+            .route("/_admin/slow_queries", web::get().to(slow_query_status))
+            .route("/_admin/webhooks", web::get().to(webhook_status))
+            .route("/_results/{token}", web::get().to(query_result))
+            .route("/_share", web::post().to(create_share))
+            .route("/_shared/{token}", web::get().to(serve_share))
+            .route("/_templates", web::get().to(list_templates))
+            .route("/_templates/{name}", web::get().to(run_template))
+            .route("/_query", web::get().to(list_templates))
+            .route("/_query/{name}", web::get().to(run_template))
+            .route("/_rpc/{function}", web::post().to(call_function))
+            .route(GRAPHQL_PATH, web::post().to(graphql_query))
+            .route(GRAPHQL_PATH, web::get().to(graphql_schema))
+            .route("/_batch", web::post().to(run_batch))
+            .route(MULTI_PATH, web::post().to(run_multi))
+            .route("/_tables", web::get().to(list_tables))
+            .route("/_schema/reload", web::post().to(reload_schema))
+            .route("/_schema/{table}", web::get().to(table_schema))
+            .route("/_openapi.json", web::get().to(openapi_spec))
+            .route("/{table}", web::get().to(query_all))
+            .route("/{table}", web::head().to(query_all))
+            .route("/{table}", web::post().to(insert_rows))
+            .route("/{table}", web::patch().to(update_all))
+            .route("/{table}", web::delete().to(delete_all))
+            .route("/{table}/policies", web::get().to(table_policies))
+            .route("/{table}/aggregate", web::get().to(aggregate_all))
+            .route("/{table}/bulk", web::post().to(bulk_insert))
+            .route("/{table}/aggregate/{filter}", web::get().to(aggregate_table))
+            .route("/{table}/_changes", web::get().to(table_changes))
+            .route("/{table}/{id}/history", web::get().to(row_history))
+            .route("/{table}/{filter}/_blob/{column}", web::get().to(row_blob))
+            .route("/{table}/{filter}", web::get().to(query_table))
+            .route("/{table}/{filter}", web::head().to(query_table))
+            .route("/{table}/{filter}", web::patch().to(update_table))
+            .route("/{table}/{filter}", web::delete().to(delete_table)),
+    );
+}
+
+// The `datapi` binary: parses the command line and settings, then serves,
+// snapshots or replays
+pub async fn run() -> std::io::Result<()> {
+    run_with(|options| options).await
+}
+
+// Like run, for a binary that compiles in its own hooks:
+// `datapi::run_with(|options| options.hook(TenantHook)).await`
+pub async fn run_with(register: impl FnOnce(Options) -> Options) -> std::io::Result<()> {
+    dotenv::dotenv().ok();
+    let usage_error = |e: String| -> ! {
+        eprintln!("{}\n{}", e, USAGE);
+        std::process::exit(2);
+    };
+    let (args, settings_file, flags) = take_settings(env::args().skip(1).collect()).unwrap_or_else(|e| usage_error(e));
+    for (name, value) in flags {
+        env::set_var(name, value);
+    }
+    let settings_file = settings_file
+        .or_else(|| std::path::Path::new(DEFAULT_SETTINGS_FILE).exists().then(|| DEFAULT_SETTINGS_FILE.to_string()));
+    if let Some(path) = &settings_file {
+        for (name, value) in load_settings(path) {
+            if env::var_os(name).is_none() {
+                env::set_var(name, value);
+            }
+        }
+    }
+    init_logging();
+    if let Some(path) = &settings_file {
+        log::info!("Read settings from {}", path);
+    }
+    
+    let command = parse_command(&args).unwrap_or_else(|e| usage_error(e));
+    
+    let host = env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
+    let port = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
+    let bind_address = format!("{}:{}", host, port);
+    
+    if let Command::Serve { mock: Some(schema_path) } = &command {
+        return serve_mock(schema_path, &bind_address).await;
+    }
+    if let Command::Replay { journal, target, after } = &command {
+        let target = target.clone().unwrap_or_else(|| format!("http://localhost:{}", port));
+        return run_replay(journal, &target, after.as_deref()).await;
+    }
+    
+    let database_url = env::var("DATABASE_URL")
+        .expect("DATABASE_URL must be set");
+    if let Some(database) = unsupported_database(&database_url) {
+        panic!("DATABASE_URL must be a postgres:// URL; {} databases are not supported", database);
+    }
+    
+    let config = Config::from_env();
+    let connect_options: PgConnectOptions = database_url
+        .parse::<PgConnectOptions>()
+        .expect("Invalid DATABASE_URL")
+        .application_name(APPLICATION_NAME)
+        .statement_cache_capacity(config.statement_cache_capacity);
+    
+    let tracer_provider = config.otlp_tracing.then(init_tracing);
+    let pool = pool_options(&config)
+        .connect_with(connect_options)
+        .await
+        .expect("Failed to create pool");
+    
+    log::info!("Connected to database");
+    if let Command::Snapshot { dir, check } = &command {
+        return run_snapshot(&pool, dir, *check).await;
+    }
+    
+    let mut options = register(Options::with_config(&pool, config).await);
+    let tls = TlsConfig::from_env();
+    let socket = SocketConfig::from_env();
+    if tls.is_some() && socket.as_ref().is_some_and(|s| s.only) {
+        panic!("DATAPI_SOCKET_ONLY leaves no TCP port for DATAPI_TLS_CERT");
+    }
+    let port: u16 = port.parse().unwrap_or_else(|_| panic!("Invalid PORT {}", port));
+    options.https_port = tls.as_ref().and_then(|tls| tls.redirect_port).map(|_| web::Data::new(HttpsPort(port)));
+    let shutdown_grace = Duration::from_secs(env_parse("DATAPI_SHUTDOWN_GRACE_SECS", 30));
+    let shutdown = options.draining.clone();
+    // Every pool, to close once the server has drained
+    let mut pools = vec![pool.clone()];
+    pools.extend(options.pools());
+    let audit = options.audit.clone();
+    
+    match &tls {
+        _ if socket.as_ref().is_some_and(|s| s.only) => {}
+        Some(tls) => log::info!(
+            "Starting server at https://{}{}",
+            bind_address,
+            tls.redirect_port.map(|p| format!(", redirecting http on port {}", p)).unwrap_or_default()
+        ),
+        None => log::info!("Starting server at {}", bind_address),
+    }
+    
+    let server = HttpServer::new(move || App::new().configure(|cfg| configure(cfg, pool.clone(), &options)));
+    let server = match &tls {
+        Some(tls) => {
+            let server = server.bind_rustls_0_23(&bind_address, tls.server_config())?;
+            match tls.redirect_port {
+                Some(redirect_port) => server.bind((host.as_str(), redirect_port))?,
+                None => server,
+            }
+        }
+        None if socket.as_ref().is_some_and(|s| s.only) => server,
+        None => server.bind(&bind_address)?,
+    };
+    #[cfg(unix)]
+    let server = match &socket {
+        Some(socket) => {
+            socket.remove_stale();
+            let server = server.listen_uds(socket.listen()?)?;
+            log::info!("Listening on unix socket {} (mode {:o})", socket.path, socket.mode);
+            server
+        }
+        None => server,
+    };
+    let server = server.shutdown_timeout(shutdown_grace.as_secs()).disable_signals().run();
+    tokio::spawn(shutdown_on_signal(server.handle(), shutdown, shutdown_grace));
+    let server = server.await;
+    if let Some(socket) = &socket {
+        socket.remove();
+    }
+    if let Some(audit) = audit {
+        audit.flush().await;
+    }
+    for pool in pools {
+        pool.close().await;
+    }
+    log::info!("Database connections closed");
+    if let Some(provider) = tracer_provider {
+        if let Err(e) = provider.shutdown() {
+            log::warn!("Flushing traces failed: {}", e);
+        }
+    }
+    server
+}
@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
+use crate::middleware::current_request_id;
+
+// Bundled English templates for user-facing messages. Locale files loaded
+// from DATAPI_LOCALES_DIR override these per key; `{name}` placeholders are
+// filled from the message arguments.
+pub(crate) const DEFAULT_MESSAGES: &[(&str, &str)] = &[
+    ("invalid_table_name", "Invalid table name"),
+    ("invalid_column_name", "Invalid column name"),
+    ("empty_select", "The select list contains an empty column name"),
+    ("invalid_timezone", "Unknown time zone {tz}, expected an IANA name like Europe/Berlin"),
+    ("unknown_embed", "No foreign key relates {table} to {embed}"),
+    ("ambiguous_embed", "{embed} matches more than one foreign key of {table}, embed it by the referencing column"),
+    ("invalid_embed_option", "Invalid option {option} of embed {embed}, expected page=N or page_size=N"),
+    ("embed_not_list", "{embed} embeds a single row and can't be paged"),
+    ("embed_restricted", "{embed} has claim filters, encrypted or masked columns and can't be embedded"),
+    ("distinct_unsupported", "distinct and distinct_on can't be combined with {param}"),
+    ("empty_search", "search needs at least one word"),
+    ("invalid_search_config", "Invalid text search configuration {config}"),
+    ("search_encrypted", "Column {column} is encrypted and can't be searched"),
+    ("search_columns_required", "search needs search_columns, {table} has no tsvector column"),
+    ("invalid_alias", "Invalid alias {alias}, aliases are letters, digits and underscores and don't start with __datapi"),
+    ("alias_protected", "Column {column} is masked or encrypted and can't be renamed"),
+    ("alias_conflict", "Alias {alias} is the name of another column of {table}"),
+    ("select_only_binary", "omit_binary=true leaves none of the selected columns"),
+    ("masked_column", "Column {column} is masked and can't be used here"),
+    ("aggregate_encrypted", "Column {column} is encrypted and can't be aggregated or grouped by"),
+    ("blob_encrypted", "Column {column} is encrypted and can't be fetched raw"),
+    ("hook_rejected", "{reason}"),
+    ("invalid_range", "Invalid range {value}, expected (low,high)"),
+    ("invalid_structured_filter", "Invalid filter parameter {parameter}, expected filter[column] or filter[column][operator]"),
+    ("unknown_structured_operator", "Unknown filter operator {operator}, expected one of {operators}"),
+    ("invalid_structured_null", "filter[...][is] takes null or not_null, not {value}"),
+    ("structured_filter_staged", "Writes to moderated tables take their filters in the path, not as filter[...] parameters"),
+    ("blob_not_binary", "Column {column} is {type}, _blob needs a bytea column"),
+    ("blob_row_not_found", "No row matches the filter"),
+    ("blob_ambiguous", "The filter matches more than one row, _blob needs a single row"),
+    ("blob_null", "Column {column} is NULL in the matching row"),
+    ("invalid_sort_order", "Invalid sort order. Use 'asc' or 'desc'"),
+    ("invalid_sort_modifier", "Invalid sort modifier {modifier}. Use asc, desc, nullsfirst, nullslast or ci"),
+    ("unsupported_collation", "Unsupported collation: {collation}"),
+    ("invalid_filter", "Invalid filter: {cause}"),
+    ("url_decode_failed", "Failed to decode URL"),
+    ("invalid_filter_format", "Invalid filter format"),
+    ("no_valid_operator", "No valid operator found"),
+    ("invalid_json_filter", "@> needs a JSON value, got {value}"),
+    ("invalid_spatial_filter", "{operator} needs {count} numbers, got {value}"),
+    ("spatial_filter_unsupported", "bbox and dwithin need a geometry or geography column, {column} is {type}"),
+    ("geojson_without_geometry", "format=geojson needs a geometry or geography column, {table} has none"),
+    ("array_filter_unsupported", "contains and overlaps need an array column, {column} is {type}"),
+    ("invalid_value_list", "Invalid value list, expected in.(value1,value2,...)"),
+    ("invalid_filter_group", "Invalid filter group, expected or=(filter1,filter2,...) or and=(...)"),
+    ("invalid_consistency_token", "Invalid consistency token, expected a WAL location such as 0/16B3748"),
+    ("invalid_aggregate", "Invalid aggregate {aggregate}, expected function(column) or count(*)"),
+    ("unsupported_aggregate", "Unsupported aggregate function {function}"),
+    ("database_error", "Database error, the server log has the details"),
+    ("constraint_violation", "The write violates a constraint: {error}"),
+    ("database_rejected", "The database rejected the request: {error}"),
+    ("internal_error", "Internal error, the server log has the details"),
+    ("invalid_json_body", "Invalid JSON request body: {reason}"),
+    ("invalid_path", "Invalid path segment: {reason}"),
+    ("admin_disabled", "Admin API is disabled"),
+    ("include_deleted_admin_only", "include_deleted requires the admin token"),
+    ("sql_disabled", "The SQL endpoint is disabled, set DATAPI_ENABLE_SQL=true to enable it"),
+    ("sql_not_select", "Only SELECT statements can be run, not {statement}"),
+    ("unauthorized", "Missing or invalid credentials"),
+    ("invalid_pid", "Invalid query pid"),
+    ("query_not_found", "No running API query with pid {pid}"),
+    ("database_unavailable", "Database is unavailable, retry later"),
+    ("result_not_found", "Result not found or expired"),
+    ("table_not_found", "Table {table} does not exist"),
+    ("invalid_offset", "Invalid offset, expected a multiple of the page size"),
+    ("invalid_cursor", "Invalid cursor"),
+    ("cursor_mismatch", "The cursor belongs to another sort or filter (fingerprint {fingerprint}), start again with cursor="),
+    ("cursor_unsupported", "Cursor pagination needs an order on NOT NULL columns ending in a unique key"),
+    ("unsupported_format", "Unsupported format {format}, use 'json', 'csv', 'ndjson', 'parquet', 'geojson', 'xlsx' or 'arrow'"),
+    ("missing_aggregate", "Missing agg parameter, e.g. agg=count(*),sum(amount)"),
+    ("missing_bucket", "time_column, from, to and compare require bucket and time_column, e.g. bucket=day&time_column=created_at"),
+    ("invalid_bucket", "Invalid bucket {bucket}, use hour, day, week or month"),
+    ("invalid_time_bound", "Invalid time {value}, expected a date or timestamp such as 2026-01-31 or 2026-01-31T12:00:00Z"),
+    ("invalid_compare", "Invalid compare {compare}, use previous_period or previous_year"),
+    ("compare_requires_range", "compare requires from and to, with from before to"),
+    ("table_not_found_suggestions", "Table {table} does not exist, did you mean {suggestions}?"),
+    ("writes_disabled", "Writes are disabled"),
+    ("table_read_only", "Table {table} is read-only"),
+    ("shares_disabled", "Share links are disabled, set DATAPI_SHARE_SECRET"),
+    ("invalid_share_expiry", "expires_in must be between 1 and {max} seconds"),
+    ("invalid_share", "Invalid share: {reason}"),
+    ("share_expired", "This share link has expired"),
+    ("share_not_found", "Share link not found"),
+    ("share_parameter_fixed", "Share links only take page and format; {parameter} is fixed by the link"),
+    ("invalid_envelope", "Invalid envelope {value}, use 'default', 'bare' or 'items_meta'"),
+    ("statement_timeout", "The query took longer than the statement timeout. Narrow the filters or raise timeout_ms"),
+    ("invalid_query_string", "Invalid query string: {reason}"),
+    ("template_not_found", "Template {template} not found"),
+    ("function_not_found", "Function {function} not found"),
+    ("invalid_function_args", "No signature of {function} takes the arguments {args}"),
+    ("ambiguous_function_args", "Several signatures of {function} take the arguments {args}"),
+    ("database_not_found", "Database {database} not found"),
+    ("schema_not_found", "Schema {schema} is not available"),
+    ("random_sort_single_page", "sort=random returns a single page of at most {max} rows, without page, cursor, materialize or ndjson"),
+    ("invalid_debug", "Invalid debug {value}, expected semantics with a JSON response"),
+    ("journal_unavailable", "The write could not be journaled and was not run"),
+    ("invalid_on_conflict", "Invalid on_conflict value {value}, use 'error', 'ignore', 'merge' or 'replace'"),
+    ("missing_conflict_target", "Table {table} has no primary or unique key to merge on, name one with conflict_columns"),
+    ("invalid_consistency", "Invalid consistency {value}, expected strong, bounded or eventual"),
+    ("unknown_preset", "Table {table} has no preset {preset}"),
+    ("invalid_isolation", "Invalid isolation {value}, expected read_committed, repeatable_read or serializable"),
+    ("isolation_not_allowed", "Isolation {value} is not allowed, the strictest level is {max}"),
+    ("serialization_failure", "The transaction conflicted with a concurrent one, retry it"),
+    ("unknown_template_param", "Unknown parameter {param} for template {template}"),
+    ("missing_template_param", "Template {template} requires parameter {param}"),
+    ("invalid_template_param", "Invalid value {value} for parameter {param}, expected {expected}"),
+    ("materialize_read_only", "Results can't be materialized in read-only mode"),
+    ("invalid_write_body", "Invalid request body, expected a JSON object or a non-empty array of objects"),
+    ("unknown_column", "Column {column} does not exist in table {table}"),
+    ("invalid_update_body", "Invalid request body, expected a JSON object of column values"),
+    ("missing_write_filter", "An update without filters would change every row, add a filter"),
+    ("invalid_bulk_body", "Invalid request body: {cause}"),
+    ("unsupported_write_content_type", "Unsupported content type, send application/json, application/x-ndjson, application/msgpack or text/csv"),
+    ("undecodable_write_body", "Invalid {format} request body: {cause}"),
+    ("invalid_column_format", "Invalid column format {format}, expected column:format"),
+    ("invalid_value", "Invalid {type} value {value}"),
+    ("invalid_filter_value", "Invalid filter value {value} for {column}, expected {type}"),
+    ("invalid_rows", "{count} rows failed validation, nothing was inserted"),
+    ("invalid_expression", "Invalid expression {expression}"),
+    ("unsupported_function", "Unsupported function {function}"),
+    ("invalid_api_key", "Missing or invalid API key, send one in the X-API-Key header"),
+    ("insufficient_scope", "The API key is read-only and can't be used for {method} requests"),
+    ("invalid_token", "Invalid bearer token: {reason}"),
+    ("missing_token", "Missing credentials, send a JWT as Authorization: Bearer <token>"),
+    ("rate_limited", "Too many requests, retry in {seconds} seconds"),
+    ("row_quota_exceeded", "The API key's quota of {max_rows} rows per day is used up, retry in {seconds} seconds"),
+    ("invalid_table_limit", "Invalid {limit} for table {table}, expected a non-negative integer or null"),
+    ("unknown_table_limit", "{limit} can't be changed at runtime, only {limits}"),
+    ("config_not_persistable", "persist needs a config file, set DATAPI_CONFIG"),
+    ("config_persist_failed", "Failed to write the config file: {error}"),
+    ("moderated_param", "{param} can't be used with {table}, whose writes need approval"),
+    ("moderated_bulk", "Writes to {table} need approval and can't be bulk-loaded, POST them to /{table}"),
+    ("invalid_pending_status", "Invalid status {status}, expected pending, approved, rejected or all"),
+    ("pending_not_found", "No pending change {id}"),
+    ("pending_decided", "Change {id} was already {status}"),
+    ("table_not_granted", "The token doesn't grant {access} access to table {table}"),
+    ("claim_required", "Table {table} is only served to bearer tokens with the {claim} claim"),
+    ("claim_mismatch", "{column} must match the token's claim"),
+    ("history_not_configured", "Change history is not recorded for table {table}"),
+    ("changes_not_configured", "Changes are not streamed for table {table}"),
+    ("invalid_history_until", "Invalid until parameter, expected a date or an RFC 3339 timestamp"),
+    ("checkpoints_unsupported", "checkpoints and resume_token apply to whole NDJSON exports, without page or page_size"),
+    ("invalid_batch_body", "A batch must be a JSON array of 1 to {max} operations"),
+    ("invalid_multi_body", "A multi-read must be a JSON object of 1 to {max} named reads"),
+    ("multi_param_unsupported", "{param} is not supported in a multi-read, which returns JSON pages"),
+    ("multi_param_request_wide", "{param} applies to every read of a multi-read; pass it in the query string of POST /_multi"),
+    ("invalid_fields", "Invalid fields {fields}, expected paths such as /data/*/id or data.*.id"),
+    ("table_not_pending", "Table {table} is not waiting for approval"),
+    ("missing_delete_filter", "A delete without filters removes every row, add a filter or confirm_all=true"),
+    ("invalid_on_overflow", "Invalid on_overflow value {value}, use 'reject' or 'summarize'"),
+    ("invalid_count", "Invalid count value {value}, use 'exact', 'estimated' or 'none'"),
+    ("count_failed", "Counting the rows failed, total_count is omitted"),
+    ("count_estimated_after_failure", "total_count is estimated because counting {table} failed recently"),
+    ("row_limit_exceeded", "The request matches more than {max_rows} rows. Narrow the filters or use on_overflow=summarize"),
+    ("row_limit_guidance", "The request matches {count} rows, more than the limit of {max_rows}. Narrow the filters, e.g. to a range of {column}"),
+    ("row_limit_guidance_unsorted", "The request matches {count} rows, more than the limit of {max_rows}. Narrow the filters"),
+    ("graphql_syntax", "Invalid GraphQL query at character {position}: {reason}"),
+    ("graphql_unknown_field", "Unknown field {field} on type {type}"),
+    ("graphql_invalid_argument", "Invalid {argument} of field {field}: {reason}"),
+    ("graphql_too_deep", "The query nests fields more than {max} levels deep"),
+    ("graphql_subscription_fields", "A subscription selects exactly one table, not {count} fields"),
+    ("graphql_subscription_unsupported", "A subscription to {field} takes only where and columns, not {name}"),
+];
+
+// A user-facing message, translated when the response is built.
+#[derive(Debug, Clone)]
+pub(crate) struct Message {
+    pub(crate) key: &'static str,
+    pub(crate) args: Vec<(&'static str, String)>,
+    pub(crate) cause: Option<Box<Message>>,
+    // The status bad_request answers with instead of 400, for a hook's refusal
+    pub(crate) status: Option<StatusCode>,
+    // The query parameter or filter the error is about
+    pub(crate) param: Option<String>,
+}
+
+impl Message {
+    pub(crate) fn new(key: &'static str) -> Self {
+        Message { key, args: Vec::new(), cause: None, status: None, param: None }
+    }
+
+    pub(crate) fn arg(mut self, name: &'static str, value: impl ToString) -> Self {
+        self.args.push((name, value.to_string()));
+        self
+    }
+
+    // Nests another message, rendered into the `{cause}` placeholder.
+    pub(crate) fn cause(mut self, cause: Message) -> Self {
+        self.cause = Some(Box::new(cause));
+        self
+    }
+
+    pub(crate) fn status(mut self, status: StatusCode) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub(crate) fn param(mut self, param: impl ToString) -> Self {
+        self.param = Some(param.to_string());
+        self
+    }
+
+    // The stable `code` of an error body: the message key, with variants of
+    // one error under a single code and the database failures under the
+    // short codes clients already match on
+    pub(crate) fn code(&self) -> &'static str {
+        match self.key {
+            "table_not_found_suggestions" => "table_not_found",
+            "statement_timeout" => "timeout",
+            "database_error" => "db_error",
+            key => key,
+        }
+    }
+
+    pub(crate) fn parameter(&self) -> Option<&str> {
+        self.param.as_deref().or_else(|| self.cause.as_ref().and_then(|c| c.parameter()))
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Translations {
+    // lowercase language tag -> message key -> template
+    pub(crate) locales: HashMap<String, HashMap<String, String>>,
+}
+
+impl Translations {
+    // Loads every `<language-tag>.json` file (a flat key -> template object)
+    // from the given directory.
+    pub(crate) fn load(dir: &str) -> Self {
+        let mut locales = HashMap::new();
+        let entries = match std::fs::read_dir(dir) {
+            Ok(e) => e,
+            Err(e) => {
+                log::error!("Failed to read locales directory {}: {}", dir, e);
+                return Translations::default();
+            }
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let Some(tag) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let parsed = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()));
+            match parsed {
+                Ok(messages) => {
+                    log::info!("Loaded locale {}", tag);
+                    locales.insert(tag.to_lowercase(), messages);
+                }
+                Err(e) => log::error!("Failed to load locale {}: {}", path.display(), e),
+            }
+        }
+
+        Translations { locales }
+    }
+
+    // Picks the best loaded locale for an Accept-Language header, trying each
+    // language range by descending quality, then its primary subtag.
+    pub(crate) fn negotiate(&self, accept_language: &str) -> Option<&str> {
+        let mut ranges: Vec<(String, f32)> = accept_language
+            .split(',')
+            .filter_map(|part| {
+                let mut pieces = part.split(';');
+                let tag = pieces.next()?.trim().to_lowercase();
+                let quality = pieces
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                (!tag.is_empty() && quality > 0.0).then_some((tag, quality))
+            })
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        for (tag, _) in &ranges {
+            if let Some((key, _)) = self.locales.get_key_value(tag) {
+                return Some(key);
+            }
+            let primary = tag.split('-').next().unwrap_or(tag);
+            if let Some((key, _)) = self.locales.get_key_value(primary) {
+                return Some(key);
+            }
+        }
+        None
+    }
+
+    pub(crate) fn render(&self, locale: Option<&str>, message: &Message) -> String {
+        let template = locale
+            .and_then(|l| self.locales.get(l))
+            .and_then(|m| m.get(message.key))
+            .map(String::as_str)
+            .or_else(|| {
+                DEFAULT_MESSAGES
+                    .iter()
+                    .find(|(key, _)| *key == message.key)
+                    .map(|(_, t)| *t)
+            })
+            .unwrap_or(message.key);
+
+        let mut text = template.to_string();
+        for (name, value) in &message.args {
+            text = text.replace(&format!("{{{}}}", name), value);
+        }
+        if let Some(cause) = &message.cause {
+            text = text.replace("{cause}", &self.render(locale, cause));
+        }
+        text
+    }
+}
+
+pub(crate) fn error_response(req: &HttpRequest, status: StatusCode, message: Message) -> HttpResponse {
+    error_response_with(req, status, message, serde_json::Map::new())
+}
+
+// Like error_response, with extra fields next to the error's own: "error"
+// (the translated message), "code", "parameter" and "request_id".
+pub(crate) fn error_response_with(
+    req: &HttpRequest,
+    status: StatusCode,
+    message: Message,
+    mut body: serde_json::Map<String, serde_json::Value>,
+) -> HttpResponse {
+    let (text, locale) = render_message(req, &message);
+    body.insert("error".to_string(), serde_json::json!(text));
+    body.insert("code".to_string(), serde_json::json!(message.code()));
+    if let Some(param) = message.parameter() {
+        body.insert("parameter".to_string(), serde_json::json!(param));
+    }
+    if let Some(id) = current_request_id() {
+        body.insert("request_id".to_string(), serde_json::json!(id));
+    }
+
+    HttpResponse::build(status)
+        .insert_header(("Content-Language", locale.unwrap_or("en")))
+        .json(body)
+}
+
+// Renders a message in the locale negotiated from Accept-Language, returning
+// the text and the locale (None for the bundled English).
+pub(crate) fn render_message<'a>(req: &'a HttpRequest, message: &Message) -> (String, Option<&'a str>) {
+    let translations = req.app_data::<web::Data<Translations>>();
+    let locale = match (translations, req.headers().get("Accept-Language")) {
+        (Some(t), Some(header)) => header.to_str().ok().and_then(|h| t.negotiate(h)),
+        _ => None,
+    };
+    let text = match translations {
+        Some(t) => t.render(locale, message),
+        None => Translations::default().render(None, message),
+    };
+    (text, locale)
+}
+
+pub(crate) fn bad_request(req: &HttpRequest, message: Message) -> HttpResponse {
+    error_response(req, message.status.unwrap_or(StatusCode::BAD_REQUEST), message)
+}
+
+// Answers a query string, path or JSON body that didn't deserialize with an
+// error body like the handlers' own, keeping the extractor's status.
+pub(crate) fn extractor_error<E: actix_web::ResponseError + 'static>(req: &HttpRequest, key: &'static str, e: E) -> actix_web::Error {
+    let response = error_response(req, e.status_code(), Message::new(key).arg("reason", &e));
+    actix_web::error::InternalError::from_response(e, response).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use actix_web::test::TestRequest;
+    use crate::query::QueryParams;
+
+    fn translations() -> Translations {
+        Translations {
+            locales: HashMap::from([(
+                "de".to_string(),
+                HashMap::from([
+                    ("invalid_filter".to_string(), "Ungültiger Filter: {cause}".to_string()),
+                    ("no_valid_operator".to_string(), "Kein gültiger Operator".to_string()),
+                ]),
+            )]),
+        }
+    }
+
+    #[test]
+    fn negotiates_the_best_loaded_locale() {
+        let translations = translations();
+        assert_eq!(translations.negotiate("fr;q=0.9, de-AT;q=0.8"), Some("de"));
+        assert_eq!(translations.negotiate("de;q=0, en"), None);
+        assert_eq!(translations.negotiate(""), None);
+    }
+
+    #[test]
+    fn renders_templates_with_arguments_and_causes() {
+        let translations = translations();
+        let message = Message::new("invalid_filter").cause(Message::new("no_valid_operator"));
+        assert_eq!(translations.render(Some("de"), &message), "Ungültiger Filter: Kein gültiger Operator");
+        assert_eq!(translations.render(None, &message), "Invalid filter: No valid operator found");
+        let message = Message::new("unsupported_collation").arg("collation", "xx");
+        assert_eq!(translations.render(Some("de"), &message), "Unsupported collation: xx");
+    }
+
+    #[actix_web::test]
+    async fn error_bodies_are_translated_per_request() {
+        let req = TestRequest::default()
+            .insert_header(("Accept-Language", "de"))
+            .app_data(web::Data::new(translations()))
+            .to_http_request();
+        let resp = bad_request(&req, Message::new("no_valid_operator"));
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.headers().get("Content-Language").unwrap(), "de");
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, r#"{"code":"no_valid_operator","error":"Kein gültiger Operator"}"#);
+    }
+
+    #[test]
+    fn bundled_locales_load() {
+        let translations = Translations::load("locales");
+        assert!(translations.locales["de"].contains_key("invalid_filter"));
+    }
+
+    #[test]
+    fn codes_are_stable_across_message_variants() {
+        assert_eq!(Message::new("invalid_filter").code(), "invalid_filter");
+        assert_eq!(Message::new("table_not_found_suggestions").code(), "table_not_found");
+        assert_eq!(Message::new("statement_timeout").code(), "timeout");
+        assert_eq!(Message::new("database_error").code(), "db_error");
+    }
+
+    #[test]
+    fn parameters_come_from_the_cause_too() {
+        let message = Message::new("invalid_filter").cause(Message::new("invalid_filter_value").param("amount"));
+        assert_eq!(message.parameter(), Some("amount"));
+        assert_eq!(Message::new("invalid_filter").param("or").parameter(), Some("or"));
+        assert_eq!(Message::new("invalid_filter").parameter(), None);
+    }
+
+    #[actix_web::test]
+    async fn error_bodies_name_their_parameter() {
+        let req = TestRequest::default().to_http_request();
+        let body = json_body(bad_request(&req, Message::new("invalid_offset").param("offset"))).await;
+        assert_eq!(body["code"], "invalid_offset");
+        assert_eq!(body["parameter"], "offset");
+        assert!(body.get("request_id").is_none());
+
+        let e = web::Query::<QueryParams>::from_query("page=x").unwrap_err();
+        let resp = extractor_error(&req, "invalid_query_string", e).error_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json_body(resp).await["code"], "invalid_query_string");
+    }
+}
@@ -0,0 +1,2389 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use aes_gcm::aead::OsRng;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use crate::access::{
+    check_off_search_path, claim_values, path_table, row_meter, ApiKeys, TokenGrants,
+};
+use crate::background::{write_audit_log, AuditSink};
+use crate::config::{env_parse, BindLogging, Config, EnvelopeStyle, PriorityClassConfig};
+use crate::filters::{FilterExpr, FilterValue};
+use crate::graphql::GRAPHQL_PATH;
+use crate::handlers::{
+    is_admin, is_health_path, TableLimits, DEFAULT_DATABASE, MULTI_PATH, SQL_PATH,
+};
+use crate::messages::{bad_request, error_response, Message};
+use crate::query::{unix_now, APPLICATION_NAME};
+use crate::serialization::XLSX_CONTENT_TYPE;
+
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    // ID of the request being handled, for the JSON log lines
+    static REQUEST_ID: String;
+}
+
+pub(crate) fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+// What a table read found, for the access and audit logs
+#[derive(Debug, Clone)]
+pub(crate) struct ReadStats {
+    pub(crate) table: String,
+    // The caller's filter conditions, see FilterCondition::describe
+    pub(crate) filters: Vec<String>,
+    pub(crate) rows: usize,
+}
+
+// Notes what a read returned, for the access log and the API key's row
+// quota. Requests made of several reads, like GraphQL queries and
+// multi-reads, add them up.
+pub(crate) fn note_read(req: &HttpRequest, table: &str, filters: Vec<String>, rows: usize) {
+    if let Some(meter) = row_meter(req) {
+        meter.record(rows as u64);
+    }
+    let mut extensions = req.extensions_mut();
+    match extensions.get_mut::<ReadStats>() {
+        Some(stats) => {
+            if !stats.table.split(',').any(|t| t == table) {
+                stats.table = format!("{},{}", stats.table, table);
+            }
+            stats.filters.extend(filters);
+            stats.rows += rows;
+        }
+        None => {
+            extensions.insert(ReadStats { table: table.to_string(), filters, rows });
+        }
+    }
+}
+
+// Tags the request with an ID, the caller's X-Request-Id when it is a
+// plausible one, returns it in the response and logs the request under
+// datapi::access once answered.
+pub(crate) async fn request_id(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    use aes_gcm::aead::rand_core::RngCore;
+    let presented = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .filter(|id| id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c)));
+    let id = match presented {
+        Some(id) => id.to_string(),
+        None => {
+            let mut bytes = [0u8; 16];
+            OsRng.fill_bytes(&mut bytes);
+            bytes.iter().map(|b| format!("{:02x}", b)).collect()
+        }
+    };
+    let started = Instant::now();
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+    let path_and_query = req.uri().path_and_query().map(|p| p.to_string()).unwrap_or_else(|| path.clone());
+    let mut res = REQUEST_ID.scope(id.clone(), next.call(req)).await?;
+    if let Ok(value) = actix_web::http::header::HeaderValue::from_str(&id) {
+        res.headers_mut().insert(actix_web::http::header::HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    let stats = res.request().extensions().get::<ReadStats>().cloned();
+    let status = res.status().as_u16();
+    let duration_ms = (started.elapsed().as_secs_f64() * 1000.0 * 100.0).round() / 100.0;
+    log::info!(
+        target: "datapi::access",
+        request_id = id.as_str(),
+        method = method.as_str(),
+        path = path.as_str(),
+        status = status,
+        duration_ms = duration_ms,
+        table = stats.as_ref().map(|s| s.table.as_str()),
+        filters = stats.as_ref().map(|s| s.filters.len()),
+        rows = stats.as_ref().map(|s| s.rows);
+        "{} {} {} {:.2}ms", method, path, status, duration_ms
+    );
+    if let Some(audit) = res.request().app_data::<web::Data<AuditLog>>() {
+        audit.record(AuditRecord {
+            at: chrono::Utc::now(),
+            request_id: id,
+            principal: audit_principal(res.request()),
+            table: stats.as_ref().map(|s| s.table.clone()).or_else(|| path_table(&path)),
+            method,
+            path: path_and_query,
+            filters: stats.as_ref().map(|s| s.filters.clone()).unwrap_or_default(),
+            rows: stats.as_ref().map(|s| s.rows),
+            duration_ms,
+            status,
+        });
+    }
+    Ok(res)
+}
+
+// A request as recorded by the audit log
+#[derive(Debug, Serialize)]
+pub(crate) struct AuditRecord {
+    pub(crate) at: chrono::DateTime<chrono::Utc>,
+    pub(crate) request_id: String,
+    // admin, jwt:{subject} or key:{fingerprint}, see audit_principal
+    pub(crate) principal: Option<String>,
+    pub(crate) method: String,
+    // Path and query string, with the filters as the caller sent them
+    pub(crate) path: String,
+    pub(crate) table: Option<String>,
+    pub(crate) filters: Vec<String>,
+    pub(crate) rows: Option<usize>,
+    pub(crate) duration_ms: f64,
+    pub(crate) status: u16,
+}
+
+pub(crate) enum AuditMessage {
+    Record(AuditRecord),
+    // Writes what is pending and answers once it is written
+    Flush(tokio::sync::oneshot::Sender<()>),
+}
+
+// Records every request to DATAPI_AUDIT_TABLE or DATAPI_AUDIT_FILE. Requests
+// only queue their record; write_audit_log writes them in batches, and
+// records that don't fit in the queue are dropped and counted rather than
+// holding up the request.
+pub(crate) struct AuditLog {
+    pub(crate) sender: tokio::sync::mpsc::Sender<AuditMessage>,
+    pub(crate) dropped: std::sync::atomic::AtomicU64,
+}
+
+// Batches the queue holds before records are dropped
+pub(crate) const AUDIT_BUFFERED_BATCHES: usize = 20;
+
+impl AuditLog {
+    // Opens the sink and starts its writer, None without an audit setting
+    pub(crate) async fn start(pool: &PgPool, config: &Config) -> Option<web::Data<AuditLog>> {
+        let sink = match (&config.audit_table, &config.audit_file) {
+            (Some(table), _) => {
+                check_off_search_path(pool, table, "audit").await;
+                sqlx::query(&format!("SELECT 1 FROM {} LIMIT 0", table))
+                    .execute(pool)
+                    .await
+                    .unwrap_or_else(|e| panic!("Failed to read the audit table {}: {}", table, e));
+                AuditSink::Table { pool: pool.clone(), table: table.clone() }
+            }
+            (None, Some(path)) => AuditSink::File(
+                tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .unwrap_or_else(|e| panic!("Failed to open audit file {}: {}", path, e)),
+            ),
+            (None, None) => return None,
+        };
+        let batch_size = env_parse("DATAPI_AUDIT_BATCH_SIZE", 500usize).max(1);
+        let interval = Duration::from_millis(env_parse("DATAPI_AUDIT_FLUSH_MS", 1000u64).max(1));
+        let (sender, receiver) = tokio::sync::mpsc::channel(batch_size * AUDIT_BUFFERED_BATCHES);
+        let audit = web::Data::new(AuditLog { sender, dropped: Default::default() });
+        tokio::spawn(write_audit_log(receiver, sink, audit.clone(), batch_size, interval));
+        Some(audit)
+    }
+    
+    pub(crate) fn record(&self, record: AuditRecord) {
+        if self.sender.try_send(AuditMessage::Record(record)).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    
+    // Waits until the records queued so far are written, for shutdown
+    pub(crate) async fn flush(&self) {
+        let (done, written) = tokio::sync::oneshot::channel();
+        if self.sender.send(AuditMessage::Flush(done)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+// Who made the request: the admin token, a JWT's subject, or an API key by
+// the start of its SHA-256, so the log holds no usable keys
+pub(crate) fn audit_principal(req: &HttpRequest) -> Option<String> {
+    if req.app_data::<web::Data<Config>>().is_some_and(|config| is_admin(req, config)) {
+        return Some("admin".to_string());
+    }
+    if let Some(grants) = req.extensions().get::<TokenGrants>() {
+        return Some(format!("jwt:{}", grants.subject.as_deref().unwrap_or_default()));
+    }
+    let key = req.headers().get(API_KEY_HEADER).and_then(|h| h.to_str().ok())?;
+    let digest = <sha2::Sha256 as sha2::Digest>::digest(key.as_bytes());
+    Some(format!("key:{}", digest[..6].iter().map(|b| format!("{:02x}", b)).collect::<String>()))
+}
+
+// DATAPI_LOG_FORMAT=json writes each log line as a JSON object with the
+// record's key-values and the current request's ID; the default is
+// env_logger's text format.
+pub(crate) fn init_logging() {
+    use std::io::Write;
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default());
+    if env::var("DATAPI_LOG_FORMAT").is_ok_and(|f| f.eq_ignore_ascii_case("json")) {
+        builder.format(|buf, record| {
+            struct Fields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+            impl<'kvs> log::kv::VisitSource<'kvs> for Fields<'_> {
+                fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+                    let value = if let Some(n) = value.to_u64() {
+                        serde_json::json!(n)
+                    } else if let Some(n) = value.to_i64() {
+                        serde_json::json!(n)
+                    } else if let Some(n) = value.to_f64() {
+                        serde_json::json!(n)
+                    } else if let Some(b) = value.to_bool() {
+                        serde_json::json!(b)
+                    } else if value.to_string() == "None" {
+                        return Ok(());
+                    } else {
+                        serde_json::json!(value.to_string())
+                    };
+                    self.0.insert(key.to_string(), value);
+                    Ok(())
+                }
+            }
+            let mut line = serde_json::Map::new();
+            line.insert("ts".to_string(), serde_json::json!(chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)));
+            line.insert("level".to_string(), serde_json::json!(record.level().as_str()));
+            line.insert("target".to_string(), serde_json::json!(record.target()));
+            line.insert("message".to_string(), serde_json::json!(record.args().to_string()));
+            if let Ok(id) = REQUEST_ID.try_with(|id| id.clone()) {
+                line.insert("request_id".to_string(), serde_json::json!(id));
+            }
+            let _ = record.key_values().visit(&mut Fields(&mut line));
+            writeln!(buf, "{}", serde_json::Value::Object(line))
+        });
+    }
+    builder.init();
+}
+
+pub(crate) struct HeaderExtractor<'a>(pub(crate) &'a actix_web::http::header::HeaderMap);
+
+impl opentelemetry::propagation::Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+    
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+// With OTLP tracing, runs each request in a span that continues the caller's
+// trace from its traceparent header.
+pub(crate) async fn trace_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    use tracing::Instrument;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    if !req.app_data::<web::Data<Config>>().is_some_and(|c| c.otlp_tracing) {
+        return next.call(req).await;
+    }
+    let parent = opentelemetry::global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(req.headers())));
+    let route = req.match_pattern().unwrap_or_else(|| req.path().to_string());
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", req.method(), route),
+        otel.kind = "server",
+        http.request.method = %req.method(),
+        url.path = %req.path(),
+        http.response.status_code = tracing::field::Empty,
+    );
+    span.set_parent(parent);
+    let res = next.call(req).instrument(span.clone()).await?;
+    span.record("http.response.status_code", res.status().as_u16());
+    Ok(res)
+}
+
+// Sends spans to OTEL_EXPORTER_OTLP_ENDPOINT over OTLP/HTTP, and reads and
+// writes W3C trace context
+pub(crate) fn init_tracing() -> opentelemetry_sdk::trace::TracerProvider {
+    use opentelemetry::trace::TracerProvider;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+    opentelemetry::global::set_text_map_propagator(opentelemetry_sdk::propagation::TraceContextPropagator::new());
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .expect("Failed to create OTLP exporter");
+    let service = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| APPLICATION_NAME.to_string());
+    let provider = opentelemetry_sdk::trace::TracerProvider::builder()
+        .with_batch_exporter(exporter, opentelemetry_sdk::runtime::Tokio)
+        .with_resource(opentelemetry_sdk::Resource::new(vec![opentelemetry::KeyValue::new("service.name", service)]))
+        .build();
+    let tracer = provider.tracer(APPLICATION_NAME);
+    opentelemetry::global::set_tracer_provider(provider.clone());
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .init();
+    provider
+}
+
+// The bind values of a statement as they may be logged, per DATAPI_LOG_BINDS.
+// Hashes use fixed keys: they match up equal values across requests and
+// instances of one build, but guessable values can be recovered by trying
+// them.
+pub(crate) fn log_binds(config: &Config, filters: &FilterExpr, extra: &[String]) -> Vec<String> {
+    let render = |value: &str| match config.log_binds {
+        BindLogging::Redact => "?".to_string(),
+        BindLogging::Hash => {
+            use std::hash::{Hash, Hasher};
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            value.hash(&mut hasher);
+            format!("#{:016x}", hasher.finish())
+        }
+        BindLogging::Plain => format!("{:?}", value),
+    };
+    let mut binds = Vec::new();
+    for filter in filters.conditions() {
+        match &filter.value {
+            FilterValue::Single(v) => binds.push(render(v)),
+            FilterValue::List(values) => {
+                binds.push(format!("[{}]", values.iter().map(|v| render(v)).collect::<Vec<_>>().join(", ")))
+            }
+            FilterValue::Null => {}
+        }
+    }
+    binds.extend(extra.iter().map(|v| render(v)));
+    binds
+}
+
+// Fails requests fast while the database is unreachable instead of queuing
+// them onto a dead pool. The circuit opens after a run of consecutive
+// connection-level errors and is closed by a background probe.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    pub(crate) failure_threshold: u32,
+    pub(crate) probe_interval: Duration,
+    pub(crate) consecutive_failures: AtomicU32,
+    pub(crate) opened_at: Mutex<Option<Instant>>,
+    // Most recent connection error, for the readiness check
+    pub(crate) last_error: Mutex<Option<LastError>>,
+    // Requests that found no free connection within the acquire timeout
+    pub(crate) pool_timeouts: std::sync::atomic::AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct LastError {
+    pub(crate) message: String,
+    pub(crate) at: chrono::DateTime<chrono::Utc>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(config: &Config) -> Self {
+        CircuitBreaker {
+            failure_threshold: config.circuit_failure_threshold,
+            probe_interval: Duration::from_secs(config.circuit_probe_secs),
+            consecutive_failures: AtomicU32::new(0),
+            opened_at: Mutex::new(None),
+            last_error: Mutex::new(None),
+            pool_timeouts: std::sync::atomic::AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn is_open(&self) -> bool {
+        self.opened_at.lock().unwrap().is_some()
+    }
+
+    // Seconds until the next probe, if the circuit is open.
+    pub(crate) fn retry_after(&self) -> Option<u64> {
+        let opened_at = (*self.opened_at.lock().unwrap())?;
+        let interval = self.probe_interval.as_secs().max(1);
+        let elapsed = opened_at.elapsed().as_secs();
+        Some(interval - elapsed % interval)
+    }
+
+    pub(crate) fn record_success(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_error(&self, error: &sqlx::Error) {
+        if !is_connection_error(error) {
+            return;
+        }
+        if matches!(error, sqlx::Error::PoolTimedOut) {
+            self.pool_timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+        self.note_error(error.to_string());
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.failure_threshold {
+            let mut opened_at = self.opened_at.lock().unwrap();
+            if opened_at.is_none() {
+                log::warn!("Opening circuit breaker after {} consecutive database errors", failures);
+                *opened_at = Some(Instant::now());
+            }
+        }
+    }
+
+    pub(crate) fn note_error(&self, message: String) {
+        *self.last_error.lock().unwrap() = Some(LastError { message, at: chrono::Utc::now() });
+    }
+
+    pub(crate) fn last_error(&self) -> Option<LastError> {
+        self.last_error.lock().unwrap().clone()
+    }
+
+    pub(crate) fn close(&self) {
+        self.consecutive_failures.store(0, Ordering::Relaxed);
+        if self.opened_at.lock().unwrap().take().is_some() {
+            log::info!("Database reachable again, closing circuit breaker");
+        }
+    }
+}
+
+// Token buckets per client: each holds up to `burst` requests and refills at
+// the configured rate, so one client can't hold the pool against the rest.
+pub(crate) struct RateLimiter {
+    pub(crate) per_second: f64,
+    pub(crate) burst: f64,
+    pub(crate) buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+// Buckets kept before full ones, which are indistinguishable from new ones,
+// are dropped
+pub(crate) const RATE_LIMIT_MAX_CLIENTS: usize = 10_000;
+
+impl RateLimiter {
+    pub(crate) fn from_config(config: &Config) -> Option<Self> {
+        let per_minute = config.rate_limit_per_minute?;
+        Some(RateLimiter {
+            per_second: f64::from(per_minute) / 60.0,
+            burst: f64::from(config.rate_limit_burst),
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Takes a token from the client's bucket. Returns the seconds until the
+    // next token when the bucket is empty.
+    pub(crate) fn acquire(&self, client: &str) -> Option<u64> {
+        take_token(&mut self.buckets.lock().unwrap(), client, self.per_second, self.burst)
+    }
+}
+
+pub(crate) fn take_token(buckets: &mut HashMap<String, (f64, Instant)>, key: &str, per_second: f64, burst: f64) -> Option<u64> {
+    let now = Instant::now();
+    if buckets.len() >= RATE_LIMIT_MAX_CLIENTS && !buckets.contains_key(key) {
+        buckets.retain(|_, (tokens, at)| *tokens + per_second * at.elapsed().as_secs_f64() < burst);
+    }
+    let (tokens, at) = buckets.entry(key.to_string()).or_insert((burst, now));
+    *tokens = (*tokens + per_second * now.duration_since(*at).as_secs_f64()).min(burst);
+    *at = now;
+    if *tokens >= 1.0 {
+        *tokens -= 1.0;
+        return None;
+    }
+    Some(((1.0 - *tokens) / per_second).ceil() as u64)
+}
+
+// The identity a request is rate limited under: the token subject, the API
+// key, or the peer address.
+pub(crate) fn rate_limit_client(req: &ServiceRequest) -> String {
+    if let Some(subject) = req.extensions().get::<TokenGrants>().and_then(|g| g.subject.clone()) {
+        return format!("sub:{}", subject);
+    }
+    if req.app_data::<web::Data<ApiKeys>>().is_some() {
+        if let Some(key) = req.headers().get(API_KEY_HEADER).and_then(|h| h.to_str().ok()) {
+            return format!("key:{}", key);
+        }
+    }
+    match req.peer_addr() {
+        Some(addr) => format!("ip:{}", addr.ip()),
+        None => "ip:unknown".to_string(),
+    }
+}
+
+// Runs inside authentication, so only valid credentials get their own bucket.
+pub(crate) async fn rate_limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let limited = match req.app_data::<web::Data<RateLimiter>>() {
+        Some(limiter) if !is_health_path(req.path()) => {
+            limiter.acquire(&rate_limit_client(&req))
+        }
+        _ => None,
+    };
+    // Then the table's own limit, from its own buckets
+    let limited = limited.or_else(|| {
+        let table = path_table(req.path()).filter(|_| !is_health_path(req.path()))?;
+        let limits = req.app_data::<web::Data<TableLimits>>()?;
+        let config = req.app_data::<web::Data<Config>>()?;
+        limits.acquire(config, &table, &rate_limit_client(&req))
+    });
+    if let Some(secs) = limited {
+        let mut resp = error_response(
+            req.request(),
+            StatusCode::TOO_MANY_REQUESTS,
+            Message::new("rate_limited").arg("seconds", secs),
+        );
+        resp.headers_mut().insert(
+            actix_web::http::header::RETRY_AFTER,
+            actix_web::http::header::HeaderValue::from(secs),
+        );
+        return Ok(req.into_response(resp).map_into_right_body());
+    }
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+// Errors that indicate the database itself is unreachable or refusing
+// connections, as opposed to a bad query.
+pub(crate) fn is_connection_error(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Io(_)
+        | sqlx::Error::Tls(_)
+        | sqlx::Error::PoolTimedOut
+        | sqlx::Error::PoolClosed
+        | sqlx::Error::WorkerCrashed => true,
+        sqlx::Error::Database(db) => db
+            .code()
+            .map(|c| c.starts_with("08") || c == "57P01" || c == "57P03" || c == "53300")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+pub(crate) async fn probe_database(pool: PgPool, breaker: web::Data<CircuitBreaker>) {
+    loop {
+        tokio::time::sleep(breaker.probe_interval).await;
+        if !breaker.is_open() {
+            continue;
+        }
+        match sqlx::query("SELECT 1").execute(&pool).await {
+            Ok(_) => breaker.close(),
+            Err(e) => log::warn!("Database probe failed: {}", e),
+        }
+    }
+}
+
+pub(crate) const CONSISTENCY_TOKEN_HEADER: &str = "x-consistency-token";
+pub(crate) const REPLICA_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// Optional read replica (DATAPI_REPLICA_URL) serving the table endpoints.
+// A client holding a consistency token (a primary WAL LSN) only reads from
+// the replica once it has replayed that far; if it doesn't catch up within
+// `max_wait`, the read goes to the primary.
+pub(crate) struct Replica {
+    pub(crate) pool: Option<PgPool>,
+    pub(crate) max_wait: Duration,
+    // How far behind the primary's WAL `?consistency=bounded` reads may be,
+    // from DATAPI_MAX_REPLICA_LAG_BYTES
+    pub(crate) max_lag_bytes: u64,
+}
+
+// `?consistency=`: strong reads the primary, bounded a replica no further
+// behind than max_lag_bytes, eventual the replica however far behind.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Consistency {
+    Strong,
+    Bounded,
+    Eventual,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ConsistencyParam {
+    pub(crate) consistency: Option<String>,
+}
+
+impl Consistency {
+    pub(crate) fn from_request(req: &HttpRequest) -> Result<Option<Self>, Message> {
+        let param = web::Query::<ConsistencyParam>::from_query(req.query_string()).ok().and_then(|q| q.0.consistency);
+        match param.as_deref() {
+            None => Ok(None),
+            Some("strong") => Ok(Some(Consistency::Strong)),
+            Some("bounded") => Ok(Some(Consistency::Bounded)),
+            Some("eventual") => Ok(Some(Consistency::Eventual)),
+            Some(other) => Err(Message::new("invalid_consistency").arg("value", other).param("consistency")),
+        }
+    }
+}
+
+impl Replica {
+    // Returns the pool the request should read from.
+    pub(crate) async fn read_pool(&self, req: &HttpRequest, primary: &PgPool) -> Result<PgPool, Message> {
+        let consistency = Consistency::from_request(req)?;
+        let Some(replica) = &self.pool else {
+            return Ok(primary.clone());
+        };
+        let token = req.headers().get(CONSISTENCY_TOKEN_HEADER);
+        let header = match (consistency, token) {
+            (Some(Consistency::Strong), _) => return Ok(primary.clone()),
+            (Some(Consistency::Eventual), _) => return Ok(replica.clone()),
+            (Some(Consistency::Bounded), None) => return Ok(self.bounded_pool(primary, replica).await),
+            (None, None) => return Ok(replica.clone()),
+            (_, Some(header)) => header,
+        };
+        let token = header
+            .to_str()
+            .ok()
+            .filter(|t| is_lsn(t))
+            .ok_or_else(|| Message::new("invalid_consistency_token"))?;
+        
+        let deadline = Instant::now() + self.max_wait;
+        loop {
+            let caught_up: Result<Option<bool>, sqlx::Error> =
+                sqlx::query_scalar("SELECT pg_last_wal_replay_lsn() >= $1::pg_lsn")
+                    .bind(token)
+                    .fetch_one(replica)
+                    .await;
+            match caught_up {
+                Ok(Some(true)) => return Ok(replica.clone()),
+                Ok(_) if Instant::now() < deadline => tokio::time::sleep(REPLICA_POLL_INTERVAL).await,
+                Ok(_) => break,
+                Err(e) => {
+                    log::warn!("Replica check failed, reading from primary: {}", e);
+                    break;
+                }
+            }
+        }
+        Ok(primary.clone())
+    }
+    
+    // The replica while its replayed WAL is within max_lag_bytes of the
+    // primary's, the primary otherwise
+    pub(crate) async fn bounded_pool(&self, primary: &PgPool, replica: &PgPool) -> PgPool {
+        let lag = async {
+            let current: String = sqlx::query_scalar("SELECT pg_current_wal_lsn()::text").fetch_one(primary).await?;
+            sqlx::query_scalar::<_, Option<f64>>("SELECT pg_wal_lsn_diff($1::pg_lsn, pg_last_wal_replay_lsn())::float8")
+                .bind(current)
+                .fetch_one(replica)
+                .await
+        };
+        match lag.await {
+            Ok(Some(lag)) if lag <= self.max_lag_bytes as f64 => replica.clone(),
+            Ok(lag) => {
+                log::debug!("Replica lag {:?} bytes is unknown or over the bound, reading from primary", lag);
+                primary.clone()
+            }
+            Err(e) => {
+                log::warn!("Replica lag check failed, reading from primary: {}", e);
+                primary.clone()
+            }
+        }
+    }
+}
+
+pub(crate) const API_KEY_HEADER: &str = "x-api-key";
+
+// A database of DATAPI_DB_<NAME>, with its own pool and circuit breaker.
+// Startup work (aliases, history, retention, rollups) only runs against the
+// default database.
+pub(crate) struct NamedDatabase {
+    pub(crate) pool: web::Data<PgPool>,
+    pub(crate) replica: web::Data<Replica>,
+    pub(crate) breaker: web::Data<CircuitBreaker>,
+}
+
+pub(crate) struct Databases(pub(crate) BTreeMap<String, NamedDatabase>);
+
+// The database a /db/{name}/ request was routed to
+#[derive(Debug, Clone)]
+pub(crate) struct DatabaseName(pub(crate) String);
+
+// Serves /db/{name}/... as the path after the name, against the named
+// database: the URI is rewritten before routing, and the database's pool,
+// replica and breaker are swapped in like priority class pools.
+pub(crate) async fn database_route(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let databases = req.app_data::<web::Data<Databases>>().cloned();
+    let (Some(databases), Some(rest)) = (databases, req.path().strip_prefix("/db/")) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let (name, rest) = rest.split_once('/').unwrap_or((rest, ""));
+    let name = name.to_lowercase();
+    let Some(database) = databases.0.get(&name) else {
+        let resp = error_response(
+            req.request(),
+            StatusCode::NOT_FOUND,
+            Message::new("database_not_found").arg("database", &name),
+        );
+        return Ok(req.into_response(resp).map_into_right_body());
+    };
+    let path = match req.query_string() {
+        "" => format!("/{}", rest),
+        query => format!("/{}?{}", rest, query),
+    };
+    let Ok(uri) = path.parse::<actix_web::http::Uri>() else {
+        let resp = error_response(
+            req.request(),
+            StatusCode::NOT_FOUND,
+            Message::new("database_not_found").arg("database", &name),
+        );
+        return Ok(req.into_response(resp).map_into_right_body());
+    };
+    req.match_info_mut().get_mut().update(&uri);
+    req.head_mut().uri = uri;
+    
+    let mut data = actix_web::dev::Extensions::new();
+    data.insert(database.pool.clone());
+    data.insert(database.replica.clone());
+    data.insert(database.breaker.clone());
+    req.add_data_container(std::rc::Rc::new(data));
+    req.extensions_mut().insert(DatabaseName(name));
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+// The pools of one priority class, on the primary and the replica.
+pub(crate) struct PriorityClass {
+    pub(crate) name: String,
+    pub(crate) config: PriorityClassConfig,
+    pub(crate) pool: web::Data<PgPool>,
+    pub(crate) replica: web::Data<Replica>,
+}
+
+impl PriorityClass {
+    pub(crate) fn route_match(&self, path: &str) -> Option<usize> {
+        self.config
+            .routes
+            .iter()
+            .filter(|route| {
+                path.strip_prefix(route.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || route.is_empty())
+            })
+            .map(|route| route.len())
+            .max()
+    }
+}
+
+pub(crate) struct PriorityClasses(pub(crate) Vec<PriorityClass>);
+
+impl PriorityClasses {
+    // An API key selects its class; otherwise the class with the longest
+    // matching route does. Requests of no class use the default pools.
+    pub(crate) fn classify(&self, req: &ServiceRequest) -> Option<&PriorityClass> {
+        let api_key = req.headers().get(API_KEY_HEADER).and_then(|h| h.to_str().ok());
+        if let Some(key) = api_key {
+            if let Some(class) = self.0.iter().find(|c| c.config.api_keys.iter().any(|k| k == key)) {
+                return Some(class);
+            }
+        }
+        self.0
+            .iter()
+            .filter_map(|c| Some((c.route_match(req.path())?, c)))
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, c)| c)
+    }
+}
+
+// Swaps the class pools in for the request, so handlers extracting
+// web::Data<PgPool> and web::Data<Replica> get the class's pools.
+pub(crate) async fn priority_class(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    // Classes are pools of the default database
+    let named = req.extensions().contains::<DatabaseName>();
+    let classes = req.app_data::<web::Data<PriorityClasses>>().cloned().filter(|_| !named);
+    if let Some(class) = classes.as_ref().and_then(|c| c.classify(&req)) {
+        log::debug!("Priority class {} for {}", class.name, req.path());
+        let mut data = actix_web::dev::Extensions::new();
+        data.insert(class.pool.clone());
+        data.insert(class.replica.clone());
+        req.add_data_container(std::rc::Rc::new(data));
+    }
+    next.call(req).await
+}
+
+// A WAL location as printed by Postgres, e.g. `16/B374D848`.
+pub(crate) fn is_lsn(token: &str) -> bool {
+    token.split_once('/').is_some_and(|(high, low)| {
+        [high, low]
+            .iter()
+            .all(|part| (1..=8).contains(&part.len()) && part.chars().all(|c| c.is_ascii_hexdigit()))
+    })
+}
+
+pub(crate) async fn circuit_breaker_guard(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    // Health endpoints report the outage themselves.
+    if !is_health_path(req.path()) {
+        let retry_after = req
+            .app_data::<web::Data<CircuitBreaker>>()
+            .and_then(|b| b.retry_after());
+        if let Some(secs) = retry_after {
+            let mut resp = error_response(
+                req.request(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                Message::new("database_unavailable"),
+            );
+            resp.headers_mut().insert(
+                actix_web::http::header::RETRY_AFTER,
+                actix_web::http::header::HeaderValue::from(secs),
+            );
+            return Ok(req.into_response(resp).map_into_right_body());
+        }
+    }
+
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+// Announces the lifecycle of deprecated tables on every response served for
+// them, keyed by the table segment at the start of the path.
+// Rewrites aliased query parameters (e.g. `limit=10&offset=20`) to their
+// datapi names (`page_size=10&page=3`) before the handlers see them. A
+// parameter given under both names keeps the datapi one.
+pub(crate) async fn parameter_aliases(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let rewritten = match req.app_data::<web::Data<Config>>() {
+        Some(config) if !config.parameter_aliases.is_empty() => {
+            rewrite_query_aliases(req.query_string(), &config.parameter_aliases)
+        }
+        _ => Ok(None),
+    };
+    
+    match rewritten {
+        Ok(Some(query)) => {
+            let uri = format!("{}?{}", req.path(), query);
+            match uri.parse() {
+                Ok(uri) => req.head_mut().uri = uri,
+                Err(e) => log::warn!("Failed to rewrite query parameters: {}", e),
+            }
+        }
+        Ok(None) => {}
+        Err(message) => {
+            let resp = bad_request(req.request(), message);
+            return Ok(req.into_response(resp).map_into_right_body());
+        }
+    }
+    
+    next.call(req).await.map(ServiceResponse::map_into_left_body)
+}
+
+// Returns the rewritten query string, or None when no alias occurs.
+pub(crate) fn rewrite_query_aliases(
+    query: &str,
+    aliases: &HashMap<String, String>,
+) -> Result<Option<String>, Message> {
+    let pairs: Vec<(String, &str)> = query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let key = urlencoding::decode(key)
+                .map(|k| k.into_owned())
+                .unwrap_or_else(|_| key.to_string());
+            (key, value)
+        })
+        .collect();
+    if !pairs.iter().any(|(key, _)| aliases.contains_key(key)) {
+        return Ok(None);
+    }
+    
+    let mut canonical: Vec<(String, String)> = Vec::new();
+    for (key, value) in &pairs {
+        if !aliases.contains_key(key) {
+            canonical.push((key.clone(), value.to_string()));
+        }
+    }
+    for (key, value) in &pairs {
+        if let Some(target) = aliases.get(key) {
+            if !canonical.iter().any(|(k, _)| k == target) {
+                canonical.push((target.clone(), value.to_string()));
+            }
+        }
+    }
+    
+    if let Some(i) = canonical.iter().position(|(k, _)| k == "offset") {
+        let (_, offset) = canonical.remove(i);
+        let page_size = canonical
+            .iter()
+            .find(|(k, _)| k == "page_size")
+            .and_then(|(_, v)| v.parse::<usize>().ok())
+            .unwrap_or(100)
+            .clamp(1, 1000);
+        let offset: usize = offset.parse().map_err(|_| Message::new("invalid_offset").param("offset"))?;
+        if !offset.is_multiple_of(page_size) {
+            return Err(Message::new("invalid_offset").param("offset"));
+        }
+        canonical.retain(|(k, _)| k != "page");
+        canonical.push(("page".to_string(), (offset / page_size + 1).to_string()));
+    }
+    
+    let query: Vec<String> = canonical
+        .iter()
+        .map(|(k, v)| format!("{}={}", urlencoding::encode(k), v))
+        .collect();
+    Ok(Some(query.join("&")))
+}
+
+pub(crate) async fn deprecation_headers(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let headers = req.app_data::<web::Data<Config>>().and_then(|config| {
+        let table = req.path().trim_start_matches('/').split('/').next()?;
+        if table.starts_with('_') {
+            return None;
+        }
+        Some(config.table(table)?.deprecation.as_ref()?.headers())
+    });
+    
+    let mut res = next.call(req).await?;
+    for (name, value) in headers.unwrap_or_default() {
+        if let Ok(value) = actix_web::http::header::HeaderValue::from_str(&value) {
+            res.headers_mut().append(
+                actix_web::http::header::HeaderName::from_static(name),
+                value,
+            );
+        }
+    }
+    Ok(res)
+}
+
+// Compress encodes every response without a Content-Encoding. Bodies below
+// the size threshold, and Parquet and XLSX files which are compressed
+// already, are marked `identity` before it sees them; strip_identity_encoding
+// removes the marker again afterwards.
+pub(crate) async fn compression_threshold(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let min_bytes = match req.app_data::<web::Data<Config>>() {
+        Some(config) if config.compression => config.compression_min_bytes,
+        _ => return next.call(req).await,
+    };
+    
+    let mut res = next.call(req).await?;
+    let small = matches!(
+        res.response().body().size(),
+        actix_web::body::BodySize::Sized(n) if n < min_bytes
+    );
+    let precompressed = res
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .is_some_and(|t| t.as_bytes().starts_with(b"application/vnd.apache.parquet") || t == XLSX_CONTENT_TYPE);
+    let headers = res.headers_mut();
+    if (small || precompressed) && !headers.contains_key(actix_web::http::header::CONTENT_ENCODING) {
+        headers.insert(
+            actix_web::http::header::CONTENT_ENCODING,
+            actix_web::http::header::HeaderValue::from_static("identity"),
+        );
+    }
+    Ok(res)
+}
+
+pub(crate) async fn strip_identity_encoding(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let mut res = next.call(req).await?;
+    let headers = res.headers_mut();
+    if headers
+        .get(actix_web::http::header::CONTENT_ENCODING)
+        .is_some_and(|e| e == "identity")
+    {
+        headers.remove(actix_web::http::header::CONTENT_ENCODING);
+    }
+    Ok(res)
+}
+
+// Append-only journal of accepted writes at DATAPI_JOURNAL, one JSON line
+// per request before it runs and one with its status after, for
+// `datapi replay` into a restored database.
+pub(crate) struct Journal {
+    pub(crate) file: Mutex<std::fs::File>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum JournalRecord {
+    Request {
+        request_id: String,
+        at: chrono::DateTime<chrono::Utc>,
+        method: String,
+        // Path and query string, without the /db/{name} prefix
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        database: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content_type: Option<String>,
+        // UTF-8 bodies as they are, others as base64
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        body: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        body_base64: Option<String>,
+    },
+    Outcome {
+        request_id: String,
+        status: u16,
+    },
+}
+
+impl Journal {
+    pub(crate) fn from_env() -> Option<Self> {
+        let path = env::var("DATAPI_JOURNAL").ok().filter(|p| !p.is_empty())?;
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap_or_else(|e| panic!("Failed to open journal {}: {}", path, e));
+        Some(Journal { file: Mutex::new(file) })
+    }
+
+    // Writes and syncs the record, so it survives a crash of the write
+    pub(crate) fn append(&self, record: &JournalRecord) -> std::io::Result<()> {
+        use std::io::Write;
+        let mut line = serde_json::to_vec(record).map_err(std::io::Error::other)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+}
+
+// Table writes and batches; the admin API, share links and function calls
+// don't change table data
+pub(crate) fn is_journaled(req: &ServiceRequest) -> bool {
+    let method = req.method();
+    let read = method == actix_web::http::Method::GET
+        || method == actix_web::http::Method::HEAD
+        || method == actix_web::http::Method::OPTIONS;
+    !read && (!req.path().starts_with("/_") || req.path() == "/_batch")
+}
+
+// Journals accepted writes ahead of running them. A write that can't be
+// journaled is refused.
+pub(crate) async fn journal_writes(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let journal = req.app_data::<web::Data<Journal>>().cloned();
+    let Some(journal) = journal.filter(|_| is_journaled(&req)) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let body = req.extract::<web::Bytes>().await?;
+    req.set_payload(body.clone().into());
+    
+    let request_id = REQUEST_ID.try_with(|id| id.clone()).unwrap_or_default();
+    let (text, base64) = match std::str::from_utf8(&body) {
+        Ok(text) => (Some(text.to_string()), None),
+        Err(_) => (None, Some(BASE64.encode(&body))),
+    };
+    let record = JournalRecord::Request {
+        request_id: request_id.clone(),
+        at: chrono::Utc::now(),
+        method: req.method().to_string(),
+        path: req.uri().path_and_query().map(|p| p.to_string()).unwrap_or_else(|| req.path().to_string()),
+        database: req.extensions().get::<DatabaseName>().map(|name| name.0.clone()),
+        content_type: req
+            .headers()
+            .get(actix_web::http::header::CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok())
+            .map(str::to_string),
+        body: text.filter(|t| !t.is_empty()),
+        body_base64: base64,
+    };
+    let written = {
+        let journal = journal.clone();
+        web::block(move || journal.append(&record)).await
+    };
+    if let Err(e) = written.map_err(std::io::Error::other).and_then(|result| result) {
+        log::error!("Journaling write {} failed: {}", request_id, e);
+        let res = error_response(req.request(), StatusCode::SERVICE_UNAVAILABLE, Message::new("journal_unavailable"));
+        return Ok(req.into_response(res).map_into_right_body());
+    }
+    
+    let res = next.call(req).await?;
+    let outcome = JournalRecord::Outcome { request_id: request_id.clone(), status: res.status().as_u16() };
+    if let Err(e) = web::block(move || journal.append(&outcome)).await.map_err(std::io::Error::other).and_then(|r| r) {
+        log::error!("Journaling the outcome of write {} failed: {}", request_id, e);
+    }
+    Ok(res.map_into_left_body())
+}
+
+// HTTPS with the PEM certificate chain and key at DATAPI_TLS_CERT and
+// DATAPI_TLS_KEY. With DATAPI_TLS_REDIRECT_PORT, plain HTTP on that port
+// redirects to the HTTPS port.
+pub(crate) struct TlsConfig {
+    pub(crate) cert: String,
+    pub(crate) key: String,
+    pub(crate) redirect_port: Option<u16>,
+}
+
+impl TlsConfig {
+    pub(crate) fn from_env() -> Option<Self> {
+        let cert = env::var("DATAPI_TLS_CERT").ok().filter(|c| !c.is_empty());
+        let key = env::var("DATAPI_TLS_KEY").ok().filter(|k| !k.is_empty());
+        let (cert, key) = match (cert, key) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, None) => return None,
+            _ => panic!("DATAPI_TLS_CERT and DATAPI_TLS_KEY must be set together"),
+        };
+        let redirect_port = env::var("DATAPI_TLS_REDIRECT_PORT").ok().map(|port| {
+            port.parse().unwrap_or_else(|_| panic!("Invalid DATAPI_TLS_REDIRECT_PORT {}", port))
+        });
+        Some(TlsConfig { cert, key, redirect_port })
+    }
+
+    pub(crate) fn server_config(&self) -> rustls::ServerConfig {
+        let read = |path: &str| {
+            std::fs::read(path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e))
+        };
+        let certs = rustls_pemfile::certs(&mut read(&self.cert).as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_or_else(|e| panic!("Invalid certificate {}: {}", self.cert, e));
+        if certs.is_empty() {
+            panic!("No certificate found in {}", self.cert);
+        }
+        let key = rustls_pemfile::private_key(&mut read(&self.key).as_slice())
+            .unwrap_or_else(|e| panic!("Invalid private key {}: {}", self.key, e))
+            .unwrap_or_else(|| panic!("No private key found in {}", self.key));
+        rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap_or_else(|e| panic!("Unusable certificate and key {}, {}: {}", self.cert, self.key, e))
+    }
+}
+
+// A Unix domain socket at DATAPI_SOCKET, served as plain HTTP besides the
+// TCP port, or instead of it with DATAPI_SOCKET_ONLY, for a reverse proxy on
+// the same host. Its file gets DATAPI_SOCKET_MODE (octal, default 660).
+pub(crate) struct SocketConfig {
+    pub(crate) path: String,
+    pub(crate) mode: u32,
+    pub(crate) only: bool,
+}
+
+impl SocketConfig {
+    pub(crate) fn from_env() -> Option<Self> {
+        let path = env::var("DATAPI_SOCKET").ok().filter(|p| !p.is_empty())?;
+        if cfg!(not(unix)) {
+            panic!("DATAPI_SOCKET needs Unix domain sockets, which this platform lacks");
+        }
+        let mode = match env::var("DATAPI_SOCKET_MODE") {
+            Ok(mode) => u32::from_str_radix(&mode, 8)
+                .ok()
+                .filter(|m| *m <= 0o777)
+                .unwrap_or_else(|| panic!("Invalid DATAPI_SOCKET_MODE {}, expected octal like 660", mode)),
+            Err(_) => 0o660,
+        };
+        Some(SocketConfig { path, mode, only: env_parse("DATAPI_SOCKET_ONLY", false) })
+    }
+    
+    // Removes the socket file a server that didn't shut down cleanly left
+    // behind. A socket something still listens on, and other files, are kept
+    // and fail the bind.
+    #[cfg(unix)]
+    pub(crate) fn remove_stale(&self) {
+        use std::os::unix::fs::FileTypeExt;
+        let socket = std::fs::symlink_metadata(&self.path).is_ok_and(|m| m.file_type().is_socket());
+        if socket && std::os::unix::net::UnixStream::connect(&self.path).is_err() {
+            log::info!("Removing stale socket {}", self.path);
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+    
+    // Binds the socket inside a private directory next to the path, so nobody
+    // can connect before it has its mode, then links it into place. Linking
+    // fails instead of replacing a file that is already there.
+    #[cfg(unix)]
+    pub(crate) fn listen(&self) -> std::io::Result<std::os::unix::net::UnixListener> {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+        let path = std::path::Path::new(&self.path);
+        let name = path.file_name().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("DATAPI_SOCKET {} has no file name", self.path))
+        })?;
+        let dir = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+        let staged = dir.join(name);
+        let listener = std::os::unix::net::UnixListener::bind(&staged)
+            .and_then(|listener| {
+                std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(self.mode))?;
+                std::fs::hard_link(&staged, path)?;
+                Ok(listener)
+            });
+        let _ = std::fs::remove_file(&staged);
+        let _ = std::fs::remove_dir(&dir);
+        listener
+    }
+    
+    pub(crate) fn remove(&self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("Removing socket {} failed: {}", self.path, e);
+        }
+    }
+}
+
+// The port HTTPS is served on, for redirecting plain HTTP requests
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HttpsPort(pub(crate) u16);
+
+// Sends plain HTTP requests to the same path over HTTPS. Health checks are
+// answered on either, for load balancers probing the plain port.
+pub(crate) async fn https_redirect(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let port = req.app_data::<web::Data<HttpsPort>>().map(|port| port.0);
+    // Requests over DATAPI_SOCKET, which have no peer address, come from a
+    // proxy on the same host
+    let plain = !req.app_config().secure() && req.peer_addr().is_some();
+    let Some(port) = port.filter(|_| plain && !is_health_path(req.path())) else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let host = req.connection_info().host().to_string();
+    // Drops the port of the host, keeping IPv6 brackets
+    let host = match host.rsplit_once(':') {
+        Some((name, p)) if !p.contains(']') && p.chars().all(|c| c.is_ascii_digit()) => name.to_string(),
+        _ => host,
+    };
+    let authority = if port == 443 { host } else { format!("{}:{}", host, port) };
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let location = format!("https://{}{}", authority, path);
+    let res = HttpResponse::PermanentRedirect()
+        .insert_header((actix_web::http::header::LOCATION, location))
+        .finish();
+    Ok(req.into_response(res).map_into_right_body())
+}
+
+// Paths kept by `?fields=`, merged into a tree. `*` matches every key or
+// array element.
+#[derive(Debug, Default)]
+pub(crate) struct FieldTree {
+    // The whole value at this point is kept
+    pub(crate) keep: bool,
+    pub(crate) children: BTreeMap<String, FieldTree>,
+}
+
+impl FieldTree {
+    // Comma-separated JSON Pointers (`/data/*/id`) or dotted paths
+    // (`data.*.id`)
+    pub(crate) fn parse(spec: &str) -> Option<Self> {
+        let mut tree = FieldTree::default();
+        for path in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let segments: Vec<String> = match path.strip_prefix('/') {
+                Some(pointer) => pointer.split('/').map(|s| s.replace("~1", "/").replace("~0", "~")).collect(),
+                None => path.split('.').map(str::to_string).collect(),
+            };
+            if segments.iter().any(String::is_empty) {
+                return None;
+            }
+            let mut node = &mut tree;
+            for segment in segments {
+                node = node.children.entry(segment).or_default();
+            }
+            node.keep = true;
+        }
+        (!tree.children.is_empty()).then_some(tree)
+    }
+    
+    pub(crate) fn child(&self, key: &str) -> Option<&FieldTree> {
+        self.children.get(key).or_else(|| self.children.get("*"))
+    }
+    
+    // The parts of the value on the tree's paths; None when none exist
+    pub(crate) fn project(&self, value: serde_json::Value) -> Option<serde_json::Value> {
+        if self.keep {
+            return Some(value);
+        }
+        match value {
+            serde_json::Value::Object(obj) => Some(serde_json::Value::Object(
+                obj.into_iter()
+                    .filter_map(|(k, v)| Some((k.clone(), self.child(&k)?.project(v)?)))
+                    .collect(),
+            )),
+            serde_json::Value::Array(items) => Some(serde_json::Value::Array(
+                items
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(i, v)| self.child(&i.to_string())?.project(v))
+                    .collect(),
+            )),
+            _ => None,
+        }
+    }
+}
+
+// Applies `?fields=` to JSON responses after serialization, so it works
+// the same for every endpoint and nesting level.
+pub(crate) async fn fields_projection(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let spec = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|q| q.get("fields").cloned());
+    let Some(spec) = spec else {
+        return Ok(next.call(req).await?.map_into_boxed_body().map_into_left_body());
+    };
+    let Some(tree) = FieldTree::parse(&spec) else {
+        let resp = bad_request(req.request(), Message::new("invalid_fields").arg("fields", &spec).param("fields"));
+        return Ok(req.into_response(resp).map_into_right_body());
+    };
+    
+    let res = next.call(req).await?;
+    let is_json = res
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .is_some_and(|t| t.as_bytes().starts_with(b"application/json"));
+    // Streamed arrays pass through rather than being buffered whole
+    let sized = matches!(res.response().body().size(), actix_web::body::BodySize::Sized(_));
+    if !is_json || !sized || !res.status().is_success() {
+        return Ok(res.map_into_boxed_body().map_into_left_body());
+    }
+    
+    let (req, res) = res.into_parts();
+    let (head, body) = res.into_parts();
+    let bytes = actix_web::body::to_bytes(body).await.map_err(|e| {
+        actix_web::error::ErrorInternalServerError(e.into().to_string())
+    })?;
+    let projected = serde_json::from_slice::<serde_json::Value>(&bytes)
+        .ok()
+        .map(|value| tree.project(value).unwrap_or(serde_json::Value::Object(Default::default())));
+    let body = match projected {
+        Some(value) => serde_json::to_vec(&value).unwrap_or_default().into(),
+        None => bytes,
+    };
+    let mut res = head.set_body(body);
+    res.headers_mut().remove(actix_web::http::header::CONTENT_LENGTH);
+    Ok(ServiceResponse::new(req, res.map_into_boxed_body()).map_into_left_body())
+}
+
+// Reshapes successful paged JSON responses per the envelope configuration
+// and `?envelope=`. It runs outside `?fields=`, whose paths address the
+// default envelope.
+pub(crate) async fn response_envelope(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let requested = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|q| q.get("envelope").cloned());
+    let config = req.app_data::<web::Data<Config>>().cloned();
+    let style = match (requested, &config) {
+        (Some(value), _) => match EnvelopeStyle::parse(&value) {
+            Some(style) => style,
+            None => {
+                let resp = bad_request(req.request(), Message::new("invalid_envelope").arg("value", &value).param("envelope"));
+                return Ok(req.into_response(resp).map_into_right_body());
+            }
+        },
+        (None, Some(config)) => config.envelope.style,
+        (None, None) => EnvelopeStyle::Default,
+    };
+    let Some(config) = config.filter(|c| style != EnvelopeStyle::Default || !c.envelope.keys.is_empty()) else {
+        return Ok(next.call(req).await?.map_into_boxed_body().map_into_left_body());
+    };
+    
+    let res = next.call(req).await?;
+    let is_json = res
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .is_some_and(|t| t.as_bytes().starts_with(b"application/json"));
+    // A streamed array has no envelope to reshape
+    let sized = matches!(res.response().body().size(), actix_web::body::BodySize::Sized(_));
+    if !is_json || !sized || !res.status().is_success() {
+        return Ok(res.map_into_boxed_body().map_into_left_body());
+    }
+    
+    let (req, res) = res.into_parts();
+    let (head, body) = res.into_parts();
+    let bytes = actix_web::body::to_bytes(body).await.map_err(|e| {
+        actix_web::error::ErrorInternalServerError(e.into().to_string())
+    })?;
+    let Ok(value) = serde_json::from_slice::<serde_json::Value>(&bytes) else {
+        return Ok(ServiceResponse::new(req, head.set_body(bytes).map_into_boxed_body()).map_into_left_body());
+    };
+    let (value, headers) = config.envelope.apply(style, value);
+    let mut res = head.set_body(web::Bytes::from(serde_json::to_vec(&value).unwrap_or_default()));
+    res.headers_mut().remove(actix_web::http::header::CONTENT_LENGTH);
+    for (name, value) in headers {
+        if let Ok(value) = actix_web::http::header::HeaderValue::from_str(&value) {
+            res.headers_mut().insert(actix_web::http::header::HeaderName::from_static(name), value);
+        }
+    }
+    Ok(ServiceResponse::new(req, res.map_into_boxed_body()).map_into_left_body())
+}
+
+// A cached GET response. The body is kept base64-encoded so entries
+// serialize to JSON for Redis as they are.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CachedResponse {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: String,
+    pub(crate) stored_at: u64,
+}
+
+pub(crate) enum CacheBackend {
+    // Cache key -> (table, expiry, response)
+    Memory {
+        max_entries: usize,
+        entries: Mutex<HashMap<String, (String, u64, CachedResponse)>>,
+    },
+    // Shared by every instance. Keys carry a global and a per-table
+    // generation, and writes invalidate by bumping them.
+    Redis(redis::aio::ConnectionManager),
+}
+
+pub(crate) const REDIS_CACHE_PREFIX: &str = "datapi:cache";
+
+// Responses of table reads, kept for the table's TTL and dropped when the
+// API writes to the table.
+pub(crate) struct ResponseCache {
+    pub(crate) backend: CacheBackend,
+    // Largest body stored; streamed responses are never stored
+    pub(crate) max_body_bytes: u64,
+}
+
+impl ResponseCache {
+    pub(crate) async fn from_config(config: &Config) -> Self {
+        let backend = match &config.cache_redis_url {
+            Some(url) => {
+                let client = redis::Client::open(url.as_str())
+                    .unwrap_or_else(|e| panic!("Invalid DATAPI_CACHE_REDIS_URL: {}", e));
+                let connection = redis::aio::ConnectionManager::new(client)
+                    .await
+                    .unwrap_or_else(|e| panic!("Failed to connect to the cache at {}: {}", url, e));
+                CacheBackend::Redis(connection)
+            }
+            None => CacheBackend::Memory {
+                max_entries: config.cache_max_entries,
+                entries: Mutex::new(HashMap::new()),
+            },
+        };
+        ResponseCache { backend, max_body_bytes: config.cache_max_body_bytes }
+    }
+
+    pub(crate) async fn redis_key(connection: &redis::aio::ConnectionManager, table: &str, key: &str) -> redis::RedisResult<String> {
+        let generations: (Option<u64>, Option<u64>) = redis::cmd("MGET")
+            .arg(format!("{}:generation", REDIS_CACHE_PREFIX))
+            .arg(format!("{}:generation:{}", REDIS_CACHE_PREFIX, table))
+            .query_async(&mut connection.clone())
+            .await?;
+        Ok(format!(
+            "{}:{}:{}:{}",
+            REDIS_CACHE_PREFIX,
+            generations.0.unwrap_or(0),
+            generations.1.unwrap_or(0),
+            key
+        ))
+    }
+
+    // Cache failures only cost the lookup, never the request
+    pub(crate) async fn get(&self, table: &str, key: &str) -> Option<CachedResponse> {
+        match &self.backend {
+            CacheBackend::Memory { entries, .. } => {
+                let entries = entries.lock().unwrap();
+                let (_, expires, response) = entries.get(key)?;
+                (*expires > unix_now()).then(|| response.clone())
+            }
+            CacheBackend::Redis(connection) => {
+                let result = async {
+                    let key = Self::redis_key(connection, table, key).await?;
+                    let value: Option<String> = redis::cmd("GET").arg(key).query_async(&mut connection.clone()).await?;
+                    Ok::<_, redis::RedisError>(value)
+                }
+                .await;
+                match result {
+                    Ok(value) => value.and_then(|v| serde_json::from_str(&v).ok()),
+                    Err(e) => {
+                        log::warn!("Cache lookup failed: {}", e);
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) async fn put(&self, table: &str, key: String, response: CachedResponse, ttl: u64) {
+        match &self.backend {
+            CacheBackend::Memory { max_entries, entries } => {
+                let mut entries = entries.lock().unwrap();
+                let now = unix_now();
+                if entries.len() >= *max_entries {
+                    entries.retain(|_, (_, expires, _)| *expires > now);
+                }
+                if entries.len() >= *max_entries {
+                    let oldest = entries.iter().min_by_key(|(_, (_, expires, _))| *expires).map(|(k, _)| k.clone());
+                    if let Some(oldest) = oldest {
+                        entries.remove(&oldest);
+                    }
+                }
+                entries.insert(key, (table.to_string(), now + ttl, response));
+            }
+            CacheBackend::Redis(connection) => {
+                let result = async {
+                    let key = Self::redis_key(connection, table, &key).await?;
+                    let value = serde_json::to_string(&response).unwrap_or_default();
+                    redis::cmd("SET")
+                        .arg(key)
+                        .arg(value)
+                        .arg("EX")
+                        .arg(ttl)
+                        .query_async::<()>(&mut connection.clone())
+                        .await
+                }
+                .await;
+                if let Err(e) = result {
+                    log::warn!("Cache store failed: {}", e);
+                }
+            }
+        }
+    }
+
+    // Drops the table's entries, or every entry for writes not tied to one
+    // table (batches)
+    pub(crate) async fn invalidate(&self, table: Option<&str>) {
+        match &self.backend {
+            CacheBackend::Memory { entries, .. } => {
+                let mut entries = entries.lock().unwrap();
+                match table {
+                    Some(table) => entries.retain(|_, (t, _, _)| t != table),
+                    None => entries.clear(),
+                }
+            }
+            CacheBackend::Redis(connection) => {
+                let generation = match table {
+                    Some(table) => format!("{}:generation:{}", REDIS_CACHE_PREFIX, table),
+                    None => format!("{}:generation", REDIS_CACHE_PREFIX),
+                };
+                let result = redis::cmd("INCR").arg(generation).query_async::<u64>(&mut connection.clone()).await;
+                if let Err(e) = result {
+                    log::warn!("Cache invalidation failed: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// What a request's Cache-Control asks of the cache
+#[derive(Debug, Default)]
+pub(crate) struct CacheHints {
+    // Don't answer from the cache, but store the fresh response
+    pub(crate) no_cache: bool,
+    // Neither answer from nor store into the cache
+    pub(crate) no_store: bool,
+    // Oldest acceptable entry; also opts tables without a TTL into caching
+    pub(crate) max_age: Option<u64>,
+}
+
+impl CacheHints {
+    pub(crate) fn of(req: &ServiceRequest) -> Self {
+        let mut hints = CacheHints::default();
+        let directives = req
+            .headers()
+            .get_all(actix_web::http::header::CACHE_CONTROL)
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .map(|d| d.trim().to_ascii_lowercase());
+        for directive in directives {
+            match directive.split_once('=') {
+                Some(("max-age", secs)) => hints.max_age = secs.trim_matches('"').parse().ok(),
+                _ if directive == "no-cache" => hints.no_cache = true,
+                _ if directive == "no-store" => hints.no_store = true,
+                _ => {}
+            }
+        }
+        hints
+    }
+}
+
+pub(crate) const CACHE_STATUS_HEADER: &str = "x-cache";
+
+// Answers repeated table reads from the ResponseCache with `X-Cache: HIT`,
+// and drops a table's entries when a write to it succeeds. The key covers
+// the path, query, the negotiated format and language, and the caller's
+// claim filter values, so callers restricted to different rows never share
+// entries. Requests with a consistency token skip the cache.
+pub(crate) async fn response_cache(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let (Some(cache), Some(config)) = (
+        req.app_data::<web::Data<ResponseCache>>().cloned(),
+        req.app_data::<web::Data<Config>>().cloned(),
+    ) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    // Health endpoints must reflect the database as it is now
+    let table = path_table(req.path())
+        .filter(|_| !is_health_path(req.path()))
+        .map(|t| t.to_lowercase());
+    if req.method() != actix_web::http::Method::GET {
+        let read = req.method() == actix_web::http::Method::HEAD
+            || req.method() == actix_web::http::Method::OPTIONS
+            || req.path() == GRAPHQL_PATH
+            || req.path() == MULTI_PATH
+            || req.path() == SQL_PATH;
+        let res = next.call(req).await?;
+        if !read && res.status().is_success() {
+            cache.invalidate(table.as_deref()).await;
+        }
+        return Ok(res.map_into_boxed_body());
+    }
+    
+    let hints = CacheHints::of(&req);
+    let limits = req.app_data::<web::Data<TableLimits>>().cloned();
+    let table_ttl = |t: &str| match &limits {
+        Some(limits) => limits.get(&config, t).cache_ttl_secs,
+        None => config.table(t).and_then(|t| t.cache_ttl_secs),
+    };
+    let ttl = match table.as_deref().map(table_ttl) {
+        None | Some(Some(0)) => 0,
+        Some(Some(ttl)) => ttl,
+        Some(None) if config.cache_ttl_secs > 0 => config.cache_ttl_secs,
+        Some(None) => hints.max_age.unwrap_or(0).min(config.cache_max_age_secs),
+    };
+    let claims = table.as_deref().map(|t| claim_values(req.request(), &config, t));
+    let (Some(table), Some(Ok(claims))) = (table, claims) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    if ttl == 0 || hints.no_store || req.headers().contains_key(CONSISTENCY_TOKEN_HEADER) {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+    let header = |name| req.headers().get(name).and_then(|h| h.to_str().ok()).unwrap_or_default();
+    let database = req.extensions().get::<DatabaseName>().map(|d| d.0.clone());
+    let key = format!(
+        "{}:{}?{}|{}|{}|{:?}",
+        database.as_deref().unwrap_or(DEFAULT_DATABASE),
+        req.path(),
+        req.query_string(),
+        header(actix_web::http::header::ACCEPT),
+        header(actix_web::http::header::ACCEPT_LANGUAGE),
+        claims
+    );
+    
+    if !hints.no_cache {
+        let cached = cache.get(&table, &key).await;
+        let age = |entry: &CachedResponse| unix_now().saturating_sub(entry.stored_at);
+        if let Some(entry) = cached.filter(|entry| hints.max_age.is_none_or(|max_age| age(entry) <= max_age)) {
+            let status = StatusCode::from_u16(entry.status).unwrap_or(StatusCode::OK);
+            let mut resp = HttpResponse::build(status);
+            for (name, value) in &entry.headers {
+                resp.append_header((name.as_str(), value.as_str()));
+            }
+            resp.insert_header((CACHE_STATUS_HEADER, "HIT"));
+            resp.insert_header((actix_web::http::header::AGE, age(&entry)));
+            let resp = resp.body(BASE64.decode(&entry.body).unwrap_or_default());
+            return Ok(req.into_response(resp));
+        }
+    }
+    
+    let mut res = next.call(req).await?;
+    let sized = matches!(
+        res.response().body().size(),
+        actix_web::body::BodySize::Sized(n) if n <= cache.max_body_bytes
+    );
+    res.headers_mut().insert(
+        actix_web::http::header::HeaderName::from_static(CACHE_STATUS_HEADER),
+        actix_web::http::header::HeaderValue::from_static("MISS"),
+    );
+    let no_store = res
+        .headers()
+        .get(actix_web::http::header::CACHE_CONTROL)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.contains("no-store"));
+    if res.status() != StatusCode::OK || !sized || no_store {
+        return Ok(res.map_into_boxed_body());
+    }
+    
+    let (req, res) = res.into_parts();
+    let (head, body) = res.into_parts();
+    let bytes = actix_web::body::to_bytes(body).await.map_err(|e| {
+        actix_web::error::ErrorInternalServerError(e.into().to_string())
+    })?;
+    let headers = head
+        .headers()
+        .iter()
+        .filter(|(name, _)| {
+            *name != actix_web::http::header::CONTENT_LENGTH
+                && *name != actix_web::http::header::DATE
+                && name.as_str() != CACHE_STATUS_HEADER
+        })
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let entry = CachedResponse {
+        status: head.status().as_u16(),
+        headers,
+        body: BASE64.encode(&bytes),
+        stored_at: unix_now(),
+    };
+    cache.put(&table, key, entry, ttl).await;
+    let res = head.set_body(bytes);
+    Ok(ServiceResponse::new(req, res.map_into_boxed_body()))
+}
+
+// Tags successful GET responses with a weak ETag over the body, after
+// projection and envelope, and answers a matching If-None-Match with 304 so
+// polling clients don't download an unchanged page again. Streamed bodies
+// (NDJSON exports, change feeds) have no size up front and aren't tagged.
+pub(crate) async fn conditional_get(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let enabled = req.app_data::<web::Data<Config>>().is_some_and(|config| config.etags);
+    if !enabled || req.method() != actix_web::http::Method::GET {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+    let if_none_match = req
+        .headers()
+        .get(actix_web::http::header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_string);
+    let res = next.call(req).await?;
+    let sized = matches!(res.response().body().size(), actix_web::body::BodySize::Sized(_));
+    if res.status() != StatusCode::OK || !sized {
+        return Ok(res.map_into_boxed_body());
+    }
+    
+    let (req, res) = res.into_parts();
+    let (mut head, body) = res.into_parts();
+    let bytes = actix_web::body::to_bytes(body).await.map_err(|e| {
+        actix_web::error::ErrorInternalServerError(e.into().to_string())
+    })?;
+    let digest = <sha2::Sha256 as sha2::Digest>::digest(&bytes);
+    let tag: String = digest[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    let etag = format!("W/\"{}\"", tag);
+    // Weak comparison: only the opaque part has to match
+    let matched = if_none_match.is_some_and(|header| {
+        header.split(',').map(str::trim).any(|candidate| {
+            candidate == "*" || candidate.trim_start_matches("W/").trim_matches('"') == tag
+        })
+    });
+    head.headers_mut().insert(
+        actix_web::http::header::ETAG,
+        actix_web::http::header::HeaderValue::from_str(&etag).expect("hex is a valid header value"),
+    );
+    if matched {
+        let mut not_modified = HttpResponse::NotModified();
+        for (name, value) in head.headers() {
+            if *name != actix_web::http::header::CONTENT_LENGTH && *name != actix_web::http::header::CONTENT_TYPE {
+                not_modified.append_header((name.clone(), value.clone()));
+            }
+        }
+        return Ok(ServiceResponse::new(req, not_modified.finish().map_into_boxed_body()));
+    }
+    let res = head.set_body(bytes);
+    Ok(ServiceResponse::new(req, res.map_into_boxed_body()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use actix_web::test::TestRequest;
+    use actix_web::App;
+    use actix_web::middleware::{from_fn, Compress};
+    use crate::cli::{parse_command, run_replay, Command};
+    use crate::config::Deprecation;
+    use crate::filters::{parse_filter, parse_multiple_filters};
+    use crate::writes::postgres_message;
+
+    #[test]
+    fn breaker_opens_after_consecutive_connection_errors() {
+        let breaker = CircuitBreaker::new(&test_config());
+        breaker.record_error(&sqlx::Error::PoolTimedOut);
+        breaker.record_success();
+        breaker.record_error(&sqlx::Error::PoolTimedOut);
+        breaker.record_error(&sqlx::Error::RowNotFound);
+        assert!(!breaker.is_open());
+        breaker.record_error(&sqlx::Error::PoolClosed);
+        assert!(breaker.is_open());
+        assert!(breaker.retry_after().is_some_and(|secs| (1..=10).contains(&secs)));
+        breaker.close();
+        assert!(!breaker.is_open() && breaker.retry_after().is_none());
+    }
+
+    #[actix_web::test]
+    async fn open_breaker_fails_requests_fast() {
+        let breaker = web::Data::new(CircuitBreaker::new(&test_config()));
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(breaker.clone())
+                .wrap(from_fn(circuit_breaker_guard))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let get = |path: &str| TestRequest::get().uri(path).to_request();
+        assert_eq!(actix_web::test::call_service(&app, get("/loans")).await.status(), StatusCode::OK);
+        breaker.record_error(&sqlx::Error::PoolTimedOut);
+        breaker.record_error(&sqlx::Error::PoolTimedOut);
+        let resp = actix_web::test::call_service(&app, get("/loans")).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(resp.headers().contains_key("Retry-After"));
+        assert_eq!(actix_web::test::call_service(&app, get("/health")).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn deprecated_tables_announce_their_sunset() {
+        let config = config_with(serde_json::json!({
+            "old_loans": { "deprecation": { "since": "2024-01-01", "sunset": "2024-07-01", "replacement": "loans" } }
+        }));
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .wrap(from_fn(deprecation_headers))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let resp = actix_web::test::call_service(&app, TestRequest::get().uri("/old_loans/id=1").to_request()).await;
+        assert_eq!(resp.headers().get("deprecation").unwrap(), "@1704067200");
+        assert_eq!(resp.headers().get("sunset").unwrap(), "Mon, 01 Jul 2024 00:00:00 GMT");
+        assert_eq!(resp.headers().get("link").unwrap(), "</loans>; rel=\"successor-version\"");
+        let resp = actix_web::test::call_service(&app, TestRequest::get().uri("/loans").to_request()).await;
+        assert!(resp.headers().get("deprecation").is_none());
+        assert_eq!(Deprecation::default().headers(), vec![("deprecation", "true".to_string())]);
+    }
+
+    #[test]
+    fn consistency_tokens_are_wal_locations() {
+        assert!(is_lsn("0/16B3748"));
+        assert!(is_lsn("16/B374D848"));
+        assert!(!is_lsn("16B3748"));
+        assert!(!is_lsn("0/"));
+        assert!(!is_lsn("0/16B3748'; --"));
+        assert!(!is_lsn("123456789/0"));
+    }
+
+    #[actix_web::test]
+    async fn reads_stay_on_the_primary_without_a_replica() {
+        let replica = Replica { pool: None, max_wait: Duration::from_millis(10), max_lag_bytes: 0 };
+        let req = TestRequest::default()
+            .insert_header((CONSISTENCY_TOKEN_HEADER, "garbage"))
+            .to_http_request();
+        assert!(replica.read_pool(&req, &offline_pool()).await.is_ok());
+
+        let replica = Replica { pool: Some(offline_pool()), max_wait: Duration::from_millis(10), max_lag_bytes: 0 };
+        let err = replica.read_pool(&req, &offline_pool()).await.unwrap_err();
+        assert_eq!(err.key, "invalid_consistency_token");
+    }
+
+    #[test]
+    fn aliased_parameters_are_rewritten() {
+        let aliases = HashMap::from([
+            ("limit".to_string(), "page_size".to_string()),
+            ("offset".to_string(), "offset".to_string()),
+            ("orderBy".to_string(), "sort".to_string()),
+        ]);
+        assert_eq!(rewrite_query_aliases("page=2&sort=id", &aliases).unwrap(), None);
+        assert_eq!(
+            rewrite_query_aliases("limit=10&offset=20&orderBy=name", &aliases).unwrap().as_deref(),
+            Some("page_size=10&sort=name&page=3")
+        );
+        // The datapi name wins over its alias
+        assert_eq!(
+            rewrite_query_aliases("sort=id&orderBy=name", &aliases).unwrap().as_deref(),
+            Some("sort=id")
+        );
+        assert_eq!(rewrite_query_aliases("limit=10&offset=15", &aliases).unwrap_err().key, "invalid_offset");
+        assert_eq!(rewrite_query_aliases("offset=x", &aliases).unwrap_err().key, "invalid_offset");
+    }
+
+    #[actix_web::test]
+    async fn only_large_responses_are_compressed() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_config()))
+                .wrap(from_fn(compression_threshold))
+                .wrap(Compress::default())
+                .wrap(from_fn(strip_identity_encoding))
+                .route("/small", web::get().to(|| async { "x".repeat(100) }))
+                .route("/large", web::get().to(|| async { "x".repeat(4096) }))
+                .route(
+                    "/parquet",
+                    web::get().to(|| async {
+                        HttpResponse::Ok().content_type("application/vnd.apache.parquet").body(vec![0u8; 4096])
+                    }),
+                ),
+        )
+        .await;
+        let encoding = |path: &str| {
+            let req = TestRequest::get().uri(path).insert_header(("Accept-Encoding", "gzip")).to_request();
+            let app = &app;
+            async move {
+                let resp = actix_web::test::call_service(app, req).await;
+                resp.headers().get("content-encoding").map(|e| e.to_str().unwrap().to_string())
+            }
+        };
+        assert_eq!(encoding("/small").await, None);
+        assert_eq!(encoding("/large").await.as_deref(), Some("gzip"));
+        assert_eq!(encoding("/parquet").await, None);
+    }
+
+    fn priority_class_named(name: &str, api_keys: &[&str], routes: &[&str]) -> PriorityClass {
+        PriorityClass {
+            name: name.to_string(),
+            config: PriorityClassConfig {
+                max_connections: 1,
+                api_keys: api_keys.iter().map(|k| k.to_string()).collect(),
+                routes: routes.iter().map(|r| r.to_string()).collect(),
+            },
+            pool: web::Data::new(offline_pool()),
+            replica: web::Data::new(Replica { pool: None, max_wait: Duration::ZERO, max_lag_bytes: 0 }),
+        }
+    }
+
+    #[actix_web::test]
+    async fn requests_join_a_class_by_api_key_or_longest_route() {
+        let classes = PriorityClasses(vec![
+            priority_class_named("batch", &["exporter"], &["/_results"]),
+            priority_class_named("reports", &[], &["/loans", "/loans/aggregate"]),
+        ]);
+        let class = |req: TestRequest| classes.classify(&req.to_srv_request()).map(|c| c.name.as_str());
+        assert_eq!(class(TestRequest::get().uri("/loans/id=1")), Some("reports"));
+        assert_eq!(class(TestRequest::get().uri("/loans/aggregate")), Some("reports"));
+        assert_eq!(class(TestRequest::get().uri("/loansx")), None);
+        assert_eq!(class(TestRequest::get().uri("/_results/abc")), Some("batch"));
+        assert_eq!(class(TestRequest::get().uri("/loans").insert_header(("X-API-Key", "exporter"))), Some("batch"));
+        assert_eq!(class(TestRequest::get().uri("/customers").insert_header(("X-API-Key", "other"))), None);
+    }
+
+    #[test]
+    fn field_trees_keep_only_their_paths() {
+        let page = serde_json::json!({
+            "data": [{ "id": 1, "name": "Bob", "a/b": 1 }, { "id": 2, "name": "Ann" }],
+            "count": 2,
+        });
+        let project = |spec: &str| FieldTree::parse(spec).unwrap().project(page.clone()).unwrap();
+        assert_eq!(
+            project("/data/*/id,count"),
+            serde_json::json!({ "data": [{ "id": 1 }, { "id": 2 }], "count": 2 })
+        );
+        assert_eq!(project("data.1.name"), serde_json::json!({ "data": [{ "name": "Ann" }] }));
+        assert_eq!(project("/data/0/a~1b"), serde_json::json!({ "data": [{ "a/b": 1 }] }));
+        assert_eq!(project("missing"), serde_json::json!({}));
+        for invalid in ["", "data..id", "/data//id"] {
+            assert!(FieldTree::parse(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[actix_web::test]
+    async fn fields_project_successful_json_responses() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(from_fn(fields_projection))
+                .route("/ok", web::get().to(|| async { HttpResponse::Ok().json(serde_json::json!({ "a": 1, "b": 2 })) }))
+                .route(
+                    "/fail",
+                    web::get().to(|| async { HttpResponse::BadRequest().json(serde_json::json!({ "error": "x" })) }),
+                ),
+        )
+        .await;
+        let body = |path: &str| {
+            let req = TestRequest::get().uri(path).to_request();
+            let app = &app;
+            async move {
+                let resp = actix_web::test::call_service(app, req).await;
+                (resp.status(), actix_web::test::read_body_json::<serde_json::Value, _>(resp).await)
+            }
+        };
+        assert_eq!(body("/ok?fields=a").await, (StatusCode::OK, serde_json::json!({ "a": 1 })));
+        assert_eq!(body("/fail?fields=a").await, (StatusCode::BAD_REQUEST, serde_json::json!({ "error": "x" })));
+        assert_eq!(body("/ok?fields=a..b").await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn rate_limits_allow_a_burst_per_client() {
+        assert!(RateLimiter::from_config(&test_config()).is_none());
+        let limiter = RateLimiter::from_config(&Config { rate_limit_per_minute: Some(6), rate_limit_burst: 2, ..test_config() }).unwrap();
+        assert_eq!(limiter.acquire("ip:10.0.0.1"), None);
+        assert_eq!(limiter.acquire("ip:10.0.0.1"), None);
+        assert_eq!(limiter.acquire("ip:10.0.0.1"), Some(10));
+        assert_eq!(limiter.acquire("ip:10.0.0.2"), None);
+    }
+
+    #[actix_web::test]
+    async fn rate_limited_requests_get_retry_after() {
+        let limiter = RateLimiter::from_config(&Config { rate_limit_per_minute: Some(1), ..test_config() }).unwrap();
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(limiter))
+                .wrap(from_fn(rate_limit))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let call = |path: &str| {
+            let req = TestRequest::get().uri(path).to_request();
+            let app = &app;
+            async move { actix_web::test::call_service(app, req).await }
+        };
+        assert_eq!(call("/loans").await.status(), StatusCode::OK);
+        let limited = call("/loans").await;
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limited.headers().get("Retry-After").unwrap(), "60");
+        assert_eq!(call("/health").await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn table_reads_are_cached_until_a_write() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let config = Config { cache_ttl_secs: 60, ..config_with(serde_json::json!({ "events": { "cache_ttl_secs": 0 } })) };
+        let cache = web::Data::new(ResponseCache::from_config(&config).await);
+        let reads = web::Data::new(AtomicUsize::new(0));
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .app_data(cache)
+                .app_data(reads.clone())
+                .wrap(from_fn(response_cache))
+                .default_service(web::to(|reads: web::Data<AtomicUsize>| async move {
+                    HttpResponse::Ok().body(reads.fetch_add(1, Ordering::SeqCst).to_string())
+                })),
+        )
+        .await;
+        let call = |req: TestRequest| {
+            let app = &app;
+            async move {
+                let resp = actix_web::test::call_service(app, req.to_request()).await;
+                let status = resp.headers().get("X-Cache").map(|h| h.to_str().unwrap().to_string());
+                (status, actix_web::test::read_body(resp).await)
+            }
+        };
+        let get = |path: &str| TestRequest::get().uri(path);
+        assert_eq!(call(get("/loans")).await, (Some("MISS".to_string()), "0".into()));
+        assert_eq!(call(get("/loans")).await, (Some("HIT".to_string()), "0".into()));
+        let no_cache = get("/loans").insert_header(("Cache-Control", "no-cache"));
+        assert_eq!(call(no_cache).await, (Some("MISS".to_string()), "1".into()));
+        assert_eq!(call(get("/events")).await, (None, "2".into()));
+
+        assert_eq!(call(TestRequest::post().uri("/loans")).await.0, None);
+        assert_eq!(call(get("/loans")).await, (Some("MISS".to_string()), "4".into()));
+    }
+
+    #[actix_web::test]
+    async fn envelopes_are_chosen_per_request() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_config()))
+                .wrap(from_fn(response_envelope))
+                .default_service(web::to(|| async {
+                    HttpResponse::Ok().json(serde_json::json!({ "data": [1, 2], "count": 2, "total_count": 2 }))
+                })),
+        )
+        .await;
+        let call = |uri: &str| {
+            let req = TestRequest::get().uri(uri).to_request();
+            let app = &app;
+            async move { actix_web::test::call_service(app, req).await }
+        };
+        let resp = call("/loans?envelope=bare").await;
+        assert_eq!(resp.headers().get("x-total-count").unwrap(), "2");
+        assert_eq!(actix_web::test::read_body_json::<serde_json::Value, _>(resp).await, serde_json::json!([1, 2]));
+        let resp = call("/loans").await;
+        assert_eq!(actix_web::test::read_body_json::<serde_json::Value, _>(resp).await["count"], 2);
+        assert_eq!(call("/loans?envelope=xml").await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn logged_binds_are_redacted_unless_configured() {
+        let filters = parse_multiple_filters("name=Bob&id=in.(1,2)&note=is.null").unwrap();
+        let cursor = ["cursor".to_string()];
+        let binds = |mode| log_binds(&Config { log_binds: mode, ..test_config() }, &filters, &cursor);
+        assert_eq!(binds(BindLogging::Redact), vec!["?", "[?, ?]", "?"]);
+        assert_eq!(binds(BindLogging::Plain), vec!["\"Bob\"", "[\"1\", \"2\"]", "\"cursor\""]);
+        let hashed = binds(BindLogging::Hash);
+        assert!(hashed[0].starts_with('#') && hashed[0].len() == 17);
+        assert_ne!(hashed[0], hashed[2]);
+    }
+
+    #[actix_web::test]
+    async fn named_databases_are_routed_by_prefix() {
+        let archive = NamedDatabase {
+            pool: web::Data::new(offline_pool()),
+            replica: web::Data::new(Replica { pool: None, max_wait: Duration::ZERO, max_lag_bytes: 0 }),
+            breaker: web::Data::new(CircuitBreaker::new(&test_config())),
+        };
+        let databases = Databases(BTreeMap::from([("archive".to_string(), archive)]));
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(databases))
+                .wrap(from_fn(database_route))
+                .route(
+                    "/{table}",
+                    web::get().to(|req: HttpRequest| async move {
+                        let database = req.extensions().get::<DatabaseName>().map(|d| d.0.clone());
+                        HttpResponse::Ok().body(format!("{}?{} {:?}", req.match_info().query("table"), req.query_string(), database))
+                    }),
+                ),
+        )
+        .await;
+        let call = |uri: &str| {
+            let req = TestRequest::get().uri(uri).to_request();
+            let app = &app;
+            async move {
+                let resp = actix_web::test::call_service(app, req).await;
+                (resp.status(), actix_web::test::read_body(resp).await)
+            }
+        };
+        assert_eq!(call("/loans?page=2").await, (StatusCode::OK, "loans?page=2 None".into()));
+        assert_eq!(call("/db/Archive/loans?page=2").await, (StatusCode::OK, "loans?page=2 Some(\"archive\")".into()));
+        assert_eq!(call("/db/other/loans").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn trace_context_is_read_from_traceparent() {
+        use opentelemetry::propagation::TextMapPropagator;
+        use opentelemetry::trace::TraceContextExt;
+        let req = TestRequest::default()
+            .insert_header(("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"))
+            .to_http_request();
+        let propagator = opentelemetry_sdk::propagation::TraceContextPropagator::new();
+        let context = propagator.extract(&HeaderExtractor(req.headers()));
+        let span = context.span();
+        assert_eq!(span.span_context().trace_id().to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert!(span.span_context().is_remote());
+
+        let empty = TestRequest::default().to_http_request();
+        assert!(!propagator.extract(&HeaderExtractor(empty.headers())).span().span_context().is_valid());
+    }
+
+    #[actix_web::test]
+    async fn requests_carry_an_id() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(from_fn(request_id))
+                .default_service(web::to(|| async { HttpResponse::Ok().body(REQUEST_ID.with(|id| id.clone())) })),
+        )
+        .await;
+        let call = |id: Option<&str>| {
+            let mut req = TestRequest::get().uri("/loans");
+            if let Some(id) = id {
+                req = req.insert_header((REQUEST_ID_HEADER, id));
+            }
+            let req = req.to_request();
+            let app = &app;
+            async move {
+                let resp = actix_web::test::call_service(app, req).await;
+                let header = resp.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap().to_string();
+                (header, actix_web::test::read_body(resp).await)
+            }
+        };
+        let (header, body) = call(Some("trace-42")).await;
+        assert_eq!((header.as_str(), &body[..]), ("trace-42", &b"trace-42"[..]));
+        let (header, body) = call(Some("no spaces")).await;
+        assert_eq!((header.len(), header.as_bytes()), (32, &body[..]));
+        assert_ne!(call(None).await.0, call(None).await.0);
+    }
+
+    #[actix_web::test]
+    async fn plain_http_redirects_to_the_https_port() {
+        let app = |port: Option<u16>| {
+            actix_web::test::init_service(
+                App::new()
+                    .configure(move |cfg| {
+                        if let Some(port) = port {
+                            cfg.app_data(web::Data::new(HttpsPort(port)));
+                        }
+                    })
+                    .wrap(from_fn(https_redirect))
+                    .default_service(web::to(HttpResponse::Ok)),
+            )
+        };
+        let get = |path: &str, host: &str| {
+            TestRequest::get()
+                .uri(path)
+                .insert_header(("Host", host))
+                .peer_addr("203.0.113.9:50000".parse().unwrap())
+                .to_request()
+        };
+
+        let redirecting = app(Some(8443)).await;
+        let resp = actix_web::test::call_service(&redirecting, get("/loans?limit=5", "example.com:8080")).await;
+        assert_eq!(resp.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(resp.headers().get("Location").unwrap(), "https://example.com:8443/loans?limit=5");
+        let resp = actix_web::test::call_service(&redirecting, get("/", "[::1]")).await;
+        assert_eq!(resp.headers().get("Location").unwrap(), "https://[::1]:8443/");
+        let health = actix_web::test::call_service(&redirecting, get("/health", "example.com")).await;
+        assert_eq!(health.status(), StatusCode::OK);
+        // Requests over the unix socket have no peer address
+        let local = TestRequest::get().uri("/loans").insert_header(("Host", "example.com")).to_request();
+        assert_eq!(actix_web::test::call_service(&redirecting, local).await.status(), StatusCode::OK);
+
+        let default_port = app(Some(443)).await;
+        let resp = actix_web::test::call_service(&default_port, get("/loans", "example.com:80")).await;
+        assert_eq!(resp.headers().get("Location").unwrap(), "https://example.com/loans");
+
+        let plain = app(None).await;
+        assert_eq!(actix_web::test::call_service(&plain, get("/loans", "example.com")).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn writes_are_journaled_around_their_outcome() {
+        let path = std::env::temp_dir().join(format!("datapi-journal-{}.jsonl", std::process::id()));
+        let file = std::fs::OpenOptions::new().create(true).truncate(true).write(true).open(&path).unwrap();
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(web::Data::new(Journal { file: Mutex::new(file) }))
+                .wrap(from_fn(journal_writes))
+                .default_service(web::to(|body: web::Bytes| async move { HttpResponse::Created().body(body) })),
+        )
+        .await;
+        let write = TestRequest::post()
+            .uri("/loans?on_conflict=merge")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(r#"{"id":1}"#)
+            .to_request();
+        let resp = actix_web::test::call_service(&app, write).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(actix_web::test::read_body(resp).await, r#"{"id":1}"#.as_bytes());
+        let binary = TestRequest::patch().uri("/loans?id=eq.1").set_payload(vec![0xff, 0x00]).to_request();
+        actix_web::test::call_service(&app, binary).await;
+        for req in [TestRequest::get().uri("/loans"), TestRequest::post().uri("/_admin/maintenance")] {
+            actix_web::test::call_service(&app, req.to_request()).await;
+        }
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<JournalRecord> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records.len(), 4);
+        let JournalRecord::Request { method, path: request_path, content_type, body, body_base64, .. } = &records[0] else {
+            panic!("expected the request first");
+        };
+        assert_eq!((method.as_str(), request_path.as_str()), ("POST", "/loans?on_conflict=merge"));
+        assert_eq!((content_type.as_deref(), body.as_deref(), body_base64), (Some("application/json"), Some(r#"{"id":1}"#), &None));
+        assert!(matches!(records[1], JournalRecord::Outcome { status: 201, .. }));
+        assert!(matches!(&records[2], JournalRecord::Request { body: None, body_base64: Some(encoded), .. } if encoded == "/wA="));
+
+        let missing = run_replay(path.to_str().unwrap(), "http://127.0.0.1:1", Some("unknown")).await;
+        std::fs::remove_file(&path).unwrap();
+        assert!(missing.unwrap_err().to_string().ends_with("has no request unknown"));
+
+        let args = ["replay", "journal.jsonl", "--after", "trace-42"].map(String::from);
+        assert!(matches!(
+            parse_command(&args),
+            Ok(Command::Replay { ref journal, target: None, after: Some(ref after) }) if journal == "journal.jsonl" && after == "trace-42"
+        ));
+        assert!(parse_command(&["replay".to_string()]).is_err());
+    }
+
+    #[actix_web::test]
+    async fn unchanged_pages_are_answered_with_304() {
+        let app = |config: Config| {
+            actix_web::test::init_service(
+                App::new()
+                    .app_data(web::Data::new(config))
+                    .wrap(from_fn(conditional_get))
+                    .route("/loans", web::get().to(|| async { HttpResponse::Ok().insert_header(("X-Total-Count", "1")).json([1]) }))
+                    .route("/missing", web::get().to(HttpResponse::NotFound)),
+            )
+        };
+        let get = |path: &str, tag: Option<&str>| {
+            let mut req = TestRequest::get().uri(path);
+            if let Some(tag) = tag {
+                req = req.insert_header(("If-None-Match", tag.to_string()));
+            }
+            req.to_request()
+        };
+        let tagged = app(test_config()).await;
+        let resp = actix_web::test::call_service(&tagged, get("/loans", None)).await;
+        let etag = resp.headers().get("ETag").unwrap().to_str().unwrap().to_string();
+        assert!(etag.starts_with("W/\"") && etag.len() == 36);
+        assert_eq!(actix_web::test::read_body(resp).await, "[1]");
+
+        let strong = etag.trim_start_matches("W/");
+        for tag in [etag.as_str(), strong, "\"other\", *"] {
+            let resp = actix_web::test::call_service(&tagged, get("/loans", Some(tag))).await;
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(resp.headers().get("ETag").unwrap(), etag.as_str());
+            assert_eq!(resp.headers().get("X-Total-Count").unwrap(), "1");
+        }
+        let resp = actix_web::test::call_service(&tagged, get("/loans", Some("W/\"other\""))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = actix_web::test::call_service(&tagged, get("/missing", None)).await;
+        assert!(resp.headers().get("ETag").is_none());
+
+        let untagged = app(Config { etags: false, ..test_config() }).await;
+        let resp = actix_web::test::call_service(&untagged, get("/loans", Some(&etag))).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get("ETag").is_none());
+    }
+
+    #[actix_web::test]
+    async fn streamed_arrays_pass_through_unprojected() {
+        let app = actix_web::test::init_service(App::new().wrap(from_fn(fields_projection)).route(
+            "/stream",
+            web::get().to(|| async {
+                let chunk = web::Bytes::from_static(b"[{\"a\":1,\"b\":2}]");
+                HttpResponse::Ok()
+                    .content_type("application/json")
+                    .streaming(futures_util::stream::once(async move { Ok::<_, actix_web::Error>(chunk) }))
+            }),
+        ))
+        .await;
+        let resp = actix_web::test::call_service(&app, TestRequest::get().uri("/stream?fields=a").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(actix_web::test::read_body(resp).await, "[{\"a\":1,\"b\":2}]");
+    }
+
+    #[test]
+    fn audit_principals_never_hold_a_key() {
+        let config = web::Data::new(test_config());
+        let admin = TestRequest::default()
+            .insert_header(("Authorization", "Bearer secret"))
+            .app_data(config.clone())
+            .to_http_request();
+        assert_eq!(audit_principal(&admin).as_deref(), Some("admin"));
+        let keyed = TestRequest::default().insert_header((API_KEY_HEADER, "abc")).app_data(config).to_http_request();
+        assert_eq!(audit_principal(&keyed).as_deref(), Some("key:ba7816bf8f01"));
+        assert_eq!(audit_principal(&TestRequest::default().to_http_request()), None);
+
+        let condition = parse_filter("amount>5").unwrap();
+        assert_eq!(condition.describe(), "amount > 5");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_sockets_are_replaced_and_get_their_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("datapi-{}.sock", std::process::id()));
+        let socket = SocketConfig { path: path.to_string_lossy().into_owned(), mode: 0o640, only: false };
+        // Nothing listens on a socket whose server is gone
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        socket.remove_stale();
+        assert!(!path.exists());
+
+        let listener = socket.listen().unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o640);
+        assert!(std::os::unix::net::UnixStream::connect(&path).is_ok());
+        socket.remove_stale();
+        assert!(path.exists());
+        // A file already at the path is kept
+        assert!(socket.listen().is_err());
+        drop(listener);
+        socket.remove();
+
+        std::fs::write(&path, "not a socket").unwrap();
+        socket.remove_stale();
+        assert!(path.exists());
+        socket.remove();
+    }
+
+    #[actix_web::test]
+    async fn error_bodies_carry_the_request_id() {
+        let req = TestRequest::default().to_http_request();
+        let resp = REQUEST_ID
+            .scope("req-1".to_string(), async { bad_request(&req, Message::new("invalid_offset")) })
+            .await;
+        assert_eq!(json_body(resp).await["request_id"], "req-1");
+        assert_eq!(postgres_message(&sqlx::Error::RowNotFound), "");
+    }
+
+    #[test]
+    fn reads_of_one_request_add_up() {
+        let req = TestRequest::default().to_http_request();
+        note_read(&req, "loans", vec!["amount > 5".to_string()], 3);
+        note_read(&req, "customers", Vec::new(), 2);
+        note_read(&req, "loans", Vec::new(), 1);
+        let extensions = req.extensions();
+        let stats = extensions.get::<ReadStats>().unwrap();
+        assert_eq!((stats.table.as_str(), stats.rows), ("loans,customers", 6));
+        assert_eq!(stats.filters, ["amount > 5"]);
+    }
+}
@@ -0,0 +1,450 @@
+use std::collections::BTreeMap;
+use actix_web::http::StatusCode;
+use actix_web::middleware::Compress;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use serde::Deserialize;
+use crate::access::glob_match;
+use crate::filters::{parse_multiple_filters, FilterExpr, FilterValue};
+use crate::handlers::health_check;
+use crate::messages::{bad_request, error_response, extractor_error, Message};
+use crate::query::QueryResult;
+use crate::writes::json_to_text;
+
+// Mock mode: `datapi serve --mock schema.json` serves generated rows for
+// the declared tables, without a database.
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct MockSchema {
+    // Same schema, same rows
+    #[serde(default)]
+    pub(crate) seed: u64,
+    pub(crate) tables: BTreeMap<String, MockTable>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct MockTable {
+    #[serde(default = "default_mock_rows")]
+    pub(crate) rows: usize,
+    pub(crate) columns: BTreeMap<String, MockColumn>,
+}
+
+pub(crate) fn default_mock_rows() -> usize {
+    100
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct MockColumn {
+    #[serde(rename = "type")]
+    pub(crate) kind: MockType,
+    // Range of numbers; for dates and timestamps `from` and `to` instead
+    pub(crate) min: Option<f64>,
+    pub(crate) max: Option<f64>,
+    pub(crate) from: Option<chrono::NaiveDate>,
+    pub(crate) to: Option<chrono::NaiveDate>,
+    #[serde(default)]
+    pub(crate) distribution: MockDistribution,
+    // Integers counting up from `min` (default 1), one per row
+    #[serde(default)]
+    pub(crate) sequence: bool,
+    // Picked from instead of generated, with optional relative weights
+    #[serde(default)]
+    pub(crate) values: Vec<serde_json::Value>,
+    #[serde(default)]
+    pub(crate) weights: Vec<f64>,
+    // Fraction of rows that are NULL
+    #[serde(default)]
+    pub(crate) null_rate: f64,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MockType {
+    Integer,
+    Number,
+    Text,
+    Boolean,
+    Date,
+    Timestamp,
+    Uuid,
+    Name,
+    Email,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum MockDistribution {
+    #[default]
+    Uniform,
+    // Centered in the range, three standard deviations to either end
+    Normal,
+}
+
+pub(crate) const MOCK_FIRST_NAMES: &[&str] = &["Sarah", "John", "Maria", "Wei", "Aisha", "Lucas", "Emma", "Omar", "Yuki", "Elena"];
+pub(crate) const MOCK_LAST_NAMES: &[&str] = &["Brown", "Smith", "Garcia", "Chen", "Khan", "Silva", "Müller", "Haddad", "Sato", "Rossi"];
+pub(crate) const MOCK_WORDS: &[&str] = &["alpha", "bravo", "delta", "echo", "lima", "nova", "orbit", "pixel", "quartz", "tango"];
+
+// splitmix64, good enough for fake data and reproducible across platforms
+pub(crate) struct MockRng(pub(crate) u64);
+
+impl MockRng {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+    
+    // Uniform in [0, 1)
+    pub(crate) fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    
+    pub(crate) fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[(self.next() % items.len() as u64) as usize]
+    }
+    
+    pub(crate) fn in_range(&mut self, min: f64, max: f64, distribution: MockDistribution) -> f64 {
+        match distribution {
+            MockDistribution::Uniform => min + self.unit() * (max - min),
+            MockDistribution::Normal => {
+                // Box-Muller
+                let z = (-2.0 * (1.0 - self.unit()).ln()).sqrt() * (std::f64::consts::TAU * self.unit()).cos();
+                ((min + max) / 2.0 + z * (max - min) / 6.0).clamp(min, max)
+            }
+        }
+    }
+}
+
+impl MockColumn {
+    pub(crate) fn check(&self) -> Result<(), String> {
+        if !self.weights.is_empty() && self.weights.len() != self.values.len() {
+            return Err("weights must have one entry per value".to_string());
+        }
+        if self.weights.iter().any(|w| *w < 0.0) || (!self.weights.is_empty() && self.weights.iter().sum::<f64>() <= 0.0) {
+            return Err("weights must be non-negative and not all zero".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.null_rate) {
+            return Err("null_rate must be between 0 and 1".to_string());
+        }
+        if self.min.zip(self.max).is_some_and(|(min, max)| min > max) || self.from.zip(self.to).is_some_and(|(from, to)| from > to) {
+            return Err("the range is empty".to_string());
+        }
+        Ok(())
+    }
+    
+    pub(crate) fn generate(&self, row: usize, rng: &mut MockRng) -> serde_json::Value {
+        if self.null_rate > 0.0 && rng.unit() < self.null_rate {
+            return serde_json::Value::Null;
+        }
+        if !self.values.is_empty() {
+            if self.weights.is_empty() {
+                return rng.pick(&self.values).clone();
+            }
+            let mut target = rng.unit() * self.weights.iter().sum::<f64>();
+            for (value, weight) in self.values.iter().zip(&self.weights) {
+                if target < *weight {
+                    return value.clone();
+                }
+                target -= weight;
+            }
+            return self.values[self.values.len() - 1].clone();
+        }
+        
+        let from = self.from.unwrap_or(chrono::NaiveDate::from_ymd_opt(2020, 1, 1).unwrap_or_default());
+        let to = self.to.unwrap_or(chrono::NaiveDate::from_ymd_opt(2025, 12, 31).unwrap_or_default());
+        let days = (to - from).num_days() as f64;
+        match self.kind {
+            MockType::Integer if self.sequence => serde_json::json!(self.min.unwrap_or(1.0) as i64 + row as i64),
+            MockType::Integer => {
+                let value = rng.in_range(self.min.unwrap_or(0.0), self.max.unwrap_or(1000.0) + 1.0, self.distribution);
+                serde_json::json!((value.floor() as i64).min(self.max.unwrap_or(1000.0) as i64))
+            }
+            MockType::Number => {
+                let value = rng.in_range(self.min.unwrap_or(0.0), self.max.unwrap_or(1000.0), self.distribution);
+                serde_json::json!((value * 100.0).round() / 100.0)
+            }
+            MockType::Boolean => serde_json::json!(rng.unit() < 0.5),
+            MockType::Text => serde_json::json!(format!("{} {}", rng.pick(MOCK_WORDS), rng.pick(MOCK_WORDS))),
+            MockType::Name => serde_json::json!(format!("{} {}", rng.pick(MOCK_FIRST_NAMES), rng.pick(MOCK_LAST_NAMES))),
+            MockType::Email => serde_json::json!(format!(
+                "{}.{}{}@example.com",
+                rng.pick(MOCK_FIRST_NAMES).to_lowercase(),
+                rng.pick(MOCK_WORDS),
+                row + 1
+            )),
+            MockType::Date => {
+                let offset = rng.in_range(0.0, days, self.distribution).round() as i64;
+                serde_json::json!((from + chrono::Duration::days(offset)).to_string())
+            }
+            MockType::Timestamp => {
+                let seconds = rng.in_range(0.0, (days + 1.0) * 86400.0 - 1.0, self.distribution) as i64;
+                let at = from.and_hms_opt(0, 0, 0).unwrap_or_default() + chrono::Duration::seconds(seconds);
+                serde_json::json!(at.format("%Y-%m-%dT%H:%M:%S").to_string())
+            }
+            MockType::Uuid => {
+                let (a, b) = (rng.next(), rng.next());
+                serde_json::json!(format!(
+                    "{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+                    a >> 32,
+                    (a >> 16) & 0xffff,
+                    a & 0xfff,
+                    0x8000 | (b >> 48) & 0x3fff,
+                    b & 0xffff_ffff_ffff
+                ))
+            }
+        }
+    }
+}
+
+// The generated rows of every table, by lowercase table name
+pub(crate) struct MockData {
+    pub(crate) tables: BTreeMap<String, Vec<serde_json::Map<String, serde_json::Value>>>,
+}
+
+impl MockData {
+    pub(crate) fn load(path: &str) -> Self {
+        let text = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("Failed to read mock schema {}: {}", path, e));
+        let schema: MockSchema = serde_json::from_str(&text)
+            .unwrap_or_else(|e| panic!("Invalid mock schema {}: {}", path, e));
+        
+        let mut rng = MockRng(schema.seed);
+        let mut tables = BTreeMap::new();
+        for (name, table) in &schema.tables {
+            for (column_name, column) in &table.columns {
+                if let Err(e) = column.check() {
+                    panic!("Invalid mock column {}.{}: {}", name, column_name, e);
+                }
+            }
+            let rows = (0..table.rows)
+                .map(|row| {
+                    table
+                        .columns
+                        .iter()
+                        .map(|(column_name, column)| (column_name.clone(), column.generate(row, &mut rng)))
+                        .collect()
+                })
+                .collect();
+            tables.insert(name.to_lowercase(), rows);
+        }
+        MockData { tables }
+    }
+}
+
+// Evaluates filters against a generated row the way the SQL does: values
+// compare as text
+pub(crate) fn mock_matches(filters: &FilterExpr, row: &serde_json::Map<String, serde_json::Value>) -> bool {
+    let c = match filters {
+        FilterExpr::Condition(c) => c,
+        FilterExpr::And(children) => return children.iter().all(|f| mock_matches(f, row)),
+        FilterExpr::Or(children) => return children.iter().any(|f| mock_matches(f, row)),
+        FilterExpr::Sql(_) => return true,
+    };
+    let value = row.get(&c.column).and_then(json_to_text);
+    match (&c.value, value) {
+        (FilterValue::Null, value) => value.is_none() == (c.operator == "IS"),
+        (_, None) => false,
+        (FilterValue::List(list), Some(value)) if c.is_range_operator() => match list.as_slice() {
+            [low, high] => (*low <= value && value <= *high) == (c.operator == "BETWEEN"),
+            _ => false,
+        },
+        (FilterValue::List(list), Some(value)) => list.contains(&value) == (c.operator == "IN"),
+        (FilterValue::Single(target), Some(value)) => {
+            let like = |pattern: &str, value: &str| glob_match(&pattern.replace('%', "*"), value);
+            match c.operator.as_str() {
+                "=" => value == *target,
+                "!=" | "<>" => value != *target,
+                "<" => value < *target,
+                "<=" => value <= *target,
+                ">" => value > *target,
+                ">=" => value >= *target,
+                "LIKE" => like(target, &value),
+                "NOT LIKE" => !like(target, &value),
+                "ILIKE" => like(&target.to_lowercase(), &value.to_lowercase()),
+                "NOT ILIKE" => !like(&target.to_lowercase(), &value.to_lowercase()),
+                _ => false,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct MockParams {
+    pub(crate) page: Option<usize>,
+    pub(crate) page_size: Option<usize>,
+    pub(crate) sort: Option<String>,
+    pub(crate) order: Option<String>,
+}
+
+pub(crate) async fn mock_query_all(
+    req: HttpRequest,
+    data: web::Data<MockData>,
+    path: web::Path<String>,
+    params: web::Query<MockParams>,
+) -> impl Responder {
+    run_mock_query(&req, &data, &path.into_inner(), None, &params)
+}
+
+pub(crate) async fn mock_query_table(
+    req: HttpRequest,
+    data: web::Data<MockData>,
+    path: web::Path<(String, String)>,
+    params: web::Query<MockParams>,
+) -> impl Responder {
+    let (table, filters_str) = path.into_inner();
+    run_mock_query(&req, &data, &table, Some(&filters_str), &params)
+}
+
+pub(crate) fn run_mock_query(
+    req: &HttpRequest,
+    data: &MockData,
+    table: &str,
+    filters_str: Option<&str>,
+    params: &MockParams,
+) -> HttpResponse {
+    let Some(rows) = data.tables.get(&table.to_lowercase()) else {
+        return error_response(req, StatusCode::NOT_FOUND, Message::new("table_not_found").arg("table", table));
+    };
+    let filters = match filters_str.map(parse_multiple_filters).transpose() {
+        Ok(filters) => filters.unwrap_or_else(FilterExpr::none),
+        Err(e) => return bad_request(req, Message::new("invalid_filter").cause(e)),
+    };
+    
+    let mut matching: Vec<&serde_json::Map<String, serde_json::Value>> =
+        rows.iter().filter(|row| mock_matches(&filters, row)).collect();
+    if let Some(sort) = &params.sort {
+        let descending = match params.order.as_deref().unwrap_or("asc").to_lowercase().as_str() {
+            "asc" => false,
+            "desc" => true,
+            _ => return bad_request(req, Message::new("invalid_sort_order").param("order")),
+        };
+        if rows.first().is_some_and(|row| !row.contains_key(sort)) {
+            return bad_request(req, Message::new("unknown_column").arg("column", sort).arg("table", table).param("sort"));
+        }
+        // NULLs last, like Postgres for ascending order
+        let key = |row: &serde_json::Map<String, serde_json::Value>| {
+            let value = row.get(sort).cloned().unwrap_or_default();
+            (value.is_null(), value.as_f64(), json_to_text(&value))
+        };
+        matching.sort_by(|a, b| {
+            let (a, b) = (key(a), key(b));
+            let ordering = a.0.cmp(&b.0).then(
+                a.1.zip(b.1)
+                    .and_then(|(x, y)| x.partial_cmp(&y))
+                    .unwrap_or_else(|| a.2.cmp(&b.2)),
+            );
+            if descending { ordering.reverse() } else { ordering }
+        });
+    }
+    
+    let page = params.page.unwrap_or(1).max(1);
+    let page_size = params.page_size.unwrap_or(100).min(1000);
+    let data: Vec<serde_json::Value> = matching
+        .iter()
+        .skip((page - 1) * page_size)
+        .take(page_size)
+        .map(|row| serde_json::Value::Object((*row).clone()))
+        .collect();
+    QueryResult {
+        count: data.len(),
+        data,
+        page,
+        page_size,
+        total_count: Some(matching.len()),
+        total_count_estimated: false,
+        result_token: None,
+        next_cursor: None,
+        sort_fingerprint: None,
+        warnings: Vec::new(),
+        total_pages: None,
+        has_next: false,
+    }
+    .into_response(req, HttpResponse::Ok())
+}
+
+pub(crate) async fn serve_mock(schema_path: &str, bind_address: &str) -> std::io::Result<()> {
+    let data = web::Data::new(MockData::load(schema_path));
+    log::info!("Serving mock data for {} tables from {}", data.tables.len(), schema_path);
+    log::info!("Starting mock server at {}", bind_address);
+    
+    HttpServer::new(move || {
+        App::new()
+            .app_data(data.clone())
+            .app_data(web::QueryConfig::default().error_handler(|e, req| extractor_error(req, "invalid_query_string", e)))
+            .wrap(Compress::default())
+            .route("/health", web::get().to(health_check))
+            .route("/{table}", web::get().to(mock_query_all))
+            .route("/{table}/{filter}", web::get().to(mock_query_table))
+    })
+    .bind(bind_address)?
+    .run()
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn mock_data_is_reproducible_and_in_range() {
+        let data = MockData::load("mock.example.json");
+        let users = &data.tables["users"];
+        assert_eq!(users.len(), 200);
+        assert_eq!(users[0]["id"], 1);
+        assert_eq!(users[199]["id"], 200);
+        for user in users {
+            assert!((18..=90).contains(&user["age"].as_i64().unwrap()));
+            assert!(["active", "inactive"].contains(&user["status"].as_str().unwrap()));
+            let created = user["created_at"].as_str().unwrap();
+            assert!(("2023-01-01".."2025-07-01").contains(&created), "{}", created);
+        }
+        assert_eq!(MockData::load("mock.example.json").tables["users"], *users);
+
+        let column: MockColumn = serde_json::from_value(serde_json::json!({
+            "type": "text", "values": ["a", "b"], "weights": [1]
+        }))
+        .unwrap();
+        assert!(column.check().is_err());
+        let column: MockColumn = serde_json::from_value(serde_json::json!({ "type": "integer", "min": 5, "max": 1 })).unwrap();
+        assert!(column.check().is_err());
+    }
+
+    #[actix_web::test]
+    async fn mock_queries_filter_sort_and_page() {
+        let row = |id: i64, status: &str| match serde_json::json!({ "id": id, "status": status }) {
+            serde_json::Value::Object(obj) => obj,
+            _ => unreachable!(),
+        };
+        let data = MockData {
+            tables: BTreeMap::from([(
+                "loans".to_string(),
+                vec![row(2, "open"), row(10, "closed"), row(1, "open"), row(3, "Opening")],
+            )]),
+        };
+        assert!(mock_matches(&parse_multiple_filters("status~*open%").unwrap(), &row(3, "Opening")));
+        assert!(!mock_matches(&parse_multiple_filters("status=is.null").unwrap(), &row(3, "open")));
+
+        let req = TestRequest::default().to_http_request();
+        let params = |page_size: usize| MockParams {
+            page: Some(1),
+            page_size: Some(page_size),
+            sort: Some("id".to_string()),
+            order: Some("desc".to_string()),
+        };
+        let query = |filters: Option<&str>, page_size: usize| {
+            let resp = run_mock_query(&req, &data, "Loans", filters, &params(page_size));
+            async move { json_body(resp).await }
+        };
+        let body = query(Some("status=open"), 10).await;
+        assert_eq!(body["data"], serde_json::json!([row(2, "open"), row(1, "open")]));
+        // Numbers sort numerically
+        let body = query(None, 2).await;
+        assert_eq!(body["data"], serde_json::json!([row(10, "closed"), row(3, "Opening")]));
+        assert_eq!(body["total_count"], 4);
+        let resp = run_mock_query(&req, &data, "refunds", None, &params(1));
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::BTreeMap;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use sqlx::PgPool;
+use crate::access::{fetch_table_names, Exposure};
+use crate::config::{Config, ROLLUP_BUCKETS};
+use crate::handlers::column_tags;
+use crate::messages::{error_response, Message};
+use crate::query::{
+    fetch_column_types, fetch_column_write_info, fetch_comments, fetch_relation_updatable,
+    APPLICATION_NAME,
+};
+use crate::writes::quote_identifier;
+
+// OpenAPI 3 description of the served tables, generated from the catalog on
+// every request so it follows schema changes.
+
+// JSON Schema for a column, by its format_type() text and how row_to_json
+// renders it: timestamps as strings, numerics as numbers unless
+// DATAPI_NUMERIC_STRINGS is set
+pub(crate) fn openapi_column_schema(pg_type: &str, numeric_strings: bool) -> serde_json::Value {
+    if let Some(element) = pg_type.strip_suffix("[]") {
+        return serde_json::json!({ "type": "array", "items": openapi_column_schema(element, numeric_strings) });
+    }
+    let base = pg_type.split('(').next().unwrap_or_default().trim();
+    match base {
+        "smallint" | "integer" => serde_json::json!({ "type": "integer", "format": "int32" }),
+        "bigint" => serde_json::json!({ "type": "integer", "format": "int64" }),
+        "real" => serde_json::json!({ "type": "number", "format": "float" }),
+        "double precision" => serde_json::json!({ "type": "number", "format": "double" }),
+        "numeric" if numeric_strings => serde_json::json!({ "type": "string", "format": "decimal" }),
+        "numeric" => serde_json::json!({ "type": "number" }),
+        "boolean" => serde_json::json!({ "type": "boolean" }),
+        "date" => serde_json::json!({ "type": "string", "format": "date" }),
+        "timestamp with time zone" => serde_json::json!({ "type": "string", "format": "date-time" }),
+        "uuid" => serde_json::json!({ "type": "string", "format": "uuid" }),
+        "bytea" => serde_json::json!({ "type": "string", "format": "byte" }),
+        "interval" => serde_json::json!({ "type": "string", "format": "duration" }),
+        "json" | "jsonb" => serde_json::json!({}),
+        "geometry" | "geography" => serde_json::json!({ "type": "object", "description": "GeoJSON geometry" }),
+        "text" | "character varying" | "character" => serde_json::json!({ "type": "string" }),
+        _ => serde_json::json!({ "type": "string", "description": pg_type }),
+    }
+}
+
+// The JSON value of a constant column default such as `'active'::text`,
+// `0` or `false`, in the column's JSON type. Expressions like now() give None.
+pub(crate) fn openapi_default(expr: &str, schema: &serde_json::Value) -> Option<serde_json::Value> {
+    let expr = expr.trim();
+    let value = if let Some(quoted) = expr.strip_prefix('\'') {
+        // The literal ends at the first quote not doubled
+        let mut text = String::new();
+        let mut chars = quoted.chars().peekable();
+        loop {
+            match chars.next()? {
+                '\'' if chars.peek() == Some(&'\'') => {
+                    chars.next();
+                    text.push('\'');
+                }
+                '\'' => break,
+                c => text.push(c),
+            }
+        }
+        let rest: String = chars.collect();
+        if !rest.is_empty() && !rest.starts_with("::") {
+            return None;
+        }
+        serde_json::Value::String(text)
+    } else {
+        let literal = expr.split("::").next().unwrap_or_default().trim_matches(|c| c == '(' || c == ')');
+        match literal {
+            "true" => serde_json::json!(true),
+            "false" => serde_json::json!(false),
+            "NULL" => serde_json::Value::Null,
+            _ => serde_json::Value::String(literal.parse::<f64>().ok().map(|_| literal.to_string())?),
+        }
+    };
+    // Numbers and booleans keep their type unless the column renders as text
+    Some(match (schema["type"].as_str(), value) {
+        (Some("integer"), serde_json::Value::String(n)) => n.parse::<i64>().ok().map(serde_json::Value::from)?,
+        (Some("number"), serde_json::Value::String(n)) => n.parse::<f64>().ok().map(serde_json::Value::from)?,
+        (Some("boolean"), serde_json::Value::String(b)) => serde_json::json!(b.parse::<bool>().ok()?),
+        (_, value) => value,
+    })
+}
+
+pub(crate) fn openapi_parameter(name: &str, description: &str, schema: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({ "name": name, "in": "query", "description": description, "schema": schema })
+}
+
+pub(crate) fn openapi_ref(kind: &str, name: &str) -> serde_json::Value {
+    serde_json::json!({ "$ref": format!("#/components/{}/{}", kind, name) })
+}
+
+pub(crate) fn openapi_json_response(description: &str, schema: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({ "description": description, "content": { "application/json": { "schema": schema } } })
+}
+
+// The document for the given tables; write operations only when writes are
+// enabled. Deprecated tables mark their operations deprecated.
+pub(crate) async fn openapi_document(
+    pool: &PgPool,
+    config: &Config,
+    tables: &[String],
+) -> Result<serde_json::Value, sqlx::Error> {
+    let read_parameters = [
+        "page", "page_size", "sort", "order", "collate", "select", "or", "cursor", "format", "fields",
+        "materialize", "on_overflow", "checkpoints", "resume_token", "stream", "count", "timeout_ms", "envelope",
+        "consistency", "debug", "preset", "include_deleted", "filter",
+    ];
+    let mut parameters = serde_json::Map::new();
+    for (name, description, schema) in [
+        ("page", "Page number, from 1", serde_json::json!({ "type": "integer", "minimum": 1, "default": 1 })),
+        ("page_size", "Rows per page", serde_json::json!({ "type": "integer", "minimum": 0, "maximum": config.max_page_size, "default": config.default_page_size })),
+        ("sort", "Comma-separated columns or expressions, each optionally suffixed with :asc or :desc, :nullsfirst or :nullslast and :ci, or random for a sample", serde_json::json!({ "type": "string" })),
+        ("order", "Sort direction", serde_json::json!({ "type": "string", "enum": ["asc", "desc"] })),
+        ("collate", "Collation for text sort columns", serde_json::json!({ "type": "string" })),
+        ("select", "Comma-separated columns to return, each optionally renamed as column:alias", serde_json::json!({ "type": "string" })),
+        ("omit_binary", "Leave bytea columns out of the result", serde_json::json!({ "type": "boolean" })),
+        ("embed", "Comma-separated related tables to nest, through foreign keys; list embeds take a page, e.g. orders(page=2,page_size=10)", serde_json::json!({ "type": "string" })),
+        ("distinct", "Drop duplicate rows", serde_json::json!({ "type": "boolean" })),
+        ("distinct_on", "Comma-separated columns to keep the first row (in sort order) of each combination of", serde_json::json!({ "type": "string" })),
+        ("search", "Full-text search in websearch syntax, e.g. \"rust -java\"; sort=rank orders by relevance", serde_json::json!({ "type": "string" })),
+        ("search_columns", "Comma-separated columns to search, the tsvector columns by default", serde_json::json!({ "type": "string" })),
+        ("search_config", "Text search configuration, e.g. english", serde_json::json!({ "type": "string" })),
+        ("tz", "IANA time zone for timestamptz values, e.g. Europe/Berlin", serde_json::json!({ "type": "string", "default": "UTC" })),
+        ("or", "OR group AND-ed with the path filters, e.g. (status=active,status=pending)", serde_json::json!({ "type": "string" })),
+        ("cursor", "Keyset pagination: empty for the first page, then the previous next_cursor", serde_json::json!({ "type": "string" })),
+        ("format", "Response format", serde_json::json!({ "type": "string", "enum": ["json", "csv", "ndjson", "parquet", "geojson", "xlsx", "arrow"] })),
+        ("fields", "Comma-separated paths to keep in the response, e.g. /data/*/id", serde_json::json!({ "type": "string" })),
+        ("materialize", "Store the result and return a result_token for paging it", serde_json::json!({ "type": "boolean" })),
+        ("on_overflow", "What to do when more than the row limit match", serde_json::json!({ "type": "string", "enum": ["reject", "summarize"] })),
+        ("checkpoints", "NDJSON exports: emit a resume token every 10,000 rows", serde_json::json!({ "type": "boolean" })),
+        ("resume_token", "NDJSON exports: continue after the row of a checkpoint", serde_json::json!({ "type": "string" })),
+        ("stream", "JSON: stream every matching row as a bare array, without paging or count", serde_json::json!({ "type": "boolean" })),
+        ("envelope", "Shape of the response", serde_json::json!({ "type": "string", "enum": ["default", "bare", "items_meta"] })),
+        ("timeout_ms", "Statement timeout for this read, capped by the server maximum", serde_json::json!({ "type": "integer", "minimum": 1 })),
+        ("debug", "semantics adds a debug section on how NULLs, text comparison and collations shaped the result", serde_json::json!({ "type": "string", "enum": ["semantics"] })),
+        ("consistency", "Where the read runs: the primary, a replica within the lag bound, or any replica", serde_json::json!({ "type": "string", "enum": ["strong", "bounded", "eventual"] })),
+        ("preset", "A configured filter preset of the table, AND-ed with the path filters", serde_json::json!({ "type": "string" })),
+        ("include_deleted", "Admin token only: include soft-deleted rows", serde_json::json!({ "type": "boolean" })),
+        ("count", "How total_count is computed; none omits it", serde_json::json!({ "type": "string", "enum": ["exact", "estimated", "none"], "default": "exact" })),
+        ("group_by", "Comma-separated grouping columns", serde_json::json!({ "type": "string" })),
+        ("agg", "Comma-separated aggregates, e.g. sum(amount),count(*)", serde_json::json!({ "type": "string" })),
+        ("bucket", "Group by time bucket of time_column first", serde_json::json!({ "type": "string", "enum": ROLLUP_BUCKETS })),
+        ("time_column", "Column bucketed by bucket and ranged by from and to", serde_json::json!({ "type": "string" })),
+        ("from", "Start of the time_column range, inclusive", serde_json::json!({ "type": "string" })),
+        ("to", "End of the time_column range, exclusive", serde_json::json!({ "type": "string" })),
+        ("compare", "Add the previous series aligned by bucket, with percentage deltas", serde_json::json!({ "type": "string", "enum": ["previous_period", "previous_year"] })),
+        ("on_conflict", "What to do with rows whose key already exists", serde_json::json!({ "type": "string", "enum": ["error", "ignore", "merge", "replace"] })),
+        ("conflict_columns", "Comma-separated key columns on_conflict applies to, the primary key by default", serde_json::json!({ "type": "string" })),
+    ] {
+        parameters.insert(name.to_string(), openapi_parameter(name, description, schema));
+    }
+    parameters.insert("filter".to_string(), serde_json::json!({
+        "name": "filter",
+        "in": "query",
+        "style": "deepObject",
+        "explode": true,
+        "description": "Conditions as filter[column][operator]=value, AND-ed with the path filters; operators are eq (the default), neq, gt, gte, lt, lte, like, ilike, nlike, nilike, in, nin, between, nbetween, contains, overlaps and is (null or not_null)",
+        "schema": { "type": "object", "additionalProperties": { "type": "object", "additionalProperties": { "type": "string" } } }
+    }));
+    parameters.insert("filters".to_string(), serde_json::json!({
+        "name": "filters",
+        "in": "path",
+        "required": true,
+        "description": "&-separated conditions such as status=active&amount>=100, name~Jo%, id=in.(1,2), created_at=2024-01-01..2024-02-01 or email=is.null",
+        "schema": { "type": "string" }
+    }));
+    
+    let mut schemas = serde_json::Map::new();
+    schemas.insert("Error".to_string(), serde_json::json!({
+        "type": "object",
+        "properties": {
+            "error": { "type": "string" },
+            "code": { "type": "string" },
+            "parameter": { "type": "string" },
+            "request_id": { "type": "string" }
+        },
+        "required": ["error", "code"]
+    }));
+    let error = openapi_json_response("Error", openapi_ref("schemas", "Error"));
+    
+    let mut paths = serde_json::Map::new();
+    let mut tags = Vec::new();
+    for name in tables {
+        let column_types = fetch_column_types(pool, &quote_identifier(name)).await?;
+        let write_info = fetch_column_write_info(pool, &quote_identifier(name)).await?;
+        let (description, column_descriptions) = fetch_comments(pool, &quote_identifier(name)).await?;
+        let updatable = fetch_relation_updatable(pool, &quote_identifier(name)).await?.unwrap_or(true);
+        let table_config = config.table(name);
+        let encrypted = |column: &str| table_config.is_some_and(|t| t.encrypted_columns.iter().any(|c| c == column));
+        let mut properties: BTreeMap<&String, serde_json::Value> = BTreeMap::new();
+        let mut required = Vec::new();
+        for (column, (pg_type, not_null)) in &column_types {
+            let mut schema = if encrypted(column) {
+                serde_json::json!({ "type": "string", "description": "Encrypted" })
+            } else {
+                openapi_column_schema(pg_type, config.numeric_strings)
+            };
+            if !not_null {
+                schema["nullable"] = serde_json::json!(true);
+            }
+            let info = write_info.get(column.as_str());
+            if let Some(info) = info.filter(|_| !encrypted(column)) {
+                if !info.labels.is_empty() {
+                    schema["enum"] = serde_json::json!(info.labels);
+                }
+                if let Some(expr) = &info.default {
+                    match openapi_default(expr, &schema) {
+                        Some(value) => schema["default"] = value,
+                        None => schema["x-datapi-default"] = serde_json::json!(expr),
+                    }
+                }
+            }
+            if info.is_some_and(|i| i.read_only) {
+                schema["readOnly"] = serde_json::json!(true);
+            }
+            // Inserts must give the columns that can't be left to the database
+            if *not_null && info.is_some_and(|i| i.default.is_none() && !i.identity && !i.read_only) {
+                required.push(column.clone());
+            }
+            if let Some(comment) = column_descriptions.get(column) {
+                schema["description"] = serde_json::json!(comment);
+            }
+            let tags = column_tags(table_config, column);
+            if !tags.is_empty() {
+                schema["x-datapi-tags"] = serde_json::json!(tags);
+            }
+            // Reads serve the mask instead of the value, writes take the value
+            if let Some(mask) = config.mask(name, column) {
+                schema["x-datapi-mask"] = serde_json::json!(mask);
+            }
+            properties.insert(column, schema);
+        }
+        for column in table_config.map(|t| t.virtual_columns.keys()).into_iter().flatten() {
+            let mut schema = serde_json::json!({ "description": "Virtual column", "readOnly": true });
+            let tags = column_tags(table_config, column);
+            if !tags.is_empty() {
+                schema["x-datapi-tags"] = serde_json::json!(tags);
+            }
+            properties.insert(column, schema);
+        }
+        let mut schema = serde_json::json!({ "type": "object", "properties": properties });
+        match &description {
+            Some(comment) => {
+                schema["description"] = serde_json::json!(comment);
+                tags.push(serde_json::json!({ "name": name, "description": comment }));
+            }
+            None => tags.push(serde_json::json!({ "name": name })),
+        }
+        schemas.insert(name.clone(), schema);
+        required.sort();
+        let insert_schema = if required.is_empty() {
+            openapi_ref("schemas", name)
+        } else {
+            serde_json::json!({ "allOf": [openapi_ref("schemas", name), { "required": required }] })
+        };
+        schemas.insert(format!("{}_insert", name), insert_schema);
+        schemas.insert(format!("{}_page", name), serde_json::json!({
+            "type": "object",
+            "properties": {
+                "data": { "type": "array", "items": openapi_ref("schemas", name) },
+                "count": { "type": "integer" },
+                "page": { "type": "integer" },
+                "page_size": { "type": "integer" },
+                "total_count": { "type": "integer" },
+                "total_count_estimated": { "type": "boolean" },
+                "total_pages": { "type": "integer" },
+                "has_next": { "type": "boolean" },
+                "next_cursor": { "type": "string" },
+                "sort_fingerprint": { "type": "string" },
+                "result_token": { "type": "string" }
+            }
+        }));
+        schemas.insert(format!("{}_rows", name), serde_json::json!({
+            "type": "object",
+            "properties": {
+                "data": { "type": "array", "items": openapi_ref("schemas", name) },
+                "count": { "type": "integer" }
+            }
+        }));
+        
+        let deprecated = table_config.is_some_and(|t| t.deprecation.is_some());
+        let page = openapi_json_response("A page of rows", openapi_ref("schemas", &format!("{}_page", name)));
+        let rows = openapi_json_response("The affected rows", openapi_ref("schemas", &format!("{}_rows", name)));
+        let read_refs: Vec<serde_json::Value> = read_parameters.iter().map(|p| openapi_ref("parameters", p)).collect();
+        let mut filtered_refs = vec![openapi_ref("parameters", "filters")];
+        filtered_refs.extend(read_refs.iter().cloned());
+        let operation = |id: String, summary: String, parameters: &[serde_json::Value], ok: (&str, &serde_json::Value)| {
+            serde_json::json!({
+                "operationId": id,
+                "summary": summary,
+                "tags": [name],
+                "deprecated": deprecated,
+                "parameters": parameters,
+                "responses": { ok.0: ok.1, "400": error, "404": error }
+            })
+        };
+        let insert = format!("{}_insert", name);
+        let body = serde_json::json!({
+            "required": true,
+            "content": { "application/json": { "schema": {
+                "oneOf": [openapi_ref("schemas", &insert), { "type": "array", "items": openapi_ref("schemas", &insert) }]
+            } } }
+        });
+        let set_body = serde_json::json!({
+            "required": true,
+            "content": { "application/json": { "schema": openapi_ref("schemas", name) } }
+        });
+        
+        let mut all = serde_json::Map::new();
+        all.insert("get".to_string(), operation(format!("list_{}", name), format!("List {} rows", name), &read_refs, ("200", &page)));
+        let mut filtered = serde_json::Map::new();
+        filtered.insert("get".to_string(), operation(format!("filter_{}", name), format!("List {} rows matching filters", name), &filtered_refs, ("200", &page)));
+        if config.writes_enabled && !config.read_only_table(name) && updatable {
+            let or = [openapi_ref("parameters", "filter"), openapi_ref("parameters", "or")];
+            let filtered_or = [openapi_ref("parameters", "filters"), openapi_ref("parameters", "filter"), openapi_ref("parameters", "or")];
+            let conflict = [openapi_ref("parameters", "on_conflict"), openapi_ref("parameters", "conflict_columns")];
+            let mut insert = operation(format!("insert_{}", name), format!("Insert {} rows", name), &conflict, ("201", &rows));
+            insert["requestBody"] = body;
+            all.insert("post".to_string(), insert);
+            let mut update = operation(format!("update_all_{}", name), format!("Update every {} row", name), &or, ("200", &rows));
+            update["requestBody"] = set_body.clone();
+            all.insert("patch".to_string(), update);
+            all.insert("delete".to_string(), operation(format!("delete_all_{}", name), format!("Delete every {} row, with confirm_all=true", name), &or, ("200", &rows)));
+            let mut update = operation(format!("update_{}", name), format!("Update {} rows matching filters", name), &filtered_or, ("200", &rows));
+            update["requestBody"] = set_body;
+            filtered.insert("patch".to_string(), update);
+            filtered.insert("delete".to_string(), operation(format!("delete_{}", name), format!("Delete {} rows matching filters", name), &filtered_or, ("200", &rows)));
+        }
+        let aggregate_refs: Vec<serde_json::Value> = ["group_by", "agg", "filter", "or", "page", "page_size", "bucket", "time_column", "from", "to", "compare", "timeout_ms", "consistency"]
+            .iter()
+            .map(|p| openapi_ref("parameters", p))
+            .collect();
+        let aggregate = openapi_json_response("Aggregated groups", serde_json::json!({ "type": "object" }));
+        paths.insert(format!("/{}", name), serde_json::Value::Object(all));
+        paths.insert(format!("/{}/aggregate", name), serde_json::json!({
+            "get": operation(format!("aggregate_{}", name), format!("Aggregate {} rows", name), &aggregate_refs, ("200", &aggregate))
+        }));
+        paths.insert(format!("/{}/{{filters}}", name), serde_json::Value::Object(filtered));
+        if table_config.is_some_and(|t| t.history.is_some()) {
+            let history = openapi_json_response("Versions of the row, newest first", serde_json::json!({ "type": "object" }));
+            let history_refs = [
+                serde_json::json!({ "name": "id", "in": "path", "required": true, "schema": { "type": "string" } }),
+                serde_json::json!({ "name": "until", "in": "query", "schema": { "type": "string" } }),
+                serde_json::json!({ "name": "limit", "in": "query", "schema": { "type": "integer" } }),
+            ];
+            paths.insert(format!("/{}/{{id}}/history", name), serde_json::json!({
+                "get": operation(format!("history_{}", name), format!("Change history of a {} row", name), &history_refs, ("200", &history))
+            }));
+        }
+        if table_config.is_some_and(|t| t.changes.is_some()) {
+            let changes = serde_json::json!({
+                "description": "Server-sent events named insert, update or delete",
+                "content": { "text/event-stream": { "schema": { "type": "string" } } }
+            });
+            paths.insert(format!("/{}/_changes", name), serde_json::json!({
+                "get": operation(format!("changes_{}", name), format!("Stream changes of {} rows", name), &[], ("200", &changes))
+            }));
+        }
+    }
+    paths.insert("/health".to_string(), serde_json::json!({
+        "get": { "operationId": "health", "summary": "Liveness check", "security": [], "responses": { "200": { "description": "Running" } } }
+    }));
+    paths.insert("/readyz".to_string(), serde_json::json!({
+        "get": { "operationId": "readiness", "summary": "Database readiness", "security": [], "responses": {
+            "200": { "description": "Ready" }, "503": { "description": "A database is unreachable" }
+        } }
+    }));
+    paths.insert("/health/ready".to_string(), serde_json::json!({
+        "get": { "operationId": "healthReady", "summary": "Database readiness with pool stats", "security": [], "responses": {
+            "200": { "description": "Ready" }, "503": { "description": "A database is unreachable" }
+        } }
+    }));
+    
+    let mut document = serde_json::json!({
+        "openapi": "3.0.3",
+        "info": { "title": APPLICATION_NAME, "version": env!("CARGO_PKG_VERSION") },
+        "tags": tags,
+        "paths": paths,
+        "components": { "schemas": schemas, "parameters": parameters }
+    });
+    // Either scheme is accepted when both are configured
+    let mut schemes = serde_json::Map::new();
+    if !config.api_keys.is_empty() || config.api_keys_table.is_some() {
+        schemes.insert("apiKey".to_string(), serde_json::json!({ "type": "apiKey", "in": "header", "name": "X-API-Key" }));
+    }
+    if config.jwt.is_some() {
+        schemes.insert("bearer".to_string(), serde_json::json!({ "type": "http", "scheme": "bearer", "bearerFormat": "JWT" }));
+    }
+    if !schemes.is_empty() {
+        document["security"] = schemes.keys().map(|name| serde_json::json!({ name: [] })).collect();
+        document["components"]["securitySchemes"] = serde_json::Value::Object(schemes);
+    }
+    Ok(document)
+}
+
+pub(crate) async fn openapi_spec(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    exposure: Option<web::Data<Exposure>>,
+) -> impl Responder {
+    let document = match fetch_table_names(&pool).await {
+        Ok(names) => {
+            let mut tables: Vec<String> = names
+                .into_iter()
+                .filter(|name| exposure.as_ref().is_none_or(|e| e.is_exposed(name)))
+                .collect();
+            tables.sort();
+            openapi_document(&pool, &config, &tables).await
+        }
+        Err(e) => Err(e),
+    };
+    match document {
+        Ok(document) => HttpResponse::Ok().json(document),
+        Err(e) => {
+            log::error!("Database error: {}", e);
+            error_response(&req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+
+    #[actix_web::test]
+    async fn openapi_types_follow_the_rendering() {
+        assert_eq!(openapi_column_schema("bigint", false), serde_json::json!({ "type": "integer", "format": "int64" }));
+        assert_eq!(openapi_column_schema("numeric(10,2)", false), serde_json::json!({ "type": "number" }));
+        assert_eq!(openapi_column_schema("numeric(10,2)", true), serde_json::json!({ "type": "string", "format": "decimal" }));
+        assert_eq!(
+            openapi_column_schema("timestamp with time zone", false),
+            serde_json::json!({ "type": "string", "format": "date-time" })
+        );
+        assert_eq!(
+            openapi_column_schema("integer[]", false),
+            serde_json::json!({ "type": "array", "items": { "type": "integer", "format": "int32" } })
+        );
+        assert_eq!(openapi_column_schema("inet", false), serde_json::json!({ "type": "string", "description": "inet" }));
+
+        let document = openapi_document(&offline_pool(), &test_config(), &[]).await.unwrap();
+        assert_eq!(document["openapi"], "3.0.3");
+        assert_eq!(document["paths"].as_object().unwrap().keys().collect::<Vec<_>>(), ["/health", "/health/ready", "/readyz"]);
+        assert!(document["components"]["parameters"]["filters"]["required"].as_bool().unwrap());
+    }
+
+    #[test]
+    fn constant_defaults_become_openapi_defaults() {
+        let typed = |kind: &str| serde_json::json!({ "type": kind });
+        assert_eq!(openapi_default("'active'::text", &typed("string")), Some(serde_json::json!("active")));
+        assert_eq!(openapi_default("'it''s'::character varying", &typed("string")), Some(serde_json::json!("it's")));
+        assert_eq!(openapi_default("0", &typed("integer")), Some(serde_json::json!(0)));
+        assert_eq!(openapi_default("(-1.5)::numeric", &typed("number")), Some(serde_json::json!(-1.5)));
+        assert_eq!(openapi_default("'12.50'::numeric", &typed("string")), Some(serde_json::json!("12.50")));
+        assert_eq!(openapi_default("false", &typed("boolean")), Some(serde_json::json!(false)));
+        assert_eq!(openapi_default("NULL::text", &typed("string")), Some(serde_json::Value::Null));
+        assert_eq!(openapi_default("now()", &typed("string")), None);
+        assert_eq!(openapi_default("nextval('loans_id_seq'::regclass)", &typed("integer")), None);
+        assert_eq!(openapi_default("'a' || 'b'", &typed("string")), None);
+        assert_eq!(openapi_default("'x'::text", &typed("integer")), None);
+    }
+}
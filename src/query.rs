@@ -0,0 +1,3794 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use futures_util::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::{Column, Executor, PgPool, Row, TypeInfo};
+use crate::access::{
+    quota_max_page_size, quota_max_rows, quota_page_size, table_grant_message, Exposure,
+    TokenGrants,
+};
+use crate::background::ACTOR_HEADER;
+use crate::config::{Config, Mask};
+use crate::filters::{
+    bind_filters, build_where_clause, live_rows, parse_sort, resolve_collation,
+    sanitize_column_name, search_condition, split_group_items, split_sort_modifiers, tsquery_sql,
+    validate_sort_order, FilterExpr, FilterValue, SPATIAL_TYPES,
+};
+use crate::handlers::{is_admin, reject_non_admin, TableLimits};
+use crate::hooks::request_hooks;
+use crate::messages::{
+    bad_request, error_response, error_response_with, render_message, Message, Translations,
+};
+use crate::middleware::{log_binds, note_read, CircuitBreaker, DatabaseName, Databases, Replica};
+use crate::serialization::{
+    csv_response, file_response, geojson_response, protect_columns, record_batch, response_format,
+    row_to_json, stream_response, write_arrow, write_page, write_parquet, write_xlsx,
+    JsonPageWriter, PageMeta, ResponseFormat, StreamFraming, ValueFormat, ARROW_CONTENT_TYPE,
+    XLSX_CONTENT_TYPE,
+};
+use crate::writes::{database_error_response, quote_identifier};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct QueryResult {
+    pub(crate) data: Vec<serde_json::Value>,
+    pub(crate) count: usize,
+    pub(crate) page: usize,
+    pub(crate) page_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) total_count: Option<usize>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) total_count_estimated: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) total_pages: Option<usize>,
+    #[serde(default)]
+    pub(crate) has_next: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) result_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) next_cursor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) sort_fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) warnings: Vec<String>,
+}
+
+impl QueryResult {
+    // The page as JSON with total_pages, has_next and its Link header
+    pub(crate) fn into_response(mut self, req: &HttpRequest, mut builder: actix_web::HttpResponseBuilder) -> HttpResponse {
+        let position = PagePosition::of(req, self.page, self.page_size, self.count, self.total_count, self.next_cursor.as_deref());
+        self.total_pages = position.total_pages;
+        self.has_next = position.has_next;
+        builder.insert_header((actix_web::http::header::LINK, position.links));
+        builder.json(self)
+    }
+}
+
+// Where a page stands among the others: total_pages when the total is known,
+// whether there is a next page, and an RFC 8288 Link header with the next,
+// prev, first and last pages as URLs relative to the request's.
+pub(crate) struct PagePosition {
+    pub(crate) total_pages: Option<usize>,
+    pub(crate) has_next: bool,
+    pub(crate) links: String,
+}
+
+impl PagePosition {
+    // Without a total, a full page is taken to have a next one. Keyset pages
+    // (`?cursor=`) only link to the next cursor and the first page.
+    pub(crate) fn of(
+        req: &HttpRequest,
+        page: usize,
+        page_size: usize,
+        count: usize,
+        total_count: Option<usize>,
+        next_cursor: Option<&str>,
+    ) -> Self {
+        let keyset = req.query_string().split('&').any(|p| p.split('=').next() == Some("cursor"));
+        let total_pages = total_count.map(|total| total.div_ceil(page_size.max(1)));
+        let has_next = match (keyset, total_pages) {
+            (true, _) => next_cursor.is_some(),
+            (false, Some(pages)) => page < pages,
+            (false, None) => count > 0 && count == page_size,
+        };
+        let mut links = Vec::new();
+        if keyset {
+            if let Some(cursor) = next_cursor {
+                links.push((page_url(req, "cursor", cursor), "next"));
+            }
+            links.push((page_url(req, "cursor", ""), "first"));
+        } else {
+            if has_next {
+                links.push((page_url(req, "page", &(page + 1).to_string()), "next"));
+            }
+            if page > 1 {
+                let prev = total_pages.map_or(page - 1, |pages| (page - 1).min(pages.max(1)));
+                links.push((page_url(req, "page", &prev.to_string()), "prev"));
+            }
+            links.push((page_url(req, "page", "1"), "first"));
+            if let Some(pages) = total_pages {
+                links.push((page_url(req, "page", &pages.max(1).to_string()), "last"));
+            }
+        }
+        let links = links.iter().map(|(url, rel)| format!("<{}>; rel=\"{}\"", url, rel)).collect::<Vec<_>>().join(", ");
+        PagePosition { total_pages, has_next, links }
+    }
+}
+
+// The request's path and query with `param` set to `value`, dropping the
+// page and cursor it had
+pub(crate) fn page_url(req: &HttpRequest, param: &str, value: &str) -> String {
+    let mut query: Vec<&str> = req
+        .query_string()
+        .split('&')
+        .filter(|p| !p.is_empty() && !matches!(p.split('=').next(), Some("page" | "cursor")))
+        .collect();
+    let set = format!("{}={}", param, urlencoding::encode(value));
+    query.push(&set);
+    format!("{}?{}", req.path(), query.join("&"))
+}
+
+pub(crate) type PgQuery<'q> = sqlx::query::Query<'q, sqlx::Postgres, sqlx::postgres::PgArguments>;
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct QueryParams {
+    pub(crate) page: Option<usize>,
+    pub(crate) page_size: Option<usize>,
+    pub(crate) sort: Option<String>,
+    pub(crate) order: Option<String>,
+    pub(crate) collate: Option<String>,
+    pub(crate) materialize: Option<bool>,
+    pub(crate) select: Option<String>,
+    // An OR group AND-ed with the path filters, e.g. `(status=active,status=pending)`
+    pub(crate) or: Option<String>,
+    // Keyset pagination: empty for the first page, then the previous next_cursor
+    pub(crate) cursor: Option<String>,
+    // json (default), csv, ndjson, parquet, geojson, xlsx or arrow
+    pub(crate) format: Option<String>,
+    // Leaves bytea columns out of the result
+    pub(crate) omit_binary: Option<bool>,
+    // IANA time zone timestamptz values are rendered in, UTC by default
+    pub(crate) tz: Option<String>,
+    // Related rows to nest, e.g. `customer,items`, see embed_select
+    pub(crate) embed: Option<String>,
+    // Drops duplicate rows, or keeps one row per distinct_on columns, see
+    // Distinct
+    pub(crate) distinct: Option<bool>,
+    pub(crate) distinct_on: Option<String>,
+    // Full-text search over search_columns (the table's tsvector columns by
+    // default), see search_condition; `sort=rank` orders by relevance
+    pub(crate) search: Option<String>,
+    pub(crate) search_columns: Option<String>,
+    // Text search configuration such as `english`, the server's default
+    // otherwise
+    pub(crate) search_config: Option<String>,
+    // What to do when the read matches more than max_rows: reject (default)
+    // or summarize
+    pub(crate) on_overflow: Option<String>,
+    // NDJSON exports: emit resume tokens every EXPORT_CHECKPOINT_ROWS rows,
+    // and continue an interrupted export after one
+    pub(crate) checkpoints: Option<bool>,
+    pub(crate) resume_token: Option<String>,
+    // JSON: stream every matching row as a bare array instead of a page
+    pub(crate) stream: Option<bool>,
+    // How total_count is computed: exact (default), estimated or none
+    pub(crate) count: Option<String>,
+    // statement_timeout for this read, see apply_request_timeout
+    pub(crate) timeout_ms: Option<u64>,
+    // `semantics` adds a debug section, see semantics_report
+    pub(crate) debug: Option<String>,
+    // A configured preset of the table, AND-ed with the path filters
+    pub(crate) preset: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum CountMode {
+    Exact,
+    // From pg_class.reltuples for unfiltered tables, otherwise from the
+    // planner's row estimate
+    Estimated,
+    None,
+}
+
+impl CountMode {
+    pub(crate) fn parse(value: Option<&str>) -> Result<CountMode, Message> {
+        match value {
+            None | Some("exact") => Ok(CountMode::Exact),
+            Some("estimated") => Ok(CountMode::Estimated),
+            Some("none") => Ok(CountMode::None),
+            Some(other) => Err(Message::new("invalid_count").arg("value", other)),
+        }
+    }
+}
+
+// Parses `?select=id,name` into a column list for the SELECT clause; no
+// select parameter means `*`.
+// `*` includes the table's virtual columns. With the table's `columns` from
+// fetch_select_columns, geometries are selected as GeoJSON and `omit_binary`
+// leaves out bytea columns; `*` is spelled out when either applies.
+pub(crate) fn parse_select(
+    config: &Config,
+    table: &str,
+    select: Option<&str>,
+    columns: &[(String, String)],
+    omit_binary: bool,
+) -> Result<String, Message> {
+    let type_of = |column: &str| columns.iter().find(|(name, _)| name == column).map(|(_, t)| t.as_str());
+    let spatial = |column: &str| type_of(column).is_some_and(|t| SPATIAL_TYPES.contains(&t));
+    let omitted = |column: &str| omit_binary && type_of(column) == Some("bytea");
+    let geojson = |column: &str| format!("ST_AsGeoJSON({})::json AS {}", column, column);
+    let Some(select) = select else {
+        let spelled_out = columns.iter().any(|(name, _)| spatial(name) || omitted(name));
+        let mut columns = match spelled_out {
+            false => vec!["*".to_string()],
+            true => columns
+                .iter()
+                .filter(|(name, _)| !omitted(name))
+                .map(|(name, _)| match spatial(name) {
+                    true => geojson(&quote_identifier(name)),
+                    false => quote_identifier(name),
+                })
+                .collect(),
+        };
+        if let Some(table_config) = config.table(table.trim_matches('"')) {
+            for (name, expr) in &table_config.virtual_columns {
+                columns.push(format!("({}) AS {}", expr, name));
+            }
+        }
+        return Ok(columns.join(", "));
+    };
+    
+    let mut selected = Vec::new();
+    for column in select.split(',').map(str::trim) {
+        if column.is_empty() {
+            return Err(Message::new("empty_select"));
+        }
+        let (column, alias) = match column.split_once(':') {
+            Some((column, alias)) => (column.trim(), Some(alias.trim())),
+            None => (column, None),
+        };
+        let column = sanitize_column_name(column)?;
+        let lower = column.to_lowercase();
+        if omitted(&lower) {
+            continue;
+        }
+        let alias = match alias {
+            Some(alias) => select_alias(config, table, &lower, alias, columns)?,
+            None => None,
+        };
+        let name = alias.clone().unwrap_or_else(|| lower.clone());
+        selected.push(match config.virtual_column(table, &column) {
+            Some(expr) => format!("({}) AS {}", expr, name),
+            None if spatial(&lower) => format!("ST_AsGeoJSON({})::json AS {}", column, name),
+            None => match alias {
+                Some(alias) => format!("{} AS {}", column, alias),
+                None => column,
+            },
+        });
+    }
+    if selected.is_empty() {
+        return Err(Message::new("select_only_binary"));
+    }
+    Ok(selected.join(", "))
+}
+
+// The quoted alias of `select=column:alias`, None when it renames nothing.
+// Aliases keep their case, can't take the name of another column of the
+// table, which filters, sorts and protection refer to, and can't rename a
+// masked or encrypted column out of its protection.
+pub(crate) fn select_alias(
+    config: &Config,
+    table: &str,
+    column: &str,
+    alias: &str,
+    columns: &[(String, String)],
+) -> Result<Option<String>, Message> {
+    if alias.is_empty() || sanitize_column_name(alias).is_err() || alias.starts_with("__datapi") {
+        return Err(Message::new("invalid_alias").arg("alias", alias));
+    }
+    if alias == column {
+        return Ok(None);
+    }
+    let table_config = config.table(table.trim_matches('"'));
+    if config.mask(table, column).is_some() || table_config.is_some_and(|t| t.encrypted_columns.iter().any(|c| c == column)) {
+        return Err(Message::new("alias_protected").arg("column", column));
+    }
+    let lower = alias.to_lowercase();
+    let taken = columns.iter().any(|(name, _)| *name == lower)
+        || config.virtual_column(table, &lower).is_some()
+        || config.mask(table, &lower).is_some();
+    if taken {
+        return Err(Message::new("alias_conflict").arg("alias", alias).arg("table", table.trim_matches('"')));
+    }
+    Ok(Some(quote_identifier(alias)))
+}
+
+// The table's columns of a string type, which a collation applies to
+pub(crate) async fn fetch_text_columns(pool: &PgPool, table: &str) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT a.attname::text
+         FROM pg_attribute a
+         JOIN pg_type t ON t.oid = a.atttypid
+         WHERE a.attrelid = to_regclass($1) AND a.attnum > 0 AND NOT a.attisdropped AND t.typcategory = 'S'",
+    )
+    .bind(table)
+    .fetch_all(pool)
+    .await
+}
+
+// fetch_text_columns through the request's schema cache
+pub(crate) async fn is_text_column(req: &HttpRequest, pool: &PgPool, table: &str, column: &str) -> Result<bool, sqlx::Error> {
+    let cache = req.app_data::<web::Data<SchemaCache>>().filter(|cache| !cache.ttl.is_zero());
+    let key = SchemaCache::key(req, table);
+    let columns = match cache.and_then(|cache| cache.get(&cache.text_columns, &key)) {
+        Some(columns) => columns,
+        None => {
+            let columns = fetch_text_columns(pool, table).await?;
+            if let Some(cache) = cache {
+                cache.text_columns.lock().unwrap().insert(key, (Instant::now(), columns.clone()));
+            }
+            columns
+        }
+    };
+    Ok(columns.contains(&column.to_lowercase()))
+}
+
+// application_name of every pooled connection, used to find API-issued
+// queries in pg_stat_activity.
+pub(crate) const APPLICATION_NAME: &str = "datapi";
+
+// Prefixes a generated statement with a comment naming its table, so running
+// queries can be attributed from pg_stat_activity. The table name is already
+// sanitized and cannot close the comment.
+pub(crate) fn tag_query(table: &str, sql: &str) -> String {
+    format!("/* datapi table={} */ {}", table, sql)
+}
+
+pub(crate) const UNSTABLE_ORDER_WARNING: &str =
+    "299 - \"Row order is not stable across pages; sort by a unique column\"";
+
+// Returns the column lists of the table's unique keys whose columns are all
+// NOT NULL, primary key first.
+pub(crate) async fn fetch_unique_keys(pool: &PgPool, table: &str) -> Result<Vec<Vec<String>>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT array_agg(a.attname::text ORDER BY k.ord) AS columns
+         FROM pg_index i
+         CROSS JOIN LATERAL unnest(i.indkey) WITH ORDINALITY AS k(attnum, ord)
+         JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = k.attnum
+         WHERE i.indrelid = to_regclass($1)
+           AND i.indisunique
+           AND i.indpred IS NULL
+           AND i.indexprs IS NULL
+         GROUP BY i.indexrelid, i.indisprimary
+         HAVING bool_and(a.attnotnull)
+         ORDER BY i.indisprimary DESC",
+    )
+    .bind(table)
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter().map(|row| row.try_get("columns")).collect()
+}
+
+// fetch_unique_keys through the request's schema cache
+pub(crate) async fn unique_keys(req: &HttpRequest, pool: &PgPool, table: &str) -> Result<Vec<Vec<String>>, sqlx::Error> {
+    let cache = req.app_data::<web::Data<SchemaCache>>().filter(|cache| !cache.ttl.is_zero());
+    let key = SchemaCache::key(req, table);
+    if let Some(keys) = cache.and_then(|cache| cache.get(&cache.unique_keys, &key)) {
+        return Ok(keys);
+    }
+    let keys = fetch_unique_keys(pool, table).await?;
+    if let Some(cache) = cache {
+        cache.unique_keys.lock().unwrap().insert(key, (Instant::now(), keys.clone()));
+    }
+    Ok(keys)
+}
+
+// The single-column primary key filled by an identity or a serial default,
+// if the table has one
+pub(crate) async fn fetch_generated_key(pool: &PgPool, table: &str) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT a.attname::text
+         FROM pg_index i
+         JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = i.indkey[0]
+         LEFT JOIN pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum
+         WHERE i.indrelid = to_regclass($1)
+           AND i.indisprimary
+           AND i.indnkeyatts = 1
+           AND (a.attidentity <> '' OR pg_get_expr(d.adbin, d.adrelid) LIKE 'nextval(%')",
+    )
+    .bind(table)
+    .fetch_optional(pool)
+    .await
+}
+
+// Builds the ORDER BY clause, appending the table's primary (or first usable
+// unique) key as a tiebreaker so that OFFSET pages never repeat or skip rows.
+// The returned flag is false when no stable order could be guaranteed.
+// A collation is only applied when the sort column is a text column.
+pub(crate) async fn build_order_by(
+    req: &HttpRequest,
+    pool: &PgPool,
+    table: &str,
+    sort_column: Option<&str>,
+    sort_order: &str,
+    nulls: Option<&'static str>,
+    collation: Option<&str>,
+) -> OrderBy {
+    let keys = match unique_keys(req, pool, table).await {
+        Ok(k) => k,
+        Err(e) => {
+            log::error!("Unique key lookup error: {}", e);
+            Vec::new()
+        }
+    };
+
+    let mut order = OrderBy {
+        terms: Vec::new(),
+        direction: sort_order.to_string(),
+        stable: false,
+    };
+    if let Some(col) = sort_column {
+        let term_collation = match collation {
+            Some(c) => match is_text_column(req, pool, table, col).await {
+                Ok(true) => Some(c.to_string()),
+                Ok(false) => None,
+                Err(e) => {
+                    log::error!("Column type lookup error: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        order.terms.push(OrderTerm { column: col.to_string(), collation: term_collation, nulls });
+
+        let col_lower = col.to_lowercase();
+        if keys.iter().any(|k| k.len() == 1 && k[0] == col_lower) {
+            order.stable = true;
+            return order;
+        }
+    }
+
+    if let Some(key) = keys.first() {
+        let sorted = sort_column.map(|c| c.to_lowercase());
+        for key_col in key {
+            if sorted.as_deref() != Some(key_col.as_str()) {
+                order.terms.push(OrderTerm { column: key_col.clone(), collation: None, nulls: None });
+            }
+        }
+        order.stable = true;
+    }
+    order
+}
+
+// Column name -> (SQL type, NOT NULL)
+pub(crate) async fn fetch_column_types(
+    pool: &PgPool,
+    table: &str,
+) -> Result<HashMap<String, (String, bool)>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT attname::text AS name, format_type(atttypid, atttypmod) AS type, attnotnull
+         FROM pg_attribute
+         WHERE attrelid = to_regclass($1) AND attnum > 0 AND NOT attisdropped",
+    )
+    .bind(table)
+    .fetch_all(pool)
+    .await?;
+    
+    rows.iter()
+        .map(|row| Ok((row.try_get("name")?, (row.try_get("type")?, row.try_get("attnotnull")?))))
+        .collect()
+}
+
+// Column metadata of the tables requests touch, kept for
+// DATAPI_SCHEMA_CACHE_SECS and dropped by POST /_schema/reload. Keyed by
+// database, empty for the primary one, and table.
+#[derive(Default)]
+pub(crate) struct SchemaCache {
+    pub(crate) ttl: Duration,
+    pub(crate) column_types: SchemaEntries<HashMap<String, (String, bool)>>,
+    pub(crate) select_columns: SchemaEntries<Vec<(String, String)>>,
+    pub(crate) unique_keys: SchemaEntries<Vec<Vec<String>>>,
+    pub(crate) text_columns: SchemaEntries<Vec<String>>,
+}
+
+// (database, table) -> when it was looked up, what was found
+pub(crate) type SchemaEntries<T> = Mutex<HashMap<(String, String), (Instant, T)>>;
+
+impl SchemaCache {
+    pub(crate) fn key(req: &HttpRequest, table: &str) -> (String, String) {
+        let database = req.extensions().get::<DatabaseName>().map(|name| name.0.clone()).unwrap_or_default();
+        (database, table.to_string())
+    }
+    
+    pub(crate) fn get<T: Clone>(&self, entries: &SchemaEntries<T>, key: &(String, String)) -> Option<T> {
+        let entries = entries.lock().unwrap();
+        entries.get(key).filter(|(at, _)| at.elapsed() < self.ttl).map(|(_, value)| value.clone())
+    }
+    
+    pub(crate) fn clear(&self) {
+        self.column_types.lock().unwrap().clear();
+        self.select_columns.lock().unwrap().clear();
+        self.unique_keys.lock().unwrap().clear();
+        self.text_columns.lock().unwrap().clear();
+    }
+}
+
+// fetch_column_types through the request's schema cache
+pub(crate) async fn column_types(
+    req: &HttpRequest,
+    pool: &PgPool,
+    table: &str,
+) -> Result<HashMap<String, (String, bool)>, sqlx::Error> {
+    let cache = req.app_data::<web::Data<SchemaCache>>().filter(|cache| !cache.ttl.is_zero());
+    let key = SchemaCache::key(req, table);
+    if let Some(columns) = cache.and_then(|cache| cache.get(&cache.column_types, &key)) {
+        return Ok(columns);
+    }
+    let columns = fetch_column_types(pool, table).await?;
+    if let Some(cache) = cache {
+        cache.column_types.lock().unwrap().insert(key, (Instant::now(), columns.clone()));
+    }
+    Ok(columns)
+}
+
+// fetch_select_columns through the request's schema cache
+pub(crate) async fn select_columns(req: &HttpRequest, pool: &PgPool, table: &str) -> Result<Vec<(String, String)>, sqlx::Error> {
+    let cache = req.app_data::<web::Data<SchemaCache>>().filter(|cache| !cache.ttl.is_zero());
+    let key = SchemaCache::key(req, table);
+    if let Some(columns) = cache.and_then(|cache| cache.get(&cache.select_columns, &key)) {
+        return Ok(columns);
+    }
+    let columns = fetch_select_columns(pool, table).await?;
+    if let Some(cache) = cache {
+        cache.select_columns.lock().unwrap().insert(key, (Instant::now(), columns.clone()));
+    }
+    Ok(columns)
+}
+
+// Whether Postgres can write to a relation (`c` in pg_class): views and
+// foreign tables when they're updatable (automatically, or through INSTEAD
+// OF triggers or rules), materialized views never
+pub(crate) const RELATION_UPDATABLE_SQL: &str = "CASE WHEN c.relkind IN ('v', 'f') THEN pg_relation_is_updatable(c.oid::regclass, true) > 0
+              ELSE c.relkind <> 'm' END";
+
+pub(crate) fn relation_kind_name(relkind: &str) -> &'static str {
+    match relkind {
+        "r" => "table",
+        "v" => "view",
+        "m" => "materialized_view",
+        "f" => "foreign_table",
+        _ => "partitioned_table",
+    }
+}
+
+// None when the relation doesn't exist
+pub(crate) async fn fetch_relation_updatable(pool: &PgPool, table: &str) -> Result<Option<bool>, sqlx::Error> {
+    sqlx::query_scalar(&format!(
+        "SELECT {} FROM pg_class c WHERE c.oid = to_regclass($1)",
+        RELATION_UPDATABLE_SQL
+    ))
+    .bind(table)
+    .fetch_optional(pool)
+    .await
+}
+
+// The table's columns in order with their type names (`pg_type.typname`,
+// e.g. `geometry` or `bytea`), see parse_select
+pub(crate) async fn fetch_select_columns(pool: &PgPool, table: &str) -> Result<Vec<(String, String)>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT a.attname::text AS name, t.typname::text AS type
+         FROM pg_attribute a JOIN pg_type t ON t.oid = a.atttypid
+         WHERE a.attrelid = to_regclass($1) AND a.attnum > 0 AND NOT a.attisdropped
+         ORDER BY a.attnum",
+    )
+    .bind(table)
+    .fetch_all(pool)
+    .await?;
+    
+    rows.iter()
+        .map(|row| Ok((row.try_get("name")?, row.try_get("type")?)))
+        .collect()
+}
+
+// A foreign key between a table and another one
+#[derive(Debug)]
+pub(crate) struct Relation {
+    // The other table's name, and how to write it in SQL
+    pub(crate) name: String,
+    pub(crate) sql: String,
+    // (this table's column, the other table's column) pairs
+    pub(crate) columns: Vec<(String, String)>,
+    // The other table references this one, so its rows embed as an array
+    pub(crate) many: bool,
+    // The other table's primary key
+    pub(crate) key: Vec<String>,
+}
+
+impl Relation {
+    // By the other table's name, or a referencing column without `_id`:
+    // `customer` for `orders.customer_id -> customers`
+    pub(crate) fn matches(&self, embed: &str) -> bool {
+        let by_column = match self.columns.as_slice() {
+            [(local, _)] if !self.many => local.strip_suffix("_id") == Some(embed),
+            _ => false,
+        };
+        self.name == embed || by_column
+    }
+    
+    // The other table as exposure and grants name it: schema-qualified when
+    // it's off the search path
+    pub(crate) fn qualified_name(&self) -> String {
+        self.sql.replace('"', "")
+    }
+    
+    // The join to the outer table's row and the embedded table's filters
+    pub(crate) fn conditions(&self, table: &str, filters: &[String]) -> String {
+        let mut conditions: Vec<String> = self
+            .columns
+            .iter()
+            .map(|(local, remote)| format!("e.{} = {}.{}", quote_identifier(remote), table, quote_identifier(local)))
+            .collect();
+        conditions.extend(filters.iter().map(|f| format!("({})", f)));
+        conditions.join(" AND ")
+    }
+    
+    // A correlated subquery selecting the related row as a JSON object, or
+    // a page of related rows as an array. Pages follow the primary key, so
+    // they neither repeat nor skip rows.
+    pub(crate) fn select_sql(&self, table: &str, embed: &str, filters: &[String], limit: usize, offset: usize) -> String {
+        let conditions = self.conditions(table, filters);
+        match self.many {
+            false => format!(
+                "(SELECT row_to_json(e) FROM {} e WHERE {} LIMIT 1) AS {}",
+                self.sql, conditions, quote_identifier(embed)
+            ),
+            true => format!(
+                "(SELECT coalesce(json_agg(e), '[]'::json) FROM (SELECT * FROM {} e WHERE {}{} LIMIT {}{}) e) AS {}",
+                self.sql,
+                conditions,
+                match self.key.is_empty() {
+                    true => String::new(),
+                    false => format!(" ORDER BY {}", self.key.iter().map(|c| format!("e.{}", quote_identifier(c))).collect::<Vec<_>>().join(", ")),
+                },
+                limit,
+                match offset {
+                    0 => String::new(),
+                    offset => format!(" OFFSET {}", offset),
+                },
+                quote_identifier(embed)
+            ),
+        }
+    }
+    
+    // The number of related rows of a paged list embed, as `{embed}_count`
+    pub(crate) fn count_sql(&self, table: &str, embed: &str, filters: &[String]) -> String {
+        format!(
+            "(SELECT count(*) FROM {} e WHERE {}) AS {}",
+            self.sql,
+            self.conditions(table, filters),
+            quote_identifier(&format!("{}_count", embed))
+        )
+    }
+}
+
+// A list embed's page, `orders(page=2,page_size=10)`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct EmbedPage {
+    pub(crate) page: usize,
+    pub(crate) page_size: Option<usize>,
+}
+
+// An embed name and its paging, if it has any
+pub(crate) fn parse_embed(item: &str) -> Result<(&str, Option<EmbedPage>), Message> {
+    let Some((name, options)) = item.split_once('(') else {
+        return Ok((item, None));
+    };
+    let name = name.trim();
+    let invalid = |option: &str| Message::new("invalid_embed_option").arg("embed", name).arg("option", option.trim());
+    let options = options.strip_suffix(')').ok_or_else(|| invalid(options))?;
+    let mut page = EmbedPage { page: 1, page_size: None };
+    for option in options.split(',') {
+        let value = match option.split_once('=') {
+            Some((_, value)) => value.trim().parse::<usize>().ok().filter(|n| *n > 0),
+            None => None,
+        };
+        match (option.split_once('=').map(|(key, _)| key.trim()), value) {
+            (Some("page"), Some(value)) => page.page = value,
+            (Some("page_size"), Some(value)) => page.page_size = Some(value),
+            _ => return Err(invalid(option)),
+        }
+    }
+    Ok((name, Some(page)))
+}
+
+// The foreign keys from and to a table
+pub(crate) async fn fetch_relations(pool: &PgPool, table: &str) -> Result<Vec<Relation>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT other.relname::text AS name, other.oid::regclass::text AS sql,
+                c.conrelid = to_regclass($1) AS outgoing,
+                ARRAY(SELECT a.attname::text FROM unnest(c.conkey) WITH ORDINALITY k(attnum, n)
+                      JOIN pg_attribute a ON a.attrelid = c.conrelid AND a.attnum = k.attnum ORDER BY k.n) AS referencing,
+                ARRAY(SELECT a.attname::text FROM unnest(c.confkey) WITH ORDINALITY k(attnum, n)
+                      JOIN pg_attribute a ON a.attrelid = c.confrelid AND a.attnum = k.attnum ORDER BY k.n) AS referenced,
+                ARRAY(SELECT a.attname::text FROM pg_index i CROSS JOIN LATERAL unnest(i.indkey) WITH ORDINALITY k(attnum, n)
+                      JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = k.attnum
+                      WHERE i.indrelid = other.oid AND i.indisprimary ORDER BY k.n) AS key
+         FROM pg_constraint c
+         JOIN pg_class other
+           ON other.oid = CASE WHEN c.conrelid = to_regclass($1) THEN c.confrelid ELSE c.conrelid END
+         WHERE c.contype = 'f' AND (c.conrelid = to_regclass($1) OR c.confrelid = to_regclass($1))
+         ORDER BY c.conname",
+    )
+    .bind(table)
+    .fetch_all(pool)
+    .await?;
+    
+    rows.iter()
+        .map(|row| {
+            let outgoing: bool = row.try_get("outgoing")?;
+            let referencing: Vec<String> = row.try_get("referencing")?;
+            let referenced: Vec<String> = row.try_get("referenced")?;
+            let columns = match outgoing {
+                true => referencing.into_iter().zip(referenced).collect(),
+                false => referenced.into_iter().zip(referencing).collect(),
+            };
+            Ok(Relation { name: row.try_get("name")?, sql: row.try_get("sql")?, columns, many: !outgoing, key: row.try_get("key")? })
+        })
+        .collect()
+}
+
+// `?embed=customer,items`: a select column per name with the related row
+// (many-to-one) or rows (one-to-many), optionally a page of them with their
+// count, `items(page=2,page_size=10)`. Tables the caller can't see as
+// configured, through claim filters or encrypted columns, aren't embedded,
+// and ones the token doesn't grant are refused like the table itself.
+pub(crate) fn embed_select(
+    req: &HttpRequest,
+    config: &Config,
+    table: &str,
+    relations: &[Relation],
+    embed: &str,
+) -> Result<Vec<String>, Message> {
+    let exposure = req.app_data::<web::Data<Exposure>>();
+    let mut columns = Vec::new();
+    let items = split_group_items(embed).map_err(|_| match embed.split(',').any(|item| item.trim().is_empty()) {
+        true => Message::new("empty_select"),
+        false => Message::new("invalid_embed_option").arg("embed", embed).arg("option", embed),
+    })?;
+    for item in items {
+        let (name, page) = parse_embed(item.trim())?;
+        if name.is_empty() {
+            return Err(Message::new("empty_select"));
+        }
+        let name = sanitize_column_name(name)?.to_lowercase();
+        let matching: Vec<&Relation> = relations
+            .iter()
+            .filter(|r| r.matches(&name))
+            .filter(|r| exposure.is_none_or(|e| e.is_exposed(&r.qualified_name())))
+            .collect();
+        let relation = match matching.as_slice() {
+            [relation] => relation,
+            [] => return Err(Message::new("unknown_embed").arg("embed", &name).arg("table", table.trim_matches('"'))),
+            _ => return Err(Message::new("ambiguous_embed").arg("embed", &name).arg("table", table.trim_matches('"'))),
+        };
+        if let Some(message) = table_grant_message(req, &relation.qualified_name(), &actix_web::http::Method::GET) {
+            return Err(message);
+        }
+        let other = config.table(&relation.name);
+        if other.is_some_and(|t| !t.claim_filters.is_empty() || !t.encrypted_columns.is_empty() || !t.masked_columns.is_empty()) {
+            return Err(Message::new("embed_restricted").arg("embed", &name));
+        }
+        if page.is_some() && !relation.many {
+            return Err(Message::new("embed_not_list").arg("embed", &name));
+        }
+        let mut filters = other.map(|t| t.filters.clone()).unwrap_or_default();
+        filters.extend(live_rows(req, config, &relation.name)?);
+        let max = quota_page_size(req, config.table_max_page_size(&relation.name));
+        let (limit, offset) = match page {
+            Some(EmbedPage { page, page_size }) => {
+                let size = page_size.unwrap_or(max).min(max);
+                (size, (page - 1) * size)
+            }
+            None => (max, 0),
+        };
+        columns.push(relation.select_sql(table, &name, &filters, limit, offset));
+        if page.is_some() {
+            columns.push(relation.count_sql(table, &name, &filters));
+        }
+    }
+    Ok(columns)
+}
+
+// The COMMENT ON text of a table and of its commented columns
+pub(crate) async fn fetch_comments(
+    pool: &PgPool,
+    table: &str,
+) -> Result<(Option<String>, HashMap<String, String>), sqlx::Error> {
+    let description: Option<String> = sqlx::query_scalar("SELECT obj_description(to_regclass($1), 'pg_class')")
+        .bind(table)
+        .fetch_one(pool)
+        .await?;
+    let rows = sqlx::query(
+        "SELECT attname::text AS name, col_description(attrelid, attnum) AS description
+         FROM pg_attribute
+         WHERE attrelid = to_regclass($1) AND attnum > 0 AND NOT attisdropped
+           AND col_description(attrelid, attnum) IS NOT NULL",
+    )
+    .bind(table)
+    .fetch_all(pool)
+    .await?;
+    let columns = rows
+        .iter()
+        .map(|row| Ok((row.try_get("name")?, row.try_get("description")?)))
+        .collect::<Result<_, sqlx::Error>>()?;
+    Ok((description, columns))
+}
+
+// What the catalog says about writing a column, for the OpenAPI request
+// schemas
+#[derive(Debug, Default)]
+pub(crate) struct ColumnWriteInfo {
+    // pg_get_expr() of the column default
+    pub(crate) default: Option<String>,
+    pub(crate) identity: bool,
+    // Generated columns and GENERATED ALWAYS identities can't be written
+    pub(crate) read_only: bool,
+    // Labels of an enum column, in their sort order
+    pub(crate) labels: Vec<String>,
+}
+
+pub(crate) async fn fetch_column_write_info(pool: &PgPool, table: &str) -> Result<HashMap<String, ColumnWriteInfo>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT a.attname::text AS name,
+                pg_get_expr(d.adbin, d.adrelid) AS default_expr,
+                a.attidentity <> '' AS identity,
+                a.attidentity = 'a' OR a.attgenerated <> '' AS read_only,
+                ARRAY(SELECT e.enumlabel::text FROM pg_enum e
+                      WHERE e.enumtypid = a.atttypid ORDER BY e.enumsortorder) AS labels
+         FROM pg_attribute a
+         LEFT JOIN pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum
+         WHERE a.attrelid = to_regclass($1) AND a.attnum > 0 AND NOT a.attisdropped",
+    )
+    .bind(table)
+    .fetch_all(pool)
+    .await?;
+    rows.iter()
+        .map(|row| {
+            let read_only = row.try_get("read_only")?;
+            let info = ColumnWriteInfo {
+                // A generated column's expression isn't a default
+                default: if read_only { None } else { row.try_get("default_expr")? },
+                identity: row.try_get("identity")?,
+                read_only,
+                labels: row.try_get("labels")?,
+            };
+            Ok((row.try_get("name")?, info))
+        })
+        .collect()
+}
+
+pub(crate) const CURSOR_COLUMN_PREFIX: &str = "__datapi_cursor_";
+
+// Keyset pagination over a stable order: the next page starts after the row
+// whose order values the cursor holds, compared as a row value so Postgres
+// can use the index. The values travel as text and are cast back to the
+// column types, along with the sort fingerprint of the walk.
+pub(crate) struct Keyset {
+    // Row comparison against the cursor, None on the first page
+    pub(crate) predicate: Option<String>,
+    pub(crate) values: Vec<String>,
+    pub(crate) terms: Vec<String>,
+    pub(crate) fingerprint: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CursorPayload {
+    #[serde(rename = "f")]
+    pub(crate) fingerprint: String,
+    #[serde(rename = "v")]
+    pub(crate) values: Vec<String>,
+}
+
+// Identifies the order and filters of a keyset walk. A cursor only continues
+// the walk it came from: after a change to the sort or filters its position
+// means nothing, and the pages would skip or repeat rows.
+pub(crate) fn sort_fingerprint(order_by: &OrderBy, filters: &FilterExpr) -> String {
+    let canonical = format!("{}\n{:?}", order_by.clause(), filters);
+    let digest = <sha2::Sha256 as sha2::Digest>::digest(canonical.as_bytes());
+    digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Keyset {
+    pub(crate) fn new(
+        order_by: &OrderBy,
+        columns: &HashMap<String, (String, bool)>,
+        cursor: &str,
+        first_param: usize,
+        fingerprint: String,
+    ) -> Result<Self, Message> {
+        let mut types = Vec::new();
+        for term in &order_by.terms {
+            match columns.get(&term.column.to_lowercase()) {
+                Some((sql_type, true)) if order_by.stable => types.push(sql_type),
+                _ => return Err(Message::new("cursor_unsupported")),
+            }
+        }
+        if types.is_empty() {
+            return Err(Message::new("cursor_unsupported"));
+        }
+        let terms = order_by.terms.iter().map(OrderTerm::sql).collect();
+        
+        if cursor.is_empty() {
+            return Ok(Keyset { predicate: None, values: Vec::new(), terms, fingerprint });
+        }
+        let payload: CursorPayload = BASE64_URL
+            .decode(cursor)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .filter(|p: &CursorPayload| p.values.len() == types.len())
+            .ok_or_else(|| Message::new("invalid_cursor"))?;
+        if payload.fingerprint != fingerprint {
+            return Err(Message::new("cursor_mismatch").arg("fingerprint", &payload.fingerprint));
+        }
+        let values = payload.values;
+        
+        let placeholders: Vec<String> = order_by
+            .terms
+            .iter()
+            .zip(&types)
+            .enumerate()
+            .map(|(i, (term, sql_type))| {
+                let cast = format!("CAST(${}::text AS {})", first_param + i + 1, sql_type);
+                match &term.collation {
+                    Some(c) => format!("{} COLLATE \"{}\"", cast, c),
+                    None => cast,
+                }
+            })
+            .collect();
+        let comparison = if order_by.direction == "DESC" { "<" } else { ">" };
+        let predicate = format!(
+            "({}) {} ({})",
+            terms.join(", "),
+            comparison,
+            placeholders.join(", ")
+        );
+        Ok(Keyset { predicate: Some(predicate), values, terms, fingerprint })
+    }
+    
+    pub(crate) fn cursor(&self, values: Vec<String>) -> String {
+        let payload = CursorPayload { fingerprint: self.fingerprint.clone(), values };
+        BASE64_URL.encode(serde_json::to_vec(&payload).unwrap_or_default())
+    }
+    
+    pub(crate) fn where_clause(&self, where_clause: &str) -> String {
+        match &self.predicate {
+            Some(p) if where_clause.is_empty() => format!(" WHERE {}", p),
+            Some(p) => format!("{} AND {}", where_clause, p),
+            None => where_clause.to_string(),
+        }
+    }
+    
+    // The order values as text, appended to the select list
+    pub(crate) fn select_columns(&self) -> String {
+        self.terms
+            .iter()
+            .enumerate()
+            .map(|(i, term)| format!(", ({})::text AS {}{}", term, CURSOR_COLUMN_PREFIX, i))
+            .collect()
+    }
+    
+    // Removes the order values from a result row
+    pub(crate) fn take_values(&self, obj: &mut serde_json::Map<String, serde_json::Value>) -> Vec<String> {
+        (0..self.terms.len())
+            .map(|i| match obj.remove(&format!("{}{}", CURSOR_COLUMN_PREFIX, i)) {
+                Some(serde_json::Value::String(s)) => s,
+                _ => String::new(),
+            })
+            .collect()
+    }
+}
+
+pub(crate) struct OrderTerm {
+    pub(crate) column: String,
+    pub(crate) collation: Option<String>,
+    // NULLS FIRST or LAST, from the sort's modifiers
+    pub(crate) nulls: Option<&'static str>,
+}
+
+impl OrderTerm {
+    pub(crate) fn sql(&self) -> String {
+        match &self.collation {
+            Some(c) => format!("{} COLLATE \"{}\"", self.column, c),
+            None => self.column.clone(),
+        }
+    }
+}
+
+// `?distinct=true` drops duplicate rows; `?distinct_on=a,b` keeps the first
+// row per a, b in sort order. `distinct_on` wins when both are given.
+#[derive(Debug)]
+pub(crate) enum Distinct {
+    Rows,
+    On(Vec<String>),
+}
+
+impl Distinct {
+    // `columns` from fetch_select_columns check the distinct_on columns
+    // exist, unless they couldn't be looked up
+    pub(crate) fn parse(
+        config: &Config,
+        table: &str,
+        query_params: &QueryParams,
+        columns: &[(String, String)],
+    ) -> Result<Option<Self>, Message> {
+        let Some(on) = query_params.distinct_on.as_deref() else {
+            return Ok(query_params.distinct.unwrap_or(false).then_some(Distinct::Rows));
+        };
+        let mut terms = Vec::new();
+        for column in on.split(',').map(str::trim) {
+            if column.is_empty() {
+                return Err(Message::new("empty_select"));
+            }
+            let column = sanitize_column_name(column)?.to_lowercase();
+            let term = match config.virtual_column(table, &column) {
+                Some(expr) => format!("({})", expr),
+                None if columns.is_empty() || columns.iter().any(|(name, _)| *name == column) => column,
+                None => {
+                    return Err(Message::new("unknown_column").arg("column", column).arg("table", table.trim_matches('"')))
+                }
+            };
+            terms.push(term);
+        }
+        Ok(Some(Distinct::On(terms)))
+    }
+    
+    pub(crate) fn select_list(&self, select_list: &str) -> String {
+        match self {
+            Distinct::Rows => format!("DISTINCT {}", select_list),
+            Distinct::On(terms) => format!("DISTINCT ON ({}) {}", terms.join(", "), select_list),
+        }
+    }
+    
+    // What the count counts: the distinct rows, or the distinct values of
+    // the distinct_on columns
+    pub(crate) fn count_list(&self, select_list: &str) -> String {
+        match self {
+            Distinct::Rows => format!("DISTINCT {}", select_list),
+            Distinct::On(terms) => format!("DISTINCT {}", terms.join(", ")),
+        }
+    }
+}
+
+// ORDER BY terms, all in the same direction. `stable` means the terms end in
+// a unique key, so the order is total.
+pub(crate) struct OrderBy {
+    pub(crate) terms: Vec<OrderTerm>,
+    pub(crate) direction: String,
+    pub(crate) stable: bool,
+}
+
+impl OrderBy {
+    // DISTINCT ON needs the ORDER BY to start with its expressions
+    pub(crate) fn lead_with(&mut self, terms: &[String]) {
+        self.terms.retain(|t| !terms.iter().any(|term| term.eq_ignore_ascii_case(&t.column)));
+        let leading = terms.iter().map(|term| OrderTerm { column: term.clone(), collation: None, nulls: None });
+        self.terms.splice(0..0, leading);
+    }
+    
+    // SELECT DISTINCT can only be ordered by selected columns, so the key
+    // terms of a `select` without them go and the order may not be total
+    pub(crate) fn keep_selected(&mut self, select: &str) {
+        let selected: Vec<String> = select
+            .split(',')
+            .map(|c| c.split(':').next().unwrap_or_default().trim().to_lowercase())
+            .collect();
+        let before = self.terms.len();
+        self.terms.retain(|t| selected.contains(&t.column.trim_matches('"').to_lowercase()));
+        if self.terms.len() < before {
+            self.stable = false;
+        }
+    }
+    
+    pub(crate) fn clause(&self) -> String {
+        if self.terms.is_empty() {
+            return String::new();
+        }
+        let terms: Vec<String> = self
+            .terms
+            .iter()
+            .map(|t| match t.nulls {
+                Some(nulls) => format!("{} {} NULLS {}", t.sql(), self.direction, nulls),
+                None => format!("{} {}", t.sql(), self.direction),
+            })
+            .collect();
+        format!(" ORDER BY {}", terms.join(", "))
+    }
+}
+
+// Materializing writes a result table, which only the primary can do.
+// Reads of archived rows only go to the table's archive database.
+pub(crate) async fn read_pool(
+    req: &HttpRequest,
+    primary: &PgPool,
+    replica: &Replica,
+    config: &Config,
+    table: &str,
+    filters: &FilterExpr,
+    query_params: &QueryParams,
+) -> Result<PgPool, Message> {
+    if query_params.materialize.unwrap_or(false) {
+        return Ok(primary.clone());
+    }
+    let archive = config.table(table.trim_matches('"')).and_then(|t| t.archive.as_ref());
+    if let Some(archive) = archive.filter(|a| a.covers(filters)) {
+        let databases = req.app_data::<web::Data<Databases>>();
+        if let Some(database) = databases.and_then(|d| d.0.get(&archive.database)) {
+            log::debug!("Reading {} from archive database {}", table, archive.database);
+            return Ok(database.pool.get_ref().clone());
+        }
+    }
+    replica.read_pool(req, primary).await
+}
+
+// Shared execution path of the table endpoints: parses the read parameters
+// for the given (already sanitized) table and filters, builds the SQL, runs
+// the count and page queries and renders the response.
+pub(crate) async fn run_query(
+    req: &HttpRequest,
+    pool: &PgPool,
+    config: &Config,
+    breaker: &CircuitBreaker,
+    table: &str,
+    filters: &FilterExpr,
+    query_params: &QueryParams,
+) -> HttpResponse {
+    // The table's default_sort and default_count stand in for missing
+    // parameters
+    let defaulted;
+    let query_params = match config.table(table.trim_matches('"')) {
+        Some(t) if (query_params.sort.is_none() && t.default_sort.is_some()) || (query_params.count.is_none() && t.default_count.is_some()) => {
+            defaulted = QueryParams {
+                sort: query_params.sort.clone().or_else(|| t.default_sort.clone()),
+                count: query_params.count.clone().or_else(|| t.default_count.clone()),
+                ..query_params.clone()
+            };
+            &defaulted
+        }
+        _ => query_params,
+    };
+    // The search condition comes last, so its parameter is the last one of the
+    // filters and `sort=rank` can refer to it
+    let mut rank = None;
+    let searched;
+    let filters = match &query_params.search {
+        Some(_) => {
+            let columns = match column_types(req, pool, table).await {
+                Ok(c) => c,
+                Err(e) => return database_error_response(req, breaker, e),
+            };
+            let condition = match search_condition(config, table, query_params, &columns) {
+                Ok(Some(c)) => c,
+                Ok(None) => unreachable!("search is given"),
+                Err(e) => return bad_request(req, e.param("search")),
+            };
+            let mut children = match filters {
+                FilterExpr::And(children) => children.clone(),
+                other => vec![other.clone()],
+            };
+            let param = filters.param_count() + 1;
+            rank = Some(format!("ts_rank({}, {})", condition.column, tsquery_sql(condition.cast.as_deref(), param)));
+            children.push(FilterExpr::Condition(condition));
+            searched = FilterExpr::And(children);
+            &searched
+        }
+        None => filters,
+    };
+    if req.method() == actix_web::http::Method::HEAD {
+        return count_response(req, pool, config, breaker, table, filters, query_params).await;
+    }
+    let trace = QueryTrace::start();
+    let read = match ReadParams::parse(req, config, table, query_params, rank.as_deref()) {
+        Ok(read) => read,
+        Err(e) => return bad_request(req, e),
+    };
+    let plan = match build_read(req, pool, config, breaker, table, filters, query_params, &read).await {
+        Ok(plan) => plan,
+        Err(response) => return response,
+    };
+    execute_read(req, pool, config, breaker, table, filters, query_params, read, plan, trace).await
+}
+
+// The read parameters of a table request that are checked before any SQL
+// is built or the database is asked
+pub(crate) struct ReadParams<'a> {
+    pub(crate) page: usize,
+    pub(crate) page_size: usize,
+    pub(crate) format: ResponseFormat,
+    pub(crate) summarize: bool,
+    pub(crate) count_mode: CountMode,
+    pub(crate) value_format: ValueFormat,
+    pub(crate) semantics: bool,
+    // `sort=random` samples rows: a single page of at most
+    // random_sort_max_rows, never cached
+    pub(crate) random: bool,
+    pub(crate) framing: Option<StreamFraming>,
+    pub(crate) sort_column: Option<String>,
+    pub(crate) sort_order: String,
+    pub(crate) sort_nulls: Option<&'static str>,
+    pub(crate) collation: Option<&'a str>,
+}
+
+impl<'a> ReadParams<'a> {
+    // `rank` is the search's ts_rank expression, which `sort=rank` orders by
+    pub(crate) fn parse(
+        req: &HttpRequest,
+        config: &'a Config,
+        table: &str,
+        query_params: &QueryParams,
+        rank: Option<&str>,
+    ) -> Result<Self, Message> {
+        let page = query_params.page.unwrap_or(1).max(1);
+        let page_size = quota_page_size(req, config.table_page_size(table, query_params.page_size));
+        let format = response_format(req, query_params.format.as_deref()).map_err(|e| e.param("format"))?;
+        // Workbooks are downloaded whole, up to DATAPI_XLSX_MAX_ROWS rows unless
+        // a smaller page is asked for
+        let page_size = match format {
+            ResponseFormat::Xlsx => {
+                quota_page_size(req, query_params.page_size.unwrap_or(config.xlsx_max_rows).min(config.xlsx_max_rows))
+            }
+            _ => page_size,
+        };
+        let summarize = match query_params.on_overflow.as_deref() {
+            None | Some("reject") => false,
+            Some("summarize") => true,
+            Some(other) => return Err(Message::new("invalid_on_overflow").arg("value", other).param("on_overflow")),
+        };
+        let count_mode = CountMode::parse(query_params.count.as_deref()).map_err(|e| e.param("count"))?;
+        let value_format = match query_params.tz.as_deref().map(str::parse::<chrono_tz::Tz>) {
+            None => config.value_format(),
+            Some(Ok(tz)) => ValueFormat { tz, ..config.value_format() },
+            Some(Err(_)) => {
+                let tz = query_params.tz.as_deref().unwrap_or_default();
+                return Err(Message::new("invalid_timezone").arg("tz", tz).param("tz"));
+            }
+        };
+        let semantics = match query_params.debug.as_deref() {
+            None => false,
+            Some("semantics") if format == ResponseFormat::Json => true,
+            Some(other) => return Err(Message::new("invalid_debug").arg("value", other).param("debug")),
+        };
+        
+        let random = query_params.sort.as_deref().is_some_and(|s| s.eq_ignore_ascii_case("random"));
+        let paged = page > 1 || query_params.cursor.is_some() || query_params.materialize.unwrap_or(false);
+        let framing = match format {
+            ResponseFormat::Ndjson => Some(StreamFraming::Lines),
+            ResponseFormat::Json if query_params.stream.unwrap_or(false) => Some(StreamFraming::Array),
+            _ => None,
+        };
+        if random && (paged || framing.is_some()) {
+            return Err(Message::new("random_sort_single_page").arg("max", config.random_sort_max_rows).param("sort"));
+        }
+        let page_size = if random { page_size.min(config.random_sort_max_rows) } else { page_size };
+        
+        let (sort_column, sort_direction, sort_nulls) = match query_params.sort.as_deref() {
+            _ if random => (Some("random()".to_string()), None, None),
+            // Most relevant first unless asked otherwise
+            Some(sort) if rank.is_some() && sort.split(':').next().is_some_and(|s| s.eq_ignore_ascii_case("rank")) => {
+                let (_, modifiers) = split_sort_modifiers(sort).map_err(|e| e.param("sort"))?;
+                let direction = match modifiers.direction {
+                    Some(d) => d,
+                    None if query_params.order.is_some() => {
+                        validate_sort_order(query_params.order.as_deref().unwrap_or_default()).map_err(|e| e.param("order"))?
+                    }
+                    None => "DESC".to_string(),
+                };
+                (rank.map(str::to_string), Some(direction), modifiers.nulls)
+            }
+            Some(sort) => {
+                let (sql, modifiers) = parse_sort(config, table, sort).map_err(|e| e.param("sort"))?;
+                (Some(sql), modifiers.direction, modifiers.nulls)
+            }
+            None => (None, None, None),
+        };
+        let sort_order = match (sort_direction, &query_params.order) {
+            (Some(direction), _) => direction,
+            (None, Some(order)) => validate_sort_order(order).map_err(|e| e.param("order"))?,
+            (None, None) => "ASC".to_string(),
+        };
+        let collation = match &query_params.collate {
+            Some(collate) => Some(resolve_collation(config, collate).map_err(|e| e.param("collate"))?),
+            None => None,
+        };
+        
+        Ok(ReadParams {
+            page,
+            page_size,
+            format,
+            summarize,
+            count_mode,
+            value_format,
+            semantics,
+            random,
+            framing,
+            sort_column,
+            sort_order,
+            sort_nulls,
+            collation,
+        })
+    }
+}
+
+// The SQL of a table read, from its parameters and the table's columns,
+// relations and keys
+pub(crate) struct ReadPlan {
+    pub(crate) where_clause: String,
+    pub(crate) select_list: String,
+    pub(crate) geometry_column: Option<String>,
+    // The read's page size, reduced under DATAPI_PAGE_BYTE_BUDGET
+    pub(crate) page_size: usize,
+    pub(crate) budget_warning: Option<actix_web::http::header::HeaderValue>,
+    pub(crate) order_by: OrderBy,
+    pub(crate) order_by_clause: String,
+    pub(crate) stable_order: bool,
+    pub(crate) keyset: Option<Keyset>,
+    // The page query, with the values bound after the filters'
+    pub(crate) query: String,
+    pub(crate) page_values: Vec<String>,
+}
+
+// Builds a read's SQL; refusals come back as the response to send
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn build_read(
+    req: &HttpRequest,
+    pool: &PgPool,
+    config: &Config,
+    breaker: &CircuitBreaker,
+    table: &str,
+    filters: &FilterExpr,
+    query_params: &QueryParams,
+    read: &ReadParams<'_>,
+) -> Result<ReadPlan, HttpResponse> {
+    let where_clause = build_where_clause(filters);
+    
+    let table_columns = match select_columns(req, pool, table).await {
+        Ok(columns) => columns,
+        Err(e) => {
+            log::warn!("Columns of {} unavailable, geometries and binaries are selected as they are: {}", table, e);
+            Vec::new()
+        }
+    };
+    let geometry_column = table_columns
+        .iter()
+        .find(|(_, sql_type)| SPATIAL_TYPES.contains(&sql_type.as_str()))
+        .map(|(name, _)| name.clone());
+    if read.format == ResponseFormat::GeoJson && geometry_column.is_none() {
+        let message = Message::new("geojson_without_geometry").arg("table", table.trim_matches('"')).param("format");
+        return Err(bad_request(req, message));
+    }
+    let omit_binary = query_params.omit_binary.unwrap_or(false);
+    let select_list = parse_select(config, table, query_params.select.as_deref(), &table_columns, omit_binary)
+        .map_err(|e| bad_request(req, e.param("select")))?;
+    let select_list = match &query_params.embed {
+        Some(embed) => {
+            let relations = fetch_relations(pool, table).await.map_err(|e| database_error_response(req, breaker, e))?;
+            let columns = embed_select(req, config, table, &relations, embed).map_err(|e| bad_request(req, e.param("embed")))?;
+            std::iter::once(select_list).chain(columns).collect::<Vec<_>>().join(", ")
+        }
+        None => select_list,
+    };
+    let distinct = Distinct::parse(config, table, query_params, &table_columns)
+        .map_err(|e| bad_request(req, e.param("distinct_on")))?;
+    if distinct.is_some() {
+        let resumable = query_params.checkpoints.unwrap_or(false) || query_params.resume_token.is_some();
+        let unsupported = [
+            ("cursor", query_params.cursor.is_some()),
+            ("materialize", query_params.materialize.unwrap_or(false)),
+            ("checkpoints", resumable),
+        ];
+        if let Some((param, _)) = unsupported.iter().find(|(_, given)| *given) {
+            return Err(bad_request(req, Message::new("distinct_unsupported").arg("param", param).param(param)));
+        }
+    }
+    let select_list = match &distinct {
+        Some(distinct) => distinct.select_list(&select_list),
+        None => select_list,
+    };
+    
+    // Wide rows get smaller pages under DATAPI_PAGE_BYTE_BUDGET; NDJSON
+    // streams and isn't capped
+    let budget_cap = match read.framing {
+        Some(_) => None,
+        None => page_size_for_budget(req, pool, config, table, &select_list, read.page_size).await,
+    };
+    let page_size = budget_cap.unwrap_or(read.page_size);
+    let offset = (read.page - 1) * page_size;
+    let budget_warning = budget_cap.and_then(|rows| {
+        let warning = format!(
+            "299 - \"Page size reduced to {} rows to keep the response under {} bytes\"",
+            rows,
+            config.page_byte_budget.unwrap_or_default()
+        );
+        actix_web::http::header::HeaderValue::from_str(&warning).ok()
+    });
+    
+    let mut order_by = build_order_by(
+        req,
+        pool,
+        table,
+        read.sort_column.as_deref(),
+        &read.sort_order,
+        read.sort_nulls,
+        read.collation,
+    )
+    .await;
+    match (&distinct, query_params.select.as_deref()) {
+        (Some(Distinct::On(terms)), _) => order_by.lead_with(terms),
+        (Some(Distinct::Rows), Some(select)) => order_by.keep_selected(select),
+        _ => {}
+    }
+    let order_by_clause = order_by.clause();
+    let stable_order = order_by.stable || read.random;
+    
+    let keyset = match &query_params.cursor {
+        Some(cursor) => {
+            let columns = column_types(req, pool, table).await.map_err(|e| database_error_response(req, breaker, e))?;
+            let keyset = Keyset::new(&order_by, &columns, cursor, filters.param_count(), sort_fingerprint(&order_by, filters));
+            Some(keyset.map_err(|e| bad_request(req, e.param("cursor")))?)
+        }
+        None => None,
+    };
+    
+    // Cursor pages start after the cursor row instead of at an offset. The
+    // page is bound after the filter and cursor values, so every page of a
+    // filter shape reuses one prepared statement.
+    let mut page_values = keyset.as_ref().map(|k| k.values.clone()).unwrap_or_default();
+    let limit_param = filters.param_count() + page_values.len() + 1;
+    page_values.push(page_size.to_string());
+    let query = match &keyset {
+        Some(keyset) => tag_query(table, &format!(
+            "SELECT {}{} FROM {}{}{} LIMIT ${}::bigint",
+            select_list,
+            keyset.select_columns(),
+            table,
+            keyset.where_clause(&where_clause),
+            order_by_clause,
+            limit_param
+        )),
+        None => {
+            page_values.push(offset.to_string());
+            tag_query(table, &format!(
+                "SELECT {} FROM {}{}{} LIMIT ${}::bigint OFFSET ${}::bigint",
+                select_list, table, where_clause, order_by_clause, limit_param, limit_param + 1
+            ))
+        }
+    };
+    
+    Ok(ReadPlan {
+        where_clause,
+        select_list,
+        geometry_column,
+        page_size,
+        budget_warning,
+        order_by,
+        order_by_clause,
+        stable_order,
+        keyset,
+        query,
+        page_values,
+    })
+}
+
+// Runs a built read: the row limit check, then materializing, streaming or
+// the page query, whose rows are rendered in the requested format
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn execute_read(
+    req: &HttpRequest,
+    pool: &PgPool,
+    config: &Config,
+    breaker: &CircuitBreaker,
+    table: &str,
+    filters: &FilterExpr,
+    query_params: &QueryParams,
+    read: ReadParams<'_>,
+    plan: ReadPlan,
+    mut trace: QueryTrace,
+) -> HttpResponse {
+    let ReadParams { page, format, summarize, count_mode, value_format, semantics, random, framing, .. } = read;
+    let ReadPlan {
+        where_clause,
+        select_list,
+        geometry_column,
+        page_size,
+        budget_warning,
+        order_by,
+        order_by_clause,
+        stable_order,
+        keyset,
+        query,
+        page_values,
+    } = plan;
+    
+    let max_rows = match req.app_data::<web::Data<TableLimits>>() {
+        Some(limits) => limits.max_rows(config, table),
+        None => config.max_rows(table),
+    };
+    let max_rows = quota_max_rows(req, max_rows);
+    if let Some(max_rows) = max_rows {
+        let sort_key = order_by.terms.first().filter(|_| !random).map(|t| {
+            let sort = query_params.sort.as_deref().map(|s| s.rsplit_once(':').map_or(s, |(expr, _)| expr));
+            let name = sort.unwrap_or(&t.column);
+            (name.to_lowercase(), t.column.as_str())
+        });
+        let limit = RowLimit { max_rows, summarize, sort_key };
+        if let Some(resp) =
+            row_limit_response(req, pool, config, breaker, table, &where_clause, filters, limit).await
+        {
+            return resp;
+        }
+    }
+
+    if query_params.materialize.unwrap_or(false) {
+        if config.read_only {
+            return error_response(req, StatusCode::FORBIDDEN, Message::new("materialize_read_only"));
+        }
+        let materialized = materialize_result(
+            pool,
+            config,
+            &claim_settings(req, config),
+            table,
+            &select_list,
+            &where_clause,
+            &order_by_clause,
+            filters,
+        )
+        .await;
+        return match materialized {
+            Ok(token) => {
+                breaker.record_success();
+                let mut resp =
+                    result_page_response(req, pool, config, breaker, &token, page, page_size).await;
+                if !stable_order {
+                    resp.headers_mut().insert(
+                        actix_web::http::header::WARNING,
+                        actix_web::http::header::HeaderValue::from_static(UNSTABLE_ORDER_WARNING),
+                    );
+                }
+                if let Some(warning) = budget_warning {
+                    resp.headers_mut().append(actix_web::http::header::WARNING, warning);
+                }
+                resp
+            }
+            Err(e) => database_error_response(req, breaker, e),
+        };
+    }
+    
+    // NDJSON and streamed JSON return the whole result unless a page is
+    // requested explicitly, without the page size cap but within an API
+    // key's max_page_size
+    if let Some(framing) = framing {
+        let resumable = query_params.checkpoints.unwrap_or(false) || query_params.resume_token.is_some();
+        if resumable {
+            if query_params.page.is_some() || query_params.page_size.is_some() || framing == StreamFraming::Array {
+                return bad_request(req, Message::new("checkpoints_unsupported").param("checkpoints"));
+            }
+            let columns = match column_types(req, pool, table).await {
+                Ok(c) => c,
+                Err(e) => return database_error_response(req, breaker, e),
+            };
+            let token = query_params.resume_token.as_deref().unwrap_or_default();
+            let fingerprint = sort_fingerprint(&order_by, filters);
+            let keyset = match Keyset::new(&order_by, &columns, token, filters.param_count(), fingerprint) {
+                Ok(k) => k,
+                Err(e) => return bad_request(req, e.param("resume_token")),
+            };
+            // A key's max_page_size caps each export; the last checkpoint
+            // resumes it
+            let mut page = Vec::new();
+            let mut limit = String::new();
+            if let Some(size) = quota_max_page_size(req) {
+                page.push(size.to_string());
+                limit = format!(" LIMIT ${}::bigint", filters.param_count() + keyset.values.len() + 1);
+            }
+            let stream_query = tag_query(table, &format!(
+                "SELECT {}{} FROM {}{}{}{}",
+                select_list,
+                keyset.select_columns(),
+                table,
+                keyset.where_clause(&where_clause),
+                order_by_clause,
+                limit
+            ));
+            log::info!("Streaming resumable query: {}", stream_query);
+            return stream_response(req, pool, breaker, table, stream_query, filters, Some(keyset), page, query_params.timeout_ms, value_format, framing).await;
+        }
+        
+        // The page is bound like the paged query's
+        let first = filters.param_count() + 1;
+        let (limit, page) = match query_params.page_size.map(|size| quota_page_size(req, size)) {
+            Some(size) => (
+                format!(" LIMIT ${}::bigint OFFSET ${}::bigint", first, first + 1),
+                vec![size.to_string(), ((page - 1) * size).to_string()],
+            ),
+            None => match quota_max_page_size(req) {
+                Some(size) => (format!(" LIMIT ${}::bigint", first), vec![size.to_string()]),
+                None => (String::new(), Vec::new()),
+            },
+        };
+        let stream_query = tag_query(table, &format!(
+            "SELECT {} FROM {}{}{}{}",
+            select_list, table, where_clause, order_by_clause, limit
+        ));
+        log::info!("Streaming query: {}", stream_query);
+        return stream_response(req, pool, breaker, table, stream_query, filters, None, page, query_params.timeout_ms, value_format, framing).await;
+    }
+    
+    let binds = log_binds(config, filters, &page_values);
+    log::info!("Executing query: {}", query);
+    if !binds.is_empty() {
+        log::info!("With values: [{}]", binds.join(", "));
+    }
+    
+    trace.mark("parse");
+    let mut tx = match begin_table_transaction(pool, config, table).await {
+        Ok(tx) => tx,
+        Err(e) => return database_error_response(req, breaker, e),
+    };
+    if let Err(e) = apply_request_timeout(&mut tx, config, query_params.timeout_ms).await {
+        return database_error_response(req, breaker, e);
+    }
+    if let Err(e) = apply_claim_settings(&mut tx, &claim_settings(req, config)).await {
+        return database_error_response(req, breaker, e);
+    }
+    trace.mark("acquire");
+    
+    // Execute main query
+    let mut query_builder = bind_filters(sqlx::query(&query), filters);
+    for value in &page_values {
+        query_builder = query_builder.bind(value);
+    }
+    
+    let started = Instant::now();
+    // Streamed so the time to the first row (execute) and the rest (fetch)
+    // are timed apart
+    let mut rows = Vec::new();
+    let mut stream = query_builder.fetch(&mut *tx);
+    loop {
+        match stream.try_next().await {
+            Ok(Some(row)) => {
+                if rows.is_empty() {
+                    trace.mark("execute");
+                }
+                rows.push(row);
+            }
+            Ok(None) => break,
+            Err(e) => return database_error_response(req, breaker, e),
+        }
+    }
+    drop(stream);
+    if rows.is_empty() {
+        trace.mark("execute");
+    }
+    trace.mark("fetch");
+    breaker.record_success();
+    note_read(req, table, filters.conditions().iter().map(|c| c.describe()).collect(), rows.len());
+    
+    let elapsed = started.elapsed();
+    record_slow_query(req, config, table, &query, &binds, elapsed);
+    let slow = config.query_hint_ms.is_some_and(|ms| elapsed >= Duration::from_millis(ms));
+    let hint = if slow && !filters.conditions().is_empty() && is_admin(req, config) {
+        query_hint(pool, table, &query, filters, &page_values).await
+    } else {
+        None
+    };
+    
+    // CSV and Parquet need the columns even for an empty page
+    let describe_columns = |columns: &[sqlx::postgres::PgColumn]| -> Vec<(String, String)> {
+        columns
+            .iter()
+            .map(|c| (c.name().to_string(), c.type_info().name().to_string()))
+            .collect()
+    };
+    let mut columns = match rows.first() {
+        Some(row) => describe_columns(row.columns()),
+        None if format != ResponseFormat::Json => match (&mut *tx).describe(&query).await {
+            Ok(described) => describe_columns(described.columns()),
+            Err(e) => {
+                log::error!("Describe error: {}", e);
+                Vec::new()
+            }
+        },
+        None => Vec::new(),
+    };
+    columns.retain(|(name, _)| !name.starts_with(CURSOR_COLUMN_PREFIX));
+    
+    // Get total count. This runs last because a failure aborts the
+    // transaction, and a missing count is tolerated.
+    let (counted, total_count_estimated, mut warnings) =
+        count_total(req, tx, config, table, filters, &where_clause, query_params, count_mode).await;
+    let total_count = counted.unwrap_or_else(|e| {
+        breaker.record_error(&e);
+        warnings.push(Message::new("count_failed"));
+        None
+    });
+    // Warning headers are ASCII, so they carry the English text
+    let warning_headers: Vec<_> = warnings
+        .iter()
+        .filter_map(|w| {
+            let text = Translations::default().render(None, w);
+            actix_web::http::header::HeaderValue::from_str(&format!("299 - \"{}\"", text)).ok()
+        })
+        .collect();
+    let warnings: Vec<String> = warnings.iter().map(|w| render_message(req, w).0).collect();
+    trace.mark("count");
+    
+    let hooks = request_hooks(req);
+    if format == ResponseFormat::Json {
+        // Hooks get the page as maps, which then serialize the usual way
+        let hooked = (!hooks.is_empty()).then(|| {
+            let mut data: Vec<_> = rows
+                .iter()
+                .map(|row| {
+                    let mut obj = row_to_json(row, value_format);
+                    if let Some(keyset) = &keyset {
+                        keyset.take_values(&mut obj);
+                    }
+                    protect_columns(config, table, &mut obj);
+                    obj
+                })
+                .collect();
+            hooks.after_rows(table, &mut data);
+            data
+        });
+        let next_cursor = match (&keyset, rows.last()) {
+            (Some(keyset), Some(last)) if rows.len() == page_size => {
+                Some(keyset.cursor(keyset.take_values(&mut row_to_json(last, config.value_format()))))
+            }
+            _ => None,
+        };
+        let debug = match semantics {
+            true => Some(serde_json::json!({ "semantics": semantics_report(pool, table, filters, &order_by, query_params).await })),
+            false => None,
+        };
+        let position = PagePosition::of(req, page, page_size, rows.len(), total_count, next_cursor.as_deref());
+        let meta = PageMeta {
+            count: hooked.as_ref().map_or(rows.len(), Vec::len),
+            page,
+            page_size,
+            total_count,
+            total_count_estimated,
+            total_pages: position.total_pages,
+            has_next: position.has_next,
+            next_cursor,
+            sort_fingerprint: keyset.as_ref().map(|k| k.fingerprint.clone()),
+            warnings,
+            debug,
+        };
+        let mut builder = HttpResponse::Ok();
+        builder.insert_header((actix_web::http::header::LINK, position.links));
+        if !stable_order {
+            builder.insert_header(("Warning", UNSTABLE_ORDER_WARNING));
+        }
+        if let Some(warning) = budget_warning {
+            builder.append_header(("Warning", warning));
+        }
+        for warning in warning_headers {
+            builder.append_header(("Warning", warning));
+        }
+        if random {
+            builder.insert_header((actix_web::http::header::CACHE_CONTROL, "no-store"));
+        }
+        if let Some(hint) = hint.and_then(|h| actix_web::http::header::HeaderValue::from_str(&h).ok()) {
+            builder.insert_header((QUERY_HINT_HEADER, hint));
+        }
+        let body = match &hooked {
+            Some(data) => write_page(data, &meta),
+            None => JsonPageWriter::new(config, table, &rows, value_format).write(&meta),
+        };
+        trace.mark("serialize");
+        if let Some(timing) = trace.finish(config, table, &query, &binds) {
+            builder.insert_header((actix_web::http::header::HeaderName::from_static("server-timing"), timing));
+        }
+        return builder.content_type("application/json").body(body);
+    }
+    
+    // CSV keeps the numerics' exact digits, as text like every field
+    let file_format = ValueFormat {
+        numeric_strings: format != ResponseFormat::GeoJson || config.numeric_strings,
+        ..value_format
+    };
+    let mut next_cursor = None;
+    let mut results: Vec<_> = rows
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let mut obj = row_to_json(row, file_format);
+            if let Some(keyset) = &keyset {
+                let values = keyset.take_values(&mut obj);
+                if i + 1 == page_size {
+                    next_cursor = Some(keyset.cursor(values));
+                }
+            }
+            protect_columns(config, table, &mut obj);
+            obj
+        })
+        .collect();
+    hooks.after_rows(table, &mut results);
+    let results: Vec<serde_json::Value> = results.into_iter().map(serde_json::Value::Object).collect();
+    
+    let response = QueryResult {
+        count: results.len(),
+        data: results,
+        page,
+        page_size,
+        total_count,
+        total_count_estimated,
+        total_pages: None,
+        has_next: false,
+        result_token: None,
+        next_cursor,
+        sort_fingerprint: keyset.as_ref().map(|k| k.fingerprint.clone()),
+        warnings,
+    };
+    {
+        let position = PagePosition::of(req, page, page_size, response.count, total_count, response.next_cursor.as_deref());
+        let columns: Vec<(String, String)> =
+            columns.into_iter().filter(|(name, _)| config.mask(table, name) != Some(Mask::Omit)).collect();
+        let mut resp = match format {
+            ResponseFormat::Parquet => {
+                // Rows a hook changed no longer line up with the typed values
+                let batch = record_batch(config, table, &columns, &rows, &response.data, hooks.is_empty());
+                match batch.and_then(|batch| write_parquet(&batch)) {
+                    Ok(body) => {
+                        let content_type = "application/vnd.apache.parquet";
+                        file_response(table, "parquet", content_type, body, &response)
+                    }
+                    Err(e) => {
+                        log::error!("Parquet serialization error: {}", e);
+                        error_response(req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("internal_error"))
+                    }
+                }
+            }
+            ResponseFormat::Arrow => {
+                let batch = record_batch(config, table, &columns, &rows, &response.data, hooks.is_empty());
+                match batch.and_then(|batch| write_arrow(&batch)) {
+                    Ok(body) => file_response(table, "arrows", ARROW_CONTENT_TYPE, body, &response),
+                    Err(e) => {
+                        log::error!("Arrow serialization error: {}", e);
+                        error_response(req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("internal_error"))
+                    }
+                }
+            }
+            ResponseFormat::Xlsx => {
+                match write_xlsx(config, table, &columns, &rows, &response.data, hooks.is_empty(), value_format.tz) {
+                    Ok(body) => file_response(table, "xlsx", XLSX_CONTENT_TYPE, body, &response),
+                    Err(e) => {
+                        log::error!("XLSX serialization error: {}", e);
+                        error_response(req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("internal_error"))
+                    }
+                }
+            }
+            ResponseFormat::GeoJson => geojson_response(req, table, geometry_column.as_deref().unwrap_or_default(), &response),
+            _ => csv_response(req, table, &columns, &response),
+        };
+        if !stable_order {
+            resp.headers_mut().insert(
+                actix_web::http::header::WARNING,
+                actix_web::http::header::HeaderValue::from_static(UNSTABLE_ORDER_WARNING),
+            );
+        }
+        if let Some(warning) = budget_warning {
+            resp.headers_mut().append(actix_web::http::header::WARNING, warning);
+        }
+        for warning in warning_headers {
+            resp.headers_mut().append(actix_web::http::header::WARNING, warning);
+        }
+        if random {
+            resp.headers_mut().insert(
+                actix_web::http::header::CACHE_CONTROL,
+                actix_web::http::header::HeaderValue::from_static("no-store"),
+            );
+        }
+        if let Some(hint) = hint.and_then(|h| actix_web::http::header::HeaderValue::from_str(&h).ok()) {
+            resp.headers_mut().insert(actix_web::http::header::HeaderName::from_static(QUERY_HINT_HEADER), hint);
+        }
+        if let Ok(links) = actix_web::http::header::HeaderValue::from_str(&position.links) {
+            resp.headers_mut().insert(actix_web::http::header::LINK, links);
+        }
+        trace.mark("serialize");
+        if let Some(timing) = trace.finish(config, table, &query, &binds) {
+            resp.headers_mut().insert(actix_web::http::header::HeaderName::from_static("server-timing"), timing);
+        }
+        resp
+    }
+}
+
+// The total_count of a read and whether it is estimated, committing the
+// transaction. Kept counts stand in for the count of unfiltered reads unless
+// count=exact asks for one, and tables whose counts keep failing are
+// estimated for a while.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn count_total(
+    req: &HttpRequest,
+    mut tx: sqlx::Transaction<'_, sqlx::Postgres>,
+    config: &Config,
+    table: &str,
+    filters: &FilterExpr,
+    where_clause: &str,
+    query_params: &QueryParams,
+    count_mode: CountMode,
+) -> (Result<Option<usize>, sqlx::Error>, bool, Vec<Message>) {
+    // Validated by the caller
+    let distinct = Distinct::parse(config, table, query_params, &[])
+        .ok()
+        .flatten()
+        .and_then(|d| Some(d.count_list(&parse_select(config, table, query_params.select.as_deref(), &[], false).ok()?)));
+    let count_query = tag_query(table, &match &distinct {
+        Some(list) => format!("SELECT COUNT(*) as count FROM (SELECT {} FROM {}{}) counted", list, table, where_clause),
+        None => format!("SELECT COUNT(*) as count FROM {}{}", table, where_clause),
+    });
+    let kept = req
+        .app_data::<web::Data<RowCounts>>()
+        .filter(|_| filters.is_empty() && distinct.is_none() && !req.extensions().contains::<DatabaseName>())
+        .filter(|_| query_params.count.is_none() || count_mode == CountMode::Estimated)
+        .and_then(|counts| counts.get(table));
+    let failures = req.app_data::<web::Data<CountFailures>>();
+    let mut warnings = Vec::new();
+    let count_mode = match count_mode {
+        CountMode::Exact if kept.is_none() && failures.is_some_and(|f| f.take_estimate(table)) => {
+            warnings.push(Message::new("count_estimated_after_failure").arg("table", table));
+            CountMode::Estimated
+        }
+        mode => mode,
+    };
+    let counted = match count_mode {
+        _ if kept.is_some() => Ok(kept),
+        CountMode::Exact => bind_filters(sqlx::query(&count_query), filters)
+            .fetch_one(&mut *tx)
+            .await
+            .map(|row| Some(row.try_get::<i64, _>("count").unwrap_or(0) as usize)),
+        CountMode::Estimated => estimate_count(&mut tx, table, where_clause, filters, distinct.as_deref()).await.map(Some),
+        CountMode::None => Ok(None),
+    };
+    match &counted {
+        Ok(_) => {
+            if let Err(e) = tx.commit().await {
+                log::warn!("Commit failed: {}", e);
+            }
+        }
+        Err(e) => {
+            log::error!("Count query error: {}", e);
+            if let Some(failures) = failures {
+                failures.record(config, table, e);
+            }
+        }
+    }
+    let estimated = (count_mode == CountMode::Estimated || kept.is_some()) && matches!(counted, Ok(Some(_)));
+    (counted, estimated, warnings)
+}
+
+// HEAD of a table read: only the count runs, and the response is the count
+// and paging headers of the page the GET would return, without a body.
+pub(crate) async fn count_response(
+    req: &HttpRequest,
+    pool: &PgPool,
+    config: &Config,
+    breaker: &CircuitBreaker,
+    table: &str,
+    filters: &FilterExpr,
+    query_params: &QueryParams,
+) -> HttpResponse {
+    let page = query_params.page.unwrap_or(1).max(1);
+    let page_size = quota_page_size(req, config.table_page_size(table, query_params.page_size));
+    let count_mode = match CountMode::parse(query_params.count.as_deref()) {
+        Ok(mode) => mode,
+        Err(e) => return bad_request(req, e),
+    };
+    if let Err(e) = Distinct::parse(config, table, query_params, &[]) {
+        return bad_request(req, e);
+    }
+    let mut tx = match begin_table_transaction(pool, config, table).await {
+        Ok(tx) => tx,
+        Err(e) => return database_error_response(req, breaker, e),
+    };
+    if let Err(e) = apply_request_timeout(&mut tx, config, query_params.timeout_ms).await {
+        return database_error_response(req, breaker, e);
+    }
+    if let Err(e) = apply_claim_settings(&mut tx, &claim_settings(req, config)).await {
+        return database_error_response(req, breaker, e);
+    }
+    let where_clause = build_where_clause(filters);
+    let (counted, estimated, warnings) =
+        count_total(req, tx, config, table, filters, &where_clause, query_params, count_mode).await;
+    let total_count = match counted {
+        Ok(count) => count,
+        Err(e) => return database_error_response(req, breaker, e),
+    };
+    breaker.record_success();
+    
+    let mut builder = HttpResponse::Ok();
+    if let Some(total) = total_count {
+        builder.insert_header(("X-Total-Count", total.to_string()));
+        if estimated {
+            builder.insert_header(("X-Total-Count-Estimated", "true"));
+        }
+        let count = total.saturating_sub((page - 1) * page_size).min(page_size);
+        let position = PagePosition::of(req, page, page_size, count, total_count, None);
+        if let Some(pages) = position.total_pages {
+            builder.insert_header(("X-Total-Pages", pages.to_string()));
+        }
+        builder.insert_header((actix_web::http::header::LINK, position.links));
+    }
+    for warning in &warnings {
+        let text = Translations::default().render(None, warning);
+        builder.append_header((actix_web::http::header::WARNING, format!("299 - \"{}\"", text)));
+    }
+    builder.finish()
+}
+
+// Phase timings of a table read: acquiring the connection and setting up the
+// transaction, executing up to the first row, fetching the rest, counting
+// and serializing. Each read logs them with its statement under the
+// datapi::sql target at debug level.
+pub(crate) struct QueryTrace {
+    pub(crate) started: std::time::SystemTime,
+    pub(crate) last: Instant,
+    pub(crate) phases: Vec<(&'static str, Duration)>,
+}
+
+impl QueryTrace {
+    pub(crate) fn start() -> Self {
+        QueryTrace { started: std::time::SystemTime::now(), last: Instant::now(), phases: Vec::new() }
+    }
+    
+    pub(crate) fn mark(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        self.phases.push((phase, now - self.last));
+        self.last = now;
+    }
+    
+    // Logs the read; returns the Server-Timing header when enabled
+    pub(crate) fn finish(
+        &self,
+        config: &Config,
+        table: &str,
+        sql: &str,
+        binds: &[String],
+    ) -> Option<actix_web::http::header::HeaderValue> {
+        let millis = |d: &Duration| d.as_secs_f64() * 1000.0;
+        if log::log_enabled!(target: "datapi::sql", log::Level::Debug) {
+            let phases: Vec<String> = self.phases.iter().map(|(p, d)| format!("{}={:.2}ms", p, millis(d))).collect();
+            log::debug!(target: "datapi::sql", "table={} {} binds=[{}] sql={}", table, phases.join(" "), binds.join(", "), sql);
+        }
+        if config.otlp_tracing {
+            self.export_spans(table, sql);
+        }
+        if !config.server_timing {
+            return None;
+        }
+        let timing: Vec<String> = self.phases.iter().map(|(p, d)| format!("{};dur={:.2}", p, millis(d))).collect();
+        actix_web::http::header::HeaderValue::from_str(&timing.join(", ")).ok()
+    }
+    
+    // The phases as consecutive child spans of the request's span. They are
+    // built after the fact from the measured durations, so the phases
+    // themselves stay plain code.
+    pub(crate) fn export_spans(&self, table: &str, sql: &str) {
+        use opentelemetry::trace::{Span, TraceContextExt, Tracer};
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        let parent = tracing::Span::current().context();
+        if !parent.span().span_context().is_valid() {
+            return;
+        }
+        let tracer = opentelemetry::global::tracer(APPLICATION_NAME);
+        let mut start = self.started;
+        for (phase, duration) in &self.phases {
+            let mut attributes = vec![
+                opentelemetry::KeyValue::new("db.system", "postgresql"),
+                opentelemetry::KeyValue::new("datapi.table", table.to_string()),
+            ];
+            if *phase == "execute" {
+                attributes.push(opentelemetry::KeyValue::new("db.statement", sql.to_string()));
+            }
+            let mut span = tracer
+                .span_builder(format!("datapi.{}", phase))
+                .with_start_time(start)
+                .with_attributes(attributes)
+                .start_with_context(&tracer, &parent);
+            start += *duration;
+            span.end_with_timestamp(start);
+        }
+    }
+}
+
+pub(crate) const QUERY_HINT_HEADER: &str = "x-query-hint";
+
+// Suggests indexes for a slow filtered read: EXPLAIN shows which filters are
+// applied to rows while scanning the table rather than through an index
+// condition, and the table's indexes (with their usage from
+// pg_stat_user_indexes) whether one already leads with a filtered column.
+// Filters on columns of other types than numbers, booleans, dates and
+// timestamps compare as text, so they need an expression index on
+// `column::text` to be usable.
+pub(crate) async fn query_hint(
+    pool: &PgPool,
+    table: &str,
+    query: &str,
+    filters: &FilterExpr,
+    extra_values: &[String],
+) -> Option<String> {
+    let explain = format!("EXPLAIN (FORMAT JSON) {}", query);
+    let mut explain_query = bind_filters(sqlx::query(&explain), filters);
+    for value in extra_values {
+        explain_query = explain_query.bind(value);
+    }
+    let plan = match explain_query.fetch_one(pool).await {
+        Ok(row) => row.try_get_unchecked::<String, _>(0).ok()?,
+        Err(e) => {
+            log::warn!("Query hint EXPLAIN failed: {}", e);
+            return None;
+        }
+    };
+    let plan: serde_json::Value = serde_json::from_str(&plan).ok()?;
+    
+    // Filter expressions of scans of the table, e.g. a sequential scan or a
+    // scan of the primary key for the sort order
+    let relation = table.trim_matches('"');
+    let mut scan_filters = Vec::new();
+    let mut nodes = vec![plan.get(0)?.get("Plan")?];
+    while let Some(node) = nodes.pop() {
+        if node["Relation Name"] == relation {
+            if let Some(filter) = node["Filter"].as_str() {
+                scan_filters.push(filter.to_string());
+            }
+        }
+        nodes.extend(node["Plans"].as_array().into_iter().flatten());
+    }
+    if scan_filters.is_empty() {
+        return None;
+    }
+    
+    let column_types = fetch_column_types(pool, table).await.ok()?;
+    let indexes = sqlx::query(
+        "SELECT s.indexrelname::text AS name, s.idx_scan, pg_get_indexdef(s.indexrelid, 1, true) AS leading
+         FROM pg_stat_user_indexes s
+         WHERE s.relid = to_regclass($1)",
+    )
+    .bind(table)
+    .fetch_all(pool)
+    .await
+    .ok()?;
+    let indexes: Vec<(String, i64, String)> = indexes
+        .iter()
+        .map(|row| {
+            let leading: String = row.try_get("leading").unwrap_or_default();
+            (
+                row.try_get("name").unwrap_or_default(),
+                row.try_get("idx_scan").unwrap_or(0),
+                leading.replace(['(', ')', '"'], ""),
+            )
+        })
+        .collect();
+    
+    let mut hints: Vec<String> = Vec::new();
+    for condition in filters.conditions() {
+        let column = condition.column.trim_matches('"');
+        let Some((sql_type, _)) = column_types.get(column) else {
+            continue;
+        };
+        if !scan_filters.iter().any(|f| f.contains(column)) {
+            continue;
+        }
+        // Typed and text columns are compared as they are
+        let plain = condition.cast.is_some()
+            || matches!(sql_type.split('(').next().unwrap_or_default(), "text" | "character varying" | "character");
+        let wanted = if plain { column.to_string() } else { format!("{}::text", column) };
+        if indexes.iter().any(|(_, _, leading)| *leading == wanted) {
+            // Indexed; the planner prefers the scan, e.g. for an unselective filter
+            continue;
+        }
+        let hint = if plain {
+            format!("CREATE INDEX ON {} ({})", table, quote_identifier(column))
+        } else {
+            format!("CREATE INDEX ON {} (({}::text))", table, quote_identifier(column))
+        };
+        let unusable = indexes.iter().find(|(_, _, leading)| leading == column);
+        let hint = match unusable {
+            Some((name, scans, _)) => format!(
+                "{} (index {} on {} is not used by the filter's comparison, {} scans)",
+                hint, name, column, scans
+            ),
+            None => hint,
+        };
+        if !hints.contains(&hint) {
+            hints.push(hint);
+        }
+    }
+    if hints.is_empty() {
+        return None;
+    }
+    Some(format!("{} is filtered while scanning; consider {}", relation, hints.join("; ")))
+}
+
+pub(crate) struct RowLimit<'a> {
+    pub(crate) max_rows: u64,
+    pub(crate) summarize: bool,
+    // API name and SQL of the leading order column
+    pub(crate) sort_key: Option<(String, &'a str)>,
+}
+
+// Checks a read against the table's row limit before it runs. Counting stops
+// at the first row past the limit; only an overflowing read with
+// `on_overflow=summarize` pays for the full count and the sort key range.
+// Returns the response that replaces the read, if any.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn row_limit_response(
+    req: &HttpRequest,
+    pool: &PgPool,
+    config: &Config,
+    breaker: &CircuitBreaker,
+    table: &str,
+    where_clause: &str,
+    filters: &FilterExpr,
+    limit: RowLimit<'_>,
+) -> Option<HttpResponse> {
+    let database_error = |e: sqlx::Error| database_error_response(req, breaker, e);
+    let mut tx = match begin_table_transaction(pool, config, table).await {
+        Ok(tx) => tx,
+        Err(e) => return Some(database_error(e)),
+    };
+    if let Err(e) = apply_claim_settings(&mut tx, &claim_settings(req, config)).await {
+        return Some(database_error(e));
+    }
+    
+    let probe = tag_query(table, &format!(
+        "SELECT COUNT(*) AS count FROM (SELECT 1 FROM {}{} LIMIT {}) probe",
+        table, where_clause, limit.max_rows + 1
+    ));
+    let matched: i64 = match bind_filters(sqlx::query(&probe), filters).fetch_one(&mut *tx).await {
+        Ok(row) => row.try_get("count").unwrap_or(0),
+        Err(e) => return Some(database_error(e)),
+    };
+    breaker.record_success();
+    if (matched as u64) <= limit.max_rows {
+        return None;
+    }
+    
+    if !limit.summarize {
+        let mut body = serde_json::Map::new();
+        body.insert("max_rows".to_string(), serde_json::json!(limit.max_rows));
+        return Some(error_response_with(
+            req,
+            StatusCode::PAYLOAD_TOO_LARGE,
+            Message::new("row_limit_exceeded").arg("max_rows", limit.max_rows),
+            body,
+        ));
+    }
+    
+    // The range of an encrypted or masked column would expose its values
+    let encrypted = |column: &str| {
+        config
+            .table(table)
+            .is_some_and(|t| t.encrypted_columns.iter().any(|c| *c == column) || t.masked_columns.contains_key(column))
+    };
+    let sort_key = limit.sort_key.filter(|(name, _)| !encrypted(name));
+    let range = match &sort_key {
+        Some((_, sql)) => format!(", MIN({0}) AS min, MAX({0}) AS max", sql),
+        None => String::new(),
+    };
+    let summary_query = tag_query(table, &format!(
+        "SELECT COUNT(*) AS count{} FROM {}{}",
+        range, table, where_clause
+    ));
+    let row = match bind_filters(sqlx::query(&summary_query), filters).fetch_one(&mut *tx).await {
+        Ok(row) => row,
+        Err(e) => return Some(database_error(e)),
+    };
+    let count: i64 = row.try_get("count").unwrap_or(0);
+    
+    let mut summary = row_to_json(&row, config.value_format());
+    let guidance = match &sort_key {
+        Some((name, _)) => {
+            summary.insert("sort_column".to_string(), serde_json::json!(name));
+            Message::new("row_limit_guidance").arg("column", name)
+        }
+        None => Message::new("row_limit_guidance_unsorted"),
+    };
+    let guidance = guidance.arg("count", count).arg("max_rows", limit.max_rows);
+    let (text, locale) = render_message(req, &guidance);
+    
+    Some(
+        HttpResponse::Ok()
+            .insert_header(("Content-Language", locale.unwrap_or("en")))
+            .json(serde_json::json!({
+                "summary": summary,
+                "max_rows": limit.max_rows,
+                "guidance": text,
+            })),
+    )
+}
+
+// Starts the transaction a table's queries run in, applying the table's
+// configured session settings with SET LOCAL semantics.
+pub(crate) async fn begin_table_transaction<'a>(
+    pool: &'a PgPool,
+    config: &Config,
+    table: &str,
+) -> Result<sqlx::Transaction<'a, sqlx::Postgres>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    apply_table_settings(&mut tx, config, table).await?;
+    Ok(tx)
+}
+
+// Counts the rows matching the filters and one more condition, which gets
+// `value` as the parameter after the filters'
+pub(crate) async fn count_matching(
+    pool: &PgPool,
+    table: &str,
+    filters: &FilterExpr,
+    condition: impl Fn(usize) -> String,
+    value: Option<&str>,
+) -> Result<i64, sqlx::Error> {
+    let where_clause = build_where_clause(filters);
+    let condition = condition(filters.param_count() + 1);
+    let sql = match where_clause.is_empty() {
+        true => format!("SELECT COUNT(*) FROM {} WHERE {}", table, condition),
+        false => format!("SELECT COUNT(*) FROM {}{} AND {}", table, where_clause, condition),
+    };
+    let sql = tag_query(table, &sql);
+    let mut query = bind_filters(sqlx::query(&sql), filters);
+    if let Some(value) = value {
+        query = query.bind(value);
+    }
+    query.fetch_one(pool).await?.try_get(0)
+}
+
+pub(crate) fn is_text_type(sql_type: &str) -> bool {
+    ["text", "character", "citext", "name"].iter().any(|t| sql_type.starts_with(t))
+}
+
+// ?debug=semantics: where NULLs, the text comparison of filters and
+// collations shaped the result. Each top-level filter is checked against the
+// rows the other filters match: rows whose column is NULL, which no
+// comparison matches, and for columns still compared as text (like uuids
+// and enums) rows a comparison of the typed value would have decided
+// differently. The sort column reports where
+// its NULLs go and the collation it sorts by. Checks that fail are left out.
+pub(crate) async fn semantics_report(
+    pool: &PgPool,
+    table: &str,
+    filters: &FilterExpr,
+    order_by: &OrderBy,
+    query_params: &QueryParams,
+) -> Vec<serde_json::Value> {
+    let columns = fetch_column_types(pool, table).await.unwrap_or_default();
+    let children = match filters {
+        FilterExpr::And(children) => children.clone(),
+        other => vec![other.clone()],
+    };
+    let mut findings = Vec::new();
+    for (i, child) in children.iter().enumerate() {
+        let FilterExpr::Condition(c) = child else {
+            continue;
+        };
+        if c.operator == "IS" || c.operator == "IS NOT" {
+            continue;
+        }
+        let value = match &c.value {
+            FilterValue::Single(v) => v.clone(),
+            FilterValue::List(values) => format!("({})", values.join(",")),
+            FilterValue::Null => continue,
+        };
+        let filter = format!("{} {} {}", c.column, c.operator, value);
+        let others = FilterExpr::And(children.iter().enumerate().filter(|(j, _)| *j != i).map(|(_, f)| f.clone()).collect());
+        let column_type = columns.get(&c.column.to_lowercase());
+        
+        if !column_type.is_some_and(|(_, not_null)| *not_null) {
+            match count_matching(pool, table, &others, |_| format!("{} IS NULL", c.column), None).await {
+                Ok(rows) if rows > 0 => findings.push(serde_json::json!({
+                    "kind": "null",
+                    "filter": filter,
+                    "rows": rows,
+                    "note": format!("{} rows excluded by {} because {} is NULL", rows, c.operator, c.column),
+                })),
+                Ok(_) => {}
+                Err(e) => log::debug!("Semantics NULL check failed: {}", e),
+            }
+        }
+        
+        let comparison = ["=", "!=", "<", ">", "<=", ">="].contains(&c.operator.as_str());
+        let Some((sql_type, _)) = column_type.filter(|(t, _)| comparison && c.cast.is_none() && !is_text_type(t)) else {
+            continue;
+        };
+        let FilterValue::Single(single) = &c.value else {
+            continue;
+        };
+        let differs = |n: usize| {
+            format!(
+                "({col}::text {op} ${n}::text) IS DISTINCT FROM ({col} {op} ${n}::{ty})",
+                col = c.column,
+                op = c.operator,
+                n = n,
+                ty = sql_type
+            )
+        };
+        match count_matching(pool, table, &others, differs, Some(single)).await {
+            Ok(rows) if rows > 0 => findings.push(serde_json::json!({
+                "kind": "cast",
+                "filter": filter,
+                "rows": rows,
+                "note": format!(
+                    "{} is compared as text; {} rows match differently than a {} comparison would",
+                    c.column, rows, sql_type
+                ),
+            })),
+            Ok(_) => {}
+            Err(e) => log::debug!("Semantics cast check failed: {}", e),
+        }
+    }
+    
+    let Some(term) = order_by.terms.first().filter(|_| query_params.sort.is_some()) else {
+        return findings;
+    };
+    let sort = query_params.sort.clone().unwrap_or_default();
+    match count_matching(pool, table, filters, |_| format!("({}) IS NULL", term.column), None).await {
+        Ok(rows) if rows > 0 => {
+            let position = if order_by.direction == "DESC" { "first" } else { "last" };
+            findings.push(serde_json::json!({
+                "kind": "null_order",
+                "sort": sort,
+                "rows": rows,
+                "note": format!("{} rows with a NULL {} sort {} ({})", rows, term.column, position, order_by.direction),
+            }));
+        }
+        Ok(_) => {}
+        Err(e) => log::debug!("Semantics NULL order check failed: {}", e),
+    }
+    let collation = match &term.collation {
+        Some(collation) => Some(collation.clone()),
+        None if columns.get(&term.column.to_lowercase()).is_some_and(|(t, _)| is_text_type(t)) => {
+            sqlx::query_scalar::<_, String>(
+                "SELECT CASE WHEN c.collname = 'default'
+                             THEN (SELECT datcollate::text FROM pg_database WHERE datname = current_database())
+                             ELSE c.collname::text END
+                 FROM pg_attribute a JOIN pg_collation c ON c.oid = a.attcollation
+                 WHERE a.attrelid = to_regclass($1) AND a.attname = $2",
+            )
+            .bind(table)
+            .bind(term.column.to_lowercase())
+            .fetch_optional(pool)
+            .await
+            .unwrap_or_else(|e| {
+                log::debug!("Semantics collation check failed: {}", e);
+                None
+            })
+        }
+        None => None,
+    };
+    if let Some(collation) = collation {
+        findings.push(serde_json::json!({
+            "kind": "collation",
+            "sort": sort,
+            "collation": collation,
+            "note": format!("{} is sorted by the collation {}", term.column, collation),
+        }));
+    }
+    findings
+}
+
+// Estimated JSON bytes per row by table and select list, for
+// DATAPI_PAGE_BYTE_BUDGET, each kept for ROW_WIDTH_TTL
+#[derive(Default)]
+pub(crate) struct RowWidths {
+    pub(crate) widths: Mutex<HashMap<String, (Option<f64>, Instant)>>,
+}
+
+pub(crate) const ROW_WIDTH_TTL: Duration = Duration::from_secs(300);
+
+// Rows sampled for the width of tables without statistics or of a select
+// list with virtual columns
+pub(crate) const ROW_WIDTH_SAMPLE: usize = 100;
+
+impl RowWidths {
+    // The average width of the table's rows as JSON objects. Plain reads
+    // add up pg_stats' average column widths and the keys; otherwise, or
+    // before the table is analyzed, a sample of rows is rendered.
+    pub(crate) async fn estimate(&self, pool: &PgPool, table: &str, select_list: &str) -> Option<f64> {
+        let key = format!("{} {}", RowCounts::key(table), select_list);
+        if let Some((width, at)) = self.widths.lock().unwrap().get(&key) {
+            if at.elapsed() < ROW_WIDTH_TTL {
+                return *width;
+            }
+        }
+        let from_stats = if select_list == "*" {
+            sqlx::query_scalar::<_, Option<f64>>(
+                "SELECT sum(s.avg_width + length(s.attname) + 4)::float8
+                 FROM pg_stats s
+                 JOIN pg_class c ON c.relname = s.tablename
+                 JOIN pg_namespace n ON n.oid = c.relnamespace AND n.nspname = s.schemaname
+                 WHERE c.oid = to_regclass($1)",
+            )
+            .bind(table)
+            .fetch_one(pool)
+            .await
+        } else {
+            Ok(None)
+        };
+        let width = match from_stats {
+            Ok(Some(width)) => Ok(Some(width)),
+            Ok(None) => {
+                let sample = tag_query(table, &format!(
+                    "SELECT avg(octet_length(row_to_json(t)::text))::float8 FROM (SELECT {} FROM {} LIMIT {}) t",
+                    select_list, table, ROW_WIDTH_SAMPLE
+                ));
+                sqlx::query_scalar::<_, Option<f64>>(&sample).fetch_one(pool).await
+            }
+            Err(e) => Err(e),
+        };
+        let width = width.unwrap_or_else(|e| {
+            log::warn!("Row width estimate for {} failed: {}", table, e);
+            None
+        });
+        self.widths.lock().unwrap().insert(key, (width, Instant::now()));
+        width
+    }
+}
+
+// The page size that keeps a page of the table under the byte budget, when
+// that is fewer rows than asked for
+pub(crate) async fn page_size_for_budget(req: &HttpRequest, pool: &PgPool, config: &Config, table: &str, select_list: &str, page_size: usize) -> Option<usize> {
+    let budget = config.page_byte_budget?;
+    let widths = req.app_data::<web::Data<RowWidths>>()?;
+    let width = widths.estimate(pool, table, select_list).await?;
+    let rows = ((budget as f64 / width.max(1.0)) as usize).max(1);
+    (rows < page_size).then_some(rows)
+}
+
+// Failed counts per table, served at /_admin/counts
+#[derive(Default)]
+pub(crate) struct CountFailures {
+    pub(crate) tables: Mutex<BTreeMap<String, CountFailure>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct CountFailure {
+    pub(crate) failures: u64,
+    // Exact counts answered with an estimate after a failure
+    pub(crate) estimated: u64,
+    pub(crate) last_error: Option<String>,
+    pub(crate) last_failed_at: Option<chrono::DateTime<chrono::Utc>>,
+    // Set with DATAPI_COUNT_FAILURE_SKIP_SECS
+    pub(crate) estimate_until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl CountFailures {
+    pub(crate) fn record(&self, config: &Config, table: &str, error: &sqlx::Error) {
+        let now = chrono::Utc::now();
+        let mut tables = self.tables.lock().unwrap();
+        let failure = tables.entry(RowCounts::key(table)).or_default();
+        failure.failures += 1;
+        failure.last_error = Some(error.to_string());
+        failure.last_failed_at = Some(now);
+        if let Some(skip) = config.count_failure_skip.and_then(|d| chrono::Duration::from_std(d).ok()) {
+            failure.estimate_until = Some(now + skip);
+        }
+    }
+    
+    // Whether an exact count of the table should be estimated instead
+    pub(crate) fn take_estimate(&self, table: &str) -> bool {
+        let mut tables = self.tables.lock().unwrap();
+        let Some(failure) = tables.get_mut(&RowCounts::key(table)) else {
+            return false;
+        };
+        let estimate = failure.estimate_until.is_some_and(|until| chrono::Utc::now() < until);
+        if estimate {
+            failure.estimated += 1;
+        }
+        estimate
+    }
+}
+
+pub(crate) async fn count_failure_status(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    failures: web::Data<CountFailures>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    let tables = failures.tables.lock().unwrap();
+    HttpResponse::Ok().json(&*tables)
+}
+
+// Reads slower than DATAPI_SLOW_QUERY_MS per table, served at
+// /_admin/slow_queries
+#[derive(Default)]
+pub(crate) struct SlowQueries {
+    pub(crate) tables: Mutex<BTreeMap<String, SlowQueryStats>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct SlowQueryStats {
+    pub(crate) count: u64,
+    pub(crate) total_ms: f64,
+    pub(crate) max_ms: f64,
+    pub(crate) last_query: Option<String>,
+    pub(crate) last_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// Logs a read that took at least DATAPI_SLOW_QUERY_MS under datapi::slow_query,
+// with its bind values as DATAPI_LOG_BINDS renders them and the caller (the
+// token subject or X-Actor, else the peer address), and counts it.
+pub(crate) fn record_slow_query(req: &HttpRequest, config: &Config, table: &str, query: &str, binds: &[String], elapsed: Duration) {
+    if config.slow_query_ms.is_none_or(|ms| elapsed < Duration::from_millis(ms)) {
+        return;
+    }
+    let duration_ms = (elapsed.as_secs_f64() * 1000.0 * 100.0).round() / 100.0;
+    let client = request_actor(req).unwrap_or_else(|| match req.peer_addr() {
+        Some(addr) => addr.ip().to_string(),
+        None => "unknown".to_string(),
+    });
+    let binds = binds.join(", ");
+    log::warn!(
+        target: "datapi::slow_query",
+        table = table,
+        duration_ms = duration_ms,
+        client = client.as_str(),
+        binds = binds.as_str();
+        "Slow query on {} took {:.2}ms for {}: {} with values [{}]", table, duration_ms, client, query, binds
+    );
+    if let Some(slow) = req.app_data::<web::Data<SlowQueries>>() {
+        let mut tables = slow.tables.lock().unwrap();
+        let stats = tables.entry(RowCounts::key(table)).or_default();
+        stats.count += 1;
+        stats.total_ms += duration_ms;
+        stats.max_ms = stats.max_ms.max(duration_ms);
+        stats.last_query = Some(query.to_string());
+        stats.last_at = Some(chrono::Utc::now());
+    }
+}
+
+pub(crate) async fn slow_query_status(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    slow: web::Data<SlowQueries>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    let tables = slow.tables.lock().unwrap();
+    HttpResponse::Ok().json(serde_json::json!({ "threshold_ms": config.slow_query_ms, "tables": &*tables }))
+}
+
+// Row counts of the tables with count_refresh_secs, keyed like the table
+// configuration
+#[derive(Default)]
+pub(crate) struct RowCounts {
+    pub(crate) counts: Mutex<HashMap<String, i64>>,
+}
+
+impl RowCounts {
+    pub(crate) fn key(table: &str) -> String {
+        table.trim_matches('"').to_lowercase()
+    }
+    
+    pub(crate) fn get(&self, table: &str) -> Option<usize> {
+        self.counts.lock().unwrap().get(&Self::key(table)).map(|count| (*count).max(0) as usize)
+    }
+    
+    pub(crate) fn set(&self, table: &str, count: i64) {
+        self.counts.lock().unwrap().insert(Self::key(table), count);
+    }
+    
+    // Counts only move once the table has been counted
+    pub(crate) fn adjust(&self, table: &str, delta: i64) {
+        if let Some(count) = self.counts.lock().unwrap().get_mut(&Self::key(table)) {
+            *count += delta;
+        }
+    }
+    
+    pub(crate) fn forget(&self, table: &str) {
+        self.counts.lock().unwrap().remove(&Self::key(table));
+    }
+}
+
+// Moves the kept count of a table by a committed write. Requests routed to a
+// named database write elsewhere.
+pub(crate) fn adjust_row_count(req: &HttpRequest, table: &str, delta: i64) {
+    if req.extensions().contains::<DatabaseName>() {
+        return;
+    }
+    if let Some(counts) = req.app_data::<web::Data<RowCounts>>() {
+        counts.adjust(table, delta);
+    }
+}
+
+// Drops the kept count after a write whose effect on it isn't known, e.g. an
+// upsert, so reads count the table until the next recount
+pub(crate) fn forget_row_count(req: &HttpRequest, table: &str) {
+    if req.extensions().contains::<DatabaseName>() {
+        return;
+    }
+    if let Some(counts) = req.app_data::<web::Data<RowCounts>>() {
+        counts.forget(table);
+    }
+}
+
+pub(crate) async fn maintain_row_count(pool: PgPool, table: String, interval: Duration, counts: web::Data<RowCounts>) {
+    let query = tag_query(&table, &format!("SELECT COUNT(*) FROM {}", table));
+    loop {
+        match sqlx::query_scalar::<_, i64>(&query).fetch_one(&pool).await {
+            Ok(count) => counts.set(&table, count),
+            Err(e) => log::warn!("Counting rows of {} failed: {}", table, e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+// count=estimated: reltuples is free for a plain table that has been
+// analyzed; filtered reads, views and unanalyzed tables take the planner's
+// estimate instead.
+pub(crate) async fn estimate_count(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    table: &str,
+    where_clause: &str,
+    filters: &FilterExpr,
+    distinct: Option<&str>,
+) -> Result<usize, sqlx::Error> {
+    if where_clause.is_empty() && distinct.is_none() {
+        let reltuples: Option<f32> = sqlx::query_scalar(
+            "SELECT reltuples FROM pg_class WHERE oid = to_regclass($1) AND relkind IN ('r', 'm') AND reltuples >= 0",
+        )
+        .bind(table)
+        .fetch_optional(&mut **tx)
+        .await?;
+        if let Some(reltuples) = reltuples {
+            return Ok(reltuples as usize);
+        }
+    }
+    let plan: serde_json::Value = bind_filters(
+        sqlx::query(&format!("EXPLAIN (FORMAT JSON) SELECT {} FROM {}{}", distinct.unwrap_or("1"), table, where_clause)),
+        filters,
+    )
+    .fetch_one(&mut **tx)
+    .await?
+    .try_get(0)?;
+    Ok(plan[0]["Plan"]["Plan Rows"].as_f64().unwrap_or(0.0) as usize)
+}
+
+// `?timeout_ms=` sets statement_timeout for the rest of the transaction,
+// after the table settings. It is capped by the server maximum, and 0 (no
+// timeout in Postgres) counts as 1.
+pub(crate) async fn apply_request_timeout(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    config: &Config,
+    timeout_ms: Option<u64>,
+) -> Result<(), sqlx::Error> {
+    let Some(ms) = timeout_ms else {
+        return Ok(());
+    };
+    let ms = config.max_statement_timeout_ms.map_or(ms, |max| ms.min(max)).max(1);
+    sqlx::query("SELECT set_config('statement_timeout', $1, true)")
+        .bind(ms.to_string())
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+// Write transactions also pass the caller to the history triggers as
+// `datapi.actor`: the JWT subject, or else the X-Actor header naming the end
+// user the client acts for. Claim settings are set like for reads.
+pub(crate) async fn begin_write_transaction<'a>(
+    req: &HttpRequest,
+    pool: &'a PgPool,
+    config: &Config,
+    table: &str,
+) -> Result<sqlx::Transaction<'a, sqlx::Postgres>, sqlx::Error> {
+    let mut tx = begin_table_transaction(pool, config, table).await?;
+    apply_actor(&mut tx, req).await?;
+    apply_claim_settings(&mut tx, &claim_settings(req, config)).await?;
+    Ok(tx)
+}
+
+// The caller a write is made by, see begin_write_transaction
+pub(crate) fn request_actor(req: &HttpRequest) -> Option<String> {
+    let subject = req.extensions().get::<TokenGrants>().and_then(|g| g.subject.clone());
+    subject.or_else(|| req.headers().get(ACTOR_HEADER).and_then(|v| v.to_str().ok()).map(str::to_string))
+}
+
+pub(crate) async fn apply_actor(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    req: &HttpRequest,
+) -> Result<(), sqlx::Error> {
+    if let Some(actor) = request_actor(req) {
+        sqlx::query("SELECT set_config('datapi.actor', $1, true)")
+            .bind(actor)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
+}
+
+// The jwt_settings of the request's token, with string claims as they are
+// and others as JSON. Missing claims are left unset.
+pub(crate) fn claim_settings(req: &HttpRequest, config: &Config) -> Vec<(String, String)> {
+    let Some(jwt) = config.jwt.as_ref().filter(|jwt| !jwt.settings.is_empty()) else {
+        return Vec::new();
+    };
+    let extensions = req.extensions();
+    let Some(grants) = extensions.get::<TokenGrants>() else {
+        return Vec::new();
+    };
+    jwt.settings
+        .iter()
+        .filter_map(|(setting, claim)| {
+            let value = claim.split('.').try_fold(&grants.claims, |value, key| value.get(key))?;
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                serde_json::Value::Null => return None,
+                other => other.to_string(),
+            };
+            Some((setting.clone(), value))
+        })
+        .collect()
+}
+
+// Sets the claim settings for the rest of the transaction, see claim_settings
+pub(crate) async fn apply_claim_settings(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    settings: &[(String, String)],
+) -> Result<(), sqlx::Error> {
+    for (name, value) in settings {
+        sqlx::query("SELECT set_config($1, $2, true)")
+            .bind(name)
+            .bind(value)
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
+}
+
+// Sets the table's configured settings for the rest of the transaction
+pub(crate) async fn apply_table_settings(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    config: &Config,
+    table: &str,
+) -> Result<(), sqlx::Error> {
+    if let Some(table_config) = config.table(table) {
+        for (name, value) in &table_config.settings {
+            sqlx::query("SELECT set_config($1, $2, true)")
+                .bind(name)
+                .bind(value)
+                .execute(&mut **tx)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+// Materialized results live in unlogged tables named after their token. The
+// table comment records the expiry (unix seconds) and the row count so any
+// instance can page from or clean up a result.
+pub(crate) const RESULT_TABLE_PREFIX: &str = "datapi_result_";
+pub(crate) const RESULT_ROW_COLUMN: &str = "__datapi_row";
+pub(crate) const RESULT_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(crate) fn result_table_name(token: &str) -> Option<String> {
+    let valid = token.len() == 32 && token.chars().all(|c| c.is_ascii_hexdigit());
+    valid.then(|| format!("{}{}", RESULT_TABLE_PREFIX, token.to_ascii_lowercase()))
+}
+
+pub(crate) fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub(crate) struct ResultMeta {
+    pub(crate) expires: u64,
+    pub(crate) rows: u64,
+    // Source table, used to apply its response policies (e.g. encryption)
+    pub(crate) table: Option<String>,
+}
+
+// Parses a `datapi expires=<unix> rows=<n> table=<name>` table comment.
+pub(crate) fn parse_result_comment(comment: &str) -> Option<ResultMeta> {
+    let rest = comment.strip_prefix("datapi ")?;
+    let mut expires = None;
+    let mut rows = None;
+    let mut table = None;
+    for part in rest.split_whitespace() {
+        match part.split_once('=') {
+            Some(("expires", v)) => expires = v.parse().ok(),
+            Some(("rows", v)) => rows = v.parse().ok(),
+            Some(("table", v)) => table = Some(v.to_string()),
+            _ => {}
+        }
+    }
+    Some(ResultMeta { expires: expires?, rows: rows?, table })
+}
+
+// Runs the full (unpaginated) query once into a result table and returns its
+// token. Rows are numbered in the requested order so pages are index lookups.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn materialize_result(
+    pool: &PgPool,
+    config: &Config,
+    settings: &[(String, String)],
+    table: &str,
+    select_list: &str,
+    where_clause: &str,
+    order_by_clause: &str,
+    filters: &FilterExpr,
+) -> Result<String, sqlx::Error> {
+    let mut tx = begin_table_transaction(pool, config, table).await?;
+    apply_claim_settings(&mut tx, settings).await?;
+    
+    let token: String = sqlx::query_scalar("SELECT replace(gen_random_uuid()::text, '-', '')")
+        .fetch_one(&mut *tx)
+        .await?;
+    let result_table = format!("{}{}", RESULT_TABLE_PREFIX, token);
+    
+    sqlx::query(&format!(
+        "CREATE UNLOGGED TABLE {} AS SELECT 0::bigint AS {}, {} FROM {} WITH NO DATA",
+        result_table, RESULT_ROW_COLUMN, select_list, table
+    ))
+    .execute(&mut *tx)
+    .await?;
+    
+    let insert = tag_query(table, &format!(
+        "INSERT INTO {} SELECT row_number() OVER ({}), {} FROM {}{}",
+        result_table,
+        order_by_clause.trim(),
+        select_list,
+        table,
+        where_clause
+    ));
+    log::info!("Materializing result: {}", insert);
+    let rows = bind_filters(sqlx::query(&insert), filters).execute(&mut *tx).await?.rows_affected();
+    
+    sqlx::query(&format!("ALTER TABLE {} ADD PRIMARY KEY ({})", result_table, RESULT_ROW_COLUMN))
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(&format!(
+        "COMMENT ON TABLE {} IS 'datapi expires={} rows={} table={}'",
+        result_table,
+        unix_now() + config.result_ttl_secs,
+        rows,
+        table
+    ))
+    .execute(&mut *tx)
+    .await?;
+    
+    tx.commit().await?;
+    Ok(token)
+}
+
+pub(crate) async fn result_page_response(
+    req: &HttpRequest,
+    pool: &PgPool,
+    config: &Config,
+    breaker: &CircuitBreaker,
+    token: &str,
+    page: usize,
+    page_size: usize,
+) -> HttpResponse {
+    let Some(result_table) = result_table_name(token) else {
+        return error_response(req, StatusCode::NOT_FOUND, Message::new("result_not_found"));
+    };
+    
+    let comment: Result<Option<String>, sqlx::Error> =
+        sqlx::query_scalar("SELECT obj_description(to_regclass($1), 'pg_class')")
+            .bind(&result_table)
+            .fetch_one(pool)
+            .await;
+    let meta = match comment {
+        Ok(Some(c)) => match parse_result_comment(&c) {
+            Some(meta) if meta.expires > unix_now() => meta,
+            _ => return error_response(req, StatusCode::NOT_FOUND, Message::new("result_not_found")),
+        },
+        Ok(None) => return error_response(req, StatusCode::NOT_FOUND, Message::new("result_not_found")),
+        Err(e) => return database_error_response(req, breaker, e),
+    };
+    
+    let offset = (page - 1) * page_size;
+    let query = format!(
+        "SELECT * FROM {} WHERE {} > $1 ORDER BY {} LIMIT $2",
+        result_table, RESULT_ROW_COLUMN, RESULT_ROW_COLUMN
+    );
+    
+    match sqlx::query(&query)
+        .bind(offset as i64)
+        .bind(page_size as i64)
+        .fetch_all(pool)
+        .await
+    {
+        Ok(rows) => {
+            breaker.record_success();
+            note_read(req, meta.table.as_deref().unwrap_or(&result_table), Vec::new(), rows.len());
+            let results: Vec<serde_json::Value> = rows
+                .iter()
+                .map(|row| {
+                    let mut obj = row_to_json(row, config.value_format());
+                    obj.remove(RESULT_ROW_COLUMN);
+                    if let Some(table) = &meta.table {
+                        protect_columns(config, table, &mut obj);
+                    }
+                    serde_json::Value::Object(obj)
+                })
+                .collect();
+            
+            QueryResult {
+                count: results.len(),
+                data: results,
+                page,
+                page_size,
+                total_count: Some(meta.rows as usize),
+                total_count_estimated: false,
+                result_token: Some(token.to_ascii_lowercase()),
+                next_cursor: None,
+                sort_fingerprint: None,
+                warnings: Vec::new(),
+                total_pages: None,
+                has_next: false,
+            }
+            .into_response(req, HttpResponse::Ok())
+        }
+        Err(e) => database_error_response(req, breaker, e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ResultPageParams {
+    pub(crate) page: Option<usize>,
+    pub(crate) page_size: Option<usize>,
+}
+
+pub(crate) async fn query_result(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<String>,
+    query_params: web::Query<ResultPageParams>,
+) -> impl Responder {
+    let page = query_params.page.unwrap_or(1).max(1);
+    let page_size = quota_page_size(&req, config.page_size(query_params.page_size));
+    result_page_response(&req, &pool, &config, &breaker, &path.into_inner(), page, page_size).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use actix_web::test::TestRequest;
+    use sqlx::postgres::PgPoolOptions;
+    use crate::background::SCHEMA_RELOADED;
+    use crate::config::{JwtConfig, JwtKey};
+    use crate::filters::{finish_filters_with, parse_multiple_filters};
+    use crate::handlers::{reload_schema, sniff_content_type};
+
+    #[actix_web::test]
+    async fn order_without_a_known_key_is_unstable() {
+        let pool = offline_pool();
+        let req = TestRequest::default().to_http_request();
+        let order = build_order_by(&req, &pool, "t", Some("name"), "DESC", None, None).await;
+        assert_eq!((order.clause(), order.stable), (" ORDER BY name DESC".to_string(), false));
+        let order = build_order_by(&req, &pool, "t", None, "ASC", None, None).await;
+        assert_eq!((order.clause(), order.stable), (String::new(), false));
+    }
+
+    #[actix_web::test]
+    async fn collation_needs_a_known_text_column() {
+        // The column type can't be looked up, so no COLLATE is added
+        let req = TestRequest::default().to_http_request();
+        let order = build_order_by(&req, &offline_pool(), "t", Some("name"), "ASC", None, Some("de-DE")).await;
+        assert_eq!(order.clause(), " ORDER BY name ASC");
+    }
+
+    #[actix_web::test]
+    async fn order_keys_and_text_columns_come_from_the_schema_cache() {
+        let cache = web::Data::new(SchemaCache { ttl: Duration::from_secs(60), ..Default::default() });
+        let key = (String::new(), "t".to_string());
+        cache.unique_keys.lock().unwrap().insert(key.clone(), (Instant::now(), vec![vec!["id".to_string()]]));
+        cache.text_columns.lock().unwrap().insert(key, (Instant::now(), vec!["name".to_string()]));
+        let req = TestRequest::default().app_data(cache.clone()).to_http_request();
+        let order = build_order_by(&req, &offline_pool(), "t", Some("name"), "ASC", None, Some("de-DE")).await;
+        assert_eq!((order.clause(), order.stable), (" ORDER BY name COLLATE \"de-DE\" ASC, id ASC".to_string(), true));
+        cache.clear();
+        assert!(cache.unique_keys.lock().unwrap().is_empty() && cache.text_columns.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn queries_are_tagged_with_their_table() {
+        assert_eq!(tag_query("loans", "SELECT 1"), "/* datapi table=loans */ SELECT 1");
+    }
+
+    #[test]
+    fn result_tokens_name_their_tables() {
+        assert_eq!(
+            result_table_name("0123456789ABCDEF0123456789abcdef").as_deref(),
+            Some("datapi_result_0123456789abcdef0123456789abcdef")
+        );
+        assert!(result_table_name("0123456789abcdef").is_none());
+        assert!(result_table_name("0123456789abcdef0123456789abcdeg").is_none());
+        let meta = parse_result_comment("datapi expires=1700000000 rows=42 table=loans").unwrap();
+        assert_eq!((meta.expires, meta.rows, meta.table.as_deref()), (1_700_000_000, 42, Some("loans")));
+        assert!(parse_result_comment("datapi expires=1700000000 rows=42").unwrap().table.is_none());
+        assert!(parse_result_comment("datapi rows=42").is_none());
+        assert!(parse_result_comment("expires=1 rows=2").is_none());
+    }
+
+    #[actix_web::test]
+    async fn unknown_result_tokens_are_not_found() {
+        let req = TestRequest::default().to_http_request();
+        let breaker = CircuitBreaker::new(&test_config());
+        let resp = result_page_response(&req, &offline_pool(), &test_config(), &breaker, "not-a-token", 1, 10).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn select_lists_are_sanitized() {
+        let config = test_config();
+        assert_eq!(parse_select(&config, "t", None, &[], false).unwrap(), "*");
+        assert_eq!(parse_select(&config, "t", Some("id, name"), &[], false).unwrap(), "id, name");
+        assert_eq!(parse_select(&config, "t", Some("id,,name"), &[], false).unwrap_err().key, "empty_select");
+        assert_eq!(parse_select(&config, "t", Some("id;drop"), &[], false).unwrap_err().key, "invalid_column_name");
+    }
+
+    fn order_by(terms: &[(&str, Option<&str>)], direction: &str, stable: bool) -> OrderBy {
+        OrderBy {
+            terms: terms
+                .iter()
+                .map(|(column, collation)| OrderTerm { column: column.to_string(), collation: collation.map(String::from), nulls: None })
+                .collect(),
+            direction: direction.to_string(),
+            stable,
+        }
+    }
+
+    #[test]
+    fn cursors_continue_after_the_last_row() {
+        let columns = HashMap::from([
+            ("name".to_string(), ("text".to_string(), true)),
+            ("id".to_string(), ("integer".to_string(), true)),
+            ("note".to_string(), ("text".to_string(), false)),
+        ]);
+        let order = order_by(&[("name", Some("de-DE")), ("id", None)], "DESC", true);
+
+        let fingerprint = sort_fingerprint(&order, &FilterExpr::none());
+        let first = Keyset::new(&order, &columns, "", 1, fingerprint.clone()).unwrap();
+        assert!(first.predicate.is_none());
+        assert_eq!(first.where_clause(" WHERE a = $1"), " WHERE a = $1");
+        assert_eq!(
+            first.select_columns(),
+            ", (name COLLATE \"de-DE\")::text AS __datapi_cursor_0, (id)::text AS __datapi_cursor_1"
+        );
+
+        let cursor = first.cursor(vec!["Bob".to_string(), "7".to_string()]);
+        let next = Keyset::new(&order, &columns, &cursor, 1, fingerprint.clone()).unwrap();
+        assert_eq!(next.values, ["Bob", "7"]);
+        assert_eq!(
+            next.where_clause(" WHERE a = $1"),
+            " WHERE a = $1 AND (name COLLATE \"de-DE\", id) < (CAST($2::text AS text) COLLATE \"de-DE\", CAST($3::text AS integer))"
+        );
+        let mut row = serde_json::Map::new();
+        row.insert("__datapi_cursor_0".to_string(), serde_json::json!("Bob"));
+        row.insert("__datapi_cursor_1".to_string(), serde_json::json!("7"));
+        assert_eq!(next.take_values(&mut row), ["Bob", "7"]);
+        assert!(row.is_empty());
+
+        let new = |cursor: &str| Keyset::new(&order, &columns, cursor, 0, fingerprint.clone());
+        assert_eq!(new("not base64!").err().unwrap().key, "invalid_cursor");
+        assert_eq!(new(&first.cursor(vec!["Bob".to_string()])).err().unwrap().key, "invalid_cursor");
+    }
+
+    #[test]
+    fn cursors_only_continue_their_own_sort() {
+        let columns = HashMap::from([("id".to_string(), ("integer".to_string(), true))]);
+        let ascending = order_by(&[("id", None)], "ASC", true);
+        let descending = order_by(&[("id", None)], "DESC", true);
+        let unfiltered = sort_fingerprint(&ascending, &FilterExpr::none());
+        assert_eq!(unfiltered.len(), 16);
+        assert_eq!(unfiltered, sort_fingerprint(&ascending, &FilterExpr::none()));
+        assert_ne!(unfiltered, sort_fingerprint(&descending, &FilterExpr::none()));
+        let filters = parse_multiple_filters("id=1").unwrap();
+        assert_ne!(unfiltered, sort_fingerprint(&ascending, &filters));
+
+        let first = Keyset::new(&ascending, &columns, "", 0, unfiltered.clone()).unwrap();
+        let cursor = first.cursor(vec!["7".to_string()]);
+        assert!(Keyset::new(&ascending, &columns, &cursor, 0, unfiltered.clone()).is_ok());
+        let other = sort_fingerprint(&descending, &FilterExpr::none());
+        let mismatch = Keyset::new(&descending, &columns, &cursor, 0, other).err().unwrap();
+        assert_eq!(mismatch.key, "cursor_mismatch");
+        assert_eq!(mismatch.args, [("fingerprint", unfiltered)]);
+    }
+
+    #[test]
+    fn cursors_need_a_stable_order_on_not_null_columns() {
+        let columns = HashMap::from([
+            ("id".to_string(), ("integer".to_string(), true)),
+            ("note".to_string(), ("text".to_string(), false)),
+        ]);
+        for order in [
+            order_by(&[("id", None)], "ASC", false),
+            order_by(&[("note", None), ("id", None)], "ASC", true),
+            order_by(&[], "ASC", true),
+        ] {
+            let fingerprint = sort_fingerprint(&order, &FilterExpr::none());
+            assert_eq!(Keyset::new(&order, &columns, "", 0, fingerprint).err().unwrap().key, "cursor_unsupported");
+        }
+    }
+
+    #[test]
+    fn virtual_columns_expand_to_their_expressions() {
+        let config = config_with(serde_json::json!({
+            "orders": { "virtual_columns": { "gross": "net * 1.19" } }
+        }));
+        assert_eq!(parse_select(&config, "orders", None, &[], false).unwrap(), "*, (net * 1.19) AS gross");
+        assert_eq!(parse_select(&config, "\"orders\"", Some("id,Gross"), &[], false).unwrap(), "id, (net * 1.19) AS gross");
+        assert_eq!(parse_select(&config, "refunds", None, &[], false).unwrap(), "*");
+
+        let filters = finish_filters_with(&TestRequest::default().to_http_request(), parse_multiple_filters("gross>100&id=1").unwrap(), None, &config, "orders", &HashMap::new()).unwrap();
+        assert_eq!(build_where_clause(&filters), " WHERE (net * 1.19)::text > $1::text AND id::text = $2::text");
+    }
+
+    #[actix_web::test]
+    async fn overflow_handling_is_validated() {
+        let req = TestRequest::default().to_http_request();
+        let config = Config { max_rows: Some(10), ..test_config() };
+        let breaker = CircuitBreaker::new(&config);
+        let run = |params| {
+            let params = query_params(params);
+            let (req, config, breaker) = (&req, &config, &breaker);
+            async move { run_query(req, &offline_pool(), config, breaker, "loans", &FilterExpr::none(), &params).await }
+        };
+        let resp = run(serde_json::json!({ "on_overflow": "truncate" })).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        // The row count probe needs the database
+        let resp = run(serde_json::json!({ "on_overflow": "summarize" })).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn resumable_exports_cover_the_whole_table() {
+        let req = TestRequest::default().to_http_request();
+        let config = test_config();
+        let breaker = CircuitBreaker::new(&config);
+        let run = |params| {
+            let params = query_params(params);
+            let (req, config, breaker) = (&req, &config, &breaker);
+            async move { run_query(req, &offline_pool(), config, breaker, "loans", &FilterExpr::none(), &params).await }
+        };
+        let resp = run(serde_json::json!({ "format": "ndjson", "checkpoints": true, "page_size": 10 })).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json_body(resp).await["error"],
+            "checkpoints and resume_token apply to whole NDJSON exports, without page or page_size"
+        );
+        let resp = run(serde_json::json!({ "format": "ndjson", "resume_token": "abc" })).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_web::test]
+    async fn read_only_mode_refuses_to_materialize() {
+        let req = TestRequest::default().to_http_request();
+        let config = Config { read_only: true, ..test_config() };
+        let params = query_params(serde_json::json!({ "materialize": true }));
+        let resp = run_query(&req, &offline_pool(), &config, &CircuitBreaker::new(&config), "loans", &FilterExpr::none(), &params).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(json_body(resp).await["error"], "Results can't be materialized in read-only mode");
+    }
+
+    #[test]
+    fn counts_are_exact_unless_asked_otherwise() {
+        assert_eq!(CountMode::parse(None).unwrap(), CountMode::Exact);
+        assert_eq!(CountMode::parse(Some("estimated")).unwrap(), CountMode::Estimated);
+        assert_eq!(CountMode::parse(Some("none")).unwrap(), CountMode::None);
+        assert_eq!(CountMode::parse(Some("fast")).unwrap_err().key, "invalid_count");
+    }
+
+    #[test]
+    fn server_timing_lists_the_phases_when_enabled() {
+        let mut trace = QueryTrace::start();
+        trace.mark("acquire");
+        trace.mark("execute");
+        assert!(trace.finish(&test_config(), "loans", "SELECT 1", &[]).is_none());
+        let timing = trace.finish(&Config { server_timing: true, ..test_config() }, "loans", "SELECT 1", &[]).unwrap();
+        let phases: Vec<&str> = timing.to_str().unwrap().split(", ").map(|p| p.split(';').next().unwrap()).collect();
+        assert_eq!(phases, vec!["acquire", "execute"]);
+    }
+
+    #[actix_web::test]
+    async fn consistency_picks_the_primary_or_the_replica() {
+        let replica_pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(200))
+            .connect_lazy("postgres://datapi@127.0.0.1:1/replica")
+            .unwrap();
+        let replica = Replica { pool: Some(replica_pool), max_wait: Duration::ZERO, max_lag_bytes: 1024 };
+        let database = |uri: &str| {
+            let req = TestRequest::get().uri(uri).to_http_request();
+            let replica = &replica;
+            async move {
+                let pool = replica.read_pool(&req, &offline_pool()).await?;
+                Ok::<_, Message>(pool.connect_options().get_database().unwrap_or_default().to_string())
+            }
+        };
+        assert_eq!(database("/loans").await.unwrap(), "replica");
+        assert_eq!(database("/loans?consistency=strong").await.unwrap(), "datapi");
+        assert_eq!(database("/loans?consistency=eventual").await.unwrap(), "replica");
+        // The lag can't be measured, so bounded reads fall back to the primary
+        assert_eq!(database("/loans?consistency=bounded").await.unwrap(), "datapi");
+        assert_eq!(database("/loans?consistency=fresh").await.unwrap_err().key, "invalid_consistency");
+    }
+
+    #[actix_web::test]
+    async fn random_samples_are_a_single_page() {
+        let req = TestRequest::default().to_http_request();
+        let config = test_config();
+        let breaker = CircuitBreaker::new(&config);
+        let run = |params| {
+            let params = query_params(params);
+            let (req, config, breaker) = (&req, &config, &breaker);
+            async move { run_query(req, &offline_pool(), config, breaker, "loans", &FilterExpr::none(), &params).await }
+        };
+        for params in [
+            serde_json::json!({ "sort": "random", "page": 2 }),
+            serde_json::json!({ "sort": "RANDOM", "format": "ndjson" }),
+            serde_json::json!({ "sort": "random", "materialize": true }),
+        ] {
+            let resp = run(params).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(
+                json_body(resp).await["error"],
+                "sort=random returns a single page of at most 100 rows, without page, cursor, materialize or ndjson"
+            );
+        }
+        assert_eq!(run(serde_json::json!({ "sort": "random" })).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn kept_row_counts_move_with_writes_once_counted() {
+        let counts = web::Data::new(RowCounts::default());
+        counts.adjust("loans", 5);
+        assert_eq!(counts.get("loans"), None);
+        counts.set("loans", 10);
+        counts.adjust("\"Loans\"", -3);
+        assert_eq!(counts.get("LOANS"), Some(7));
+        counts.adjust("loans", -20);
+        assert_eq!(counts.get("loans"), Some(0));
+
+        let req = TestRequest::default().app_data(counts.clone()).to_http_request();
+        counts.set("loans", 10);
+        adjust_row_count(&req, "loans", 2);
+        assert_eq!(counts.get("loans"), Some(12));
+        req.extensions_mut().insert(DatabaseName("archive".to_string()));
+        adjust_row_count(&req, "loans", 2);
+        assert_eq!(counts.get("loans"), Some(12));
+    }
+
+    #[actix_web::test]
+    async fn semantics_debugging_reports_what_it_can_check() {
+        let req = TestRequest::default().to_http_request();
+        let config = test_config();
+        let breaker = CircuitBreaker::new(&config);
+        for params in [serde_json::json!({ "debug": "plan" }), serde_json::json!({ "debug": "semantics", "format": "csv" })] {
+            let params = query_params(params);
+            let resp = run_query(&req, &offline_pool(), &config, &breaker, "loans", &FilterExpr::none(), &params).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+
+        // Checks needing the database are left out when it fails
+        let filters = parse_multiple_filters("status=open").unwrap();
+        let params = query_params(serde_json::json!({ "sort": "name" }));
+        let report = semantics_report(&offline_pool(), "loans", &filters, &order_by(&[("name", Some("de-DE-x-icu"))], "ASC", false), &params).await;
+        assert_eq!(
+            report,
+            vec![serde_json::json!({
+                "kind": "collation", "sort": "name", "collation": "de-DE-x-icu",
+                "note": "name is sorted by the collation de-DE-x-icu"
+            })]
+        );
+        assert!(is_text_type("character varying(20)") && !is_text_type("integer"));
+    }
+
+    #[actix_web::test]
+    async fn page_sizes_shrink_to_the_byte_budget() {
+        let widths = web::Data::new(RowWidths::default());
+        let key = |table: &str| format!("{} *", RowCounts::key(table));
+        for (table, width) in [("loans", Some(100.0)), ("scans", Some(5000.0)), ("empty", None)] {
+            widths.widths.lock().unwrap().insert(key(table), (width, Instant::now()));
+        }
+        let req = TestRequest::default().app_data(widths).to_http_request();
+        let pool = offline_pool();
+        let config = Config { page_byte_budget: Some(1000), ..test_config() };
+        let cap = |table: &'static str, page_size: usize| page_size_for_budget(&req, &pool, &config, table, "*", page_size);
+        assert_eq!(cap("loans", 100).await, Some(10));
+        assert_eq!(cap("loans", 5).await, None);
+        assert_eq!(cap("scans", 100).await, Some(1));
+        assert_eq!(cap("empty", 100).await, None);
+        let unbudgeted = test_config();
+        assert_eq!(page_size_for_budget(&req, &pool, &unbudgeted, "loans", "*", 100).await, None);
+    }
+
+    #[test]
+    fn failed_counts_are_estimated_for_a_while() {
+        let failures = CountFailures::default();
+        assert!(!failures.take_estimate("loans"));
+        failures.record(&test_config(), "loans", &sqlx::Error::PoolTimedOut);
+        // Without a skip period the next count is exact again
+        assert!(!failures.take_estimate("loans"));
+        let config = Config { count_failure_skip: Some(Duration::from_secs(60)), ..test_config() };
+        failures.record(&config, "loans", &sqlx::Error::PoolTimedOut);
+        assert!(failures.take_estimate("loans"));
+        assert!(!failures.take_estimate("customers"));
+
+        let report = serde_json::to_value(&*failures.tables.lock().unwrap()).unwrap();
+        let loans = &report[RowCounts::key("loans")];
+        assert_eq!((loans["failures"].as_u64(), loans["estimated"].as_u64()), (Some(2), Some(1)));
+        assert_eq!(loans["last_error"], sqlx::Error::PoolTimedOut.to_string());
+        assert!(loans["estimate_until"].is_string());
+    }
+
+    #[test]
+    fn claim_settings_follow_the_token() {
+        let jwt = |settings: &[(&str, &str)]| JwtConfig {
+            key: JwtKey::Secret("s3cret".to_string()),
+            issuer: None,
+            audience: None,
+            tables_claim: "tables".to_string(),
+            role_claim: "role".to_string(),
+            roles: BTreeMap::new(),
+            settings: settings.iter().map(|(s, c)| (s.to_string(), c.to_string())).collect(),
+        };
+        let config = Config {
+            jwt: Some(jwt(&[("request.jwt.claim.sub", "sub"), ("request.org_id", "org.id"), ("request.team", "team")])),
+            ..test_config()
+        };
+        let req = TestRequest::default().to_http_request();
+        assert!(claim_settings(&req, &config).is_empty());
+        let mut token = grants(&["loans"]);
+        token.claims["sub"] = serde_json::json!("alice");
+        token.claims["team"] = serde_json::Value::Null;
+        req.extensions_mut().insert(token);
+        assert_eq!(
+            claim_settings(&req, &config),
+            [("request.jwt.claim.sub".to_string(), "alice".to_string()), ("request.org_id".to_string(), "7".to_string())]
+        );
+        assert!(claim_settings(&req, &Config { jwt: Some(jwt(&[])), ..test_config() }).is_empty());
+    }
+
+    #[test]
+    fn pages_link_to_their_neighbours() {
+        let position = |uri: &str, page, count, total: Option<usize>, cursor: Option<&str>| {
+            PagePosition::of(&TestRequest::get().uri(uri).to_http_request(), page, 10, count, total, cursor)
+        };
+        let middle = position("/loans?status=open&page=2&page_size=10", 2, 10, Some(35), None);
+        assert_eq!((middle.total_pages, middle.has_next), (Some(4), true));
+        assert_eq!(
+            middle.links,
+            "</loans?status=open&page_size=10&page=3>; rel=\"next\", </loans?status=open&page_size=10&page=1>; rel=\"prev\", \
+             </loans?status=open&page_size=10&page=1>; rel=\"first\", </loans?status=open&page_size=10&page=4>; rel=\"last\""
+        );
+        // Past the end, prev goes back to the last page
+        let past = position("/loans?page=9", 9, 0, Some(35), None);
+        assert!(!past.has_next);
+        assert!(past.links.starts_with("</loans?page=4>; rel=\"prev\""));
+        let uncounted = position("/loans", 1, 10, None, None);
+        assert_eq!((uncounted.total_pages, uncounted.has_next), (None, true));
+        assert_eq!(uncounted.links, "</loans?page=2>; rel=\"next\", </loans?page=1>; rel=\"first\"");
+        assert!(!position("/loans", 1, 7, None, None).has_next);
+        let keyset = position("/loans?cursor=abc&page_size=10", 1, 10, None, Some("a b"));
+        assert!(keyset.has_next);
+        assert_eq!(
+            keyset.links,
+            "</loans?page_size=10&cursor=a%20b>; rel=\"next\", </loans?page_size=10&cursor=>; rel=\"first\""
+        );
+    }
+
+    #[actix_web::test]
+    async fn head_reads_only_count() {
+        let req = TestRequest::default().method(actix_web::http::Method::HEAD).to_http_request();
+        let config = test_config();
+        let breaker = CircuitBreaker::new(&config);
+        let read = |params: serde_json::Value| {
+            let params = query_params(params);
+            let (req, config, breaker) = (&req, &config, &breaker);
+            async move { run_query(req, &offline_pool(), config, breaker, "loans", &FilterExpr::none(), &params).await }
+        };
+        let resp = read(serde_json::json!({ "count": "maybe" })).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        // The count needs the database, and a HEAD has no body to carry a page
+        // without one
+        let resp = read(serde_json::json!({})).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(resp.headers().get("X-Total-Count").is_none());
+    }
+
+    #[actix_web::test]
+    async fn geometries_are_geojson_and_filter_by_box_and_distance() {
+        let config = test_config();
+        let spatial = [("id".to_string(), "int4".to_string()), ("geom".to_string(), "geometry".to_string())];
+        assert_eq!(
+            parse_select(&config, "places", None, &spatial, false).unwrap(),
+            "\"id\", ST_AsGeoJSON(\"geom\")::json AS \"geom\""
+        );
+        assert_eq!(parse_select(&config, "places", Some("geom"), &spatial, false).unwrap(), "ST_AsGeoJSON(geom)::json AS geom");
+
+        let columns = HashMap::from([
+            ("geom".to_string(), ("geometry(Point,4326)".to_string(), false)),
+            ("area".to_string(), ("geography".to_string(), false)),
+            ("name".to_string(), ("text".to_string(), true)),
+        ]);
+        let req = TestRequest::default().to_http_request();
+        let where_clause = |filters: &str| {
+            let filters = parse_multiple_filters(filters)?;
+            finish_filters_with(&req, filters, None, &config, "places", &columns).map(|f| build_where_clause(&f))
+        };
+        assert_eq!(
+            where_clause("geom=bbox.(13.0,52.3,13.8,52.7)").unwrap(),
+            " WHERE geom && ST_MakeEnvelope(($1::float8[])[1], ($1::float8[])[2], ($1::float8[])[3], ($1::float8[])[4], 4326)"
+        );
+        assert_eq!(
+            where_clause("area=dwithin.(13.4,52.5,500)").unwrap(),
+            " WHERE ST_DWithin(area, ST_SetSRID(ST_MakePoint(($1::float8[])[1], ($1::float8[])[2]), 4326)::geography, ($1::float8[])[3])"
+        );
+        let e = where_clause("geom=dwithin.(13.4,52.5)").unwrap_err();
+        assert_eq!(e.key, "invalid_spatial_filter");
+        assert_eq!(where_clause("name=bbox.(0,0,1,1)").unwrap_err().key, "spatial_filter_unsupported");
+
+        let req = TestRequest::default().insert_header(("Accept", "application/geo+json")).to_http_request();
+        assert_eq!(response_format(&req, None).unwrap(), ResponseFormat::GeoJson);
+        let result = QueryResult {
+            data: vec![serde_json::json!({ "id": 1, "geom": { "type": "Point", "coordinates": [13.4, 52.5] } })],
+            count: 1,
+            page: 1,
+            page_size: 100,
+            total_count: Some(1),
+            total_count_estimated: false,
+            total_pages: Some(1),
+            has_next: false,
+            result_token: None,
+            next_cursor: None,
+            sort_fingerprint: None,
+            warnings: Vec::new(),
+        };
+        let resp = geojson_response(&TestRequest::default().to_http_request(), "places", "geom", &result);
+        assert_eq!(resp.headers().get("content-type").unwrap(), "application/geo+json");
+        assert_eq!(
+            json_body(resp).await,
+            serde_json::json!({ "type": "FeatureCollection", "features": [{
+                "type": "Feature",
+                "geometry": { "type": "Point", "coordinates": [13.4, 52.5] },
+                "properties": { "id": 1 }
+            }] })
+        );
+    }
+
+    #[test]
+    fn binary_columns_can_be_left_out_or_fetched_raw() {
+        let config = test_config();
+        let columns = [("id".to_string(), "int4".to_string()), ("scan".to_string(), "bytea".to_string())];
+        assert_eq!(parse_select(&config, "files", None, &columns, false).unwrap(), "*");
+        assert_eq!(parse_select(&config, "files", None, &columns, true).unwrap(), "\"id\"");
+        assert_eq!(parse_select(&config, "files", Some("id,scan"), &columns, true).unwrap(), "id");
+        assert_eq!(parse_select(&config, "files", Some("scan"), &columns, true).unwrap_err().key, "select_only_binary");
+
+        assert_eq!(sniff_content_type(b"\x89PNG\r\n\x1a\n...."), "image/png");
+        assert_eq!(sniff_content_type(b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff_content_type(b"%PDF-1.7"), "application/pdf");
+        assert_eq!(sniff_content_type(b"plain"), "application/octet-stream");
+    }
+
+    #[test]
+    fn embeds_select_related_rows_through_foreign_keys() {
+        let orders = relation("orders", &[("id", "customer_id")], true);
+        assert_eq!(
+            orders.select_sql("customers", "orders", &["\"deleted_at\" IS NULL".to_string()], 10, 0),
+            "(SELECT coalesce(json_agg(e), '[]'::json) FROM (SELECT * FROM orders e \
+             WHERE e.\"customer_id\" = customers.\"id\" AND (\"deleted_at\" IS NULL) LIMIT 10) e) AS \"orders\""
+        );
+        let customer = relation("customers", &[("customer_id", "id")], false);
+        assert!(customer.matches("customer") && customer.matches("customers"));
+        assert_eq!(
+            customer.select_sql("orders", "customer", &[], 10, 0),
+            "(SELECT row_to_json(e) FROM customers e WHERE e.\"id\" = orders.\"customer_id\" LIMIT 1) AS \"customer\""
+        );
+
+        let req = TestRequest::default().to_http_request();
+        let config = config_with(serde_json::json!({ "people": { "claim_filters": { "owner": "sub" } } }));
+        let relations = [
+            customer,
+            relation("people", &[("billed_by_id", "id")], false),
+            relation("people", &[("shipped_by_id", "id")], false),
+        ];
+        assert_eq!(embed_select(&req, &config, "orders", &relations, "Customer").unwrap().len(), 1);
+        assert_eq!(embed_select(&req, &config, "orders", &relations, "items").unwrap_err().key, "unknown_embed");
+        assert_eq!(embed_select(&req, &config, "orders", &relations, "people").unwrap_err().key, "ambiguous_embed");
+        assert_eq!(embed_select(&req, &config, "orders", &relations, "billed_by").unwrap_err().key, "embed_restricted");
+        assert_eq!(embed_select(&req, &config, "orders", &relations, "customer,").unwrap_err().key, "empty_select");
+
+        // Soft-deleted rows stay hidden inside embeds
+        let config = config_with(serde_json::json!({ "customers": { "soft_delete": "deleted_at" } }));
+        let embedded = embed_select(&req, &config, "orders", &relations, "customer").unwrap();
+        assert!(embedded[0].contains("AND (\"deleted_at\" IS NULL)"), "{}", embedded[0]);
+
+        // Embedded tables need a grant like the table itself
+        req.extensions_mut().insert(grants(&["orders"]));
+        let e = embed_select(&req, &config, "orders", &relations, "customer").unwrap_err();
+        assert_eq!((e.key, e.status), ("table_not_granted", Some(StatusCode::FORBIDDEN)));
+    }
+
+    #[actix_web::test]
+    async fn distinct_rows_and_distinct_on_columns() {
+        let config = config_with(serde_json::json!({ "orders": { "virtual_columns": { "gross": "net * 1.19" } } }));
+        let columns = [("id".to_string(), "int4".to_string()), ("customer".to_string(), "text".to_string())];
+        let parse = |params: serde_json::Value| Distinct::parse(&config, "orders", &query_params(params), &columns);
+        assert!(parse(serde_json::json!({})).unwrap().is_none());
+        let rows = parse(serde_json::json!({ "distinct": true })).unwrap().unwrap();
+        assert_eq!(rows.select_list("customer"), "DISTINCT customer");
+        let on = parse(serde_json::json!({ "distinct": true, "distinct_on": "Customer,gross" })).unwrap().unwrap();
+        assert_eq!(on.select_list("*"), "DISTINCT ON (customer, (net * 1.19)) *");
+        assert_eq!(on.count_list("*"), "DISTINCT customer, (net * 1.19)");
+        let e = parse(serde_json::json!({ "distinct_on": "total" })).unwrap_err();
+        assert_eq!(e.key, "unknown_column");
+
+        let mut order = order_by(&[("created", None), ("customer", None), ("id", None)], "DESC", true);
+        order.lead_with(&["customer".to_string()]);
+        assert_eq!(order.clause(), " ORDER BY customer DESC, created DESC, id DESC");
+        let mut order = order_by(&[("created", None), ("id", None)], "ASC", true);
+        order.keep_selected("Created, customer");
+        assert_eq!((order.clause(), order.stable), (" ORDER BY created ASC".to_string(), false));
+
+        let req = TestRequest::default().to_http_request();
+        let breaker = CircuitBreaker::new(&config);
+        let params = query_params(serde_json::json!({ "distinct": true, "cursor": "" }));
+        let resp = run_query(&req, &offline_pool(), &config, &breaker, "orders", &FilterExpr::none(), &params).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json_body(resp).await["error"], "distinct and distinct_on can't be combined with cursor");
+    }
+
+    #[test]
+    fn nulls_modifiers_apply_to_the_sorted_column_only() {
+        let mut order = order_by(&[("due", None), ("id", None)], "ASC", true);
+        order.terms[0].nulls = Some("LAST");
+        assert_eq!(order.clause(), " ORDER BY due ASC NULLS LAST, id ASC");
+    }
+
+    #[test]
+    fn selected_columns_can_be_renamed() {
+        let config = config_with(serde_json::json!({ "people": { "encrypted_columns": ["ssn"] } }));
+        let columns = [("id".to_string(), "int4".to_string()), ("name".to_string(), "text".to_string()), ("ssn".to_string(), "text".to_string())];
+        let select = |text: &str| parse_select(&config, "people", Some(text), &columns, false);
+        assert_eq!(select("id,name:fullName").unwrap(), "id, name AS \"fullName\"");
+        assert_eq!(select("name:name").unwrap(), "name");
+        assert_eq!(select("name:id").unwrap_err().key, "alias_conflict");
+        assert_eq!(select("ssn:plain").unwrap_err().key, "alias_protected");
+        assert_eq!(select("name:__datapi_x").unwrap_err().key, "invalid_alias");
+        assert_eq!(select("name:a b").unwrap_err().key, "invalid_alias");
+    }
+
+    #[test]
+    fn only_updatable_relations_are_writable() {
+        assert_eq!(relation_kind_name("m"), "materialized_view");
+        assert_eq!(relation_kind_name("p"), "partitioned_table");
+        assert!(RELATION_UPDATABLE_SQL.contains("pg_relation_is_updatable"));
+        assert!(RELATION_UPDATABLE_SQL.contains("c.relkind <> 'm'"));
+    }
+
+    #[actix_web::test]
+    async fn slow_reads_are_counted_per_table() {
+        let config = Config { slow_query_ms: Some(10), ..test_config() };
+        let slow = web::Data::new(SlowQueries::default());
+        let req = TestRequest::default()
+            .insert_header(("Authorization", "Bearer secret"))
+            .app_data(slow.clone())
+            .to_http_request();
+        let binds = ["7".to_string()];
+        record_slow_query(&req, &config, "loans", "SELECT 1", &binds, Duration::from_millis(5));
+        assert!(slow.tables.lock().unwrap().is_empty());
+        record_slow_query(&req, &config, "loans", "SELECT 2", &binds, Duration::from_millis(30));
+        record_slow_query(&req, &config, "\"loans\"", "SELECT 3", &binds, Duration::from_millis(20));
+
+        let resp = slow_query_status(req.clone(), web::Data::new(config), slow).await.respond_to(&req);
+        let body = json_body(resp).await;
+        assert_eq!(body["threshold_ms"], 10);
+        assert_eq!(body["tables"]["loans"]["count"], 2);
+        assert_eq!(body["tables"]["loans"]["max_ms"], 30.0);
+        assert_eq!(body["tables"]["loans"]["total_ms"], 50.0);
+        assert_eq!(body["tables"]["loans"]["last_query"], "SELECT 3");
+    }
+
+    #[actix_web::test]
+    async fn table_metadata_is_cached_per_database_until_reloaded() {
+        let cache = web::Data::new(SchemaCache { ttl: Duration::from_secs(60), ..Default::default() });
+        let req = TestRequest::default()
+            .insert_header(("Authorization", "Bearer secret"))
+            .app_data(cache.clone())
+            .to_http_request();
+        let columns = HashMap::from([("id".to_string(), ("integer".to_string(), false))]);
+        let key = SchemaCache::key(&req, "loans");
+        assert_eq!(key, (String::new(), "loans".to_string()));
+        cache.column_types.lock().unwrap().insert(key.clone(), (Instant::now(), columns.clone()));
+        // Served from the cache, the offline pool is never asked
+        assert_eq!(column_types(&req, &offline_pool(), "loans").await.unwrap(), columns);
+        req.extensions_mut().insert(DatabaseName("reports".to_string()));
+        assert_eq!(SchemaCache::key(&req, "loans").0, "reports");
+
+        let stale = SchemaCache { ttl: Duration::ZERO, ..Default::default() };
+        stale.column_types.lock().unwrap().insert(key.clone(), (Instant::now(), columns));
+        assert!(stale.get(&stale.column_types, &key).is_none());
+
+        let resp = reload_schema(req.clone(), web::Data::new(test_config()), cache.clone()).await.respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(cache.column_types.lock().unwrap().is_empty());
+        assert!(SCHEMA_RELOADED.lock().unwrap().is_some());
+    }
+
+    #[test]
+    fn parses_embed_pages() {
+        assert_eq!(parse_embed("orders").unwrap(), ("orders", None));
+        assert_eq!(
+            parse_embed("orders(page=2, page_size=10)").unwrap(),
+            ("orders", Some(EmbedPage { page: 2, page_size: Some(10) }))
+        );
+        assert_eq!(parse_embed("orders(page_size=5)").unwrap().1, Some(EmbedPage { page: 1, page_size: Some(5) }));
+        for invalid in ["orders(page=0)", "orders(limit=5)", "orders(page=2", "orders(page)"] {
+            assert_eq!(parse_embed(invalid).unwrap_err().key, "invalid_embed_option", "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn embeds_select_pages_of_related_rows() {
+        let orders = Relation { key: vec!["id".to_string()], ..relation("orders", &[("id", "customer_id")], true) };
+        let filters = vec!["\"deleted_at\" IS NULL".to_string()];
+        assert_eq!(
+            orders.select_sql("customers", "orders", &filters, 10, 20),
+            "(SELECT coalesce(json_agg(e), '[]'::json) FROM (SELECT * FROM orders e \
+             WHERE e.\"customer_id\" = customers.\"id\" AND (\"deleted_at\" IS NULL) \
+             ORDER BY e.\"id\" LIMIT 10 OFFSET 20) e) AS \"orders\""
+        );
+        assert_eq!(
+            orders.count_sql("customers", "orders", &[]),
+            "(SELECT count(*) FROM orders e WHERE e.\"customer_id\" = customers.\"id\") AS \"orders_count\""
+        );
+
+        let req = TestRequest::default().to_http_request();
+        let config = test_config();
+        let relations = [orders, relation("people", &[("customer_id", "id")], false)];
+        let columns = embed_select(&req, &config, "customers", &relations, "orders(page=3,page_size=5000)").unwrap();
+        assert!(columns[0].contains("LIMIT 1000 OFFSET 2000"), "{}", columns[0]);
+        assert!(columns[1].ends_with("AS \"orders_count\""));
+        assert_eq!(embed_select(&req, &config, "customers", &relations, "people(page=2)").unwrap_err().key, "embed_not_list");
+    }
+
+    #[test]
+    fn read_params_are_checked_before_the_sql_is_built() {
+        let config = test_config();
+        let req = TestRequest::default().to_http_request();
+        let parse = |params: serde_json::Value, rank: Option<&str>| {
+            ReadParams::parse(&req, &config, "loans", &query_params(params), rank).map_err(|e| (e.key, e.parameter().map(str::to_string)))
+        };
+        let read = parse(serde_json::json!({ "page": 3, "page_size": 20, "sort": "amount:desc:nullsfirst" }), None).unwrap();
+        assert_eq!((read.page, read.page_size), (3, 20));
+        assert_eq!((read.sort_column.as_deref(), read.sort_order.as_str()), (Some("amount"), "DESC"));
+        assert_eq!(read.sort_nulls, Some("FIRST"));
+        let read = parse(serde_json::json!({ "sort": "rank" }), Some("ts_rank(doc, q)")).unwrap();
+        assert_eq!((read.sort_column.as_deref(), read.sort_order.as_str()), (Some("ts_rank(doc, q)"), "DESC"));
+        let read = parse(serde_json::json!({ "sort": "random", "page_size": 500 }), None).unwrap();
+        assert!(read.random);
+        assert_eq!(read.page_size, config.random_sort_max_rows);
+
+        let refused = |params: serde_json::Value| parse(params, None).err().unwrap();
+        assert_eq!(refused(serde_json::json!({ "sort": "random", "page": 2 })), ("random_sort_single_page", Some("sort".to_string())));
+        assert_eq!(refused(serde_json::json!({ "tz": "Mars/Olympus" })), ("invalid_timezone", Some("tz".to_string())));
+        assert_eq!(refused(serde_json::json!({ "on_overflow": "drop" })), ("invalid_on_overflow", Some("on_overflow".to_string())));
+        assert_eq!(refused(serde_json::json!({ "collate": "fr" })), ("unsupported_collation", Some("collate".to_string())));
+        assert_eq!(refused(serde_json::json!({ "order": "sideways" })).1, Some("order".to_string()));
+    }
+}
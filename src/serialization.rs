@@ -0,0 +1,1141 @@
+use std::collections::BTreeMap;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use futures_util::{StreamExt, TryStreamExt};
+use serde::Serialize;
+use sqlx::postgres::PgRow;
+use sqlx::types::Decimal;
+use sqlx::{Column, PgPool, Row, TypeInfo};
+use crate::access::row_meter;
+use crate::config::{Config, FieldCipher, Mask};
+use crate::filters::{bind_filters, FilterExpr};
+use crate::hooks::request_hooks;
+use crate::messages::{error_response, Message};
+use crate::middleware::CircuitBreaker;
+use crate::query::{
+    apply_claim_settings, apply_request_timeout, begin_table_transaction, claim_settings, Keyset,
+    QueryResult, CURSOR_COLUMN_PREFIX,
+};
+use crate::writes::database_error_response;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum ResponseFormat {
+    Json,
+    Csv,
+    Ndjson,
+    Parquet,
+    GeoJson,
+    Xlsx,
+    Arrow,
+}
+
+// `?format=` wins over the Accept header.
+pub(crate) fn response_format(req: &HttpRequest, format: Option<&str>) -> Result<ResponseFormat, Message> {
+    match format.map(str::to_lowercase).as_deref() {
+        Some("json") => Ok(ResponseFormat::Json),
+        Some("csv") => Ok(ResponseFormat::Csv),
+        Some("ndjson") => Ok(ResponseFormat::Ndjson),
+        Some("parquet") => Ok(ResponseFormat::Parquet),
+        Some("geojson") => Ok(ResponseFormat::GeoJson),
+        Some("xlsx") => Ok(ResponseFormat::Xlsx),
+        Some("arrow") => Ok(ResponseFormat::Arrow),
+        Some(other) => Err(Message::new("unsupported_format").arg("format", other)),
+        None => {
+            let accept = req
+                .headers()
+                .get(actix_web::http::header::ACCEPT)
+                .and_then(|h| h.to_str().ok())
+                .unwrap_or("");
+            Ok(if accept.contains("text/csv") {
+                ResponseFormat::Csv
+            } else if accept.contains("application/x-ndjson") {
+                ResponseFormat::Ndjson
+            } else if accept.contains("application/vnd.apache.parquet") {
+                ResponseFormat::Parquet
+            } else if accept.contains("application/geo+json") {
+                ResponseFormat::GeoJson
+            } else if accept.contains(XLSX_CONTENT_TYPE) {
+                ResponseFormat::Xlsx
+            } else if accept.contains(ARROW_CONTENT_TYPE) {
+                ResponseFormat::Arrow
+            } else {
+                ResponseFormat::Json
+            })
+        }
+    }
+}
+
+pub(crate) const XLSX_CONTENT_TYPE: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+pub(crate) const ARROW_CONTENT_TYPE: &str = "application/vnd.apache.arrow.stream";
+
+pub(crate) const NDJSON_CHUNK_BYTES: usize = 64 * 1024;
+pub(crate) const NDJSON_BUFFERED_CHUNKS: usize = 8;
+pub(crate) const EXPORT_CHECKPOINT_ROWS: u64 = 10_000;
+
+// How streamed rows are delimited: one object per line (NDJSON), or the
+// elements of a single JSON array (`format=json&stream=true`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum StreamFraming {
+    Lines,
+    Array,
+}
+
+// Streams every matching row. The query runs in its own task that fetches
+// rows incrementally and hands ~64 KiB chunks over a bounded channel: once
+// NDJSON_BUFFERED_CHUNKS wait for a slow client, the task stops fetching, so
+// memory stays flat however large the result. The first chunk is awaited
+// before responding so query errors still get a proper error status; a
+// failure later aborts the response.
+//
+// With a keyset the NDJSON export is resumable: the query selects the order
+// values, and every EXPORT_CHECKPOINT_ROWS rows a `{"_resume_token": ...}`
+// line records the last row sent. A final `{"_complete": true}` line tells a
+// finished export from a cut-off one. `page` holds the LIMIT and OFFSET
+// values, bound after the filter and keyset values.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn stream_response(
+    req: &HttpRequest,
+    pool: &PgPool,
+    breaker: &CircuitBreaker,
+    table: &str,
+    query: String,
+    filters: &FilterExpr,
+    keyset: Option<Keyset>,
+    page: Vec<String>,
+    timeout_ms: Option<u64>,
+    format: ValueFormat,
+    framing: StreamFraming,
+) -> HttpResponse {
+    let Some(config) = req.app_data::<web::Data<Config>>().cloned() else {
+        return error_response(req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("internal_error"));
+    };
+    let (sender, mut receiver) =
+        tokio::sync::mpsc::channel::<Result<web::Bytes, sqlx::Error>>(NDJSON_BUFFERED_CHUNKS);
+    let settings = claim_settings(req, &config);
+    let pool = pool.clone();
+    let table = table.to_string();
+    let filters = filters.clone();
+    let hooks = request_hooks(req);
+    let meter = row_meter(req);
+    
+    tokio::spawn(async move {
+        let begin = async {
+            let mut tx = begin_table_transaction(&pool, &config, &table).await?;
+            apply_request_timeout(&mut tx, &config, timeout_ms).await?;
+            apply_claim_settings(&mut tx, &settings).await?;
+            Ok(tx)
+        };
+        let mut tx = match begin.await {
+            Ok(tx) => tx,
+            Err(e) => {
+                let _ = sender.send(Err(e)).await;
+                return;
+            }
+        };
+        let mut stream = bind_filters(sqlx::query(&query), &filters);
+        for value in keyset.iter().flat_map(|k| &k.values).chain(&page) {
+            stream = stream.bind(value);
+        }
+        let mut rows = stream.fetch(&mut *tx);
+        let mut buffer = match framing {
+            StreamFraming::Lines => Vec::new(),
+            StreamFraming::Array => b"[".to_vec(),
+        };
+        let mut first = true;
+        let mut sent = 0u64;
+        let mut written = 0u64;
+        // Rows count against the key's quota as their chunk goes out
+        let mut metered = 0u64;
+        let mut meter_sent = |written: u64| {
+            if let Some(meter) = &meter {
+                meter.record(written - metered);
+            }
+            metered = written;
+        };
+        loop {
+            match rows.try_next().await {
+                Ok(Some(row)) => {
+                    let mut obj = row_to_json(&row, format);
+                    let last = keyset.as_ref().map(|k| (k, k.take_values(&mut obj)));
+                    protect_columns(&config, &table, &mut obj);
+                    let mut objs = vec![obj];
+                    hooks.after_rows(&table, &mut objs);
+                    for obj in &objs {
+                        if framing == StreamFraming::Array && written > 0 {
+                            buffer.push(b',');
+                        }
+                        if serde_json::to_writer(&mut buffer, obj).is_ok() {
+                            written += 1;
+                            if framing == StreamFraming::Lines {
+                                buffer.push(b'\n');
+                            }
+                        }
+                    }
+                    sent += 1;
+                    if let Some((keyset, last)) = last.filter(|_| sent.is_multiple_of(EXPORT_CHECKPOINT_ROWS)) {
+                        let checkpoint = serde_json::json!({ "_resume_token": keyset.cursor(last), "_rows": sent });
+                        let _ = serde_json::to_writer(&mut buffer, &checkpoint);
+                        buffer.push(b'\n');
+                    }
+                    if first || buffer.len() >= NDJSON_CHUNK_BYTES {
+                        first = false;
+                        let chunk = web::Bytes::from(std::mem::take(&mut buffer));
+                        if sender.send(Ok(chunk)).await.is_err() {
+                            // Client went away
+                            return;
+                        }
+                        meter_sent(written);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    let _ = sender.send(Err(e)).await;
+                    return;
+                }
+            }
+        }
+        drop(rows);
+        if framing == StreamFraming::Array {
+            buffer.push(b']');
+        }
+        if keyset.is_some() {
+            let _ = serde_json::to_writer(&mut buffer, &serde_json::json!({ "_complete": true, "_rows": sent }));
+            buffer.push(b'\n');
+        }
+        if !buffer.is_empty() && sender.send(Ok(web::Bytes::from(buffer))).await.is_ok() {
+            meter_sent(written);
+        }
+        if let Err(e) = tx.commit().await {
+            log::warn!("Commit failed: {}", e);
+        }
+    });
+    
+    let first = match receiver.recv().await {
+        Some(Ok(chunk)) => chunk,
+        Some(Err(e)) => {
+            return database_error_response(req, breaker, e);
+        }
+        None => web::Bytes::new(),
+    };
+    breaker.record_success();
+    
+    let rest = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let item = receiver.recv().await?;
+        let item = item.map_err(|e| {
+            log::error!("Streaming error: {}", e);
+            actix_web::error::ErrorInternalServerError(e)
+        });
+        Some((item, receiver))
+    });
+    let body = futures_util::stream::once(async move { Ok::<_, actix_web::Error>(first) }).chain(rest);
+    
+    let content_type = match framing {
+        StreamFraming::Lines => "application/x-ndjson",
+        StreamFraming::Array => "application/json",
+    };
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .streaming(body)
+}
+
+pub(crate) fn csv_field(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+// Builds an Arrow record batch from the page for Parquet and Arrow IPC.
+// Integers, floats, booleans, dates and timestamps keep their Arrow types;
+// numeric becomes a 38-digit decimal at the largest scale on the page.
+// Encrypted, masked and other columns are written as strings from the JSON
+// rendering, as is every column unless `typed`.
+pub(crate) fn record_batch(
+    config: &Config,
+    table: &str,
+    columns: &[(String, String)],
+    rows: &[PgRow],
+    results: &[serde_json::Value],
+    typed: bool,
+) -> Result<arrow_array::RecordBatch, Box<dyn std::error::Error>> {
+    use arrow_array::{
+        ArrayRef, BooleanArray, Date32Array, Decimal128Array, Float32Array, Float64Array,
+        Int16Array, Int32Array, Int64Array, RecordBatch, StringArray,
+        TimestampMicrosecondArray,
+    };
+    use std::sync::Arc;
+    
+    let encrypted = |column: &str| {
+        config
+            .table(table.trim_matches('"'))
+            .is_some_and(|t| t.encrypted_columns.iter().any(|c| c == &column.to_lowercase()))
+    };
+    let epoch = chrono::NaiveDate::from_ymd_opt(1970, 1, 1).unwrap_or_default();
+    
+    let mut arrays: Vec<(String, ArrayRef)> = Vec::new();
+    for (name, type_name) in columns {
+        let name = name.as_str();
+        let mask = config.mask(table, name);
+        let strings = || -> ArrayRef {
+            Arc::new(
+                results
+                    .iter()
+                    .map(|row| match row.get(name) {
+                        Some(serde_json::Value::Null) | None => None,
+                        Some(serde_json::Value::String(s)) => Some(s.clone()),
+                        Some(other) => Some(other.to_string()),
+                    })
+                    .collect::<StringArray>(),
+            )
+        };
+        let array: ArrayRef = if encrypted(name) || mask.is_some() || !typed {
+            strings()
+        } else {
+            match type_name.as_str() {
+                "INT2" => Arc::new(Int16Array::from(column_values::<i16>(rows, name)?)),
+                "INT4" => Arc::new(Int32Array::from(column_values::<i32>(rows, name)?)),
+                "INT8" => Arc::new(Int64Array::from(column_values::<i64>(rows, name)?)),
+                "FLOAT4" => Arc::new(Float32Array::from(column_values::<f32>(rows, name)?)),
+                "FLOAT8" => Arc::new(Float64Array::from(column_values::<f64>(rows, name)?)),
+                "BOOL" => Arc::new(BooleanArray::from(column_values::<bool>(rows, name)?)),
+                "DATE" => Arc::new(
+                    column_values::<chrono::NaiveDate>(rows, name)?
+                        .into_iter()
+                        .map(|d| d.map(|d| (d - epoch).num_days() as i32))
+                        .collect::<Date32Array>(),
+                ),
+                "TIMESTAMP" => Arc::new(
+                    column_values::<chrono::NaiveDateTime>(rows, name)?
+                        .into_iter()
+                        .map(|t| t.map(|t| t.and_utc().timestamp_micros()))
+                        .collect::<TimestampMicrosecondArray>(),
+                ),
+                "TIMESTAMPTZ" => Arc::new(
+                    column_values::<chrono::DateTime<chrono::Utc>>(rows, name)?
+                        .into_iter()
+                        .map(|t| t.map(|t| t.timestamp_micros()))
+                        .collect::<TimestampMicrosecondArray>()
+                        .with_timezone("UTC"),
+                ),
+                "NUMERIC" => {
+                    let values = column_values::<Decimal>(rows, name)?;
+                    let scale = values.iter().flatten().map(|d| d.scale()).max().unwrap_or(0);
+                    let mantissas: Decimal128Array = values
+                        .into_iter()
+                        .map(|value| {
+                            value.map(|mut d| {
+                                d.rescale(scale);
+                                d.mantissa()
+                            })
+                        })
+                        .collect();
+                    Arc::new(mantissas.with_precision_and_scale(38, scale as i8)?)
+                }
+                _ => strings(),
+            }
+        };
+        arrays.push((name.to_string(), array));
+    }
+    
+    Ok(RecordBatch::try_from_iter(arrays)?)
+}
+
+pub(crate) fn write_parquet(batch: &arrow_array::RecordBatch) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut writer = parquet::arrow::ArrowWriter::try_new(Vec::new(), batch.schema(), None)?;
+    writer.write(batch)?;
+    Ok(writer.into_inner()?)
+}
+
+// An Arrow IPC stream holding the page as a single record batch, which
+// pyarrow, polars and DuckDB read without parsing
+pub(crate) fn write_arrow(batch: &arrow_array::RecordBatch) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut writer = arrow_ipc::writer::StreamWriter::try_new(Vec::new(), &batch.schema())?;
+    writer.write(batch)?;
+    writer.finish()?;
+    Ok(writer.into_inner()?)
+}
+
+// Builds a workbook with the page on one sheet, under a bold header row that
+// stays in view. Integers, floats, numerics, booleans, dates and timestamps
+// are typed cells, timestamptz in the request's time zone since Excel has
+// none; values Excel can't hold exactly (beyond 15 digits, before 1900) and
+// other, encrypted and masked columns are text from the JSON rendering, as is
+// every column unless `typed`.
+pub(crate) fn write_xlsx(
+    config: &Config,
+    table: &str,
+    columns: &[(String, String)],
+    rows: &[PgRow],
+    results: &[serde_json::Value],
+    typed: bool,
+    tz: chrono_tz::Tz,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    use chrono::Datelike;
+    use rust_xlsxwriter::{Format, Workbook};
+    
+    enum Cell {
+        Number(f64),
+        Bool(bool),
+        Date(chrono::NaiveDate),
+        DateTime(chrono::NaiveDateTime),
+    }
+    // Excel's numbers are doubles
+    const EXACT: u64 = 1 << 53;
+    // Characters Excel allows in a cell
+    const MAX_TEXT: usize = 32_767;
+    
+    let encrypted = |column: &str| {
+        config
+            .table(table.trim_matches('"'))
+            .is_some_and(|t| t.encrypted_columns.iter().any(|c| c == &column.to_lowercase()))
+    };
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet();
+    let header = Format::new().set_bold();
+    let date = Format::new().set_num_format("yyyy-mm-dd");
+    let datetime = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+    for (col, (name, type_name)) in columns.iter().enumerate() {
+        let col = u16::try_from(col)?;
+        let name = name.as_str();
+        sheet.write_string_with_format(0, col, name, &header)?;
+        let text = encrypted(name) || config.mask(table, name).is_some() || !typed;
+        let cell = |row: &PgRow| -> Result<Option<Cell>, sqlx::Error> {
+            Ok(match type_name.as_str() {
+                _ if text => None,
+                "INT2" => row.try_get::<Option<i16>, _>(name)?.map(|v| Cell::Number(v.into())),
+                "INT4" => row.try_get::<Option<i32>, _>(name)?.map(|v| Cell::Number(v.into())),
+                "INT8" => row.try_get::<Option<i64>, _>(name)?.filter(|v| v.unsigned_abs() < EXACT).map(|v| Cell::Number(v as f64)),
+                "FLOAT4" => row.try_get::<Option<f32>, _>(name)?.map(|v| Cell::Number(v.into())),
+                "FLOAT8" => row.try_get::<Option<f64>, _>(name)?.filter(|v| v.is_finite()).map(Cell::Number),
+                "NUMERIC" => row
+                    .try_get::<Option<Decimal>, _>(name)?
+                    .filter(|d| d.mantissa().unsigned_abs() < u128::from(EXACT))
+                    .and_then(|d| d.to_string().parse().ok())
+                    .map(Cell::Number),
+                "BOOL" => row.try_get::<Option<bool>, _>(name)?.map(Cell::Bool),
+                "DATE" => row.try_get::<Option<chrono::NaiveDate>, _>(name)?.filter(|d| d.year() >= 1900).map(Cell::Date),
+                "TIMESTAMP" => row
+                    .try_get::<Option<chrono::NaiveDateTime>, _>(name)?
+                    .filter(|t| t.year() >= 1900)
+                    .map(Cell::DateTime),
+                "TIMESTAMPTZ" => row
+                    .try_get::<Option<chrono::DateTime<chrono::Utc>>, _>(name)?
+                    .map(|t| t.with_timezone(&tz).naive_local())
+                    .filter(|t| t.year() >= 1900)
+                    .map(Cell::DateTime),
+                _ => None,
+            })
+        };
+        for (i, (row, result)) in rows.iter().zip(results).enumerate() {
+            let i = u32::try_from(i + 1)?;
+            match cell(row)? {
+                Some(Cell::Number(n)) => sheet.write_number(i, col, n)?,
+                Some(Cell::Bool(b)) => sheet.write_boolean(i, col, b)?,
+                Some(Cell::Date(d)) => sheet.write_datetime_with_format(i, col, d, &date)?,
+                Some(Cell::DateTime(t)) => sheet.write_datetime_with_format(i, col, t, &datetime)?,
+                None => {
+                    let text = match result.get(name) {
+                        Some(serde_json::Value::Null) | None => continue,
+                        Some(serde_json::Value::String(s)) => s.chars().take(MAX_TEXT).collect::<String>(),
+                        Some(other) => other.to_string().chars().take(MAX_TEXT).collect(),
+                    };
+                    sheet.write_string(i, col, text)?
+                }
+            };
+        }
+    }
+    sheet.set_freeze_panes(1, 0)?;
+    Ok(workbook.save_to_buffer()?)
+}
+
+pub(crate) fn column_values<'r, T>(rows: &'r [PgRow], name: &str) -> Result<Vec<Option<T>>, sqlx::Error>
+where
+    T: sqlx::Decode<'r, sqlx::Postgres> + sqlx::Type<sqlx::Postgres>,
+{
+    rows.iter().map(|r| r.try_get::<Option<T>, _>(name)).collect()
+}
+
+pub(crate) fn write_csv(columns: &[String], rows: &[serde_json::Value]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(columns)?;
+    for row in rows {
+        writer.write_record(
+            columns
+                .iter()
+                .map(|c| csv_field(row.get(c).unwrap_or(&serde_json::Value::Null))),
+        )?;
+    }
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+// Renders the page as CSV with a header row in column order.
+pub(crate) fn csv_response(req: &HttpRequest, table: &str, columns: &[(String, String)], result: &QueryResult) -> HttpResponse {
+    let names: Vec<String> = columns.iter().map(|(name, _)| name.clone()).collect();
+    match write_csv(&names, &result.data) {
+        Ok(body) => file_response(table, "csv", "text/csv; charset=utf-8", body, result),
+        Err(e) => {
+            log::error!("CSV serialization error: {}", e);
+            error_response(req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("internal_error"))
+        }
+    }
+}
+
+// A page as a GeoJSON FeatureCollection: each row is a feature with the
+// table's first geometry column as its geometry and the other columns as
+// properties
+pub(crate) fn geojson_response(req: &HttpRequest, table: &str, geometry_column: &str, result: &QueryResult) -> HttpResponse {
+    let features: Vec<serde_json::Value> = result
+        .data
+        .iter()
+        .map(|row| {
+            let mut properties = row.as_object().cloned().unwrap_or_default();
+            let geometry = properties.remove(geometry_column).unwrap_or(serde_json::Value::Null);
+            serde_json::json!({ "type": "Feature", "geometry": geometry, "properties": properties })
+        })
+        .collect();
+    let collection = serde_json::json!({ "type": "FeatureCollection", "features": features });
+    match serde_json::to_vec(&collection) {
+        Ok(body) => file_response(table, "geojson", "application/geo+json", body, result),
+        Err(e) => {
+            log::error!("GeoJSON serialization error: {}", e);
+            error_response(req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("internal_error"))
+        }
+    }
+}
+
+// A page rendered as a downloadable file. Pagination metadata moves to
+// X-Total-Count / X-Next-Cursor headers.
+pub(crate) fn file_response(
+    table: &str,
+    extension: &str,
+    content_type: &str,
+    body: Vec<u8>,
+    result: &QueryResult,
+) -> HttpResponse {
+    let mut builder = HttpResponse::Ok();
+    builder.content_type(content_type).insert_header((
+        actix_web::http::header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}.{}\"", table.trim_matches('"'), extension),
+    ));
+    if let Some(total) = result.total_count {
+        builder.insert_header(("X-Total-Count", total.to_string()));
+        if result.total_count_estimated {
+            builder.insert_header(("X-Total-Count-Estimated", "true"));
+        }
+    }
+    if let Some(cursor) = &result.next_cursor {
+        builder.insert_header(("X-Next-Cursor", cursor.as_str()));
+    }
+    if let Some(fingerprint) = &result.sort_fingerprint {
+        builder.insert_header(("X-Sort-Fingerprint", fingerprint.as_str()));
+    }
+    builder.body(body)
+}
+
+// Masks, then encrypts the values of the table's configured sensitive
+// columns in place. NULLs of encrypted columns are left as-is.
+pub(crate) fn protect_columns(config: &Config, table: &str, obj: &mut serde_json::Map<String, serde_json::Value>) {
+    let Some(table_config) = config.table(table.trim_matches('"')) else {
+        return;
+    };
+    for (column, mask) in &table_config.masked_columns {
+        if let Some(value) = obj.remove(column) {
+            if let Some(masked) = mask.apply(&value) {
+                obj.insert(column.clone(), masked);
+            }
+        }
+    }
+    let Some(cipher) = &config.cipher else {
+        return;
+    };
+    for column in table_config.encrypted_columns.iter().filter(|c| !table_config.masked_columns.contains_key(*c)) {
+        if let Some(value) = obj.get_mut(column) {
+            if !value.is_null() {
+                *value = serde_json::Value::String(cipher.encrypt(value));
+            }
+        }
+    }
+}
+
+pub(crate) fn row_to_json(row: &PgRow, format: ValueFormat) -> serde_json::Map<String, serde_json::Value> {
+    let mut obj = serde_json::Map::new();
+    
+    for (i, column) in row.columns().iter().enumerate() {
+        let kind = ColumnKind::of(column.type_info());
+        obj.insert(column.name().to_string(), cell_to_json(row, i, kind, format));
+    }
+    
+    obj
+}
+
+// QueryResult without the data, for pages serialized by JsonPageWriter
+#[derive(Serialize)]
+pub(crate) struct PageMeta {
+    pub(crate) count: usize,
+    pub(crate) page: usize,
+    pub(crate) page_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) total_count: Option<usize>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) total_count_estimated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) total_pages: Option<usize>,
+    pub(crate) has_next: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) next_cursor: Option<String>,
+    // With a cursor: the order and filters the cursors belong to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) sort_fingerprint: Option<String>,
+    // Things that went wrong without failing the request, like the count
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) warnings: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) debug: Option<serde_json::Value>,
+}
+
+// How a column's values are decoded, picked once per result from its type
+#[derive(Clone, Copy)]
+pub(crate) enum ColumnKind {
+    Int2,
+    Int4,
+    Int8,
+    Float4,
+    Float8,
+    Numeric,
+    Text,
+    // Enums and citext, sent as their text
+    Label,
+    Bool,
+    Date,
+    Time,
+    Timestamp,
+    Timestamptz,
+    Interval,
+    Json,
+    Uuid,
+    Bytea,
+    Int4Array,
+    Int8Array,
+    Float8Array,
+    NumericArray,
+    TextArray,
+    BoolArray,
+    // Rendered as an unsupported_type marker, see unsupported_value
+    Unsupported,
+}
+
+impl ColumnKind {
+    pub(crate) fn of(type_info: &sqlx::postgres::PgTypeInfo) -> Self {
+        match type_info.name() {
+            "INT2" => ColumnKind::Int2,
+            "INT4" => ColumnKind::Int4,
+            "INT8" => ColumnKind::Int8,
+            "FLOAT4" => ColumnKind::Float4,
+            "FLOAT8" => ColumnKind::Float8,
+            "NUMERIC" => ColumnKind::Numeric,
+            "TEXT" | "VARCHAR" | "BPCHAR" | "NAME" | "UNKNOWN" => ColumnKind::Text,
+            "citext" | "CITEXT" => ColumnKind::Label,
+            "BOOL" => ColumnKind::Bool,
+            "DATE" => ColumnKind::Date,
+            "TIME" => ColumnKind::Time,
+            "TIMESTAMP" => ColumnKind::Timestamp,
+            "TIMESTAMPTZ" => ColumnKind::Timestamptz,
+            "INTERVAL" => ColumnKind::Interval,
+            "JSON" | "JSONB" => ColumnKind::Json,
+            "UUID" => ColumnKind::Uuid,
+            "BYTEA" => ColumnKind::Bytea,
+            "INT2[]" | "INT4[]" => ColumnKind::Int4Array,
+            "INT8[]" => ColumnKind::Int8Array,
+            "FLOAT8[]" => ColumnKind::Float8Array,
+            "NUMERIC[]" => ColumnKind::NumericArray,
+            "TEXT[]" | "VARCHAR[]" | "BPCHAR[]" | "NAME[]" => ColumnKind::TextArray,
+            "BOOL[]" => ColumnKind::BoolArray,
+            _ if matches!(type_info.kind(), sqlx::postgres::PgTypeKind::Enum(_)) => ColumnKind::Label,
+            _ => ColumnKind::Unsupported,
+        }
+    }
+}
+
+pub(crate) struct PageColumn {
+    pub(crate) index: usize,
+    // `"name":`, escaped once for the page
+    pub(crate) key: Vec<u8>,
+    pub(crate) kind: ColumnKind,
+    pub(crate) encrypted: bool,
+    pub(crate) mask: Option<Mask>,
+}
+
+// Serializes a page of rows straight into the response body, column by
+// column per row, instead of building a serde_json::Map per row. The output
+// matches serializing a QueryResult of row_to_json objects: keys are sorted,
+// a repeated column name keeps its last value, cursor columns are left out
+// and masked and encrypted columns are protected as protect_columns does.
+pub(crate) struct JsonPageWriter<'a> {
+    pub(crate) rows: &'a [PgRow],
+    pub(crate) columns: Vec<PageColumn>,
+    pub(crate) cipher: Option<&'a FieldCipher>,
+    pub(crate) format: ValueFormat,
+}
+
+impl<'a> JsonPageWriter<'a> {
+    pub(crate) fn new(config: &'a Config, table: &str, rows: &'a [PgRow], format: ValueFormat) -> Self {
+        let encrypted: &[String] = match config.table(table.trim_matches('"')) {
+            Some(t) if config.cipher.is_some() => &t.encrypted_columns,
+            _ => &[],
+        };
+        let mut by_name: BTreeMap<&str, PageColumn> = BTreeMap::new();
+        if let Some(first) = rows.first() {
+            for (index, column) in first.columns().iter().enumerate() {
+                let name = column.name();
+                let mask = config.mask(table, name);
+                if name.starts_with(CURSOR_COLUMN_PREFIX) || mask == Some(Mask::Omit) {
+                    continue;
+                }
+                let mut key = serde_json::to_vec(name).unwrap_or_default();
+                key.push(b':');
+                by_name.insert(name, PageColumn {
+                    index,
+                    key,
+                    kind: ColumnKind::of(column.type_info()),
+                    encrypted: encrypted.iter().any(|c| c == name) && mask.is_none(),
+                    mask,
+                });
+            }
+        }
+        JsonPageWriter {
+            rows,
+            columns: by_name.into_values().collect(),
+            cipher: config.cipher.as_ref(),
+            format,
+        }
+    }
+    
+    pub(crate) fn write(&self, meta: &PageMeta) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(64 + self.rows.len() * self.columns.len() * 16);
+        buf.extend_from_slice(b"{\"data\":[");
+        for (i, row) in self.rows.iter().enumerate() {
+            if i > 0 {
+                buf.push(b',');
+            }
+            buf.push(b'{');
+            for (j, column) in self.columns.iter().enumerate() {
+                if j > 0 {
+                    buf.push(b',');
+                }
+                buf.extend_from_slice(&column.key);
+                self.write_value(&mut buf, row, column);
+            }
+            buf.push(b'}');
+        }
+        buf.extend_from_slice(b"],");
+        // PageMeta always has fields, so its serialization starts with `{"`
+        let meta = serde_json::to_vec(meta).unwrap_or_default();
+        buf.extend_from_slice(&meta[1..]);
+        buf
+    }
+    
+    pub(crate) fn write_value(&self, buf: &mut Vec<u8>, row: &PgRow, column: &PageColumn) {
+        let i = column.index;
+        if row.try_get_raw(i).map_or(true, |v| sqlx::ValueRef::is_null(&v)) {
+            buf.extend_from_slice(b"null");
+            return;
+        }
+        if let Some(mask) = column.mask {
+            let value = mask.apply(&cell_to_json(row, i, column.kind, self.format)).unwrap_or_default();
+            let _ = serde_json::to_writer(&mut *buf, &value);
+            return;
+        }
+        if column.encrypted {
+            if let Some(cipher) = self.cipher {
+                let value = cell_to_json(row, i, column.kind, self.format);
+                let _ = serde_json::to_writer(&mut *buf, &cipher.encrypt(&value));
+                return;
+            }
+        }
+        let written = match column.kind {
+            ColumnKind::Int4 => row.try_get::<i32, _>(i).map(|v| serde_json::to_writer(&mut *buf, &v)),
+            ColumnKind::Int8 => row.try_get::<i64, _>(i).map(|v| serde_json::to_writer(&mut *buf, &v)),
+            ColumnKind::Float8 => row.try_get::<f64, _>(i).map(|v| serde_json::to_writer(&mut *buf, &v)),
+            ColumnKind::Text => row.try_get::<&str, _>(i).map(|v| serde_json::to_writer(&mut *buf, v)),
+            ColumnKind::Bool => row.try_get::<bool, _>(i).map(|v| serde_json::to_writer(&mut *buf, &v)),
+            _ => Ok(serde_json::to_writer(&mut *buf, &cell_to_json(row, i, column.kind, self.format))),
+        };
+        if !matches!(written, Ok(Ok(()))) {
+            let _ = serde_json::to_writer(&mut *buf, &unsupported_value(row, i));
+        }
+    }
+}
+
+// The page shape JsonPageWriter writes, from rows that are already maps
+pub(crate) fn write_page(data: &[serde_json::Map<String, serde_json::Value>], meta: &PageMeta) -> Vec<u8> {
+    let mut buf = b"{\"data\":".to_vec();
+    let _ = serde_json::to_writer(&mut buf, data);
+    buf.push(b',');
+    let meta = serde_json::to_vec(meta).unwrap_or_default();
+    buf.extend_from_slice(&meta[1..]);
+    buf
+}
+
+pub(crate) const DEFAULT_BINARY_MAX_BYTES: usize = 1024 * 1024;
+
+// Settings for rendering values, see Config::value_format
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ValueFormat {
+    pub(crate) numeric_strings: bool,
+    pub(crate) binary_max_bytes: usize,
+    // From `?tz=`
+    pub(crate) tz: chrono_tz::Tz,
+}
+
+// One value as row_to_json renders it. Numerics are JSON numbers, which
+// clients parse as doubles, unless DATAPI_NUMERIC_STRINGS keeps their exact
+// digits in strings. Timestamps are RFC 3339, without an offset for
+// `timestamp` and in UTC or the `?tz=` zone for `timestamptz`. Intervals are
+// ISO 8601 durations. `bytea` is base64, or `{"binary_bytes": n}` above
+// DATAPI_BINARY_MAX_BYTES.
+pub(crate) fn cell_to_json(row: &PgRow, i: usize, kind: ColumnKind, format: ValueFormat) -> serde_json::Value {
+    use serde_json::json;
+    if row.try_get_raw(i).map_or(true, |v| sqlx::ValueRef::is_null(&v)) {
+        return serde_json::Value::Null;
+    }
+    let numeric = |d: Decimal| match format.numeric_strings {
+        true => json!(d.to_string()),
+        false => d.to_string().parse::<serde_json::Number>().map_or_else(|_| json!(d.to_string()), serde_json::Value::Number),
+    };
+    let value = match kind {
+        ColumnKind::Int2 => row.try_get::<i16, _>(i).map(|v| json!(v)),
+        ColumnKind::Int4 => row.try_get::<i32, _>(i).map(|v| json!(v)),
+        ColumnKind::Int8 => row.try_get::<i64, _>(i).map(|v| json!(v)),
+        ColumnKind::Float4 => row.try_get::<f32, _>(i).map(|v| json!(v)),
+        ColumnKind::Float8 => row.try_get::<f64, _>(i).map(|v| json!(v)),
+        ColumnKind::Numeric => row.try_get::<Decimal, _>(i).map(numeric),
+        ColumnKind::Text => row.try_get::<String, _>(i).map(|v| json!(v)),
+        ColumnKind::Label => row.try_get_unchecked::<String, _>(i).map(|v| json!(v)),
+        ColumnKind::Bool => row.try_get::<bool, _>(i).map(|v| json!(v)),
+        ColumnKind::Date => row.try_get::<chrono::NaiveDate, _>(i).map(|v| json!(v.to_string())),
+        ColumnKind::Time => row.try_get::<chrono::NaiveTime, _>(i).map(|v| json!(v.to_string())),
+        ColumnKind::Timestamp => row
+            .try_get::<chrono::NaiveDateTime, _>(i)
+            .map(|v| json!(v.format("%Y-%m-%dT%H:%M:%S%.f").to_string())),
+        ColumnKind::Timestamptz => row.try_get::<chrono::DateTime<chrono::Utc>, _>(i).map(|v| match format.tz {
+            chrono_tz::UTC => json!(v.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)),
+            tz => json!(v.with_timezone(&tz).to_rfc3339_opts(chrono::SecondsFormat::AutoSi, false)),
+        }),
+        ColumnKind::Interval => row.try_get::<sqlx::postgres::types::PgInterval, _>(i).map(|v| json!(iso_duration(&v))),
+        ColumnKind::Json => row.try_get::<serde_json::Value, _>(i),
+        ColumnKind::Uuid => match raw_uuid(row, i) {
+            Some(uuid) => Ok(json!(uuid)),
+            None => return unsupported_value(row, i),
+        },
+        ColumnKind::Bytea => row.try_get::<&[u8], _>(i).map(|v| match v.len() > format.binary_max_bytes {
+            true => json!({ "binary_bytes": v.len() }),
+            false => json!(BASE64.encode(v)),
+        }),
+        ColumnKind::Int4Array if row.columns()[i].type_info().name() == "INT2[]" => {
+            row.try_get::<Vec<Option<i16>>, _>(i).map(|v| json!(v))
+        }
+        ColumnKind::Int4Array => row.try_get::<Vec<Option<i32>>, _>(i).map(|v| json!(v)),
+        ColumnKind::Int8Array => row.try_get::<Vec<Option<i64>>, _>(i).map(|v| json!(v)),
+        ColumnKind::Float8Array => row.try_get::<Vec<Option<f64>>, _>(i).map(|v| json!(v)),
+        ColumnKind::NumericArray => row
+            .try_get::<Vec<Option<Decimal>>, _>(i)
+            .map(|v| serde_json::Value::Array(v.into_iter().map(|d| d.map_or(serde_json::Value::Null, numeric)).collect())),
+        ColumnKind::TextArray => row.try_get::<Vec<Option<String>>, _>(i).map(|v| json!(v)),
+        ColumnKind::BoolArray => row.try_get::<Vec<Option<bool>>, _>(i).map(|v| json!(v)),
+        ColumnKind::Unsupported => return unsupported_value(row, i),
+    };
+    value.unwrap_or_else(|_| unsupported_value(row, i))
+}
+
+// An interval as an ISO 8601 duration like `P1Y2M3DT4H5M6.5S`. Each part
+// keeps its own sign, as Postgres' iso_8601 interval style does.
+pub(crate) fn iso_duration(interval: &sqlx::postgres::types::PgInterval) -> String {
+    let mut duration = String::from("P");
+    let (years, months) = (interval.months / 12, interval.months % 12);
+    for (value, unit) in [(years as i64, 'Y'), (months as i64, 'M'), (interval.days as i64, 'D')] {
+        if value != 0 {
+            duration.push_str(&format!("{}{}", value, unit));
+        }
+    }
+    let micros = interval.microseconds;
+    let (hours, minutes) = (micros / 3_600_000_000, micros / 60_000_000 % 60);
+    let (seconds, fraction) = (micros / 1_000_000 % 60, micros % 1_000_000);
+    if micros != 0 {
+        duration.push('T');
+        if hours != 0 {
+            duration.push_str(&format!("{}H", hours));
+        }
+        if minutes != 0 {
+            duration.push_str(&format!("{}M", minutes));
+        }
+        if seconds != 0 || fraction != 0 {
+            let sign = if seconds == 0 && fraction < 0 { "-" } else { "" };
+            let fraction = format!("{:06}", fraction.abs());
+            match fraction.trim_end_matches('0') {
+                "" => duration.push_str(&format!("{}{}S", sign, seconds)),
+                digits => duration.push_str(&format!("{}{}.{}S", sign, seconds, digits)),
+            }
+        }
+    }
+    if duration == "P" {
+        duration.push_str("T0S");
+    }
+    duration
+}
+
+// A value datapi can't decode, such as a range or a numeric NaN: an
+// object naming its type, so it isn't mistaken for NULL. Casting the column
+// to text in `select` gets its text form.
+pub(crate) fn unsupported_value(row: &PgRow, i: usize) -> serde_json::Value {
+    let type_name = row.columns().get(i).map_or("unknown", |c| c.type_info().name());
+    serde_json::json!({ "unsupported_type": type_name.to_lowercase() })
+}
+
+// sqlx decodes uuids only with its uuid feature; the value is 16 bytes, or
+// text from the simple query protocol
+pub(crate) fn raw_uuid(row: &PgRow, i: usize) -> Option<String> {
+    let value = row.try_get_raw(i).ok()?;
+    if value.format() == sqlx::postgres::PgValueFormat::Text {
+        return value.as_str().ok().map(str::to_string);
+    }
+    let bytes: [u8; 16] = value.as_bytes().ok()?.try_into().ok()?;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    Some(format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use actix_web::test::TestRequest;
+    use std::collections::HashMap;
+    use aes_gcm::aead::{Aead, KeyInit};
+    use aes_gcm::{Aes256Gcm, Key};
+    use crate::filters::{filter_column_sql, finish_filters_with, parse_multiple_filters};
+    use crate::query::{embed_select, run_query};
+
+    const TEST_KEY: [u8; 32] = [7; 32];
+
+    fn test_cipher() -> FieldCipher {
+        FieldCipher { key_id: "k1".to_string(), cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&TEST_KEY)) }
+    }
+
+    fn decrypt(sealed: &str) -> serde_json::Value {
+        let sealed = BASE64.decode(sealed.strip_prefix("enc:A256GCM:k1:").unwrap()).unwrap();
+        let (nonce, ciphertext) = sealed.split_at(12);
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&TEST_KEY));
+        let plaintext = cipher.decrypt(nonce.into(), ciphertext).unwrap();
+        serde_json::from_slice(&plaintext).unwrap()
+    }
+
+    #[test]
+    fn encrypted_columns_are_sealed_in_responses() {
+        let config = Config {
+            cipher: Some(test_cipher()),
+            ..config_with(serde_json::json!({ "loans": { "encrypted_columns": ["ssn", "score"] } }))
+        };
+        let mut row = serde_json::json!({ "id": 1, "ssn": "123-45-6789", "score": null }).as_object().unwrap().clone();
+        protect_columns(&config, "loans", &mut row);
+        assert_eq!(row["id"], 1);
+        assert!(row["score"].is_null());
+        let sealed = row["ssn"].as_str().unwrap();
+        assert_eq!(decrypt(sealed), "123-45-6789");
+        // A fresh nonce per value
+        let mut again = serde_json::json!({ "ssn": "123-45-6789" }).as_object().unwrap().clone();
+        protect_columns(&config, "loans", &mut again);
+        assert_ne!(again["ssn"].as_str().unwrap(), sealed);
+
+        let mut other = serde_json::json!({ "ssn": "x" }).as_object().unwrap().clone();
+        protect_columns(&config, "customers", &mut other);
+        assert_eq!(other["ssn"], "x");
+    }
+
+    #[test]
+    fn format_parameter_wins_over_accept() {
+        let req = TestRequest::default().insert_header(("Accept", "text/csv")).to_http_request();
+        assert_eq!(response_format(&req, None).unwrap(), ResponseFormat::Csv);
+        assert_eq!(response_format(&req, Some("JSON")).unwrap(), ResponseFormat::Json);
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(response_format(&req, None).unwrap(), ResponseFormat::Json);
+        assert_eq!(response_format(&req, Some("xml")).unwrap_err().key, "unsupported_format");
+    }
+
+    #[actix_web::test]
+    async fn csv_pages_carry_a_header_row_and_metadata_headers() {
+        let result = QueryResult {
+            data: vec![
+                serde_json::json!({ "id": 1, "name": "Bob, Jr.", "note": null }),
+                serde_json::json!({ "id": 2, "name": "Ann", "note": "say \"hi\"" }),
+            ],
+            count: 2,
+            page: 1,
+            page_size: 2,
+            total_count: Some(5),
+            total_count_estimated: true,
+            total_pages: None,
+            has_next: true,
+            result_token: None,
+            next_cursor: Some("abc".to_string()),
+            sort_fingerprint: Some("0123456789abcdef".to_string()),
+            warnings: Vec::new(),
+        };
+        let columns = ["id", "name", "note"].map(|c| (c.to_string(), "TEXT".to_string()));
+        let resp = csv_response(&TestRequest::default().to_http_request(), "\"Loans\"", &columns, &result);
+        assert_eq!(resp.headers().get("content-disposition").unwrap(), "attachment; filename=\"Loans.csv\"");
+        assert_eq!(resp.headers().get("x-total-count").unwrap(), "5");
+        assert_eq!(resp.headers().get("x-total-count-estimated").unwrap(), "true");
+        assert_eq!(resp.headers().get("x-next-cursor").unwrap(), "abc");
+        assert_eq!(resp.headers().get("x-sort-fingerprint").unwrap(), "0123456789abcdef");
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "id,name,note\n1,\"Bob, Jr.\",\n2,Ann,\"say \"\"hi\"\"\"\n");
+    }
+
+    #[actix_web::test]
+    async fn ndjson_is_negotiated_and_reports_query_errors_up_front() {
+        let req = TestRequest::default()
+            .insert_header(("Accept", "application/x-ndjson"))
+            .app_data(web::Data::new(test_config()))
+            .to_http_request();
+        assert_eq!(response_format(&req, None).unwrap(), ResponseFormat::Ndjson);
+        assert_eq!(response_format(&req, Some("ndjson")).unwrap(), ResponseFormat::Ndjson);
+
+        let breaker = CircuitBreaker::new(&test_config());
+        let query = "SELECT * FROM loans".to_string();
+        let value_format = test_config().value_format();
+        for framing in [StreamFraming::Lines, StreamFraming::Array] {
+            let resp = stream_response(&req, &offline_pool(), &breaker, "loans", query.clone(), &FilterExpr::none(), None, Vec::new(), None, value_format, framing).await;
+            assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    #[test]
+    fn parquet_keeps_arrow_types_for_an_empty_page() {
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let config = config_with(serde_json::json!({ "loans": { "encrypted_columns": ["amount"] } }));
+        let columns = [("id", "INT8"), ("opened", "DATE"), ("amount", "NUMERIC"), ("note", "TEXT")]
+            .map(|(name, type_name)| (name.to_string(), type_name.to_string()));
+        let types = |typed: bool| {
+            let batch = record_batch(&config, "loans", &columns, &[], &[], typed).unwrap();
+            let file = write_parquet(&batch).unwrap();
+            let reader = ParquetRecordBatchReaderBuilder::try_new(web::Bytes::from(file)).unwrap();
+            reader.schema().fields().iter().map(|f| f.data_type().to_string()).collect::<Vec<_>>()
+        };
+        // Encrypted columns are ciphertext strings
+        assert_eq!(types(true), ["Int64", "Date32", "Utf8", "Utf8"]);
+        // Rows a hook rewrote are written from their JSON
+        assert_eq!(types(false), ["Utf8", "Utf8", "Utf8", "Utf8"]);
+    }
+
+    #[test]
+    fn json_pages_match_the_query_result_shape() {
+        let config = test_config();
+        let meta = PageMeta { count: 0, page: 2, page_size: 50, total_count: Some(50), total_count_estimated: true, total_pages: Some(1), has_next: false, next_cursor: None, sort_fingerprint: None, warnings: vec!["count_failed".to_string()], debug: None };
+        let body = JsonPageWriter::new(&config, "loans", &[], config.value_format()).write(&meta);
+        let expected = QueryResult {
+            data: Vec::new(),
+            count: 0,
+            page: 2,
+            page_size: 50,
+            total_count: Some(50),
+            total_count_estimated: true,
+            total_pages: Some(1),
+            has_next: false,
+            result_token: None,
+            next_cursor: None,
+            sort_fingerprint: None,
+            warnings: vec!["count_failed".to_string()],
+        };
+        assert_eq!(body, serde_json::to_vec(&expected).unwrap());
+        assert_eq!(write_page(&[], &meta), body);
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["total_count_estimated"], true);
+
+        let kind = |name: &'static str| ColumnKind::of(&sqlx::postgres::PgTypeInfo::with_name(name));
+        assert!(matches!(kind("VARCHAR"), ColumnKind::Text));
+        assert!(matches!(kind("INT8"), ColumnKind::Int8));
+        assert!(matches!(kind("JSONB"), ColumnKind::Json));
+        assert!(matches!(kind("TIMESTAMPTZ"), ColumnKind::Timestamptz));
+        assert!(matches!(kind("TEXT[]"), ColumnKind::TextArray));
+    }
+
+    #[actix_web::test]
+    async fn intervals_are_iso_durations_and_timestamps_follow_tz() {
+        let interval = |months, days, microseconds| iso_duration(&sqlx::postgres::types::PgInterval { months, days, microseconds });
+        assert_eq!(interval(14, 3, 14_706_500_000), "P1Y2M3DT4H5M6.5S");
+        assert_eq!(interval(0, -1, 0), "P-1D");
+        assert_eq!(interval(0, 0, -500_000), "PT-0.5S");
+        assert_eq!(interval(0, 0, 0), "PT0S");
+
+        let req = TestRequest::default().to_http_request();
+        let config = test_config();
+        let breaker = CircuitBreaker::new(&config);
+        let params = query_params(serde_json::json!({ "tz": "Mars/Olympus" }));
+        let resp = run_query(&req, &offline_pool(), &config, &breaker, "loans", &FilterExpr::none(), &params).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json_body(resp).await["error"],
+            "Unknown time zone Mars/Olympus, expected an IANA name like Europe/Berlin"
+        );
+    }
+
+    #[test]
+    fn masked_columns_are_hashed_redacted_or_omitted() {
+        let digest = format!("{:x}", <sha2::Sha256 as sha2::Digest>::digest(b"42"));
+        assert_eq!(Mask::Hash.apply(&serde_json::json!(42)), Some(serde_json::json!(digest)));
+        assert_eq!(Mask::Redact.apply(&serde_json::json!("x")), Some(serde_json::json!("[REDACTED]")));
+        assert_eq!(Mask::Redact.apply(&serde_json::Value::Null), Some(serde_json::Value::Null));
+        assert_eq!(Mask::Omit.apply(&serde_json::Value::Null), None);
+
+        let config = config_with(serde_json::json!({
+            "customers": { "masked_columns": { "email": "redact", "ssn": "omit" } }
+        }));
+        let mut row = objects(serde_json::json!([{ "id": 1, "email": "a@b.c", "ssn": "123" }])).remove(0);
+        protect_columns(&config, "\"customers\"", &mut row);
+        assert_eq!(serde_json::Value::Object(row), serde_json::json!({ "id": 1, "email": "[REDACTED]" }));
+
+        let req = TestRequest::default().to_http_request();
+        let filters = parse_multiple_filters("email=a@b.c").unwrap();
+        let e = finish_filters_with(&req, filters, None, &config, "customers", &HashMap::new()).unwrap_err();
+        assert_eq!((e.key, e.args), ("masked_column", vec![("column", "email".to_string())]));
+        assert_eq!(filter_column_sql(&config, "customers", "Email->x").unwrap_err().key, "masked_column");
+        let relations = [relation("customers", &[("customer_id", "id")], false)];
+        assert_eq!(embed_select(&req, &config, "orders", &relations, "customer").unwrap_err().key, "embed_restricted");
+    }
+
+    #[test]
+    fn workbooks_are_asked_for_by_format_or_accept() {
+        let req = TestRequest::default().app_data(web::Data::new(test_config())).to_http_request();
+        assert_eq!(response_format(&req, Some("xlsx")).unwrap(), ResponseFormat::Xlsx);
+        let req = TestRequest::default()
+            .insert_header(("Accept", XLSX_CONTENT_TYPE))
+            .app_data(web::Data::new(test_config()))
+            .to_http_request();
+        assert_eq!(response_format(&req, None).unwrap(), ResponseFormat::Xlsx);
+
+        let columns = [("id".to_string(), "INT8".to_string()), ("note".to_string(), "TEXT".to_string())];
+        let workbook = write_xlsx(&test_config(), "loans", &columns, &[], &[], true, chrono_tz::UTC).unwrap();
+        // A workbook is a zip archive
+        assert!(workbook.starts_with(b"PK"));
+    }
+
+    #[test]
+    fn arrow_streams_hold_the_record_batch() {
+        let columns = [("id", "INT8"), ("opened", "DATE"), ("note", "TEXT")]
+            .map(|(name, type_name)| (name.to_string(), type_name.to_string()));
+        let batch = record_batch(&test_config(), "loans", &columns, &[], &[], true).unwrap();
+        let stream = write_arrow(&batch).unwrap();
+        let reader = arrow_ipc::reader::StreamReader::try_new(std::io::Cursor::new(stream), None).unwrap();
+        let types: Vec<String> = reader.schema().fields().iter().map(|f| f.data_type().to_string()).collect();
+        assert_eq!(types, ["Int64", "Date32", "Utf8"]);
+
+        let req = TestRequest::default()
+            .insert_header(("Accept", ARROW_CONTENT_TYPE))
+            .app_data(web::Data::new(test_config()))
+            .to_http_request();
+        assert_eq!(response_format(&req, None).unwrap(), ResponseFormat::Arrow);
+        assert_eq!(response_format(&req, Some("feather")).unwrap_err().key, "unsupported_format");
+    }
+}
@@ -0,0 +1,172 @@
+// Helpers shared by the modules' tests
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+use actix_web::body::MessageBody;
+use actix_web::test::TestRequest;
+use actix_web::{HttpRequest, HttpResponse};
+use sqlx::PgPool;
+use sqlx::postgres::PgPoolOptions;
+use crate::access::{ExposedTables, Exposure, TokenGrants};
+use crate::config::{BindLogging, Config, EnvelopeConfig, RollupConfig, TableGrant, TemplateConfig};
+use crate::filters::{build_where_clause, parse_multiple_filters};
+use crate::query::{QueryParams, Relation};
+use crate::serialization::DEFAULT_BINARY_MAX_BYTES;
+use crate::writes::{write_objects, IsolationLevel};
+
+// A pool whose connections always fail, for code paths that must cope
+// without the database
+pub(crate) fn offline_pool() -> PgPool {
+    PgPoolOptions::new()
+        .acquire_timeout(std::time::Duration::from_millis(200))
+        .connect_lazy("postgres://datapi@127.0.0.1:1/datapi")
+        .unwrap()
+}
+
+pub(crate) async fn json_body<B: MessageBody + 'static>(resp: HttpResponse<B>) -> serde_json::Value {
+    let body = actix_web::body::to_bytes(resp.map_into_boxed_body().into_body()).await.unwrap();
+    serde_json::from_slice(&body).unwrap()
+}
+
+// A configuration with fixed values, independent of the environment
+pub(crate) fn test_config() -> Config {
+    Config {
+        collations: HashMap::from([("de".to_string(), "de-DE-x-icu".to_string())]),
+        admin_token: Some("secret".to_string()),
+        circuit_failure_threshold: 2,
+        circuit_probe_secs: 10,
+        result_ttl_secs: 600,
+        tables: HashMap::new(),
+        cipher: None,
+        rollups: Vec::new(),
+        resolve_tables: false,
+        parameter_aliases: HashMap::new(),
+        max_rows: None,
+        compression: true,
+        compression_min_bytes: 1024,
+        priority_classes: BTreeMap::new(),
+        writes_enabled: true,
+        allow_unfiltered_writes: false,
+        query_hint_ms: None,
+        api_keys: Vec::new(),
+        api_keys_table: None,
+        jwt: None,
+        aliases: BTreeMap::new(),
+        rate_limit_per_minute: None,
+        rate_limit_burst: 1,
+        cors: None,
+        read_only: false,
+        cache_ttl_secs: 0,
+        cache_max_age_secs: 300,
+        cache_max_entries: 1000,
+        cache_max_body_bytes: 1024 * 1024,
+        cache_redis_url: None,
+        share_secret: None,
+        share_max_secs: 3600,
+        templates: BTreeMap::new(),
+        statement_timeout_ms: None,
+        max_statement_timeout_ms: None,
+        envelope: EnvelopeConfig::default(),
+        log_binds: BindLogging::Redact,
+        server_timing: false,
+        databases: BTreeMap::new(),
+        schemas: Vec::new(),
+        functions: Vec::new(),
+        random_sort_max_rows: 100,
+        otlp_tracing: false,
+        pool_max_connections: 5,
+        default_page_size: 100,
+        max_page_size: 1000,
+        page_byte_budget: None,
+        count_failure_skip: None,
+        max_isolation: IsolationLevel::Serializable,
+        queries_table: None,
+        sql_enabled: false,
+        etags: true,
+        exports: BTreeMap::new(),
+        numeric_strings: false,
+        binary_max_bytes: DEFAULT_BINARY_MAX_BYTES,
+        slow_query_ms: None,
+        api_key_quotas: BTreeMap::new(),
+        pool_min_connections: 0,
+        pool_acquire_timeout: Duration::from_secs(30),
+        pool_idle_timeout: Some(Duration::from_secs(600)),
+        pool_max_lifetime: Some(Duration::from_secs(1800)),
+        pool_test_before_acquire: true,
+        audit_table: None,
+        audit_file: None,
+        statement_cache_capacity: 100,
+        schema_cache_ttl: Duration::from_secs(60),
+        xlsx_max_rows: 100_000,
+    }
+}
+
+// test_config with the `tables` of a config file
+pub(crate) fn config_with(tables: serde_json::Value) -> Config {
+    Config { tables: serde_json::from_value(tables).unwrap(), ..test_config() }
+}
+
+pub(crate) fn admin_request(token: Option<&str>) -> HttpRequest {
+    let mut req = TestRequest::default();
+    if let Some(token) = token {
+        req = req.insert_header(("Authorization", format!("Bearer {}", token)));
+    }
+    req.to_http_request()
+}
+
+pub(crate) fn where_sql(filters: &str) -> String {
+    build_where_clause(&parse_multiple_filters(filters).unwrap())
+}
+
+pub(crate) fn rollup(measures: &[&str]) -> RollupConfig {
+    serde_json::from_value(serde_json::json!({
+        "table": "daily_sales", "source": "orders", "time_column": "created_at",
+        "dimensions": ["region"], "measures": measures
+    }))
+    .unwrap()
+}
+
+pub(crate) fn query_params(params: serde_json::Value) -> QueryParams {
+    serde_json::from_value(params).unwrap()
+}
+
+pub(crate) fn exposure(require_approval: bool) -> Exposure {
+    Exposure {
+        patterns: vec!["api_*".to_string()],
+        require_approval: std::sync::atomic::AtomicBool::new(require_approval),
+        tables: Mutex::new(ExposedTables::default()),
+    }
+}
+
+pub(crate) fn loan_columns() -> HashMap<String, (String, bool)> {
+    HashMap::from([
+        ("id".to_string(), ("integer".to_string(), true)),
+        ("status".to_string(), ("text".to_string(), false)),
+    ])
+}
+
+pub(crate) fn objects(body: serde_json::Value) -> Vec<serde_json::Map<String, serde_json::Value>> {
+    write_objects(body).unwrap()
+}
+
+pub(crate) fn grants(entries: &[&str]) -> TokenGrants {
+    TokenGrants {
+        subject: Some("alice".to_string()),
+        tables: entries.iter().map(|e| TableGrant::parse(e).unwrap()).collect(),
+        claims: serde_json::json!({ "org": { "id": 7 } }),
+    }
+}
+
+pub(crate) fn template(config: serde_json::Value) -> TemplateConfig {
+    serde_json::from_value::<TemplateConfig>(config).unwrap().validate("overdue", &HashMap::new()).unwrap()
+}
+
+pub(crate) fn relation(name: &str, columns: &[(&str, &str)], many: bool) -> Relation {
+    Relation {
+        name: name.to_string(),
+        sql: name.to_string(),
+        columns: columns.iter().map(|(local, remote)| (local.to_string(), remote.to_string())).collect(),
+        many,
+        key: Vec::new(),
+    }
+}
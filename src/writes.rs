@@ -0,0 +1,2315 @@
+use std::collections::HashMap;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgRow;
+use sqlx::{Column, PgPool, Row};
+use crate::access::{
+    claim_values, pin_claim_values, resolve_table, table_grant_denied, table_read_only, Exposure,
+};
+use crate::config::Config;
+use crate::filters::{
+    bind_filters, build_where_clause, coerce_value, finish_filters, finish_filters_with,
+    has_structured_filters, parse_column_formats, parse_multiple_filters, request_filters,
+    sanitize_table_name, Coercion, ColumnFormat, FilterExpr,
+};
+use crate::handlers::reject_non_admin;
+use crate::messages::{bad_request, error_response, error_response_with, render_message, Message};
+use crate::middleware::{CircuitBreaker, CONSISTENCY_TOKEN_HEADER};
+use crate::query::{
+    adjust_row_count, apply_actor, apply_claim_settings, apply_table_settings,
+    begin_write_transaction, claim_settings, column_types, fetch_generated_key, forget_row_count,
+    request_actor, tag_query, unique_keys, QueryResult,
+};
+use crate::serialization::{cell_to_json, protect_columns, row_to_json, ColumnKind, ValueFormat};
+
+// Identifier quoting for column names taken from the catalog
+pub(crate) fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// A JSON value as the text bound for a column; the SQL casts it to the
+// column type. Arrays and objects are passed as JSON text.
+pub(crate) fn json_to_text(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+// Maps a failed write onto the client's fault where the database says so:
+// constraint violations are conflicts, invalid values bad requests.
+pub(crate) fn write_error_response(req: &HttpRequest, breaker: &CircuitBreaker, e: sqlx::Error) -> HttpResponse {
+    write_error_response_with(req, breaker, e, serde_json::Map::new())
+}
+
+// Answers a failed query with 500, or 504 when it ran into the statement
+// timeout.
+pub(crate) fn database_error_response(req: &HttpRequest, breaker: &CircuitBreaker, e: sqlx::Error) -> HttpResponse {
+    if is_statement_timeout(&e) {
+        log::warn!("Statement timeout: {}", e);
+        return error_response(req, StatusCode::GATEWAY_TIMEOUT, Message::new("statement_timeout"));
+    }
+    log::error!("Database error: {}", e);
+    breaker.record_error(&e);
+    error_response(req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"))
+}
+
+// SQLSTATE query_canceled, which a query cancelled with pg_cancel_backend
+// raises too
+pub(crate) fn is_statement_timeout(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Database(db) => db.code().as_deref() == Some("57014"),
+        _ => false,
+    }
+}
+
+pub(crate) fn write_error_response_with(
+    req: &HttpRequest,
+    breaker: &CircuitBreaker,
+    e: sqlx::Error,
+    body: serde_json::Map<String, serde_json::Value>,
+) -> HttpResponse {
+    if is_statement_timeout(&e) {
+        return database_error_response(req, breaker, e);
+    }
+    let code = match &e {
+        sqlx::Error::Database(db) => db.code().map(|c| c.to_string()),
+        _ => None,
+    };
+    let (status, message) = match code.as_deref() {
+        // A serializable or repeatable read transaction lost to a concurrent
+        // one and can be retried
+        Some("40001") => (StatusCode::CONFLICT, Message::new("serialization_failure")),
+        Some(c) if c.starts_with("23") => {
+            (StatusCode::CONFLICT, Message::new("constraint_violation").arg("error", postgres_message(&e)))
+        }
+        Some(c) if c.starts_with("22") => {
+            (StatusCode::BAD_REQUEST, Message::new("database_rejected").arg("error", postgres_message(&e)))
+        }
+        _ => {
+            log::error!("Database error: {}", e);
+            breaker.record_error(&e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"))
+        }
+    };
+    error_response_with(req, status, message, body)
+}
+
+// The server's own message of a database error, without the SQL or
+// connection details the sqlx error may carry
+pub(crate) fn postgres_message(e: &sqlx::Error) -> String {
+    match e {
+        sqlx::Error::Database(db) => db.message().to_string(),
+        _ => String::new(),
+    }
+}
+
+// Renders the rows a write returned. The X-Consistency-Token header carries
+// the primary's WAL position after the commit, for reading the write back
+// from a replica.
+pub(crate) async fn write_response(
+    pool: &PgPool,
+    config: &Config,
+    table: &str,
+    status: StatusCode,
+    rows: &[PgRow],
+) -> HttpResponse {
+    write_response_with(pool, config, table, status, rows, serde_json::Map::new()).await
+}
+
+// Like write_response, with extra fields next to "data" in the body.
+pub(crate) async fn write_response_with(
+    pool: &PgPool,
+    config: &Config,
+    table: &str,
+    status: StatusCode,
+    rows: &[PgRow],
+    mut body: serde_json::Map<String, serde_json::Value>,
+) -> HttpResponse {
+    let data: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| {
+            let mut obj = row_to_json(row, config.value_format());
+            protect_columns(config, table, &mut obj);
+            serde_json::Value::Object(obj)
+        })
+        .collect();
+    
+    body.insert("count".to_string(), serde_json::json!(data.len()));
+    body.insert("data".to_string(), serde_json::Value::Array(data));
+    let mut builder = write_response_builder(pool, status).await;
+    builder.json(body)
+}
+
+pub(crate) async fn write_response_builder(pool: &PgPool, status: StatusCode) -> actix_web::HttpResponseBuilder {
+    let mut builder = HttpResponse::build(status);
+    let lsn: Result<String, sqlx::Error> =
+        sqlx::query_scalar("SELECT pg_current_wal_lsn()::text").fetch_one(pool).await;
+    match lsn {
+        Ok(lsn) => {
+            builder.insert_header((CONSISTENCY_TOKEN_HEADER, lsn));
+        }
+        Err(e) => log::warn!("WAL position lookup failed: {}", e),
+    }
+    builder
+}
+
+// Inserts a JSON object, or an array of objects, as rows. Keys must be
+// columns of the table; columns missing from an object get their default.
+// Writes always go to the primary.
+// What an insert does with a row whose key already exists: fail with 409,
+// skip it, update the columns the body gives, or replace the whole row
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ConflictStrategy {
+    Error,
+    Ignore,
+    Merge,
+    Replace,
+}
+
+impl ConflictStrategy {
+    pub(crate) fn parse(strategy: &str) -> Option<Self> {
+        match strategy.trim().to_lowercase().as_str() {
+            "error" => Some(ConflictStrategy::Error),
+            "ignore" => Some(ConflictStrategy::Ignore),
+            "merge" => Some(ConflictStrategy::Merge),
+            "replace" => Some(ConflictStrategy::Replace),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct InsertParams {
+    pub(crate) on_conflict: Option<String>,
+    pub(crate) conflict_columns: Option<String>,
+}
+
+// The conflict handling of an insert, with the key columns it applies to.
+// An empty target on ignore skips rows violating any unique constraint.
+#[derive(Debug, Clone)]
+pub(crate) struct OnConflict {
+    pub(crate) strategy: ConflictStrategy,
+    pub(crate) target: Vec<String>,
+}
+
+impl OnConflict {
+    pub(crate) const ERROR: OnConflict = OnConflict { strategy: ConflictStrategy::Error, target: Vec::new() };
+
+    // The request's on_conflict, else the table's, else error. Merging and
+    // replacing without conflict_columns use the primary (or first unique) key.
+    pub(crate) async fn from_request(req: &HttpRequest, pool: &PgPool, config: &Config, table: &str, params: &InsertParams) -> Result<Self, (StatusCode, Message)> {
+        let strategy = match params.on_conflict.as_deref() {
+            Some(value) => ConflictStrategy::parse(value)
+                .ok_or_else(|| (StatusCode::BAD_REQUEST, Message::new("invalid_on_conflict").arg("value", value).param("on_conflict")))?,
+            None => config
+                .table(table.trim_matches('"'))
+                .and_then(|t| t.on_conflict)
+                .unwrap_or(ConflictStrategy::Error),
+        };
+        let mut target: Vec<String> = params
+            .conflict_columns
+            .iter()
+            .flat_map(|columns| columns.split(','))
+            .map(|column| column.trim().to_string())
+            .filter(|column| !column.is_empty())
+            .collect();
+        if target.is_empty() && matches!(strategy, ConflictStrategy::Merge | ConflictStrategy::Replace) {
+            let keys = unique_keys(req, pool, table).await.unwrap_or_else(|e| {
+                log::error!("Unique key lookup error: {}", e);
+                Vec::new()
+            });
+            target = keys.into_iter().next().ok_or_else(|| {
+                (StatusCode::BAD_REQUEST, Message::new("missing_conflict_target").arg("table", table.trim_matches('"')).param("conflict_columns"))
+            })?;
+        }
+        Ok(OnConflict { strategy, target })
+    }
+
+    // Whether rows the statement returns may be updated ones rather than new
+    pub(crate) fn updates(&self) -> bool {
+        matches!(self.strategy, ConflictStrategy::Merge | ConflictStrategy::Replace)
+    }
+
+    // The ON CONFLICT clause for an insert of `columns`. Merge sets the
+    // columns given, replace every column; the key columns are left alone.
+    pub(crate) fn clause(&self, table: &str, column_types: &HashMap<String, (String, bool)>, columns: &[&str]) -> Result<String, Message> {
+        if let Some(column) = self.target.iter().find(|c| !column_types.contains_key(*c)) {
+            return Err(Message::new("unknown_column").arg("column", column).arg("table", table).param("conflict_columns"));
+        }
+        let target = if self.target.is_empty() {
+            String::new()
+        } else {
+            let columns: Vec<String> = self.target.iter().map(|c| quote_identifier(c)).collect();
+            format!(" ({})", columns.join(", "))
+        };
+        let mut set: Vec<&str> = match self.strategy {
+            ConflictStrategy::Error => return Ok(String::new()),
+            ConflictStrategy::Ignore => return Ok(format!(" ON CONFLICT{} DO NOTHING", target)),
+            ConflictStrategy::Merge => columns.to_vec(),
+            ConflictStrategy::Replace => column_types.keys().map(String::as_str).collect(),
+        };
+        set.retain(|column| !self.target.iter().any(|key| key == column));
+        set.sort_unstable();
+        // Setting a key column to itself still returns the row
+        if set.is_empty() {
+            set.push(&self.target[0]);
+        }
+        let assignments: Vec<String> = set
+            .iter()
+            .map(|column| format!("{0} = EXCLUDED.{0}", quote_identifier(column)))
+            .collect();
+        Ok(format!(" ON CONFLICT{} DO UPDATE SET {}", target, assignments.join(", ")))
+    }
+}
+
+pub(crate) async fn insert_rows(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<String>,
+    params: web::Query<InsertParams>,
+    body: web::Bytes,
+) -> impl Responder {
+    if !config.writes_enabled {
+        return error_response(&req, StatusCode::FORBIDDEN, Message::new("writes_disabled"));
+    }
+    let body = match decode_write_body(&req, &body) {
+        Ok(body) => body,
+        Err((status, message)) => return error_response(&req, status, message),
+    };
+    let table = match sanitize_table_name(&path.into_inner()) {
+        Ok(t) => t,
+        Err(e) => return bad_request(&req, e),
+    };
+    if let Some(resp) = table_read_only(&req, &pool, &config, &table).await {
+        return resp;
+    }
+    if config.moderated(&table) {
+        let unsupported = [("on_conflict", params.on_conflict.is_some()), ("conflict_columns", params.conflict_columns.is_some())];
+        if let Some(resp) = reject_moderated_param(&req, &table, &unsupported) {
+            return resp;
+        }
+        return stage_write(&req, &pool, &config, None, vec![BatchOperation::Insert { table, data: body }]).await;
+    }
+    let table = match resolve_table(&req, &pool, &config, table).await {
+        Ok(t) => t,
+        Err(response) => return response,
+    };
+    
+    let Some(mut objects) = write_objects(body) else {
+        return bad_request(&req, Message::new("invalid_write_body"));
+    };
+    let pinned = claim_values(&req, &config, &table)
+        .and_then(|pinned| objects.iter_mut().try_for_each(|obj| pin_claim_values(&pinned, obj)));
+    if let Err(e) = pinned {
+        return error_response(&req, StatusCode::FORBIDDEN, e);
+    }
+    
+    let column_types = match column_types(&req, &pool, &table).await {
+        Ok(c) => c,
+        Err(e) => return write_error_response(&req, &breaker, e),
+    };
+    let conflict = match OnConflict::from_request(&req, &pool, &config, &table, &params).await {
+        Ok(conflict) => conflict,
+        Err((status, message)) => return error_response(&req, status, message),
+    };
+    let insert = match prepare_insert(&table, &column_types, &objects, &conflict) {
+        Ok(insert) => insert,
+        Err(e) => return bad_request(&req, e),
+    };
+    log::info!("Executing insert: {}", insert.query);
+    
+    let mut tx = match begin_write_transaction(&req, &pool, &config, &table).await {
+        Ok(tx) => tx,
+        Err(e) => return write_error_response(&req, &breaker, e),
+    };
+    let (rows, inserted) = match insert.run(&mut tx).await {
+        Ok(result) => result,
+        Err(e) => return write_error_response(&req, &breaker, e),
+    };
+    if let Err(e) = tx.commit().await {
+        return write_error_response(&req, &breaker, e);
+    }
+    breaker.record_success();
+    if conflict.updates() {
+        forget_row_count(&req, &table);
+    } else {
+        adjust_row_count(&req, &table, inserted as i64);
+    }
+    
+    let location = match rows.as_slice() {
+        [row] => inserted_row_location(&pool, &config, &table, row).await,
+        _ => None,
+    };
+    let mut response = if prefers_minimal(&req) {
+        write_response_builder(&pool, StatusCode::CREATED).await.finish()
+    } else {
+        write_response(&pool, &config, &table, StatusCode::CREATED, &rows).await
+    };
+    if let Some(location) = location.and_then(|l| actix_web::http::header::HeaderValue::from_str(&l).ok()) {
+        response.headers_mut().insert(actix_web::http::header::LOCATION, location);
+    }
+    response
+}
+
+// `Prefer: return=minimal` omits the inserted rows from the response
+pub(crate) fn prefers_minimal(req: &HttpRequest) -> bool {
+    req.headers()
+        .get_all("Prefer")
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split([',', ';']))
+        .any(|preference| preference.trim().eq_ignore_ascii_case("return=minimal"))
+}
+
+// The path filtering on the generated key of an inserted row, e.g.
+// `/orders/order_id=42`. Encrypted keys aren't disclosed.
+pub(crate) async fn inserted_row_location(pool: &PgPool, config: &Config, table: &str, row: &PgRow) -> Option<String> {
+    let column = match fetch_generated_key(pool, table).await {
+        Ok(column) => column?,
+        Err(e) => {
+            log::warn!("Generated key lookup failed: {}", e);
+            return None;
+        }
+    };
+    let encrypted = config
+        .table(table.trim_matches('"'))
+        .is_some_and(|t| t.encrypted_columns.contains(&column.to_lowercase()));
+    if encrypted {
+        return None;
+    }
+    let index = row.columns().iter().position(|c| c.name() == column)?;
+    let kind = ColumnKind::of(row.columns()[index].type_info());
+    let value = json_to_text(&cell_to_json(row, index, kind, ValueFormat { numeric_strings: true, ..config.value_format() }))?;
+    Some(format!(
+        "/{}/{}={}",
+        urlencoding::encode(table.trim_matches('"')),
+        urlencoding::encode(&column),
+        urlencoding::encode(&value)
+    ))
+}
+
+// A write body: one object or a non-empty array of objects
+// Encodings accepted for write bodies, by Content-Type
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum WriteFormat {
+    Json,
+    Ndjson,
+    MessagePack,
+    Csv,
+}
+
+impl WriteFormat {
+    // Bodies without a Content-Type are read as JSON
+    pub(crate) fn of(req: &HttpRequest) -> Option<Self> {
+        let content_type = req
+            .headers()
+            .get(actix_web::http::header::CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.split(';').next())
+            .map(|h| h.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        match content_type.as_str() {
+            "" | "application/json" => Some(WriteFormat::Json),
+            "application/x-ndjson" | "application/ndjson" | "application/jsonl" => Some(WriteFormat::Ndjson),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(WriteFormat::MessagePack)
+            }
+            "text/csv" => Some(WriteFormat::Csv),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            WriteFormat::Json => "JSON",
+            WriteFormat::Ndjson => "NDJSON",
+            WriteFormat::MessagePack => "MessagePack",
+            WriteFormat::Csv => "CSV",
+        }
+    }
+
+    // The body as the JSON it stands for. NDJSON and CSV bodies give an array
+    // of their records, or the record itself when there is only one, so a
+    // single-record body also works for updates. Empty CSV fields are null.
+    pub(crate) fn decode(self, body: &[u8]) -> Result<serde_json::Value, String> {
+        let records = match self {
+            WriteFormat::Json => return serde_json::from_slice(body).map_err(|e| e.to_string()),
+            WriteFormat::MessagePack => return rmp_serde::from_slice(body).map_err(|e| e.to_string()),
+            WriteFormat::Ndjson => body
+                .split(|b| *b == b'\n')
+                .enumerate()
+                .filter(|(_, line)| !line.trim_ascii().is_empty())
+                .map(|(i, line)| serde_json::from_slice(line).map_err(|e| format!("line {}: {}", i + 1, e)))
+                .collect::<Result<Vec<serde_json::Value>, String>>()?,
+            WriteFormat::Csv => {
+                let mut reader = csv::ReaderBuilder::new().has_headers(true).from_reader(body);
+                let header = reader.headers().map_err(|e| e.to_string())?.clone();
+                let mut records = Vec::new();
+                for record in reader.records() {
+                    let record = record.map_err(|e| e.to_string())?;
+                    let obj = header
+                        .iter()
+                        .zip(record.iter())
+                        .map(|(column, value)| {
+                            let value = match value {
+                                "" => serde_json::Value::Null,
+                                v => serde_json::Value::String(v.to_string()),
+                            };
+                            (column.to_string(), value)
+                        })
+                        .collect();
+                    records.push(serde_json::Value::Object(obj));
+                }
+                records
+            }
+        };
+        match <[serde_json::Value; 1]>::try_from(records) {
+            Ok([record]) => Ok(record),
+            Err(records) => Ok(serde_json::Value::Array(records)),
+        }
+    }
+}
+
+// Reads an insert or update body in the format named by its Content-Type.
+pub(crate) fn decode_write_body(req: &HttpRequest, body: &[u8]) -> Result<serde_json::Value, (StatusCode, Message)> {
+    let Some(format) = WriteFormat::of(req) else {
+        return Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, Message::new("unsupported_write_content_type")));
+    };
+    format.decode(body).map_err(|cause| {
+        (
+            StatusCode::BAD_REQUEST,
+            Message::new("undecodable_write_body").arg("format", format.name()).arg("cause", cause),
+        )
+    })
+}
+
+pub(crate) fn write_objects(body: serde_json::Value) -> Option<Vec<serde_json::Map<String, serde_json::Value>>> {
+    match body {
+        serde_json::Value::Object(obj) => Some(vec![obj]),
+        serde_json::Value::Array(items) if !items.is_empty() => items
+            .into_iter()
+            .map(|item| match item {
+                serde_json::Value::Object(obj) => Some(obj),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+// A write statement ready to run. The filter parameters are bound first,
+// then `values`.
+pub(crate) struct PreparedWrite {
+    pub(crate) query: String,
+    pub(crate) filters: FilterExpr,
+    pub(crate) values: Vec<Option<String>>,
+    // Whether the statement returns the affected rows
+    pub(crate) returning: bool,
+}
+
+impl PreparedWrite {
+    // The returned rows (if any) and the number of affected rows
+    pub(crate) async fn run(&self, tx: &mut sqlx::Transaction<'_, sqlx::Postgres>) -> Result<(Vec<PgRow>, u64), sqlx::Error> {
+        let mut query = bind_filters(sqlx::query(&self.query), &self.filters);
+        for value in &self.values {
+            query = query.bind(value.clone());
+        }
+        if self.returning {
+            let rows = query.fetch_all(&mut **tx).await?;
+            let count = rows.len() as u64;
+            Ok((rows, count))
+        } else {
+            Ok((Vec::new(), query.execute(&mut **tx).await?.rows_affected()))
+        }
+    }
+}
+
+pub(crate) fn prepare_insert(
+    table: &str,
+    column_types: &HashMap<String, (String, bool)>,
+    objects: &[serde_json::Map<String, serde_json::Value>],
+    conflict: &OnConflict,
+) -> Result<PreparedWrite, Message> {
+    let columns = write_columns(column_types, objects.iter().flat_map(|obj| obj.keys()))
+        .map_err(|column| Message::new("unknown_column").arg("column", column).arg("table", table))?;
+    
+    let mut param = 0;
+    let mut values = Vec::new();
+    let mut binds = Vec::new();
+    for obj in objects {
+        let row: Vec<String> = columns
+            .iter()
+            .map(|(key, name)| match obj.get(key) {
+                Some(value) => {
+                    param += 1;
+                    binds.push(json_to_text(value));
+                    format!("CAST(${}::text AS {})", param, column_types[name].0)
+                }
+                None => "DEFAULT".to_string(),
+            })
+            .collect();
+        values.push(format!("({})", row.join(", ")));
+    }
+    let names: Vec<&str> = columns.iter().map(|(_, name)| name.as_str()).collect();
+    let on_conflict = conflict.clause(table, column_types, &names)?;
+    // An empty object inserts a row of defaults
+    let query = if columns.is_empty() && objects.len() == 1 {
+        tag_query(table, &format!("INSERT INTO {} DEFAULT VALUES{} RETURNING *", table, on_conflict))
+    } else if columns.is_empty() {
+        return Err(Message::new("invalid_write_body"));
+    } else {
+        let column_list: Vec<String> = names.iter().map(|name| quote_identifier(name)).collect();
+        tag_query(table, &format!(
+            "INSERT INTO {} ({}) VALUES {}{} RETURNING *",
+            table,
+            column_list.join(", "),
+            values.join(", "),
+            on_conflict
+        ))
+    };
+    Ok(PreparedWrite { query, filters: FilterExpr::none(), values: binds, returning: true })
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct WriteParams {
+    pub(crate) or: Option<String>,
+    // Leave rows that already hold the given values untouched
+    pub(crate) skip_unchanged: Option<bool>,
+}
+
+pub(crate) async fn update_table(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<(String, String)>,
+    params: web::Query<WriteParams>,
+    body: web::Bytes,
+) -> impl Responder {
+    let body = match decode_write_body(&req, &body) {
+        Ok(body) => body,
+        Err((status, message)) => return error_response(&req, status, message),
+    };
+    let (table_name, filters_str) = path.into_inner();
+    if config.moderated(&table_name) {
+        if has_structured_filters(&req) {
+            return bad_request(&req, Message::new("structured_filter_staged"));
+        }
+        return stage_update(&req, &pool, &config, table_name, Some(filters_str), &params, body).await;
+    }
+    let filters = match request_filters(&req, Some(&filters_str)) {
+        Ok(f) => f,
+        Err(e) => return bad_request(&req, Message::new("invalid_filter").cause(e)),
+    };
+    run_update(&req, &pool, &config, &breaker, &table_name, filters, &params, body).await
+}
+
+pub(crate) async fn update_all(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<String>,
+    params: web::Query<WriteParams>,
+    body: web::Bytes,
+) -> impl Responder {
+    let body = match decode_write_body(&req, &body) {
+        Ok(body) => body,
+        Err((status, message)) => return error_response(&req, status, message),
+    };
+    let table_name = path.into_inner();
+    if config.moderated(&table_name) {
+        if has_structured_filters(&req) {
+            return bad_request(&req, Message::new("structured_filter_staged"));
+        }
+        return stage_update(&req, &pool, &config, table_name, None, &params, body).await;
+    }
+    let filters = match request_filters(&req, None) {
+        Ok(f) => f,
+        Err(e) => return bad_request(&req, Message::new("invalid_filter").cause(e)),
+    };
+    run_update(&req, &pool, &config, &breaker, &table_name, filters, &params, body).await
+}
+
+pub(crate) async fn stage_update(
+    req: &HttpRequest,
+    pool: &PgPool,
+    config: &Config,
+    table: String,
+    filter: Option<String>,
+    params: &WriteParams,
+    set: serde_json::Value,
+) -> HttpResponse {
+    if let Some(resp) = reject_moderated_param(req, &table, &[("skip_unchanged", params.skip_unchanged.is_some())]) {
+        return resp;
+    }
+    let operation = BatchOperation::Update { table, filter, or: params.or.clone(), set };
+    stage_write(req, pool, config, None, vec![operation]).await
+}
+
+// Sets the columns of the body's object on the rows matching the filters.
+// Unless DATAPI_ALLOW_UNFILTERED_WRITES is set, the request must filter;
+// the table's configured filters don't count.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn run_update(
+    req: &HttpRequest,
+    pool: &PgPool,
+    config: &Config,
+    breaker: &CircuitBreaker,
+    table_name: &str,
+    filters: FilterExpr,
+    params: &WriteParams,
+    body: serde_json::Value,
+) -> HttpResponse {
+    if !config.writes_enabled {
+        return error_response(req, StatusCode::FORBIDDEN, Message::new("writes_disabled"));
+    }
+    let table = match sanitize_table_name(table_name) {
+        Ok(t) => t,
+        Err(e) => return bad_request(req, e),
+    };
+    if let Some(resp) = table_read_only(req, pool, config, &table).await {
+        return resp;
+    }
+    let table = match resolve_table(req, pool, config, table).await {
+        Ok(t) => t,
+        Err(response) => return response,
+    };
+    if filters.is_empty() && params.or.is_none() && !config.allow_unfiltered_writes {
+        return bad_request(req, Message::new("missing_write_filter"));
+    }
+    let filters = match finish_filters(req, pool, filters, params.or.as_deref(), config, &table).await {
+        Ok(f) => f,
+        Err(e) => return bad_request(req, e),
+    };
+    let mut values = match body {
+        serde_json::Value::Object(obj) if !obj.is_empty() => obj,
+        _ => return bad_request(req, Message::new("invalid_update_body")),
+    };
+    // Rows can't be moved out of the claimed values
+    if let Err(e) = claim_values(req, config, &table).and_then(|pinned| pin_claim_values(&pinned, &mut values)) {
+        return error_response(req, StatusCode::FORBIDDEN, e);
+    }
+    
+    let column_types = match column_types(req, pool, &table).await {
+        Ok(c) => c,
+        Err(e) => return write_error_response(req, breaker, e),
+    };
+    let skip_unchanged = params.skip_unchanged.unwrap_or(false);
+    let update = match prepare_update(&table, &column_types, filters, &values, skip_unchanged) {
+        Ok(update) => update,
+        Err(e) => return bad_request(req, e),
+    };
+    log::info!("Executing update: {}", update.query);
+    
+    let mut tx = match begin_write_transaction(req, pool, config, &table).await {
+        Ok(tx) => tx,
+        Err(e) => return write_error_response(req, breaker, e),
+    };
+    let rows = match update.run(&mut tx).await {
+        Ok((rows, _)) => rows,
+        Err(e) => return write_error_response(req, breaker, e),
+    };
+    if let Err(e) = tx.commit().await {
+        return write_error_response(req, breaker, e);
+    }
+    breaker.record_success();
+    
+    let mut body = serde_json::Map::new();
+    if skip_unchanged {
+        body.insert("changed".to_string(), serde_json::json!(!rows.is_empty()));
+    }
+    write_response_with(pool, config, &table, StatusCode::OK, &rows, body).await
+}
+
+// With skip_unchanged, rows whose columns already hold the values (compared
+// as the type's text, so 1.0 and 1.00 differ) aren't written at all, which
+// also keeps them out of history and change triggers.
+pub(crate) fn prepare_update(
+    table: &str,
+    column_types: &HashMap<String, (String, bool)>,
+    filters: FilterExpr,
+    values: &serde_json::Map<String, serde_json::Value>,
+    skip_unchanged: bool,
+) -> Result<PreparedWrite, Message> {
+    let columns = write_columns(column_types, values.keys())
+        .map_err(|column| Message::new("unknown_column").arg("column", column).arg("table", table))?;
+    
+    // SET parameters are numbered after the filter parameters
+    let first_param = filters.param_count();
+    let assignments: Vec<String> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, (_, name))| {
+            format!(
+                "{} = CAST(${}::text AS {})",
+                quote_identifier(name),
+                first_param + i + 1,
+                column_types[name].0
+            )
+        })
+        .collect();
+    let mut where_clause = build_where_clause(&filters);
+    if skip_unchanged {
+        let differs: Vec<String> = columns
+            .iter()
+            .enumerate()
+            .map(|(i, (_, name))| {
+                format!(
+                    "{}::text IS DISTINCT FROM CAST(${}::text AS {})::text",
+                    quote_identifier(name),
+                    first_param + i + 1,
+                    column_types[name].0
+                )
+            })
+            .collect();
+        let differs = format!("({})", differs.join(" OR "));
+        where_clause = match where_clause.is_empty() {
+            true => format!(" WHERE {}", differs),
+            false => format!("{} AND {}", where_clause, differs),
+        };
+    }
+    let query = tag_query(table, &format!(
+        "UPDATE {} SET {}{} RETURNING *",
+        table,
+        assignments.join(", "),
+        where_clause
+    ));
+    let values = columns.iter().map(|(key, _)| json_to_text(&values[key])).collect();
+    Ok(PreparedWrite { query, filters, values, returning: true })
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DeleteParams {
+    pub(crate) or: Option<String>,
+    // Return the deleted rows instead of only their count
+    pub(crate) returning: Option<bool>,
+    // Required to delete without filters
+    pub(crate) confirm_all: Option<bool>,
+}
+
+impl DeleteParams {
+    pub(crate) fn staged(&self, table: String, filter: Option<String>) -> BatchOperation {
+        BatchOperation::Delete {
+            table,
+            filter,
+            or: self.or.clone(),
+            returning: self.returning,
+            confirm_all: self.confirm_all,
+        }
+    }
+}
+
+pub(crate) async fn delete_table(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<(String, String)>,
+    params: web::Query<DeleteParams>,
+) -> impl Responder {
+    let (table_name, filters_str) = path.into_inner();
+    if config.moderated(&table_name) {
+        if has_structured_filters(&req) {
+            return bad_request(&req, Message::new("structured_filter_staged"));
+        }
+        return stage_write(&req, &pool, &config, None, vec![params.staged(table_name, Some(filters_str))]).await;
+    }
+    let filters = match request_filters(&req, Some(&filters_str)) {
+        Ok(f) => f,
+        Err(e) => return bad_request(&req, Message::new("invalid_filter").cause(e)),
+    };
+    run_delete(&req, &pool, &config, &breaker, &table_name, filters, &params).await
+}
+
+pub(crate) async fn delete_all(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<String>,
+    params: web::Query<DeleteParams>,
+) -> impl Responder {
+    let table_name = path.into_inner();
+    if config.moderated(&table_name) {
+        if has_structured_filters(&req) {
+            return bad_request(&req, Message::new("structured_filter_staged"));
+        }
+        return stage_write(&req, &pool, &config, None, vec![params.staged(table_name, None)]).await;
+    }
+    let filters = match request_filters(&req, None) {
+        Ok(f) => f,
+        Err(e) => return bad_request(&req, Message::new("invalid_filter").cause(e)),
+    };
+    run_delete(&req, &pool, &config, &breaker, &table_name, filters, &params).await
+}
+
+// Deletes the rows matching the filters. Without filters the request must
+// pass confirm_all=true.
+pub(crate) async fn run_delete(
+    req: &HttpRequest,
+    pool: &PgPool,
+    config: &Config,
+    breaker: &CircuitBreaker,
+    table_name: &str,
+    filters: FilterExpr,
+    params: &DeleteParams,
+) -> HttpResponse {
+    if !config.writes_enabled {
+        return error_response(req, StatusCode::FORBIDDEN, Message::new("writes_disabled"));
+    }
+    let table = match sanitize_table_name(table_name) {
+        Ok(t) => t,
+        Err(e) => return bad_request(req, e),
+    };
+    if let Some(resp) = table_read_only(req, pool, config, &table).await {
+        return resp;
+    }
+    let table = match resolve_table(req, pool, config, table).await {
+        Ok(t) => t,
+        Err(response) => return response,
+    };
+    if filters.is_empty() && params.or.is_none() && !params.confirm_all.unwrap_or(false) {
+        return bad_request(req, Message::new("missing_delete_filter"));
+    }
+    let filters = match finish_filters(req, pool, filters, params.or.as_deref(), config, &table).await {
+        Ok(f) => f,
+        Err(e) => return bad_request(req, e),
+    };
+    
+    let returning = params.returning.unwrap_or(false);
+    let soft_delete = config.soft_delete_column(&table);
+    let delete = prepare_delete(&table, soft_delete, filters, returning);
+    log::info!("Executing delete: {}", delete.query);
+    
+    let mut tx = match begin_write_transaction(req, pool, config, &table).await {
+        Ok(tx) => tx,
+        Err(e) => return write_error_response(req, breaker, e),
+    };
+    let (rows, deleted) = match delete.run(&mut tx).await {
+        Ok(result) => result,
+        Err(e) => return write_error_response(req, breaker, e),
+    };
+    if let Err(e) = tx.commit().await {
+        return write_error_response(req, breaker, e);
+    }
+    breaker.record_success();
+    if soft_delete.is_none() {
+        adjust_row_count(req, &table, -(deleted as i64));
+    }
+    
+    if returning {
+        return write_response(pool, config, &table, StatusCode::OK, &rows).await;
+    }
+    write_response_builder(pool, StatusCode::OK)
+        .await
+        .json(serde_json::json!({ "count": deleted }))
+}
+
+// With a soft-delete column the rows are stamped rather than removed
+pub(crate) fn prepare_delete(table: &str, soft_delete: Option<&str>, filters: FilterExpr, returning: bool) -> PreparedWrite {
+    let statement = match soft_delete {
+        Some(column) => format!("UPDATE {} SET {} = now()", table, quote_identifier(column)),
+        None => format!("DELETE FROM {}", table),
+    };
+    let query = tag_query(table, &format!(
+        "{}{}{}",
+        statement,
+        build_where_clause(&filters),
+        if returning { " RETURNING *" } else { "" }
+    ));
+    PreparedWrite { query, filters, values: Vec::new(), returning }
+}
+
+pub(crate) const MAX_BATCH_OPERATIONS: usize = 1000;
+
+// One operation of POST /_batch. `filter` uses the path filter syntax of
+// the single-table endpoints.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub(crate) enum BatchOperation {
+    Insert {
+        table: String,
+        data: serde_json::Value,
+    },
+    Update {
+        table: String,
+        filter: Option<String>,
+        or: Option<String>,
+        set: serde_json::Value,
+    },
+    Delete {
+        table: String,
+        filter: Option<String>,
+        or: Option<String>,
+        returning: Option<bool>,
+        confirm_all: Option<bool>,
+    },
+}
+
+// `?isolation=` of a batch, ordered from the weakest level
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum IsolationLevel {
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct IsolationParam {
+    pub(crate) isolation: Option<String>,
+}
+
+impl IsolationLevel {
+    pub(crate) fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read_committed" => Some(IsolationLevel::ReadCommitted),
+            "repeatable_read" => Some(IsolationLevel::RepeatableRead),
+            "serializable" => Some(IsolationLevel::Serializable),
+            _ => None,
+        }
+    }
+    
+    // The requested level, if no stricter than the configured maximum
+    pub(crate) fn from_request(req: &HttpRequest, config: &Config) -> Result<Option<Self>, Message> {
+        let param = web::Query::<IsolationParam>::from_query(req.query_string()).ok().and_then(|q| q.0.isolation);
+        let Some(param) = param else {
+            return Ok(None);
+        };
+        let level = Self::parse(&param).ok_or_else(|| Message::new("invalid_isolation").arg("value", &param).param("isolation"))?;
+        if level > config.max_isolation {
+            let max = config.max_isolation.name();
+            return Err(Message::new("isolation_not_allowed").arg("value", &param).arg("max", max).param("isolation"));
+        }
+        Ok(Some(level))
+    }
+    
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            IsolationLevel::ReadCommitted => "read_committed",
+            IsolationLevel::RepeatableRead => "repeatable_read",
+            IsolationLevel::Serializable => "serializable",
+        }
+    }
+    
+    pub(crate) fn sql(self) -> &'static str {
+        match self {
+            IsolationLevel::ReadCommitted => "READ COMMITTED",
+            IsolationLevel::RepeatableRead => "REPEATABLE READ",
+            IsolationLevel::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
+pub(crate) struct BatchStep {
+    pub(crate) op: &'static str,
+    pub(crate) table: String,
+    pub(crate) status: StatusCode,
+    pub(crate) write: PreparedWrite,
+}
+
+// Checks one batch operation and builds its statement, the same way the
+// single-table endpoints do
+pub(crate) async fn prepare_batch_step(
+    req: &HttpRequest,
+    pool: &PgPool,
+    config: &Config,
+    exposure: Option<&Exposure>,
+    operation: BatchOperation,
+) -> Result<BatchStep, HttpResponse> {
+    let (op, table_name) = match &operation {
+        BatchOperation::Insert { table, .. } => ("insert", table),
+        BatchOperation::Update { table, .. } => ("update", table),
+        BatchOperation::Delete { table, .. } => ("delete", table),
+    };
+    let table = sanitize_table_name(table_name).map_err(|e| bad_request(req, e))?;
+    if exposure.is_some_and(|exposure| !exposure.is_exposed(&table)) {
+        return Err(error_response(req, StatusCode::NOT_FOUND, Message::new("table_not_found").arg("table", &table)));
+    }
+    if let Some(resp) = table_grant_denied(req, &table, req.method()) {
+        return Err(resp);
+    }
+    if let Some(resp) = table_read_only(req, pool, config, &table).await {
+        return Err(resp);
+    }
+    let table = resolve_table(req, pool, config, table).await?;
+    let column_types = column_types(req, pool, &table).await.map_err(|e| {
+        log::error!("Database error: {}", e);
+        error_response(req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"))
+    })?;
+    let filters = |filter: &Option<String>, or: &Option<String>| -> Result<(FilterExpr, bool), Message> {
+        let filters = match filter {
+            Some(filter) => parse_multiple_filters(filter).map_err(|e| Message::new("invalid_filter").cause(e))?,
+            None => FilterExpr::none(),
+        };
+        let unfiltered = filters.is_empty() && or.is_none();
+        Ok((finish_filters_with(req, filters, or.as_deref(), config, &table, &column_types)?, unfiltered))
+    };
+
+    let pinned = claim_values(req, config, &table).map_err(|e| error_response(req, StatusCode::FORBIDDEN, e))?;
+    let forbidden = |e| error_response(req, StatusCode::FORBIDDEN, e);
+    
+    let write = match operation {
+        BatchOperation::Insert { data, .. } => match write_objects(data) {
+            Some(mut objects) => {
+                for obj in &mut objects {
+                    pin_claim_values(&pinned, obj).map_err(forbidden)?;
+                }
+                prepare_insert(&table, &column_types, &objects, &OnConflict::ERROR)
+            }
+            None => Err(Message::new("invalid_write_body")),
+        },
+        BatchOperation::Update { filter, or, mut set, .. } => {
+            match &mut set {
+                serde_json::Value::Object(values) if !values.is_empty() => {
+                    pin_claim_values(&pinned, values).map_err(forbidden)?
+                }
+                _ => {}
+            }
+            filters(&filter, &or).and_then(|(filters, unfiltered)| {
+                if unfiltered && !config.allow_unfiltered_writes {
+                    return Err(Message::new("missing_write_filter"));
+                }
+                match set {
+                    serde_json::Value::Object(values) if !values.is_empty() => {
+                        prepare_update(&table, &column_types, filters, &values, false)
+                    }
+                    _ => Err(Message::new("invalid_update_body")),
+                }
+            })
+        }
+        BatchOperation::Delete { filter, or, returning, confirm_all, .. } => {
+            filters(&filter, &or).and_then(|(filters, unfiltered)| {
+                if unfiltered && !confirm_all.unwrap_or(false) {
+                    return Err(Message::new("missing_delete_filter"));
+                }
+                Ok(prepare_delete(&table, config.soft_delete_column(&table), filters, returning.unwrap_or(false)))
+            })
+        }
+    };
+    let write = write.map_err(|e| bad_request(req, e))?;
+    let status = if op == "insert" { StatusCode::CREATED } else { StatusCode::OK };
+    Ok(BatchStep { op, table, status, write })
+}
+
+// Runs an ordered list of inserts, updates and deletes, possibly across
+// tables, in one transaction. Nothing is applied unless every operation
+// succeeds; a failure reports the index of the operation that failed. The
+// transaction runs at the `?isolation=` level, by default the database's.
+pub(crate) async fn run_batch(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    exposure: Option<web::Data<Exposure>>,
+    body: web::Json<Vec<BatchOperation>>,
+) -> impl Responder {
+    if !config.writes_enabled {
+        return error_response(&req, StatusCode::FORBIDDEN, Message::new("writes_disabled"));
+    }
+    let operations = body.into_inner();
+    if operations.is_empty() || operations.len() > MAX_BATCH_OPERATIONS {
+        return bad_request(&req, Message::new("invalid_batch_body").arg("max", MAX_BATCH_OPERATIONS));
+    }
+    let isolation = match IsolationLevel::from_request(&req, &config) {
+        Ok(isolation) => isolation,
+        Err(message) => return bad_request(&req, message),
+    };
+    // One moderated table puts the whole batch up for approval
+    if operations.iter().any(|op| config.moderated(op.table())) {
+        if let Some(resp) = reject_moderated_param(&req, "_batch", &[("isolation", isolation.is_some())]) {
+            return resp;
+        }
+        return stage_write(&req, &pool, &config, exposure.as_ref().map(|e| e.get_ref()), operations).await;
+    }
+    
+    let mut steps = Vec::with_capacity(operations.len());
+    for (index, operation) in operations.into_iter().enumerate() {
+        match prepare_batch_step(&req, &pool, &config, exposure.as_ref().map(|e| e.get_ref()), operation).await {
+            Ok(step) => steps.push(step),
+            Err(resp) => return with_batch_operation(resp, index).await,
+        }
+    }
+    
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return write_error_response(&req, &breaker, e),
+    };
+    // Has to come before the transaction's first query
+    if let Some(isolation) = isolation {
+        let set = format!("SET TRANSACTION ISOLATION LEVEL {}", isolation.sql());
+        if let Err(e) = sqlx::query(&set).execute(&mut *tx).await {
+            return write_error_response(&req, &breaker, e);
+        }
+    }
+    if let Err(e) = apply_actor(&mut tx, &req).await {
+        return write_error_response(&req, &breaker, e);
+    }
+    if let Err(e) = apply_claim_settings(&mut tx, &claim_settings(&req, &config)).await {
+        return write_error_response(&req, &breaker, e);
+    }
+    let results = match run_batch_steps(&mut tx, &config, &steps).await {
+        Ok(results) => results,
+        Err((index, e)) => {
+            let mut body = serde_json::Map::new();
+            body.insert("operation".to_string(), serde_json::json!(index));
+            return write_error_response_with(&req, &breaker, e, body);
+        }
+    };
+    if let Err(e) = tx.commit().await {
+        return write_error_response(&req, &breaker, e);
+    }
+    breaker.record_success();
+    let results = batch_results(&req, &config, &steps, results);
+    write_response_builder(&pool, StatusCode::OK)
+        .await
+        .json(serde_json::json!({ "results": results }))
+}
+
+// Runs the steps in order, returning each one's rows and count, or the index
+// of the step that failed
+pub(crate) async fn run_batch_steps(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    config: &Config,
+    steps: &[BatchStep],
+) -> Result<Vec<(Vec<PgRow>, u64)>, (usize, sqlx::Error)> {
+    let mut results = Vec::with_capacity(steps.len());
+    for (index, step) in steps.iter().enumerate() {
+        log::info!("Executing batch {} {}: {}", index, step.op, step.write.query);
+        let result = match apply_table_settings(tx, config, &step.table).await {
+            Ok(()) => step.write.run(tx).await,
+            Err(e) => Err(e),
+        };
+        results.push(result.map_err(|e| (index, e))?);
+    }
+    Ok(results)
+}
+
+// The results of committed steps as the response lists them, moving the
+// kept row counts along
+pub(crate) fn batch_results(
+    req: &HttpRequest,
+    config: &Config,
+    steps: &[BatchStep],
+    results: Vec<(Vec<PgRow>, u64)>,
+) -> Vec<serde_json::Value> {
+    for (step, (_, count)) in steps.iter().zip(&results) {
+        match step.op {
+            "insert" => adjust_row_count(req, &step.table, *count as i64),
+            "delete" if config.soft_delete_column(&step.table).is_none() => {
+                adjust_row_count(req, &step.table, -(*count as i64))
+            }
+            _ => {}
+        }
+    }
+    steps
+        .iter()
+        .zip(results)
+        .map(|(step, (rows, count))| {
+            let mut result = serde_json::json!({
+                "op": step.op,
+                "table": step.table.trim_matches('"'),
+                "status": step.status.as_u16(),
+                "count": count,
+            });
+            if step.write.returning {
+                let data: Vec<serde_json::Value> = rows
+                    .iter()
+                    .map(|row| {
+                        let mut obj = row_to_json(row, config.value_format());
+                        protect_columns(config, &step.table, &mut obj);
+                        serde_json::Value::Object(obj)
+                    })
+                    .collect();
+                result["data"] = serde_json::Value::Array(data);
+            }
+            result
+        })
+        .collect()
+}
+
+// Adds the failing operation's index to an error response of a batch
+pub(crate) async fn with_batch_operation(resp: HttpResponse, index: usize) -> HttpResponse {
+    let mut builder = HttpResponse::build(resp.status());
+    for (name, value) in resp.headers() {
+        builder.insert_header((name.clone(), value.clone()));
+    }
+    let bytes = actix_web::body::to_bytes(resp.into_body()).await.unwrap_or_default();
+    match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(serde_json::Value::Object(mut body)) => {
+            body.insert("operation".to_string(), serde_json::json!(index));
+            builder.json(body)
+        }
+        _ => builder.body(bytes),
+    }
+}
+
+// Moderated tables: writes through the API are checked like a batch would
+// check them, then staged in datapi_pending.changes (off the search path like
+// datapi_history). An admin approves a change, which runs its operations in
+// one transaction as its requester, or rejects it. Tables with claim_filters
+// can't be moderated, the staged operations don't carry the claims.
+pub(crate) const MODERATION_SETUP: &[&str] = &[
+    "CREATE SCHEMA IF NOT EXISTS datapi_pending",
+    "CREATE TABLE IF NOT EXISTS datapi_pending.changes (
+        id bigserial PRIMARY KEY,
+        tables text[] NOT NULL,
+        operations jsonb NOT NULL,
+        settings jsonb NOT NULL DEFAULT '[]',
+        requested_by text,
+        requested_at timestamptz NOT NULL DEFAULT now(),
+        status text NOT NULL DEFAULT 'pending',
+        decided_by text,
+        decided_at timestamptz,
+        reason text,
+        result jsonb
+    )",
+    "CREATE INDEX IF NOT EXISTS changes_status_idx ON datapi_pending.changes (status, id)",
+];
+
+pub(crate) async fn install_moderation(pool: &PgPool, config: &Config) {
+    if !config.tables.values().any(|t| t.moderated) {
+        return;
+    }
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = pool.begin().await?;
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('datapi_pending'))")
+            .execute(&mut *tx)
+            .await?;
+        for statement in MODERATION_SETUP {
+            sqlx::query(statement).execute(&mut *tx).await?;
+        }
+        tx.commit().await
+    }
+    .await;
+    if let Err(e) = result {
+        panic!("Failed to install the pending changes table: {}", e);
+    }
+}
+
+impl BatchOperation {
+    pub(crate) fn table(&self) -> &str {
+        match self {
+            BatchOperation::Insert { table, .. }
+            | BatchOperation::Update { table, .. }
+            | BatchOperation::Delete { table, .. } => table,
+        }
+    }
+}
+
+// Rejects write parameters a staged operation can't carry
+pub(crate) fn reject_moderated_param(req: &HttpRequest, table: &str, params: &[(&str, bool)]) -> Option<HttpResponse> {
+    let (param, _) = params.iter().find(|(_, given)| *given)?;
+    Some(bad_request(req, Message::new("moderated_param").arg("param", param).arg("table", table).param(param)))
+}
+
+// Stages the operations for approval instead of running them, answering 202
+// with the pending change's id
+pub(crate) async fn stage_write(
+    req: &HttpRequest,
+    pool: &PgPool,
+    config: &Config,
+    exposure: Option<&Exposure>,
+    operations: Vec<BatchOperation>,
+) -> HttpResponse {
+    if !config.writes_enabled {
+        return error_response(req, StatusCode::FORBIDDEN, Message::new("writes_disabled"));
+    }
+    let mut tables = Vec::new();
+    for (index, operation) in operations.iter().enumerate() {
+        match prepare_batch_step(req, pool, config, exposure, operation.clone()).await {
+            Ok(step) => tables.push(step.table.trim_matches('"').to_string()),
+            Err(resp) if operations.len() > 1 => return with_batch_operation(resp, index).await,
+            Err(resp) => return resp,
+        }
+    }
+    tables.dedup();
+    let settings: Vec<(String, String)> = claim_settings(req, config);
+    let staged = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO datapi_pending.changes (tables, operations, settings, requested_by) \
+         VALUES ($1, $2, $3, $4) RETURNING id",
+    )
+    .bind(&tables)
+    .bind(serde_json::json!(operations))
+    .bind(serde_json::json!(settings))
+    .bind(request_actor(req))
+    .fetch_one(pool)
+    .await;
+    match staged {
+        Ok(id) => {
+            log::info!("Staged change {} to {} for approval", id, tables.join(", "));
+            HttpResponse::Accepted()
+                .insert_header((actix_web::http::header::LOCATION, format!("/_admin/pending/{}", id)))
+                .json(serde_json::json!({ "pending": id, "status": "pending" }))
+        }
+        Err(e) => {
+            log::error!("Database error: {}", e);
+            error_response(req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct PendingParams {
+    pub(crate) page: Option<usize>,
+    pub(crate) page_size: Option<usize>,
+    // pending (the default), approved, rejected or all
+    pub(crate) status: Option<String>,
+    pub(crate) table: Option<String>,
+}
+
+pub(crate) const PENDING_STATUSES: &[&str] = &["pending", "approved", "rejected", "all"];
+
+// The staged changes, oldest first. The requester's claim settings stay in
+// the database.
+pub(crate) async fn list_pending(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    params: web::Query<PendingParams>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    let status = params.status.as_deref().unwrap_or("pending").to_lowercase();
+    if !PENDING_STATUSES.contains(&status.as_str()) {
+        return bad_request(&req, Message::new("invalid_pending_status").arg("status", &status).param("status"));
+    }
+    let page = params.page.unwrap_or(1).max(1);
+    let page_size = config.page_size(params.page_size);
+    let condition = "($1 = 'all' OR status = $1) AND ($2::text IS NULL OR $2 = ANY(tables))";
+    let table = params.table.as_ref().map(|t| t.to_lowercase());
+    let result: Result<(Vec<serde_json::Value>, i64), sqlx::Error> = async {
+        let rows = sqlx::query_scalar(&format!(
+            "SELECT to_jsonb(c) - 'settings' FROM datapi_pending.changes c WHERE {} ORDER BY id LIMIT $3 OFFSET $4",
+            condition
+        ))
+        .bind(&status)
+        .bind(&table)
+        .bind(page_size as i64)
+        .bind(((page - 1) * page_size) as i64)
+        .fetch_all(pool.get_ref())
+        .await?;
+        let total: i64 = sqlx::query_scalar(&format!("SELECT count(*) FROM datapi_pending.changes WHERE {}", condition))
+            .bind(&status)
+            .bind(&table)
+            .fetch_one(pool.get_ref())
+            .await?;
+        Ok((rows, total))
+    }
+    .await;
+    let (data, total) = match result {
+        Ok(result) => result,
+        Err(e) => return database_error_response(&req, &breaker, e),
+    };
+    QueryResult {
+        count: data.len(),
+        data,
+        page,
+        page_size,
+        total_count: Some(total as usize),
+        total_count_estimated: false,
+        total_pages: None,
+        has_next: false,
+        result_token: None,
+        next_cursor: None,
+        sort_fingerprint: None,
+        warnings: Vec::new(),
+    }
+    .into_response(&req, HttpResponse::Ok())
+}
+
+pub(crate) async fn get_pending(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<i64>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    let id = path.into_inner();
+    let change: Result<Option<serde_json::Value>, sqlx::Error> =
+        sqlx::query_scalar("SELECT to_jsonb(c) - 'settings' FROM datapi_pending.changes c WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool.get_ref())
+            .await;
+    match change {
+        Ok(Some(change)) => HttpResponse::Ok().json(change),
+        Ok(None) => error_response(&req, StatusCode::NOT_FOUND, Message::new("pending_not_found").arg("id", id)),
+        Err(e) => database_error_response(&req, &breaker, e),
+    }
+}
+
+// Locks a pending change for its decision; the error response when it
+// doesn't exist or was decided already
+pub(crate) async fn lock_pending(
+    req: &HttpRequest,
+    breaker: &CircuitBreaker,
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    id: i64,
+) -> Result<PgRow, HttpResponse> {
+    let row = sqlx::query("SELECT status, operations, settings, requested_by FROM datapi_pending.changes WHERE id = $1 FOR UPDATE")
+        .bind(id)
+        .fetch_optional(&mut **tx)
+        .await
+        .map_err(|e| database_error_response(req, breaker, e))?;
+    let Some(row) = row else {
+        return Err(error_response(req, StatusCode::NOT_FOUND, Message::new("pending_not_found").arg("id", id)));
+    };
+    let status: String = row.get("status");
+    if status != "pending" {
+        let message = Message::new("pending_decided").arg("id", id).arg("status", status);
+        return Err(error_response(req, StatusCode::CONFLICT, message));
+    }
+    Ok(row)
+}
+
+// Applies a pending change in one transaction, with the requester as the
+// actor and their claim settings. If an operation fails nothing is applied
+// and the change stays pending.
+pub(crate) async fn approve_pending(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    exposure: Option<web::Data<Exposure>>,
+    path: web::Path<i64>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    if !config.writes_enabled {
+        return error_response(&req, StatusCode::FORBIDDEN, Message::new("writes_disabled"));
+    }
+    let id = path.into_inner();
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return write_error_response(&req, &breaker, e),
+    };
+    let row = match lock_pending(&req, &breaker, &mut tx, id).await {
+        Ok(row) => row,
+        Err(resp) => return resp,
+    };
+    let operations: Vec<BatchOperation> = match serde_json::from_value(row.get("operations")) {
+        Ok(operations) => operations,
+        Err(e) => {
+            log::error!("Pending change {} can't be read: {}", id, e);
+            return error_response(&req, StatusCode::INTERNAL_SERVER_ERROR, Message::new("database_error"));
+        }
+    };
+    let settings: Vec<(String, String)> = serde_json::from_value(row.get("settings")).unwrap_or_default();
+    let requested_by: Option<String> = row.get("requested_by");
+    
+    let mut steps = Vec::with_capacity(operations.len());
+    for (index, operation) in operations.into_iter().enumerate() {
+        match prepare_batch_step(&req, &pool, &config, exposure.as_ref().map(|e| e.get_ref()), operation).await {
+            Ok(step) => steps.push(step),
+            Err(resp) => return with_batch_operation(resp, index).await,
+        }
+    }
+    let prepared = async {
+        if let Some(actor) = &requested_by {
+            sqlx::query("SELECT set_config('datapi.actor', $1, true)").bind(actor).execute(&mut *tx).await?;
+        }
+        apply_claim_settings(&mut tx, &settings).await
+    };
+    if let Err(e) = prepared.await {
+        return write_error_response(&req, &breaker, e);
+    }
+    let results = match run_batch_steps(&mut tx, &config, &steps).await {
+        Ok(results) => results,
+        Err((index, e)) => {
+            let mut body = serde_json::Map::new();
+            body.insert("operation".to_string(), serde_json::json!(index));
+            return write_error_response_with(&req, &breaker, e, body);
+        }
+    };
+    let counts: Vec<serde_json::Value> = steps
+        .iter()
+        .zip(&results)
+        .map(|(step, (_, count))| serde_json::json!({ "op": step.op, "table": step.table.trim_matches('"'), "count": count }))
+        .collect();
+    let decided = sqlx::query(
+        "UPDATE datapi_pending.changes SET status = 'approved', decided_by = $2, decided_at = now(), result = $3 WHERE id = $1",
+    )
+    .bind(id)
+    .bind(request_actor(&req))
+    .bind(serde_json::json!(counts))
+    .execute(&mut *tx)
+    .await;
+    if let Err(e) = decided {
+        return write_error_response(&req, &breaker, e);
+    }
+    if let Err(e) = tx.commit().await {
+        return write_error_response(&req, &breaker, e);
+    }
+    breaker.record_success();
+    log::info!("Approved pending change {}", id);
+    let results = batch_results(&req, &config, &steps, results);
+    write_response_builder(&pool, StatusCode::OK)
+        .await
+        .json(serde_json::json!({ "id": id, "status": "approved", "results": results }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct RejectBody {
+    pub(crate) reason: Option<String>,
+}
+
+pub(crate) async fn reject_pending(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<i64>,
+    body: Option<web::Json<RejectBody>>,
+) -> impl Responder {
+    if let Some(resp) = reject_non_admin(&req, &config) {
+        return resp;
+    }
+    let id = path.into_inner();
+    let reason = body.and_then(|b| b.into_inner().reason);
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(e) => return database_error_response(&req, &breaker, e),
+    };
+    if let Err(resp) = lock_pending(&req, &breaker, &mut tx, id).await {
+        return resp;
+    }
+    let decided = sqlx::query(
+        "UPDATE datapi_pending.changes SET status = 'rejected', decided_by = $2, decided_at = now(), reason = $3 WHERE id = $1",
+    )
+    .bind(id)
+    .bind(request_actor(&req))
+    .bind(&reason)
+    .execute(&mut *tx)
+    .await;
+    if let Err(e) = decided {
+        return database_error_response(&req, &breaker, e);
+    }
+    if let Err(e) = tx.commit().await {
+        return database_error_response(&req, &breaker, e);
+    }
+    log::info!("Rejected pending change {}", id);
+    HttpResponse::Ok().json(serde_json::json!({ "id": id, "status": "rejected" }))
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RowError {
+    // 1-based data row, not counting a CSV header
+    pub(crate) row: usize,
+    pub(crate) column: String,
+    pub(crate) error: String,
+}
+
+// Failures reported per request; `failed` counts all invalid rows
+pub(crate) const MAX_ROW_ERRORS: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct BulkParams {
+    pub(crate) formats: Option<String>,
+    // Insert the valid rows and report the others instead of rejecting all
+    pub(crate) skip_invalid: Option<bool>,
+}
+
+// Values of a bulk body by row and column, None for NULL
+pub(crate) type BulkRows = Vec<Vec<Option<String>>>;
+
+// pin_claim_values for bulk rows, adding the pinned columns rows leave out
+pub(crate) fn pin_bulk_claim_values(pinned: &[(String, String)], header: &mut Vec<String>, rows: &mut BulkRows) -> Result<(), Message> {
+    for (column, value) in pinned {
+        let index = match header.iter().position(|h| h == column) {
+            Some(index) => index,
+            None => {
+                header.push(column.clone());
+                header.len() - 1
+            }
+        };
+        for row in rows.iter_mut() {
+            if row.len() <= index {
+                row.resize(index + 1, None);
+            }
+            if row[index].as_ref().is_some_and(|given| given != value) {
+                return Err(Message::new("claim_mismatch").arg("column", column));
+            }
+            row[index] = Some(value.clone());
+        }
+    }
+    Ok(())
+}
+
+// Reads a bulk body into a header of column names and its rows. CSV needs a
+// header row; empty CSV fields are NULL.
+pub(crate) fn parse_bulk_body(format: WriteFormat, body: &[u8]) -> Result<(Vec<String>, BulkRows), String> {
+    if format == WriteFormat::Csv {
+        let mut reader = csv::ReaderBuilder::new().has_headers(true).from_reader(body);
+        let header: Vec<String> = reader
+            .headers()
+            .map_err(|e| e.to_string())?
+            .iter()
+            .map(str::to_string)
+            .collect();
+        let mut rows = Vec::new();
+        for record in reader.records() {
+            let record = record.map_err(|e| e.to_string())?;
+            rows.push(record.iter().map(|v| (!v.is_empty()).then(|| v.to_string())).collect());
+        }
+        return Ok((header, rows));
+    }
+    
+    let items = match format.decode(body)? {
+        serde_json::Value::Array(items) => items,
+        item => vec![item],
+    };
+    let items: Vec<serde_json::Map<String, serde_json::Value>> = items
+        .into_iter()
+        .map(|item| match item {
+            serde_json::Value::Object(obj) => Ok(obj),
+            _ => Err("expected objects".to_string()),
+        })
+        .collect::<Result<_, _>>()?;
+    let mut header: Vec<String> = Vec::new();
+    for key in items.iter().flat_map(|obj| obj.keys()) {
+        if !header.contains(key) {
+            header.push(key.clone());
+        }
+    }
+    let rows = items
+        .iter()
+        .map(|obj| header.iter().map(|k| obj.get(k).and_then(json_to_text)).collect())
+        .collect();
+    Ok((header, rows))
+}
+
+// A row for COPY ... (FORMAT csv, NULL '\N'): values always quoted, so only
+// the unquoted marker is NULL
+pub(crate) fn write_copy_row(buf: &mut Vec<u8>, values: &[Option<String>]) {
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            buf.push(b',');
+        }
+        match value {
+            None => buf.extend_from_slice(b"\\N"),
+            Some(v) => {
+                buf.push(b'"');
+                buf.extend_from_slice(v.replace('"', "\"\"").as_bytes());
+                buf.push(b'"');
+            }
+        }
+    }
+    buf.push(b'\n');
+}
+
+// The input row a COPY error refers to, from its `COPY t, line N` context
+pub(crate) fn copy_error_row(e: &sqlx::Error) -> Option<usize> {
+    let sqlx::Error::Database(db) = e else {
+        return None;
+    };
+    let context = db.try_downcast_ref::<sqlx::postgres::PgDatabaseError>()?.r#where()?;
+    let line = context.split(", line ").nth(1)?;
+    line.split(|c: char| !c.is_ascii_digit()).next()?.parse().ok()
+}
+
+// Inserts many rows per request through COPY FROM STDIN. The body is a JSON
+// array of objects or CSV with a header row. Every value is checked against
+// its column type first; by default any invalid row rejects the request with
+// the failures by row and column.
+pub(crate) async fn bulk_insert(
+    req: HttpRequest,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    breaker: web::Data<CircuitBreaker>,
+    path: web::Path<String>,
+    params: web::Query<BulkParams>,
+    body: web::Bytes,
+) -> impl Responder {
+    if !config.writes_enabled {
+        return error_response(&req, StatusCode::FORBIDDEN, Message::new("writes_disabled"));
+    }
+    let table = match sanitize_table_name(&path.into_inner()) {
+        Ok(t) => t,
+        Err(e) => return bad_request(&req, e),
+    };
+    if let Some(resp) = table_read_only(&req, &pool, &config, &table).await {
+        return resp;
+    }
+    if config.moderated(&table) {
+        return error_response(&req, StatusCode::CONFLICT, Message::new("moderated_bulk").arg("table", &table));
+    }
+    let table = match resolve_table(&req, &pool, &config, table).await {
+        Ok(t) => t,
+        Err(response) => return response,
+    };
+    let formats = match parse_column_formats(params.formats.as_deref().unwrap_or_default()) {
+        Ok(f) => f,
+        Err(e) => return bad_request(&req, e),
+    };
+    
+    let Some(format) = WriteFormat::of(&req) else {
+        return error_response(
+            &req,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Message::new("unsupported_write_content_type"),
+        );
+    };
+    let (mut header, mut rows) = match parse_bulk_body(format, &body) {
+        Ok(parsed) => parsed,
+        Err(cause) => return bad_request(&req, Message::new("invalid_bulk_body").arg("cause", cause)),
+    };
+    if header.is_empty() || rows.is_empty() {
+        return bad_request(&req, Message::new("invalid_write_body"));
+    }
+    let pinned = claim_values(&req, &config, &table)
+        .and_then(|pinned| pin_bulk_claim_values(&pinned, &mut header, &mut rows));
+    if let Err(e) = pinned {
+        return error_response(&req, StatusCode::FORBIDDEN, e);
+    }
+    
+    let column_types = match column_types(&req, &pool, &table).await {
+        Ok(c) => c,
+        Err(e) => return write_error_response(&req, &breaker, e),
+    };
+    let columns = match write_columns(&column_types, header.iter()) {
+        Ok(c) => c,
+        Err(column) => {
+            return bad_request(
+                &req,
+                Message::new("unknown_column").arg("column", column).arg("table", &table),
+            )
+        }
+    };
+    let coercions: Vec<(Coercion, Option<&ColumnFormat>)> = columns
+        .iter()
+        .map(|(_, name)| (Coercion::of(&column_types[name].0), formats.get(&name.to_lowercase())))
+        .collect();
+    
+    let mut data = Vec::with_capacity(body.len() + rows.len() * 4);
+    let mut copied = Vec::new();
+    let mut errors = Vec::new();
+    let mut failed = 0;
+    for (i, row) in rows.iter().enumerate() {
+        let mut values = Vec::with_capacity(columns.len());
+        let mut valid = true;
+        for (j, (key, _)) in columns.iter().enumerate() {
+            let value = row.get(j).cloned().flatten();
+            let (coercion, format) = coercions[j];
+            match value.as_deref().map(|v| coerce_value(v, coercion, format)) {
+                Some(Some(v)) => values.push(Some(v)),
+                None => values.push(None),
+                Some(None) => {
+                    valid = false;
+                    if errors.len() < MAX_ROW_ERRORS {
+                        let message = Message::new("invalid_value")
+                            .arg("type", coercion.name())
+                            .arg("value", value.unwrap_or_default());
+                        errors.push(RowError {
+                            row: i + 1,
+                            column: key.clone(),
+                            error: render_message(&req, &message).0,
+                        });
+                    }
+                }
+            }
+        }
+        if valid {
+            write_copy_row(&mut data, &values);
+            copied.push(i + 1);
+        } else {
+            failed += 1;
+        }
+    }
+    let skip_invalid = params.skip_invalid.unwrap_or(false);
+    if failed > 0 && !skip_invalid {
+        let mut body = serde_json::Map::new();
+        body.insert("failed".to_string(), serde_json::json!(failed));
+        body.insert("errors".to_string(), serde_json::json!(errors));
+        return error_response_with(
+            &req,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Message::new("invalid_rows").arg("count", failed),
+            body,
+        );
+    }
+    
+    let column_list: Vec<String> = columns.iter().map(|(_, name)| quote_identifier(name)).collect();
+    let statement = tag_query(&table, &format!(
+        "COPY {} ({}) FROM STDIN (FORMAT csv, NULL '\\N')",
+        table,
+        column_list.join(", ")
+    ));
+    log::info!("Executing bulk insert of {} rows: {}", copied.len(), statement);
+    
+    let mut tx = match begin_write_transaction(&req, &pool, &config, &table).await {
+        Ok(tx) => tx,
+        Err(e) => return write_error_response(&req, &breaker, e),
+    };
+    let inserted = if copied.is_empty() {
+        Ok(0)
+    } else {
+        match tx.copy_in_raw(&statement).await {
+            Ok(mut copy) => match copy.send(data).await {
+                Ok(_) => copy.finish().await,
+                Err(e) => {
+                    let _ = copy.abort("send failed").await;
+                    Err(e)
+                }
+            },
+            Err(e) => Err(e),
+        }
+    };
+    let inserted = match inserted {
+        Ok(n) => n,
+        Err(e) => {
+            // Point at the input row, the COPY line counts copied rows only
+            let row = copy_error_row(&e).and_then(|line| copied.get(line.wrapping_sub(1)).copied());
+            let mut body = serde_json::Map::new();
+            if let Some(row) = row {
+                body.insert("row".to_string(), serde_json::json!(row));
+            }
+            return write_error_response_with(&req, &breaker, e, body);
+        }
+    };
+    if let Err(e) = tx.commit().await {
+        return write_error_response(&req, &breaker, e);
+    }
+    breaker.record_success();
+    adjust_row_count(&req, &table, inserted as i64);
+    
+    write_response_builder(&pool, StatusCode::CREATED).await.json(serde_json::json!({
+        "inserted": inserted,
+        "failed": failed,
+        "errors": errors,
+    }))
+}
+
+// Maps the keys of a write body onto the table's columns, case-insensitively
+// for lowercase column names. Returns (key, column) pairs in first-seen
+// order, or the first key that isn't a column.
+pub(crate) fn write_columns<'a>(
+    column_types: &HashMap<String, (String, bool)>,
+    keys: impl Iterator<Item = &'a String>,
+) -> Result<Vec<(String, String)>, String> {
+    let mut columns: Vec<(String, String)> = Vec::new();
+    for key in keys {
+        if columns.iter().any(|(k, _)| k == key) {
+            continue;
+        }
+        let name = if column_types.contains_key(key) {
+            key.clone()
+        } else if column_types.contains_key(&key.to_lowercase()) {
+            key.to_lowercase()
+        } else {
+            return Err(key.clone());
+        };
+        columns.push((key.clone(), name));
+    }
+    Ok(columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use actix_web::test::TestRequest;
+    use actix_web::HttpMessage;
+    use crate::access::TokenGrants;
+    use crate::background::ACTOR_HEADER;
+
+    #[test]
+    fn write_keys_map_onto_columns() {
+        let column_types = HashMap::from([
+            ("id".to_string(), ("integer".to_string(), true)),
+            ("name".to_string(), ("text".to_string(), false)),
+            ("Title".to_string(), ("text".to_string(), false)),
+        ]);
+        let keys = ["Name", "id", "Title", "name", "id"].map(String::from);
+        assert_eq!(
+            write_columns(&column_types, keys.iter()).unwrap(),
+            [("Name", "name"), ("id", "id"), ("Title", "Title"), ("name", "name")]
+                .map(|(k, c)| (k.to_string(), c.to_string()))
+        );
+        let keys = ["id", "title"].map(String::from);
+        assert_eq!(write_columns(&column_types, keys.iter()).unwrap_err(), "title");
+        assert_eq!(quote_identifier("we\"ird"), "\"we\"\"ird\"");
+        assert_eq!(json_to_text(&serde_json::json!(null)), None);
+        assert_eq!(json_to_text(&serde_json::json!("a")).as_deref(), Some("a"));
+        assert_eq!(json_to_text(&serde_json::json!({ "k": [1] })).as_deref(), Some("{\"k\":[1]}"));
+    }
+
+    #[actix_web::test]
+    async fn inserts_need_writes_enabled_and_objects() {
+        let req = TestRequest::default().to_http_request();
+        let insert = |config: Config, body: serde_json::Value| {
+            insert_rows(
+                req.clone(),
+                web::Data::new(offline_pool()),
+                web::Data::new(config),
+                web::Data::new(CircuitBreaker::new(&test_config())),
+                web::Path::from("loans".to_string()),
+                web::Query(InsertParams { on_conflict: None, conflict_columns: None }),
+                web::Bytes::from(body.to_string()),
+            )
+        };
+        let disabled = Config { writes_enabled: false, ..test_config() };
+        let resp = insert(disabled, serde_json::json!({})).await.respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        for body in [serde_json::json!([]), serde_json::json!([{}, 1]), serde_json::json!("row")] {
+            let resp = insert(test_config(), body).await.respond_to(&req);
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[actix_web::test]
+    async fn updates_need_a_filter_and_an_object() {
+        let req = TestRequest::default().to_http_request();
+        let breaker = CircuitBreaker::new(&test_config());
+        let update = |config: Config, filters: &str, or: Option<&str>, body: serde_json::Value| {
+            let filters = parse_multiple_filters(filters).unwrap_or_else(|_| FilterExpr::none());
+            let params = WriteParams { or: or.map(String::from), skip_unchanged: None };
+            let (req, breaker) = (&req, &breaker);
+            async move {
+                run_update(req, &offline_pool(), &config, breaker, "loans", filters, &params, body).await.status()
+            }
+        };
+        let body = serde_json::json!({ "status": "closed" });
+        assert_eq!(update(test_config(), "", None, body.clone()).await, StatusCode::BAD_REQUEST);
+        assert_eq!(update(test_config(), "id=1", None, serde_json::json!({})).await, StatusCode::BAD_REQUEST);
+        assert_eq!(update(test_config(), "id=1", None, serde_json::json!([body])).await, StatusCode::BAD_REQUEST);
+        // Past validation the column lookup needs the database
+        assert_eq!(update(test_config(), "", Some("(id=1,id=2)"), body.clone()).await, StatusCode::INTERNAL_SERVER_ERROR);
+        let unfiltered = Config { allow_unfiltered_writes: true, ..test_config() };
+        assert_eq!(update(unfiltered, "", None, body.clone()).await, StatusCode::INTERNAL_SERVER_ERROR);
+        let disabled = Config { writes_enabled: false, ..test_config() };
+        assert_eq!(update(disabled, "id=1", None, body).await, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn unfiltered_deletes_need_confirmation() {
+        let req = TestRequest::default().to_http_request();
+        let breaker = CircuitBreaker::new(&test_config());
+        let delete = |params: serde_json::Value| {
+            let params: DeleteParams = serde_json::from_value(params).unwrap();
+            let (req, breaker) = (&req, &breaker);
+            async move {
+                let config = test_config();
+                run_delete(req, &offline_pool(), &config, breaker, "loans", FilterExpr::none(), &params).await
+            }
+        };
+        let resp = delete(serde_json::json!({})).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json_body(resp).await["error"],
+            "A delete without filters removes every row, add a filter or confirm_all=true"
+        );
+        let resp = delete(serde_json::json!({ "confirm_all": true })).await;
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn bulk_bodies_read_as_header_and_rows() {
+        let (header, rows) = parse_bulk_body(WriteFormat::Csv, b"id,name\n1,Bob\n2,\n").unwrap();
+        assert_eq!(header, ["id", "name"]);
+        assert_eq!(rows, vec![vec![Some("1".to_string()), Some("Bob".to_string())], vec![Some("2".to_string()), None]]);
+
+        let body = br#"[{"id": 1}, {"name": "Ann", "id": null}]"#;
+        let (header, rows) = parse_bulk_body(WriteFormat::Json, body).unwrap();
+        assert_eq!(header, ["id", "name"]);
+        assert_eq!(rows, vec![vec![Some("1".to_string()), None], vec![None, Some("Ann".to_string())]]);
+        assert!(parse_bulk_body(WriteFormat::Json, b"[1]").is_err());
+
+        let mut buf = Vec::new();
+        write_copy_row(&mut buf, &[Some("say \"hi\"".to_string()), None, Some(String::new())]);
+        assert_eq!(buf, b"\"say \"\"hi\"\"\",\\N,\"\"\n");
+    }
+
+    #[test]
+    fn writes_are_prepared_with_numbered_parameters() {
+        let insert = prepare_insert("loans", &loan_columns(), &objects(serde_json::json!([{ "id": 1 }, { "status": "open" }])), &OnConflict::ERROR).unwrap();
+        assert_eq!(
+            insert.query,
+            "/* datapi table=loans */ INSERT INTO loans (\"id\", \"status\") VALUES \
+             (CAST($1::text AS integer), DEFAULT), (DEFAULT, CAST($2::text AS text)) RETURNING *"
+        );
+        assert_eq!(insert.values, [Some("1".to_string()), Some("open".to_string())]);
+        let defaults = prepare_insert("loans", &loan_columns(), &objects(serde_json::json!({})), &OnConflict::ERROR).unwrap();
+        assert_eq!(defaults.query, "/* datapi table=loans */ INSERT INTO loans DEFAULT VALUES RETURNING *");
+        let unknown = prepare_insert("loans", &loan_columns(), &objects(serde_json::json!({ "amount": 1 })), &OnConflict::ERROR);
+        assert_eq!(unknown.err().unwrap().key, "unknown_column");
+
+        let set = objects(serde_json::json!({ "status": "closed" })).remove(0);
+        let update = prepare_update("loans", &loan_columns(), parse_multiple_filters("id=1").unwrap(), &set, false).unwrap();
+        assert_eq!(
+            update.query,
+            "/* datapi table=loans */ UPDATE loans SET \"status\" = CAST($2::text AS text) WHERE id::text = $1::text RETURNING *"
+        );
+        let changing = prepare_update("loans", &loan_columns(), parse_multiple_filters("id=1").unwrap(), &set, true).unwrap();
+        assert_eq!(
+            changing.query,
+            "/* datapi table=loans */ UPDATE loans SET \"status\" = CAST($2::text AS text) WHERE id::text = $1::text \
+             AND (\"status\"::text IS DISTINCT FROM CAST($2::text AS text)::text) RETURNING *"
+        );
+        let everywhere = prepare_update("loans", &loan_columns(), FilterExpr::none(), &set, true).unwrap();
+        assert!(everywhere.query.contains(" WHERE (\"status\"::text IS DISTINCT FROM CAST($1::text AS text)::text) "));
+        let delete = prepare_delete("loans", None, parse_multiple_filters("id=1").unwrap(), false);
+        assert_eq!(delete.query, "/* datapi table=loans */ DELETE FROM loans WHERE id::text = $1::text");
+        let soft = prepare_delete("loans", Some("deleted_at"), parse_multiple_filters("id=1").unwrap(), true);
+        assert_eq!(soft.query, "/* datapi table=loans */ UPDATE loans SET \"deleted_at\" = now() WHERE id::text = $1::text RETURNING *");
+        assert!(write_objects(serde_json::json!([])).is_none());
+    }
+
+    #[actix_web::test]
+    async fn batches_are_bounded_and_report_the_failing_operation() {
+        let operations: Vec<BatchOperation> = serde_json::from_value(serde_json::json!([
+            { "op": "insert", "table": "loans", "data": { "id": 1 } },
+            { "op": "delete", "table": "loans", "filter": "id=1", "returning": true },
+        ]))
+        .unwrap();
+        assert!(matches!(operations[1], BatchOperation::Delete { returning: Some(true), .. }));
+
+        let req = TestRequest::default().to_http_request();
+        let resp = run_batch(
+            req.clone(),
+            web::Data::new(offline_pool()),
+            web::Data::new(test_config()),
+            web::Data::new(CircuitBreaker::new(&test_config())),
+            None,
+            web::Json(Vec::new()),
+        )
+        .await
+        .respond_to(&req);
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = bad_request(&req, Message::new("missing_write_filter"));
+        let resp = with_batch_operation(resp, 3).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(json_body(resp).await["operation"], 3);
+    }
+
+    #[test]
+    fn minimal_returns_are_found_among_preferences() {
+        let prefers = |header: &str| prefers_minimal(&TestRequest::default().insert_header(("Prefer", header)).to_http_request());
+        assert!(prefers("return=minimal"));
+        assert!(prefers("resolution=merge-duplicates, Return=Minimal"));
+        assert!(!prefers("return=representation"));
+        assert!(!prefers_minimal(&TestRequest::default().to_http_request()));
+    }
+
+    #[test]
+    fn write_bodies_decode_by_content_type() {
+        let format = |content_type: Option<&str>| {
+            let mut req = TestRequest::default();
+            if let Some(content_type) = content_type {
+                req = req.insert_header(("Content-Type", content_type));
+            }
+            WriteFormat::of(&req.to_http_request())
+        };
+        assert_eq!(format(None), Some(WriteFormat::Json));
+        assert_eq!(format(Some("application/x-ndjson; charset=utf-8")), Some(WriteFormat::Ndjson));
+        assert_eq!(format(Some("application/xml")), None);
+
+        assert_eq!(
+            WriteFormat::Ndjson.decode(b"{\"id\":1}\n\n{\"id\":2}\n").unwrap(),
+            serde_json::json!([{ "id": 1 }, { "id": 2 }])
+        );
+        assert_eq!(WriteFormat::Ndjson.decode(b"{\"id\":1}\nnope").unwrap_err().split(':').next(), Some("line 2"));
+        assert_eq!(WriteFormat::Csv.decode(b"id,name\n1,\n").unwrap(), serde_json::json!({ "id": "1", "name": null }));
+        let packed = rmp_serde::to_vec_named(&serde_json::json!({ "id": 1 })).unwrap();
+        assert_eq!(WriteFormat::MessagePack.decode(&packed).unwrap(), serde_json::json!({ "id": 1 }));
+    }
+
+    // A database error with a SQLSTATE, as the server would send it
+    #[derive(Debug)]
+    struct ServerError {
+        code: &'static str,
+        message: &'static str,
+    }
+
+    impl std::fmt::Display for ServerError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(self.message)
+        }
+    }
+
+    impl std::error::Error for ServerError {}
+
+    impl sqlx::error::DatabaseError for ServerError {
+        fn message(&self) -> &str {
+            self.message
+        }
+
+        fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+            Some(self.code.into())
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> sqlx::error::ErrorKind {
+            sqlx::error::ErrorKind::Other
+        }
+    }
+
+    fn server_error(code: &'static str, message: &'static str) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(ServerError { code, message }))
+    }
+
+    #[actix_web::test]
+    async fn statement_timeouts_answer_504() {
+        let req = TestRequest::default().to_http_request();
+        let breaker = CircuitBreaker::new(&test_config());
+        let timeout = server_error("57014", "canceling statement due to statement timeout");
+        assert!(is_statement_timeout(&timeout));
+        assert!(is_statement_timeout(&server_error("57014", "Abbruch der Anweisung wegen Zeitüberschreitung")));
+        assert!(!is_statement_timeout(&server_error("40P01", "deadlock detected")));
+        assert_eq!(database_error_response(&req, &breaker, timeout).status(), StatusCode::GATEWAY_TIMEOUT);
+        let resp = database_error_response(&req, &breaker, server_error("42P01", "relation \"x\" does not exist"));
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn conflicts_are_resolved_per_strategy() {
+        assert_eq!(ConflictStrategy::parse(" Merge "), Some(ConflictStrategy::Merge));
+        assert_eq!(ConflictStrategy::parse("upsert"), None);
+        let conflict = |strategy, target: &[&str]| OnConflict { strategy, target: target.iter().map(|c| c.to_string()).collect() };
+        let columns = loan_columns();
+        let clause = |c: OnConflict, given: &[&str]| c.clause("loans", &columns, given);
+
+        assert_eq!(clause(OnConflict::ERROR, &["id"]).unwrap(), "");
+        assert_eq!(clause(conflict(ConflictStrategy::Ignore, &[]), &["id"]).unwrap(), " ON CONFLICT DO NOTHING");
+        assert_eq!(
+            clause(conflict(ConflictStrategy::Merge, &["id"]), &["id", "status"]).unwrap(),
+            " ON CONFLICT (\"id\") DO UPDATE SET \"status\" = EXCLUDED.\"status\""
+        );
+        assert_eq!(
+            clause(conflict(ConflictStrategy::Merge, &["id"]), &["id"]).unwrap(),
+            " ON CONFLICT (\"id\") DO UPDATE SET \"id\" = EXCLUDED.\"id\""
+        );
+        assert_eq!(
+            clause(conflict(ConflictStrategy::Replace, &["status"]), &["status"]).unwrap(),
+            " ON CONFLICT (\"status\") DO UPDATE SET \"id\" = EXCLUDED.\"id\""
+        );
+        assert_eq!(clause(conflict(ConflictStrategy::Ignore, &["code"]), &["id"]).err().unwrap().key, "unknown_column");
+        assert!(conflict(ConflictStrategy::Replace, &["id"]).updates());
+        assert!(!conflict(ConflictStrategy::Ignore, &[]).updates());
+
+        let upsert = conflict(ConflictStrategy::Merge, &["id"]);
+        let insert = prepare_insert("loans", &columns, &objects(serde_json::json!({ "id": 1, "status": "open" })), &upsert).unwrap();
+        assert!(insert.query.ends_with(" ON CONFLICT (\"id\") DO UPDATE SET \"status\" = EXCLUDED.\"status\" RETURNING *"));
+    }
+
+    #[actix_web::test]
+    async fn on_conflict_comes_from_the_request_or_the_table() {
+        let mut config = test_config();
+        config.tables.insert("loans".to_string(), serde_json::from_value(serde_json::json!({ "on_conflict": "ignore" })).unwrap());
+        let params = |on_conflict: Option<&str>, columns: Option<&str>| InsertParams {
+            on_conflict: on_conflict.map(str::to_string),
+            conflict_columns: columns.map(str::to_string),
+        };
+        let pool = offline_pool();
+        let req = TestRequest::default().to_http_request();
+        let table = OnConflict::from_request(&req, &pool, &config, "loans", &params(None, None)).await.unwrap();
+        assert_eq!((table.strategy, table.target.len()), (ConflictStrategy::Ignore, 0));
+        let asked = OnConflict::from_request(&req, &pool, &config, "loans", &params(Some("merge"), Some("id, code"))).await.unwrap();
+        assert_eq!((asked.strategy, asked.target), (ConflictStrategy::Merge, vec!["id".to_string(), "code".to_string()]));
+        let plain = OnConflict::from_request(&req, &pool, &test_config(), "loans", &params(None, None)).await.unwrap();
+        assert_eq!(plain.strategy, ConflictStrategy::Error);
+        let (status, message) = OnConflict::from_request(&req, &pool, &config, "loans", &params(Some("skip"), None)).await.err().unwrap();
+        assert_eq!((status, message.key), (StatusCode::BAD_REQUEST, "invalid_on_conflict"));
+        // Without a reachable database there is no key to merge on
+        let (_, message) = OnConflict::from_request(&req, &pool, &config, "loans", &params(Some("replace"), None)).await.err().unwrap();
+        assert_eq!(message.key, "missing_conflict_target");
+    }
+
+    #[actix_web::test]
+    async fn batches_pick_an_isolation_up_to_the_maximum() {
+        let level = |query: &str, config: &Config| {
+            IsolationLevel::from_request(&TestRequest::post().uri(&format!("/_batch{}", query)).to_http_request(), config)
+        };
+        let config = test_config();
+        assert_eq!(level("", &config).unwrap(), None);
+        assert_eq!(level("?isolation=serializable", &config).unwrap(), Some(IsolationLevel::Serializable));
+        assert_eq!(level("?isolation=snapshot", &config).err().unwrap().key, "invalid_isolation");
+        let capped = Config { max_isolation: IsolationLevel::RepeatableRead, ..test_config() };
+        assert_eq!(level("?isolation=repeatable_read", &capped).unwrap().map(IsolationLevel::sql), Some("REPEATABLE READ"));
+        let refused = level("?isolation=serializable", &capped).err().unwrap();
+        assert_eq!((refused.key, refused.args[1].1.as_str()), ("isolation_not_allowed", "repeatable_read"));
+
+        let req = TestRequest::default().to_http_request();
+        let breaker = CircuitBreaker::new(&config);
+        let conflict = write_error_response(&req, &breaker, server_error("40001", "could not serialize access"));
+        assert_eq!(conflict.status(), StatusCode::CONFLICT);
+        assert_eq!(json_body(conflict).await["error"], "The transaction conflicted with a concurrent one, retry it");
+    }
+
+    #[actix_web::test]
+    async fn moderated_writes_are_staged_for_approval() {
+        let config = config_with(serde_json::json!({ "loans": { "moderated": true } }));
+        assert!(config.moderated("loans") && config.moderated("\"LOANS\""));
+        assert!(!config.moderated("books"));
+
+        let req = TestRequest::default().to_http_request();
+        assert!(reject_moderated_param(&req, "loans", &[("on_conflict", false)]).is_none());
+        let resp = reject_moderated_param(&req, "loans", &[("on_conflict", false), ("skip_unchanged", true)]).unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json_body(resp).await["error"],
+            "skip_unchanged can't be used with loans, whose writes need approval"
+        );
+
+        let read_only = Config { writes_enabled: false, ..config };
+        let operation = BatchOperation::Insert { table: "loans".to_string(), data: serde_json::json!({ "id": 1 }) };
+        let resp = stage_write(&req, &offline_pool(), &read_only, None, vec![operation.clone()]).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // Staged operations are stored as JSON and read back to run on approval
+        let stored = serde_json::to_value(&operation).unwrap();
+        assert_eq!(stored, serde_json::json!({ "op": "insert", "table": "loans", "data": { "id": 1 } }));
+        let restored: BatchOperation = serde_json::from_value(stored).unwrap();
+        assert_eq!(restored.table(), "loans");
+    }
+
+    #[actix_web::test]
+    async fn pending_changes_are_listed_for_admins_by_status() {
+        let config = web::Data::new(test_config());
+        let breaker = web::Data::new(CircuitBreaker::new(&config));
+        let list = |token: Option<&'static str>, status: Option<&str>| {
+            let params = PendingParams { page: None, page_size: None, status: status.map(str::to_string), table: None };
+            let (config, breaker) = (config.clone(), breaker.clone());
+            async move {
+                let req = admin_request(token);
+                list_pending(req.clone(), web::Data::new(offline_pool()), config, breaker, web::Query(params))
+                    .await
+                    .respond_to(&req)
+            }
+        };
+        assert_eq!(list(None, None).await.status(), StatusCode::UNAUTHORIZED);
+        let resp = list(Some("secret"), Some("maybe")).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            json_body(resp).await["error"],
+            "Invalid status maybe, expected pending, approved, rejected or all"
+        );
+
+        let req = TestRequest::default().insert_header((ACTOR_HEADER, "ana")).to_http_request();
+        assert_eq!(request_actor(&req).as_deref(), Some("ana"));
+        req.extensions_mut().insert(TokenGrants { subject: Some("bo".to_string()), tables: Vec::new(), claims: serde_json::Value::Null });
+        assert_eq!(request_actor(&req).as_deref(), Some("bo"));
+    }
+}